@@ -0,0 +1,30 @@
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+
+@group(0) @binding(0) var screen_texture: texture_2d<f32>;
+@group(0) @binding(1) var screen_sampler: sampler;
+@group(0) @binding(2) var lut_texture: texture_3d<f32>;
+@group(0) @binding(3) var lut_sampler: sampler;
+
+fn linear_to_srgb(color: vec3f) -> vec3f {
+    let low = color * 12.92;
+    let high = 1.055 * pow(color, vec3(1.0 / 2.4)) - 0.055;
+    return select(high, low, color <= vec3(0.0031308));
+}
+
+fn srgb_to_linear(color: vec3f) -> vec3f {
+    let low = color / 12.92;
+    let high = pow((color + 0.055) / 1.055, vec3(2.4));
+    return select(high, low, color <= vec3(0.04045));
+}
+
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4f {
+    let color = textureSample(screen_texture, screen_sampler, in.uv);
+    // The looks are authored for display encoded colors but the view target is still linear
+    let encoded = linear_to_srgb(saturate(color.rgb));
+    // 0 and 1 need to land on the centers of the first and last texels
+    let size = f32(textureDimensions(lut_texture).x);
+    let uvw = encoded * (size - 1.0) / size + 0.5 / size;
+    let graded = textureSample(lut_texture, lut_sampler, uvw).rgb;
+    return vec4(srgb_to_linear(graded), color.a);
+}
@@ -0,0 +1,76 @@
+#import bevy_pbr::{
+    mesh_view_bindings as view_bindings,
+    prepass_utils,
+    view_transformations::depth_ndc_to_view_z,
+}
+#ifdef DEFERRED_PREPASS
+#import bevy_pbr::pbr_deferred_functions::pbr_input_from_deferred_gbuffer
+#endif
+
+// Same order as the DebugView enum
+const DEPTH: u32 = 1u;
+const NORMALS: u32 = 2u;
+const BASE_COLOR: u32 = 3u;
+const METALLIC_ROUGHNESS: u32 = 4u;
+const MOTION_VECTORS: u32 = 5u;
+const AMBIENT_OCCLUSION: u32 = 6u;
+
+// The targets the view doesn't have
+const MISSING: vec3<f32> = vec3(1.0, 0.0, 1.0);
+
+@group(2) @binding(0) var<uniform> debug_view: u32;
+
+@vertex
+fn vertex(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
+    // A triangle covering the screen, the mesh positions are ignored
+    let uv = vec2(f32((index << 1u) & 2u), f32(index & 2u));
+    return vec4(uv * 2.0 - 1.0, 0.0, 1.0);
+}
+
+@fragment
+fn fragment(@builtin(position) frag_coord: vec4<f32>) -> @location(0) vec4<f32> {
+    var color = MISSING;
+    switch debug_view {
+        case DEPTH: {
+#ifdef DEPTH_PREPASS
+            // Logarithmic so both the close ground and the far hills are readable
+            let distance = -depth_ndc_to_view_z(prepass_utils::prepass_depth(frag_coord, 0u));
+            color = vec3(1.0 - saturate(log2(distance + 1.0) / 10.0));
+#endif
+        }
+        case NORMALS: {
+#ifdef DEFERRED_PREPASS
+            let gbuffer = textureLoad(view_bindings::deferred_prepass_texture, vec2<i32>(frag_coord.xy), 0);
+            color = pbr_input_from_deferred_gbuffer(frag_coord, gbuffer).N * 0.5 + 0.5;
+#else ifdef NORMAL_PREPASS
+            color = prepass_utils::prepass_normal(frag_coord, 0u) * 0.5 + 0.5;
+#endif
+        }
+        case BASE_COLOR: {
+#ifdef DEFERRED_PREPASS
+            let gbuffer = textureLoad(view_bindings::deferred_prepass_texture, vec2<i32>(frag_coord.xy), 0);
+            color = pbr_input_from_deferred_gbuffer(frag_coord, gbuffer).material.base_color.rgb;
+#endif
+        }
+        case METALLIC_ROUGHNESS: {
+#ifdef DEFERRED_PREPASS
+            // Like the textures of the gltf, roughness in green and metallic in blue
+            let gbuffer = textureLoad(view_bindings::deferred_prepass_texture, vec2<i32>(frag_coord.xy), 0);
+            let material = pbr_input_from_deferred_gbuffer(frag_coord, gbuffer).material;
+            color = vec3(0.0, material.perceptual_roughness, material.metallic);
+#endif
+        }
+        case MOTION_VECTORS: {
+#ifdef MOTION_VECTOR_PREPASS
+            color = vec3(prepass_utils::prepass_motion_vector(frag_coord, 0u) * 50.0 + 0.5, 0.5);
+#endif
+        }
+        case AMBIENT_OCCLUSION: {
+#ifdef SCREEN_SPACE_AMBIENT_OCCLUSION
+            color = vec3(textureLoad(view_bindings::screen_space_ambient_occlusion_texture, vec2<i32>(frag_coord.xy), 0).r);
+#endif
+        }
+        default: {}
+    }
+    return vec4(color, 1.0);
+}
@@ -0,0 +1,74 @@
+#import bevy_pbr::mesh_view_bindings::{view, globals}
+
+struct DustSettings {
+    // xyz: direction the light travels, w: brightness
+    light: vec4<f32>,
+    // x: size of the volume around the camera, y: fraction of visible particles,
+    // z: particle size, w: drift speed
+    params: vec4<f32>,
+}
+@group(2) @binding(0) var<uniform> settings: DustSettings;
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(1) uv: vec2<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) brightness: f32,
+};
+
+fn hash(p: vec3<f32>) -> f32 {
+    return fract(sin(dot(p, vec3(12.9898, 78.233, 37.719))) * 43758.5453);
+}
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+    let volume_size = settings.params.x;
+    let particle_size = settings.params.z;
+    let drift_speed = settings.params.w;
+
+    // Hide the particles above the configured density
+    if hash(vertex.position) > settings.params.y {
+        out.clip_position = vec4(0.0, 0.0, -1.0, 1.0);
+        return out;
+    }
+
+    // Slowly drift each particle in its own direction
+    let seed = vertex.position;
+    let drift = (vec3(hash(seed.yzx), hash(seed.zxy), hash(seed)) - 0.5) * drift_speed;
+    let time = globals.time;
+    let wobble = vec3(sin(time * 0.3 + seed.x * 6.28), sin(time * 0.2 + seed.y * 6.28), 0.0) * 0.02;
+    let local = seed + drift * time + wobble;
+
+    // Wrap the particles in a volume that follows the camera so they never run out
+    let camera = view.world_position;
+    let offset = fract((local * volume_size - camera) / volume_size) - 0.5;
+    let center = camera + offset * volume_size;
+
+    // Expand the quad facing the camera
+    let corner = (vertex.uv - 0.5) * particle_size;
+    let right = view.world_from_view[0].xyz;
+    let up = view.world_from_view[1].xyz;
+    let world_position = center + right * corner.x + up * corner.y;
+    out.clip_position = view.clip_from_world * vec4(world_position, 1.0);
+    out.uv = vertex.uv;
+
+    // Forward scattering, the motes light up when looking towards the sun
+    let view_dir = normalize(center - camera);
+    let scattering = pow(saturate(dot(view_dir, -settings.light.xyz)), 4.0);
+    // Fade out near the edges of the volume so wrapping isn't visible
+    let edge_fade = 1.0 - smoothstep(0.35, 0.5, length(offset));
+    out.brightness = settings.light.w * (0.2 + scattering) * edge_fade;
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    let d = length(in.uv - 0.5) * 2.0;
+    let alpha = saturate(1.0 - d * d);
+    return vec4(vec3(in.brightness * alpha), 1.0);
+}
@@ -2,16 +2,53 @@
     pbr_deferred_functions::deferred_output,
     pbr_fragment::pbr_input_from_standard_material,
     forward_io::{VertexOutput, FragmentOutput},
+    mesh_view_bindings::globals,
     prepass_utils,
     view_transformations::{depth_ndc_to_view_z, frag_coord_to_ndc},
 }
 #import bevy_render::globals::Globals
+#import forest_scene::wind::wind_scroll
+
+struct FoamSettings {
+    // x: speed of the primary water octave, y: time scale of the water, z: its scale,
+    // w: streak strength
+    streaks: vec4<f32>,
+    // x: depth where the foam is gone, yz: depth texture origin, w: depth texture extent
+    shore: vec4<f32>,
+    // x: max depth encoded in the depth texture
+    depth_region: vec4<f32>,
+}
+
+@group(2) @binding(0) var<uniform> foam_settings: FoamSettings;
+@group(2) @binding(1) var depth_texture: texture_2d<f32>;
+@group(2) @binding(2) var depth_sampler: sampler;
 
 fn depth_fade(frag_coord: vec4<f32>, distance: f32) -> f32 {
     let depth = depth_ndc_to_view_z(prepass_utils::prepass_depth(frag_coord, 0u));
     return saturate((depth_ndc_to_view_z(frag_coord.z) - depth) / distance);
 }
 
+// Breaks the foam into streaks drifting downwind with the primary water octave
+fn streaks(uv: vec2<f32>) -> f32 {
+    let tau = 6.28318530718;
+    let time = globals.time * foam_settings.streaks.y;
+    let offset = wind_scroll(time, foam_settings.streaks.x);
+    let p = (uv * foam_settings.streaks.z + offset) * tau;
+    let wave = sin(p.x * 3.0 + sin(p.y * 2.0)) * sin(p.y * 5.0 + sin(p.x * 4.0));
+    return mix(1.0, wave * 0.5 + 0.5, foam_settings.streaks.w);
+}
+
+// 1 on the shoreline, fading to 0 at the falloff depth
+fn shore_coverage(world_xz: vec2<f32>) -> f32 {
+    let falloff = foam_settings.shore.x;
+    if falloff <= 0.0 || foam_settings.shore.w <= 0.0 {
+        return 1.0;
+    }
+    let uv = (world_xz - foam_settings.shore.yz) / foam_settings.shore.w;
+    let depth = textureSampleLevel(depth_texture, depth_sampler, uv, 0.0).r * foam_settings.depth_region.x;
+    return 1.0 - smoothstep(0.0, falloff, depth);
+}
+
 @fragment
 fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
     let offset = 0.9;
@@ -27,5 +64,9 @@ fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
     if foam > 0.25 {
         discard;
     }
-    return vec4(1.0 - foam);
+    let coverage = streaks(in.uv) * shore_coverage(in.world_position.xz);
+    if coverage <= 0.01 {
+        discard;
+    }
+    return vec4(vec3(1.0 - foam), (1.0 - foam) * coverage);
 }
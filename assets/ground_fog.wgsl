@@ -0,0 +1,62 @@
+#import bevy_pbr::{
+    forward_io::VertexOutput,
+    mesh_view_bindings::view,
+    prepass_utils,
+    view_transformations::{frag_coord_to_ndc, position_ndc_to_world},
+}
+
+struct GroundFogSettings {
+    color: vec4<f32>,
+    // x: base height, y: falloff, z: density
+    params: vec4<f32>,
+    bounds_min: vec4<f32>,
+    bounds_max: vec4<f32>,
+}
+@group(2) @binding(0) var<uniform> settings: GroundFogSettings;
+
+// Integrates an exponential height fog along a ray.
+// The density is constant below the base height and falls off exponentially above it.
+fn optical_depth(origin: vec3<f32>, dir: vec3<f32>, ray_length: f32) -> f32 {
+    let base = settings.params.x;
+    let falloff = settings.params.y;
+    let density = settings.params.z;
+
+    let start_density = density * exp(-falloff * max(origin.y - base, 0.0));
+    let k = falloff * dir.y;
+    if abs(k) < 1e-4 {
+        return start_density * ray_length;
+    }
+    return start_density * (1.0 - exp(-k * ray_length)) / k;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    let camera = view.world_position;
+    let to_fragment = in.world_position.xyz - camera;
+    let dir = normalize(to_fragment);
+
+    // Find where the ray enters the volume, it starts at the camera when it's inside
+    let inv_dir = 1.0 / dir;
+    let t0 = (settings.bounds_min.xyz - camera) * inv_dir;
+    let t1 = (settings.bounds_max.xyz - camera) * inv_dir;
+    let t_min = min(t0, t1);
+    let t_enter = max(max(max(t_min.x, t_min.y), t_min.z), 0.0);
+
+    // The ray leaves the volume at the back face or stops at the scene geometry
+    var t_exit = length(to_fragment);
+    let depth = prepass_utils::prepass_depth(in.position, 0u);
+    // depth is 0 for the sky with reversed z
+    if depth > 0.0 {
+        let ndc = frag_coord_to_ndc(in.position);
+        let scene_position = position_ndc_to_world(vec3(ndc.xy, depth));
+        t_exit = min(t_exit, distance(scene_position, camera));
+    }
+
+    if t_exit <= t_enter {
+        discard;
+    }
+
+    let fog = optical_depth(camera + dir * t_enter, dir, t_exit - t_enter);
+    let alpha = 1.0 - exp(-fog);
+    return vec4(settings.color.rgb, alpha * settings.color.a);
+}
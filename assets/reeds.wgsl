@@ -0,0 +1,45 @@
+#import bevy_pbr::{
+    forward_io::{Vertex, VertexOutput},
+    mesh_functions,
+    mesh_view_bindings::globals,
+    view_transformations::position_world_to_clip,
+}
+#import forest_scene::wind::wind_sway
+
+struct ReedSwaySettings {
+    // How far the tip of a 1m reed moves
+    strength: f32,
+}
+@group(2) @binding(100) var<uniform> settings: ReedSwaySettings;
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+
+    let world_from_local = mesh_functions::get_world_from_local(vertex.instance_index);
+    var world_position = mesh_functions::mesh_position_local_to_world(
+        world_from_local,
+        vec4(vertex.position, 1.0),
+    );
+
+    // The base stays in place and the bend grows toward the tip
+    let origin = world_from_local[3].xyz;
+    let sway = wind_sway(origin, vertex.position.y, settings.strength, globals.time);
+    world_position += vec4(sway, 0.0);
+
+    out.world_position = world_position;
+    out.position = position_world_to_clip(world_position.xyz);
+#ifdef VERTEX_NORMALS
+    out.world_normal = mesh_functions::mesh_normal_local_to_world(
+        vertex.normal,
+        vertex.instance_index,
+    );
+#endif
+#ifdef VERTEX_UVS_A
+    out.uv = vertex.uv;
+#endif
+#ifdef VERTEX_OUTPUT_INSTANCE_INDEX
+    out.instance_index = vertex.instance_index;
+#endif
+    return out;
+}
@@ -0,0 +1,42 @@
+// Only draws into the shadow maps. The prepass of the camera always has the normal or the deferred
+// target while the shadow views have neither, so that's what tells them apart.
+
+#ifdef PREPASS_PIPELINE
+#import bevy_pbr::{
+    prepass_io::{VertexOutput, FragmentOutput},
+    pbr_prepass_functions::prepass_alpha_discard,
+}
+
+#ifdef PREPASS_FRAGMENT
+@fragment
+fn fragment(in: VertexOutput) -> FragmentOutput {
+#ifdef NORMAL_PREPASS
+    discard;
+#endif
+#ifdef DEFERRED_PREPASS
+    discard;
+#endif
+    prepass_alpha_discard(in);
+    var out: FragmentOutput;
+#ifdef UNCLIPPED_DEPTH_ORTHO_EMULATION
+    out.frag_depth = in.unclipped_depth;
+#endif
+    return out;
+}
+#else
+@fragment
+fn fragment(in: VertexOutput) {
+    prepass_alpha_discard(in);
+}
+#endif
+
+#else
+#import bevy_pbr::forward_io::{VertexOutput, FragmentOutput}
+
+@fragment
+fn fragment(in: VertexOutput) -> FragmentOutput {
+    var out: FragmentOutput;
+    discard;
+    return out;
+}
+#endif
@@ -0,0 +1,109 @@
+#import bevy_pbr::mesh_view_bindings::view
+
+struct SkySettings {
+    zenith_color: vec4<f32>,
+    horizon_color: vec4<f32>,
+    // xyz: direction the light travels, w: cosine of the sun radius
+    sun: vec4<f32>,
+    // x: brightness of the sky, y: brightness of the sun disk
+    params: vec4<f32>,
+    // xyz: celestial pole, w: rotation of the stars around it
+    stars: vec4<f32>,
+    // xyz: direction toward the moon, w: cosine of the moon radius
+    moon: vec4<f32>,
+    // x: brightness of the stars, y: moon phase angle, z: brightness of the moon disk
+    night: vec4<f32>,
+}
+@group(2) @binding(0) var<uniform> settings: SkySettings;
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) direction: vec3<f32>,
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+    // Keep the sphere centered on the camera
+    let world_position = view.world_position + vertex.position;
+    out.clip_position = view.clip_from_world * vec4(world_position, 1.0);
+    // Push it to the far plane, with reverse z that's a depth of 0
+    out.clip_position.z = 0.0;
+    out.direction = vertex.position;
+    return out;
+}
+
+fn hash(p: vec3<f32>) -> f32 {
+    return fract(sin(dot(p, vec3(12.9898, 78.233, 37.719))) * 43758.5453);
+}
+
+// Rotates v around a unit axis, Rodrigues' formula
+fn rotate(v: vec3<f32>, axis: vec3<f32>, angle: f32) -> vec3<f32> {
+    let c = cos(angle);
+    let s = sin(angle);
+    return v * c + cross(axis, v) * s + axis * dot(axis, v) * (1.0 - c);
+}
+
+// A few stars scattered in a grid of cells around the sphere
+fn stars(direction: vec3<f32>) -> f32 {
+    let p = direction * 300.0;
+    let cell = floor(p);
+    let h = hash(cell);
+    if h < 0.97 {
+        return 0.0;
+    }
+    let jitter = vec3(hash(cell + 1.0), hash(cell + 2.0), hash(cell + 3.0)) - 0.5;
+    let center = cell + 0.5 + jitter * 0.6;
+    return smoothstep(0.3, 0.0, length(p - center)) * (h - 0.97) / 0.03;
+}
+
+// The moon disk shaded like a sphere lit from the side given by the phase
+fn moon(direction: vec3<f32>) -> f32 {
+    let to_moon = settings.moon.xyz;
+    let cos_radius = settings.moon.w;
+    if dot(direction, to_moon) < cos_radius {
+        return 0.0;
+    }
+    let right = normalize(cross(to_moon, vec3(0.0, 1.0, 0.0)));
+    let up = cross(right, to_moon);
+    let sin_radius = sqrt(1.0 - cos_radius * cos_radius);
+    let uv = vec2(dot(direction, right), dot(direction, up)) / sin_radius;
+    let normal = vec3(uv, sqrt(saturate(1.0 - dot(uv, uv))));
+    let phase = settings.night.y;
+    let light = vec3(sin(phase), 0.0, -cos(phase));
+    // The dark side still gets a bit of earthshine
+    return 0.03 + smoothstep(-0.05, 0.05, dot(normal, light));
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    let direction = normalize(in.direction);
+
+    let height = saturate(direction.y);
+    var color = mix(settings.horizon_color.rgb, settings.zenith_color.rgb, sqrt(height));
+    // Darken the ground side a bit so the horizon reads
+    if direction.y < 0.0 {
+        color *= mix(1.0, 0.5, saturate(-direction.y * 4.0));
+    }
+
+    // Analytic sun disk with a soft edge and a small glow around it
+    let to_sun = -settings.sun.xyz;
+    let cos_angle = dot(direction, to_sun);
+    let cos_radius = settings.sun.w;
+    let edge = (1.0 - cos_radius) * 0.2;
+    let disk = smoothstep(cos_radius - edge, cos_radius + edge, cos_angle);
+    let glow = pow(saturate(cos_angle), 256.0) * 0.25;
+    color += (disk * settings.params.y + glow) * settings.horizon_color.rgb;
+
+    // The stars are fixed on a sphere turning around the celestial pole
+    let star_direction = rotate(direction, settings.stars.xyz, -settings.stars.w);
+    let above_horizon = smoothstep(-0.02, 0.05, direction.y);
+    color += stars(star_direction) * settings.night.x * above_horizon;
+    color += moon(direction) * settings.night.z * vec3(0.9, 0.92, 1.0);
+
+    return vec4(color * settings.params.x, 1.0);
+}
@@ -0,0 +1,76 @@
+#import bevy_pbr::mesh_view_bindings::{view, globals}
+
+struct SnowSettings {
+    // x: size of the volume around the camera, y: fraction of visible flakes,
+    // z: flake size, w: fall speed
+    params: vec4<f32>,
+    // xy: wind drift on x and z, z: ground height under the camera, w: brightness
+    wind: vec4<f32>,
+}
+@group(2) @binding(0) var<uniform> settings: SnowSettings;
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(1) uv: vec2<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) alpha: f32,
+};
+
+fn hash(p: vec3<f32>) -> f32 {
+    return fract(sin(dot(p, vec3(12.9898, 78.233, 37.719))) * 43758.5453);
+}
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+    let volume_size = settings.params.x;
+    let flake_size = settings.params.z;
+    let fall_speed = settings.params.w;
+
+    // Hide the flakes above the configured intensity
+    if hash(vertex.position) > settings.params.y {
+        out.clip_position = vec4(0.0, 0.0, -1.0, 1.0);
+        return out;
+    }
+
+    // Every flake falls at its own speed and follows the wind with a bit of flutter
+    let seed = vertex.position;
+    let time = globals.time;
+    let speed = 0.7 + 0.6 * hash(seed.zxy);
+    let flutter = vec3(sin(time * 1.3 + seed.y * 6.28), 0.0, cos(time * 1.1 + seed.x * 6.28)) * 0.15;
+    let velocity = vec3(settings.wind.x, -fall_speed, settings.wind.y) * speed;
+    let local = seed * volume_size + velocity * time + flutter;
+
+    // Wrap the flakes in a volume that follows the camera, the bottom stops at the ground so the
+    // flakes reaching it start over at the top instead of falling through the terrain
+    let camera = view.world_position;
+    let bottom = max(camera.y - volume_size * 0.5, settings.wind.z);
+    let top = max(camera.y + volume_size * 0.5, bottom + 1.0);
+    let offset_xz = fract((local.xz - camera.xz) / volume_size) - 0.5;
+    let y = bottom + fract((local.y - bottom) / (top - bottom)) * (top - bottom);
+    let center = vec3(camera.x + offset_xz.x * volume_size, y, camera.z + offset_xz.y * volume_size);
+
+    // Expand the quad facing the camera
+    let corner = (vertex.uv - 0.5) * flake_size;
+    let right = view.world_from_view[0].xyz;
+    let up = view.world_from_view[1].xyz;
+    let world_position = center + right * corner.x + up * corner.y;
+    out.clip_position = view.clip_from_world * vec4(world_position, 1.0);
+    out.uv = vertex.uv;
+
+    // Fade out near the sides of the volume so wrapping isn't visible
+    let edge_fade = 1.0 - smoothstep(0.35, 0.5, length(offset_xz));
+    out.alpha = edge_fade;
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    let d = length(in.uv - 0.5) * 2.0;
+    let alpha = saturate(1.0 - d * d) * in.alpha;
+    return vec4(vec3(settings.wind.w), alpha);
+}
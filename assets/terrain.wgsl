@@ -1,171 +1,360 @@
-#import bevy_pbr::{
-    pbr_deferred_functions::deferred_output,
-    pbr_fragment::pbr_input_from_standard_material,
-    prepass_io::{VertexOutput, FragmentOutput},
-    prepass_utils,
-    mesh_view_bindings::view,
-    pbr_functions,
-    pbr_functions::SampleBias,
-    pbr_bindings,
-    lighting,
-    parallax_mapping,
-    pbr_types::{PbrInput, pbr_input_new},
-}
-
-
-struct TerrainMaterialSettings {
-    max_steepness: f32,
-}
-@group(2) @binding(100) var<uniform> settings: TerrainMaterialSettings;
-
-// #define USE_PARALLAX
-// #define USE_TRIPLANAR
-
-fn get_uv(uv: vec2f, Vt: vec3f) -> vec2f {
-#ifdef USE_PARALLAX
-    return parallax_mapping::parallaxed_uv(
-        pbr_bindings::material.parallax_depth_scale,
-        pbr_bindings::material.max_parallax_layer_count,
-        pbr_bindings::material.max_relief_mapping_search_steps,
-        uv,
-        // Flip the direction of Vt to go toward the surface to make the
-        // parallax mapping algorithm easier to understand and reason
-        // about.
-        -Vt,
-    );
-#else // USE_PARALLAX
-    return uv;
-#endif // USE_PARALLAX
-}
-
-
-fn triplanar_mapping(
-    world_pos: vec4f,
-    scale: f32,
-    blend_axes: vec3f,
-    bias: SampleBias,
-    t: texture_2d<f32>,
-    s: sampler,
-    Vt: vec3f
-) -> vec4f {
-    let scaled_world_pos = world_pos / scale;
-#ifdef USE_TRIPLANAR
-    let x_projeciton = pbr_functions::sample_texture(
-        t, s, scaled_world_pos.yz, bias
-    ) * blend_axes.x;
-    let y_projection = pbr_functions::sample_texture(
-        t, s, scaled_world_pos.xz, bias
-    ) * blend_axes.y;
-    let z_projection = pbr_functions::sample_texture(
-        t, s, scaled_world_pos.xy, bias
-    ) * blend_axes.z;
-    let base_color = x_projeciton + y_projection + z_projection;
-    return base_color;
-#else
-    return pbr_functions::sample_texture(
-        t, s, get_uv(scaled_world_pos.xz, Vt), bias
-    );
-#endif
-}
-
-@fragment
-fn fragment(in: VertexOutput, @builtin(front_facing) is_front: bool) -> FragmentOutput {
-    // Create the PBR input.
-    var pbr_input = pbr_input_from_standard_material(in, is_front);
-    // var pbr_input: PbrInput = pbr_input_new();
-
-    // let up = vec3(0.0, 1.0, 0.0);
-    // let steepness = length(cross(in.world_normal, up));
-    // pbr_input.material.base_color = mix(
-    //     pbr_input.material.base_color,
-    //     vec4(1.0, 0.0, 0.0, 1.0),
-    //     saturate(steepness - settings.max_steepness)
-    // );
-
-// #ifdef USE_PARALLAX
-//     let V = pbr_input.V;
-//     let N = in.world_normal;
-//     let T = in.world_tangent.xyz;
-//     let B = in.world_tangent.w * cross(N, T);
-//     // Transform V from fragment to camera in world space to tangent space.
-//     let Vt = vec3(dot(V, T), dot(V, B), dot(V, N));
-// #else
-//     let Vt = vec3(0.0);
-// #endif
-
-//     let double_sided = false;
-//     pbr_input.frag_coord = in.position;
-//     pbr_input.world_position = in.world_position;
-//     pbr_input.world_normal = pbr_functions::prepare_world_normal(
-//         in.world_normal,
-//         double_sided,
-//         is_front,
-//     );
-
-// // //     // pbr_input.is_orthographic = view.clip_from_view[3].w == 1.0;
-
-//     // pbr_input.N = normalize(pbr_input.world_normal);
-
-//     var blend_axes = abs(in.world_normal);
-//     blend_axes /= blend_axes.x + blend_axes.y + blend_axes.z;
-
-//     let scale = 25.0;
-
-//     var bias: SampleBias;
-//     bias.mip_bias = view.mip_bias;
-
-//     // base_color
-//     let base_color = triplanar_mapping(
-//         in.world_position,
-//         scale,
-//         blend_axes,
-//         bias,
-//         pbr_bindings::base_color_texture,
-//         pbr_bindings::base_color_sampler,
-//         Vt
-//     );
-//     pbr_input.material.base_color = base_color;
-
-//     // metallic_roughness
-//     var metallic: f32 = pbr_bindings::material.metallic;
-//     var perceptual_roughness: f32 = pbr_bindings::material.perceptual_roughness;
-//     let roughness = lighting::perceptualRoughnessToRoughness(perceptual_roughness);
-
-//     let metallic_roughness = triplanar_mapping(
-//         in.world_position,
-//         scale,
-//         blend_axes,
-//         bias,
-//         pbr_bindings::metallic_roughness_texture,
-//         pbr_bindings::base_color_sampler,
-//         Vt
-//     );
-//     metallic *= metallic_roughness.b;
-//     perceptual_roughness *= metallic_roughness.g;
-
-//     pbr_input.material.metallic = metallic;
-//     pbr_input.material.perceptual_roughness = perceptual_roughness;
-
-//     // normal_map
-//     let Nt = triplanar_mapping(
-//         in.world_position,
-//         scale,
-//         blend_axes,
-//         bias,
-//         pbr_bindings::normal_map_texture,
-//         pbr_bindings::normal_map_sampler,
-//         Vt
-//     ).rgb;
-//     let TBN = pbr_functions::calculate_tbn_mikktspace(in.world_normal, in.world_tangent);
-//     pbr_input.N = pbr_functions::apply_normal_mapping(
-//         pbr_bindings::material.flags,
-//         TBN,
-//         double_sided,
-//         is_front,
-//         Nt,
-//         view.mip_bias,
-//     );
-
-    // Send the rest to the deferred shader.
-    return deferred_output(in, pbr_input);
-}
+#import bevy_pbr::{
+    pbr_fragment::pbr_input_from_standard_material,
+    prepass_utils,
+    mesh_view_bindings::view,
+    pbr_functions,
+    pbr_functions::SampleBias,
+    pbr_bindings,
+    lighting,
+    parallax_mapping,
+    pbr_types::{PbrInput, pbr_input_new},
+}
+
+#ifdef PREPASS_PIPELINE
+#import bevy_pbr::{
+    prepass_io::{VertexOutput, FragmentOutput},
+    pbr_deferred_functions::deferred_output,
+}
+#else
+#import bevy_pbr::{
+    forward_io::{VertexOutput, FragmentOutput},
+    pbr_functions::{apply_pbr_lighting, main_pass_post_lighting_processing},
+}
+#endif
+
+
+struct TerrainMaterialSettings {
+    max_steepness: f32,
+    snow_height: f32,
+    puddle_amount: f32,
+    root_blend_strength: f32,
+    // rgb: haze color over the land, a: strength
+    haze_sky_tint: vec4f,
+    haze_water_tint: vec4f,
+    haze_start: f32,
+    water_level: f32,
+    detail_tiling: f32,
+    detail_strength: f32,
+    detail_fade_start: f32,
+    detail_fade_end: f32,
+    // xy: world origin of the footprint texture, z: its extent, w: 1 to show the footprints
+    footprint_region: vec4f,
+    // xy: world origin of the canopy texture, z: its extent, w: strength of the darkening
+    canopy_region: vec4f,
+    canopy_fade_start: f32,
+    canopy_fade_end: f32,
+}
+@group(2) @binding(100) var<uniform> settings: TerrainMaterialSettings;
+#ifdef TERRAIN_DETAIL_NORMAL
+@group(2) @binding(101) var detail_normal_texture: texture_2d<f32>;
+@group(2) @binding(102) var detail_normal_sampler: sampler;
+#endif
+#ifdef TERRAIN_DETAIL_ALBEDO
+@group(2) @binding(103) var detail_albedo_texture: texture_2d<f32>;
+@group(2) @binding(104) var detail_albedo_sampler: sampler;
+#endif
+#ifdef TERRAIN_FOOTPRINTS
+@group(2) @binding(105) var footprint_texture: texture_2d<f32>;
+@group(2) @binding(106) var footprint_sampler: sampler;
+#endif
+#ifdef TERRAIN_CANOPY_SHADOW
+@group(2) @binding(107) var canopy_texture: texture_2d<f32>;
+@group(2) @binding(108) var canopy_sampler: sampler;
+#endif
+
+// #define USE_PARALLAX
+// #define USE_TRIPLANAR
+
+fn get_uv(uv: vec2f, Vt: vec3f) -> vec2f {
+#ifdef USE_PARALLAX
+    return parallax_mapping::parallaxed_uv(
+        pbr_bindings::material.parallax_depth_scale,
+        pbr_bindings::material.max_parallax_layer_count,
+        pbr_bindings::material.max_relief_mapping_search_steps,
+        uv,
+        // Flip the direction of Vt to go toward the surface to make the
+        // parallax mapping algorithm easier to understand and reason
+        // about.
+        -Vt,
+    );
+#else // USE_PARALLAX
+    return uv;
+#endif // USE_PARALLAX
+}
+
+
+fn triplanar_mapping(
+    world_pos: vec4f,
+    scale: f32,
+    blend_axes: vec3f,
+    bias: SampleBias,
+    t: texture_2d<f32>,
+    s: sampler,
+    Vt: vec3f
+) -> vec4f {
+    let scaled_world_pos = world_pos / scale;
+#ifdef USE_TRIPLANAR
+    let x_projeciton = pbr_functions::sample_texture(
+        t, s, scaled_world_pos.yz, bias
+    ) * blend_axes.x;
+    let y_projection = pbr_functions::sample_texture(
+        t, s, scaled_world_pos.xz, bias
+    ) * blend_axes.y;
+    let z_projection = pbr_functions::sample_texture(
+        t, s, scaled_world_pos.xy, bias
+    ) * blend_axes.z;
+    let base_color = x_projeciton + y_projection + z_projection;
+    return base_color;
+#else
+    return pbr_functions::sample_texture(
+        t, s, get_uv(scaled_world_pos.xz, Vt), bias
+    );
+#endif
+}
+
+// Distant terrain fades into the horizon haze. The haze takes the water tint when the view ray
+// passes low over the lake, approximated with the height of the ray half way to the fragment.
+// It starts where the volumetric fog stops so the two don't stack up, the ground fog is drawn on
+// top of the result like it is on anything else.
+fn aerial_perspective(world_position: vec3f) -> vec4f {
+    let camera = view.world_position;
+    let haze_distance = max(distance(world_position, camera) - settings.haze_start, 0.0);
+    let amount = settings.haze_sky_tint.a * (1.0 - exp(-haze_distance * 0.004));
+    let ray_height = (camera.y + world_position.y) * 0.5 - settings.water_level;
+    let over_water = 1.0 - smoothstep(0.0, 15.0, ray_height);
+    let tint = mix(settings.haze_sky_tint.rgb, settings.haze_water_tint.rgb, over_water);
+    return vec4(tint, amount);
+}
+
+// The detail textures only show up close to the camera, further away they would alias into noise
+// that the TAA can't settle.
+fn detail_amount(world_position: vec3f) -> f32 {
+    let distance = distance(world_position, view.world_position);
+    let fade = 1.0 - smoothstep(settings.detail_fade_start, settings.detail_fade_end, distance);
+    return settings.detail_strength * fade;
+}
+
+// How deep the camera's footprints are pressed in the ground, the texture follows the camera and
+// is blank on its border
+fn footprint_depth(world_position: vec3f) -> f32 {
+#ifdef TERRAIN_FOOTPRINTS
+    if settings.footprint_region.w > 0.0 {
+        let uv = (world_position.xz - settings.footprint_region.xy) / settings.footprint_region.z;
+        return textureSample(footprint_texture, footprint_sampler, uv).r;
+    }
+#endif
+    return 0.0;
+}
+
+// How much the forest darkens the ground past the sun shadows, it fades in before they stop
+fn canopy_shadow(world_position: vec3f) -> f32 {
+#ifdef TERRAIN_CANOPY_SHADOW
+    let region = settings.canopy_region;
+    let uv = (world_position.xz - region.xy) / region.z;
+    let occlusion = textureSampleLevel(canopy_texture, canopy_sampler, uv, 0.0).r;
+    let distance = distance(world_position, view.world_position);
+    let fade = smoothstep(settings.canopy_fade_start, settings.canopy_fade_end, distance);
+    return occlusion * region.w * fade;
+#else
+    return 0.0;
+#endif
+}
+
+@fragment
+fn fragment(in: VertexOutput, @builtin(front_facing) is_front: bool) -> FragmentOutput {
+    // Create the PBR input.
+    var pbr_input = pbr_input_from_standard_material(in, is_front);
+    // var pbr_input: PbrInput = pbr_input_new();
+
+    // let up = vec3(0.0, 1.0, 0.0);
+    // let steepness = length(cross(in.world_normal, up));
+    // pbr_input.material.base_color = mix(
+    //     pbr_input.material.base_color,
+    //     vec4(1.0, 0.0, 0.0, 1.0),
+    //     saturate(steepness - settings.max_steepness)
+    // );
+
+// #ifdef USE_PARALLAX
+//     let V = pbr_input.V;
+//     let N = in.world_normal;
+//     let T = in.world_tangent.xyz;
+//     let B = in.world_tangent.w * cross(N, T);
+//     // Transform V from fragment to camera in world space to tangent space.
+//     let Vt = vec3(dot(V, T), dot(V, B), dot(V, N));
+// #else
+//     let Vt = vec3(0.0);
+// #endif
+
+//     let double_sided = false;
+//     pbr_input.frag_coord = in.position;
+//     pbr_input.world_position = in.world_position;
+//     pbr_input.world_normal = pbr_functions::prepare_world_normal(
+//         in.world_normal,
+//         double_sided,
+//         is_front,
+//     );
+
+// // //     // pbr_input.is_orthographic = view.clip_from_view[3].w == 1.0;
+
+//     // pbr_input.N = normalize(pbr_input.world_normal);
+
+//     var blend_axes = abs(in.world_normal);
+//     blend_axes /= blend_axes.x + blend_axes.y + blend_axes.z;
+
+//     let scale = 25.0;
+
+//     var bias: SampleBias;
+//     bias.mip_bias = view.mip_bias;
+
+//     // base_color
+//     let base_color = triplanar_mapping(
+//         in.world_position,
+//         scale,
+//         blend_axes,
+//         bias,
+//         pbr_bindings::base_color_texture,
+//         pbr_bindings::base_color_sampler,
+//         Vt
+//     );
+//     pbr_input.material.base_color = base_color;
+
+//     // metallic_roughness
+//     var metallic: f32 = pbr_bindings::material.metallic;
+//     var perceptual_roughness: f32 = pbr_bindings::material.perceptual_roughness;
+//     let roughness = lighting::perceptualRoughnessToRoughness(perceptual_roughness);
+
+//     let metallic_roughness = triplanar_mapping(
+//         in.world_position,
+//         scale,
+//         blend_axes,
+//         bias,
+//         pbr_bindings::metallic_roughness_texture,
+//         pbr_bindings::base_color_sampler,
+//         Vt
+//     );
+//     metallic *= metallic_roughness.b;
+//     perceptual_roughness *= metallic_roughness.g;
+
+//     pbr_input.material.metallic = metallic;
+//     pbr_input.material.perceptual_roughness = perceptual_roughness;
+
+//     // normal_map
+//     let Nt = triplanar_mapping(
+//         in.world_position,
+//         scale,
+//         blend_axes,
+//         bias,
+//         pbr_bindings::normal_map_texture,
+//         pbr_bindings::normal_map_sampler,
+//         Vt
+//     ).rgb;
+//     let TBN = pbr_functions::calculate_tbn_mikktspace(in.world_normal, in.world_tangent);
+//     pbr_input.N = pbr_functions::apply_normal_mapping(
+//         pbr_bindings::material.flags,
+//         TBN,
+//         double_sided,
+//         is_front,
+//         Nt,
+//         view.mip_bias,
+//     );
+
+#ifdef VERTEX_UVS_A
+    let detail = detail_amount(in.world_position.xyz);
+    let detail_uv = in.uv * settings.detail_tiling;
+#ifdef TERRAIN_DETAIL_ALBEDO
+    // Mid gray leaves the ground color as it is
+    let detail_albedo = textureSample(detail_albedo_texture, detail_albedo_sampler, detail_uv).rgb;
+    pbr_input.material.base_color = vec4(
+        pbr_input.material.base_color.rgb * mix(vec3(1.0), detail_albedo * 2.0, detail),
+        pbr_input.material.base_color.a,
+    );
+#endif
+#ifdef TERRAIN_DETAIL_NORMAL
+#ifdef VERTEX_TANGENTS
+    let detail_nt = textureSample(detail_normal_texture, detail_normal_sampler, detail_uv).rgb * 2.0 - 1.0;
+    let TBN = pbr_functions::calculate_tbn_mikktspace(pbr_input.world_normal, in.world_tangent);
+    let detail_n = normalize(TBN * detail_nt);
+    // Adds how much the detail bends the surface normal on top of the ground normal map
+    pbr_input.N = normalize(pbr_input.N + (detail_n - pbr_input.world_normal) * detail);
+#endif
+#endif
+#endif
+
+    // Snow covers the flat ground above the snow line, fading in over a few meters
+#ifdef VERTEX_COLORS
+    // Baked from the snow expression of the terrain config, the color itself is white
+    let snow_line = in.color.a;
+#else
+#ifdef TERRAIN_SNOW
+    let snow_line = saturate((in.world_position.y - settings.snow_height) / 4.0);
+#else
+    let snow_line = 0.0;
+#endif
+#endif
+    // The footprints press the snow down to the darker ground
+    let footprint = footprint_depth(in.world_position.xyz);
+    let snow = snow_line * smoothstep(0.6, 0.9, pbr_input.N.y) * (1.0 - 0.6 * footprint);
+    pbr_input.material.base_color = vec4(
+        mix(pbr_input.material.base_color.rgb, vec3(0.9, 0.92, 0.95), snow),
+        pbr_input.material.base_color.a,
+    );
+    pbr_input.material.perceptual_roughness = mix(pbr_input.material.perceptual_roughness, 0.9, snow);
+
+#ifdef VERTEX_UVS_B
+    // Needles and bark debris pile up around the tree roots
+    let debris = in.uv_b.y * settings.root_blend_strength;
+    pbr_input.material.base_color = vec4(
+        mix(pbr_input.material.base_color.rgb, vec3(0.22, 0.13, 0.07), debris * 0.6) * (1.0 - 0.4 * debris),
+        pbr_input.material.base_color.a,
+    );
+
+#ifdef TERRAIN_PUDDLES
+    // The puddle mask is baked in the second uv channel
+    let puddle = saturate(in.uv_b.x * settings.puddle_amount * 2.0 - (1.0 - settings.puddle_amount));
+    // Standing water is a dark mirror, the normal map is hidden under it
+    pbr_input.material.base_color = vec4(
+        pbr_input.material.base_color.rgb * mix(1.0, 0.4, puddle),
+        pbr_input.material.base_color.a,
+    );
+    pbr_input.material.perceptual_roughness = mix(pbr_input.material.perceptual_roughness, 0.02, puddle);
+    pbr_input.N = normalize(mix(pbr_input.N, pbr_input.world_normal, puddle));
+#endif
+#endif
+#ifdef TERRAIN_PUDDLES
+    // Trodden wet ground soaks up the water and goes darker
+    pbr_input.material.base_color = vec4(
+        pbr_input.material.base_color.rgb * (1.0 - 0.35 * footprint * settings.puddle_amount),
+        pbr_input.material.base_color.a,
+    );
+#endif
+
+    // Stands in for the shadows of the trees past the reach of the sun cascades
+    let canopy = canopy_shadow(in.world_position.xyz);
+    pbr_input.material.base_color = vec4(
+        pbr_input.material.base_color.rgb * (1.0 - canopy),
+        pbr_input.material.base_color.a,
+    );
+
+    let haze = aerial_perspective(in.world_position.xyz);
+
+#ifdef PREPASS_PIPELINE
+    // The lighting happens later so the haze replaces part of the diffuse color with an emissive
+    // one, the emissive light is scaled by the exposure in the lighting pass
+    pbr_input.material.base_color = vec4(
+        pbr_input.material.base_color.rgb * (1.0 - haze.a),
+        pbr_input.material.base_color.a,
+    );
+    pbr_input.material.emissive = vec4(
+        pbr_input.material.emissive.rgb + haze.rgb * haze.a / view.exposure,
+        pbr_input.material.emissive.a,
+    );
+    // Send the rest to the deferred shader.
+    let out = deferred_output(in, pbr_input);
+#else
+    var out: FragmentOutput;
+    out.color = apply_pbr_lighting(pbr_input);
+    out.color = vec4(mix(out.color.rgb, haze.rgb, haze.a), out.color.a);
+    out.color = main_pass_post_lighting_processing(pbr_input, out.color);
+#endif
+    return out;
+}
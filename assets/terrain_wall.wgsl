@@ -0,0 +1,62 @@
+#import bevy_pbr::pbr_fragment::pbr_input_from_standard_material
+
+#ifdef PREPASS_PIPELINE
+#import bevy_pbr::{
+    prepass_io::{VertexOutput, FragmentOutput},
+    pbr_deferred_functions::deferred_output,
+}
+#else
+#import bevy_pbr::{
+    forward_io::{VertexOutput, FragmentOutput},
+    pbr_functions::{apply_pbr_lighting, main_pass_post_lighting_processing},
+}
+#endif
+
+struct WallSettings {
+    soil_color: vec4f,
+    rock_color: vec4f,
+    soil_depth: f32,
+    layer_thickness: f32,
+}
+@group(2) @binding(100) var<uniform> settings: WallSettings;
+
+fn hash(p: f32) -> f32 {
+    return fract(sin(p * 12.9898) * 43758.5453);
+}
+
+// Soil under the ground, then bands of rock of slightly different shades. The bands wave a little
+// along the wall so they don't look ruled.
+fn strata_color(along: f32, depth: f32) -> vec3f {
+    let wave = sin(along * 0.15) * 0.3 + sin(along * 0.53) * 0.1;
+    let rock_depth = depth - settings.soil_depth + wave * settings.layer_thickness;
+    let band = floor(rock_depth / settings.layer_thickness);
+    let shade = 0.75 + 0.35 * hash(band);
+    let rock = settings.rock_color.rgb * shade;
+    // A darker line at the bottom of every band
+    let seam = smoothstep(0.9, 1.0, fract(rock_depth / settings.layer_thickness));
+    let layered = rock * (1.0 - 0.4 * seam);
+    let soil = smoothstep(-0.2, 0.2, rock_depth);
+    return mix(settings.soil_color.rgb, layered, soil);
+}
+
+@fragment
+fn fragment(in: VertexOutput, @builtin(front_facing) is_front: bool) -> FragmentOutput {
+    var pbr_input = pbr_input_from_standard_material(in, is_front);
+
+#ifdef VERTEX_UVS_A
+    // x: distance along the side, y: depth below the ground, in meters
+    pbr_input.material.base_color = vec4(
+        strata_color(in.uv.x, in.uv.y),
+        pbr_input.material.base_color.a,
+    );
+#endif
+
+#ifdef PREPASS_PIPELINE
+    let out = deferred_output(in, pbr_input);
+#else
+    var out: FragmentOutput;
+    out.color = apply_pbr_lighting(pbr_input);
+    out.color = main_pass_post_lighting_processing(pbr_input, out.color);
+#endif
+    return out;
+}
@@ -0,0 +1,47 @@
+#import bevy_pbr::{
+    pbr_fragment::pbr_input_from_standard_material,
+    pbr_functions::alpha_discard,
+}
+
+#ifdef PREPASS_PIPELINE
+#import bevy_pbr::{
+    prepass_io::{VertexOutput, FragmentOutput},
+    pbr_deferred_functions::deferred_output,
+}
+#else
+#import bevy_pbr::{
+    forward_io::{VertexOutput, FragmentOutput},
+    pbr_functions::{apply_pbr_lighting, main_pass_post_lighting_processing},
+}
+#endif
+
+struct TreeSnowSettings {
+    snow_amount: f32,
+}
+@group(2) @binding(100) var<uniform> settings: TreeSnowSettings;
+
+const SNOW_COLOR: vec3<f32> = vec3(0.9, 0.92, 0.95);
+
+@fragment
+fn fragment(in: VertexOutput, @builtin(front_facing) is_front: bool) -> FragmentOutput {
+    var pbr_input = pbr_input_from_standard_material(in, is_front);
+    pbr_input.material.base_color = alpha_discard(pbr_input.material, pbr_input.material.base_color);
+
+    // Snow settles on the surfaces facing up
+    if settings.snow_amount > 0.0 {
+        let up = smoothstep(0.2, 0.8, pbr_input.N.y);
+        let snow = saturate(settings.snow_amount * up);
+        let base_color = pbr_input.material.base_color;
+        pbr_input.material.base_color = vec4(mix(base_color.rgb, SNOW_COLOR, snow), base_color.a);
+        pbr_input.material.perceptual_roughness = mix(pbr_input.material.perceptual_roughness, 0.9, snow);
+    }
+
+#ifdef PREPASS_PIPELINE
+    let out = deferred_output(in, pbr_input);
+#else
+    var out: FragmentOutput;
+    out.color = apply_pbr_lighting(pbr_input);
+    out.color = main_pass_post_lighting_processing(pbr_input, out.color);
+#endif
+    return out;
+}
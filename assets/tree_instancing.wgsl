@@ -0,0 +1,133 @@
+// Places a tree mesh at every instance of its batch, see the `tree_instancing` module. The
+// forward pass shades it like a tree material without the snow, the shadow pass keeps the prepass
+// fragment shader of the material.
+
+#import bevy_pbr::view_transformations::position_world_to_clip
+
+#ifdef PREPASS_PIPELINE
+#import bevy_pbr::prepass_io::VertexOutput
+#else
+#import bevy_pbr::{
+    forward_io::{VertexOutput, FragmentOutput},
+    mesh_types::MESH_FLAGS_SHADOW_RECEIVER_BIT,
+    pbr_fragment::pbr_input_from_standard_material,
+    pbr_functions::{alpha_discard, apply_pbr_lighting, main_pass_post_lighting_processing},
+}
+#endif
+
+// The locations of the attributes are the ones of bevy's mesh and prepass pipelines
+struct Vertex {
+    @location(0) position: vec3<f32>,
+#ifdef PREPASS_PIPELINE
+#ifdef VERTEX_UVS_A
+    @location(1) uv: vec2<f32>,
+#endif
+#ifdef VERTEX_UVS_B
+    @location(2) uv_b: vec2<f32>,
+#endif
+#ifdef NORMAL_PREPASS_OR_DEFERRED_PREPASS
+    @location(3) normal: vec3<f32>,
+#ifdef VERTEX_TANGENTS
+    @location(4) tangent: vec4<f32>,
+#endif
+#endif
+#ifdef VERTEX_COLORS
+    @location(7) color: vec4<f32>,
+#endif
+#else
+#ifdef VERTEX_NORMALS
+    @location(1) normal: vec3<f32>,
+#endif
+#ifdef VERTEX_UVS_A
+    @location(2) uv: vec2<f32>,
+#endif
+#ifdef VERTEX_UVS_B
+    @location(3) uv_b: vec2<f32>,
+#endif
+#ifdef VERTEX_TANGENTS
+    @location(4) tangent: vec4<f32>,
+#endif
+#ifdef VERTEX_COLORS
+    @location(5) color: vec4<f32>,
+#endif
+#endif
+    // Rows of the affine transform of the instance
+    @location(10) world_from_local_x: vec4<f32>,
+    @location(11) world_from_local_y: vec4<f32>,
+    @location(12) world_from_local_z: vec4<f32>,
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    let world_from_local = transpose(mat4x4<f32>(
+        vertex.world_from_local_x,
+        vertex.world_from_local_y,
+        vertex.world_from_local_z,
+        vec4(0.0, 0.0, 0.0, 1.0),
+    ));
+    // The trees are scaled uniformly, the normals don't need the inverse transpose
+    let world_from_local_3x3 = mat3x3<f32>(
+        world_from_local[0].xyz,
+        world_from_local[1].xyz,
+        world_from_local[2].xyz,
+    );
+
+    var out: VertexOutput;
+    out.world_position = world_from_local * vec4(vertex.position, 1.0);
+    out.position = position_world_to_clip(out.world_position.xyz);
+#ifdef UNCLIPPED_DEPTH_ORTHO_EMULATION
+    out.unclipped_depth = out.position.z;
+    out.position.z = min(out.position.z, 1.0);
+#endif
+
+#ifdef VERTEX_UVS_A
+    out.uv = vertex.uv;
+#endif
+#ifdef VERTEX_UVS_B
+    out.uv_b = vertex.uv_b;
+#endif
+#ifdef VERTEX_COLORS
+    out.color = vertex.color;
+#endif
+
+#ifdef PREPASS_PIPELINE
+#ifdef NORMAL_PREPASS_OR_DEFERRED_PREPASS
+    out.world_normal = normalize(world_from_local_3x3 * vertex.normal);
+#ifdef VERTEX_TANGENTS
+    out.world_tangent = vec4(normalize(world_from_local_3x3 * vertex.tangent.xyz), vertex.tangent.w);
+#endif
+#endif
+#ifdef MOTION_VECTOR_PREPASS
+    // The trees don't move
+    out.previous_world_position = out.world_position;
+#endif
+#else
+#ifdef VERTEX_NORMALS
+    out.world_normal = normalize(world_from_local_3x3 * vertex.normal);
+#endif
+#ifdef VERTEX_TANGENTS
+    out.world_tangent = vec4(normalize(world_from_local_3x3 * vertex.tangent.xyz), vertex.tangent.w);
+#endif
+#endif
+
+#ifdef VERTEX_OUTPUT_INSTANCE_INDEX
+    // The instances aren't meshes, the flags of the mesh are replaced in the fragment shader
+    out.instance_index = 0u;
+#endif
+    return out;
+}
+
+#ifndef PREPASS_PIPELINE
+@fragment
+fn fragment(in: VertexOutput, @builtin(front_facing) is_front: bool) -> FragmentOutput {
+    var pbr_input = pbr_input_from_standard_material(in, is_front);
+    // Read from the mesh at index 0, which isn't this tree
+    pbr_input.flags = MESH_FLAGS_SHADOW_RECEIVER_BIT;
+    pbr_input.material.base_color = alpha_discard(pbr_input.material, pbr_input.material.base_color);
+
+    var out: FragmentOutput;
+    out.color = apply_pbr_lighting(pbr_input);
+    out.color = main_pass_post_lighting_processing(pbr_input, out.color);
+    return out;
+}
+#endif
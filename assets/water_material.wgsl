@@ -1,25 +1,48 @@
 // A shader that creates water ripples by overlaying 4 normal maps on top of one
 // another.
 //
-// This is used in the `ssr` example. It only supports deferred rendering.
+// This is used in the `ssr` example. The same entry point is used by the deferred and the
+// forward renderers.
 
 #import bevy_pbr::{
-    pbr_deferred_functions::deferred_output,
     pbr_fragment::pbr_input_from_standard_material,
-    prepass_io::{VertexOutput, FragmentOutput},
     prepass_utils,
 }
+
+#ifdef PREPASS_PIPELINE
+#import bevy_pbr::{
+    prepass_io::{VertexOutput, FragmentOutput},
+    pbr_deferred_functions::deferred_output,
+}
+#else
+#import bevy_pbr::{
+    forward_io::{VertexOutput, FragmentOutput},
+    pbr_functions::{apply_pbr_lighting, main_pass_post_lighting_processing},
+}
+#endif
 #import bevy_render::globals::Globals
+#import forest_scene::wind::wind_scroll
 
 // Parameters to the water shader.
 struct WaterSettings {
     // How much to displace each octave each frame, in the u and v directions.
-    // Two octaves are packed into each `vec4`.
+    // Two octaves are packed into each `vec4`. The primary octave drifts downwind instead, at
+    // the speed in x.
     octave_vectors: array<vec4<f32>, 2>,
     // How wide the waves are in each octave.
     octave_scales: vec4<f32>,
     // How high the waves are in each octave.
     octave_strengths: vec4<f32>,
+    // The region covered by the ripple texture.
+    // xy is the world space origin, z the extent and w the strength of the ripples.
+    ripple_region: vec4<f32>,
+    // Color of the suspended particles in deep water
+    murk_color: vec4<f32>,
+    // x: murk density, y: clarity depth, z: depth texture origin x, w: depth texture origin z
+    murk_params: vec4<f32>,
+    // x: depth and shelter texture extent, y: max depth encoded in the texture,
+    // z: shelter strength
+    depth_region: vec4<f32>,
 }
 
 @group(0) @binding(1) var<uniform> globals: Globals;
@@ -27,6 +50,12 @@ struct WaterSettings {
 @group(2) @binding(100) var water_normals_texture: texture_2d<f32>;
 @group(2) @binding(101) var water_normals_sampler: sampler;
 @group(2) @binding(102) var<uniform> water_settings: WaterSettings;
+@group(2) @binding(103) var ripples_texture: texture_2d<f32>;
+@group(2) @binding(104) var ripples_sampler: sampler;
+@group(2) @binding(105) var depth_texture: texture_2d<f32>;
+@group(2) @binding(106) var depth_sampler: sampler;
+@group(2) @binding(107) var shelter_texture: texture_2d<f32>;
+@group(2) @binding(108) var shelter_sampler: sampler;
 
 // Samples a single octave of noise and returns the resulting normal.
 fn sample_noise_octave(uv: vec2<f32>, strength: f32) -> vec3<f32> {
@@ -35,29 +64,100 @@ fn sample_noise_octave(uv: vec2<f32>, strength: f32) -> vec3<f32> {
     return normalize(mix(vec3(0.0, 1.0, 0.0), N, strength));
 }
 
-// Samples all four octaves of noise and returns the resulting normal.
-fn sample_noise(uv: vec2<f32>, time: f32) -> vec3<f32> {
-    let uv0 = uv * water_settings.octave_scales[0] + water_settings.octave_vectors[0].xy * time;
+// Samples all four octaves of noise and returns the resulting normal, `calm` flattens them.
+fn sample_noise(uv: vec2<f32>, time: f32, calm: f32) -> vec3<f32> {
+    let drift = wind_scroll(time, water_settings.octave_vectors[0].x);
+    let uv0 = uv * water_settings.octave_scales[0] + drift;
     let uv1 = uv * water_settings.octave_scales[1] + water_settings.octave_vectors[0].zw * time;
     let uv2 = uv * water_settings.octave_scales[2] + water_settings.octave_vectors[1].xy * time;
     let uv3 = uv * water_settings.octave_scales[3] + water_settings.octave_vectors[1].zw * time;
+    let strengths = water_settings.octave_strengths * (1.0 - calm);
     return normalize(
-        sample_noise_octave(uv0, water_settings.octave_strengths[0]) +
-        sample_noise_octave(uv1, water_settings.octave_strengths[1]) +
-        sample_noise_octave(uv2, water_settings.octave_strengths[2]) +
-        sample_noise_octave(uv3, water_settings.octave_strengths[3])
+        sample_noise_octave(uv0, strengths[0]) +
+        sample_noise_octave(uv1, strengths[1]) +
+        sample_noise_octave(uv2, strengths[2]) +
+        sample_noise_octave(uv3, strengths[3])
     );
 }
 
+// Samples the slopes of the ripple simulation around the camera.
+fn sample_ripples(world_xz: vec2<f32>) -> vec2<f32> {
+    let region = water_settings.ripple_region;
+    if region.w == 0.0 {
+        return vec2(0.0);
+    }
+    let uv = (world_xz - region.xy) / region.z;
+    // Fade out near the edges so the region scrolling with the camera doesn't show a seam
+    let edge = min(min(uv.x, 1.0 - uv.x), min(uv.y, 1.0 - uv.y));
+    let fade = saturate(edge * 10.0);
+    if fade <= 0.0 {
+        return vec2(0.0);
+    }
+    return textureSampleLevel(ripples_texture, ripples_sampler, uv, 0.0).rg * fade * region.w;
+}
+
+// How much of the murk color is scattered back towards the viewer.
+fn murk_amount(world_xz: vec2<f32>, V: vec3<f32>) -> f32 {
+    let density = water_settings.murk_params.x;
+    if density <= 0.0 {
+        return 0.0;
+    }
+    let uv = (world_xz - water_settings.murk_params.zw) / water_settings.depth_region.x;
+    let depth = textureSampleLevel(depth_texture, depth_sampler, uv, 0.0).r * water_settings.depth_region.y;
+    let murky_depth = max(depth - water_settings.murk_params.y, 0.0);
+    // Looking at a grazing angle goes through more water
+    let view_distance = murky_depth / max(V.y, 0.1);
+    return 1.0 - exp(-density * view_distance);
+}
+
+// How sheltered from the wind the water is by the terrain upwind, 0 out in the open.
+fn shelter_amount(world_xz: vec2<f32>) -> f32 {
+    let strength = water_settings.depth_region.z;
+    if strength <= 0.0 {
+        return 0.0;
+    }
+    let uv = (world_xz - water_settings.murk_params.zw) / water_settings.depth_region.x;
+    return textureSampleLevel(shelter_texture, shelter_sampler, uv, 0.0).r * strength;
+}
+
 @fragment
 fn fragment(in: VertexOutput, @builtin(front_facing) is_front: bool) -> FragmentOutput {
     // Create the PBR input.
     var pbr_input = pbr_input_from_standard_material(in, is_front);
-    // Bump the normal.
-    pbr_input.N = sample_noise(in.uv, globals.time * 0.15);
+    // Bump the normal, the waves die down in the lee of the terrain.
+    let shelter = shelter_amount(in.world_position.xz);
+    pbr_input.N = sample_noise(in.uv, globals.time * 0.15, shelter);
+    // Add the ripples created by anything moving through the water.
+    let ripples = sample_ripples(in.world_position.xz);
+    pbr_input.N = normalize(pbr_input.N + vec3(-ripples.x, 0.0, -ripples.y));
+
+    // Layer the murky volume under the clear surface, the roughness is left alone so the
+    // reflections stay sharp.
+    let murk = murk_amount(in.world_position.xz, pbr_input.V);
+    pbr_input.material.base_color = mix(
+        pbr_input.material.base_color,
+        vec4(water_settings.murk_color.rgb, pbr_input.material.base_color.a),
+        murk,
+    );
+    // The sheltered water is a bit darker and duller than the wind-swept surface.
+    pbr_input.material.base_color = vec4(
+        pbr_input.material.base_color.rgb * (1.0 - 0.3 * shelter),
+        pbr_input.material.base_color.a,
+    );
+    pbr_input.material.perceptual_roughness = min(
+        pbr_input.material.perceptual_roughness + 0.05 * shelter,
+        1.0,
+    );
 
     // let depth = bevy_pbr::prepass_utils::prepass_depth(in.position, 0u);
 
+#ifdef PREPASS_PIPELINE
     // Send the rest to the deferred shader.
-    return deferred_output(in, pbr_input);
+    let out = deferred_output(in, pbr_input);
+#else
+    var out: FragmentOutput;
+    out.color = apply_pbr_lighting(pbr_input);
+    out.color = main_pass_post_lighting_processing(pbr_input, out.color);
+#endif
+    return out;
 }
@@ -0,0 +1,37 @@
+// The wind shared by every material that moves with it, see the `wind` module.
+//
+// A material opts in with a `#[uniform(120)] wind: WindUniform` field and `add_wind_material`,
+// its shader imports the helpers it needs from here.
+#define_import_path forest_scene::wind
+
+struct Wind {
+    // xy: direction the wind blows toward on the ground plane, z: strength, w: gusts per second
+    params: vec4<f32>,
+}
+
+@group(2) @binding(120) var<uniform> wind: Wind;
+
+fn wind_direction() -> vec2<f32> {
+    return wind.params.xy;
+}
+
+// Gusts going back and forth between -1.3 and 1.3 times the strength. The phase comes from the
+// position so neighbours don't move in lockstep, and rolls downwind so a gust crosses the scene.
+fn wind_gust(world_xz: vec2<f32>, time: f32) -> f32 {
+    let phase = dot(world_xz, vec2(0.37, 0.61)) - dot(world_xz, wind.params.xy) * 0.05;
+    let t = time * wind.params.w;
+    let gust = sin(t * 6.2831 + phase) + 0.3 * sin(t * 15.7 + phase * 2.0);
+    return gust * wind.params.z;
+}
+
+// Horizontal displacement of a point `height` meters above its anchor, the bend grows
+// quadratically toward the tip and `flexibility` is how far the tip of a 1m stem moves
+fn wind_sway(anchor: vec3<f32>, height: f32, flexibility: f32, time: f32) -> vec3<f32> {
+    let bend = height * height * flexibility * wind_gust(anchor.xz, time);
+    return vec3(wind.params.x * bend, 0.0, wind.params.y * bend);
+}
+
+// How far something drifting downwind at `speed` has moved after `time`
+fn wind_scroll(time: f32, speed: f32) -> vec2<f32> {
+    return wind.params.xy * wind.params.z * speed * time;
+}
@@ -0,0 +1,122 @@
+//! Reduced motion, reduced flashing and high contrast overlays.
+//!
+//! The `accessibility` section of the scene config is the only switch for these, the systems
+//! that move the camera on their own or flash something read it every time they start instead of
+//! keeping their own toggle, so a change applies right away.
+//!
+//! - `reduce_flashing` caps how strong and how fast anything flashes, see [`Flash`].
+//! - `reduce_motion` stops the camera from gliding after the keys are released, halves the motion
+//!   blur, slows the flythrough down and makes Home jump to the start instead of flying there.
+//! - `high_contrast_ui` puts the overlays on an opaque background.
+//!
+//! Everything is off by default.
+
+use bevy::prelude::*;
+
+use crate::{camera_controller::CameraController, overlay::OverlayNodes, SceneConfig};
+
+/// Strongest flash with `reduce_flashing`, as a fraction of the full intensity
+const MAX_FLASH_AMPLITUDE: f32 = 0.1;
+/// Fastest flash with `reduce_flashing`, the photosensitivity guidelines start at 3 Hz
+const MAX_FLASH_HZ: f32 = 0.5;
+const HIGH_CONTRAST_BACKGROUND: Color = Color::srgba(0.0, 0.0, 0.0, 0.95);
+
+#[derive(Reflect, Clone, Debug, Default)]
+pub struct AccessibilityConfig {
+    /// Cap the intensity and the rate of the flashing effects
+    pub reduce_flashing: bool,
+    /// No camera glide, half the motion blur and a slower flythrough
+    pub reduce_motion: bool,
+    /// Opaque backgrounds behind the overlays
+    pub high_contrast_ui: bool,
+}
+
+/// A periodic flash, its amplitude is a fraction of the full intensity
+#[derive(Clone, Copy, Debug)]
+pub struct Flash {
+    pub amplitude: f32,
+    pub hz: f32,
+}
+
+impl Flash {
+    /// Intensity of the flash `elapsed` seconds after it started, between `1 - 2 * amplitude`
+    /// and 1
+    pub fn intensity(self, elapsed: f32) -> f32 {
+        1.0 - self.amplitude + self.amplitude * (elapsed * std::f32::consts::TAU * self.hz).cos()
+    }
+}
+
+impl AccessibilityConfig {
+    /// The flash as it should be shown, every flashing effect goes through this
+    pub fn flash(&self, flash: Flash) -> Flash {
+        if !self.reduce_flashing {
+            return flash;
+        }
+        Flash {
+            amplitude: flash.amplitude.min(MAX_FLASH_AMPLITUDE),
+            hz: flash.hz.min(MAX_FLASH_HZ),
+        }
+    }
+
+    pub fn motion_blur_shutter_angle(&self, shutter_angle: f32) -> f32 {
+        if self.reduce_motion {
+            shutter_angle * 0.5
+        } else {
+            shutter_angle
+        }
+    }
+
+    /// Friction of the camera controller, all of it stops the camera as soon as the keys are
+    /// released
+    pub fn camera_friction(&self) -> f32 {
+        if self.reduce_motion {
+            1.0
+        } else {
+            CameraController::default().friction
+        }
+    }
+
+    /// Frames taken by a flythrough that takes `frames` normally
+    pub fn flythrough_frames(&self, frames: u32) -> u32 {
+        if self.reduce_motion {
+            frames * 2
+        } else {
+            frames
+        }
+    }
+}
+
+/// What an overlay node had before the high contrast background
+#[derive(Component)]
+pub struct NormalBackground(Color);
+
+pub fn apply_ui_contrast(
+    mut commands: Commands,
+    scene_config: Res<SceneConfig>,
+    mut nodes: Query<(Entity, &mut BackgroundColor, Option<&NormalBackground>), OverlayNodes>,
+) {
+    let spawned = nodes.iter().any(|(_, _, normal)| normal.is_none());
+    if !scene_config.is_changed() && !spawned {
+        return;
+    }
+    let high_contrast = scene_config.accessibility.high_contrast_ui;
+    for (entity, mut background, normal) in &mut nodes {
+        let normal = match normal {
+            Some(normal) => normal.0,
+            None => {
+                commands
+                    .entity(entity)
+                    .insert(NormalBackground(background.0));
+                background.0
+            }
+        };
+        let color = if high_contrast {
+            HIGH_CONTRAST_BACKGROUND
+        } else {
+            normal
+        };
+        if background.0 != color {
+            background.0 = color;
+        }
+    }
+}
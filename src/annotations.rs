@@ -0,0 +1,577 @@
+//! Text labels pinned to the terrain.
+//!
+//! J opens a small input box for a new annotation at the terrain under the crosshair, Enter places
+//! it and Escape cancels. While the box is open every key goes to the text, none of the other
+//! shortcuts or the camera controls see them. An annotation is drawn as a label at the top of a
+//! stem rising from the ground, the labels are UI nodes so they always face the camera, and they
+//! fade out with the distance so a crowded area stays readable.
+//!
+//! Shift+J lists the annotations of the current world, clicking one moves the camera to it and the
+//! buttons next to it edit its text or delete it. The annotations are saved to
+//! [`ANNOTATIONS_PATH`] by seed, only the ones of the current seed are shown and the others are
+//! counted in the list and the stats overlay.
+
+use std::collections::BTreeMap;
+
+use bevy::{
+    input::{
+        keyboard::{Key, KeyboardInput},
+        ButtonState, InputSystem,
+    },
+    prelude::*,
+    tasks::IoTaskPool,
+    window::PrimaryWindow,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    camera_controller::CameraController, heightfield::TerrainHeightfield, overlay::StatsOverlay,
+    split_view::SecondaryCamera, terrain::TerrainConfig,
+};
+
+pub const ANNOTATIONS_PATH: &str = "assets/annotations.ron";
+
+const MAX_TEXT_LENGTH: usize = 64;
+const STEM_HEIGHT: f32 = 4.0;
+const STEM_COLOR: Color = Color::srgb(1.0, 1.0, 1.0);
+const FOOT_RADIUS: f32 = 0.15;
+/// Distances between which the labels fade out
+const FADE_START: f32 = 80.0;
+const FADE_END: f32 = 300.0;
+/// Where the camera ends up when jumping to an annotation, from the top of its stem
+const JUMP_DISTANCE: f32 = 15.0;
+const JUMP_HEIGHT: f32 = 5.0;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Annotation {
+    pub x: f32,
+    pub z: f32,
+    pub text: String,
+}
+
+impl Annotation {
+    /// Foot of the stem, on the terrain as it is now
+    fn ground(&self, heightfield: &TerrainHeightfield) -> Option<Vec3> {
+        let height = heightfield.height_at(vec2(self.x, self.z))?;
+        Some(Vec3::new(self.x, height, self.z))
+    }
+}
+
+/// Every annotation, by the seed of the world they were placed in
+#[derive(Resource, Default)]
+pub struct Annotations {
+    by_seed: BTreeMap<u32, Vec<Annotation>>,
+}
+
+impl Annotations {
+    pub fn of_seed(&self, seed: u32) -> &[Annotation] {
+        self.by_seed.get(&seed).map_or(&[], Vec::as_slice)
+    }
+
+    /// Count of the annotations of the other seeds
+    fn hidden(&self, seed: u32) -> usize {
+        self.by_seed
+            .iter()
+            .filter(|(s, _)| **s != seed)
+            .map(|(_, annotations)| annotations.len())
+            .sum()
+    }
+
+    pub fn load() -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            if let Ok(file) = std::fs::read_to_string(ANNOTATIONS_PATH) {
+                match ron::from_str(&file) {
+                    Ok(by_seed) => return Self { by_seed },
+                    Err(err) => warn!("failed to read {ANNOTATIONS_PATH}: {err}"),
+                }
+            }
+        }
+        Self::default()
+    }
+
+    fn save(&self) {
+        let Ok(serialized) = ron::ser::to_string_pretty(&self.by_seed, default()) else {
+            error!("failed to serialize the annotations");
+            return;
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        IoTaskPool::get()
+            .spawn(async move {
+                if let Err(err) = std::fs::write(ANNOTATIONS_PATH, serialized) {
+                    error!("failed to write {ANNOTATIONS_PATH}: {err}");
+                }
+            })
+            .detach();
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum TypingTarget {
+    New {
+        x: f32,
+        z: f32,
+    },
+    /// Index in the annotations of the seed
+    Existing(usize),
+}
+
+/// The text of an annotation being typed
+struct Typing {
+    seed: u32,
+    target: TypingTarget,
+    text: String,
+}
+
+#[derive(Resource, Default)]
+struct AnnotationEditor {
+    panel_open: bool,
+    typing: Option<Typing>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AnnotationAction {
+    GoTo,
+    Edit,
+    Delete,
+}
+
+#[derive(Component)]
+struct AnnotationButton {
+    index: usize,
+    action: AnnotationAction,
+}
+
+#[derive(Component)]
+struct AnnotationLabel {
+    index: usize,
+}
+
+#[derive(Component)]
+struct AnnotationInputBox;
+
+#[derive(Component)]
+struct AnnotationPanel;
+
+#[derive(Component)]
+struct AnnotationPanelHeader;
+
+#[derive(Component)]
+struct AnnotationList;
+
+pub struct AnnotationsPlugin;
+
+impl Plugin for AnnotationsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Annotations::load())
+            .init_resource::<AnnotationEditor>()
+            .add_systems(Startup, spawn_annotation_ui)
+            .add_systems(PreUpdate, type_annotation.after(InputSystem))
+            .add_systems(
+                Update,
+                (
+                    annotation_input.run_if(resource_exists::<TerrainHeightfield>),
+                    click_annotation_buttons.run_if(resource_exists::<TerrainHeightfield>),
+                    update_annotation_panel,
+                    draw_annotations.run_if(resource_exists::<TerrainHeightfield>),
+                )
+                    .chain()
+                    .run_if(resource_exists::<TerrainConfig>),
+            );
+    }
+}
+
+fn text_style(font_size: f32) -> TextStyle {
+    TextStyle {
+        font_size,
+        ..default()
+    }
+}
+
+fn spawn_annotation_ui(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::from_section("", text_style(18.0))
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                bottom: Val::Px(40.0),
+                left: Val::Percent(35.0),
+                width: Val::Percent(30.0),
+                padding: UiRect::all(Val::Px(6.0)),
+                ..default()
+            })
+            .with_background_color(Color::srgba(0.0, 0.0, 0.0, 0.8)),
+        Visibility::Hidden,
+        AnnotationInputBox,
+    ));
+
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    bottom: Val::Px(5.0),
+                    left: Val::Px(5.0),
+                    flex_direction: FlexDirection::Column,
+                    row_gap: Val::Px(2.0),
+                    padding: UiRect::all(Val::Px(4.0)),
+                    ..default()
+                },
+                background_color: Color::srgba(0.0, 0.0, 0.0, 0.6).into(),
+                visibility: Visibility::Hidden,
+                ..default()
+            },
+            AnnotationPanel,
+        ))
+        .with_children(|panel| {
+            panel.spawn((
+                TextBundle::from_section("", text_style(16.0)),
+                AnnotationPanelHeader,
+            ));
+            panel.spawn((
+                NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Column,
+                        row_gap: Val::Px(2.0),
+                        ..default()
+                    },
+                    ..default()
+                },
+                AnnotationList,
+            ));
+        });
+}
+
+/// Feeds the keys to the annotation being typed, and keeps them from the rest of the app. Runs
+/// right after the input is read so no other system sees the keys pressed while typing.
+fn type_annotation(
+    mut events: EventReader<KeyboardInput>,
+    mut keyboard: ResMut<ButtonInput<KeyCode>>,
+    mut editor: ResMut<AnnotationEditor>,
+    mut annotations: ResMut<Annotations>,
+    mut input_box: Query<(&mut Text, &mut Visibility), With<AnnotationInputBox>>,
+) {
+    // Read even while not typing, the key opening the box isn't part of the text
+    let events: Vec<_> = events
+        .read()
+        .filter(|event| event.state == ButtonState::Pressed)
+        .map(|event| event.logical_key.clone())
+        .collect();
+    if editor.typing.is_none() {
+        return;
+    }
+
+    let mut done = None;
+    if let Some(typing) = &mut editor.bypass_change_detection().typing {
+        for key in events {
+            match key {
+                Key::Character(characters) => {
+                    for character in characters.chars().filter(|c| !c.is_control()) {
+                        if typing.text.chars().count() < MAX_TEXT_LENGTH {
+                            typing.text.push(character);
+                        }
+                    }
+                }
+                Key::Space if typing.text.chars().count() < MAX_TEXT_LENGTH => {
+                    typing.text.push(' ');
+                }
+                Key::Backspace => {
+                    typing.text.pop();
+                }
+                Key::Enter => done = Some(true),
+                Key::Escape => done = Some(false),
+                _ => {}
+            }
+            if done.is_some() {
+                break;
+            }
+        }
+    }
+    keyboard.reset_all();
+
+    let Some(confirmed) = done else {
+        let typing = editor.typing.as_ref().unwrap();
+        for (mut text, mut visibility) in &mut input_box {
+            let prompt = match typing.target {
+                TypingTarget::New { .. } => "New annotation",
+                TypingTarget::Existing(_) => "Edit annotation",
+            };
+            text.sections[0].value =
+                format!("{prompt}: {}_\nEnter saves, Escape cancels", typing.text);
+            *visibility = Visibility::Inherited;
+        }
+        return;
+    };
+
+    let typing = editor.typing.take().unwrap();
+    for (_, mut visibility) in &mut input_box {
+        *visibility = Visibility::Hidden;
+    }
+    let text = typing.text.trim().to_string();
+    if !confirmed || text.is_empty() {
+        return;
+    }
+    let of_seed = annotations.by_seed.entry(typing.seed).or_default();
+    match typing.target {
+        TypingTarget::New { x, z } => {
+            info!("annotation \"{text}\" placed at {x:.1}, {z:.1}");
+            of_seed.push(Annotation { x, z, text });
+        }
+        TypingTarget::Existing(index) => {
+            let Some(annotation) = of_seed.get_mut(index) else {
+                return;
+            };
+            annotation.text = text;
+        }
+    }
+    annotations.save();
+}
+
+/// J starts typing an annotation at the terrain under the crosshair, Shift+J shows the list
+fn annotation_input(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    terrain_config: Res<TerrainConfig>,
+    heightfield: Res<TerrainHeightfield>,
+    mut editor: ResMut<AnnotationEditor>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform), (With<Camera3d>, Without<SecondaryCamera>)>,
+    mut panel: Query<&mut Visibility, With<AnnotationPanel>>,
+) {
+    if !keyboard.just_pressed(KeyCode::KeyJ) {
+        return;
+    }
+    if keyboard.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+        editor.panel_open = !editor.panel_open;
+        for mut visibility in &mut panel {
+            *visibility = if editor.panel_open {
+                Visibility::Inherited
+            } else {
+                Visibility::Hidden
+            };
+        }
+        return;
+    }
+
+    let (Ok(window), Ok((camera, camera_transform))) = (window.get_single(), camera.get_single())
+    else {
+        return;
+    };
+    // The center of the screen while the camera holds the cursor
+    let cursor = window.cursor_position().unwrap_or(window.size() * 0.5);
+    let Some(hit) = camera
+        .viewport_to_world(camera_transform, cursor)
+        .and_then(|ray| heightfield.raycast(ray, 2000.0))
+    else {
+        info!("not over the terrain, no annotation placed");
+        return;
+    };
+    editor.typing = Some(Typing {
+        seed: terrain_config.seed,
+        target: TypingTarget::New { x: hit.x, z: hit.z },
+        text: String::new(),
+    });
+}
+
+/// Moves the camera to an annotation, edits or deletes it from the list
+#[allow(clippy::type_complexity)]
+fn click_annotation_buttons(
+    terrain_config: Res<TerrainConfig>,
+    heightfield: Res<TerrainHeightfield>,
+    mut editor: ResMut<AnnotationEditor>,
+    mut annotations: ResMut<Annotations>,
+    buttons: Query<(&AnnotationButton, &Interaction), Changed<Interaction>>,
+    mut camera: Query<
+        (&mut Transform, &mut CameraController),
+        (With<Camera3d>, Without<SecondaryCamera>),
+    >,
+) {
+    if !editor.panel_open || editor.typing.is_some() {
+        return;
+    }
+    let Some((button, _)) = buttons
+        .iter()
+        .find(|(_, interaction)| **interaction == Interaction::Pressed)
+    else {
+        return;
+    };
+    let seed = terrain_config.seed;
+    let Some(annotation) = annotations.of_seed(seed).get(button.index) else {
+        return;
+    };
+
+    match button.action {
+        AnnotationAction::GoTo => {
+            let (Some(ground), Ok((mut transform, mut controller))) =
+                (annotation.ground(&heightfield), camera.get_single_mut())
+            else {
+                return;
+            };
+            let target = ground + Vec3::Y * STEM_HEIGHT;
+            // Keeps looking the same way, from the side of the annotation the camera is on
+            let back = (transform.translation - target)
+                .with_y(0.0)
+                .try_normalize()
+                .unwrap_or(Vec3::Z);
+            let mut position = target + back * JUMP_DISTANCE + Vec3::Y * JUMP_HEIGHT;
+            if let Some(height) = heightfield.height_at(position.xz()) {
+                position.y = position.y.max(height + JUMP_HEIGHT);
+            }
+            *transform = Transform::from_translation(position).looking_at(target, Vec3::Y);
+            // Picks up the yaw and pitch of the new transform
+            controller.initialized = false;
+        }
+        AnnotationAction::Edit => {
+            editor.typing = Some(Typing {
+                seed,
+                target: TypingTarget::Existing(button.index),
+                text: annotation.text.clone(),
+            });
+        }
+        AnnotationAction::Delete => {
+            info!("annotation \"{}\" deleted", annotation.text);
+            let of_seed = annotations.by_seed.get_mut(&seed).unwrap();
+            of_seed.remove(button.index);
+            if of_seed.is_empty() {
+                annotations.by_seed.remove(&seed);
+            }
+            annotations.save();
+        }
+    }
+}
+
+/// Rebuilds the list and the labels once the annotations or the seed changed
+fn update_annotation_panel(
+    mut commands: Commands,
+    terrain_config: Res<TerrainConfig>,
+    annotations: Res<Annotations>,
+    labels: Query<Entity, With<AnnotationLabel>>,
+    list: Query<Entity, With<AnnotationList>>,
+    mut header: Query<&mut Text, With<AnnotationPanelHeader>>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    if !annotations.is_changed() && !terrain_config.is_changed() {
+        return;
+    }
+    let seed = terrain_config.seed;
+    let of_seed = annotations.of_seed(seed);
+    let hidden = annotations.hidden(seed);
+
+    let mut summary = format!("{} on seed {seed}", of_seed.len());
+    if hidden > 0 {
+        summary += &format!(", {hidden} of other seeds hidden");
+    }
+    if of_seed.is_empty() && hidden == 0 {
+        stats.remove("Annotations");
+    } else {
+        stats.set("Annotations", format!("{summary}, Shift+J lists them"));
+    }
+    for mut text in &mut header {
+        text.sections[0].value = format!("Annotations: {summary}\nJ adds one under the crosshair");
+    }
+
+    for label in &labels {
+        commands.entity(label).despawn_recursive();
+    }
+    for (index, annotation) in of_seed.iter().enumerate() {
+        commands.spawn((
+            TextBundle::from_section(annotation.text.clone(), text_style(16.0))
+                .with_style(Style {
+                    position_type: PositionType::Absolute,
+                    padding: UiRect::horizontal(Val::Px(4.0)),
+                    ..default()
+                })
+                .with_background_color(Color::srgba(0.0, 0.0, 0.0, 0.6)),
+            Visibility::Hidden,
+            AnnotationLabel { index },
+        ));
+    }
+
+    let Ok(list) = list.get_single() else {
+        return;
+    };
+    commands.entity(list).despawn_descendants();
+    commands.entity(list).with_children(|list| {
+        for (index, annotation) in of_seed.iter().enumerate() {
+            list.spawn(NodeBundle {
+                style: Style {
+                    column_gap: Val::Px(4.0),
+                    ..default()
+                },
+                ..default()
+            })
+            .with_children(|row| {
+                for (action, text) in [
+                    (AnnotationAction::GoTo, annotation.text.as_str()),
+                    (AnnotationAction::Edit, "edit"),
+                    (AnnotationAction::Delete, "delete"),
+                ] {
+                    row.spawn((
+                        ButtonBundle {
+                            style: Style {
+                                padding: UiRect::horizontal(Val::Px(4.0)),
+                                ..default()
+                            },
+                            background_color: Color::srgba(1.0, 1.0, 1.0, 0.15).into(),
+                            ..default()
+                        },
+                        AnnotationButton { index, action },
+                    ))
+                    .with_children(|button| {
+                        button.spawn(TextBundle::from_section(text, text_style(16.0)));
+                    });
+                }
+            });
+        }
+    });
+}
+
+/// Draws the stems and places their labels, fading with the distance to the camera
+#[allow(clippy::type_complexity)]
+fn draw_annotations(
+    terrain_config: Res<TerrainConfig>,
+    heightfield: Res<TerrainHeightfield>,
+    annotations: Res<Annotations>,
+    camera: Query<(&Camera, &GlobalTransform), (With<Camera3d>, Without<SecondaryCamera>)>,
+    mut labels: Query<(
+        &AnnotationLabel,
+        &Node,
+        &mut Text,
+        &mut Style,
+        &mut BackgroundColor,
+        &mut Visibility,
+    )>,
+    mut gizmos: Gizmos,
+) {
+    let Ok((camera, camera_transform)) = camera.get_single() else {
+        return;
+    };
+    let of_seed = annotations.of_seed(terrain_config.seed);
+    for (label, node, mut text, mut style, mut background, mut visibility) in &mut labels {
+        let Some(ground) = of_seed
+            .get(label.index)
+            .and_then(|annotation| annotation.ground(&heightfield))
+        else {
+            *visibility = Visibility::Hidden;
+            continue;
+        };
+        let top = ground + Vec3::Y * STEM_HEIGHT;
+        let distance = camera_transform.translation().distance(top);
+        let alpha = 1.0 - ((distance - FADE_START) / (FADE_END - FADE_START)).clamp(0.0, 1.0);
+        // Hidden while the top of the stem is behind the camera
+        let position = camera.world_to_viewport(camera_transform, top);
+        let Some(position) = position.filter(|_| alpha > 0.0) else {
+            *visibility = Visibility::Hidden;
+            continue;
+        };
+
+        let color = STEM_COLOR.with_alpha(alpha);
+        gizmos.line(ground, top, color);
+        gizmos.sphere(ground, Quat::IDENTITY, FOOT_RADIUS, color);
+        *visibility = Visibility::Inherited;
+        // Centered above the stem
+        let size = node.size();
+        style.left = Val::Px(position.x - size.x * 0.5);
+        style.top = Val::Px(position.y - size.y);
+        text.sections[0].style.color = Color::WHITE.with_alpha(alpha);
+        background.0 = Color::srgba(0.0, 0.0, 0.0, 0.6 * alpha);
+    }
+}
@@ -0,0 +1,138 @@
+use bevy::{
+    core_pipeline::{
+        experimental::taa::{TemporalAntiAliasBundle, TemporalAntiAliasSettings},
+        fxaa::{Fxaa, Sensitivity},
+        prepass::DeferredPrepass,
+    },
+    prelude::*,
+    render::camera::{MipBias, TemporalJitter},
+};
+
+use crate::{overlay::StatsOverlay, quality::Quality, split_view::SecondaryCamera, SceneConfig};
+
+#[derive(Reflect, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FxaaQuality {
+    Low,
+    Medium,
+    #[default]
+    High,
+    Ultra,
+    Extreme,
+}
+
+impl From<FxaaQuality> for Sensitivity {
+    fn from(quality: FxaaQuality) -> Self {
+        match quality {
+            FxaaQuality::Low => Sensitivity::Low,
+            FxaaQuality::Medium => Sensitivity::Medium,
+            FxaaQuality::High => Sensitivity::High,
+            FxaaQuality::Ultra => Sensitivity::Ultra,
+            FxaaQuality::Extreme => Sensitivity::Extreme,
+        }
+    }
+}
+
+#[derive(Reflect, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AntiAliasing {
+    Off,
+    Fxaa {
+        quality: FxaaQuality,
+    },
+    #[default]
+    Taa,
+    Msaa {
+        samples: u32,
+    },
+}
+
+impl AntiAliasing {
+    /// MSAA can't be used with the deferred renderer and TAA needs MSAA to be off.
+    /// Any invalid combination is downgraded to something that works.
+    fn resolve(self, deferred: bool) -> Self {
+        match self {
+            AntiAliasing::Msaa { .. } if deferred => {
+                warn!("MSAA is not supported with the deferred renderer, falling back to FXAA");
+                AntiAliasing::Fxaa {
+                    quality: FxaaQuality::default(),
+                }
+            }
+            AntiAliasing::Msaa { samples } if !matches!(samples, 2 | 4 | 8) => {
+                warn!("{samples} MSAA samples is not supported, using 4 samples instead");
+                AntiAliasing::Msaa { samples: 4 }
+            }
+            aa => aa,
+        }
+    }
+
+    fn msaa(self) -> Msaa {
+        match self {
+            AntiAliasing::Msaa { samples: 2 } => Msaa::Sample2,
+            AntiAliasing::Msaa { samples: 8 } => Msaa::Sample8,
+            AntiAliasing::Msaa { .. } => Msaa::Sample4,
+            _ => Msaa::Off,
+        }
+    }
+}
+
+impl std::fmt::Display for AntiAliasing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AntiAliasing::Off => write!(f, "Off"),
+            AntiAliasing::Fxaa { quality } => write!(f, "FXAA ({quality:?})"),
+            AntiAliasing::Taa => write!(f, "TAA"),
+            AntiAliasing::Msaa { samples } => write!(f, "MSAA x{samples}"),
+        }
+    }
+}
+
+/// Applies the configured anti aliasing to every 3d camera
+pub fn apply_anti_aliasing(
+    mut commands: Commands,
+    scene_config: Res<SceneConfig>,
+    quality: Res<Quality>,
+    mut msaa: ResMut<Msaa>,
+    cameras: Query<(Entity, Has<DeferredPrepass>), (With<Camera3d>, Without<SecondaryCamera>)>,
+    mut stats: ResMut<StatsOverlay>,
+    mut applied: Local<Option<AntiAliasing>>,
+) {
+    if *applied == Some(scene_config.anti_aliasing) {
+        return;
+    }
+    *applied = Some(scene_config.anti_aliasing);
+
+    let deferred = cameras.iter().any(|(_, deferred)| deferred);
+    let anti_aliasing = quality
+        .anti_aliasing(scene_config.anti_aliasing)
+        .resolve(deferred);
+
+    // Msaa is global so it needs to be set before touching the cameras
+    *msaa = anti_aliasing.msaa();
+
+    for (entity, _) in &cameras {
+        let mut camera = commands.entity(entity);
+        // Always start from a clean camera so switching modes never leaves stale components
+        // around. The depth and motion vector prepasses are left alone because other effects
+        // need them.
+        camera.remove::<(TemporalAntiAliasSettings, TemporalJitter, MipBias, Fxaa)>();
+        match anti_aliasing {
+            AntiAliasing::Off | AntiAliasing::Msaa { .. } => {}
+            AntiAliasing::Fxaa { quality } => {
+                camera.insert(Fxaa {
+                    enabled: true,
+                    edge_threshold: quality.into(),
+                    edge_threshold_min: quality.into(),
+                });
+            }
+            AntiAliasing::Taa => {
+                // A fresh bundle resets the history so nothing from a previous TAA session leaks
+                camera.insert(TemporalAntiAliasBundle {
+                    settings: TemporalAntiAliasSettings { reset: true },
+                    ..default()
+                });
+            }
+        }
+    }
+
+    info!("anti aliasing set to {anti_aliasing}");
+    stats.set("AA", anti_aliasing.to_string());
+}
@@ -0,0 +1,280 @@
+//! Saving the session so a crash doesn't lose the tweaks.
+//!
+//! Every `interval_seconds` and when the app exits, the live scene and terrain configs are written
+//! to `autosave/session.scn.ron` in the assets, with the camera and the sculpting and tree edits
+//! next to it in `session.ron`. The snapshot is cloned in the frame and serialized on the IO task
+//! pool, only the save on exit is written right away. Every file is written to a temporary file
+//! first and renamed over the old one so a crash in the middle never leaves half a session. The
+//! previous sessions are moved to `autosave/history`, only the last `keep` ones are kept.
+//!
+//! When the session at startup is newer than the configs, Enter restores it within
+//! [`RESTORE_PROMPT_SECONDS`], or right away with `--restore-session`. The session is loaded like
+//! the configs, once they're in, so it replaces them. The benchmark, the smoke test and the
+//! scenarios never autosave.
+
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use bevy::{app::AppExit, prelude::*, tasks::IoTaskPool};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    camera_controller::CameraController, cli::CliArgs, heightfield::TerrainHeightfield,
+    overlay::StatsOverlay, scene_debug::ConfigCarrier, sculpt::TerrainEdits,
+    split_view::SecondaryCamera, terrain::TerrainConfig, tree_edit::TreeEdits, SceneConfig,
+};
+
+const AUTOSAVE_DIR: &str = "assets/autosave";
+/// Asset path of the saved configs
+const SESSION_SCENE: &str = "autosave/session.scn.ron";
+const SESSION_SCENE_FILE: &str = "session.scn.ron";
+const SESSION_STATE_FILE: &str = "session.ron";
+const HISTORY_DIR: &str = "history";
+/// How long the restore is offered at startup
+pub const RESTORE_PROMPT_SECONDS: f32 = 10.0;
+
+/// Settings of the session autosave
+#[derive(Reflect, Clone, Debug)]
+pub struct AutosaveConfig {
+    pub enabled: bool,
+    pub interval_seconds: f32,
+    /// Previous sessions kept in the history
+    pub keep: u32,
+}
+
+impl Default for AutosaveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_seconds: 60.0,
+            keep: 5,
+        }
+    }
+}
+
+/// What the session saves besides the configs
+#[derive(Serialize, Deserialize)]
+struct SessionState {
+    camera_translation: [f32; 3],
+    camera_rotation: [f32; 4],
+    terrain_edits: TerrainEdits,
+    tree_edits: TreeEdits,
+}
+
+/// A session newer than the configs, offered for restore at startup
+#[derive(Resource)]
+pub struct SessionRestore {
+    confirmed: bool,
+}
+
+/// Offers to restore the last session if it's newer than both configs
+pub fn check_for_session(mut commands: Commands, cli: Res<CliArgs>) {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let Some(session) = modified(&Path::new(AUTOSAVE_DIR).join(SESSION_SCENE_FILE)) else {
+        if cli.restore_session {
+            warn!("--restore-session, but there's no session in {AUTOSAVE_DIR}");
+        }
+        return;
+    };
+    let configs = [
+        "assets/scene_config.scn.ron",
+        "assets/terrain_config.scn.ron",
+    ]
+    .map(|path| modified(Path::new(path)).unwrap_or(SystemTime::UNIX_EPOCH));
+    if cli.restore_session {
+        info!("restoring the last session");
+        commands.insert_resource(SessionRestore { confirmed: true });
+    } else if configs.iter().all(|config| session > *config) {
+        warn!(
+            "the autosaved session is newer than the configs, press Enter within \
+            {RESTORE_PROMPT_SECONDS} seconds or start with --restore-session to restore it"
+        );
+        commands.insert_resource(SessionRestore { confirmed: false });
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn restore_session(
+    mut commands: Commands,
+    time: Res<Time<Real>>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut restore: ResMut<SessionRestore>,
+    asset_server: Res<AssetServer>,
+    configs_loaded: (
+        Option<Res<SceneConfig>>,
+        Option<Res<TerrainConfig>>,
+        Option<Res<TerrainHeightfield>>,
+    ),
+    mut stats: ResMut<StatsOverlay>,
+    mut camera: Query<
+        (&mut Transform, &mut CameraController),
+        (With<Camera3d>, Without<SecondaryCamera>),
+    >,
+) {
+    if !restore.confirmed {
+        let remaining = RESTORE_PROMPT_SECONDS - time.elapsed_seconds();
+        if keyboard.just_pressed(KeyCode::Enter) {
+            info!("restoring the last session");
+            restore.confirmed = true;
+        } else if remaining <= 0.0 {
+            info!("keeping the configs, the last session stays in {AUTOSAVE_DIR}");
+            commands.remove_resource::<SessionRestore>();
+            stats.remove("Autosave");
+            return;
+        } else {
+            stats.set(
+                "Autosave",
+                format!("newer session found, Enter to restore ({remaining:.0}s)"),
+            );
+            return;
+        }
+    }
+    // The session replaces the configs, it has to come after them
+    let (Some(_), Some(_), Some(_)) = configs_loaded else {
+        stats.set("Autosave", "restoring once the world is loaded");
+        return;
+    };
+    commands.remove_resource::<SessionRestore>();
+    stats.remove("Autosave");
+
+    let path = Path::new(AUTOSAVE_DIR).join(SESSION_STATE_FILE);
+    match std::fs::read_to_string(&path)
+        .map_err(|err| err.to_string())
+        .and_then(|file| ron::from_str::<SessionState>(&file).map_err(|err| err.to_string()))
+    {
+        Ok(state) => {
+            // Picked up when the terrain is generated again for the restored config
+            commands.insert_resource(state.terrain_edits);
+            commands.insert_resource(state.tree_edits);
+            if let Ok((mut transform, mut controller)) = camera.get_single_mut() {
+                transform.translation = Vec3::from(state.camera_translation);
+                transform.rotation = Quat::from_array(state.camera_rotation);
+                controller.initialized = false;
+            }
+        }
+        Err(err) => warn!(
+            "failed to read {}, only the configs are restored: {err}",
+            path.display()
+        ),
+    }
+    commands.spawn((
+        DynamicSceneBundle {
+            scene: asset_server.load(SESSION_SCENE),
+            ..default()
+        },
+        ConfigCarrier,
+    ));
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn autosave_session(
+    time: Res<Time<Real>>,
+    cli: Res<CliArgs>,
+    type_registry: Res<AppTypeRegistry>,
+    scene_config: Res<SceneConfig>,
+    terrain_config: Res<TerrainConfig>,
+    terrain_edits: Res<TerrainEdits>,
+    tree_edits: Res<TreeEdits>,
+    restore: Option<Res<SessionRestore>>,
+    camera: Query<&Transform, (With<CameraController>, Without<SecondaryCamera>)>,
+    mut exit: EventReader<AppExit>,
+    mut last_save: Local<f32>,
+) {
+    let exiting = exit.read().count() > 0;
+    let config = &scene_config.autosave;
+    let exempt = cli.smoke_test || cli.benchmark_baseline.is_some() || cli.scenario.is_some();
+    // The session offered for restore mustn't be replaced before the user answered
+    if !config.enabled || exempt || restore.is_some() {
+        return;
+    }
+    let now = time.elapsed_seconds();
+    if !exiting && now - *last_save < config.interval_seconds {
+        return;
+    }
+    *last_save = now;
+    let Ok(camera) = camera.get_single() else {
+        return;
+    };
+
+    let state = SessionState {
+        camera_translation: camera.translation.to_array(),
+        camera_rotation: camera.rotation.to_array(),
+        terrain_edits: terrain_edits.clone(),
+        tree_edits: tree_edits.clone(),
+    };
+    let type_registry = type_registry.clone();
+    let (scene_config, terrain_config) = (scene_config.clone(), terrain_config.clone());
+    let keep = config.keep;
+    let save = move || {
+        let mut scene_world = World::new();
+        scene_world.insert_resource(type_registry.clone());
+        scene_world.insert_resource(scene_config);
+        scene_world.insert_resource(terrain_config);
+        let scene = DynamicScene::from_world(&scene_world).serialize(&type_registry.read());
+        let state = ron::ser::to_string_pretty(&state, default());
+        match (scene, state) {
+            (Ok(scene), Ok(state)) => match write_session(&scene, &state, keep) {
+                Ok(()) => info!("session saved to {AUTOSAVE_DIR}"),
+                Err(err) => error!("failed to save the session: {err}"),
+            },
+            _ => error!("failed to serialize the session"),
+        }
+    };
+
+    #[cfg(not(target_arch = "wasm32"))]
+    if exiting {
+        // The task pool won't get to it once the app is gone
+        save();
+    } else {
+        IoTaskPool::get().spawn(async move { save() }).detach();
+    }
+}
+
+/// Moves the current session to the history, writes the new one and prunes the history
+fn write_session(scene: &str, state: &str, keep: u32) -> std::io::Result<()> {
+    let dir = Path::new(AUTOSAVE_DIR);
+    let history = dir.join(HISTORY_DIR);
+    std::fs::create_dir_all(&history)?;
+
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis());
+    for file in [SESSION_SCENE_FILE, SESSION_STATE_FILE] {
+        let current = dir.join(file);
+        if current.exists() {
+            // Zero padded so the names sort by age
+            std::fs::rename(&current, history.join(format!("{timestamp:015}_{file}")))?;
+        }
+    }
+    write_atomic(&dir.join(SESSION_SCENE_FILE), scene)?;
+    write_atomic(&dir.join(SESSION_STATE_FILE), state)?;
+
+    let mut sessions: Vec<PathBuf> = std::fs::read_dir(&history)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.ends_with(SESSION_SCENE_FILE))
+        })
+        .collect();
+    sessions.sort();
+    let excess = sessions.len().saturating_sub(keep as usize);
+    for scene in &sessions[..excess] {
+        let name = scene.file_name().and_then(|name| name.to_str());
+        if let Some(prefix) = name.and_then(|name| name.strip_suffix(SESSION_SCENE_FILE)) {
+            let _ = std::fs::remove_file(history.join(format!("{prefix}{SESSION_STATE_FILE}")));
+        }
+        std::fs::remove_file(scene)?;
+    }
+    Ok(())
+}
+
+/// Writes a temporary file and renames it over `path`, the rename replaces it in one go
+pub fn write_atomic(path: &Path, contents: &str) -> std::io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, contents)?;
+    std::fs::rename(&temporary, path)
+}
@@ -0,0 +1,272 @@
+//! Performance numbers compared against a stored baseline.
+//!
+//! `--benchmark-baseline <path>` waits for the world to be generated, flies the camera along the
+//! path of the `camera_path` module for [`BENCHMARK_FRAMES`] frames and compares the results with
+//! the baseline JSON. The app exits with an error when a metric got worse by more than
+//! `--benchmark-tolerance` percent. `--write-baseline` records the results to the path instead.
+//!
+//! Once the world is ready the tree candidates are also scattered again on a single thread and on
+//! the task pool to log what the parallelism brings, the benchmark fails if the two disagree.
+
+use std::time::{Duration, Instant};
+
+use bevy::{app::AppExit, ecs::entity::Entities, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    camera_path::Flythrough,
+    cli::CliArgs,
+    heightfield::TerrainHeightfield,
+    scatter::{scatter, scatter_sequential, PlacementGrid, ScatterInputs, ScatterModifiers},
+    terrain::TerrainConfig,
+    tree_lod::TreeLods,
+};
+
+/// Frames rendered before measuring so shaders and textures are ready
+const WARMUP_FRAMES: u32 = 120;
+/// Frames measured along the camera path
+pub const BENCHMARK_FRAMES: u32 = 600;
+pub const DEFAULT_TOLERANCE: f32 = 10.0;
+
+/// Time spent in the last terrain generation
+#[derive(Resource, Default, Debug)]
+pub struct GenerationTimings {
+    pub mesh: Duration,
+    /// Scattering and spawning the trees and the other layers
+    pub trees: Duration,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct BenchmarkMetrics {
+    pub mesh_generation_ms: f64,
+    pub tree_placement_ms: f64,
+    pub peak_entities: u32,
+    /// Average frame time along the camera path
+    pub frame_time_ms: f64,
+}
+
+impl BenchmarkMetrics {
+    /// Name and value of every metric, higher is worse for all of them
+    fn values(&self) -> [(&'static str, f64); 4] {
+        [
+            ("mesh generation (ms)", self.mesh_generation_ms),
+            ("tree placement (ms)", self.tree_placement_ms),
+            ("peak entities", self.peak_entities as f64),
+            ("frame time (ms)", self.frame_time_ms),
+        ]
+    }
+}
+
+enum BenchmarkState {
+    Loading,
+    Warmup(u32),
+    Measuring,
+}
+
+#[derive(Resource)]
+pub struct Benchmark {
+    state: BenchmarkState,
+    frame_times: Vec<Duration>,
+    peak_entities: u32,
+}
+
+pub fn start_benchmark(mut commands: Commands, cli: Res<CliArgs>) {
+    let Some(path) = &cli.benchmark_baseline else {
+        return;
+    };
+    if cli.write_baseline {
+        info!(
+            "benchmarking, the results will be written to {}",
+            path.display()
+        );
+    } else {
+        info!("benchmarking against {}", path.display());
+    }
+    commands.insert_resource(Benchmark {
+        state: BenchmarkState::Loading,
+        frame_times: Vec::with_capacity(BENCHMARK_FRAMES as usize),
+        peak_entities: 0,
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn run_benchmark(
+    mut commands: Commands,
+    cli: Res<CliArgs>,
+    mut benchmark: ResMut<Benchmark>,
+    time: Res<Time<Real>>,
+    entities: &Entities,
+    tree_lods: Option<Res<TreeLods>>,
+    heightfield: Option<Res<TerrainHeightfield>>,
+    timings: Option<Res<GenerationTimings>>,
+    terrain_config: Option<Res<TerrainConfig>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    benchmark.peak_entities = benchmark.peak_entities.max(entities.len());
+
+    match benchmark.state {
+        BenchmarkState::Loading => {
+            if tree_lods.is_none() || timings.is_none() {
+                return;
+            }
+            let (Some(terrain_config), Some(heightfield)) = (terrain_config, &heightfield) else {
+                return;
+            };
+            if !compare_scatter(&terrain_config, heightfield) {
+                commands.remove_resource::<Benchmark>();
+                exit.send(AppExit::error());
+                return;
+            }
+            benchmark.state = BenchmarkState::Warmup(0);
+        }
+        BenchmarkState::Warmup(frame) if frame < WARMUP_FRAMES => {
+            benchmark.state = BenchmarkState::Warmup(frame + 1);
+        }
+        BenchmarkState::Warmup(_) => {
+            let Some(heightfield) = heightfield else {
+                return;
+            };
+            commands.insert_resource(Flythrough::new(
+                Flythrough::load_path(&heightfield),
+                BENCHMARK_FRAMES,
+            ));
+            benchmark.state = BenchmarkState::Measuring;
+        }
+        BenchmarkState::Measuring => {
+            benchmark.frame_times.push(time.delta());
+            if benchmark.frame_times.len() < BENCHMARK_FRAMES as usize {
+                return;
+            }
+            // Checked before leaving the loading state
+            let Some(timings) = timings else {
+                return;
+            };
+            let frame_time =
+                benchmark.frame_times.iter().sum::<Duration>() / benchmark.frame_times.len() as u32;
+            let metrics = BenchmarkMetrics {
+                mesh_generation_ms: timings.mesh.as_secs_f64() * 1000.0,
+                tree_placement_ms: timings.trees.as_secs_f64() * 1000.0,
+                peak_entities: benchmark.peak_entities,
+                frame_time_ms: frame_time.as_secs_f64() * 1000.0,
+            };
+            commands.remove_resource::<Benchmark>();
+            exit.send(finish_benchmark(&cli, &metrics));
+        }
+    }
+}
+
+/// Times the tree candidates on a single thread and in parallel, returns whether both found the
+/// same ones
+fn compare_scatter(terrain_config: &TerrainConfig, heightfield: &TerrainHeightfield) -> bool {
+    // The normals of the terrain mesh aren't kept around, the heightfield ones are close enough
+    let resolution = heightfield.resolution;
+    let normals: Vec<[f32; 3]> = (0..resolution * resolution)
+        .map(|i| {
+            let position = heightfield.world_position(i % resolution, i / resolution);
+            heightfield
+                .normal_at(position.xz())
+                .unwrap_or(Vec3::Y)
+                .to_array()
+        })
+        .collect();
+    let layer = terrain_config.tree_layer();
+    let inputs = ScatterInputs {
+        layer: &layer,
+        modifiers: &ScatterModifiers::default(),
+        heightfield,
+        normals: &normals,
+        water_level: terrain_config.water_level,
+        variant_count: 1,
+        seed: layer.rng_seed(terrain_config.seed),
+        grid: &PlacementGrid::default(),
+    };
+
+    let start = Instant::now();
+    let sequential = scatter_sequential(&inputs);
+    let sequential_time = start.elapsed();
+    let start = Instant::now();
+    let parallel = scatter(&inputs);
+    let parallel_time = start.elapsed();
+    info!(
+        "{} tree candidates: {:.1} ms on one thread, {:.1} ms in parallel",
+        normals.len(),
+        sequential_time.as_secs_f64() * 1000.0,
+        parallel_time.as_secs_f64() * 1000.0,
+    );
+
+    let same = sequential == parallel;
+    if !same {
+        error!(
+            "the parallel scatter placed {} trees, the sequential one {}",
+            parallel.len(),
+            sequential.len()
+        );
+    }
+    same
+}
+
+fn finish_benchmark(cli: &CliArgs, metrics: &BenchmarkMetrics) -> AppExit {
+    let Some(path) = &cli.benchmark_baseline else {
+        return AppExit::Success;
+    };
+
+    if cli.write_baseline {
+        let result = serde_json::to_string_pretty(metrics)
+            .map_err(|err| err.to_string())
+            .and_then(|json| std::fs::write(path, json).map_err(|err| err.to_string()));
+        return match result {
+            Ok(()) => {
+                info!("benchmark baseline written to {}", path.display());
+                AppExit::Success
+            }
+            Err(err) => {
+                error!("failed to write {}: {err}", path.display());
+                AppExit::error()
+            }
+        };
+    }
+
+    let baseline = std::fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|json| {
+            serde_json::from_str::<BenchmarkMetrics>(&json).map_err(|err| err.to_string())
+        });
+    let baseline = match baseline {
+        Ok(baseline) => baseline,
+        Err(err) => {
+            error!(
+                "failed to read the benchmark baseline {}: {err}",
+                path.display()
+            );
+            return AppExit::error();
+        }
+    };
+
+    let tolerance = cli.benchmark_tolerance.unwrap_or(DEFAULT_TOLERANCE) as f64;
+    let mut regressions = 0;
+    println!(
+        "{:<22} {:>12} {:>12} {:>9}",
+        "metric", "baseline", "current", "delta"
+    );
+    for ((name, before), (_, after)) in baseline.values().into_iter().zip(metrics.values()) {
+        let delta = if before > 0.0 {
+            (after - before) / before * 100.0
+        } else {
+            0.0
+        };
+        let regressed = delta > tolerance;
+        regressions += regressed as u32;
+        println!(
+            "{name:<22} {before:>12.2} {after:>12.2} {delta:>+8.1}%{}",
+            if regressed { "  REGRESSED" } else { "" }
+        );
+    }
+
+    if regressions > 0 {
+        error!("{regressions} metric(s) regressed by more than {tolerance}%");
+        AppExit::error()
+    } else {
+        info!("no regression beyond {tolerance}%");
+        AppExit::Success
+    }
+}
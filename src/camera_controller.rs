@@ -5,9 +5,11 @@ use std::f32::consts::PI;
 use bevy::{
     input::mouse::{MouseMotion, MouseScrollUnit, MouseWheel},
     prelude::*,
-    window::CursorGrabMode,
+    window::{CursorGrabMode, PrimaryWindow},
 };
 
+use crate::split_view::{SecondaryCamera, SplitView};
+
 /// Based on Valorant's default sensitivity, not entirely sure why it is exactly 1.0 / 180.0,
 /// but I'm guessing it is a misunderstanding between degrees/radians and then sticking with
 /// it because it felt nice.
@@ -64,19 +66,34 @@ impl Default for CameraController {
 
 #[allow(clippy::too_many_arguments)]
 pub fn camera_controller(
-    time: Res<Time>,
-    mut windows: Query<&mut Window>,
+    // Keeps moving while the scene is paused, see the `frame_step` module
+    time: Res<Time<Real>>,
+    mut windows: Query<&mut Window, With<PrimaryWindow>>,
     mut mouse_events: EventReader<MouseMotion>,
     mut scroll_events: EventReader<MouseWheel>,
     mouse_button_input: Res<ButtonInput<MouseButton>>,
     key_input: Res<ButtonInput<KeyCode>>,
     mut toggle_cursor_grab: Local<bool>,
     mut mouse_cursor_grab: Local<bool>,
-    mut query: Query<(&mut Transform, &mut CameraController), With<Camera>>,
+    split_view: Res<SplitView>,
+    mut query: Query<
+        (
+            &mut Transform,
+            &mut CameraController,
+            Option<&mut Projection>,
+            Has<SecondaryCamera>,
+        ),
+        With<Camera>,
+    >,
 ) {
     let dt = time.delta_seconds();
 
-    let Ok((mut transform, mut controller)) = query.get_single_mut() else {
+    // Only one camera is controlled at a time, see the `split_view` module
+    let controls_secondary = split_view.controls_secondary();
+    let Some((mut transform, mut controller, mut projection, _)) = query
+        .iter_mut()
+        .find(|(.., secondary)| *secondary == controls_secondary)
+    else {
         return;
     };
 
@@ -91,6 +108,18 @@ pub fn camera_controller(
         return;
     }
 
+    // Nothing typed or clicked in the overlay window moves the camera, see the `ui_window` module
+    let active = windows.get_single().is_ok_and(|window| {
+        window.focused
+            && (window.cursor_position().is_some()
+                || window.cursor.grab_mode == CursorGrabMode::Locked)
+    });
+    if !active {
+        mouse_events.clear();
+        scroll_events.clear();
+    }
+    let pressed = |key: KeyCode| active && key_input.pressed(key);
+
     let mut scroll = 0.0;
     for scroll_event in scroll_events.read() {
         let amount = match scroll_event.unit {
@@ -99,36 +128,50 @@ pub fn camera_controller(
         };
         scroll += amount;
     }
-    controller.walk_speed += scroll * controller.scroll_factor * controller.walk_speed;
+    // The scroll zooms the orthographic projection, see the `projection` module
+    let orthographic = match projection.as_deref_mut() {
+        Some(Projection::Orthographic(orthographic)) => {
+            if scroll != 0.0 {
+                orthographic.scale = (orthographic.scale
+                    * (1.0 - scroll * controller.scroll_factor))
+                    .clamp(0.01, 100.0);
+            }
+            true
+        }
+        _ => false,
+    };
+    if !orthographic {
+        controller.walk_speed += scroll * controller.scroll_factor * controller.walk_speed;
+    }
     controller.run_speed = controller.walk_speed * 3.0;
 
     // Handle key input
     let mut axis_input = Vec3::ZERO;
-    if key_input.pressed(controller.key_forward) {
+    if pressed(controller.key_forward) {
         axis_input.z += 1.0;
     }
-    if key_input.pressed(controller.key_back) {
+    if pressed(controller.key_back) {
         axis_input.z -= 1.0;
     }
-    if key_input.pressed(controller.key_right) {
+    if pressed(controller.key_right) {
         axis_input.x += 1.0;
     }
-    if key_input.pressed(controller.key_left) {
+    if pressed(controller.key_left) {
         axis_input.x -= 1.0;
     }
-    if key_input.pressed(controller.key_up) {
+    if pressed(controller.key_up) {
         axis_input.y += 1.0;
     }
-    if key_input.pressed(controller.key_down) {
+    if pressed(controller.key_down) {
         axis_input.y -= 1.0;
     }
 
     let mut cursor_grab_change = false;
-    if key_input.just_pressed(controller.keyboard_key_toggle_cursor_grab) {
+    if active && key_input.just_pressed(controller.keyboard_key_toggle_cursor_grab) {
         *toggle_cursor_grab = !*toggle_cursor_grab;
         cursor_grab_change = true;
     }
-    if mouse_button_input.just_pressed(controller.mouse_key_cursor_grab) {
+    if active && mouse_button_input.just_pressed(controller.mouse_key_cursor_grab) {
         *mouse_cursor_grab = true;
         cursor_grab_change = true;
     }
@@ -140,7 +183,7 @@ pub fn camera_controller(
 
     // Apply movement update
     if axis_input != Vec3::ZERO {
-        let max_speed = if key_input.pressed(controller.key_run) {
+        let max_speed = if pressed(controller.key_run) {
             controller.run_speed
         } else {
             controller.walk_speed
@@ -153,7 +196,15 @@ pub fn camera_controller(
             controller.velocity = Vec3::ZERO;
         }
     }
-    let forward = *transform.forward();
+    // Moving forward doesn't change an orthographic view, it pans along the ground instead. The
+    // up vector takes over from the forward one when looking straight down.
+    let forward = if orthographic {
+        (*transform.forward() + *transform.up())
+            .with_y(0.0)
+            .normalize_or_zero()
+    } else {
+        *transform.forward()
+    };
     let right = *transform.right();
     transform.translation += controller.velocity.x * dt * right
         + controller.velocity.y * dt * Vec3::Y
@@ -0,0 +1,188 @@
+//! Camera flythrough stored as a few keyframes.
+//!
+//! The keyframes are evenly spaced along the path and the camera goes through them on a
+//! Catmull-Rom spline so a handful of points is enough for a smooth flight. F5 plays the path, the
+//! benchmark flies the same one so the numbers match what can be watched interactively.
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    camera_controller::CameraController, deterministic::DeterministicClock,
+    heightfield::TerrainHeightfield, split_view::SecondaryCamera, SceneConfig,
+};
+
+pub const CAMERA_PATH_PATH: &str = "assets/camera_path.ron";
+
+/// Frames taken by an interactive flythrough, twice as many with `reduce_motion`
+pub const FLYTHROUGH_FRAMES: u32 = 1200;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CameraKeyframe {
+    pub position: [f32; 3],
+    pub look_at: [f32; 3],
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CameraPath {
+    pub keyframes: Vec<CameraKeyframe>,
+}
+
+impl CameraPath {
+    /// Loads the path saved in the assets, if any
+    pub fn load() -> Option<Self> {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let file = std::fs::read_to_string(CAMERA_PATH_PATH).ok()?;
+            match ron::from_str::<Self>(&file) {
+                Ok(path) if path.keyframes.len() >= 2 => return Some(path),
+                Ok(_) => warn!("{CAMERA_PATH_PATH} needs at least 2 keyframes"),
+                Err(err) => warn!("failed to read {CAMERA_PATH_PATH}: {err}"),
+            }
+        }
+        None
+    }
+
+    /// Circles the terrain a few meters above the ground, looking at its center
+    pub fn orbit(heightfield: &TerrainHeightfield, height: f32) -> Self {
+        let radius = heightfield.size * 0.3;
+        let keyframes = (0..=8)
+            .map(|i| {
+                let angle = i as f32 / 8.0 * std::f32::consts::TAU;
+                let xz = Vec2::from_angle(angle) * radius;
+                let ground = heightfield.height_at(xz).unwrap_or(0.0).max(0.0);
+                CameraKeyframe {
+                    position: [xz.x, ground + height, xz.y],
+                    look_at: [0.0, ground, 0.0],
+                }
+            })
+            .collect();
+        Self { keyframes }
+    }
+
+    /// Camera transform at `t` between 0 and 1
+    pub fn sample(&self, t: f32) -> Transform {
+        let count = self.keyframes.len();
+        let segments = (count - 1) as f32;
+        let position = t.clamp(0.0, 1.0) * segments;
+        let index = (position.floor() as usize).min(count - 2);
+        let local = position - index as f32;
+        let point = |offset: isize, f: fn(&CameraKeyframe) -> [f32; 3]| {
+            let i = (index as isize + offset).clamp(0, count as isize - 1) as usize;
+            Vec3::from(f(&self.keyframes[i]))
+        };
+        let spline = |f: fn(&CameraKeyframe) -> [f32; 3]| {
+            catmull_rom(point(-1, f), point(0, f), point(1, f), point(2, f), local)
+        };
+        let position = spline(|k| k.position);
+        let look_at = spline(|k| k.look_at);
+        Transform::from_translation(position).looking_at(look_at, Vec3::Y)
+    }
+}
+
+fn catmull_rom(p0: Vec3, p1: Vec3, p2: Vec3, p3: Vec3, t: f32) -> Vec3 {
+    let (t2, t3) = (t * t, t * t * t);
+    0.5 * (2.0 * p1
+        + (p2 - p0) * t
+        + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2
+        + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3)
+}
+
+/// Camera following a [`CameraPath`], advanced by one step every frame so a run always renders the
+/// same views regardless of the frame rate.
+///
+/// In deterministic mode it's advanced by the fixed steps of the clock instead, so the path stays
+/// in sync with the animation of the scene, see the `deterministic` module.
+#[derive(Resource)]
+pub struct Flythrough {
+    pub path: CameraPath,
+    pub frame: u32,
+    pub frames: u32,
+    controller_enabled: Option<bool>,
+}
+
+impl Flythrough {
+    pub fn new(path: CameraPath, frames: u32) -> Self {
+        Self {
+            path,
+            frame: 0,
+            frames: frames.max(1),
+            controller_enabled: None,
+        }
+    }
+
+    /// The saved path, or a circle around the terrain when there's none
+    pub fn load_path(heightfield: &TerrainHeightfield) -> CameraPath {
+        CameraPath::load().unwrap_or_else(|| {
+            warn!("no camera path in {CAMERA_PATH_PATH}, orbiting the terrain instead");
+            CameraPath::orbit(heightfield, 30.0)
+        })
+    }
+
+    pub fn finished(&self) -> bool {
+        self.frame >= self.frames
+    }
+}
+
+pub fn toggle_flythrough(
+    mut commands: Commands,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    heightfield: Res<TerrainHeightfield>,
+    scene_config: Option<Res<SceneConfig>>,
+    flythrough: Option<Res<Flythrough>>,
+) {
+    if !keyboard.just_pressed(KeyCode::F5) {
+        return;
+    }
+    // The benchmark and the scenarios keep their own pace, they have to render the same frames
+    let frames = scene_config.map_or(FLYTHROUGH_FRAMES, |scene_config| {
+        scene_config
+            .accessibility
+            .flythrough_frames(FLYTHROUGH_FRAMES)
+    });
+    match flythrough {
+        Some(_) => commands.remove_resource::<Flythrough>(),
+        None => {
+            commands.insert_resource(Flythrough::new(Flythrough::load_path(&heightfield), frames))
+        }
+    }
+}
+
+pub fn fly_camera(
+    mut commands: Commands,
+    flythrough: Option<ResMut<Flythrough>>,
+    clock: Res<DeterministicClock>,
+    // Controller state to restore once the flythrough is over
+    mut restore_controller: Local<Option<bool>>,
+    mut camera: Query<(&mut Transform, &mut CameraController), Without<SecondaryCamera>>,
+) {
+    let Ok((mut transform, mut controller)) = camera.get_single_mut() else {
+        return;
+    };
+    let Some(mut flythrough) = flythrough else {
+        if let Some(enabled) = restore_controller.take() {
+            controller.enabled = enabled;
+            // Picks up the yaw and pitch where the path left the camera
+            controller.initialized = false;
+        }
+        return;
+    };
+    if flythrough.controller_enabled.is_none() {
+        flythrough.controller_enabled = Some(std::mem::replace(&mut controller.enabled, false));
+    }
+    *restore_controller = flythrough.controller_enabled;
+
+    let progress = if clock.enabled {
+        // Between the last step and the next one so the camera moves every frame
+        flythrough.frame += clock.steps;
+        flythrough.frame as f32 + clock.overstep_fraction()
+    } else {
+        let frame = flythrough.frame;
+        flythrough.frame += 1;
+        frame as f32
+    };
+    *transform = flythrough.path.sample(progress / flythrough.frames as f32);
+    if flythrough.finished() {
+        commands.remove_resource::<Flythrough>();
+    }
+}
@@ -0,0 +1,298 @@
+//! Moves the camera to a good viewpoint once the terrain is generated.
+//!
+//! The fixed spawn position used by [`crate::spawn_camera`] is sometimes inside a hill or staring
+//! at empty water depending on the seed.
+//!
+//! The viewpoint is the start of the world, kept in the terrain config with the seed it was found
+//! for so it's the same every time the world is loaded. It's only looked for again when the seed
+//! changes or the terrain covers it. Home flies the camera back to it, Shift+Home picks the next
+//! best viewpoint as the new start.
+
+use bevy::prelude::*;
+
+use crate::{
+    camera_controller::CameraController, camera_path::Flythrough, heightfield::TerrainHeightfield,
+    overlay::StatsOverlay, split_view::SecondaryCamera, terrain::TerrainConfig, SceneConfig,
+};
+
+/// How far the camera needs to be from the ground to not be considered inside of it
+const CLEARANCE: f32 = 0.5;
+/// How long the camera takes to fly back to the start
+const RETURN_SECONDS: f32 = 1.5;
+/// Fraction of the terrain size between two viewpoints offered as start
+const START_SEPARATION: f32 = 0.1;
+
+/// Start of the world saved in the terrain config
+#[derive(Reflect, Clone, Debug, PartialEq)]
+pub struct StartPosition {
+    /// Seed of the terrain it was found for
+    pub seed: u32,
+    /// Rank of the viewpoint, Shift+Home moves to the next one
+    pub rank: u32,
+    pub translation: Vec3,
+    pub look_at: Vec3,
+}
+
+impl StartPosition {
+    fn transform(&self) -> Transform {
+        Transform::from_translation(self.translation).looking_at(self.look_at, Vec3::Y)
+    }
+}
+
+/// The start of the current world, Home brings the camera back to it
+#[derive(Resource, Clone, Debug)]
+pub struct WorldStart(pub StartPosition);
+
+/// The camera flying back to the start
+#[derive(Resource)]
+pub struct ReturnToStart {
+    from: Transform,
+    elapsed: f32,
+}
+
+pub fn place_camera(
+    mut commands: Commands,
+    heightfield: Res<TerrainHeightfield>,
+    scene_config: Res<SceneConfig>,
+    mut terrain_config: ResMut<TerrainConfig>,
+    mut camera: Query<
+        (&mut Transform, &mut CameraController),
+        (With<Camera>, Without<SecondaryCamera>),
+    >,
+    mut placed: Local<bool>,
+) {
+    if *placed && !heightfield.is_changed() {
+        return;
+    }
+    let Ok((mut transform, mut controller)) = camera.get_single_mut() else {
+        return;
+    };
+
+    let start = update_start(&heightfield, &scene_config, &mut terrain_config);
+    match &start {
+        Some(start) => commands.insert_resource(WorldStart(start.clone())),
+        None => commands.remove_resource::<WorldStart>(),
+    }
+
+    if !*placed {
+        *placed = true;
+        if !scene_config.camera_fixed_spawn {
+            match start {
+                Some(start) => {
+                    info!("camera moved to viewpoint {}", start.translation);
+                    *transform = start.transform();
+                    // make the controller pick up the new orientation
+                    controller.initialized = false;
+                    return;
+                }
+                None => warn!("no good viewpoint found, keeping the default camera position"),
+            }
+        }
+    }
+
+    // Never yank the camera around after a regeneration unless it ended up underground
+    let position = transform.translation;
+    if let Some(height) = heightfield.height_at(position.xz()) {
+        if position.y < height + CLEARANCE {
+            transform.translation.y = height + scene_config.camera_spawn_height;
+            info!(
+                "camera was underground, moved it to {}",
+                transform.translation
+            );
+        }
+    }
+}
+
+/// The saved start if it's still usable, or the best viewpoint of the terrain saved in its place
+fn update_start(
+    heightfield: &TerrainHeightfield,
+    scene_config: &SceneConfig,
+    terrain_config: &mut ResMut<TerrainConfig>,
+) -> Option<StartPosition> {
+    if let Some(start) = &terrain_config.start {
+        if start.seed == terrain_config.seed && is_clear(heightfield, start.translation) {
+            return Some(start.clone());
+        }
+    }
+    let start = find_viewpoints(
+        heightfield,
+        terrain_config.water_level,
+        scene_config.camera_spawn_height,
+    )
+    .into_iter()
+    .next()
+    .map(|(translation, look_at)| StartPosition {
+        seed: terrain_config.seed,
+        rank: 0,
+        translation,
+        look_at,
+    });
+    if let Some(start) = &start {
+        info!("new start of the world at {}", start.translation);
+    }
+    // Nothing has to be generated again for it
+    terrain_config
+        .bypass_change_detection()
+        .start
+        .clone_from(&start);
+    start
+}
+
+/// Home flies back to the start, Shift+Home replaces the start with the next best viewpoint
+#[allow(clippy::too_many_arguments)]
+pub fn return_to_start(
+    mut commands: Commands,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    heightfield: Res<TerrainHeightfield>,
+    scene_config: Res<SceneConfig>,
+    mut terrain_config: ResMut<TerrainConfig>,
+    start: Option<ResMut<WorldStart>>,
+    camera: Query<&Transform, (With<CameraController>, Without<SecondaryCamera>)>,
+    mut stats: ResMut<StatsOverlay>,
+    mut shown_distance: Local<Option<i32>>,
+) {
+    let Some(mut start) = start else {
+        stats.remove("Start");
+        *shown_distance = None;
+        return;
+    };
+    let Ok(transform) = camera.get_single() else {
+        return;
+    };
+
+    if keyboard.just_pressed(KeyCode::Home) {
+        if keyboard.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+            let viewpoints = find_viewpoints(
+                &heightfield,
+                terrain_config.water_level,
+                scene_config.camera_spawn_height,
+            );
+            if viewpoints.is_empty() {
+                warn!("no other viewpoint found, the start is unchanged");
+                return;
+            }
+            let rank = (start.0.rank + 1) % viewpoints.len() as u32;
+            let (translation, look_at) = viewpoints[rank as usize];
+            start.0 = StartPosition {
+                seed: terrain_config.seed,
+                rank,
+                translation,
+                look_at,
+            };
+            terrain_config.bypass_change_detection().start = Some(start.0.clone());
+            info!("new start of the world at {translation} (viewpoint {rank})");
+        }
+        // A flythrough would take the camera right back
+        commands.remove_resource::<Flythrough>();
+        commands.insert_resource(ReturnToStart {
+            from: *transform,
+            elapsed: 0.0,
+        });
+    }
+
+    let distance = transform.translation.distance(start.0.translation).round() as i32;
+    if *shown_distance != Some(distance) {
+        *shown_distance = Some(distance);
+        stats.set("Start", format!("{distance}m away, Home to go back"));
+    }
+}
+
+/// Eases the camera from where Home was pressed to the start, or jumps there with
+/// `reduce_motion`
+pub fn fly_to_start(
+    mut commands: Commands,
+    time: Res<Time<Real>>,
+    scene_config: Res<SceneConfig>,
+    start: Option<Res<WorldStart>>,
+    mut flight: ResMut<ReturnToStart>,
+    mut camera: Query<
+        (&mut Transform, &mut CameraController),
+        (With<Camera>, Without<SecondaryCamera>),
+    >,
+) {
+    let (Some(start), Ok((mut transform, mut controller))) = (start, camera.get_single_mut())
+    else {
+        commands.remove_resource::<ReturnToStart>();
+        return;
+    };
+    flight.elapsed += if scene_config.accessibility.reduce_motion {
+        RETURN_SECONDS
+    } else {
+        time.delta_seconds()
+    };
+    let t = (flight.elapsed / RETURN_SECONDS).min(1.0);
+    let t = t * t * (3.0 - 2.0 * t);
+    let target = start.0.transform();
+    transform.translation = flight.from.translation.lerp(target.translation, t);
+    transform.rotation = flight.from.rotation.slerp(target.rotation, t);
+    controller.velocity = Vec3::ZERO;
+    if flight.elapsed >= RETURN_SECONDS {
+        // Picks up the yaw and pitch of the start
+        controller.initialized = false;
+        commands.remove_resource::<ReturnToStart>();
+    }
+}
+
+/// Points just above the shoreline that can see the highest peak of the terrain, the best first,
+/// with the position of the peak. They're at least [`START_SEPARATION`] apart.
+fn find_viewpoints(
+    heightfield: &TerrainHeightfield,
+    water_level: f32,
+    height: f32,
+) -> Vec<(Vec3, Vec3)> {
+    let (peak_x, peak_z) = heightfield.highest_point();
+    let peak = heightfield.world_position(peak_x, peak_z);
+    let resolution = heightfield.resolution;
+    // Checking every vertex is overkill, the viewpoint only needs to be roughly on the shore
+    let step = (resolution / 128).max(1);
+
+    let mut candidates: Vec<(f32, Vec3)> = vec![];
+    for z in (1..resolution - 1).step_by(step) {
+        for x in (1..resolution - 1).step_by(step) {
+            let ground = heightfield.height(x, z);
+            if ground < water_level || ground > water_level + 1.0 {
+                continue;
+            }
+            let near_water = [(x - 1, z), (x + 1, z), (x, z - 1), (x, z + 1)]
+                .into_iter()
+                .any(|(x, z)| heightfield.height(x, z) < water_level);
+            if !near_water {
+                continue;
+            }
+
+            let ground = heightfield.world_position(x, z);
+            let eye = Vec3::new(ground.x, ground.y.max(water_level) + height, ground.z);
+            if !is_clear(heightfield, eye) || !heightfield.line_of_sight(eye, peak) {
+                continue;
+            }
+
+            // Prefer a view where the peak is neither too close nor too far
+            let score = (eye.xz().distance(peak.xz()) - heightfield.size * 0.3).abs();
+            candidates.push((score, eye));
+        }
+    }
+    candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let separation = heightfield.size * START_SEPARATION;
+    let mut viewpoints: Vec<(Vec3, Vec3)> = vec![];
+    for (_, eye) in candidates {
+        if viewpoints
+            .iter()
+            .all(|(other, _)| other.xz().distance(eye.xz()) > separation)
+        {
+            viewpoints.push((eye, peak));
+        }
+    }
+    viewpoints
+}
+
+/// Checks that the camera isn't inside the terrain, including a small area around it so the near
+/// plane doesn't clip into a slope
+fn is_clear(heightfield: &TerrainHeightfield, eye: Vec3) -> bool {
+    let offsets = [Vec2::ZERO, Vec2::X, -Vec2::X, Vec2::Y, -Vec2::Y];
+    offsets.into_iter().all(|offset| {
+        heightfield
+            .height_at(eye.xz() + offset)
+            .map_or(true, |h| eye.y > h + CLEARANCE)
+    })
+}
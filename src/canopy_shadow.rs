@@ -0,0 +1,171 @@
+//! Darkening the ground under the distant forest, past the reach of the sun shadows.
+//!
+//! The sun cascades stop at the range of `fog_shadows`, past it the forested hillsides were as
+//! bright as the open ground. Once the trees are placed, every canopy is splatted into a coarse
+//! texture over the terrain and the terrain shader darkens the ground under them by `strength`. The
+//! darkening fades in over the last [`FADE_FRACTION`] of the shadow range, where the real shadows
+//! are still drawn, so it's already there when they stop.
+//!
+//! The deferred lighting runs after the terrain shader so the sun can't be singled out, the ground
+//! color is darkened instead. That also takes some of the ambient light, which the canopy blocks
+//! too. There's nothing to blend with when the sun doesn't cast shadows, the texture is dropped and
+//! the terrain shader skips it.
+
+use bevy::{
+    pbr::{CascadeShadowConfig, VolumetricLight},
+    prelude::*,
+    render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+        texture::{ImageAddressMode, ImageFilterMode, ImageSampler, ImageSamplerDescriptor},
+    },
+};
+
+use crate::{
+    heightfield::TerrainHeightfield, sky::Moonlight, terrain::TreePlacements, SceneConfig,
+};
+
+/// Radius of the canopy of a tree at scale 1
+const CANOPY_RADIUS: f32 = 2.5;
+/// How much of the light a single canopy blocks at its center
+const CANOPY_OPACITY: f32 = 0.7;
+/// Part of the shadow range over which the baked darkening fades in
+const FADE_FRACTION: f32 = 0.2;
+
+/// Settings of the baked canopy darkening
+#[derive(Reflect, Clone, Debug)]
+pub struct CanopyShadowConfig {
+    /// How dark the ground under a dense canopy gets, 0 disables it
+    pub strength: f32,
+    /// Texels on each side of the texture, one byte each
+    pub resolution: u32,
+}
+
+impl Default for CanopyShadowConfig {
+    fn default() -> Self {
+        Self {
+            strength: 0.6,
+            resolution: 256,
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct CanopyShadow {
+    image: Option<Handle<Image>>,
+    /// xy: world origin of the texture, z: its extent, w: strength
+    region: Vec4,
+    /// Distances from the camera between which the darkening fades in
+    fade: Vec2,
+}
+
+impl CanopyShadow {
+    pub fn image(&self) -> Option<&Handle<Image>> {
+        self.image.as_ref()
+    }
+
+    pub fn region(&self) -> Vec4 {
+        self.region
+    }
+
+    pub fn fade(&self) -> Vec2 {
+        self.fade
+    }
+}
+
+/// Splats the canopies of the placed trees, again whenever they move
+pub fn bake_canopy_shadow(
+    scene_config: Res<SceneConfig>,
+    tree_placements: Res<TreePlacements>,
+    heightfield: Res<TerrainHeightfield>,
+    sun: Query<
+        (&DirectionalLight, &CascadeShadowConfig),
+        (With<VolumetricLight>, Without<Moonlight>),
+    >,
+    mut canopy_shadow: ResMut<CanopyShadow>,
+    mut images: ResMut<Assets<Image>>,
+    // Whether it was baked and at which resolution, a crossfading config only changes the strength
+    mut baked: Local<Option<u32>>,
+) {
+    let Ok((light, cascades)) = sun.get_single() else {
+        return;
+    };
+    let config = &scene_config.canopy_shadow;
+    let range = cascades.bounds.last().copied().unwrap_or(0.0);
+    if !light.shadows_enabled || config.strength <= 0.0 || range <= 0.0 {
+        if baked.take().is_some() {
+            *canopy_shadow = CanopyShadow::default();
+        }
+        return;
+    }
+
+    // Square covering the rotated terrain, like the water depth
+    let (sin, cos) = heightfield.rotation.sin_cos();
+    let extent = heightfield.size * (sin.abs() + cos.abs());
+    let origin = Vec2::splat(-extent * 0.5);
+    let region = origin.extend(extent).extend(config.strength);
+    let fade = Vec2::new(range * (1.0 - FADE_FRACTION), range);
+    if canopy_shadow.region != region || canopy_shadow.fade != fade {
+        canopy_shadow.region = region;
+        canopy_shadow.fade = fade;
+    }
+    if !tree_placements.is_changed() && *baked == Some(config.resolution) {
+        return;
+    }
+    *baked = Some(config.resolution);
+    let _span = info_span!("bake_canopy_shadow").entered();
+
+    let resolution = config.resolution as usize;
+    let texel = extent / resolution as f32;
+    // How much light still gets through each texel
+    let mut light_through = vec![1.0f32; resolution * resolution];
+    for tree in &tree_placements.trees {
+        let radius = CANOPY_RADIUS * tree.scale;
+        let center = (tree.position.xz() - origin) / texel;
+        let reach = radius / texel;
+        let min = (center - reach).floor().max(Vec2::ZERO).as_uvec2();
+        let max = (center + reach)
+            .ceil()
+            .min(Vec2::splat(resolution as f32 - 1.0))
+            .as_uvec2();
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                let distance = (Vec2::new(x as f32, y as f32) + 0.5).distance(center) * texel;
+                if distance >= radius {
+                    continue;
+                }
+                // Thinner toward the edge of the canopy
+                let t = distance / radius;
+                let opacity = CANOPY_OPACITY * (1.0 - t * t);
+                light_through[y as usize * resolution + x as usize] *= 1.0 - opacity;
+            }
+        }
+    }
+    let data: Vec<u8> = light_through
+        .iter()
+        .map(|through| ((1.0 - through).clamp(0.0, 1.0) * 255.0).round() as u8)
+        .collect();
+    canopy_shadow.image = Some(images.add(canopy_image(config.resolution, data)));
+}
+
+fn canopy_image(resolution: u32, data: Vec<u8>) -> Image {
+    let mut image = Image::new(
+        Extent3d {
+            width: resolution,
+            height: resolution,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::R8Unorm,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+    image.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
+        address_mode_u: ImageAddressMode::ClampToEdge,
+        address_mode_v: ImageAddressMode::ClampToEdge,
+        mag_filter: ImageFilterMode::Linear,
+        min_filter: ImageFilterMode::Linear,
+        ..default()
+    });
+    image
+}
@@ -0,0 +1,139 @@
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+
+/// Command line arguments
+#[derive(Resource, Default, Debug)]
+pub struct CliArgs {
+    /// Export the overhead map once the world is generated
+    pub export_map: bool,
+    /// Resolution of the exported map in pixels
+    pub map_resolution: Option<u32>,
+    /// World code to generate instead of the one in the terrain config
+    pub world_code: Option<String>,
+    /// Record a chrome trace, see the `trace` module
+    pub trace: bool,
+    /// Keep old config files as they are and only migrate them in memory
+    pub no_config_writeback: bool,
+    /// Leave the fields at their default out of the configs saved with Ctrl+S
+    pub compact_configs: bool,
+    /// Start a print render at this resolution once the world is loaded
+    pub render: Option<UVec2>,
+    /// Frames averaged for every tile of the print render
+    pub render_samples: Option<u32>,
+    /// Use the forward renderer instead of the deferred one
+    pub forward: bool,
+    /// Run the benchmark and compare it with this baseline JSON, see the `benchmark` module
+    pub benchmark_baseline: Option<PathBuf>,
+    /// Write the benchmark results to the baseline path instead of comparing them
+    pub write_baseline: bool,
+    /// Percentage a metric can get worse by before the benchmark fails
+    pub benchmark_tolerance: Option<f32>,
+    /// Exit once the scene is populated, or with an error, see the `smoke_test` module
+    pub smoke_test: bool,
+    /// Convert the textures and exit, handled before the app starts, see the `preprocess` module
+    pub preprocess_assets: bool,
+    /// Downscaled variant of the processed textures to load, like `1k` or `2k`
+    pub texture_tier: Option<String>,
+    /// Scripted actions to play once the world is loaded, see the `scenario` module
+    pub scenario: Option<PathBuf>,
+    /// Start the scenario over after its last action
+    pub scenario_loop: bool,
+    /// Restore the autosaved session without asking, see the `autosave` module
+    pub restore_session: bool,
+    /// Detect the quality preset again instead of reading it from the prefs, see the `quality`
+    /// module
+    pub redetect: bool,
+    /// Advance the scene by fixed steps so recordings match, see the `deterministic` module
+    pub deterministic: bool,
+    /// Address the remote control listens on, see the `remote` module
+    pub remote_bind: Option<String>,
+}
+
+impl CliArgs {
+    pub fn parse() -> Self {
+        let mut cli = Self::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--export-map" => cli.export_map = true,
+                "--trace" => cli.trace = true,
+                "--no-config-writeback" => cli.no_config_writeback = true,
+                "--compact-configs" => cli.compact_configs = true,
+                "--forward" => cli.forward = true,
+                "--write-baseline" => cli.write_baseline = true,
+                "--smoke-test" => cli.smoke_test = true,
+                "--preprocess-assets" => cli.preprocess_assets = true,
+                "--scenario-loop" => cli.scenario_loop = true,
+                "--restore-session" => cli.restore_session = true,
+                "--redetect" => cli.redetect = true,
+                "--deterministic" => cli.deterministic = true,
+                "--map-resolution" => {
+                    cli.map_resolution = args.next().and_then(|v| v.parse().ok());
+                    if cli.map_resolution.is_none() {
+                        warn!("--map-resolution expects a number of pixels");
+                    }
+                }
+                "--world-code" => {
+                    cli.world_code = args.next();
+                    if cli.world_code.is_none() {
+                        warn!("--world-code expects a code");
+                    }
+                }
+                "--render" => {
+                    cli.render = args.next().and_then(|v| {
+                        let (width, height) = v.split_once('x')?;
+                        Some(UVec2::new(width.parse().ok()?, height.parse().ok()?))
+                    });
+                    if cli.render.is_none() {
+                        warn!("--render expects a resolution like 7680x4320");
+                    }
+                }
+                "--render-samples" => {
+                    cli.render_samples = args.next().and_then(|v| v.parse().ok());
+                    if cli.render_samples.is_none() {
+                        warn!("--render-samples expects a number of frames");
+                    }
+                }
+                "--benchmark-baseline" => {
+                    cli.benchmark_baseline = args.next().map(PathBuf::from);
+                    if cli.benchmark_baseline.is_none() {
+                        warn!("--benchmark-baseline expects the path of a JSON file");
+                    }
+                }
+                "--scenario" => {
+                    cli.scenario = args.next().map(PathBuf::from);
+                    if cli.scenario.is_none() {
+                        warn!("--scenario expects the path of a RON file");
+                    }
+                }
+                "--remote-bind" => {
+                    cli.remote_bind = args.next();
+                    if cli.remote_bind.is_none() {
+                        warn!("--remote-bind expects an address like 0.0.0.0:8787");
+                    }
+                }
+                "--texture-tier" => {
+                    cli.texture_tier = args.next();
+                    if cli.texture_tier.is_none() {
+                        warn!("--texture-tier expects a tier like 1k or 2k");
+                    }
+                }
+                "--benchmark-tolerance" => {
+                    cli.benchmark_tolerance = args.next().and_then(|v| v.parse().ok());
+                    if cli.benchmark_tolerance.is_none() {
+                        warn!("--benchmark-tolerance expects a percentage");
+                    }
+                }
+                _ => warn!("unknown argument {arg}"),
+            }
+        }
+        if cli.write_baseline && cli.benchmark_baseline.is_none() {
+            warn!("--write-baseline needs --benchmark-baseline <path>");
+        }
+        if cli.scenario_loop && cli.scenario.is_none() {
+            warn!("--scenario-loop needs --scenario <path>");
+        }
+        cli
+    }
+}
@@ -0,0 +1,403 @@
+//! Tonemapping LUTs and custom film looks.
+//!
+//! TonyMcMapface, AgX and BlenderFilmic sample a LUT that bevy only embeds with its
+//! `tonemapping_luts` feature, without it they render pink. That feature is enabled through ours so
+//! we know which tonemappers can be used, the config validation replaces the other ones with
+//! AcesFitted. The embedded LUTs are added to the image assets when the tonemapping plugin is
+//! built so there's nothing to wait for before switching to them.
+//!
+//! A `.cube` file can also be set in the scene config. It's loaded as a 3D image and applied by a
+//! post process pass right after the tonemapping, the previous look is kept until it's loaded.
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext, LoadState, RenderAssetUsages},
+    core_pipeline::{
+        core_3d::graph::{Core3d, Node3d},
+        fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+        tonemapping::Tonemapping,
+    },
+    ecs::query::QueryItem,
+    prelude::*,
+    render::{
+        extract_component::{ExtractComponent, ExtractComponentPlugin},
+        render_asset::RenderAssets,
+        render_graph::{
+            NodeRunError, RenderGraphApp, RenderGraphContext, RenderLabel, ViewNode, ViewNodeRunner,
+        },
+        render_resource::{
+            binding_types::{sampler, texture_2d, texture_3d},
+            BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, CachedRenderPipelineId,
+            ColorTargetState, ColorWrites, Extent3d, FilterMode, FragmentState, Operations,
+            PipelineCache, RenderPassColorAttachment, RenderPassDescriptor,
+            RenderPipelineDescriptor, Sampler, SamplerBindingType, SamplerDescriptor, ShaderStages,
+            TextureDimension, TextureFormat, TextureSampleType,
+        },
+        renderer::{RenderContext, RenderDevice},
+        texture::{BevyDefault, GpuImage},
+        view::ViewTarget,
+        RenderApp,
+    },
+};
+
+use crate::SceneConfig;
+
+pub const TONEMAPPERS: [Tonemapping; 8] = [
+    Tonemapping::None,
+    Tonemapping::Reinhard,
+    Tonemapping::ReinhardLuminance,
+    Tonemapping::AcesFitted,
+    Tonemapping::AgX,
+    Tonemapping::SomewhatBoringDisplayTransform,
+    Tonemapping::TonyMcMapface,
+    Tonemapping::BlenderFilmic,
+];
+
+/// Checks that the LUT needed by a tonemapper is bundled
+pub fn tonemapping_available(tonemapping: Tonemapping) -> bool {
+    match tonemapping {
+        Tonemapping::AgX | Tonemapping::TonyMcMapface | Tonemapping::BlenderFilmic => {
+            cfg!(feature = "tonemapping_luts")
+        }
+        _ => true,
+    }
+}
+
+pub fn available_tonemappers() -> impl Iterator<Item = Tonemapping> {
+    TONEMAPPERS
+        .into_iter()
+        .filter(|tonemapping| tonemapping_available(*tonemapping))
+}
+
+#[derive(Debug)]
+pub enum CubeLutError {
+    Io(std::io::Error),
+    InvalidLine(usize),
+    MissingSize,
+    /// Only 3D LUTs can be applied to the image
+    Unsupported1d,
+    /// The input range of the LUT isn't 0 to 1
+    UnsupportedDomain,
+    WrongEntryCount {
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl std::fmt::Display for CubeLutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CubeLutError::Io(err) => write!(f, "failed to read the LUT: {err}"),
+            CubeLutError::InvalidLine(line) => write!(f, "invalid LUT line {line}"),
+            CubeLutError::MissingSize => write!(f, "the LUT has no LUT_3D_SIZE"),
+            CubeLutError::Unsupported1d => write!(f, "1D LUTs aren't supported"),
+            CubeLutError::UnsupportedDomain => {
+                write!(f, "only LUTs with a 0 to 1 domain are supported")
+            }
+            CubeLutError::WrongEntryCount { expected, found } => {
+                write!(f, "the LUT has {found} entries, expected {expected}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CubeLutError {}
+
+/// Parses an Adobe/Resolve `.cube` file into a 3D image
+pub fn parse_cube(text: &str) -> Result<Image, CubeLutError> {
+    let mut size = None;
+    let mut entries = vec![];
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = CubeLutError::InvalidLine(index + 1);
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("TITLE") => {}
+            Some("LUT_1D_SIZE") => return Err(CubeLutError::Unsupported1d),
+            Some("LUT_3D_SIZE") => {
+                let parsed = words.next().and_then(|w| w.parse::<u32>().ok());
+                size = Some(parsed.filter(|s| (2..=256).contains(s)).ok_or(invalid)?);
+            }
+            Some(keyword @ ("DOMAIN_MIN" | "DOMAIN_MAX")) => {
+                let expected = if keyword == "DOMAIN_MIN" { 0.0 } else { 1.0 };
+                let values = words.map(str::parse::<f32>).collect::<Result<Vec<_>, _>>();
+                if !values.is_ok_and(|v| v.len() == 3 && v.iter().all(|&v| v == expected)) {
+                    return Err(CubeLutError::UnsupportedDomain);
+                }
+            }
+            _ => {
+                let values = line
+                    .split_whitespace()
+                    .map(str::parse::<f32>)
+                    .collect::<Result<Vec<_>, _>>()
+                    .ok()
+                    .filter(|v| v.len() == 3)
+                    .ok_or(invalid)?;
+                entries.push([values[0], values[1], values[2]]);
+            }
+        }
+    }
+
+    let size = size.ok_or(CubeLutError::MissingSize)?;
+    let expected = (size as usize).pow(3);
+    if entries.len() != expected {
+        return Err(CubeLutError::WrongEntryCount {
+            expected,
+            found: entries.len(),
+        });
+    }
+
+    // Red changes the fastest in the file, like the x axis of the texture. 10 bits per channel
+    // is enough for a LUT and the format is filterable everywhere unlike the float ones.
+    let quantize = |v: f32| (v.clamp(0.0, 1.0) * 1023.0).round() as u32;
+    let data = entries
+        .iter()
+        .flat_map(|&[r, g, b]| {
+            (quantize(r) | quantize(g) << 10 | quantize(b) << 20 | 3 << 30).to_le_bytes()
+        })
+        .collect();
+    Ok(Image::new(
+        Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: size,
+        },
+        TextureDimension::D3,
+        data,
+        TextureFormat::Rgb10a2Unorm,
+        RenderAssetUsages::RENDER_WORLD,
+    ))
+}
+
+#[derive(Default)]
+pub struct CubeLutLoader;
+
+impl AssetLoader for CubeLutLoader {
+    type Asset = Image;
+    type Settings = ();
+    type Error = CubeLutError;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<Image, CubeLutError> {
+        let mut text = String::new();
+        reader
+            .read_to_string(&mut text)
+            .await
+            .map_err(CubeLutError::Io)?;
+        parse_cube(&text)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["cube"]
+    }
+}
+
+/// 3D LUT applied to the tonemapped image of a camera
+#[derive(Component, ExtractComponent, Clone)]
+pub struct ColorLut {
+    pub image: Handle<Image>,
+}
+
+/// Loads the LUT of the scene config and puts it on the camera once it's ready
+pub fn apply_color_lut(
+    mut commands: Commands,
+    scene_config: Res<SceneConfig>,
+    asset_server: Res<AssetServer>,
+    mut pending: Local<Option<Handle<Image>>>,
+    cameras: Query<Entity, With<Camera3d>>,
+) {
+    if scene_config.is_changed() {
+        *pending = scene_config
+            .color_lut
+            .as_ref()
+            .map(|path| asset_server.load(path.clone()));
+        if pending.is_none() {
+            for camera in &cameras {
+                commands.entity(camera).remove::<ColorLut>();
+            }
+        }
+    }
+    let Some(handle) = pending.as_ref() else {
+        return;
+    };
+    match asset_server.load_state(handle) {
+        LoadState::Loaded => {
+            for camera in &cameras {
+                commands.entity(camera).insert(ColorLut {
+                    image: handle.clone(),
+                });
+            }
+            *pending = None;
+        }
+        LoadState::Failed(err) => {
+            warn!("failed to load the color LUT: {err}");
+            for camera in &cameras {
+                commands.entity(camera).remove::<ColorLut>();
+            }
+            *pending = None;
+        }
+        _ => {}
+    }
+}
+
+pub struct ColorLutPlugin;
+
+impl Plugin for ColorLutPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset_loader::<CubeLutLoader>()
+            .add_plugins(ExtractComponentPlugin::<ColorLut>::default());
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app
+            .add_render_graph_node::<ViewNodeRunner<ColorLutNode>>(Core3d, ColorLutLabel)
+            .add_render_graph_edges(
+                Core3d,
+                (
+                    Node3d::Tonemapping,
+                    ColorLutLabel,
+                    Node3d::EndMainPassPostProcessing,
+                ),
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app.init_resource::<ColorLutPipeline>();
+    }
+}
+
+#[derive(RenderLabel, Debug, Clone, Hash, PartialEq, Eq)]
+struct ColorLutLabel;
+
+#[derive(Resource)]
+struct ColorLutPipeline {
+    layout: BindGroupLayout,
+    sampler: Sampler,
+    hdr: CachedRenderPipelineId,
+    sdr: CachedRenderPipelineId,
+}
+
+impl FromWorld for ColorLutPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let layout = render_device.create_bind_group_layout(
+            "color_lut_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::FRAGMENT,
+                (
+                    texture_2d(TextureSampleType::Float { filterable: true }),
+                    sampler(SamplerBindingType::Filtering),
+                    texture_3d(TextureSampleType::Float { filterable: true }),
+                    sampler(SamplerBindingType::Filtering),
+                ),
+            ),
+        );
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..default()
+        });
+
+        let shader = world.resource::<AssetServer>().load("color_lut.wgsl");
+        let descriptor = |format| RenderPipelineDescriptor {
+            label: Some("color_lut_pipeline".into()),
+            layout: vec![layout.clone()],
+            vertex: fullscreen_shader_vertex_state(),
+            fragment: Some(FragmentState {
+                shader: shader.clone(),
+                shader_defs: vec![],
+                entry_point: "fragment".into(),
+                targets: vec![Some(ColorTargetState {
+                    format,
+                    blend: None,
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: default(),
+            depth_stencil: None,
+            multisample: default(),
+            push_constant_ranges: vec![],
+        };
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let hdr = pipeline_cache.queue_render_pipeline(descriptor(ViewTarget::TEXTURE_FORMAT_HDR));
+        let sdr = pipeline_cache.queue_render_pipeline(descriptor(TextureFormat::bevy_default()));
+
+        Self {
+            layout,
+            sampler,
+            hdr,
+            sdr,
+        }
+    }
+}
+
+#[derive(Default)]
+struct ColorLutNode;
+
+impl ViewNode for ColorLutNode {
+    type ViewQuery = (&'static ViewTarget, &'static ColorLut);
+
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        (view_target, color_lut): QueryItem<Self::ViewQuery>,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let color_lut_pipeline = world.resource::<ColorLutPipeline>();
+        let pipeline_id = if view_target.is_hdr() {
+            color_lut_pipeline.hdr
+        } else {
+            color_lut_pipeline.sdr
+        };
+        let Some(pipeline) = world
+            .resource::<PipelineCache>()
+            .get_render_pipeline(pipeline_id)
+        else {
+            return Ok(());
+        };
+        let Some(lut) = world
+            .resource::<RenderAssets<GpuImage>>()
+            .get(&color_lut.image)
+        else {
+            return Ok(());
+        };
+
+        let post_process = view_target.post_process_write();
+        let bind_group = render_context.render_device().create_bind_group(
+            "color_lut_bind_group",
+            &color_lut_pipeline.layout,
+            &BindGroupEntries::sequential((
+                post_process.source,
+                &color_lut_pipeline.sampler,
+                &lut.texture_view,
+                &color_lut_pipeline.sampler,
+            )),
+        );
+        let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+            label: Some("color_lut_pass"),
+            color_attachments: &[Some(RenderPassColorAttachment {
+                view: post_process.destination,
+                resolve_target: None,
+                ops: Operations::default(),
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        render_pass.set_render_pipeline(pipeline);
+        render_pass.set_bind_group(0, &bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+//! Conversion from a color temperature to an RGB color.
+
+use bevy::prelude::*;
+
+/// Range where the approximation in [`kelvin_to_color`] holds
+pub const MIN_KELVIN: f32 = 1000.0;
+pub const MAX_KELVIN: f32 = 40000.0;
+
+/// Color of a black body at the given temperature in kelvin.
+///
+/// Uses Tanner Helland's fit of the blackbody curve, it's within a few percent of the real curve
+/// over [`MIN_KELVIN`]..[`MAX_KELVIN`] and 6500K maps to white. Lower temperatures are the warm
+/// orange of a sunrise, higher ones the blue of an overcast sky.
+pub fn kelvin_to_color(kelvin: f32) -> Color {
+    let temp = kelvin.clamp(MIN_KELVIN, MAX_KELVIN) / 100.0;
+
+    let red = if temp <= 66.0 {
+        255.0
+    } else {
+        329.69873 * (temp - 60.0).powf(-0.13320476)
+    };
+    let green = if temp <= 66.0 {
+        99.4708 * temp.ln() - 161.11957
+    } else {
+        288.12216 * (temp - 60.0).powf(-0.075514846)
+    };
+    let blue = if temp >= 66.0 {
+        255.0
+    } else if temp <= 19.0 {
+        0.0
+    } else {
+        138.51773 * (temp - 10.0).ln() - 305.0448
+    };
+
+    Color::srgb(
+        (red / 255.0).clamp(0.0, 1.0),
+        (green / 255.0).clamp(0.0, 1.0),
+        (blue / 255.0).clamp(0.0, 1.0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daylight_is_white() {
+        let color = kelvin_to_color(6500.0).to_srgba();
+        for channel in [color.red, color.green, color.blue] {
+            assert!((channel - 1.0).abs() < 0.03, "{color:?}");
+        }
+    }
+
+    #[test]
+    fn low_temperatures_are_warm_and_high_ones_cool() {
+        let warm = kelvin_to_color(2000.0).to_srgba();
+        assert!(warm.red > warm.green && warm.green > warm.blue, "{warm:?}");
+        let cool = kelvin_to_color(10000.0).to_srgba();
+        assert!(cool.blue > cool.green && cool.green > cool.red, "{cool:?}");
+    }
+
+    #[test]
+    fn colors_get_bluer_as_the_temperature_rises() {
+        let mut previous = f32::MIN;
+        for kelvin in (MIN_KELVIN as u32..=MAX_KELVIN as u32).step_by(500) {
+            let color = kelvin_to_color(kelvin as f32).to_srgba();
+            let blueness = color.blue - color.red;
+            assert!(
+                blueness >= previous,
+                "{kelvin}K is warmer than the one before"
+            );
+            previous = blueness;
+        }
+    }
+
+    #[test]
+    fn temperatures_are_clamped_to_the_fitted_range() {
+        assert_eq!(kelvin_to_color(0.0), kelvin_to_color(MIN_KELVIN));
+        assert_eq!(kelvin_to_color(100_000.0), kelvin_to_color(MAX_KELVIN));
+    }
+}
@@ -0,0 +1,115 @@
+//! Colors of the debug visualizations, with a palette for color-blind eyes.
+//!
+//! Every gizmo and overlay drawn to debug the scene takes its color from [`DebugPalette`] instead
+//! of a constant, and reads it every frame so switching the palette restyles what's on screen right
+//! away. The default palette pairs red and green, like the visible and occluded tree clusters,
+//! which deuteranopes can't tell apart. `ColorBlind` picks from the viridis ramp instead, the pairs
+//! differ in lightness and go from blue to yellow.
+//!
+//! `debug_palette` in the prefs picks the palette at launch, U switches it for the session.
+
+use bevy::{pbr::wireframe::WireframeConfig, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::overlay::StatsOverlay;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DebugPaletteKind {
+    #[default]
+    Default,
+    ColorBlind,
+}
+
+impl DebugPaletteKind {
+    fn next(self) -> Self {
+        match self {
+            Self::Default => Self::ColorBlind,
+            Self::ColorBlind => Self::Default,
+        }
+    }
+
+    pub fn palette(self) -> DebugPalette {
+        match self {
+            Self::Default => DebugPalette {
+                kind: self,
+                bounds: Color::srgb(1.0, 1.0, 0.0),
+                selected: Color::srgb(0.0, 1.0, 1.0),
+                missing: Color::srgb(1.0, 0.3, 0.3),
+                hovered: Color::srgb(1.0, 0.8, 0.0),
+                visible: Color::srgb(0.0, 1.0, 0.0),
+                occluded: Color::srgb(1.0, 0.0, 0.0),
+                measure: Color::srgb(1.0, 0.9, 0.2),
+                guides: Color::srgba(1.0, 1.0, 1.0, 0.6),
+                wireframe: Color::WHITE,
+            },
+            Self::ColorBlind => DebugPalette {
+                kind: self,
+                bounds: Color::srgb(0.99, 0.91, 0.15),
+                selected: Color::srgb(0.99, 0.91, 0.15),
+                missing: Color::srgb(0.23, 0.32, 0.55),
+                hovered: Color::srgb(0.37, 0.79, 0.38),
+                visible: Color::srgb(0.99, 0.91, 0.15),
+                occluded: Color::srgb(0.23, 0.32, 0.55),
+                measure: Color::srgb(0.99, 0.91, 0.15),
+                guides: Color::srgba(1.0, 1.0, 1.0, 0.6),
+                wireframe: Color::WHITE,
+            },
+        }
+    }
+}
+
+#[derive(Resource, Clone, Debug)]
+pub struct DebugPalette {
+    pub kind: DebugPaletteKind,
+    /// Bounds of the selected category of the scene debug panel
+    pub bounds: Color,
+    /// The inspected tree
+    pub selected: Color,
+    /// The inspected tree once it was removed from the scene
+    pub missing: Color,
+    /// The tree under the cursor in the tree edit mode
+    pub hovered: Color,
+    /// Tree clusters that passed the occlusion test
+    pub visible: Color,
+    pub occluded: Color,
+    /// The measure mode line, its horizontal and vertical legs are drawn fainter
+    pub measure: Color,
+    /// Composition guides of the photo mode
+    pub guides: Color,
+    pub wireframe: Color,
+}
+
+impl Default for DebugPalette {
+    fn default() -> Self {
+        DebugPaletteKind::default().palette()
+    }
+}
+
+pub fn cycle_debug_palette(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut palette: ResMut<DebugPalette>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    if keyboard.just_pressed(KeyCode::KeyU) {
+        *palette = palette.kind.next().palette();
+        info!("{:?} debug palette", palette.kind);
+    }
+    if !palette.is_changed() {
+        return;
+    }
+    if palette.kind == DebugPaletteKind::Default {
+        stats.remove("Debug palette");
+    } else {
+        stats.set("Debug palette", format!("{:?}", palette.kind));
+    }
+}
+
+/// The wireframes are drawn by bevy, their color lives in its config
+pub fn sync_wireframe_color(
+    palette: Res<DebugPalette>,
+    mut wireframe_config: ResMut<WireframeConfig>,
+) {
+    if palette.is_changed() {
+        wireframe_config.default_color = palette.wireframe;
+    }
+}
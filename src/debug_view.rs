@@ -0,0 +1,194 @@
+//! Looking at what's in the G-buffer.
+//!
+//! G cycles through the depth, the normals, the base color, the metallic and roughness, the motion
+//! vectors and the SSAO of the main camera. The selected target is drawn over the whole screen by
+//! a triangle reading the prepass and deferred textures, back to off despawns it and nothing else
+//! was touched. The targets a camera doesn't have, like the base color with the forward renderer,
+//! show up magenta. Only built with the `debug_views` feature.
+//!
+//! The volumetric fog is applied in place to the view target in this version of bevy, there's no
+//! texture of its contribution to show.
+
+use bevy::{
+    core_pipeline::prepass::{DeferredPrepass, MotionVectorPrepass, NormalPrepass},
+    pbr::{
+        MaterialPipeline, MaterialPipelineKey, NotShadowCaster, ScreenSpaceAmbientOcclusionSettings,
+    },
+    prelude::*,
+    render::{
+        mesh::{MeshVertexBufferLayoutRef, PrimitiveTopology},
+        render_asset::RenderAssetUsages,
+        render_resource::{
+            AsBindGroup, CompareFunction, RenderPipelineDescriptor, ShaderRef,
+            SpecializedMeshPipelineError,
+        },
+        view::NoFrustumCulling,
+    },
+};
+
+use crate::{
+    camera_controller::CameraController, overlay::StatsOverlay, split_view::SecondaryCamera,
+};
+
+pub struct DebugViewPlugin;
+
+impl Plugin for DebugViewPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(MaterialPlugin::<DebugViewMaterial> {
+            prepass_enabled: false,
+            shadows_enabled: false,
+            ..default()
+        })
+        .init_resource::<DebugView>()
+        .add_systems(Update, cycle_debug_view);
+    }
+}
+
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum DebugView {
+    #[default]
+    Off,
+    Depth,
+    Normals,
+    BaseColor,
+    MetallicRoughness,
+    MotionVectors,
+    AmbientOcclusion,
+}
+
+impl DebugView {
+    fn next(self) -> Self {
+        match self {
+            Self::Off => Self::Depth,
+            Self::Depth => Self::Normals,
+            Self::Normals => Self::BaseColor,
+            Self::BaseColor => Self::MetallicRoughness,
+            Self::MetallicRoughness => Self::MotionVectors,
+            Self::MotionVectors => Self::AmbientOcclusion,
+            Self::AmbientOcclusion => Self::Off,
+        }
+    }
+}
+
+#[derive(Asset, TypePath, AsBindGroup, Clone)]
+pub struct DebugViewMaterial {
+    /// Index of the [`DebugView`], 0 is never drawn
+    #[uniform(0)]
+    view: u32,
+}
+
+impl Material for DebugViewMaterial {
+    fn vertex_shader() -> ShaderRef {
+        "debug_view.wgsl".into()
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        "debug_view.wgsl".into()
+    }
+
+    // Drawn in the transparent pass, after the deferred lighting and the SSAO
+    fn alpha_mode(&self) -> AlphaMode {
+        AlphaMode::Blend
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayoutRef,
+        _key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        let vertex_layout = layout
+            .0
+            .get_layout(&[Mesh::ATTRIBUTE_POSITION.at_shader_location(0)])?;
+        descriptor.vertex.buffers = vec![vertex_layout];
+        descriptor.primitive.cull_mode = None;
+        if let Some(depth_stencil) = descriptor.depth_stencil.as_mut() {
+            depth_stencil.depth_compare = CompareFunction::Always;
+            depth_stencil.depth_write_enabled = false;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Component)]
+struct DebugViewOverlay;
+
+/// The positions are ignored, the vertex shader places the triangle over the screen
+fn fullscreen_triangle() -> Mesh {
+    Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::RENDER_WORLD,
+    )
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, vec![[0.0; 3]; 3])
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn cycle_debug_view(
+    mut commands: Commands,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut view: ResMut<DebugView>,
+    mut stats: ResMut<StatsOverlay>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<DebugViewMaterial>>,
+    overlay: Query<(Entity, &Handle<DebugViewMaterial>), With<DebugViewOverlay>>,
+    camera: Query<
+        (
+            Has<DeferredPrepass>,
+            Has<NormalPrepass>,
+            Has<MotionVectorPrepass>,
+            Has<ScreenSpaceAmbientOcclusionSettings>,
+        ),
+        (With<CameraController>, Without<SecondaryCamera>),
+    >,
+) {
+    if !keyboard.just_pressed(KeyCode::KeyG) {
+        return;
+    }
+    *view = view.next();
+
+    if *view == DebugView::Off {
+        for (entity, _) in &overlay {
+            commands.entity(entity).despawn_recursive();
+        }
+        stats.remove("Debug view");
+        return;
+    }
+
+    let material = DebugViewMaterial { view: *view as u32 };
+    match overlay.get_single() {
+        Ok((_, handle)) => {
+            if let Some(existing) = materials.get_mut(handle) {
+                *existing = material;
+            }
+        }
+        Err(_) => {
+            commands.spawn((
+                MaterialMeshBundle {
+                    mesh: meshes.add(fullscreen_triangle()),
+                    material: materials.add(material),
+                    ..default()
+                },
+                NoFrustumCulling,
+                NotShadowCaster,
+                DebugViewOverlay,
+            ));
+        }
+    }
+
+    let (deferred, normal_prepass, motion_vectors, ssao) = camera.get_single().unwrap_or_default();
+    let available = match *view {
+        DebugView::Off | DebugView::Depth => true,
+        DebugView::Normals => deferred || normal_prepass,
+        DebugView::BaseColor | DebugView::MetallicRoughness => deferred,
+        DebugView::MotionVectors => motion_vectors,
+        DebugView::AmbientOcclusion => ssao,
+    };
+    stats.set(
+        "Debug view",
+        if available {
+            format!("{:?}, G for the next", *view)
+        } else {
+            format!("{:?} isn't rendered by this camera", *view)
+        },
+    );
+}
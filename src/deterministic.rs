@@ -0,0 +1,150 @@
+//! Deterministic procedural animation, for recordings that match from one take to the next.
+//!
+//! The water, the wind, the dust, the sky and the puddles all animate from the virtual clock,
+//! which normally advances by the frame delta. Two takes of the same camera path at different
+//! frame rates sample them at different times, and what's integrated frame by frame, like the
+//! drifting debris, ends up somewhere else.
+//!
+//! In deterministic mode the virtual clock only moves by whole fixed timesteps, taken from an
+//! accumulator of the real time. The integrations go through [`DeterministicClock::deltas`] to run
+//! once per step, so the scene after N steps is the same however the steps fell in frames. The
+//! flythrough moves with the steps too and interpolates the leftover time between them, the camera
+//! stays smooth at any frame rate.
+//!
+//! `--deterministic` or `deterministic: true` in the prefs turns it on. The scenarios and the
+//! print renders started from the command line always use it.
+
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::cli::CliArgs;
+
+#[derive(Resource, Default)]
+pub struct DeterministicClock {
+    pub enabled: bool,
+    /// Real time not turned into a step yet
+    accumulator: Duration,
+    /// Steps the virtual clock advanced by this frame
+    pub steps: u32,
+    step: Duration,
+}
+
+impl DeterministicClock {
+    /// Deltas to integrate the frame with, one per fixed step in deterministic mode
+    pub fn deltas(&self, frame_delta: f32) -> impl Iterator<Item = f32> {
+        if self.enabled {
+            std::iter::repeat(self.step.as_secs_f32()).take(self.steps as usize)
+        } else {
+            std::iter::repeat(frame_delta).take(1)
+        }
+    }
+
+    /// How far into the next step the real time is, between 0 and 1
+    pub fn overstep_fraction(&self) -> f32 {
+        if self.step.is_zero() {
+            return 0.0;
+        }
+        self.accumulator.as_secs_f32() / self.step.as_secs_f32()
+    }
+}
+
+/// Turns the deterministic mode on from the command line or the prefs, needs to run after
+/// [`pick_quality_preset`](crate::quality::pick_quality_preset) which reads the prefs
+pub fn setup_deterministic_clock(world: &mut World) {
+    let cli = world.resource::<CliArgs>();
+    let enabled = cli.deterministic || cli.scenario.is_some() || cli.render.is_some();
+    if enabled {
+        info!("deterministic mode, the scene advances by fixed steps");
+        // The real time only reaches the virtual clock through `advance_deterministic_clock`
+        world
+            .resource_mut::<Time<Virtual>>()
+            .set_relative_speed(0.0);
+    }
+    world.insert_resource(DeterministicClock {
+        enabled,
+        ..default()
+    });
+}
+
+/// Advances the virtual clock by the whole steps in the accumulated real time, runs right after the
+/// clocks are updated so every system of the frame sees the steps
+pub fn advance_deterministic_clock(
+    mut clock: ResMut<DeterministicClock>,
+    real_time: Res<Time<Real>>,
+    fixed_time: Res<Time<Fixed>>,
+    mut virtual_time: ResMut<Time<Virtual>>,
+    mut time: ResMut<Time>,
+) {
+    if !clock.enabled {
+        return;
+    }
+    clock.step = fixed_time.timestep();
+    // Paused, the frame step advances the clock by a single step on its own
+    if virtual_time.is_paused() {
+        clock.steps = 0;
+        return;
+    }
+    // Like the virtual clock, a long frame doesn't make the scene jump ahead
+    clock.accumulator += real_time.delta().min(virtual_time.max_delta());
+    let steps = (clock.accumulator.as_nanos() / clock.step.as_nanos()) as u32;
+    clock.accumulator -= clock.step * steps;
+    clock.steps = steps;
+    virtual_time.advance_by(clock.step * steps);
+    *time = virtual_time.as_generic();
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::time::{TimePlugin, TimeSystem, TimeUpdateStrategy};
+
+    use super::*;
+    use crate::{
+        weather::{self, Wetness},
+        SceneConfig,
+    };
+
+    /// Updates an app in deterministic mode with the frame durations in milliseconds, over and
+    /// over, until 10 virtual seconds passed. Gives the time the shaders animate from and the
+    /// puddles integrated frame by frame.
+    fn run_for_10_seconds(frames: &[u64]) -> (Duration, f32) {
+        let mut app = App::new();
+        app.add_plugins(TimePlugin)
+            .insert_resource(CliArgs {
+                deterministic: true,
+                ..default()
+            })
+            .insert_resource(SceneConfig {
+                rain_intensity: 1.0,
+                ..default()
+            })
+            .init_resource::<Wetness>()
+            .add_systems(First, advance_deterministic_clock.after(TimeSystem))
+            .add_systems(Update, weather::update_wetness);
+        setup_deterministic_clock(app.world_mut());
+
+        for &frame in frames.iter().cycle() {
+            app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+                frame,
+            )));
+            app.update();
+            if app.world().resource::<Time<Virtual>>().elapsed() >= Duration::from_secs(10) {
+                break;
+            }
+        }
+        (
+            app.world().resource::<Time>().elapsed(),
+            app.world().resource::<Wetness>().puddle_amount,
+        )
+    }
+
+    #[test]
+    fn different_frame_rates_give_the_same_scene() {
+        // Shorter than the 1/64s step, the clock can't overshoot the 10 seconds
+        let steady = run_for_10_seconds(&[7]);
+        let uneven = run_for_10_seconds(&[3, 15, 9, 1, 12]);
+        assert_eq!(steady.0, Duration::from_secs(10));
+        assert!(steady.1 > 0.0);
+        assert_eq!(steady, uneven);
+    }
+}
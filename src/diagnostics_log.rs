@@ -0,0 +1,349 @@
+//! The recent log messages in the app.
+//!
+//! A tracing layer added to the log plugin keeps the last [`CAPACITY`] messages of the crate and
+//! the warnings and errors of everything else, like the assets that failed to load. They show up
+//! in the release builds too, where there's often no terminal to read them.
+//!
+//! L toggles the panel listing them, Shift+L cycles the lowest severity listed. An error logged
+//! while the panel is closed flashes a small counter at the top of the screen until the panel is
+//! opened.
+
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use bevy::{
+    log::{
+        tracing_subscriber::{layer::Context, Layer},
+        BoxedLayer,
+    },
+    prelude::*,
+    utils::{
+        tracing::{
+            field::{Field, Visit},
+            Event, Level, Subscriber,
+        },
+        Instant,
+    },
+};
+
+use crate::{accessibility::Flash, SceneConfig};
+
+/// Messages kept, the oldest are dropped first
+const CAPACITY: usize = 200;
+/// Messages listed in the panel
+const PANEL_LINES: usize = 30;
+/// How long the error counter flashes after an error
+const FLASH_SECONDS: f32 = 3.0;
+/// Flashing of the error counter, toned down by `reduce_flashing`
+const ERROR_FLASH: Flash = Flash {
+    amplitude: 0.4,
+    hz: 2.0,
+};
+/// Alpha of the error counter once it stopped flashing
+const ERROR_ALPHA: f32 = 0.6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    fn next(self) -> Self {
+        match self {
+            Self::Info => Self::Warning,
+            Self::Warning => Self::Error,
+            Self::Error => Self::Info,
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            Self::Info => Color::srgb(0.85, 0.85, 0.85),
+            Self::Warning => Color::srgb(1.0, 0.8, 0.3),
+            Self::Error => Color::srgb(1.0, 0.3, 0.3),
+        }
+    }
+}
+
+struct LogEntry {
+    /// Since the log plugin started
+    time: Duration,
+    severity: Severity,
+    /// Module path of the message, without the crate name for the crate's own messages
+    target: String,
+    message: String,
+}
+
+/// Filled by the tracing layer from any thread, read by the panel
+#[derive(Default)]
+struct SharedLog {
+    entries: Mutex<VecDeque<LogEntry>>,
+    /// Every message ever logged, tells the panel when to update
+    logged: AtomicUsize,
+    errors: AtomicUsize,
+}
+
+#[derive(Resource)]
+pub struct DiagnosticsLog {
+    shared: Arc<SharedLog>,
+    open: bool,
+    min_severity: Severity,
+    /// Errors logged when the panel was last open
+    seen_errors: usize,
+}
+
+impl DiagnosticsLog {
+    /// Copies the entries at `min_severity` or above, the most recent last
+    fn entries(&self, min_severity: Severity, limit: usize) -> Vec<(Duration, Severity, String)> {
+        // Nothing can be logged while the lock is held, the layer would wait for it forever
+        let entries = self
+            .shared
+            .entries
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let mut matching: Vec<_> = entries
+            .iter()
+            .rev()
+            .filter(|entry| entry.severity >= min_severity)
+            .take(limit)
+            .map(|entry| {
+                let line = if entry.target.is_empty() {
+                    entry.message.clone()
+                } else {
+                    format!("{}: {}", entry.target, entry.message)
+                };
+                (entry.time, entry.severity, line)
+            })
+            .collect();
+        matching.reverse();
+        matching
+    }
+}
+
+struct DiagnosticsLayer {
+    shared: Arc<SharedLog>,
+    start: Instant,
+}
+
+/// Collects the message and the other fields of an event on a single line
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{value:?}");
+        } else {
+            let _ = write!(self.fields, " {}={value:?}", field.name());
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for DiagnosticsLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let target = metadata.target();
+        let own = target.starts_with(env!("CARGO_CRATE_NAME"));
+        let severity = match *metadata.level() {
+            Level::ERROR => Severity::Error,
+            Level::WARN => Severity::Warning,
+            Level::INFO if own => Severity::Info,
+            _ => return,
+        };
+
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let target = if own {
+            target
+                .trim_start_matches(env!("CARGO_CRATE_NAME"))
+                .trim_start_matches("::")
+        } else {
+            target
+        };
+        let entry = LogEntry {
+            time: self.start.elapsed(),
+            severity,
+            target: target.to_string(),
+            message: visitor.message + &visitor.fields,
+        };
+
+        let mut entries = self
+            .shared
+            .entries
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if entries.len() == CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+        self.shared.logged.fetch_add(1, Ordering::Relaxed);
+        if severity == Severity::Error {
+            self.shared.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The `custom_layer` of the log plugin, it also adds the [`DiagnosticsLog`] reading the layer
+pub fn capture_layer(app: &mut App) -> Option<BoxedLayer> {
+    let shared = Arc::new(SharedLog::default());
+    app.insert_resource(DiagnosticsLog {
+        shared: shared.clone(),
+        open: false,
+        min_severity: Severity::Info,
+        seen_errors: 0,
+    });
+    Some(Box::new(DiagnosticsLayer {
+        shared,
+        start: Instant::now(),
+    }))
+}
+
+#[derive(Component)]
+pub struct DiagnosticsLogText;
+
+#[derive(Component)]
+pub struct ErrorIndicatorText;
+
+pub fn spawn_diagnostics_panel(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::default()
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                bottom: Val::Px(5.0),
+                left: Val::Percent(25.0),
+                width: Val::Percent(50.0),
+                ..default()
+            })
+            .with_background_color(Color::srgba(0.0, 0.0, 0.0, 0.7)),
+        Visibility::Hidden,
+        DiagnosticsLogText,
+    ));
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 14.0,
+                color: Severity::Error.color(),
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(5.0),
+            left: Val::Percent(50.0),
+            ..default()
+        }),
+        Visibility::Hidden,
+        ErrorIndicatorText,
+    ));
+}
+
+pub fn diagnostics_log_input(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut log: ResMut<DiagnosticsLog>,
+    mut panel: Query<&mut Visibility, With<DiagnosticsLogText>>,
+) {
+    if !keyboard.just_pressed(KeyCode::KeyL) {
+        return;
+    }
+    if keyboard.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+        log.min_severity = log.min_severity.next();
+        log.open = true;
+    } else {
+        log.open = !log.open;
+    }
+    for mut visibility in &mut panel {
+        *visibility = if log.open {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+    }
+}
+
+/// Lists the recent messages in the panel and flashes the error counter while it's closed
+pub fn update_diagnostics_panel(
+    time: Res<Time<Real>>,
+    scene_config: Option<Res<SceneConfig>>,
+    mut log: ResMut<DiagnosticsLog>,
+    mut panel: Query<&mut Text, (With<DiagnosticsLogText>, Without<ErrorIndicatorText>)>,
+    mut indicator: Query<(&mut Text, &mut Visibility), With<ErrorIndicatorText>>,
+    mut last_logged: Local<usize>,
+    mut last_errors: Local<usize>,
+    mut flash_start: Local<Option<f32>>,
+) {
+    let logged = log.shared.logged.load(Ordering::Relaxed);
+    let errors = log.shared.errors.load(Ordering::Relaxed);
+    // Only written when it changes so the panel isn't rebuilt every frame
+    if log.open && log.seen_errors != errors {
+        log.seen_errors = errors;
+    }
+    let unseen = errors - log.seen_errors;
+
+    if log.open && (log.is_changed() || logged != *last_logged) {
+        *last_logged = logged;
+        let mut sections = vec![TextSection::new(
+            format!(
+                "Log, {:?} and above (L to close, Shift+L for the severity)\n",
+                log.min_severity
+            ),
+            TextStyle {
+                font_size: 14.0,
+                ..default()
+            },
+        )];
+        for (time, severity, line) in log.entries(log.min_severity, PANEL_LINES) {
+            sections.push(TextSection::new(
+                format!("\n{:>8.1}s {line}", time.as_secs_f32()),
+                TextStyle {
+                    font_size: 14.0,
+                    color: severity.color(),
+                    ..default()
+                },
+            ));
+        }
+        for mut text in &mut panel {
+            text.sections.clone_from(&sections);
+        }
+    }
+
+    // Flashes again for every new error
+    let now = time.elapsed_seconds();
+    if unseen == 0 {
+        *flash_start = None;
+    } else if errors != *last_errors {
+        *flash_start = Some(now);
+    }
+    *last_errors = errors;
+    let flash = scene_config.map_or(ERROR_FLASH, |scene_config| {
+        scene_config.accessibility.flash(ERROR_FLASH)
+    });
+    for (mut text, mut visibility) in &mut indicator {
+        let Some(since) = *flash_start else {
+            *visibility = Visibility::Hidden;
+            continue;
+        };
+        *visibility = Visibility::Inherited;
+        let elapsed = now - since;
+        let alpha = if elapsed < FLASH_SECONDS {
+            flash.intensity(elapsed)
+        } else {
+            ERROR_ALPHA
+        };
+        let plural = if unseen == 1 { "" } else { "s" };
+        text.sections[0].value = format!("{unseen} new error{plural}, L for the log");
+        text.sections[0].style.color = Severity::Error.color().with_alpha(alpha);
+    }
+}
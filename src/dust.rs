@@ -0,0 +1,166 @@
+//! Dust motes drifting in the light shafts.
+//!
+//! All the particles are in a single mesh and are animated in the vertex shader, the volume wraps
+//! around the camera so the CPU never touches them after spawning.
+
+use bevy::{
+    math::vec4,
+    pbr::{MaterialPipeline, MaterialPipelineKey, NotShadowCaster},
+    prelude::*,
+    render::{
+        mesh::{Indices, MeshVertexBufferLayoutRef, PrimitiveTopology},
+        render_asset::RenderAssetUsages,
+        render_resource::{
+            AsBindGroup, RenderPipelineDescriptor, ShaderRef, ShaderType,
+            SpecializedMeshPipelineError,
+        },
+        view::NoFrustumCulling,
+    },
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{sky::Moonlight, SceneConfig};
+
+const PARTICLE_COUNT: usize = 512;
+
+#[derive(Clone, Copy, ShaderType, Debug, Default)]
+pub struct DustSettings {
+    /// xyz: direction the light travels, w: brightness
+    light: Vec4,
+    /// x: size of the volume around the camera, y: fraction of visible particles,
+    /// z: particle size, w: drift speed
+    params: Vec4,
+}
+
+#[derive(Asset, TypePath, AsBindGroup, Clone)]
+pub struct DustMaterial {
+    #[uniform(0)]
+    settings: DustSettings,
+}
+
+impl Material for DustMaterial {
+    fn vertex_shader() -> ShaderRef {
+        "dust.wgsl".into()
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        "dust.wgsl".into()
+    }
+
+    fn alpha_mode(&self) -> AlphaMode {
+        AlphaMode::Add
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayoutRef,
+        _key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        let vertex_layout = layout.0.get_layout(&[
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_UV_0.at_shader_location(1),
+        ])?;
+        descriptor.vertex.buffers = vec![vertex_layout];
+        Ok(())
+    }
+}
+
+#[derive(Component)]
+pub struct DustMotes;
+
+/// Every particle is a quad, the position stores a random point in the unit cube that is shared by
+/// the 4 vertices and the uv is used to expand the quad in the vertex shader
+fn dust_mesh() -> Mesh {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut positions = Vec::with_capacity(PARTICLE_COUNT * 4);
+    let mut uvs = Vec::with_capacity(PARTICLE_COUNT * 4);
+    let mut indices = Vec::with_capacity(PARTICLE_COUNT * 6);
+    for i in 0..PARTICLE_COUNT as u32 {
+        let position: [f32; 3] = rng.gen();
+        for uv in [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]] {
+            positions.push(position);
+            uvs.push(uv);
+        }
+        let base = i * 4;
+        indices.extend([base, base + 1, base + 2, base, base + 2, base + 3]);
+    }
+    Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::RENDER_WORLD,
+    )
+    .with_inserted_indices(Indices::U32(indices))
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+}
+
+pub fn spawn_dust_motes(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<DustMaterial>>,
+) {
+    commands.spawn((
+        MaterialMeshBundle {
+            mesh: meshes.add(dust_mesh()),
+            material: materials.add(DustMaterial {
+                settings: DustSettings::default(),
+            }),
+            visibility: Visibility::Hidden,
+            ..default()
+        },
+        // The particles are placed around the camera in the shader
+        NoFrustumCulling,
+        NotShadowCaster,
+        DustMotes,
+    ));
+}
+
+pub fn update_dust_motes(
+    scene_config: Res<SceneConfig>,
+    light: Query<&GlobalTransform, (With<DirectionalLight>, Without<Moonlight>)>,
+    mut dust: Query<(&mut Visibility, &Handle<DustMaterial>), With<DustMotes>>,
+    mut materials: ResMut<Assets<DustMaterial>>,
+) {
+    let Ok(light) = light.get_single() else {
+        return;
+    };
+    let light_direction = light.forward();
+    // The motes are most visible when the sun is low
+    let low_sun = 1.0 - light_direction.y.abs();
+    let brightness = scene_config.dust_brightness * low_sun * low_sun;
+    let light = vec4(
+        light_direction.x,
+        light_direction.y,
+        light_direction.z,
+        brightness,
+    );
+
+    for (mut visibility, handle) in &mut dust {
+        let enabled = scene_config.dust_density > 0.0 && scene_config.dust_brightness > 0.0;
+        let target = if enabled {
+            Visibility::Visible
+        } else {
+            Visibility::Hidden
+        };
+        if *visibility != target {
+            *visibility = target;
+        }
+        if !enabled {
+            continue;
+        }
+
+        // Only touch the material when something changed to avoid preparing it every frame
+        let unchanged = materials
+            .get(handle)
+            .is_some_and(|m| m.settings.light == light);
+        if unchanged && !scene_config.is_changed() {
+            continue;
+        }
+        if let Some(material) = materials.get_mut(handle) {
+            material.settings = DustSettings {
+                light,
+                params: vec4(16.0, scene_config.dust_density.min(1.0), 0.015, 0.05),
+            };
+        }
+    }
+}
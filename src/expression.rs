@@ -0,0 +1,501 @@
+//! Formulas for the per-position parameters of the terrain config.
+//!
+//! An expression like `0.5 * (1 - height / 80)` is parsed once per generation into a small tree
+//! and evaluated for every placement candidate or terrain vertex. The variables are the ones in
+//! [`ExpressionInputs`], the functions are `min(a, b)`, `max(a, b)`, `clamp(x, low, high)` and
+//! `noise(n, freq)`, a perlin noise in 0..1 over the world position where `n` picks one of the
+//! noises seeded from the terrain seed. Both arguments of `noise` have to be numbers.
+
+use std::fmt::Display;
+
+use bevy::prelude::*;
+use noise::{NoiseFn, Perlin};
+
+use crate::validation::ValidationIssue;
+
+/// Formulas for the parameters of the terrain config that can vary with the position, a missing
+/// one keeps the plain fields
+#[derive(Reflect, Clone, Debug, Default, PartialEq)]
+pub struct TerrainExpressions {
+    /// Multiplies the density of the trees
+    pub density: Option<String>,
+    /// Multiplies the scale of the trees
+    pub scale: Option<String>,
+    /// Snow cover of the ground in 0..1, replaces the snow line of the scene config on the ground
+    pub snow: Option<String>,
+}
+
+impl TerrainExpressions {
+    /// Drops the expressions that don't parse and reports why
+    pub fn validate(&mut self) -> Vec<ValidationIssue> {
+        let mut issues = vec![];
+        for (field, source) in [
+            ("expressions.density", &mut self.density),
+            ("expressions.scale", &mut self.scale),
+            ("expressions.snow", &mut self.snow),
+        ] {
+            let Some(Err(err)) = source.as_deref().map(|source| Expression::parse(source, 0))
+            else {
+                continue;
+            };
+            issues.push(ValidationIssue {
+                field,
+                value: format!("{:?}", source.take().unwrap_or_default()),
+                allowed: format!("a valid expression, {err}"),
+                substituted: "None".into(),
+            });
+        }
+        issues
+    }
+}
+
+/// Parses an expression field of the config, the invalid ones were already reported and removed
+/// by the validation
+pub fn parse_field(source: &Option<String>, seed: u32) -> Option<Expression> {
+    Expression::parse(source.as_deref()?, seed).ok()
+}
+
+/// Height above the water where the ground is considered dry
+const MOISTURE_HEIGHT: f32 = 10.0;
+
+/// What an expression can read about the position it's evaluated at
+pub struct ExpressionInputs {
+    /// World space position, only read by the noise
+    pub position: Vec3,
+    pub steepness: f32,
+    /// Height above the water surface, negative under it
+    pub distance_to_water: f32,
+    /// 1 at the water level and 0 from [`MOISTURE_HEIGHT`] above it
+    pub moisture: f32,
+}
+
+impl ExpressionInputs {
+    pub fn new(position: Vec3, steepness: f32, water_level: f32) -> Self {
+        let distance_to_water = position.y - water_level;
+        Self {
+            position,
+            steepness,
+            distance_to_water,
+            moisture: (1.0 - distance_to_water / MOISTURE_HEIGHT).clamp(0.0, 1.0),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Variable {
+    Height,
+    Steepness,
+    Moisture,
+    DistanceToWater,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Function {
+    Min,
+    Max,
+    Clamp,
+}
+
+#[derive(Clone)]
+enum Node {
+    Number(f32),
+    Variable(Variable),
+    Negate(Box<Node>),
+    Binary(Operator, Box<Node>, Box<Node>),
+    Call(Function, Vec<Node>),
+    Noise { noise: Perlin, frequency: f64 },
+}
+
+/// A parsed expression
+#[derive(Clone)]
+pub struct Expression {
+    root: Node,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// Offset in characters of the error in the expression
+    pub position: usize,
+    pub message: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at character {}", self.message, self.position)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f32),
+    Identifier(String),
+    Symbol(char),
+    End,
+}
+
+struct Parser {
+    /// Tokens with their position in the expression
+    tokens: Vec<(Token, usize)>,
+    next: usize,
+    seed: u32,
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let number = text.parse().map_err(|_| ParseError {
+                position: start,
+                message: format!("{text:?} isn't a number"),
+            })?;
+            tokens.push((Token::Number(number), start));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push((Token::Identifier(chars[start..i].iter().collect()), start));
+        } else if "+-*/^(),".contains(c) {
+            tokens.push((Token::Symbol(c), start));
+            i += 1;
+        } else {
+            return Err(ParseError {
+                position: start,
+                message: format!("unexpected {c:?}"),
+            });
+        }
+    }
+    tokens.push((Token::End, chars.len()));
+    Ok(tokens)
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.next].0
+    }
+
+    fn position(&self) -> usize {
+        self.tokens[self.next].1
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.next].0.clone();
+        if token != Token::End {
+            self.next += 1;
+        }
+        token
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            position: self.position(),
+            message: message.into(),
+        })
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), ParseError> {
+        if *self.peek() == Token::Symbol(symbol) {
+            self.advance();
+            Ok(())
+        } else {
+            self.error(format!("expected {symbol:?}"))
+        }
+    }
+
+    /// Additions and subtractions, the lowest precedence
+    fn sum(&mut self) -> Result<Node, ParseError> {
+        let mut node = self.product()?;
+        loop {
+            let operator = match self.peek() {
+                Token::Symbol('+') => Operator::Add,
+                Token::Symbol('-') => Operator::Subtract,
+                _ => return Ok(node),
+            };
+            self.advance();
+            node = Node::Binary(operator, Box::new(node), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Node, ParseError> {
+        let mut node = self.unary()?;
+        loop {
+            let operator = match self.peek() {
+                Token::Symbol('*') => Operator::Multiply,
+                Token::Symbol('/') => Operator::Divide,
+                _ => return Ok(node),
+            };
+            self.advance();
+            node = Node::Binary(operator, Box::new(node), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, ParseError> {
+        if *self.peek() == Token::Symbol('-') {
+            self.advance();
+            return Ok(Node::Negate(Box::new(self.unary()?)));
+        }
+        let base = self.atom()?;
+        // Right associative, `2^3^2` is `2^(3^2)`
+        if *self.peek() == Token::Symbol('^') {
+            self.advance();
+            return Ok(Node::Binary(
+                Operator::Power,
+                Box::new(base),
+                Box::new(self.unary()?),
+            ));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<Node, ParseError> {
+        let position = self.position();
+        match self.advance() {
+            Token::Number(number) => Ok(Node::Number(number)),
+            Token::Symbol('(') => {
+                let node = self.sum()?;
+                self.expect(')')?;
+                Ok(node)
+            }
+            Token::Identifier(name) if *self.peek() == Token::Symbol('(') => {
+                self.advance();
+                self.call(&name, position)
+            }
+            Token::Identifier(name) => match name.as_str() {
+                "height" => Ok(Node::Variable(Variable::Height)),
+                "steepness" => Ok(Node::Variable(Variable::Steepness)),
+                "moisture" => Ok(Node::Variable(Variable::Moisture)),
+                "distance_to_water" => Ok(Node::Variable(Variable::DistanceToWater)),
+                _ => Err(ParseError {
+                    position,
+                    message: format!("unknown variable {name:?}"),
+                }),
+            },
+            Token::End => Err(ParseError {
+                position,
+                message: "unexpected end of the expression".into(),
+            }),
+            Token::Symbol(symbol) => Err(ParseError {
+                position,
+                message: format!("unexpected {symbol:?}"),
+            }),
+        }
+    }
+
+    /// Arguments of a function, the opening parenthesis is already consumed
+    fn call(&mut self, name: &str, position: usize) -> Result<Node, ParseError> {
+        let mut arguments = vec![];
+        if *self.peek() != Token::Symbol(')') {
+            arguments.push(self.sum()?);
+            while *self.peek() == Token::Symbol(',') {
+                self.advance();
+                arguments.push(self.sum()?);
+            }
+        }
+        self.expect(')')?;
+
+        let (function, arity) = match name {
+            "min" => (Function::Min, 2),
+            "max" => (Function::Max, 2),
+            "clamp" => (Function::Clamp, 3),
+            "noise" => {
+                let [Node::Number(n), Node::Number(frequency)] = arguments.as_slice() else {
+                    return Err(ParseError {
+                        position,
+                        message: "noise expects two numbers, noise(n, freq)".into(),
+                    });
+                };
+                return Ok(Node::Noise {
+                    noise: Perlin::new(self.seed.wrapping_add(*n as u32)),
+                    frequency: *frequency as f64,
+                });
+            }
+            _ => {
+                return Err(ParseError {
+                    position,
+                    message: format!("unknown function {name:?}"),
+                })
+            }
+        };
+        if arguments.len() != arity {
+            return Err(ParseError {
+                position,
+                message: format!("{name} expects {arity} arguments, got {}", arguments.len()),
+            });
+        }
+        Ok(Node::Call(function, arguments))
+    }
+}
+
+impl Expression {
+    /// Parses an expression, `seed` seeds the noises
+    pub fn parse(source: &str, seed: u32) -> Result<Self, ParseError> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            next: 0,
+            seed,
+        };
+        let root = parser.sum()?;
+        if *parser.peek() != Token::End {
+            return parser.error("expected an operator");
+        }
+        Ok(Self { root })
+    }
+
+    pub fn evaluate(&self, inputs: &ExpressionInputs) -> f32 {
+        evaluate(&self.root, inputs)
+    }
+}
+
+fn evaluate(node: &Node, inputs: &ExpressionInputs) -> f32 {
+    match node {
+        Node::Number(number) => *number,
+        Node::Variable(Variable::Height) => inputs.position.y,
+        Node::Variable(Variable::Steepness) => inputs.steepness,
+        Node::Variable(Variable::Moisture) => inputs.moisture,
+        Node::Variable(Variable::DistanceToWater) => inputs.distance_to_water,
+        Node::Negate(node) => -evaluate(node, inputs),
+        Node::Binary(operator, a, b) => {
+            let (a, b) = (evaluate(a, inputs), evaluate(b, inputs));
+            match operator {
+                Operator::Add => a + b,
+                Operator::Subtract => a - b,
+                Operator::Multiply => a * b,
+                Operator::Divide => a / b,
+                Operator::Power => a.powf(b),
+            }
+        }
+        Node::Call(function, arguments) => {
+            let argument = |i: usize| evaluate(&arguments[i], inputs);
+            match function {
+                Function::Min => argument(0).min(argument(1)),
+                Function::Max => argument(0).max(argument(1)),
+                // Not `f32::clamp`, it panics on inverted bounds
+                Function::Clamp => argument(0).max(argument(1)).min(argument(2)),
+            }
+        }
+        Node::Noise { noise, frequency } => {
+            let p = inputs.position.xz().as_dvec2() * *frequency;
+            (noise.get([p.x, p.y]) as f32 * 0.5 + 0.5).clamp(0.0, 1.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inputs() -> ExpressionInputs {
+        ExpressionInputs::new(vec3(10.0, 8.0, -4.0), 0.25, 3.0)
+    }
+
+    fn evaluate(source: &str) -> f32 {
+        Expression::parse(source, 42).unwrap().evaluate(&inputs())
+    }
+
+    fn parse_error(source: &str) -> ParseError {
+        Expression::parse(source, 42).err().unwrap()
+    }
+
+    #[test]
+    fn operators_follow_the_usual_precedence() {
+        assert_eq!(evaluate("1 + 2 * 3"), 7.0);
+        assert_eq!(evaluate("(1 + 2) * 3"), 9.0);
+        assert_eq!(evaluate("8 - 2 - 1"), 5.0);
+        assert_eq!(evaluate("8 / 4 / 2"), 1.0);
+        assert_eq!(evaluate("2 ^ 3 ^ 2"), 512.0);
+        assert_eq!(evaluate("-2 ^ 2"), -4.0);
+        assert_eq!(evaluate("2 * -3"), -6.0);
+        assert_eq!(evaluate(".5 + 0.25"), 0.75);
+    }
+
+    #[test]
+    fn variables_read_the_inputs() {
+        assert_eq!(evaluate("height"), 8.0);
+        assert_eq!(evaluate("steepness"), 0.25);
+        assert_eq!(evaluate("distance_to_water"), 5.0);
+        assert_eq!(evaluate("moisture"), 0.5);
+        assert_eq!(evaluate("0.5 * (1 - height / 80)"), 0.45);
+        let under_water = ExpressionInputs::new(vec3(0.0, -1.0, 0.0), 0.0, 0.0);
+        assert_eq!(under_water.distance_to_water, -1.0);
+        assert_eq!(under_water.moisture, 1.0);
+    }
+
+    #[test]
+    fn functions_evaluate_their_arguments() {
+        assert_eq!(evaluate("min(height, 2)"), 2.0);
+        assert_eq!(evaluate("max(height, 2)"), 8.0);
+        assert_eq!(evaluate("clamp(height, 0, 1)"), 1.0);
+        assert_eq!(evaluate("clamp(-height, 0, 1)"), 0.0);
+        // Inverted bounds don't panic
+        assert_eq!(evaluate("clamp(0.5, 1, 0)"), 0.0);
+    }
+
+    #[test]
+    fn noises_stay_in_range_and_depend_on_the_seed() {
+        let noise = |source: &str, seed: u32| {
+            let expression = Expression::parse(source, seed).unwrap();
+            (0..64)
+                .map(|i| {
+                    let position = vec3(i as f32 * 3.7, 0.0, i as f32 * -1.3);
+                    expression.evaluate(&ExpressionInputs::new(position, 0.0, 0.0))
+                })
+                .collect::<Vec<_>>()
+        };
+        let values = noise("noise(0, 0.1)", 42);
+        assert!(values.iter().all(|value| (0.0..=1.0).contains(value)));
+        assert_eq!(values, noise("noise(0, 0.1)", 42));
+        assert_ne!(values, noise("noise(1, 0.1)", 42));
+        assert_ne!(values, noise("noise(0, 0.1)", 43));
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_offending_character() {
+        assert_eq!(parse_error("1 + heigth").position, 4);
+        assert_eq!(parse_error("(1 + 2").position, 6);
+        assert_eq!(parse_error("1 + 2)").position, 5);
+        assert_eq!(parse_error("1 $ 2").position, 2);
+        assert_eq!(parse_error("1 +").position, 3);
+        assert_eq!(parse_error("1..2").position, 0);
+        assert_eq!(parse_error("min(1)").position, 0);
+        assert_eq!(parse_error("2 * pow(1, 2)").position, 4);
+        assert_eq!(parse_error("noise(height, 1)").position, 0);
+    }
+
+    #[test]
+    fn invalid_expressions_are_dropped_by_the_validation() {
+        let mut expressions = TerrainExpressions {
+            density: Some("1 - height / 80".into()),
+            scale: Some("1 +".into()),
+            snow: None,
+        };
+        let issues = expressions.validate();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].field, "expressions.scale");
+        assert!(issues[0].allowed.contains("at character 3"));
+        assert_eq!(
+            expressions,
+            TerrainExpressions {
+                density: Some("1 - height / 80".into()),
+                ..default()
+            }
+        );
+    }
+}
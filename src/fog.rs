@@ -0,0 +1,191 @@
+//! Height based ground fog.
+//!
+//! [`VolumetricFogSettings`](bevy::pbr::VolumetricFogSettings) fills the whole view uniformly and
+//! doesn't support any kind of height falloff in this version of bevy. To get mist pooling in the
+//! low ground we render a box covering the terrain and integrate an exponential height fog along
+//! the view ray, stopping at the scene depth from the prepass.
+//!
+//! The light shafts of the volumetric fog are shadowed by the sun cascades, past their range the
+//! sun shines through the hills. The cascades are stretched to cover the whole terrain by default
+//! so a ridge at dusk casts its wedge through the mist all the way to the horizon.
+
+use bevy::{
+    math::vec4,
+    pbr::{
+        CascadeShadowConfig, CascadeShadowConfigBuilder, DirectionalLightShadowMap,
+        MaterialPipeline, MaterialPipelineKey, NotShadowCaster, VolumetricLight,
+    },
+    prelude::*,
+    render::{
+        mesh::MeshVertexBufferLayoutRef,
+        render_resource::{
+            AsBindGroup, CompareFunction, Face, RenderPipelineDescriptor, ShaderRef, ShaderType,
+            SpecializedMeshPipelineError,
+        },
+    },
+};
+
+use crate::{sky::Moonlight, terrain::TerrainConfig, SceneConfig};
+
+/// Shadows of the sun as seen by the light shafts
+#[derive(Reflect, Clone, Debug)]
+pub struct FogShadowConfig {
+    /// Distance covered by the sun shadows, 0 covers the whole terrain from anywhere on it
+    pub range: f32,
+    /// Size of the shadow map of every cascade, rounded up to a power of two
+    pub resolution: u32,
+    pub cascades: u32,
+    /// End of the first cascade, the one with the sharp shadows close to the camera
+    pub first_cascade_distance: f32,
+}
+
+impl Default for FogShadowConfig {
+    fn default() -> Self {
+        Self {
+            range: 0.0,
+            resolution: 2048,
+            cascades: 4,
+            first_cascade_distance: 10.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ShaderType, Debug, Default)]
+pub struct GroundFogSettings {
+    color: Vec4,
+    /// x: base height, y: falloff, z: density
+    params: Vec4,
+    bounds_min: Vec4,
+    bounds_max: Vec4,
+}
+
+#[derive(Asset, TypePath, AsBindGroup, Clone)]
+pub struct GroundFogMaterial {
+    #[uniform(0)]
+    settings: GroundFogSettings,
+}
+
+impl Material for GroundFogMaterial {
+    fn fragment_shader() -> ShaderRef {
+        "ground_fog.wgsl".into()
+    }
+
+    fn alpha_mode(&self) -> AlphaMode {
+        AlphaMode::Blend
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayoutRef,
+        _key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        // Only draw the back faces so it still works when the camera is inside the volume
+        descriptor.primitive.cull_mode = Some(Face::Front);
+        // The back faces are usually behind the terrain, the shader takes care of the scene depth
+        if let Some(depth_stencil) = descriptor.depth_stencil.as_mut() {
+            depth_stencil.depth_compare = CompareFunction::Always;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Component)]
+pub struct GroundFog;
+
+pub fn spawn_ground_fog(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<GroundFogMaterial>>,
+) {
+    commands.spawn((
+        MaterialMeshBundle {
+            mesh: meshes.add(Cuboid::new(1.0, 1.0, 1.0)),
+            material: materials.add(GroundFogMaterial {
+                settings: GroundFogSettings::default(),
+            }),
+            visibility: Visibility::Hidden,
+            ..default()
+        },
+        NotShadowCaster,
+        GroundFog,
+    ));
+}
+
+/// Resizes the fog volume to cover the terrain and follows the water level
+pub fn update_ground_fog(
+    scene_config: Res<SceneConfig>,
+    terrain_config: Res<TerrainConfig>,
+    mut fog: Query<(&mut Transform, &mut Visibility, &Handle<GroundFogMaterial>), With<GroundFog>>,
+    mut materials: ResMut<Assets<GroundFogMaterial>>,
+) {
+    if !scene_config.is_changed() && !terrain_config.is_changed() {
+        return;
+    }
+
+    let base_height = terrain_config.water_level + scene_config.fog_height_base;
+    let falloff = scene_config.fog_height_falloff.max(0.001);
+    // Past this height the fog is too thin to be visible
+    let top = base_height + 5.0 / falloff;
+    let bottom = terrain_config.water_level - 1.0;
+    // The terrain can be rotated so make sure the corners are covered
+    let half_extent = terrain_config.half_size as f32 * std::f32::consts::SQRT_2;
+
+    let bounds_min = Vec3::new(-half_extent, bottom, -half_extent);
+    let bounds_max = Vec3::new(half_extent, top, half_extent);
+
+    for (mut transform, mut visibility, handle) in &mut fog {
+        *visibility = if scene_config.fog_height_density > 0.0 {
+            Visibility::Visible
+        } else {
+            Visibility::Hidden
+        };
+        *transform = Transform::from_translation((bounds_min + bounds_max) * 0.5)
+            .with_scale(bounds_max - bounds_min);
+
+        let Some(material) = materials.get_mut(handle) else {
+            continue;
+        };
+        let color = scene_config.fog_color.to_linear();
+        material.settings = GroundFogSettings {
+            color: vec4(color.red, color.green, color.blue, color.alpha),
+            params: vec4(base_height, falloff, scene_config.fog_height_density, 0.0),
+            bounds_min: bounds_min.extend(0.0),
+            bounds_max: bounds_max.extend(0.0),
+        };
+    }
+}
+
+/// Stretches the sun cascades over the range of the fog shadows
+pub fn apply_fog_shadows(
+    scene_config: Res<SceneConfig>,
+    terrain_config: Res<TerrainConfig>,
+    mut shadow_map: ResMut<DirectionalLightShadowMap>,
+    mut sun: Query<&mut CascadeShadowConfig, (With<VolumetricLight>, Without<Moonlight>)>,
+) {
+    if !scene_config.is_changed() && !terrain_config.is_changed() {
+        return;
+    }
+    let config = &scene_config.fog_shadows;
+
+    let size = config.resolution.next_power_of_two() as usize;
+    if shadow_map.size != size {
+        shadow_map.size = size;
+    }
+
+    // The diagonal of the terrain, the farthest the camera can see while standing on it
+    let range = if config.range > 0.0 {
+        config.range
+    } else {
+        terrain_config.half_size as f32 * 2.0 * std::f32::consts::SQRT_2
+    };
+    for mut cascades in &mut sun {
+        *cascades = CascadeShadowConfigBuilder {
+            num_cascades: config.cascades as usize,
+            first_cascade_far_bound: config.first_cascade_distance.min(range * 0.5),
+            maximum_distance: range,
+            ..default()
+        }
+        .into();
+    }
+}
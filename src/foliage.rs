@@ -0,0 +1,323 @@
+//! Material variants of the trees.
+//!
+//! The trees come with `StandardMaterial`s from the gltf, they are swapped for extended materials
+//! when they need to be frosted or kept out of the prepass. Materials are shared per snow bucket so
+//! thousands of trees only need a handful of materials. The leaves are masked, `leaf_alpha` in the
+//! scene config can smooth their edges with alpha to coverage when MSAA is on.
+
+use bevy::{
+    core_pipeline::prepass::DeferredPrepass,
+    pbr::{ExtendedMaterial, MaterialExtension, OpaqueRendererMethod},
+    prelude::*,
+    render::render_resource::{AsBindGroup, ShaderRef, ShaderType},
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    memory::PurgeAssets, snowfall::Snowfall, split_view::SecondaryCamera, tree_lod::TreeLods,
+    SceneConfig,
+};
+
+/// Number of distinct snow amounts, higher values means more materials
+const SNOW_BUCKETS: u8 = 4;
+/// Height over which the snow goes from nothing to fully covering the trees
+const SNOW_TRANSITION: f32 = 4.0;
+/// Bark is mostly vertical and sheltered by the branches so it only gets a light dusting
+const BARK_SNOW_FACTOR: f32 = 0.3;
+
+/// How the edges of the leaves are cut out
+#[derive(Reflect, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LeafAlpha {
+    /// Hard edges at half opacity
+    #[default]
+    Mask,
+    /// Turns the alpha into MSAA coverage for smooth edges, needs MSAA and the forward renderer.
+    /// The leaves stay masked otherwise.
+    AlphaToCoverage,
+}
+
+/// Alpha mode given to the tree materials, what [`LeafAlpha`] resolves to with the current
+/// renderer and anti aliasing
+#[derive(Resource, Clone, Copy, Debug, PartialEq)]
+pub struct TreeAlphaMode(pub AlphaMode);
+
+impl Default for TreeAlphaMode {
+    fn default() -> Self {
+        Self(AlphaMode::Mask(0.5))
+    }
+}
+
+/// A mesh of a tree with the material it was loaded with
+#[derive(Component)]
+pub struct FoliageMesh {
+    pub original: Handle<StandardMaterial>,
+    pub bark: bool,
+}
+
+#[derive(Clone, Copy, ShaderType, Debug, Default)]
+pub struct TreeSnowSettings {
+    /// 0 leaves the material untouched
+    pub snow_amount: f32,
+}
+
+/// Tree material with snow on the upward facing surfaces
+pub type SnowyTreeMaterial = ExtendedMaterial<StandardMaterial, TreeSnow>;
+
+#[derive(Asset, TypePath, AsBindGroup, Clone, Default)]
+pub struct TreeSnow {
+    #[uniform(100)]
+    pub settings: TreeSnowSettings,
+}
+
+impl MaterialExtension for TreeSnow {
+    fn fragment_shader() -> ShaderRef {
+        "tree.wgsl".into()
+    }
+
+    fn deferred_fragment_shader() -> ShaderRef {
+        "tree.wgsl".into()
+    }
+}
+
+/// Foliage material that isn't rendered in the prepass.
+///
+/// SSR raymarches the prepass depth so anything missing from it can't show up in reflections. The
+/// masked leaves are the most expensive part of the prepass so this lets users trade the tree
+/// reflections for performance.
+pub type ReflectionlessFoliageMaterial = ExtendedMaterial<StandardMaterial, ReflectionlessFoliage>;
+
+#[derive(Asset, TypePath, AsBindGroup, Clone, Default)]
+pub struct ReflectionlessFoliage {
+    #[uniform(100)]
+    pub settings: TreeSnowSettings,
+}
+
+impl MaterialExtension for ReflectionlessFoliage {
+    fn fragment_shader() -> ShaderRef {
+        "tree.wgsl".into()
+    }
+}
+
+/// Tree material only drawn in the shadow maps.
+///
+/// Used by the shadow proxies of the culled trees, the prepass of the camera and the main pass
+/// discard everything so they never show up on screen.
+pub type ShadowProxyMaterial = ExtendedMaterial<StandardMaterial, ShadowProxy>;
+
+#[derive(Asset, TypePath, AsBindGroup, Clone, Default)]
+pub struct ShadowProxy {}
+
+impl MaterialExtension for ShadowProxy {
+    fn fragment_shader() -> ShaderRef {
+        "shadow_proxy.wgsl".into()
+    }
+
+    fn prepass_fragment_shader() -> ShaderRef {
+        "shadow_proxy.wgsl".into()
+    }
+
+    fn deferred_fragment_shader() -> ShaderRef {
+        "shadow_proxy.wgsl".into()
+    }
+}
+
+/// The material variant a tree mesh should use
+#[derive(Component, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum FoliageVariant {
+    Standard,
+    Snowy(u8),
+    Reflectionless(u8),
+}
+
+fn snow_bucket(height: f32, snow_height: f32) -> u8 {
+    let amount = ((height - snow_height) / SNOW_TRANSITION).clamp(0.0, 1.0);
+    (amount * SNOW_BUCKETS as f32).round() as u8
+}
+
+fn snow_settings(bucket: u8, bark: bool) -> TreeSnowSettings {
+    let amount = bucket as f32 / SNOW_BUCKETS as f32;
+    TreeSnowSettings {
+        snow_amount: if bark {
+            amount * BARK_SNOW_FACTOR
+        } else {
+            amount
+        },
+    }
+}
+
+/// Picks the material of every tree mesh from its altitude relative to the snow line, lowered by
+/// the snow accumulation, and whether the trees should be in the reflections
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub fn update_tree_materials(
+    mut commands: Commands,
+    scene_config: Res<SceneConfig>,
+    snowfall: Res<Snowfall>,
+    mut last_snow_height: Local<f32>,
+    meshes: Query<(
+        Entity,
+        Ref<FoliageMesh>,
+        &GlobalTransform,
+        Option<&FoliageVariant>,
+    )>,
+    pbr_materials: Res<Assets<StandardMaterial>>,
+    mut snowy_materials: ResMut<Assets<SnowyTreeMaterial>>,
+    mut reflectionless_materials: ResMut<Assets<ReflectionlessFoliageMaterial>>,
+    mut snowy_cache: Local<HashMap<(AssetId<StandardMaterial>, u8), Handle<SnowyTreeMaterial>>>,
+    mut reflectionless_cache: Local<
+        HashMap<(AssetId<StandardMaterial>, u8), Handle<ReflectionlessFoliageMaterial>>,
+    >,
+    mut purge: EventReader<PurgeAssets>,
+    used_snowy: Query<&Handle<SnowyTreeMaterial>>,
+    used_reflectionless: Query<&Handle<ReflectionlessFoliageMaterial>>,
+) {
+    if purge.read().count() > 0 {
+        // The variants of the trees that were despawned or moved to another bucket
+        let snowy: HashSet<_> = used_snowy.iter().map(Handle::id).collect();
+        let reflectionless: HashSet<_> = used_reflectionless.iter().map(Handle::id).collect();
+        let before = snowy_cache.len() + reflectionless_cache.len();
+        snowy_cache.retain(|_, handle| snowy.contains(&handle.id()));
+        reflectionless_cache.retain(|_, handle| reflectionless.contains(&handle.id()));
+        let after = snowy_cache.len() + reflectionless_cache.len();
+        info!("dropped {} unused tree material variants", before - after);
+    }
+
+    // The accumulation changes every frame while it snows, the snow line only once in a while
+    let snow_line_moved = *last_snow_height != snowfall.snow_height;
+    *last_snow_height = snowfall.snow_height;
+
+    let _span = info_span!("update_tree_materials", meshes = meshes.iter().len()).entered();
+    for (entity, foliage, transform, current) in &meshes {
+        if !scene_config.is_changed()
+            && !snow_line_moved
+            && !foliage.is_changed()
+            && current.is_some()
+        {
+            continue;
+        }
+        let Some(material) = pbr_materials.get(&foliage.original) else {
+            continue;
+        };
+
+        let bucket = snow_bucket(transform.translation().y, snowfall.snow_height);
+        let variant = match (scene_config.foliage_in_reflections, bucket) {
+            (true, 0) => FoliageVariant::Standard,
+            (true, bucket) => FoliageVariant::Snowy(bucket),
+            (false, bucket) => FoliageVariant::Reflectionless(bucket),
+        };
+        if current == Some(&variant) {
+            continue;
+        }
+
+        let id = foliage.original.id();
+        let mut entity = commands.entity(entity);
+        entity
+            .remove::<(
+                Handle<StandardMaterial>,
+                Handle<SnowyTreeMaterial>,
+                Handle<ReflectionlessFoliageMaterial>,
+            )>()
+            .insert(variant);
+        match variant {
+            FoliageVariant::Standard => {
+                entity.insert(foliage.original.clone());
+            }
+            FoliageVariant::Snowy(bucket) => {
+                let handle = snowy_cache.entry((id, bucket)).or_insert_with(|| {
+                    snowy_materials.add(SnowyTreeMaterial {
+                        base: material.clone(),
+                        extension: TreeSnow {
+                            settings: snow_settings(bucket, foliage.bark),
+                        },
+                    })
+                });
+                entity.insert(handle.clone());
+            }
+            FoliageVariant::Reflectionless(bucket) => {
+                let handle = reflectionless_cache.entry((id, bucket)).or_insert_with(|| {
+                    reflectionless_materials.add(ReflectionlessFoliageMaterial {
+                        base: StandardMaterial {
+                            // Without a prepass it can't be part of the deferred gbuffer
+                            opaque_render_method: OpaqueRendererMethod::Forward,
+                            ..material.clone()
+                        },
+                        extension: ReflectionlessFoliage {
+                            settings: snow_settings(bucket, foliage.bark),
+                        },
+                    })
+                });
+                entity.insert(handle.clone());
+            }
+        }
+    }
+}
+
+/// Switches the masked tree materials and their variants to the configured [`LeafAlpha`]
+#[allow(clippy::too_many_arguments)]
+pub fn apply_leaf_alpha(
+    scene_config: Res<SceneConfig>,
+    msaa: Res<Msaa>,
+    cameras: Query<Has<DeferredPrepass>, (With<Camera3d>, Without<SecondaryCamera>)>,
+    mut alpha_mode: ResMut<TreeAlphaMode>,
+    foliage: Query<&FoliageMesh>,
+    tree_lods: Option<Res<TreeLods>>,
+    mut pbr_materials: ResMut<Assets<StandardMaterial>>,
+    mut snowy_materials: ResMut<Assets<SnowyTreeMaterial>>,
+    mut reflectionless_materials: ResMut<Assets<ReflectionlessFoliageMaterial>>,
+    mut applied: Local<Option<(LeafAlpha, bool, Msaa)>>,
+) {
+    // The renderer and the anti aliasing are switched after the config is loaded
+    let deferred = cameras.iter().any(|deferred| deferred);
+    let inputs = (scene_config.leaf_alpha, deferred, *msaa);
+    if *applied == Some(inputs) {
+        return;
+    }
+    *applied = Some(inputs);
+
+    let mode = match scene_config.leaf_alpha {
+        LeafAlpha::Mask => AlphaMode::Mask(0.5),
+        LeafAlpha::AlphaToCoverage if deferred => {
+            info!("alpha to coverage isn't supported by the deferred renderer, masking the leaves");
+            AlphaMode::Mask(0.5)
+        }
+        LeafAlpha::AlphaToCoverage if *msaa == Msaa::Off => {
+            info!("alpha to coverage needs MSAA, masking the leaves");
+            AlphaMode::Mask(0.5)
+        }
+        LeafAlpha::AlphaToCoverage => AlphaMode::AlphaToCoverage,
+    };
+    if alpha_mode.0 == mode {
+        return;
+    }
+    alpha_mode.0 = mode;
+    info!("tree materials set to {mode:?}");
+
+    // The procedural bark is the only opaque tree material, it has no edges to smooth
+    let masked = |current: AlphaMode| !matches!(current, AlphaMode::Opaque);
+    let lod_materials = tree_lods
+        .iter()
+        .flat_map(|lods| &lods.variants)
+        .flat_map(|lods| lods.levels.iter().flatten())
+        .map(|(_, material)| material.id());
+    let originals: HashSet<_> = foliage
+        .iter()
+        .map(|foliage| foliage.original.id())
+        .chain(lod_materials)
+        .collect();
+    for id in originals {
+        if let Some(material) = pbr_materials.get_mut(id) {
+            if masked(material.alpha_mode) {
+                material.alpha_mode = mode;
+            }
+        }
+    }
+    for (_, material) in snowy_materials.iter_mut() {
+        if masked(material.base.alpha_mode) {
+            material.base.alpha_mode = mode;
+        }
+    }
+    for (_, material) in reflectionless_materials.iter_mut() {
+        if masked(material.base.alpha_mode) {
+            material.base.alpha_mode = mode;
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! Footprints left in the snow and on wet ground.
+//!
+//! There's no walk mode, the camera counts as walking while it's within `max_eye_height` of the
+//! ground. Every `stride` it walks, a footprint is stamped on alternating sides of its path in a
+//! texture covering `extent` meters around it. The terrain shader presses the snow and darkens the
+//! wet ground under them, they fade out over `fade_seconds`.
+//!
+//! Like the water ripples, the region follows the camera by whole texels so the footprints stay in
+//! place in the world. Nothing is stamped while flying. With neither snow under the camera nor wet
+//! ground, the texture is cleared and the terrain shader skips it. The texture takes one byte per
+//! texel, `resolution` squared in total.
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+        texture::{ImageAddressMode, ImageFilterMode, ImageSampler, ImageSamplerDescriptor},
+    },
+};
+
+use crate::{
+    camera_controller::CameraController, heightfield::TerrainHeightfield, snowfall::Snowfall,
+    split_view::SecondaryCamera, weather::Wetness, SceneConfig,
+};
+
+/// Half the length and half the width of a footprint
+const FOOT_SIZE: Vec2 = Vec2::new(0.14, 0.06);
+/// Distance between the path of the camera and the center of a footprint
+const FOOT_SPACING: f32 = 0.12;
+/// Height over which the snow fades in under the snow line in the terrain shader
+const SNOW_FADE: f32 = 4.0;
+
+/// Settings of the footprints left by the camera
+#[derive(Reflect, Clone, Debug)]
+pub struct FootprintConfig {
+    pub enabled: bool,
+    /// How long a footprint takes to fade out
+    pub fade_seconds: f32,
+    /// Texels on each side of the footprint texture, one byte each
+    pub resolution: u32,
+    /// Size in meters of the region covered by the texture
+    pub extent: f32,
+    /// Distance walked between two footprints
+    pub stride: f32,
+    /// Highest the camera can be above the ground and still be walking
+    pub max_eye_height: f32,
+}
+
+impl Default for FootprintConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            fade_seconds: 60.0,
+            resolution: 512,
+            extent: 32.0,
+            stride: 0.7,
+            max_eye_height: 4.0,
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct Footprints {
+    image: Handle<Image>,
+    resolution: usize,
+    extent: f32,
+    /// Origin of the region in texels, it's always snapped to a texel so scrolling doesn't swim
+    origin: IVec2,
+    /// 1 for a fresh footprint, fading to 0
+    depths: Vec<f32>,
+    /// Distance walked since the last footprint
+    walked: f32,
+    left_foot: bool,
+    last_position: Option<Vec3>,
+    /// Whether there's snow or wet ground to show the footprints on
+    active: bool,
+    /// Whether any footprint is left in the texture
+    visible: bool,
+}
+
+impl Footprints {
+    pub fn image(&self) -> Option<&Handle<Image>> {
+        (self.resolution > 0).then_some(&self.image)
+    }
+
+    /// xy is the world space origin, z the extent and w is 1 when the terrain shows the footprints
+    pub fn region(&self) -> Vec4 {
+        if !self.active {
+            return Vec4::ZERO;
+        }
+        let origin = self.origin.as_vec2() * self.texel_size();
+        vec4(origin.x, origin.y, self.extent, 1.0)
+    }
+
+    fn texel_size(&self) -> f32 {
+        self.extent / self.resolution as f32
+    }
+
+    /// Moves the region so it stays centered on `center`, see [`crate::water::WaterRipples`]
+    fn recenter(&mut self, center: Vec2) {
+        let n = self.resolution as i32;
+        let origin = (center / self.texel_size()).floor().as_ivec2() - IVec2::splat(n / 2);
+        let delta = origin - self.origin;
+        if delta == IVec2::ZERO {
+            return;
+        }
+        self.origin = origin;
+        let old = self.depths.clone();
+        for y in 0..n {
+            for x in 0..n {
+                let (sx, sy) = (x + delta.x, y + delta.y);
+                let in_bounds = (0..n).contains(&sx) && (0..n).contains(&sy);
+                self.depths[(y * n + x) as usize] = if in_bounds {
+                    old[(sy * n + sx) as usize]
+                } else {
+                    0.0
+                };
+            }
+        }
+    }
+
+    /// Stamps a footprint centered on `position` pointing along `direction`
+    fn stamp(&mut self, position: Vec2, direction: Vec2) {
+        let n = self.resolution as i32;
+        let texel_size = self.texel_size();
+        let center = position / texel_size - self.origin.as_vec2();
+        let reach = (FOOT_SIZE.x / texel_size).ceil() as i32 + 1;
+        // The border stays empty so the clamped sampling outside the region reads nothing
+        let min = (center.as_ivec2() - reach).max(IVec2::ONE);
+        let max = (center.as_ivec2() + reach).min(IVec2::splat(n - 2));
+        let side = direction.perp();
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                let offset = (vec2(x as f32, y as f32) + 0.5 - center) * texel_size;
+                let local = vec2(offset.dot(direction), offset.dot(side)) / FOOT_SIZE;
+                let depth = (1.0 - local.length_squared()).clamp(0.0, 1.0).sqrt();
+                let texel = &mut self.depths[(y * n + x) as usize];
+                *texel = texel.max(depth);
+            }
+        }
+    }
+
+    fn write(&self, data: &mut [u8]) {
+        for (byte, depth) in data.iter_mut().zip(&self.depths) {
+            *byte = (depth * 255.0).round() as u8;
+        }
+    }
+}
+
+fn footprint_image(resolution: u32) -> Image {
+    let mut image = Image::new_fill(
+        Extent3d {
+            width: resolution,
+            height: resolution,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0],
+        TextureFormat::R8Unorm,
+        RenderAssetUsages::default(),
+    );
+    image.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
+        address_mode_u: ImageAddressMode::ClampToEdge,
+        address_mode_v: ImageAddressMode::ClampToEdge,
+        mag_filter: ImageFilterMode::Linear,
+        min_filter: ImageFilterMode::Linear,
+        ..default()
+    });
+    image
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn update_footprints(
+    time: Res<Time<Virtual>>,
+    scene_config: Res<SceneConfig>,
+    wetness: Res<Wetness>,
+    snowfall: Res<Snowfall>,
+    heightfield: Res<TerrainHeightfield>,
+    mut footprints: ResMut<Footprints>,
+    mut images: ResMut<Assets<Image>>,
+    camera: Query<&GlobalTransform, (With<CameraController>, Without<SecondaryCamera>)>,
+) {
+    let config = &scene_config.footprints;
+    let Ok(camera) = camera.get_single() else {
+        return;
+    };
+    let position = camera.translation();
+    let ground = heightfield.height_at(position.xz());
+    let snowy = ground.is_some_and(|ground| ground > snowfall.snow_height - SNOW_FADE);
+    let active = config.enabled && (snowy || wetness.puddle_amount > 0.0);
+    if !active {
+        if footprints.active || footprints.visible {
+            footprints.active = false;
+            footprints.visible = false;
+            footprints.depths.fill(0.0);
+            footprints.last_position = None;
+            if let Some(image) = images.get_mut(&footprints.image) {
+                footprints.write(&mut image.data);
+            }
+        }
+        return;
+    }
+
+    if footprints.resolution != config.resolution as usize || footprints.extent != config.extent {
+        let resolution = config.resolution as usize;
+        footprints.image = images.add(footprint_image(config.resolution));
+        footprints.resolution = resolution;
+        footprints.extent = config.extent;
+        footprints.depths = vec![0.0; resolution * resolution];
+        footprints.last_position = None;
+    }
+    footprints.active = true;
+    footprints.recenter(position.xz());
+
+    let walking = ground.is_some_and(|ground| position.y - ground <= config.max_eye_height);
+    let last_position = footprints.last_position.replace(position);
+    let step = last_position.map_or(Vec2::ZERO, |last| (position - last).xz());
+    // A jump across the region isn't walking, like the return to the start
+    if walking && step.length() < config.extent * 0.5 {
+        footprints.walked += step.length();
+        if footprints.walked >= config.stride {
+            footprints.walked = 0.0;
+            let direction = step.normalize_or_zero();
+            let side = if footprints.left_foot { 1.0 } else { -1.0 };
+            footprints.left_foot = !footprints.left_foot;
+            footprints.stamp(
+                position.xz() + direction.perp() * side * FOOT_SPACING,
+                direction,
+            );
+            footprints.visible = true;
+        }
+    }
+    if !footprints.visible {
+        return;
+    }
+
+    let fade = time.delta_seconds() / config.fade_seconds;
+    let mut visible = false;
+    for depth in &mut footprints.depths {
+        *depth = (*depth - fade).max(0.0);
+        visible |= *depth > 0.0;
+    }
+    footprints.visible = visible;
+    if let Some(image) = images.get_mut(&footprints.image) {
+        footprints.write(&mut image.data);
+    }
+}
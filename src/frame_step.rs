@@ -0,0 +1,58 @@
+//! Freezing the scene to look at single frames.
+//!
+//! Pause stops the virtual clock: the water, the wind in the reeds, the dust, the sky and the
+//! puddles stop while the camera, the input and the rendering keep going on the real clock. While
+//! paused, `.` advances the scene by exactly one fixed timestep.
+
+use bevy::prelude::*;
+
+use crate::{deterministic::DeterministicClock, overlay::StatsOverlay};
+
+/// A step requested by `.`, applied at the start of the next frame
+#[derive(Resource, Default)]
+pub struct FrameStep {
+    pending: bool,
+}
+
+pub fn frame_step_input(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut virtual_time: ResMut<Time<Virtual>>,
+    mut frame_step: ResMut<FrameStep>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    if keyboard.just_pressed(KeyCode::Pause) {
+        if virtual_time.is_paused() {
+            virtual_time.unpause();
+        } else {
+            virtual_time.pause();
+        }
+    }
+    if !virtual_time.is_paused() {
+        stats.remove("Paused");
+        return;
+    }
+    if keyboard.just_pressed(KeyCode::Period) {
+        frame_step.pending = true;
+    }
+    stats.set(
+        "Paused",
+        format!("{:.3}s, . to step", virtual_time.elapsed_seconds()),
+    );
+}
+
+/// Runs right after the clocks are updated so every system of the frame sees the step
+pub fn apply_frame_step(
+    mut frame_step: ResMut<FrameStep>,
+    mut virtual_time: ResMut<Time<Virtual>>,
+    fixed_time: Res<Time<Fixed>>,
+    mut time: ResMut<Time>,
+    mut clock: ResMut<DeterministicClock>,
+) {
+    if !std::mem::take(&mut frame_step.pending) {
+        return;
+    }
+    // The paused clock ignores the real time but can still be advanced by hand
+    virtual_time.advance_by(fixed_time.timestep());
+    *time = virtual_time.as_generic();
+    clock.steps = 1;
+}
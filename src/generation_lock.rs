@@ -0,0 +1,192 @@
+//! Holding back the terrain generation while tweaking the visuals.
+//!
+//! Nudging a field of the terrain config like `rotation` used to generate the trees again right
+//! away, along with every tree moved or removed by hand since. While the generation is locked, with
+//! H or `POST /generation_lock` on the remote control, the changes that would generate more than
+//! the terrain mesh again are held back. The live config keeps what's generated and the held
+//! values are listed in the stats overlay. Everything else applies right away: the material fields,
+//! the tree LODs and the whole scene config.
+//!
+//! Unlocking the generation with H, or Shift+H while it stays locked, applies the held changes in a
+//! single generation. Ctrl+H discards them. Saving the configs writes what's generated, without the
+//! held changes.
+
+use std::collections::BTreeMap;
+
+use bevy::{
+    prelude::*,
+    reflect::{Struct, TypeInfo, Typed},
+};
+
+use crate::{
+    overlay::StatsOverlay,
+    terrain::{GenerationLayer, TerrainConfig},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockAction {
+    Lock,
+    /// Unlocks the generation and applies the held changes
+    Unlock,
+    /// Applies the held changes and stays locked
+    Apply,
+    Discard,
+}
+
+#[derive(Resource, Default)]
+pub struct GenerationLock {
+    pub locked: bool,
+    /// Config the scene was last generated from
+    generated: Option<TerrainConfig>,
+    /// Values of the fields held back
+    pending: BTreeMap<&'static str, Box<dyn Reflect>>,
+    /// Action requested since the last frame
+    action: Option<LockAction>,
+}
+
+impl GenerationLock {
+    pub fn request(&mut self, action: LockAction) {
+        self.action = Some(action);
+    }
+
+    /// The held changes, as `field: generated -> held`
+    pub fn pending_changes(&self) -> Vec<String> {
+        let Some(generated) = &self.generated else {
+            return vec![];
+        };
+        self.pending
+            .iter()
+            .map(|(name, value)| {
+                format!("{name}: {:?} -> {value:?}", generated.field(name).unwrap())
+            })
+            .collect()
+    }
+}
+
+fn field_names() -> impl Iterator<Item = &'static str> {
+    let TypeInfo::Struct(info) = TerrainConfig::type_info() else {
+        unreachable!("the terrain config is a struct");
+    };
+    info.iter().map(|field| field.name())
+}
+
+fn differs(a: &TerrainConfig, b: &TerrainConfig, name: &str) -> bool {
+    let (a, b) = (a.field(name).unwrap(), b.field(name).unwrap());
+    !a.reflect_partial_eq(b)
+        .unwrap_or_else(|| format!("{a:?}") == format!("{b:?}"))
+}
+
+/// Whether changing `name` from `generated` to the value in `config` generates more than the
+/// terrain mesh again
+fn regenerates(generated: &TerrainConfig, config: &TerrainConfig, name: &str) -> bool {
+    let mut changed = generated.clone();
+    changed
+        .field_mut(name)
+        .unwrap()
+        .apply(config.field(name).unwrap());
+    changed
+        .invalidated_layers(generated)
+        .iter()
+        .any(|layer| *layer != GenerationLayer::Terrain)
+}
+
+/// H locks or unlocks the generation, Shift+H applies the held changes and Ctrl+H discards them
+pub fn generation_lock_input(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut lock: ResMut<GenerationLock>,
+) {
+    if !keyboard.just_pressed(KeyCode::KeyH) {
+        return;
+    }
+    let action = if keyboard.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]) {
+        LockAction::Discard
+    } else if keyboard.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+        LockAction::Apply
+    } else if lock.locked {
+        LockAction::Unlock
+    } else {
+        LockAction::Lock
+    };
+    lock.request(action);
+}
+
+/// Holds back the generation changes of the terrain config while locked, and applies or discards
+/// them on request. Runs before the config is generated.
+pub fn hold_generation_changes(
+    mut lock: ResMut<GenerationLock>,
+    mut terrain_config: ResMut<TerrainConfig>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    if !terrain_config.is_changed() && lock.action.is_none() {
+        return;
+    }
+    let lock = &mut *lock;
+    if terrain_config.is_changed() {
+        if let (true, Some(generated)) = (lock.locked, &lock.generated) {
+            let mut held = vec![];
+            for name in field_names() {
+                if differs(generated, &terrain_config, name)
+                    && regenerates(generated, &terrain_config, name)
+                {
+                    held.push(name);
+                }
+            }
+            if !held.is_empty() {
+                for &name in &held {
+                    let value = terrain_config.field(name).unwrap().clone_value();
+                    lock.pending.insert(name, value);
+                    terrain_config
+                        .field_mut(name)
+                        .unwrap()
+                        .apply(generated.field(name).unwrap());
+                }
+                info!("generation locked, holding back {}", held.join(", "));
+            }
+        }
+        lock.generated = Some(terrain_config.clone());
+    }
+
+    if let Some(action) = lock.action.take() {
+        match action {
+            LockAction::Lock => {
+                info!("terrain generation locked");
+                lock.locked = true;
+            }
+            LockAction::Unlock => {
+                info!("terrain generation unlocked");
+                lock.locked = false;
+            }
+            LockAction::Apply => {}
+            LockAction::Discard => {
+                let discarded = lock.pending_changes();
+                if !discarded.is_empty() {
+                    info!(
+                        "discarded the held generation changes: {}",
+                        discarded.join(", ")
+                    );
+                }
+                lock.pending.clear();
+            }
+        }
+        if matches!(action, LockAction::Unlock | LockAction::Apply) && !lock.pending.is_empty() {
+            info!("applying the held generation changes");
+            for (name, value) in std::mem::take(&mut lock.pending) {
+                terrain_config.field_mut(name).unwrap().apply(&*value);
+            }
+            lock.generated = Some(terrain_config.clone());
+        }
+    }
+
+    let changes = lock.pending_changes();
+    match (lock.locked, changes.len()) {
+        (false, 0) => stats.remove("Generation"),
+        (true, 0) => stats.set("Generation", "locked (H unlocks)"),
+        (_, count) => stats.set(
+            "Generation",
+            format!(
+                "locked, {count} pending generation changes (Shift+H applies, Ctrl+H discards): {}",
+                changes.join(", ")
+            ),
+        ),
+    }
+}
@@ -0,0 +1,210 @@
+use bevy::prelude::*;
+
+/// The heights of the generated terrain.
+///
+/// The heights are stored in the terrain's local space, before the rotation is applied, but every
+/// query takes and returns world space positions so callers never need to care about the rotation.
+/// Everything comparing the terrain to the water level, the scatter layers, the shoreline, the
+/// water clipping and the root debris, goes through it so they all agree on where the shore is.
+///
+/// The terrain is always centered on the origin and at most 4km wide since `half_size` is clamped
+/// to 2000. There's no streaming, so world positions are never rebased around the camera and
+/// everything keeps using absolute coordinates.
+#[derive(Resource, Clone, Debug)]
+pub struct TerrainHeightfield {
+    /// Side length of the terrain
+    pub size: f32,
+    /// Number of vertices on each side
+    pub resolution: usize,
+    /// Rotation around the Y axis applied to the terrain
+    pub rotation: f32,
+    /// Height of each vertex, row major with rows going along the Z axis
+    pub heights: Vec<f32>,
+}
+
+impl TerrainHeightfield {
+    /// Builds the heightfield from the vertices of the un-rotated terrain plane
+    pub fn from_positions(size: f32, rotation: f32, positions: &[[f32; 3]]) -> Self {
+        let resolution = (positions.len() as f32).sqrt() as usize;
+        debug_assert_eq!(resolution * resolution, positions.len());
+        Self {
+            size,
+            resolution,
+            rotation,
+            heights: positions.iter().map(|p| p[1]).collect(),
+        }
+    }
+
+    pub fn spacing(&self) -> f32 {
+        self.size / (self.resolution - 1) as f32
+    }
+
+    fn to_local(&self, world: Vec2) -> Vec2 {
+        let rotation = Quat::from_axis_angle(Vec3::Y, -self.rotation);
+        (rotation * Vec3::new(world.x, 0.0, world.y)).xz()
+    }
+
+    fn to_world(&self, local: Vec2) -> Vec2 {
+        let rotation = Quat::from_axis_angle(Vec3::Y, self.rotation);
+        (rotation * Vec3::new(local.x, 0.0, local.y)).xz()
+    }
+
+    /// Converts a world position to continuous grid coordinates
+    pub fn to_grid(&self, world: Vec2) -> Vec2 {
+        (self.to_local(world) + self.size * 0.5) / self.spacing()
+    }
+
+    pub fn height(&self, x: usize, z: usize) -> f32 {
+        self.heights[z * self.resolution + x]
+    }
+
+    /// The world space position of a grid vertex
+    pub fn world_position(&self, x: usize, z: usize) -> Vec3 {
+        let local = Vec2::new(x as f32, z as f32) * self.spacing() - self.size * 0.5;
+        let world = self.to_world(local);
+        Vec3::new(world.x, self.height(x, z), world.y)
+    }
+
+    /// Returns the interpolated height at a world position or None if it's outside the terrain
+    pub fn height_at(&self, world: Vec2) -> Option<f32> {
+        let grid = self.to_grid(world);
+        let max = (self.resolution - 1) as f32;
+        if grid.x < 0.0 || grid.y < 0.0 || grid.x > max || grid.y > max {
+            return None;
+        }
+        let x0 = (grid.x.floor() as usize).min(self.resolution - 2);
+        let z0 = (grid.y.floor() as usize).min(self.resolution - 2);
+        let t = grid - Vec2::new(x0 as f32, z0 as f32);
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let top = lerp(self.height(x0, z0), self.height(x0 + 1, z0), t.x);
+        let bottom = lerp(self.height(x0, z0 + 1), self.height(x0 + 1, z0 + 1), t.x);
+        Some(lerp(top, bottom, t.y))
+    }
+
+    /// The world space normal at a world position, computed from the neighbouring heights
+    pub fn normal_at(&self, world: Vec2) -> Option<Vec3> {
+        let e = self.spacing();
+        let left = self.height_at(world - Vec2::X * e)?;
+        let right = self.height_at(world + Vec2::X * e)?;
+        let back = self.height_at(world - Vec2::Y * e)?;
+        let front = self.height_at(world + Vec2::Y * e)?;
+        Some(Vec3::new(left - right, 2.0 * e, back - front).normalize())
+    }
+
+    /// Same steepness metric used by tree placement
+    pub fn steepness_at(&self, world: Vec2) -> Option<f32> {
+        Some(self.normal_at(world)?.cross(Vec3::Y).length())
+    }
+
+    /// Index of the highest vertex
+    pub fn highest_point(&self) -> (usize, usize) {
+        let (index, _) = self
+            .heights
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .unwrap_or((0, &0.0));
+        (index % self.resolution, index / self.resolution)
+    }
+
+    /// Checks that the segment between two points doesn't go through the terrain
+    pub fn line_of_sight(&self, from: Vec3, to: Vec3) -> bool {
+        let steps = (from.xz().distance(to.xz()) / self.spacing())
+            .ceil()
+            .max(1.0) as usize;
+        // skip the end so looking at a point on the ground isn't considered occluded
+        (1..steps).all(|i| {
+            let p = from.lerp(to, i as f32 / steps as f32);
+            self.height_at(p.xz()).map_or(true, |h| p.y > h)
+        })
+    }
+
+    /// Finds where a ray hits the terrain
+    pub fn raycast(&self, ray: Ray3d, max_distance: f32) -> Option<Vec3> {
+        let step = self.spacing() * 0.5;
+        let above = |t: f32| {
+            let p = ray.get_point(t);
+            self.height_at(p.xz()).map_or(true, |h| p.y > h)
+        };
+        let mut t = 0.0;
+        while t < max_distance {
+            let next = t + step;
+            if !above(next) {
+                // refine the hit between the last two samples
+                let (mut low, mut high) = (t, next);
+                for _ in 0..8 {
+                    let mid = (low + high) * 0.5;
+                    if above(mid) {
+                        low = mid;
+                    } else {
+                        high = mid;
+                    }
+                }
+                return Some(ray.get_point(high));
+            }
+            t = next;
+        }
+        None
+    }
+
+    /// Finds the grid vertex closest to a world position
+    pub fn nearest_vertex(&self, world: Vec2) -> Option<(usize, usize)> {
+        let grid = self.to_grid(world).round();
+        let max = (self.resolution - 1) as f32;
+        if grid.x < 0.0 || grid.y < 0.0 || grid.x > max || grid.y > max {
+            return None;
+        }
+        Some((grid.x as usize, grid.y as usize))
+    }
+
+    /// Grid vertices within `radius` of a world position, with their distance
+    pub fn vertices_in_radius(&self, world: Vec2, radius: f32) -> Vec<(usize, usize, f32)> {
+        let grid = self.to_grid(world);
+        let r = (radius / self.spacing()).ceil() as i64;
+        let mut vertices = vec![];
+        for z in (grid.y as i64 - r)..=(grid.y as i64 + r) {
+            for x in (grid.x as i64 - r)..=(grid.x as i64 + r) {
+                if x < 0 || z < 0 || x >= self.resolution as i64 || z >= self.resolution as i64 {
+                    continue;
+                }
+                let (x, z) = (x as usize, z as usize);
+                let distance = self.world_position(x, z).xz().distance(world);
+                if distance <= radius {
+                    vertices.push((x, z, distance));
+                }
+            }
+        }
+        vertices
+    }
+
+    pub fn set_height(&mut self, x: usize, z: usize, height: f32) {
+        self.heights[z * self.resolution + x] = height;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn world_positions_of_a_rotated_terrain_map_back_to_their_vertex() {
+        let resolution = 17;
+        let heights = (0..resolution * resolution)
+            .map(|i| i as f32 * 0.1)
+            .collect();
+        let heightfield = TerrainHeightfield {
+            size: 32.0,
+            resolution,
+            rotation: 0.7,
+            heights,
+        };
+        for z in 1..resolution - 1 {
+            for x in 1..resolution - 1 {
+                let position = heightfield.world_position(x, z);
+                assert_eq!(heightfield.nearest_vertex(position.xz()), Some((x, z)));
+                let height = heightfield.height_at(position.xz()).unwrap();
+                assert!((height - position.y).abs() < 1e-3, "{x} {z}");
+            }
+        }
+    }
+}
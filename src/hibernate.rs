@@ -0,0 +1,138 @@
+//! Throttling the app while nobody is looking at it.
+//!
+//! When the window loses the focus, winit switches to a low power update mode capped at the
+//! background frame rate of the scene config and the virtual clock is paused so the water, the
+//! dust and the weather stop animating. A minimized window can also stop rendering altogether.
+//! Everything is restored on refocus, the TAA history is reset so the first frame doesn't blend
+//! with the stale one from before the throttle.
+//!
+//! The benchmark, the smoke test and the print render are never throttled, they need every frame.
+
+use std::time::Duration;
+
+use bevy::{
+    core_pipeline::experimental::taa::TemporalAntiAliasSettings,
+    prelude::*,
+    window::PrimaryWindow,
+    winit::{UpdateMode, WinitSettings},
+};
+
+use crate::{
+    benchmark::Benchmark, overlay::StatsOverlay, print_render::PrintRender, smoke_test::SmokeTest,
+    SceneConfig,
+};
+
+/// Settings for throttling the app in the background
+#[derive(Reflect, Clone, Debug)]
+pub struct HibernationConfig {
+    /// Throttle the app at all while the window is unfocused
+    pub enabled: bool,
+    /// Frame rate cap while the window is unfocused
+    pub background_fps: f32,
+    /// Stop rendering while the window is minimized
+    pub skip_rendering_when_minimized: bool,
+}
+
+impl Default for HibernationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            background_fps: 5.0,
+            skip_rendering_when_minimized: true,
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct Hibernation {
+    active: bool,
+    /// Whether the virtual clock was paused by the throttle and not by the user
+    paused_time: bool,
+    /// Cameras deactivated because the window is minimized
+    deactivated_cameras: Vec<Entity>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn update_hibernation(
+    scene_config: Res<SceneConfig>,
+    mut hibernation: ResMut<Hibernation>,
+    mut winit_settings: ResMut<WinitSettings>,
+    mut virtual_time: ResMut<Time<Virtual>>,
+    mut stats: ResMut<StatsOverlay>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    windows: Query<&Window>,
+    mut cameras: Query<(Entity, &mut Camera, Option<&mut TemporalAntiAliasSettings>)>,
+    benchmark: Option<Res<Benchmark>>,
+    smoke_test: Option<Res<SmokeTest>>,
+    print_render: Option<Res<PrintRender>>,
+) {
+    let config = &scene_config.hibernation;
+    let Ok(window) = window.get_single() else {
+        return;
+    };
+    let exempt = benchmark.is_some() || smoke_test.is_some() || print_render.is_some();
+    // There's no minimized flag on the window, winit resizes it to nothing instead
+    let minimized = window.physical_width() == 0 || window.physical_height() == 0;
+    // Focusing the overlay window doesn't count as leaving, see the `ui_window` module
+    let focused = windows.iter().any(|window| window.focused);
+    let throttle = config.enabled && !exempt && (!focused || minimized);
+    let skip_rendering = throttle && minimized && config.skip_rendering_when_minimized;
+
+    if scene_config.is_changed() || hibernation.active != throttle {
+        // winit already tracks the focus, it only needs to know what to do without it
+        winit_settings.unfocused_mode = if config.enabled && !exempt {
+            UpdateMode::reactive_low_power(Duration::from_secs_f32(
+                1.0 / config.background_fps.max(0.1),
+            ))
+        } else {
+            UpdateMode::Continuous
+        };
+    }
+
+    if throttle && !hibernation.active {
+        // The user may have paused the scene already, it has to stay paused after the refocus
+        if !virtual_time.is_paused() {
+            virtual_time.pause();
+            hibernation.paused_time = true;
+        }
+        info!("window in the background, throttling");
+    } else if !throttle && hibernation.active {
+        if std::mem::take(&mut hibernation.paused_time) {
+            virtual_time.unpause();
+        }
+        for (.., taa) in &mut cameras {
+            if let Some(mut taa) = taa {
+                taa.reset = true;
+            }
+        }
+        info!("window in the foreground, back to full rate");
+    }
+    hibernation.active = throttle;
+
+    if skip_rendering && hibernation.deactivated_cameras.is_empty() {
+        for (entity, mut camera, _) in &mut cameras {
+            if camera.is_active {
+                camera.is_active = false;
+                hibernation.deactivated_cameras.push(entity);
+            }
+        }
+    } else if !skip_rendering {
+        // Only the cameras turned off here, the others are inactive for their own reasons
+        for entity in std::mem::take(&mut hibernation.deactivated_cameras) {
+            if let Ok((_, mut camera, _)) = cameras.get_mut(entity) {
+                camera.is_active = true;
+            }
+        }
+    }
+
+    if throttle {
+        let value = if skip_rendering {
+            "minimized, not rendering".to_string()
+        } else {
+            format!("background, {:.0} fps cap", config.background_fps)
+        };
+        stats.set("Throttled", value);
+    } else {
+        stats.remove("Throttled");
+    }
+}
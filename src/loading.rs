@@ -0,0 +1,133 @@
+//! Loading the scene in stages, the terrain first, then the trees and the skybox last.
+//!
+//! Requesting everything at startup left the order to the asset server, on a slow disk the trees
+//! could show up on an untextured void for seconds while the 4k textures were still decoding. The
+//! assets are now requested one stage at a time:
+//!
+//! - `Terrain`: the ground textures and the configs are requested at startup, the terrain is
+//!   generated as soon as its config is loaded. Done once the textures are upgraded and the
+//!   heightfield exists.
+//! - `Trees`: requests the tree glb, done once the tree variants are extracted. The procedural
+//!   trees are generated rather than loaded, the stage only waits for them.
+//! - `Sky`: requests the HDRI cubemap, the procedural sky is shown until it's loaded.
+//!
+//! An asset that fails to load doesn't hold the next stages back. What the current stage waits for
+//! is listed in the stats overlay.
+
+use bevy::{asset::LoadState, prelude::*, utils::Instant};
+
+use crate::{
+    cli::CliArgs,
+    heightfield::TerrainHeightfield,
+    overlay::StatsOverlay,
+    procgen::tree::use_procedural_trees,
+    terrain::{TerrainConfig, TerrainResources},
+    texture_streaming::TextureStreaming,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LoadingStage {
+    #[default]
+    Terrain,
+    Trees,
+    Sky,
+    Done,
+}
+
+#[derive(Resource)]
+pub struct Loading {
+    pub stage: LoadingStage,
+    /// When the current stage started
+    started: Instant,
+}
+
+impl Default for Loading {
+    fn default() -> Self {
+        Self {
+            stage: LoadingStage::default(),
+            started: Instant::now(),
+        }
+    }
+}
+
+/// Moves on to the next stage once everything the current one waits for is there, and requests the
+/// assets of the next one
+#[allow(clippy::too_many_arguments)]
+pub fn advance_loading(
+    asset_server: Res<AssetServer>,
+    mut loading: ResMut<Loading>,
+    mut texture_streaming: ResMut<TextureStreaming>,
+    mut terrain_resources: ResMut<TerrainResources>,
+    terrain_config: Option<Res<TerrainConfig>>,
+    heightfield: Option<Res<TerrainHeightfield>>,
+    cli: Res<CliArgs>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    loop {
+        let mut pending = vec![];
+        match loading.stage {
+            LoadingStage::Terrain => {
+                for texture in texture_streaming.ground_textures() {
+                    if texture.is_loading(&asset_server) {
+                        pending.push(texture.name);
+                    }
+                }
+                if terrain_config.is_none() {
+                    pending.push("terrain config");
+                } else if heightfield.is_none() {
+                    pending.push("terrain mesh");
+                }
+            }
+            LoadingStage::Trees if terrain_resources.trees.is_empty() => {
+                match &terrain_config {
+                    Some(config) if use_procedural_trees(config, &cli) => {
+                        pending.push("procedural trees");
+                    }
+                    _ => {
+                        // The variants are never extracted from a glb that failed to load
+                        let state = asset_server.load_state(terrain_resources.trees_gltf());
+                        if !matches!(state, LoadState::Failed(_)) {
+                            pending.push("tree glb");
+                        }
+                    }
+                }
+            }
+            LoadingStage::Trees => {}
+            LoadingStage::Sky => {
+                if texture_streaming.skybox.is_loading(&asset_server) {
+                    pending.push(texture_streaming.skybox.name);
+                }
+            }
+            LoadingStage::Done => return,
+        }
+
+        if !pending.is_empty() {
+            stats.set(
+                "Loading",
+                format!("{:?}, waiting for {}", loading.stage, pending.join(", ")),
+            );
+            return;
+        }
+
+        info!(
+            "{:?} stage loaded in {:.2}s",
+            loading.stage,
+            loading.started.elapsed().as_secs_f32()
+        );
+        loading.started = Instant::now();
+        loading.stage = match loading.stage {
+            LoadingStage::Terrain => {
+                terrain_resources.load_trees(&asset_server);
+                LoadingStage::Trees
+            }
+            LoadingStage::Trees => {
+                texture_streaming.load_skybox(&asset_server);
+                LoadingStage::Sky
+            }
+            LoadingStage::Sky | LoadingStage::Done => {
+                stats.remove("Loading");
+                LoadingStage::Done
+            }
+        };
+    }
+}
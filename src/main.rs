@@ -1,6 +1,8 @@
-use std::io::Write;
-
+use accessibility::AccessibilityConfig;
+use anti_aliasing::AntiAliasing;
+use autosave::AutosaveConfig;
 use bevy::{
+    app::ScheduleRunnerPlugin,
     color::palettes::css::WHITE,
     core_pipeline::{
         dof::DepthOfFieldSettings,
@@ -10,42 +12,252 @@ use bevy::{
         tonemapping::Tonemapping,
         Skybox,
     },
+    diagnostic::FrameTimeDiagnosticsPlugin,
+    log::LogPlugin,
     pbr::{
         wireframe::{WireframeConfig, WireframePlugin},
         DefaultOpaqueRendererMethod, ExtendedMaterial, ScreenSpaceAmbientOcclusionSettings,
         ScreenSpaceReflectionsSettings, VolumetricFogSettings, VolumetricLight,
     },
     prelude::*,
-    render::view::{ColorGrading, ColorGradingGlobal, ColorGradingSection},
+    render::{
+        settings::WgpuSettings,
+        view::{ColorGrading, ColorGradingGlobal, ColorGradingSection},
+        RenderPlugin,
+    },
     tasks::IoTaskPool,
+    time::TimeSystem,
+    window::ExitCondition,
+    winit::WinitPlugin,
 };
 use camera_controller::CameraController;
-use terrain::{TerrainConfig, TerrainMaterial, TerrainResources};
-use water::FoamMaterial;
+use canopy_shadow::{CanopyShadow, CanopyShadowConfig};
+use cli::CliArgs;
+use dust::DustMaterial;
+use fog::{FogShadowConfig, GroundFogMaterial};
+use foliage::{
+    LeafAlpha, ReflectionlessFoliageMaterial, ShadowProxyMaterial, SnowyTreeMaterial, TreeAlphaMode,
+};
+use footprints::FootprintConfig;
+use frame_step::FrameStep;
+use heightfield::TerrainHeightfield;
+use hibernate::{Hibernation, HibernationConfig};
+use measure::MeasureMode;
+use migration::MigratedConfigs;
+use occlusion::OcclusionCulling;
+use overlay::{ErrorBox, StatsOverlay};
+use photo_mode::PhotoMode;
+use projection::CameraProjectionMode;
+use render_layers::SceneLayer;
+use scene_debug::{ConfigCarrier, SceneDebug};
+use scene_transition::SceneConfigTransition;
+use sculpt::{SculptMode, TerrainEdits};
+use shoreline::ReedMaterial;
+use sky::{Moonlight, Sky, SkyMaterial};
+use snowfall::{SnowMaterial, Snowfall, SnowfallConfig};
+use std::time::Duration;
+use terrain::{
+    InvalidatedLayers, TerrainConfig, TerrainMaterial, TerrainResources, TreePlacements,
+};
+use texture_streaming::TextureStreaming;
+use tree_edit::{TreeEditMode, TreeEdits};
+use tree_inspect::TreeInspection;
+use tree_lod::{TreeLodTasks, TreeLods};
+use validation::{clamp_field, clamp_float_field, ValidationIssue};
+use water::{FoamConfig, FoamMaterial, WaterConfig, WaterDisturber, WaterRipples};
+use weather::Wetness;
+use wind::AppWindExt;
+use world_code::PendingWorldCode;
 
+mod accessibility;
+mod annotations;
+mod anti_aliasing;
+mod autosave;
+mod benchmark;
 mod camera_controller;
+mod camera_path;
+mod camera_spawn;
+mod canopy_shadow;
+mod cli;
+mod color_lut;
+mod color_temp;
+mod debug_palette;
+#[cfg(feature = "debug_views")]
+mod debug_view;
+mod deterministic;
+mod diagnostics_log;
+mod dust;
+mod expression;
+mod fog;
+mod foliage;
+mod footprints;
+mod frame_step;
+mod generation_lock;
+mod heightfield;
+mod hibernate;
+mod loading;
+mod map_export;
+mod measure;
+mod memory;
+mod migration;
+mod occlusion;
+mod overlay;
+mod photo_mode;
 mod plane;
+mod preprocess;
+mod print_render;
+mod procgen;
+mod projection;
+mod quality;
+#[cfg(feature = "remote_control")]
+mod remote;
+mod render_layers;
+mod render_method;
+mod ron_format;
+mod scatter;
+mod scenario;
+mod scene_debug;
+mod scene_transition;
+mod sculpt;
+mod seed_browser;
+mod shoreline;
+mod sky;
+mod sky_env_map;
+mod smoke_test;
+mod snowfall;
+mod split_view;
 mod terrain;
+mod terrain_chunks;
+mod terrain_edge;
+mod terrain_variants;
+mod texture_streaming;
+mod trace;
+mod tree_edit;
+mod tree_inspect;
+mod tree_instancing;
+mod tree_lod;
+mod tree_thinning;
+#[cfg(not(target_arch = "wasm32"))]
+mod ui_window;
+mod validation;
 mod water;
+mod weather;
+mod white_balance;
+mod wind;
+mod world_code;
 
 fn main() {
-    App::new()
-        .insert_resource(Msaa::Off)
-        .insert_resource(DefaultOpaqueRendererMethod::deferred())
-        .add_plugins((
-            DefaultPlugins.set(WindowPlugin {
-                primary_window: Some(Window {
-                    resolution: (1920.0, 1080.0).into(),
+    trace::configure_chrome_trace();
+    // Doesn't need the app at all
+    if std::env::args().any(|arg| arg == "--preprocess-assets") {
+        std::process::exit(preprocess::preprocess_assets());
+    }
+    let cli = CliArgs::parse();
+    // Checked before the window opens, see the `scenario` module
+    let scenario = match &cli.scenario {
+        Some(path) => match scenario::Scenario::load(path) {
+            Ok(scenario) => Some(scenario::ScenarioRun::new(scenario, cli.scenario_loop)),
+            Err(err) => {
+                eprintln!("invalid scenario: {err}");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let mut app = build_app(cli, false);
+    if let Some(scenario) = scenario {
+        app.insert_resource(scenario);
+    }
+    app.run();
+}
+
+/// The whole app but the scenario, `headless` builds it without a window nor a GPU for the smoke
+/// test in `cargo test`
+fn build_app(cli: CliArgs, headless: bool) -> App {
+    let default_plugins = DefaultPlugins.set(LogPlugin {
+        custom_layer: diagnostics_log::capture_layer,
+        ..default()
+    });
+    let default_plugins = if headless {
+        default_plugins
+            .set(WindowPlugin {
+                primary_window: None,
+                exit_condition: ExitCondition::DontExit,
+                ..default()
+            })
+            .set(RenderPlugin {
+                render_creation: WgpuSettings {
+                    backends: None,
                     ..default()
-                }),
+                }
+                .into(),
+                ..default()
+            })
+            .disable::<WinitPlugin>()
+            .add(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
+                1.0 / 60.0,
+            )))
+    } else {
+        default_plugins.set(WindowPlugin {
+            primary_window: Some(Window {
+                resolution: (1920.0, 1080.0).into(),
                 ..default()
             }),
+            ..default()
+        })
+    };
+
+    let mut app = App::new();
+    app.insert_resource(Msaa::Off)
+        .insert_resource(DefaultOpaqueRendererMethod::deferred())
+        .add_plugins((
+            default_plugins,
             TemporalAntiAliasPlugin,
             WireframePlugin,
+            FrameTimeDiagnosticsPlugin,
             MaterialPlugin::<FoamMaterial>::default(),
             MaterialPlugin::<ExtendedMaterial<StandardMaterial, water::Water>>::default(),
             MaterialPlugin::<ExtendedMaterial<StandardMaterial, TerrainMaterial>>::default(),
+            MaterialPlugin::<GroundFogMaterial> {
+                prepass_enabled: false,
+                ..default()
+            },
+            MaterialPlugin::<DustMaterial> {
+                prepass_enabled: false,
+                ..default()
+            },
+            MaterialPlugin::<SnowyTreeMaterial>::default(),
+            MaterialPlugin::<ReflectionlessFoliageMaterial> {
+                prepass_enabled: false,
+                ..default()
+            },
+            MaterialPlugin::<SkyMaterial> {
+                prepass_enabled: false,
+                ..default()
+            },
+            MaterialPlugin::<ReedMaterial> {
+                prepass_enabled: false,
+                ..default()
+            },
+            MaterialPlugin::<ShadowProxyMaterial>::default(),
         ))
+        .add_plugins((
+            print_render::PrintRenderPlugin,
+            color_lut::ColorLutPlugin,
+            MaterialPlugin::<SnowMaterial> {
+                prepass_enabled: false,
+                ..default()
+            },
+            MaterialPlugin::<terrain_edge::WallMaterial>::default(),
+            terrain_variants::TerrainVariantsPlugin,
+            wind::WindPlugin,
+            tree_instancing::TreeInstancingPlugin,
+            annotations::AnnotationsPlugin,
+        ))
+        .add_wind_material::<ExtendedMaterial<StandardMaterial, water::Water>>()
+        .add_wind_material::<FoamMaterial>()
+        .add_wind_material::<ReedMaterial>()
         .insert_resource(WireframeConfig {
             global: false,
             ..default()
@@ -54,77 +266,979 @@ fn main() {
             color: Color::srgb(1.0, 1.0, 1.0),
             brightness: 0.0,
         })
+        .insert_resource(cli)
+        .init_resource::<StatsOverlay>()
+        .init_resource::<ErrorBox>()
+        .init_resource::<SculptMode>()
+        .init_resource::<SceneDebug>()
+        .init_resource::<Wetness>()
+        .init_resource::<Snowfall>()
+        .init_resource::<footprints::Footprints>()
+        .init_resource::<CanopyShadow>()
+        .init_resource::<OcclusionCulling>()
+        .init_resource::<PhotoMode>()
+        .init_resource::<FrameStep>()
+        .init_resource::<Hibernation>()
+        .init_resource::<TreeAlphaMode>()
+        .init_resource::<projection::OrthographicCamera>()
+        .init_resource::<SceneConfigTransition>()
+        .init_resource::<split_view::SplitView>()
+        .add_event::<memory::PurgeAssets>()
+        .insert_resource(TerrainEdits::load())
+        .init_resource::<TreeEditMode>()
+        .init_resource::<TreeInspection>()
+        .init_resource::<MeasureMode>()
+        .init_resource::<loading::Loading>()
+        .init_resource::<seed_browser::SeedBrowser>()
+        .init_resource::<generation_lock::GenerationLock>()
+        .init_resource::<white_balance::WhiteBalance>()
+        .init_resource::<sky_env_map::SkyEnvironmentMap>()
+        .insert_resource(TreeEdits::load())
         .register_type::<TerrainConfig>()
+        .register_type::<scatter::ScatterLayer>()
+        .register_type::<tree_thinning::FarTreeBand>()
+        .register_type::<camera_spawn::StartPosition>()
         .register_type::<SceneConfig>()
+        .add_systems(PreStartup, migration::migrate_configs)
+        .add_systems(
+            First,
+            (
+                deterministic::advance_deterministic_clock,
+                frame_step::apply_frame_step,
+            )
+                .chain()
+                .after(TimeSystem),
+        )
         .add_systems(
             Startup,
             (
-                spawn_camera,
+                (
+                    texture_streaming::setup_texture_streaming,
+                    spawn_camera,
+                    quality::apply_quality_preset,
+                )
+                    .chain(),
                 terrain::setup_terrain_resources,
                 water::spawn_water,
-                // save_scene_system,
+                fog::spawn_ground_fog,
+                overlay::spawn_stats_overlay,
+                photo_mode::spawn_crop_bars,
+                dust::spawn_dust_motes,
+                snowfall::spawn_snowflakes,
+                sky::spawn_sky,
+                (
+                    scene_debug::spawn_scene_debug_panel,
+                    tree_inspect::spawn_tree_inspection_panel,
+                    measure::spawn_measure_label,
+                    diagnostics_log::spawn_diagnostics_panel,
+                    seed_browser::spawn_seed_browser,
+                ),
+                world_code::queue_world_code_from_cli,
+                trace::log_trace_path,
+                benchmark::start_benchmark,
+                autosave::check_for_session,
+                smoke_test::start_smoke_test,
                 terrain::load_terrain_config,
                 load_scene_config,
             ),
         )
+        .add_systems(
+            Update,
+            (
+                world_code::apply_pending_world_code
+                    .run_if(
+                        resource_exists::<PendingWorldCode>
+                            .and_then(resource_exists::<TerrainConfig>),
+                    )
+                    .before(validation::validate_terrain_config),
+                validation::validate_terrain_config
+                    .run_if(resource_exists_and_changed::<TerrainConfig>)
+                    .before(terrain::on_terrain_config_loaded),
+                world_code::copy_world_code.run_if(resource_exists::<TerrainConfig>),
+                terrain::reload_terrain_config
+                    .run_if(resource_exists::<terrain::TerrainConfigCarrier>)
+                    .before(validation::validate_terrain_config),
+                (
+                    generation_lock::generation_lock_input,
+                    generation_lock::hold_generation_changes
+                        .run_if(resource_exists::<TerrainConfig>)
+                        .after(validation::validate_terrain_config)
+                        .before(terrain::on_terrain_config_loaded),
+                )
+                    .chain(),
+            ),
+        )
+        // Before Update so every system sees the validated config and the transition blends
+        .add_systems(
+            PreUpdate,
+            (
+                validation::validate_scene_config
+                    .run_if(resource_exists_and_changed::<SceneConfig>),
+                scene_transition::crossfade_scene_config.run_if(resource_exists::<SceneConfig>),
+            )
+                .chain(),
+        )
         .add_systems(
             Update,
             (
                 camera_controller::camera_controller,
                 terrain::customize_tree_material,
                 toggle_wireframe,
+                save_configs,
+                (
+                    debug_palette::cycle_debug_palette,
+                    debug_palette::sync_wireframe_color,
+                )
+                    .chain(),
                 terrain::on_terrain_config_loaded.run_if(
                     resource_exists::<TerrainResources>
                         .and_then(resource_exists_and_changed::<TerrainConfig>),
                 ),
+                terrain::apply_generated_terrain
+                    .after(terrain::on_terrain_config_loaded)
+                    .run_if(
+                        resource_exists::<TerrainResources>
+                            .and_then(resource_exists::<terrain::TerrainGenerationTask>),
+                    ),
+                texture_streaming::upgrade_streamed_textures,
+                loading::advance_loading.after(texture_streaming::upgrade_streamed_textures),
                 terrain::on_terrain_resource_loaded.run_if(
                     resource_exists::<TerrainResources>.and_then(resource_exists::<TerrainConfig>),
                 ),
                 on_scene_config_loaded.run_if(resource_exists_and_changed::<SceneConfig>),
+                white_balance::update_white_balance
+                    .after(on_scene_config_loaded)
+                    .run_if(resource_exists::<SceneConfig>),
+                water::update_water_ripples.run_if(resource_exists::<WaterRipples>),
+                water::follow_water_level.run_if(resource_exists_and_changed::<TerrainConfig>),
+                water::clip_water_plane.run_if(
+                    resource_exists::<TerrainConfig>
+                        .and_then(resource_exists::<TerrainHeightfield>),
+                ),
+                (water::update_water_murk, water::update_water_shelter).run_if(
+                    resource_exists::<SceneConfig>
+                        .and_then(resource_exists::<TerrainConfig>)
+                        .and_then(resource_exists::<TerrainHeightfield>),
+                ),
+                (
+                    water::sync_foam_with_water.after(water::update_water_murk),
+                    water::sync_water_reflection.after(sky::update_sky),
+                )
+                    .run_if(resource_exists::<SceneConfig>),
+                (fog::update_ground_fog, fog::apply_fog_shadows).run_if(
+                    resource_exists::<SceneConfig>.and_then(resource_exists::<TerrainConfig>),
+                ),
+            ),
+        )
+        .add_systems(
+            Update,
+            (
+                render_method::apply_renderer_method
+                    .before(anti_aliasing::apply_anti_aliasing)
+                    .run_if(resource_exists::<SceneConfig>),
+                anti_aliasing::apply_anti_aliasing.run_if(resource_exists::<SceneConfig>),
+                color_lut::apply_color_lut.run_if(resource_exists::<SceneConfig>),
+                overlay::toggle_stats_overlay,
+                overlay::update_stats_overlay,
+                memory::report_memory,
+                memory::purge_assets_input,
+                overlay::update_error_box,
+                accessibility::apply_ui_contrast.run_if(resource_exists::<SceneConfig>),
+                dust::update_dust_motes.run_if(resource_exists::<SceneConfig>),
+                snowfall::update_snowflakes.run_if(resource_exists::<SceneConfig>),
+                (sky::update_sky, sky_env_map::compose_sky_env_map)
+                    .chain()
+                    .after(on_scene_config_loaded)
+                    .run_if(resource_exists::<SceneConfig>),
+                (projection::toggle_projection, projection::apply_projection)
+                    .chain()
+                    .after(on_scene_config_loaded)
+                    .run_if(resource_exists::<SceneConfig>),
+                foliage::apply_leaf_alpha
+                    .after(anti_aliasing::apply_anti_aliasing)
+                    .before(terrain::customize_tree_material)
+                    .run_if(resource_exists::<SceneConfig>),
+                foliage::update_tree_materials
+                    .after(terrain::customize_tree_material)
+                    .after(foliage::apply_leaf_alpha)
+                    .after(snowfall::update_snow_accumulation)
+                    .after(memory::purge_assets_input)
+                    .run_if(resource_exists::<SceneConfig>),
+                (
+                    weather::update_wetness,
+                    snowfall::update_snow_accumulation,
+                    footprints::update_footprints.run_if(resource_exists::<TerrainHeightfield>),
+                    canopy_shadow::bake_canopy_shadow.run_if(
+                        resource_exists::<TreePlacements>
+                            .and_then(resource_exists::<TerrainHeightfield>),
+                    ),
+                    terrain::sync_terrain_material_settings,
+                )
+                    .chain()
+                    .run_if(resource_exists::<SceneConfig>),
+                (
+                    camera_spawn::place_camera,
+                    camera_spawn::return_to_start,
+                    camera_spawn::fly_to_start
+                        .run_if(resource_exists::<camera_spawn::ReturnToStart>)
+                        .after(camera_controller::camera_controller),
+                )
+                    .chain()
+                    .run_if(
+                        resource_exists::<TerrainHeightfield>
+                            .and_then(resource_exists::<SceneConfig>)
+                            .and_then(resource_exists::<TerrainConfig>),
+                    ),
+            ),
+        )
+        .add_systems(
+            Update,
+            (
+                map_export::export_map_on_key,
+                map_export::export_map_on_startup,
+            )
+                .run_if(
+                    resource_exists::<TerrainHeightfield>
+                        .and_then(resource_exists::<TreePlacements>)
+                        .and_then(resource_exists::<TerrainConfig>),
+                ),
+        )
+        .add_systems(
+            Update,
+            (
+                sculpt::toggle_sculpt_mode,
+                // A generation in progress started from the edits before the stroke, its terrain
+                // would replace the stroke once it's done
+                sculpt::sculpt_terrain.run_if(
+                    resource_exists::<TerrainHeightfield>
+                        .and_then(resource_exists::<TerrainConfig>)
+                        .and_then(resource_exists::<TreePlacements>)
+                        .and_then(not(resource_exists::<terrain::TerrainGenerationTask>)),
+                ),
+            )
+                .chain(),
+        )
+        .add_systems(
+            Update,
+            (
+                measure::toggle_measure_mode,
+                measure::measure.run_if(resource_exists::<TerrainHeightfield>),
+            )
+                .chain(),
+        )
+        .add_systems(
+            Update,
+            (
+                tree_edit::toggle_tree_edit_mode,
+                // Same as the sculpting, the generated trees would replace the edit
+                tree_edit::edit_trees.run_if(
+                    resource_exists::<TerrainHeightfield>
+                        .and_then(resource_exists::<TerrainConfig>)
+                        .and_then(resource_exists::<TerrainResources>)
+                        .and_then(resource_exists::<TreePlacements>)
+                        .and_then(not(resource_exists::<terrain::TerrainGenerationTask>)),
+                ),
+            )
+                .chain(),
+        )
+        .add_systems(
+            Update,
+            (
+                trace::stop_trace,
+                print_render::start_print_render,
+                print_render::update_print_render
+                    .run_if(resource_exists::<print_render::PrintRender>),
+                procgen::tree::start_procedural_trees.run_if(resource_exists::<TerrainConfig>),
+                procgen::tree::finish_procedural_trees.run_if(
+                    resource_exists::<procgen::tree::ProceduralTreeTask>
+                        .and_then(resource_exists::<TerrainResources>)
+                        .and_then(resource_exists::<TerrainConfig>),
+                ),
+                terrain::reload_tree_gltf.run_if(
+                    resource_exists::<TerrainResources>.and_then(resource_exists::<TerrainConfig>),
+                ),
+                tree_lod::finish_tree_lods.run_if(
+                    resource_exists::<TreeLodTasks>.and_then(resource_exists::<TerrainConfig>),
+                ),
+                tree_lod::report_tree_lod_triangles.run_if(
+                    resource_exists::<TreeLods>
+                        .and_then(resource_exists_and_changed::<TreePlacements>),
+                ),
+                tree_thinning::thin_far_trees
+                    .run_if(resource_exists::<TreeLods>.and_then(resource_exists::<TerrainConfig>)),
             ),
         )
-        .run();
+        .add_systems(
+            Update,
+            (
+                occlusion::toggle_occlusion_debug,
+                occlusion::build_height_pyramid
+                    .run_if(resource_exists_and_changed::<TerrainHeightfield>),
+                occlusion::build_tree_clusters,
+                occlusion::cull_occluded_trees.run_if(
+                    resource_exists::<TerrainHeightfield>.and_then(resource_exists::<SceneConfig>),
+                ),
+            )
+                .chain(),
+        )
+        .add_systems(
+            Update,
+            (
+                shoreline::spawn_shoreline.run_if(
+                    resource_exists::<TerrainConfig>.and_then(
+                        resource_exists_and_changed::<TerrainHeightfield>
+                            .or_else(resource_exists_and_changed::<InvalidatedLayers>),
+                    ),
+                ),
+                shoreline::float_lily_pads.run_if(resource_exists_and_changed::<TerrainConfig>),
+                terrain_edge::spawn_terrain_edge.run_if(
+                    resource_exists::<TerrainConfig>
+                        .and_then(resource_exists_and_changed::<TerrainHeightfield>),
+                ),
+                shoreline::drift_water_debris.run_if(
+                    resource_exists::<shoreline::DebrisField>
+                        .and_then(resource_exists::<SceneConfig>)
+                        .and_then(resource_exists::<TerrainHeightfield>),
+                ),
+            )
+                .chain(),
+        )
+        .add_systems(
+            Update,
+            (
+                photo_mode::photo_mode_input,
+                photo_mode::update_crop_bars,
+                photo_mode::draw_framing_guides,
+                photo_mode::take_screenshot,
+            )
+                .chain(),
+        )
+        .add_systems(
+            Update,
+            (
+                camera_path::toggle_flythrough.run_if(resource_exists::<TerrainHeightfield>),
+                camera_path::fly_camera.before(camera_controller::camera_controller),
+                benchmark::run_benchmark.run_if(resource_exists::<benchmark::Benchmark>),
+            )
+                .chain(),
+        )
+        .add_systems(
+            Last,
+            smoke_test::run_smoke_test.run_if(resource_exists::<smoke_test::SmokeTest>),
+        )
+        .add_systems(
+            Update,
+            scenario::run_scenario.run_if(resource_exists::<scenario::ScenarioRun>),
+        )
+        .add_systems(
+            Update,
+            (
+                diagnostics_log::diagnostics_log_input,
+                diagnostics_log::update_diagnostics_panel,
+            )
+                .chain()
+                .run_if(resource_exists::<diagnostics_log::DiagnosticsLog>),
+        )
+        .add_systems(
+            Update,
+            (
+                seed_browser::seed_browser_input,
+                seed_browser::update_seed_thumbnails,
+                seed_browser::pick_seed,
+            )
+                .chain()
+                .run_if(resource_exists::<TerrainConfig>),
+        )
+        .add_systems(
+            Update,
+            autosave::restore_session.run_if(resource_exists::<autosave::SessionRestore>),
+        )
+        .add_systems(
+            Last,
+            autosave::autosave_session
+                .run_if(resource_exists::<SceneConfig>.and_then(resource_exists::<TerrainConfig>)),
+        )
+        .add_systems(
+            Update,
+            (
+                frame_step::frame_step_input,
+                (
+                    split_view::split_view_input,
+                    split_view::update_split_view,
+                    split_view::sync_secondary_camera,
+                )
+                    .chain()
+                    .before(camera_controller::camera_controller),
+                hibernate::update_hibernation.run_if(resource_exists::<SceneConfig>),
+                render_layers::check_camera_layers,
+                scene_debug::scene_debug_input,
+                scene_debug::update_scene_debug_panel,
+                scene_debug::highlight_selected_category,
+                tree_inspect::inspect_trees.run_if(
+                    resource_exists::<TerrainHeightfield>
+                        .and_then(resource_exists::<TerrainResources>),
+                ),
+                tree_inspect::update_tree_inspection_panel,
+            )
+                .chain(),
+        );
+    #[cfg(feature = "debug_views")]
+    app.add_plugins(debug_view::DebugViewPlugin);
+    #[cfg(feature = "remote_control")]
+    app.add_plugins(remote::RemotePlugin);
+    #[cfg(not(target_arch = "wasm32"))]
+    app.init_resource::<ui_window::DetachedOverlay>()
+        .add_systems(Update, ui_window::toggle_detached_overlay);
+    // Needs the render adapter, the texture tier it picks is read by the startup systems
+    quality::pick_quality_preset(app.world_mut());
+    // After the quality preset, the prefs can turn it on
+    deterministic::setup_deterministic_clock(app.world_mut());
+    app
 }
 
-#[derive(Resource, Reflect)]
-#[reflect(Resource)]
+#[derive(Resource, Reflect, Clone)]
+#[reflect(Resource, Default)]
 struct SceneConfig {
+    /// Layout version of the config, see the `migration` module
+    version: u32,
     env_map_intensity: f32,
     skybox_brightness: f32,
     fog_color: Color,
     fog_ambient_intensity: f32,
     fog_light_intensity: f32,
     directional_light_color: Color,
+    /// Color temperature of the sun, replaces `directional_light_color` when set
+    directional_light_kelvin: Option<f32>,
     directional_light_looking_to: Vec3,
     tonemapping: Tonemapping,
+    /// `.cube` file in the assets applied after the tonemapping for a custom film look
+    color_lut: Option<String>,
     motion_blur_shutter_angle: f32,
     motion_blur_samples: u32,
     ssr: ScreenSpaceReflectionsSettings,
     camera_walk_speed: f32,
     color_grading: ColorGradingSection,
+    /// Exposure, white balance, hue and saturation of the whole image. The temperature and the tint
+    /// are offsets on top of the auto white balance.
+    color_grading_global: ColorGradingGlobal,
+    /// How much the grading neutralizes the color of the light, 0 is off and 1 makes it white, see
+    /// the `white_balance` module
+    auto_white_balance: f32,
+    water_ripples: bool,
+    water: WaterConfig,
+    foam: FoamConfig,
+    /// Height of the ground fog relative to the water level
+    fog_height_base: f32,
+    /// How fast the ground fog thins out above its base height
+    fog_height_falloff: f32,
+    /// Density of the ground fog at its base height, 0 disables it
+    fog_height_density: f32,
+    /// Reach of the sun shadows blocking the light shafts, see the `fog` module
+    fog_shadows: FogShadowConfig,
+    /// Darkening under the forest past the reach of the sun shadows, see the `canopy_shadow` module
+    canopy_shadow: CanopyShadowConfig,
+    /// How much the distant terrain fades into the horizon haze, 0 disables it
+    aerial_perspective_strength: f32,
+    /// Color of the haze over the land, usually close to the sky at the horizon
+    aerial_perspective_sky_tint: Color,
+    /// Color of the haze when looking low across the water
+    aerial_perspective_water_tint: Color,
+    anti_aliasing: AntiAliasing,
+    /// Always spawn the camera at the same position instead of looking for a good viewpoint
+    camera_fixed_spawn: bool,
+    /// How high above the ground the camera is placed
+    camera_spawn_height: f32,
+    /// Fraction of the dust motes that are visible, 0 disables them
+    dust_density: f32,
+    dust_brightness: f32,
+    /// Whether the trees are part of the prepass and show up in the SSR reflections
+    foliage_in_reflections: bool,
+    /// Edges of the leaves, alpha to coverage only applies with MSAA and the forward renderer
+    leaf_alpha: LeafAlpha,
+    /// Background of the scene, also used for the ambient lighting when procedural
+    sky: Sky,
+    /// Altitude above which the ground and the trees are covered in snow
+    snow_height: f32,
+    /// Falling snow piling up on the ground, see the `snowfall` module
+    snowfall: SnowfallConfig,
+    /// 0 is dry, 1 is heavy rain, fills the puddles on flat ground
+    rain_intensity: f32,
+    /// How long the puddles take to dry once the rain stops
+    puddle_drying_minutes: f32,
+    /// Hide the trees behind the terrain, see the `occlusion` module
+    occlusion_culling: bool,
+    /// Use the forward renderer instead of the deferred one, only read at startup
+    forward_rendering: bool,
+    /// Throttling while the window is in the background, see the `hibernate` module
+    hibernation: HibernationConfig,
+    /// How long a new scene config takes to fade in, see the `scene_transition` module
+    transition_seconds: f32,
+    /// Saving the session in the background, see the `autosave` module
+    autosave: AutosaveConfig,
+    /// Projection of the main camera, see the `projection` module
+    projection: CameraProjectionMode,
+    /// Height of the view in meters with the orthographic projection
+    orthographic_scale: f32,
+    /// Reduced motion, reduced flashing and high contrast, see the `accessibility` module
+    accessibility: AccessibilityConfig,
+    /// Footprints of the camera in the snow and on wet ground, see the `footprints` module
+    footprints: FootprintConfig,
 }
 
 impl Default for SceneConfig {
     fn default() -> Self {
         Self {
+            version: migration::SCENE_CONFIG_VERSION,
             env_map_intensity: 2000.0,
             skybox_brightness: 2000.0,
             fog_color: WHITE.into(),
             fog_ambient_intensity: 0.1,
             fog_light_intensity: 1.5,
             directional_light_color: Srgba::new(1.0, 0.75, 0.0, 1.0).into(),
+            directional_light_kelvin: None,
             directional_light_looking_to: Vec3::new(-10.0, -1.0, 7.0),
             tonemapping: Tonemapping::default(),
+            color_lut: None,
             motion_blur_shutter_angle: 0.5,
             motion_blur_samples: 1,
             ssr: ScreenSpaceReflectionsSettings::default(),
             camera_walk_speed: CameraController::default().walk_speed,
             color_grading: Default::default(),
+            color_grading_global: Default::default(),
+            auto_white_balance: 0.0,
+            water_ripples: true,
+            water: WaterConfig::default(),
+            foam: FoamConfig::default(),
+            fog_height_base: 2.0,
+            fog_height_falloff: 0.3,
+            fog_height_density: 0.02,
+            fog_shadows: FogShadowConfig::default(),
+            canopy_shadow: CanopyShadowConfig::default(),
+            aerial_perspective_strength: 0.35,
+            aerial_perspective_sky_tint: Srgba::new(0.62, 0.7, 0.8, 1.0).into(),
+            aerial_perspective_water_tint: Srgba::new(0.45, 0.65, 0.62, 1.0).into(),
+            anti_aliasing: AntiAliasing::Taa,
+            camera_fixed_spawn: false,
+            camera_spawn_height: 3.0,
+            dust_density: 0.5,
+            dust_brightness: 1.0,
+            foliage_in_reflections: true,
+            leaf_alpha: LeafAlpha::Mask,
+            sky: Sky::Hdri,
+            snow_height: 1000.0,
+            snowfall: SnowfallConfig::default(),
+            rain_intensity: 0.0,
+            puddle_drying_minutes: 10.0,
+            occlusion_culling: true,
+            forward_rendering: false,
+            hibernation: HibernationConfig::default(),
+            transition_seconds: 0.0,
+            autosave: AutosaveConfig::default(),
+            projection: CameraProjectionMode::Perspective,
+            orthographic_scale: 100.0,
+            accessibility: AccessibilityConfig::default(),
+            footprints: FootprintConfig::default(),
         }
     }
 }
 
-fn spawn_camera(mut commands: Commands, asset_server: Res<AssetServer>) {
+impl SceneConfig {
+    /// Clamps every field in a usable range and reports what was changed
+    fn validate(&mut self) -> Vec<ValidationIssue> {
+        let default = Self::default();
+        let mut issues = vec![];
+        for (field, value, fallback) in [
+            (
+                "env_map_intensity",
+                &mut self.env_map_intensity,
+                default.env_map_intensity,
+            ),
+            (
+                "skybox_brightness",
+                &mut self.skybox_brightness,
+                default.skybox_brightness,
+            ),
+            (
+                "fog_ambient_intensity",
+                &mut self.fog_ambient_intensity,
+                default.fog_ambient_intensity,
+            ),
+            (
+                "fog_light_intensity",
+                &mut self.fog_light_intensity,
+                default.fog_light_intensity,
+            ),
+            (
+                "fog_height_density",
+                &mut self.fog_height_density,
+                default.fog_height_density,
+            ),
+            (
+                "camera_spawn_height",
+                &mut self.camera_spawn_height,
+                default.camera_spawn_height,
+            ),
+            (
+                "dust_brightness",
+                &mut self.dust_brightness,
+                default.dust_brightness,
+            ),
+            (
+                "puddle_drying_minutes",
+                &mut self.puddle_drying_minutes,
+                default.puddle_drying_minutes,
+            ),
+        ] {
+            clamp_float_field(&mut issues, field, value, 0.0, f32::MAX, fallback);
+        }
+        clamp_float_field(
+            &mut issues,
+            "rain_intensity",
+            &mut self.rain_intensity,
+            0.0,
+            1.0,
+            default.rain_intensity,
+        );
+        clamp_float_field(
+            &mut issues,
+            "auto_white_balance",
+            &mut self.auto_white_balance,
+            0.0,
+            1.0,
+            default.auto_white_balance,
+        );
+        clamp_float_field(
+            &mut issues,
+            "aerial_perspective_strength",
+            &mut self.aerial_perspective_strength,
+            0.0,
+            1.0,
+            default.aerial_perspective_strength,
+        );
+        clamp_float_field(
+            &mut issues,
+            "motion_blur_shutter_angle",
+            &mut self.motion_blur_shutter_angle,
+            0.0,
+            1.0,
+            default.motion_blur_shutter_angle,
+        );
+        clamp_field(
+            &mut issues,
+            "motion_blur_samples",
+            &mut self.motion_blur_samples,
+            0,
+            64,
+        );
+        clamp_float_field(
+            &mut issues,
+            "camera_walk_speed",
+            &mut self.camera_walk_speed,
+            0.01,
+            1000.0,
+            default.camera_walk_speed,
+        );
+        clamp_float_field(
+            &mut issues,
+            "fog_height_base",
+            &mut self.fog_height_base,
+            -100.0,
+            100.0,
+            default.fog_height_base,
+        );
+        clamp_float_field(
+            &mut issues,
+            "fog_height_falloff",
+            &mut self.fog_height_falloff,
+            0.001,
+            100.0,
+            default.fog_height_falloff,
+        );
+        clamp_float_field(
+            &mut issues,
+            "fog_shadows.range",
+            &mut self.fog_shadows.range,
+            0.0,
+            10_000.0,
+            default.fog_shadows.range,
+        );
+        clamp_field(
+            &mut issues,
+            "fog_shadows.resolution",
+            &mut self.fog_shadows.resolution,
+            256,
+            8192,
+        );
+        clamp_field(
+            &mut issues,
+            "fog_shadows.cascades",
+            &mut self.fog_shadows.cascades,
+            1,
+            4,
+        );
+        clamp_float_field(
+            &mut issues,
+            "fog_shadows.first_cascade_distance",
+            &mut self.fog_shadows.first_cascade_distance,
+            0.1,
+            1000.0,
+            default.fog_shadows.first_cascade_distance,
+        );
+        clamp_float_field(
+            &mut issues,
+            "canopy_shadow.strength",
+            &mut self.canopy_shadow.strength,
+            0.0,
+            1.0,
+            default.canopy_shadow.strength,
+        );
+        clamp_field(
+            &mut issues,
+            "canopy_shadow.resolution",
+            &mut self.canopy_shadow.resolution,
+            16,
+            2048,
+        );
+        clamp_float_field(
+            &mut issues,
+            "dust_density",
+            &mut self.dust_density,
+            0.0,
+            1.0,
+            default.dust_density,
+        );
+        clamp_float_field(
+            &mut issues,
+            "water.murk_density",
+            &mut self.water.murk_density,
+            0.0,
+            100.0,
+            default.water.murk_density,
+        );
+        clamp_float_field(
+            &mut issues,
+            "water.clarity_depth",
+            &mut self.water.clarity_depth,
+            0.0,
+            water::MAX_WATER_DEPTH,
+            default.water.clarity_depth,
+        );
+        clamp_float_field(
+            &mut issues,
+            "water.shelter_strength",
+            &mut self.water.shelter_strength,
+            0.0,
+            1.0,
+            default.water.shelter_strength,
+        );
+        clamp_float_field(
+            &mut issues,
+            "water.shelter_distance",
+            &mut self.water.shelter_distance,
+            1.0,
+            500.0,
+            default.water.shelter_distance,
+        );
+        clamp_float_field(
+            &mut issues,
+            "water.shelter_height",
+            &mut self.water.shelter_height,
+            0.1,
+            100.0,
+            default.water.shelter_height,
+        );
+        clamp_float_field(
+            &mut issues,
+            "foam.streak_strength",
+            &mut self.foam.streak_strength,
+            0.0,
+            1.0,
+            default.foam.streak_strength,
+        );
+        clamp_float_field(
+            &mut issues,
+            "foam.shore_falloff_depth",
+            &mut self.foam.shore_falloff_depth,
+            0.0,
+            water::MAX_WATER_DEPTH,
+            default.foam.shore_falloff_depth,
+        );
+        for (field, value, min, max, fallback) in [
+            (
+                "snowfall.intensity",
+                &mut self.snowfall.intensity,
+                0.0,
+                1.0,
+                default.snowfall.intensity,
+            ),
+            (
+                "snowfall.fall_speed",
+                &mut self.snowfall.fall_speed,
+                0.1,
+                20.0,
+                default.snowfall.fall_speed,
+            ),
+            (
+                "snowfall.brightness",
+                &mut self.snowfall.brightness,
+                0.0,
+                100.0,
+                default.snowfall.brightness,
+            ),
+            (
+                "snowfall.accumulation_rate",
+                &mut self.snowfall.accumulation_rate,
+                0.0,
+                10.0,
+                default.snowfall.accumulation_rate,
+            ),
+            (
+                "snowfall.melt_rate",
+                &mut self.snowfall.melt_rate,
+                0.0,
+                10.0,
+                default.snowfall.melt_rate,
+            ),
+            (
+                "snowfall.max_accumulation",
+                &mut self.snowfall.max_accumulation,
+                0.0,
+                1.0,
+                default.snowfall.max_accumulation,
+            ),
+            (
+                "snowfall.temperature",
+                &mut self.snowfall.temperature,
+                -60.0,
+                60.0,
+                default.snowfall.temperature,
+            ),
+            (
+                "snowfall.melt_temperature",
+                &mut self.snowfall.melt_temperature,
+                -60.0,
+                60.0,
+                default.snowfall.melt_temperature,
+            ),
+        ] {
+            clamp_float_field(&mut issues, field, value, min, max, fallback);
+        }
+        clamp_float_field(
+            &mut issues,
+            "hibernation.background_fps",
+            &mut self.hibernation.background_fps,
+            1.0,
+            240.0,
+            default.hibernation.background_fps,
+        );
+        clamp_float_field(
+            &mut issues,
+            "transition_seconds",
+            &mut self.transition_seconds,
+            0.0,
+            60.0,
+            default.transition_seconds,
+        );
+        clamp_float_field(
+            &mut issues,
+            "autosave.interval_seconds",
+            &mut self.autosave.interval_seconds,
+            5.0,
+            3600.0,
+            default.autosave.interval_seconds,
+        );
+        clamp_field(
+            &mut issues,
+            "autosave.keep",
+            &mut self.autosave.keep,
+            0,
+            100,
+        );
+        clamp_float_field(
+            &mut issues,
+            "orthographic_scale",
+            &mut self.orthographic_scale,
+            1.0,
+            5000.0,
+            default.orthographic_scale,
+        );
+        for (field, value, min, max, fallback) in [
+            (
+                "footprints.fade_seconds",
+                &mut self.footprints.fade_seconds,
+                0.1,
+                3600.0,
+                default.footprints.fade_seconds,
+            ),
+            (
+                "footprints.extent",
+                &mut self.footprints.extent,
+                4.0,
+                256.0,
+                default.footprints.extent,
+            ),
+            (
+                "footprints.stride",
+                &mut self.footprints.stride,
+                0.1,
+                5.0,
+                default.footprints.stride,
+            ),
+            (
+                "footprints.max_eye_height",
+                &mut self.footprints.max_eye_height,
+                0.0,
+                100.0,
+                default.footprints.max_eye_height,
+            ),
+        ] {
+            clamp_float_field(&mut issues, field, value, min, max, fallback);
+        }
+        clamp_field(
+            &mut issues,
+            "footprints.resolution",
+            &mut self.footprints.resolution,
+            64,
+            2048,
+        );
+        if !color_lut::tonemapping_available(self.tonemapping) {
+            let available: Vec<_> = color_lut::available_tonemappers()
+                .map(|tonemapping| format!("{tonemapping:?}"))
+                .collect();
+            issues.push(ValidationIssue {
+                field: "tonemapping",
+                value: format!("{:?} (its LUT isn't bundled)", self.tonemapping),
+                allowed: available.join(", "),
+                substituted: format!("{:?}", Tonemapping::AcesFitted),
+            });
+            self.tonemapping = Tonemapping::AcesFitted;
+        }
+        if let Some(kelvin) = &mut self.directional_light_kelvin {
+            clamp_float_field(
+                &mut issues,
+                "directional_light_kelvin",
+                kelvin,
+                color_temp::MIN_KELVIN,
+                color_temp::MAX_KELVIN,
+                6500.0,
+            );
+        }
+        if self.directional_light_looking_to.length_squared() < 1e-6
+            || !self.directional_light_looking_to.is_finite()
+        {
+            issues.push(ValidationIssue {
+                field: "directional_light_looking_to",
+                value: format!("{:?}", self.directional_light_looking_to),
+                allowed: "a non zero direction".into(),
+                substituted: format!("{:?}", default.directional_light_looking_to),
+            });
+            self.directional_light_looking_to = default.directional_light_looking_to;
+        }
+        issues
+    }
+}
+
+fn spawn_camera(mut commands: Commands, texture_streaming: Res<TextureStreaming>) {
     commands
         .spawn((
             Camera3dBundle {
@@ -137,15 +1251,16 @@ fn spawn_camera(mut commands: Commands, asset_server: Res<AssetServer>) {
                 ..default()
             },
             EnvironmentMapLight {
-                diffuse_map: asset_server.load("skybox/kloppenheim_01_puresky_4k_cubemap.ktx2"),
-                specular_map: asset_server.load("skybox/kloppenheim_01_puresky_4k_cubemap.ktx2"),
+                diffuse_map: texture_streaming.skybox.current(),
+                specular_map: texture_streaming.skybox.current(),
                 intensity: 2000.0,
             },
             Skybox {
-                image: asset_server.load("skybox/kloppenheim_01_puresky_4k_cubemap.ktx2"),
+                image: texture_streaming.skybox.current(),
                 brightness: 2000.0,
             },
             CameraController::default(),
+            SceneLayer::main_camera(),
             VolumetricFogSettings::default(),
             DepthPrepass,
             DeferredPrepass,
@@ -153,6 +1268,7 @@ fn spawn_camera(mut commands: Commands, asset_server: Res<AssetServer>) {
             ScreenSpaceAmbientOcclusionSettings::default(),
             DepthOfFieldSettings::default(),
             MotionBlur::default(),
+            WaterDisturber::default(),
         ))
         .insert(Tonemapping::AcesFitted)
         .insert(TemporalAntiAliasBundle::default());
@@ -169,6 +1285,7 @@ fn spawn_camera(mut commands: Commands, asset_server: Res<AssetServer>) {
             ..default()
         },
         VolumetricLight,
+        SceneLayer::lights(),
     ));
 }
 
@@ -181,56 +1298,92 @@ fn toggle_wireframe(
     }
 }
 
-// This is just there in case I need another dynamic scene
-fn _save_scene_system(world: &mut World) {
-    let mut scene_world = World::new();
-    let type_registry = world.resource::<AppTypeRegistry>().clone();
-    scene_world.insert_resource(type_registry);
-    scene_world.insert_resource(SceneConfig::default());
-    let scene = DynamicScene::from_world(&scene_world);
-    let type_registry = world.resource::<AppTypeRegistry>();
-    let type_registry = type_registry.read();
-    let serialized_scene = scene.serialize(&type_registry).unwrap();
-
-    info!("{}", serialized_scene);
+/// Ctrl+S writes the live scene and terrain configs back to their files, without the fields at
+/// their default with `--compact-configs`
+fn save_configs(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    cli: Res<CliArgs>,
+    type_registry: Res<AppTypeRegistry>,
+    scene_config: Option<Res<SceneConfig>>,
+    terrain_config: Option<Res<TerrainConfig>>,
+) {
+    let ctrl = keyboard.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+    if !ctrl || !keyboard.just_pressed(KeyCode::KeyS) {
+        return;
+    }
+    let (Some(scene_config), Some(terrain_config)) = (scene_config, terrain_config) else {
+        warn!("the configs aren't loaded yet, nothing to save");
+        return;
+    };
 
-    #[cfg(not(target_arch = "wasm32"))]
-    IoTaskPool::get()
-        .spawn(async move {
-            // Write the scene RON data to file
-            std::fs::File::create("assets/scene_config.scn.ron")
-                .and_then(|mut file| file.write(serialized_scene.as_bytes()))
-                .expect("Error while writing scene to file");
-        })
-        .detach();
+    let compact = cli.compact_configs;
+    let configs = [
+        (
+            "assets/scene_config.scn.ron",
+            migration::serialize_config(&type_registry, scene_config.clone()),
+            compact.then(|| migration::serialize_config(&type_registry, SceneConfig::default())),
+        ),
+        (
+            "assets/terrain_config.scn.ron",
+            migration::serialize_config(&type_registry, terrain_config.clone()),
+            compact.then(|| migration::serialize_config(&type_registry, TerrainConfig::default())),
+        ),
+    ];
+    for (path, serialized, defaults) in configs {
+        let formatted =
+            ron_format::format_ron(&serialized, defaults.as_deref()).unwrap_or_else(|err| {
+                error!("failed to format {path}: {err}");
+                serialized
+            });
+        #[cfg(not(target_arch = "wasm32"))]
+        IoTaskPool::get()
+            .spawn(async move {
+                match autosave::write_atomic(std::path::Path::new(path), &formatted) {
+                    Ok(()) => info!("saved the live config to {path}"),
+                    Err(err) => error!("failed to save {path}: {err}"),
+                }
+            })
+            .detach();
+    }
 }
 
-fn load_scene_config(mut commands: Commands, asset_server: Res<AssetServer>) {
-    commands.spawn(DynamicSceneBundle {
-        scene: asset_server.load("scene_config.scn.ron"),
-        ..default()
-    });
+fn load_scene_config(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    migrated_configs: Res<MigratedConfigs>,
+) {
+    commands.spawn((
+        DynamicSceneBundle {
+            scene: migrated_configs.load(&asset_server, "scene_config.scn.ron"),
+            ..default()
+        },
+        ConfigCarrier,
+    ));
 }
 
 fn on_scene_config_loaded(
     scene_config: Res<SceneConfig>,
     mut camera: Query<(
         &mut EnvironmentMapLight,
-        &mut Skybox,
-        &mut VolumetricFogSettings,
+        Option<&mut Skybox>,
+        Option<&mut VolumetricFogSettings>,
         &mut Tonemapping,
         &mut MotionBlur,
-        &mut ScreenSpaceReflectionsSettings,
+        Option<&mut ScreenSpaceReflectionsSettings>,
         &mut CameraController,
         &mut ColorGrading,
     )>,
-    mut directional_light: Query<(&mut DirectionalLight, &mut Transform)>,
+    mut directional_light: Query<(&mut DirectionalLight, &mut Transform), Without<Moonlight>>,
+    mut water_ripples: ResMut<WaterRipples>,
 ) {
-    println!("scene config changed");
+    info!("scene config changed");
+    let _span = info_span!("apply_scene_config").entered();
+
+    water_ripples.enabled = scene_config.water_ripples;
 
     for (
         mut env_map_light,
-        mut skybox,
+        skybox,
         mut fog,
         mut tonemapping,
         mut motion_blur,
@@ -240,22 +1393,39 @@ fn on_scene_config_loaded(
     ) in &mut camera
     {
         env_map_light.intensity = scene_config.env_map_intensity;
-        skybox.brightness = scene_config.skybox_brightness;
-        fog.ambient_intensity = scene_config.fog_ambient_intensity;
-        fog.fog_color = scene_config.fog_color;
-        fog.light_intensity = scene_config.fog_light_intensity;
+        if let Some(mut skybox) = skybox {
+            skybox.brightness = scene_config.skybox_brightness;
+        }
+        // Not there with the orthographic projection, see the `projection` module
+        if let Some(mut fog) = fog {
+            fog.ambient_intensity = scene_config.fog_ambient_intensity;
+            fog.fog_color = scene_config.fog_color;
+            fog.light_intensity = scene_config.fog_light_intensity;
+        }
         *tonemapping = scene_config.tonemapping;
-        motion_blur.shutter_angle = scene_config.motion_blur_shutter_angle;
+        motion_blur.shutter_angle = scene_config
+            .accessibility
+            .motion_blur_shutter_angle(scene_config.motion_blur_shutter_angle);
         motion_blur.samples = scene_config.motion_blur_samples;
-        *ssr = scene_config.ssr;
+        // Not there with the forward renderer
+        if let Some(mut ssr) = ssr {
+            *ssr = scene_config.ssr;
+        }
         camera_controller.walk_speed = scene_config.camera_walk_speed;
+        camera_controller.friction = scene_config.accessibility.camera_friction();
         color_grading.shadows = scene_config.color_grading;
         color_grading.midtones = scene_config.color_grading;
         color_grading.highlights = scene_config.color_grading;
+        // The temperature and the tint get the auto white balance in white_balance
+        color_grading.global = scene_config.color_grading_global.clone();
     }
 
     for (mut directional_light, mut transform) in &mut directional_light {
-        directional_light.color = scene_config.directional_light_color;
+        // The moonlight gets the same color in sky::update_sky
+        directional_light.color = match scene_config.directional_light_kelvin {
+            Some(kelvin) => color_temp::kelvin_to_color(kelvin),
+            None => scene_config.directional_light_color,
+        };
         *transform = transform.looking_to(scene_config.directional_light_looking_to, Vec3::Y);
     }
 }
@@ -0,0 +1,171 @@
+//! Overhead map of the generated world.
+//!
+//! This only uses the heightfield and the tree placements so it doesn't need the render pipeline.
+
+use bevy::{prelude::*, tasks::IoTaskPool};
+use image::{Rgba, RgbaImage};
+
+use crate::{
+    camera_spawn::WorldStart,
+    cli::CliArgs,
+    heightfield::TerrainHeightfield,
+    terrain::{TerrainConfig, TreePlacements},
+};
+
+const DEFAULT_RESOLUTION: u32 = 1024;
+const WATER_COLOR: [f32; 3] = [0.15, 0.3, 0.6];
+const TREE_COLOR: [u8; 4] = [30, 110, 40, 255];
+const START_COLOR: [u8; 4] = [220, 40, 40, 255];
+
+/// Rasterizes the terrain relief as a hillshade, water as a blue overlay, trees as green dots and
+/// the start of the world as a red cross
+pub fn rasterize_map(
+    heightfield: &TerrainHeightfield,
+    water_level: f32,
+    trees: &[Vec3],
+    start: Option<Vec3>,
+    resolution: u32,
+) -> RgbaImage {
+    // The terrain can be rotated, cover the whole rotated square
+    let (sin, cos) = heightfield.rotation.sin_cos();
+    let half_extent = heightfield.size * 0.5 * (sin.abs() + cos.abs());
+    let to_world =
+        |px: f32, py: f32| Vec2::new(px, py) / resolution as f32 * 2.0 * half_extent - half_extent;
+
+    let max_height = heightfield
+        .heights
+        .iter()
+        .copied()
+        .fold(water_level + 1.0, f32::max);
+    let light = Vec3::new(-1.0, 1.0, -1.0).normalize();
+
+    let mut map = RgbaImage::from_fn(resolution, resolution, |px, py| {
+        let world = to_world(px as f32 + 0.5, py as f32 + 0.5);
+        let Some(height) = heightfield.height_at(world) else {
+            return Rgba(to_rgba(WATER_COLOR));
+        };
+        let normal = heightfield.normal_at(world).unwrap_or(Vec3::Y);
+        let shade = normal.dot(light).clamp(0.0, 1.0);
+        let elevation = ((height - water_level) / (max_height - water_level)).clamp(0.0, 1.0);
+        let gray = 0.2 + 0.5 * shade + 0.3 * elevation;
+        let mut color = [gray; 3];
+        if height < water_level {
+            for (c, w) in color.iter_mut().zip(WATER_COLOR) {
+                *c = *c * 0.4 + w * 0.6;
+            }
+        }
+        Rgba(to_rgba(color))
+    });
+
+    let to_pixel =
+        |world: Vec3| (world.xz() + half_extent) / (2.0 * half_extent) * resolution as f32;
+    let mut plot = |x: i32, y: i32, color: [u8; 4]| {
+        if (0..resolution as i32).contains(&x) && (0..resolution as i32).contains(&y) {
+            map.put_pixel(x as u32, y as u32, Rgba(color));
+        }
+    };
+    let radius = (resolution as f32 / 1024.0).max(1.0) as i32;
+    for tree in trees {
+        let pixel = to_pixel(*tree);
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                if dx * dx + dy * dy > radius * radius {
+                    continue;
+                }
+                plot(pixel.x as i32 + dx, pixel.y as i32 + dy, TREE_COLOR);
+            }
+        }
+    }
+    if let Some(start) = start {
+        let pixel = to_pixel(start);
+        let size = radius * 6;
+        for d in -size..=size {
+            for width in -radius..=radius {
+                plot(pixel.x as i32 + d, pixel.y as i32 + d + width, START_COLOR);
+                plot(pixel.x as i32 + d, pixel.y as i32 - d + width, START_COLOR);
+            }
+        }
+    }
+
+    map
+}
+
+fn to_rgba(color: [f32; 3]) -> [u8; 4] {
+    let [r, g, b] = color.map(|c| (c.clamp(0.0, 1.0) * 255.0) as u8);
+    [r, g, b, 255]
+}
+
+fn export_map(
+    heightfield: &TerrainHeightfield,
+    terrain_config: &TerrainConfig,
+    tree_placements: &TreePlacements,
+    start: Option<&WorldStart>,
+    resolution: u32,
+) {
+    let trees = tree_placements
+        .trees
+        .iter()
+        .map(|t| t.position)
+        .collect::<Vec<_>>();
+    let map = rasterize_map(
+        heightfield,
+        terrain_config.water_level,
+        &trees,
+        start.map(|start| start.0.translation),
+        resolution,
+    );
+    let path = format!("map_seed_{}.png", terrain_config.seed);
+
+    #[cfg(not(target_arch = "wasm32"))]
+    IoTaskPool::get()
+        .spawn(async move {
+            match map.save(&path) {
+                Ok(()) => info!("map exported to {path}"),
+                Err(err) => error!("failed to export map to {path}: {err}"),
+            }
+        })
+        .detach();
+}
+
+pub fn export_map_on_key(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    cli: Res<CliArgs>,
+    heightfield: Res<TerrainHeightfield>,
+    terrain_config: Res<TerrainConfig>,
+    tree_placements: Res<TreePlacements>,
+    start: Option<Res<WorldStart>>,
+) {
+    if keyboard.just_pressed(KeyCode::F9) {
+        let resolution = cli.map_resolution.unwrap_or(DEFAULT_RESOLUTION);
+        export_map(
+            &heightfield,
+            &terrain_config,
+            &tree_placements,
+            start.as_deref(),
+            resolution,
+        );
+    }
+}
+
+/// Exports the map once when `--export-map` is used, as soon as the trees are placed
+pub fn export_map_on_startup(
+    cli: Res<CliArgs>,
+    heightfield: Res<TerrainHeightfield>,
+    terrain_config: Res<TerrainConfig>,
+    tree_placements: Res<TreePlacements>,
+    start: Option<Res<WorldStart>>,
+    mut exported: Local<bool>,
+) {
+    if !cli.export_map || *exported || tree_placements.trees.is_empty() {
+        return;
+    }
+    *exported = true;
+    let resolution = cli.map_resolution.unwrap_or(DEFAULT_RESOLUTION);
+    export_map(
+        &heightfield,
+        &terrain_config,
+        &tree_placements,
+        start.as_deref(),
+        resolution,
+    );
+}
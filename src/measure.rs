@@ -0,0 +1,162 @@
+//! Measuring distances and heights in the scene.
+//!
+//! N toggles the measure mode. The first click sets a point on the terrain and the second one
+//! another, the line between them is drawn with its horizontal distance, its length and the height
+//! difference next to it. Holding Shift while clicking measures from the camera to the clicked
+//! point instead. Escape clears the measurement.
+//!
+//! While the mode is on, the stats overlay shows the height of the terrain under the cursor and its
+//! steepness, the same metric as `max_steepness` in the scatter layers. The clicks are only used by
+//! the measurements, the tree inspection, the tree planting and the sculpting brush ignore them.
+
+use bevy::{prelude::*, window::PrimaryWindow};
+
+use crate::{
+    debug_palette::DebugPalette, heightfield::TerrainHeightfield, overlay::StatsOverlay,
+    split_view::SecondaryCamera,
+};
+
+/// Opacity of the horizontal and the vertical legs of the measurement
+const LEG_ALPHA: f32 = 0.4;
+const POINT_RADIUS: f32 = 0.15;
+
+#[derive(Resource, Default)]
+pub struct MeasureMode {
+    pub active: bool,
+    from: Option<Vec3>,
+    to: Option<Vec3>,
+}
+
+#[derive(Component)]
+pub struct MeasureLabel;
+
+pub fn spawn_measure_label(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 16.0,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            ..default()
+        })
+        .with_background_color(Color::srgba(0.0, 0.0, 0.0, 0.6)),
+        Visibility::Hidden,
+        MeasureLabel,
+    ));
+}
+
+pub fn toggle_measure_mode(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut measure_mode: ResMut<MeasureMode>,
+    mut label: Query<&mut Visibility, With<MeasureLabel>>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    if keyboard.just_pressed(KeyCode::KeyN) {
+        measure_mode.active = !measure_mode.active;
+    }
+    if measure_mode.active && !keyboard.just_pressed(KeyCode::Escape) {
+        return;
+    }
+    if measure_mode.from.is_some() {
+        measure_mode.from = None;
+        measure_mode.to = None;
+        for mut visibility in &mut label {
+            *visibility = Visibility::Hidden;
+        }
+    }
+    if !measure_mode.active {
+        stats.remove("Measure");
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn measure(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    heightfield: Res<TerrainHeightfield>,
+    mut measure_mode: ResMut<MeasureMode>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform), (With<Camera3d>, Without<SecondaryCamera>)>,
+    mut label: Query<(&mut Text, &mut Style, &mut Visibility), With<MeasureLabel>>,
+    mut stats: ResMut<StatsOverlay>,
+    palette: Res<DebugPalette>,
+    mut gizmos: Gizmos,
+) {
+    if !measure_mode.active {
+        return;
+    }
+    let (Ok(window), Ok((camera, camera_transform))) = (window.get_single(), camera.get_single())
+    else {
+        return;
+    };
+    // The center of the screen while the camera holds the cursor
+    let cursor = window.cursor_position().unwrap_or(window.size() * 0.5);
+    let hit = camera
+        .viewport_to_world(camera_transform, cursor)
+        .and_then(|ray| heightfield.raycast(ray, 2000.0));
+
+    let readout = hit.and_then(|hit| {
+        let steepness = heightfield.steepness_at(hit.xz())?;
+        Some(format!(
+            "height {:.2}m, steepness {steepness:.2} ({:.0}°), click to measure",
+            hit.y,
+            steepness.clamp(0.0, 1.0).asin().to_degrees()
+        ))
+    });
+    stats.set(
+        "Measure",
+        readout.unwrap_or_else(|| "not over the terrain".to_string()),
+    );
+
+    if let Some(hit) = hit.filter(|_| mouse.just_pressed(MouseButton::Left)) {
+        let shift = keyboard.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+        match (measure_mode.from, measure_mode.to) {
+            _ if shift => {
+                measure_mode.from = Some(camera_transform.translation());
+                measure_mode.to = Some(hit);
+            }
+            (Some(_), None) => measure_mode.to = Some(hit),
+            _ => {
+                measure_mode.from = Some(hit);
+                measure_mode.to = None;
+            }
+        }
+    }
+
+    let Some(from) = measure_mode.from else {
+        return;
+    };
+    gizmos.sphere(from, Quat::IDENTITY, POINT_RADIUS, palette.measure);
+    let Some(to) = measure_mode.to else {
+        return;
+    };
+    gizmos.sphere(to, Quat::IDENTITY, POINT_RADIUS, palette.measure);
+    gizmos.line(from, to, palette.measure);
+    let corner = Vec3::new(to.x, from.y, to.z);
+    let leg_color = palette.measure.with_alpha(LEG_ALPHA);
+    gizmos.line(from, corner, leg_color);
+    gizmos.line(corner, to, leg_color);
+
+    let text = format!(
+        "horizontal {:.2}m\nstraight {:.2}m\nheight {:+.2}m",
+        from.xz().distance(to.xz()),
+        from.distance(to),
+        to.y - from.y
+    );
+    let position = camera.world_to_viewport(camera_transform, from.lerp(to, 0.5));
+    for (mut label_text, mut style, mut visibility) in &mut label {
+        // Hidden while the middle of the line is behind the camera
+        let Some(position) = position else {
+            *visibility = Visibility::Hidden;
+            continue;
+        };
+        *visibility = Visibility::Inherited;
+        style.left = Val::Px(position.x + 8.0);
+        style.top = Val::Px(position.y + 8.0);
+        label_text.sections[0].value.clone_from(&text);
+    }
+}
@@ -0,0 +1,131 @@
+//! Keeping an eye on the assets kept alive.
+//!
+//! The stats overlay shows how many meshes, images and materials are loaded with a rough estimate
+//! of their size, refreshed every second. F1 purges the caches holding handles to assets no live
+//! entity uses anymore so the asset GC can reclaim them: the foliage material variants and the
+//! tree glb once its variants are extracted.
+
+use bevy::{pbr::ExtendedMaterial, prelude::*, render::mesh::Indices};
+
+use crate::{
+    foliage::SnowyTreeMaterial,
+    overlay::StatsOverlay,
+    terrain::{TerrainMaterial, TerrainResources},
+};
+
+/// Asks every cache to drop the handles no live entity uses
+#[derive(Event)]
+pub struct PurgeAssets;
+
+pub fn purge_assets_input(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut terrain_resources: Option<ResMut<TerrainResources>>,
+    mut purge: EventWriter<PurgeAssets>,
+) {
+    if !keyboard.just_pressed(KeyCode::F1) {
+        return;
+    }
+    info!("purging the unused assets");
+    if let Some(terrain_resources) = &mut terrain_resources {
+        terrain_resources.purge();
+    }
+    purge.send(PurgeAssets);
+}
+
+fn megabytes(bytes: usize) -> f32 {
+    bytes as f32 / (1024.0 * 1024.0)
+}
+
+/// Size of the vertex and index buffers, what ends up on the GPU
+fn mesh_bytes(mesh: &Mesh) -> usize {
+    let index_bytes = mesh.indices().map_or(0, |indices| match indices {
+        Indices::U16(indices) => indices.len() * 2,
+        Indices::U32(indices) => indices.len() * 4,
+    });
+    mesh.count_vertices() * mesh.get_vertex_size() as usize + index_bytes
+}
+
+pub fn report_memory(
+    time: Res<Time<Real>>,
+    mut next_report: Local<f32>,
+    meshes: Res<Assets<Mesh>>,
+    images: Res<Assets<Image>>,
+    standard_materials: Res<Assets<StandardMaterial>>,
+    terrain_materials: Res<Assets<ExtendedMaterial<StandardMaterial, TerrainMaterial>>>,
+    tree_materials: Res<Assets<SnowyTreeMaterial>>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    // Summing every mesh is too slow to do every frame
+    if time.elapsed_seconds() < *next_report {
+        return;
+    }
+    *next_report = time.elapsed_seconds() + 1.0;
+
+    let mesh_bytes: usize = meshes.iter().map(|(_, mesh)| mesh_bytes(mesh)).sum();
+    // The images without data only live on the GPU, like the render targets
+    let image_bytes: usize = images.iter().map(|(_, image)| image.data.len()).sum();
+    stats.set(
+        "Memory",
+        format!(
+            "{} meshes {:.1} MB, {} images {:.1} MB\n\
+            materials: {} standard, {} terrain, {} tree",
+            meshes.len(),
+            megabytes(mesh_bytes),
+            images.len(),
+            megabytes(image_bytes),
+            standard_materials.len(),
+            terrain_materials.len(),
+            tree_materials.len(),
+        ),
+    );
+}
+
+#[cfg(all(test, feature = "smoke_test"))]
+mod tests {
+    use super::*;
+    use crate::{
+        foliage::ReflectionlessFoliageMaterial,
+        smoke_test::{populated_headless_app, update_paced},
+        terrain::{TerrainConfig, TerrainGenerationTask},
+    };
+
+    fn asset_counts(world: &World) -> [usize; 5] {
+        [
+            world.resource::<Assets<Mesh>>().len(),
+            world.resource::<Assets<StandardMaterial>>().len(),
+            world
+                .resource::<Assets<ExtendedMaterial<StandardMaterial, TerrainMaterial>>>()
+                .len(),
+            world.resource::<Assets<SnowyTreeMaterial>>().len(),
+            world
+                .resource::<Assets<ReflectionlessFoliageMaterial>>()
+                .len(),
+        ]
+    }
+
+    fn regenerate(app: &mut App, seed: u32) {
+        app.world_mut().resource_mut::<TerrainConfig>().seed = seed;
+        update_paced(app);
+        while app.world().contains_resource::<TerrainGenerationTask>() {
+            update_paced(app);
+        }
+        // The spawned layers swap their materials and the dropped handles are freed a frame later
+        for _ in 0..10 {
+            update_paced(app);
+        }
+    }
+
+    #[test]
+    fn regenerating_the_terrain_does_not_grow_the_assets() {
+        let mut app = populated_headless_app();
+        let seed = app.world().resource::<TerrainConfig>().seed;
+        // Once with each seed for the caches to fill up
+        regenerate(&mut app, seed + 1);
+        regenerate(&mut app, seed);
+        let steady = asset_counts(app.world());
+        for i in 0..10 {
+            regenerate(&mut app, if i % 2 == 0 { seed + 1 } else { seed });
+        }
+        assert_eq!(asset_counts(app.world()), steady);
+    }
+}
@@ -0,0 +1,458 @@
+//! Upgrades config files written by older versions of the app.
+//!
+//! Both configs carry a `version`. Before the configs are loaded, the files are parsed and
+//! upgraded to the current layout: renamed fields are moved, fields that didn't exist yet are
+//! filled from the defaults and fields that were removed are dropped. Files at the current version
+//! can leave out the fields at their default, see `--compact-configs`, they're filled in on load.
+//! The upgraded file is written back, with the old file kept as `.bak`, unless
+//! `--no-config-writeback` is passed, in which case it's only used in memory.
+
+use bevy::{prelude::*, reflect::TypeRegistry, scene::serde::SceneDeserializer, utils::HashMap};
+use serde::de::DeserializeSeed;
+
+use crate::{
+    cli::CliArgs,
+    ron_format::{self, Node},
+    terrain::TerrainConfig,
+    SceneConfig,
+};
+
+/// Bumped whenever a top level field is added, renamed or removed so files written before the
+/// change get migrated. The migration doesn't look inside the fields, the ones added to nested
+/// structs are `#[reflect(default)]` instead.
+///
+/// 0. Files from before the configs were versioned.
+/// 1. The `version` field.
+/// 2. The fields added since 1, filled from the defaults.
+pub const SCENE_CONFIG_VERSION: u32 = 2;
+/// Same history as [`SCENE_CONFIG_VERSION`]
+pub const TERRAIN_CONFIG_VERSION: u32 = 2;
+
+/// A field that was renamed, files older than `version` still use `from`
+struct Rename {
+    version: u32,
+    from: &'static str,
+    to: &'static str,
+}
+
+/// No field has been renamed since the configs are versioned, new entries go at the end
+const SCENE_CONFIG_RENAMES: &[Rename] = &[];
+const TERRAIN_CONFIG_RENAMES: &[Rename] = &[];
+
+/// Configs that had to be migrated in memory because they couldn't be written back
+#[derive(Resource, Default)]
+pub struct MigratedConfigs {
+    scenes: HashMap<&'static str, Handle<DynamicScene>>,
+}
+
+impl MigratedConfigs {
+    /// The migrated scene for the config at `path` or the file itself
+    pub fn load(&self, asset_server: &AssetServer, path: &'static str) -> Handle<DynamicScene> {
+        self.scenes
+            .get(path)
+            .cloned()
+            .unwrap_or_else(|| asset_server.load(path))
+    }
+}
+
+/// Serializes a config resource the same way the config files are written
+pub fn serialize_config<R: Resource>(type_registry: &AppTypeRegistry, config: R) -> String {
+    let mut scene_world = World::new();
+    scene_world.insert_resource(type_registry.clone());
+    scene_world.insert_resource(config);
+    let scene = DynamicScene::from_world(&scene_world);
+    scene.serialize(&type_registry.read()).unwrap()
+}
+
+/// A registry with only the config types, for the tests that don't build the whole app
+#[cfg(test)]
+pub fn config_type_registry() -> AppTypeRegistry {
+    let type_registry = AppTypeRegistry::default();
+    {
+        let mut registry = type_registry.write();
+        registry.register::<SceneConfig>();
+        registry.register::<TerrainConfig>();
+    }
+    type_registry
+}
+
+/// Loads a config file the same way the scene loader does
+#[cfg(test)]
+pub fn load_config<R: Resource>(type_registry: &AppTypeRegistry, text: &str) -> R {
+    use bevy::ecs::entity::EntityHashMap;
+
+    let mut deserializer = ron::de::Deserializer::from_str(text).unwrap();
+    let scene = SceneDeserializer {
+        type_registry: &type_registry.read(),
+    }
+    .deserialize(&mut deserializer)
+    .unwrap();
+    let mut world = World::new();
+    world.insert_resource(type_registry.clone());
+    scene
+        .write_to_world(&mut world, &mut EntityHashMap::default())
+        .unwrap();
+    world.remove_resource::<R>().unwrap()
+}
+
+/// Upgrades the config resource stored in `file`, returns what was changed
+fn migrate(
+    file: &mut Node,
+    type_path: &str,
+    defaults: &Node,
+    version: u32,
+    renames: &[Rename],
+) -> Vec<String> {
+    let mut changes = vec![];
+    let (Some(fields), Some(default_fields)) = (
+        resource_fields(file, type_path),
+        resource_fields_ref(defaults, type_path),
+    ) else {
+        return changes;
+    };
+
+    let file_version = fields
+        .iter()
+        .find(|(name, _)| name == "version")
+        .and_then(|(_, value)| match value {
+            Node::Atom(atom) => atom.parse().ok(),
+            _ => None,
+        })
+        // Files from before the configs were versioned
+        .unwrap_or(0);
+    if file_version > version {
+        warn!("{type_path} has version {file_version} which is newer than this build ({version})");
+    }
+
+    for rename in renames
+        .iter()
+        .filter(|rename| rename.version > file_version)
+    {
+        if let Some((name, _)) = fields.iter_mut().find(|(name, _)| name == rename.from) {
+            *name = rename.to.to_string();
+            changes.push(format!("renamed {} to {}", rename.from, rename.to));
+        }
+    }
+
+    fields.retain(|(name, _)| {
+        let known = default_fields.iter().any(|(default, _)| default == name);
+        if !known {
+            changes.push(format!("removed unknown field {name}"));
+        }
+        known
+    });
+    for (name, value) in default_fields {
+        if file_version < version
+            && name != "version"
+            && !fields.iter().any(|(field, _)| field == name)
+        {
+            changes.push(format!("added {name} with its default value"));
+            fields.push((name.clone(), value.clone()));
+        }
+    }
+
+    if file_version != version {
+        changes.push(format!("version {file_version} -> {version}"));
+        fields.retain(|(name, _)| name != "version");
+        fields.push(("version".into(), Node::Atom(version.to_string())));
+    }
+    changes
+}
+
+fn resource_entry<'a>(file: &'a Node, type_path: &str) -> Option<&'a Node> {
+    let Node::Struct { fields, .. } = file else {
+        return None;
+    };
+    let (_, Node::Map(resources)) = fields.iter().find(|(name, _)| name == "resources")? else {
+        return None;
+    };
+    let key = Node::Atom(format!("{type_path:?}"));
+    resources
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| value)
+}
+
+fn resource_fields_ref<'a>(file: &'a Node, type_path: &str) -> Option<&'a Vec<(String, Node)>> {
+    match resource_entry(file, type_path)? {
+        Node::Struct { fields, .. } => Some(fields),
+        _ => None,
+    }
+}
+
+fn resource_fields<'a>(file: &'a mut Node, type_path: &str) -> Option<&'a mut Vec<(String, Node)>> {
+    let Node::Struct { fields, .. } = file else {
+        return None;
+    };
+    let (_, Node::Map(resources)) = fields.iter_mut().find(|(name, _)| name == "resources")? else {
+        return None;
+    };
+    let key = Node::Atom(format!("{type_path:?}"));
+    match resources.iter_mut().find(|(k, _)| *k == key)? {
+        (_, Node::Struct { fields, .. }) => Some(fields),
+        _ => None,
+    }
+}
+
+/// Migrates a config file, returns the upgraded file content if anything changed
+fn migrate_file(
+    path: &str,
+    type_path: &str,
+    defaults: &str,
+    version: u32,
+    renames: &[Rename],
+) -> Option<String> {
+    let full_path = format!("assets/{path}");
+    let text = std::fs::read_to_string(&full_path).ok()?;
+    let (mut file, defaults) = match (ron_format::parse(&text), ron_format::parse(defaults)) {
+        (Ok(file), Ok(defaults)) => (file, defaults),
+        (Err(err), _) | (_, Err(err)) => {
+            error!("failed to parse {full_path} for migration: {err}");
+            return None;
+        }
+    };
+    let changes = migrate(&mut file, type_path, &defaults, version, renames);
+    if changes.is_empty() {
+        return None;
+    }
+    info!("migrated {full_path}:\n  {}", changes.join("\n  "));
+    Some(ron_format::to_pretty_string(&file))
+}
+
+fn load_in_memory(
+    type_registry: &TypeRegistry,
+    scenes: &mut Assets<DynamicScene>,
+    text: &str,
+) -> Option<Handle<DynamicScene>> {
+    let mut deserializer = ron::de::Deserializer::from_str(text).ok()?;
+    let scene = SceneDeserializer { type_registry }
+        .deserialize(&mut deserializer)
+        .map_err(|err| error!("failed to load the migrated config: {err}"))
+        .ok()?;
+    Some(scenes.add(scene))
+}
+
+pub fn migrate_configs(world: &mut World) {
+    let mut migrated = MigratedConfigs::default();
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let write_back = !world.resource::<CliArgs>().no_config_writeback;
+        let type_registry = world.resource::<AppTypeRegistry>().clone();
+        let configs = [
+            (
+                "scene_config.scn.ron",
+                "bevy_forest_scene::SceneConfig",
+                serialize_config(&type_registry, SceneConfig::default()),
+                SCENE_CONFIG_VERSION,
+                SCENE_CONFIG_RENAMES,
+            ),
+            (
+                "terrain_config.scn.ron",
+                "bevy_forest_scene::terrain::TerrainConfig",
+                serialize_config(&type_registry, TerrainConfig::default()),
+                TERRAIN_CONFIG_VERSION,
+                TERRAIN_CONFIG_RENAMES,
+            ),
+        ];
+
+        for (path, type_path, defaults, version, renames) in configs {
+            let Some(upgraded) = migrate_file(path, type_path, &defaults, version, renames) else {
+                continue;
+            };
+            if write_back {
+                let full_path = format!("assets/{path}");
+                let written = std::fs::copy(&full_path, format!("{full_path}.bak"))
+                    .and_then(|_| std::fs::write(&full_path, &upgraded));
+                if let Err(err) = written {
+                    error!("failed to write the migrated {full_path}: {err}");
+                }
+            } else {
+                let mut scenes = world.resource_mut::<Assets<DynamicScene>>();
+                if let Some(handle) = load_in_memory(&type_registry.read(), &mut scenes, &upgraded)
+                {
+                    migrated.scenes.insert(path, handle);
+                }
+            }
+        }
+    }
+
+    world.insert_resource(migrated);
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::core_pipeline::tonemapping::Tonemapping;
+
+    use super::*;
+
+    const SCENE_CONFIG_PATH: &str = "bevy_forest_scene::SceneConfig";
+    const TERRAIN_CONFIG_PATH: &str = "bevy_forest_scene::terrain::TerrainConfig";
+
+    /// `scene_config.scn.ron` as shipped before the configs were versioned
+    const UNVERSIONED_SCENE_CONFIG: &str = r#"(
+  resources: {
+    "bevy_forest_scene::SceneConfig": (
+      env_map_intensity: 2000.0,
+      skybox_brightness: 2000.0,
+      fog_color: Srgba((
+        red: 1.0,
+        green: 1.0,
+        blue: 1.0,
+        alpha: 1.0,
+      )),
+      fog_ambient_intensity: 0.1,
+      fog_light_intensity: 1.0,
+      directional_light_color: Srgba((
+        red: 1.0,
+        green: 0.875,
+        blue: 0.75,
+        alpha: 1.0,
+      )),
+      directional_light_looking_to: (
+        x: -10.0,
+        y: -1.0,
+        z: 7.0,
+      ),
+      tonemapping: TonyMcMapface,
+      motion_blur_shutter_angle: 1.0,
+      motion_blur_samples: 2,
+      ssr: ScreenSpaceReflectionsSettings (
+        perceptual_roughness_threshold: 0.1,
+        linear_steps: 8,
+        bisection_steps: 4,
+        use_secant: true,
+        thickness: 4.0,
+        linear_march_exponent: 1.0,
+      ),
+      camera_walk_speed: 5.0,
+      color_grading: ColorGradingSection (
+        saturation: 1.0,
+        contrast: 1.0,
+        gamma: 1.0,
+        gain: 2.5,
+        lift: -0.25,
+      )
+    ),
+  },
+  entities: {},
+)"#;
+
+    /// `terrain_config.scn.ron` as written by version 1, before the chunks, the shoreline and the
+    /// tree settings
+    const VERSION_1_TERRAIN_CONFIG: &str = r#"(
+  resources: {
+    "bevy_forest_scene::terrain::TerrainConfig": (
+      version: 1,
+      half_size: 300,
+      seed: 30,
+      frequency: 0.05,
+      octaves: 6,
+      density: 0.1,
+      max_steepness: 0.7,
+      use_depth_map: false,
+      rotation: 1.0,
+      water_level: 0.0,
+      lod1_ratio: 0.4,
+      lod2_ratio: 0.15,
+      lod_distance: 80.0,
+      scatter_layers: [],
+      lens_flare: true,
+    ),
+  },
+  entities: {},
+)"#;
+
+    /// Migrates the file and loads the result, the config has to load for the migration to count
+    fn migrate_and_load<R: Resource + Default>(
+        file: &str,
+        type_path: &str,
+        version: u32,
+        renames: &[Rename],
+    ) -> (Vec<String>, R, Node) {
+        let type_registry = config_type_registry();
+        let defaults = ron_format::parse(&serialize_config(&type_registry, R::default())).unwrap();
+        let mut file = ron_format::parse(file).unwrap();
+        let changes = migrate(&mut file, type_path, &defaults, version, renames);
+        let config = load_config(&type_registry, &ron_format::to_pretty_string(&file));
+        (changes, config, file)
+    }
+
+    #[test]
+    fn unversioned_scene_configs_are_upgraded() {
+        let (changes, config, _) = migrate_and_load::<SceneConfig>(
+            UNVERSIONED_SCENE_CONFIG,
+            SCENE_CONFIG_PATH,
+            SCENE_CONFIG_VERSION,
+            SCENE_CONFIG_RENAMES,
+        );
+        assert!(changes.contains(&format!("version 0 -> {SCENE_CONFIG_VERSION}")));
+        assert!(changes.contains(&"added snow_height with its default value".to_string()));
+        assert_eq!(config.version, SCENE_CONFIG_VERSION);
+
+        // What the file had is kept
+        assert_eq!(config.fog_light_intensity, 1.0);
+        assert_eq!(config.motion_blur_samples, 2);
+        assert_eq!(config.tonemapping, Tonemapping::TonyMcMapface);
+        assert_eq!(config.camera_walk_speed, 5.0);
+        assert_eq!(config.color_grading.gain, 2.5);
+        // and the rest comes from the defaults
+        let default = SceneConfig::default();
+        assert_eq!(config.snow_height, default.snow_height);
+        assert_eq!(config.transition_seconds, default.transition_seconds);
+    }
+
+    #[test]
+    fn version_1_terrain_configs_are_upgraded() {
+        let (changes, config, mut file) = migrate_and_load::<TerrainConfig>(
+            VERSION_1_TERRAIN_CONFIG,
+            TERRAIN_CONFIG_PATH,
+            TERRAIN_CONFIG_VERSION,
+            TERRAIN_CONFIG_RENAMES,
+        );
+        assert!(changes.contains(&format!("version 1 -> {TERRAIN_CONFIG_VERSION}")));
+        assert!(changes.contains(&"added chunk_size with its default value".to_string()));
+        assert!(changes.contains(&"removed unknown field lens_flare".to_string()));
+
+        assert_eq!(config.half_size, 300);
+        assert_eq!(config.seed, 30);
+        assert_eq!(config.frequency, 0.05);
+        assert_eq!(config.max_steepness, 0.7);
+        assert_eq!(config.rotation, 1.0);
+        let default = TerrainConfig::default();
+        assert_eq!(config.chunk_size, default.chunk_size);
+        assert_eq!(config.tree_nodes, default.tree_nodes);
+
+        // Migrating the upgraded file again finds nothing to do
+        let type_registry = config_type_registry();
+        let defaults = ron_format::parse(&serialize_config(&type_registry, default)).unwrap();
+        let changes = migrate(
+            &mut file,
+            TERRAIN_CONFIG_PATH,
+            &defaults,
+            TERRAIN_CONFIG_VERSION,
+            TERRAIN_CONFIG_RENAMES,
+        );
+        assert!(changes.is_empty(), "{changes:?}");
+    }
+
+    #[test]
+    fn renames_only_apply_to_older_files() {
+        let renames = [Rename {
+            version: 2,
+            from: "seed_value",
+            to: "seed",
+        }];
+        let file = VERSION_1_TERRAIN_CONFIG.replace("seed:", "seed_value:");
+        let (changes, config, _) =
+            migrate_and_load::<TerrainConfig>(&file, TERRAIN_CONFIG_PATH, 2, &renames);
+        assert!(changes.contains(&"renamed seed_value to seed".to_string()));
+        assert_eq!(config.seed, 30);
+
+        let file = file.replace("version: 1", "version: 2");
+        let (changes, config, _) =
+            migrate_and_load::<TerrainConfig>(&file, TERRAIN_CONFIG_PATH, 2, &renames);
+        assert!(!changes.contains(&"renamed seed_value to seed".to_string()));
+        assert_eq!(config.seed, TerrainConfig::default().seed);
+    }
+}
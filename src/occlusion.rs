@@ -0,0 +1,264 @@
+//! Hides the trees behind a ridge.
+//!
+//! The trees are grouped in clusters on a grid. Every frame, the clusters in the camera frustum
+//! cast a few rays toward the camera and the cluster is hidden when the terrain blocks all of
+//! them. The rays are marched through a max height pyramid of the heightfield so most of their
+//! length is skipped in a few steps. The terrain itself is a single mesh so it's never culled.
+//!
+//! F4 draws the bounds of the clusters, the culled ones in red.
+
+use bevy::{
+    math::Affine3A,
+    prelude::*,
+    render::primitives::{Aabb, Frustum},
+    utils::HashMap,
+};
+
+use crate::{
+    debug_palette::DebugPalette, heightfield::TerrainHeightfield, overlay::StatsOverlay,
+    split_view::SecondaryCamera, terrain::TreeInstance, SceneConfig,
+};
+
+/// Side of the grid cells used to group the trees
+const CLUSTER_SIZE: f32 = 32.0;
+/// Added around the tree positions to cover the crowns
+const TREE_RADIUS: f32 = 4.0;
+const TREE_HEIGHT: f32 = 20.0;
+/// Consecutive occluded frames before a cluster is hidden, it's shown as soon as it's visible
+const HIDE_AFTER_FRAMES: u32 = 3;
+/// Rays stop a bit before the cluster so the ground it stands on doesn't occlude it
+const TARGET_MARGIN: f32 = 0.02;
+
+struct TreeCluster {
+    min: Vec3,
+    max: Vec3,
+    trees: Vec<Entity>,
+    occluded_frames: u32,
+}
+
+impl TreeCluster {
+    fn hidden(&self) -> bool {
+        self.occluded_frames >= HIDE_AFTER_FRAMES
+    }
+
+    /// Points of the cluster that need to be hidden for the whole cluster to be hidden
+    fn sample_points(&self) -> [Vec3; 5] {
+        let (min, max) = (self.min, self.max);
+        [
+            vec3(min.x, max.y, min.z),
+            vec3(max.x, max.y, min.z),
+            vec3(min.x, max.y, max.z),
+            vec3(max.x, max.y, max.z),
+            (min + max) / 2.0,
+        ]
+    }
+}
+
+/// Maximum height of every 2^level by 2^level block of heightfield cells
+struct MaxHeightPyramid {
+    levels: Vec<Vec<f32>>,
+    /// Number of cells on each side of every level
+    sizes: Vec<usize>,
+}
+
+impl MaxHeightPyramid {
+    fn new(heightfield: &TerrainHeightfield) -> Self {
+        let cells = heightfield.resolution - 1;
+        let mut level = Vec::with_capacity(cells * cells);
+        for z in 0..cells {
+            for x in 0..cells {
+                let max = heightfield
+                    .height(x, z)
+                    .max(heightfield.height(x + 1, z))
+                    .max(heightfield.height(x, z + 1))
+                    .max(heightfield.height(x + 1, z + 1));
+                level.push(max);
+            }
+        }
+
+        let mut levels = vec![level];
+        let mut sizes = vec![cells];
+        while *sizes.last().unwrap() > 1 {
+            let (previous, size) = (levels.last().unwrap(), *sizes.last().unwrap());
+            let next_size = size.div_ceil(2);
+            let mut next = vec![f32::MIN; next_size * next_size];
+            for z in 0..size {
+                for x in 0..size {
+                    let cell = &mut next[z / 2 * next_size + x / 2];
+                    *cell = cell.max(previous[z * size + x]);
+                }
+            }
+            levels.push(next);
+            sizes.push(next_size);
+        }
+        Self { levels, sizes }
+    }
+
+    /// Highest point in a rectangle of grid coordinates, at most 2 by 2 cells are read
+    fn max_in(&self, min: Vec2, max: Vec2) -> f32 {
+        let extent = (max - min).max_element().max(1.0);
+        let level = (extent.log2().ceil() as usize).min(self.levels.len() - 1);
+        let (cells, size) = (&self.levels[level], self.sizes[level]);
+        let cell_size = (1 << level) as f32;
+        let last = size as i64 - 1;
+        let to_cell = |v: f32| (v / cell_size).floor() as i64;
+        let (x0, x1) = (to_cell(min.x).max(0), to_cell(max.x).min(last));
+        let (z0, z1) = (to_cell(min.y).max(0), to_cell(max.y).min(last));
+        let mut highest = f32::MIN;
+        for z in z0..=z1 {
+            for x in x0..=x1 {
+                highest = highest.max(cells[z as usize * size + x as usize]);
+            }
+        }
+        highest
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct OcclusionCulling {
+    pub debug: bool,
+    pyramid: Option<MaxHeightPyramid>,
+    clusters: Vec<TreeCluster>,
+}
+
+impl OcclusionCulling {
+    /// Checks if the terrain is above the segment anywhere between the two world positions
+    fn blocked(&self, heightfield: &TerrainHeightfield, from: Vec3, to: Vec3) -> bool {
+        let Some(pyramid) = &self.pyramid else {
+            return false;
+        };
+        let (a, b) = (heightfield.to_grid(from.xz()), heightfield.to_grid(to.xz()));
+        if from.y.min(to.y) > pyramid.max_in(a.min(b), a.max(b)) {
+            return false;
+        }
+        if a.distance_squared(b) <= 1.0 {
+            let mid = (from + to) / 2.0;
+            return heightfield.height_at(mid.xz()).is_some_and(|h| h > mid.y);
+        }
+        let mid = (from + to) / 2.0;
+        self.blocked(heightfield, from, mid) || self.blocked(heightfield, mid, to)
+    }
+}
+
+pub fn toggle_occlusion_debug(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut occlusion: ResMut<OcclusionCulling>,
+) {
+    if keyboard.just_pressed(KeyCode::F4) {
+        occlusion.debug = !occlusion.debug;
+    }
+}
+
+pub fn build_height_pyramid(
+    heightfield: Res<TerrainHeightfield>,
+    mut occlusion: ResMut<OcclusionCulling>,
+) {
+    let _span = info_span!("build_height_pyramid").entered();
+    occlusion.pyramid = Some(MaxHeightPyramid::new(&heightfield));
+}
+
+/// Groups the trees in clusters every time trees are spawned or despawned.
+///
+/// The generation and the edit modes spawn the trees through commands, following the entities
+/// instead of the `TreePlacements` keeps the clusters from missing them until the next change.
+pub fn build_tree_clusters(
+    trees: Query<(Entity, &Transform), With<TreeInstance>>,
+    added: Query<(), Added<TreeInstance>>,
+    mut removed: RemovedComponents<TreeInstance>,
+    mut occlusion: ResMut<OcclusionCulling>,
+) {
+    // Read before returning so the removals of this frame are not seen again on the next run
+    let removed = removed.read().count() > 0;
+    if added.is_empty() && !removed {
+        return;
+    }
+    let mut clusters = HashMap::<IVec2, TreeCluster>::default();
+    for (entity, transform) in &trees {
+        let position = transform.translation;
+        let cell = (position.xz() / CLUSTER_SIZE).floor().as_ivec2();
+        let min = position - vec3(TREE_RADIUS, 0.0, TREE_RADIUS);
+        let max = position + vec3(TREE_RADIUS, TREE_HEIGHT, TREE_RADIUS);
+        let cluster = clusters.entry(cell).or_insert(TreeCluster {
+            min,
+            max,
+            trees: vec![],
+            occluded_frames: 0,
+        });
+        cluster.min = cluster.min.min(min);
+        cluster.max = cluster.max.max(max);
+        cluster.trees.push(entity);
+    }
+    occlusion.clusters = clusters.into_values().collect();
+}
+
+pub fn cull_occluded_trees(
+    scene_config: Res<SceneConfig>,
+    heightfield: Res<TerrainHeightfield>,
+    mut occlusion: ResMut<OcclusionCulling>,
+    camera: Query<(&GlobalTransform, &Frustum), (With<Camera3d>, Without<SecondaryCamera>)>,
+    mut visibility: Query<&mut Visibility, With<TreeInstance>>,
+    mut stats: ResMut<StatsOverlay>,
+    palette: Res<DebugPalette>,
+    mut gizmos: Gizmos,
+) {
+    let Ok((camera_transform, frustum)) = camera.get_single() else {
+        return;
+    };
+    let _span = info_span!("cull_occluded_trees", clusters = occlusion.clusters.len()).entered();
+    let eye = camera_transform.translation();
+
+    let mut clusters = std::mem::take(&mut occlusion.clusters);
+    for cluster in &mut clusters {
+        let was_hidden = cluster.hidden();
+        let aabb = Aabb::from_min_max(cluster.min, cluster.max);
+        if !scene_config.occlusion_culling {
+            cluster.occluded_frames = 0;
+        } else if frustum.intersects_obb(&aabb, &Affine3A::IDENTITY, true, true) {
+            // Outside the frustum the previous state is kept, the cluster isn't rendered anyway
+            let occluded = cluster.sample_points().iter().all(|&point| {
+                let target = point.lerp(eye, TARGET_MARGIN);
+                occlusion.blocked(&heightfield, eye, target)
+            });
+            cluster.occluded_frames = if occluded {
+                cluster.occluded_frames + 1
+            } else {
+                0
+            };
+        }
+
+        let hidden = cluster.hidden();
+        if hidden != was_hidden {
+            let mut trees = visibility.iter_many_mut(&cluster.trees);
+            while let Some(mut visibility) = trees.fetch_next() {
+                *visibility = if hidden {
+                    Visibility::Hidden
+                } else {
+                    Visibility::Inherited
+                };
+            }
+        }
+
+        if occlusion.debug {
+            let color = if hidden {
+                palette.occluded
+            } else {
+                palette.visible
+            };
+            gizmos.cuboid(
+                Transform::from_translation((cluster.min + cluster.max) / 2.0)
+                    .with_scale(cluster.max - cluster.min),
+                color,
+            );
+        }
+    }
+
+    let culled = clusters.iter().filter(|cluster| cluster.hidden()).count();
+    stats.set(
+        "Occlusion",
+        format!(
+            "{culled} culled / {} visible tree clusters",
+            clusters.len() - culled
+        ),
+    );
+    occlusion.clusters = clusters;
+}
@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+
+use bevy::{
+    diagnostic::{DiagnosticsStore, FrameTimeDiagnosticsPlugin},
+    prelude::*,
+};
+
+use crate::{
+    diagnostics_log::DiagnosticsLogText, scene_debug::SceneDebugText,
+    tree_inspect::TreeInspectionText,
+};
+
+/// Lines displayed in the stats overlay.
+///
+/// Any system can add its own line, they are displayed sorted by their label.
+#[derive(Resource, Default)]
+pub struct StatsOverlay {
+    lines: BTreeMap<&'static str, String>,
+}
+
+impl StatsOverlay {
+    pub fn set(&mut self, label: &'static str, value: impl Into<String>) {
+        self.lines.insert(label, value.into());
+    }
+
+    pub fn remove(&mut self, label: &'static str) {
+        self.lines.remove(label);
+    }
+}
+
+#[derive(Component)]
+pub struct StatsOverlayText;
+
+/// Errors displayed on screen until the source of the error is fixed
+#[derive(Resource, Default)]
+pub struct ErrorBox {
+    /// Messages grouped by the system that reported them
+    messages: BTreeMap<String, Vec<String>>,
+}
+
+impl ErrorBox {
+    pub fn push(&mut self, source: &str, message: impl Into<String>) {
+        self.messages
+            .entry(source.to_string())
+            .or_default()
+            .push(message.into());
+    }
+
+    pub fn clear(&mut self, source: &str) {
+        self.messages.remove(source);
+    }
+}
+
+#[derive(Component)]
+pub struct ErrorBoxText;
+
+/// Every UI node of the overlay
+pub type OverlayNodes = Or<(
+    With<StatsOverlayText>,
+    With<ErrorBoxText>,
+    With<SceneDebugText>,
+    With<TreeInspectionText>,
+    With<DiagnosticsLogText>,
+)>;
+
+pub fn spawn_stats_overlay(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 16.0,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(5.0),
+            left: Val::Px(5.0),
+            ..default()
+        }),
+        StatsOverlayText,
+    ));
+
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 16.0,
+                color: Color::srgb(1.0, 0.3, 0.3),
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(5.0),
+            left: Val::Px(5.0),
+            ..default()
+        })
+        .with_background_color(Color::srgba(0.0, 0.0, 0.0, 0.6)),
+        ErrorBoxText,
+    ));
+}
+
+pub fn update_error_box(
+    error_box: Res<ErrorBox>,
+    mut text: Query<(&mut Text, &mut Visibility), With<ErrorBoxText>>,
+) {
+    if !error_box.is_changed() {
+        return;
+    }
+    let messages = error_box.messages.values().flatten().cloned();
+    let value = messages.collect::<Vec<_>>().join("\n");
+    for (mut text, mut visibility) in &mut text {
+        *visibility = if value.is_empty() {
+            Visibility::Hidden
+        } else {
+            Visibility::Inherited
+        };
+        text.sections[0].value.clone_from(&value);
+    }
+}
+
+pub fn update_stats_overlay(
+    diagnostics: Res<DiagnosticsStore>,
+    stats: Res<StatsOverlay>,
+    mut text: Query<&mut Text, With<StatsOverlayText>>,
+) {
+    let fps = diagnostics
+        .get(&FrameTimeDiagnosticsPlugin::FPS)
+        .and_then(|fps| fps.smoothed())
+        .unwrap_or_default();
+
+    let mut value = format!("FPS: {fps:.0}");
+    for (label, line) in &stats.lines {
+        value.push_str(&format!("\n{label}: {line}"));
+    }
+
+    for mut text in &mut text {
+        text.sections[0].value.clone_from(&value);
+    }
+}
+
+pub fn toggle_stats_overlay(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut overlay: Query<&mut Visibility, With<StatsOverlayText>>,
+) {
+    if !keyboard.just_pressed(KeyCode::F3) {
+        return;
+    }
+    for mut visibility in &mut overlay {
+        *visibility = match *visibility {
+            Visibility::Hidden => Visibility::Inherited,
+            _ => Visibility::Hidden,
+        };
+    }
+}
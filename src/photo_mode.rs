@@ -0,0 +1,305 @@
+//! Framing aids to compose shots.
+//!
+//! F2 enters the photo mode. 1, 2 and 3 toggle the rule of thirds grid, the center cross and the
+//! golden spiral, C cycles the crop bars and F12 saves a screenshot cropped like the view. The
+//! print render is cropped the same way. Everything is reset when leaving the photo mode.
+
+use std::f32::consts::{FRAC_PI_2, PI};
+
+use bevy::{
+    math::vec2, prelude::*, render::view::screenshot::ScreenshotManager, tasks::IoTaskPool,
+    window::PrimaryWindow,
+};
+
+use crate::{
+    debug_palette::DebugPalette, overlay::StatsOverlay, print_render::PrintRender,
+    split_view::SecondaryCamera,
+};
+
+/// The guides are drawn on a plane this far in front of the camera
+const GUIDE_DISTANCE: f32 = 0.5;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CropAspect {
+    Scope,
+    Wide,
+    Portrait,
+}
+
+impl CropAspect {
+    /// Width divided by height
+    pub fn ratio(self) -> f32 {
+        match self {
+            CropAspect::Scope => 2.39,
+            CropAspect::Wide => 16.0 / 9.0,
+            CropAspect::Portrait => 4.0 / 5.0,
+        }
+    }
+
+    fn next(crop: Option<Self>) -> Option<Self> {
+        match crop {
+            None => Some(CropAspect::Scope),
+            Some(CropAspect::Scope) => Some(CropAspect::Wide),
+            Some(CropAspect::Wide) => Some(CropAspect::Portrait),
+            Some(CropAspect::Portrait) => None,
+        }
+    }
+
+    fn label(crop: Option<Self>) -> &'static str {
+        match crop {
+            None => "no crop",
+            Some(CropAspect::Scope) => "2.39:1",
+            Some(CropAspect::Wide) => "16:9",
+            Some(CropAspect::Portrait) => "4:5",
+        }
+    }
+}
+
+/// Largest centered rectangle of the aspect ratio that fits in an image of this size
+pub fn crop_rect(size: Vec2, crop: Option<CropAspect>) -> Rect {
+    let Some(crop) = crop else {
+        return Rect::from_corners(Vec2::ZERO, size);
+    };
+    let cropped = if size.x / size.y > crop.ratio() {
+        vec2(size.y * crop.ratio(), size.y)
+    } else {
+        vec2(size.x, size.x / crop.ratio())
+    };
+    Rect::from_center_size(size / 2.0, cropped)
+}
+
+#[derive(Resource, Default)]
+pub struct PhotoMode {
+    pub active: bool,
+    pub thirds: bool,
+    pub center: bool,
+    pub spiral: bool,
+    pub crop: Option<CropAspect>,
+}
+
+/// Black bars covering what's outside of the crop
+#[derive(Component)]
+pub struct CropBar;
+
+pub fn spawn_crop_bars(mut commands: Commands) {
+    for _ in 0..4 {
+        commands.spawn((
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    ..default()
+                },
+                background_color: Color::BLACK.into(),
+                visibility: Visibility::Hidden,
+                ..default()
+            },
+            CropBar,
+        ));
+    }
+}
+
+pub fn photo_mode_input(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut photo_mode: ResMut<PhotoMode>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    if keyboard.just_pressed(KeyCode::F2) {
+        let active = !photo_mode.active;
+        *photo_mode = PhotoMode {
+            active,
+            ..default()
+        };
+    }
+    if !photo_mode.active {
+        stats.remove("Photo mode");
+        return;
+    }
+
+    if keyboard.just_pressed(KeyCode::Digit1) {
+        photo_mode.thirds = !photo_mode.thirds;
+    }
+    if keyboard.just_pressed(KeyCode::Digit2) {
+        photo_mode.center = !photo_mode.center;
+    }
+    if keyboard.just_pressed(KeyCode::Digit3) {
+        photo_mode.spiral = !photo_mode.spiral;
+    }
+    if keyboard.just_pressed(KeyCode::KeyC) {
+        photo_mode.crop = CropAspect::next(photo_mode.crop);
+    }
+    stats.set("Photo mode", CropAspect::label(photo_mode.crop));
+}
+
+pub fn update_crop_bars(
+    photo_mode: Res<PhotoMode>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    mut bars: Query<(&mut Style, &mut Visibility), With<CropBar>>,
+) {
+    let Ok(window) = window.get_single() else {
+        return;
+    };
+    let size = window.size();
+    let rect = crop_rect(size, photo_mode.crop);
+    // Top, bottom, left and right, the unused ones end up empty
+    let placements = [
+        (0.0, 0.0, size.x, rect.min.y),
+        (0.0, rect.max.y, size.x, size.y - rect.max.y),
+        (0.0, 0.0, rect.min.x, size.y),
+        (rect.max.x, 0.0, size.x - rect.max.x, size.y),
+    ];
+    for ((mut style, mut visibility), (left, top, width, height)) in bars.iter_mut().zip(placements)
+    {
+        style.left = Val::Px(left);
+        style.top = Val::Px(top);
+        style.width = Val::Px(width);
+        style.height = Val::Px(height);
+        *visibility = if photo_mode.active && photo_mode.crop.is_some() {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+    }
+}
+
+/// Quarter circles through the squares cut out of the frame one after the other
+fn golden_spiral(rect: Rect) -> Vec<Vec2> {
+    let (mut min, mut max) = (rect.min, rect.max);
+    // Portrait frames start with the square at the top
+    let first = if rect.width() >= rect.height() { 0 } else { 1 };
+    let mut points = vec![];
+    for step in first..first + 10 {
+        let side = (max - min).min_element();
+        if side < 2.0 {
+            break;
+        }
+        // The squares go around the frame in the left, top, right, bottom order, y is down
+        let center = match step % 4 {
+            0 => {
+                min.x += side;
+                vec2(min.x, max.y)
+            }
+            1 => {
+                min.y += side;
+                vec2(min.x, min.y)
+            }
+            2 => {
+                max.x -= side;
+                vec2(max.x, min.y)
+            }
+            _ => {
+                max.y -= side;
+                vec2(max.x, max.y)
+            }
+        };
+        let start = PI + (step % 4) as f32 * FRAC_PI_2;
+        for i in 0..=16 {
+            let angle = start + FRAC_PI_2 * i as f32 / 16.0;
+            points.push(center + side * vec2(angle.cos(), angle.sin()));
+        }
+    }
+    points
+}
+
+pub fn draw_framing_guides(
+    photo_mode: Res<PhotoMode>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    print_render: Option<Res<PrintRender>>,
+    camera: Query<(&Camera, &GlobalTransform), (With<Camera3d>, Without<SecondaryCamera>)>,
+    palette: Res<DebugPalette>,
+    mut gizmos: Gizmos,
+) {
+    // Keep the guides out of the screenshots and the print render
+    if !photo_mode.active || keyboard.just_pressed(KeyCode::F12) || print_render.is_some() {
+        return;
+    }
+    let Ok((camera, camera_transform)) = camera.get_single() else {
+        return;
+    };
+    let Some(size) = camera.logical_viewport_size() else {
+        return;
+    };
+    let rect = crop_rect(size, photo_mode.crop);
+    let to_world = |point: Vec2| {
+        camera
+            .viewport_to_world(camera_transform, point)
+            .map(|ray| ray.get_point(GUIDE_DISTANCE))
+    };
+    let color = palette.guides;
+    let mut line = |a: Vec2, b: Vec2| {
+        if let (Some(a), Some(b)) = (to_world(a), to_world(b)) {
+            gizmos.line(a, b, color);
+        }
+    };
+    let at = |x: f32, y: f32| rect.min + rect.size() * vec2(x, y);
+
+    if photo_mode.thirds {
+        for t in [1.0 / 3.0, 2.0 / 3.0] {
+            line(at(t, 0.0), at(t, 1.0));
+            line(at(0.0, t), at(1.0, t));
+        }
+    }
+    if photo_mode.center {
+        let arm = rect.size().min_element() * 0.05;
+        let center = rect.center();
+        line(center - Vec2::X * arm, center + Vec2::X * arm);
+        line(center - Vec2::Y * arm, center + Vec2::Y * arm);
+    }
+    if photo_mode.spiral {
+        let points: Vec<_> = golden_spiral(rect)
+            .into_iter()
+            .filter_map(to_world)
+            .collect();
+        gizmos.linestrip(points, color);
+    }
+}
+
+pub fn take_screenshot(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    photo_mode: Res<PhotoMode>,
+    window: Query<Entity, With<PrimaryWindow>>,
+    mut screenshot_manager: ResMut<ScreenshotManager>,
+) {
+    if !photo_mode.active || !keyboard.just_pressed(KeyCode::F12) {
+        return;
+    }
+    let Ok(window) = window.get_single() else {
+        return;
+    };
+    save_screenshot(&mut screenshot_manager, window, photo_mode.crop);
+}
+
+/// Saves the next frame of the window to the working directory, cropped like the view
+pub fn save_screenshot(
+    screenshot_manager: &mut ScreenshotManager,
+    window: Entity,
+    crop: Option<CropAspect>,
+) {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let path = format!("screenshot_{timestamp}.png");
+    let result = screenshot_manager.take_screenshot(window, move |image| {
+        let size = image.size();
+        let rect = crop_rect(size.as_vec2(), crop);
+        let (min, cropped) = (rect.min.as_uvec2(), rect.size().as_uvec2());
+        let image = match image.try_into_dynamic() {
+            Ok(image) => image.crop_imm(min.x, min.y, cropped.x, cropped.y),
+            Err(err) => {
+                error!("failed to convert the screenshot: {err:?}");
+                return;
+            }
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        IoTaskPool::get()
+            .spawn(async move {
+                match image.to_rgb8().save(&path) {
+                    Ok(()) => info!("screenshot saved to {path}"),
+                    Err(err) => error!("failed to save the screenshot to {path}: {err}"),
+                }
+            })
+            .detach();
+    });
+    if result.is_err() {
+        warn!("a screenshot is already being taken");
+    }
+}
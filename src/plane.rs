@@ -1,7 +1,9 @@
+use std::collections::BTreeSet;
+
 use bevy::{
     prelude::*,
     render::{
-        mesh::{Indices, PrimitiveTopology},
+        mesh::{Indices, PrimitiveTopology, VertexAttributeValues},
         render_asset::RenderAssetUsages,
     },
 };
@@ -88,3 +90,196 @@ impl From<Plane> for Mesh {
         .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
     }
 }
+
+/// Recomputes the normals and tangents around the modified vertices of a grid of `size` vertices
+/// laid out like a subdivided plane, instead of running `compute_smooth_normals` and
+/// `generate_tangents` over the whole mesh.
+///
+/// Every vertex sharing a quad with a modified one is updated, the rest of the mesh is left as it
+/// is. The normals are the same as a full recompute, the triangles are summed in the order of the
+/// index buffer. The quads come from the grid rather than the index buffer, so the border of a
+/// terrain chunk counts even though it isn't drawn. The tangents follow the uvs without the angle
+/// weighting of mikktspace, on a height grid they're within a tiny bit of what `generate_tangents`
+/// gives.
+pub fn recompute_normals_and_tangents(mesh: &mut Mesh, size: UVec2, modified: &[usize]) {
+    let (
+        Some(VertexAttributeValues::Float32x3(positions)),
+        Some(VertexAttributeValues::Float32x2(uvs)),
+    ) = (
+        mesh.attribute(Mesh::ATTRIBUTE_POSITION),
+        mesh.attribute(Mesh::ATTRIBUTE_UV_0),
+    )
+    else {
+        return;
+    };
+    let (width, depth) = (size.x as usize, size.y as usize);
+    if width < 2 || depth < 2 || positions.len() != width * depth {
+        warn!("the mesh isn't a {width}x{depth} grid, its normals are left as they are");
+        return;
+    }
+
+    let mut region = BTreeSet::new();
+    for &vertex in modified {
+        let (x, z) = (vertex % width, vertex / width);
+        for z in z.saturating_sub(1)..=(z + 1).min(depth - 1) {
+            for x in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+                region.insert(z * width + x);
+            }
+        }
+    }
+
+    let mut updated = Vec::with_capacity(region.len());
+    for vertex in region {
+        let (x, z) = (vertex % width, vertex / width);
+        let mut normal = Vec3::ZERO;
+        let mut tangent = Vec3::ZERO;
+        let mut bitangent = Vec3::ZERO;
+        // The quads around the vertex, same triangles as in `From<Plane>`
+        for quad_z in z.saturating_sub(1)..=z.min(depth - 2) {
+            for quad_x in x.saturating_sub(1)..=x.min(width - 2) {
+                let quad = quad_z * width + quad_x;
+                for triangle in [
+                    [quad + width + 1, quad + 1, quad + width],
+                    [quad, quad + width, quad + 1],
+                ] {
+                    if !triangle.contains(&vertex) {
+                        continue;
+                    }
+                    let [a, b, c] = triangle.map(|i| Vec3::from(positions[i]));
+                    let [uv_a, uv_b, uv_c] = triangle.map(|i| Vec2::from(uvs[i]));
+                    let (edge_1, edge_2) = (b - a, c - a);
+                    let (delta_uv_1, delta_uv_2) = (uv_b - uv_a, uv_c - uv_a);
+                    normal += edge_1.cross(edge_2).normalize();
+                    let det = delta_uv_1.perp_dot(delta_uv_2);
+                    if det.abs() > f32::EPSILON {
+                        tangent += (edge_1 * delta_uv_2.y - edge_2 * delta_uv_1.y) / det;
+                        bitangent += (edge_2 * delta_uv_1.x - edge_1 * delta_uv_2.x) / det;
+                    }
+                }
+            }
+        }
+        let normal = normal.normalize_or_zero();
+        let tangent = (tangent - normal * normal.dot(tangent)).normalize_or_zero();
+        // Negated like `generate_tangents` does, the bitangent of bevy points up the texture
+        let handedness = if normal.cross(tangent).dot(bitangent) < 0.0 {
+            1.0
+        } else {
+            -1.0
+        };
+        updated.push((vertex, normal, tangent.extend(handedness)));
+    }
+
+    if let Some(VertexAttributeValues::Float32x3(normals)) =
+        mesh.attribute_mut(Mesh::ATTRIBUTE_NORMAL)
+    {
+        for &(vertex, normal, _) in &updated {
+            normals[vertex] = normal.to_array();
+        }
+    }
+    if let Some(VertexAttributeValues::Float32x4(tangents)) =
+        mesh.attribute_mut(Mesh::ATTRIBUTE_TANGENT)
+    {
+        for &(vertex, _, tangent) in &updated {
+            tangents[vertex] = tangent.to_array();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    const WIDTH: usize = 32;
+
+    fn positions(mesh: &mut Mesh) -> &mut Vec<[f32; 3]> {
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute_mut(Mesh::ATTRIBUTE_POSITION)
+        else {
+            unreachable!()
+        };
+        positions
+    }
+
+    fn recompute_all(mesh: &mut Mesh) {
+        mesh.compute_smooth_normals();
+        mesh.generate_tangents().unwrap();
+    }
+
+    fn hills() -> Mesh {
+        let mut mesh = Mesh::from(Plane {
+            size: 64.0,
+            subdivisions: WIDTH as u32 - 2,
+        });
+        for position in positions(&mut mesh) {
+            position[1] = (position[0] * 0.2).sin() * 2.0 + (position[2] * 0.1).cos() * 3.0;
+        }
+        recompute_all(&mut mesh);
+        mesh
+    }
+
+    fn normals(mesh: &Mesh) -> Vec<Vec3> {
+        let Some(VertexAttributeValues::Float32x3(normals)) =
+            mesh.attribute(Mesh::ATTRIBUTE_NORMAL)
+        else {
+            unreachable!()
+        };
+        normals.iter().map(|&normal| Vec3::from(normal)).collect()
+    }
+
+    fn tangents(mesh: &Mesh) -> Vec<Vec4> {
+        let Some(VertexAttributeValues::Float32x4(tangents)) =
+            mesh.attribute(Mesh::ATTRIBUTE_TANGENT)
+        else {
+            unreachable!()
+        };
+        tangents
+            .iter()
+            .map(|&tangent| Vec4::from(tangent))
+            .collect()
+    }
+
+    #[test]
+    fn partial_recomputation_matches_a_full_one() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..8 {
+            // A bump under a brush at a random place, touching the border sometimes
+            let center = Vec2::new(
+                rng.gen_range(0.0..WIDTH as f32),
+                rng.gen_range(0.0..WIDTH as f32),
+            );
+            let radius = rng.gen_range(1.5..6.0);
+            let strength = rng.gen_range(-3.0..3.0);
+            let mut partial = hills();
+            let mut modified = vec![];
+            for (i, position) in positions(&mut partial).iter_mut().enumerate() {
+                let vertex = Vec2::new((i % WIDTH) as f32, (i / WIDTH) as f32);
+                let falloff = 1.0 - vertex.distance(center) / radius;
+                if falloff > 0.0 {
+                    position[1] += strength * falloff * falloff;
+                    modified.push(i);
+                }
+            }
+            let mut full = partial.clone();
+            recompute_all(&mut full);
+            recompute_normals_and_tangents(&mut partial, UVec2::splat(WIDTH as u32), &modified);
+
+            let normals = normals(&partial).into_iter().zip(normals(&full));
+            for (i, (normal, expected)) in normals.enumerate() {
+                assert!(
+                    normal.abs_diff_eq(expected, 1e-5),
+                    "normal of {i}: {normal} instead of {expected}"
+                );
+            }
+            // Mikktspace weights the triangles by their angle, the partial tangents don't
+            let tangents = tangents(&partial).into_iter().zip(tangents(&full));
+            for (i, (tangent, expected)) in tangents.enumerate() {
+                assert!(
+                    tangent.truncate().dot(expected.truncate()) > 0.995 && tangent.w == expected.w,
+                    "tangent of {i}: {tangent} instead of {expected}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! Offline conversion of the textures to mipmapped KTX2.
+//!
+//! `--preprocess-assets` converts every jpg and png in a `textures` folder of the assets to a KTX2
+//! with its full mip chain, plus `_2k` and `_1k` downscaled variants picked with
+//! `--texture-tier`. Everything is written to `assets/processed` with a manifest mapping the
+//! source paths to their outputs. The runtime loads the processed files listed in the manifest and
+//! falls back to the originals when they're missing. Sources with the same hash as in the manifest
+//! and all their outputs on disk are skipped, so running it again only converts what changed.
+//!
+//! The KTX2 files are plain RGBA8, there's no basis universal encoder in pure Rust. What the jpgs
+//! were missing is the mip chain, the GPU memory is the same as with the decoded jpgs.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use image::{imageops::FilterType, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+const ASSETS_DIR: &str = "assets";
+/// Relative to the assets folder
+const PROCESSED_DIR: &str = "processed";
+pub const MANIFEST_PATH: &str = "assets/processed/manifest.ron";
+/// Name and largest side of the downscaled variants
+const TIERS: [(&str, u32); 2] = [("2k", 2048), ("1k", 1024)];
+
+/// The processed textures, keyed by the path of their source relative to the assets folder
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct ProcessedManifest {
+    pub textures: BTreeMap<String, ProcessedTexture>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProcessedTexture {
+    /// FNV-1a of the source file
+    pub source_hash: u64,
+    /// The full resolution KTX2, relative to the assets folder
+    pub full: String,
+    /// The downscaled KTX2s by tier
+    pub tiers: BTreeMap<String, String>,
+}
+
+impl ProcessedManifest {
+    pub fn load() -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            if let Ok(file) = std::fs::read_to_string(MANIFEST_PATH) {
+                match ron::from_str(&file) {
+                    Ok(manifest) => return manifest,
+                    Err(err) => warn!("failed to read {MANIFEST_PATH}: {err}"),
+                }
+            }
+        }
+        Self::default()
+    }
+
+    /// Path to load for a source texture, its processed version for the tier when it exists
+    pub fn resolve(&self, source: &str, tier: Option<&str>) -> String {
+        let Some(texture) = self.textures.get(source) else {
+            return source.to_string();
+        };
+        let path = tier
+            .and_then(|tier| texture.tiers.get(tier))
+            .unwrap_or(&texture.full);
+        if Path::new(ASSETS_DIR).join(path).exists() {
+            path.clone()
+        } else {
+            source.to_string()
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TextureKind {
+    Color,
+    Normal,
+    /// Roughness, displacement and the like, stored linear
+    Data,
+}
+
+impl TextureKind {
+    /// From the naming of the polyhaven textures, `_diff` and `_nor_gl`
+    fn of(stem: &str) -> Self {
+        if stem.contains("_diff") {
+            Self::Color
+        } else if stem.contains("_nor") {
+            Self::Normal
+        } else {
+            Self::Data
+        }
+    }
+}
+
+/// Converts the textures, returns the exit code of the process
+pub fn preprocess_assets() -> i32 {
+    let mut manifest = ProcessedManifest::load();
+    let mut sources = vec![];
+    collect_sources(Path::new(ASSETS_DIR), &mut sources);
+    sources.sort();
+
+    let mut failed = 0;
+    for source in sources {
+        let Ok(relative) = source.strip_prefix(ASSETS_DIR) else {
+            continue;
+        };
+        // The asset paths always use forward slashes
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        match process_texture(&source, &relative, manifest.textures.get(&relative)) {
+            Ok(Some(texture)) => {
+                println!("processed {relative}");
+                manifest.textures.insert(relative, texture);
+            }
+            Ok(None) => println!("{relative} is up to date"),
+            Err(err) => {
+                eprintln!("failed to process {relative}: {err}");
+                failed += 1;
+            }
+        }
+    }
+
+    let written = ron::ser::to_string_pretty(&manifest, default())
+        .map_err(|err| err.to_string())
+        .and_then(|manifest| {
+            std::fs::create_dir_all(Path::new(ASSETS_DIR).join(PROCESSED_DIR))
+                .and_then(|()| std::fs::write(MANIFEST_PATH, manifest))
+                .map_err(|err| err.to_string())
+        });
+    if let Err(err) = written {
+        eprintln!("failed to write {MANIFEST_PATH}: {err}");
+        return 1;
+    }
+    if failed > 0 {
+        eprintln!("{failed} texture(s) failed");
+        1
+    } else {
+        0
+    }
+}
+
+/// The images in the `textures` folders, outside of the processed folder
+fn collect_sources(dir: &Path, sources: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            if path != Path::new(ASSETS_DIR).join(PROCESSED_DIR) {
+                collect_sources(&path, sources);
+            }
+            continue;
+        }
+        let in_textures = dir.file_name().is_some_and(|name| name == "textures");
+        let image = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| matches!(ext, "jpg" | "jpeg" | "png"));
+        if in_textures && image {
+            sources.push(path);
+        }
+    }
+}
+
+/// Writes the KTX2s of a source, `None` if they're already up to date
+fn process_texture(
+    source: &Path,
+    relative: &str,
+    previous: Option<&ProcessedTexture>,
+) -> Result<Option<ProcessedTexture>, String> {
+    let bytes = std::fs::read(source).map_err(|err| err.to_string())?;
+    // FNV-1a, the std hasher isn't guaranteed to be stable between releases
+    let source_hash = bytes.iter().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+
+    let stem = source
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or("the file name isn't valid unicode")?;
+    let base = stem.strip_suffix("_4k").unwrap_or(stem);
+    let dir = match relative.rsplit_once('/') {
+        Some((parent, _)) => format!("{PROCESSED_DIR}/{parent}"),
+        None => PROCESSED_DIR.to_string(),
+    };
+    let full = format!("{dir}/{stem}.ktx2");
+    let tiers: BTreeMap<_, _> = TIERS
+        .iter()
+        .map(|(tier, _)| (tier.to_string(), format!("{dir}/{base}_{tier}.ktx2")))
+        .collect();
+
+    let outputs_exist = std::iter::once(&full)
+        .chain(tiers.values())
+        .all(|path| Path::new(ASSETS_DIR).join(path).exists());
+    let unchanged = previous.is_some_and(|previous| {
+        previous.source_hash == source_hash && previous.full == full && previous.tiers == tiers
+    });
+    if unchanged && outputs_exist {
+        return Ok(None);
+    }
+
+    let kind = TextureKind::of(stem);
+    let image = image::load_from_memory(&bytes)
+        .map_err(|err| err.to_string())?
+        .to_rgba8();
+    std::fs::create_dir_all(Path::new(ASSETS_DIR).join(&dir)).map_err(|err| err.to_string())?;
+    let write = |path: &str, image: RgbaImage| {
+        let ktx2 = encode_ktx2(&mip_chain(image, kind), kind == TextureKind::Color);
+        std::fs::write(Path::new(ASSETS_DIR).join(path), ktx2)
+            .map_err(|err| format!("failed to write {path}: {err}"))
+    };
+
+    write(&full, image.clone())?;
+    for (tier, size) in TIERS {
+        let largest = image.width().max(image.height());
+        let scaled = if largest > size {
+            let scale = size as f32 / largest as f32;
+            let width = ((image.width() as f32 * scale).round() as u32).max(1);
+            let height = ((image.height() as f32 * scale).round() as u32).max(1);
+            image::imageops::resize(&image, width, height, FilterType::Triangle)
+        } else {
+            image.clone()
+        };
+        write(&tiers[tier], scaled)?;
+    }
+
+    Ok(Some(ProcessedTexture {
+        source_hash,
+        full,
+        tiers,
+    }))
+}
+
+/// Every level down to 1x1, each one filtered from the previous
+fn mip_chain(image: RgbaImage, kind: TextureKind) -> Vec<RgbaImage> {
+    let mut levels = vec![image];
+    loop {
+        let last = &levels[levels.len() - 1];
+        if last.width() == 1 && last.height() == 1 {
+            return levels;
+        }
+        let width = (last.width() / 2).max(1);
+        let height = (last.height() / 2).max(1);
+        let mut next = image::imageops::resize(last, width, height, FilterType::Triangle);
+        // Averaging normals shortens them
+        if kind == TextureKind::Normal {
+            for pixel in next.pixels_mut() {
+                let n = Vec3::new(pixel[0] as f32, pixel[1] as f32, pixel[2] as f32) / 127.5 - 1.0;
+                let n = n.try_normalize().unwrap_or(Vec3::Z);
+                let [r, g, b] = ((n + 1.0) * 127.5).round().to_array().map(|c| c as u8);
+                pixel.0 = [r, g, b, pixel[3]];
+            }
+        }
+        levels.push(next);
+    }
+}
+
+const KTX2_IDENTIFIER: [u8; 12] = [
+    0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A,
+];
+const VK_FORMAT_R8G8B8A8_UNORM: u32 = 37;
+const VK_FORMAT_R8G8B8A8_SRGB: u32 = 43;
+
+/// A KTX2 container without supercompression, `levels` goes from the largest to the smallest
+fn encode_ktx2(levels: &[RgbaImage], srgb: bool) -> Vec<u8> {
+    let u32s = |out: &mut Vec<u8>, values: &[u32]| {
+        for value in values {
+            out.extend(value.to_le_bytes());
+        }
+    };
+    let (width, height) = levels[0].dimensions();
+    let level_count = levels.len() as u32;
+
+    // The basic data format descriptor of 4 samples of 8 bits
+    let mut dfd = vec![];
+    let block_size = 24 + 16 * 4;
+    let transfer = if srgb { 2 } else { 1 };
+    u32s(
+        &mut dfd,
+        &[
+            4 + block_size,
+            0,
+            2 | (block_size << 16),
+            // RGBSDA color model, BT709 primaries
+            1 | (1 << 8) | (transfer << 16),
+            0,
+            4,
+            0,
+        ],
+    );
+    for (i, channel) in [0, 1, 2, 15].into_iter().enumerate() {
+        // The alpha is always linear
+        let linear = if channel == 15 && srgb { 1 << 31 } else { 0 };
+        u32s(
+            &mut dfd,
+            &[
+                (i as u32 * 8) | (7 << 16) | (channel << 24) | linear,
+                0,
+                0,
+                255,
+            ],
+        );
+    }
+
+    let header_size = 12 + 9 * 4 + 4 * 4 + 2 * 8;
+    let dfd_offset = header_size + 24 * level_count;
+    let data_offset = (dfd_offset + dfd.len() as u32) as u64;
+
+    let mut out = KTX2_IDENTIFIER.to_vec();
+    let format = if srgb {
+        VK_FORMAT_R8G8B8A8_SRGB
+    } else {
+        VK_FORMAT_R8G8B8A8_UNORM
+    };
+    u32s(
+        &mut out,
+        &[format, 1, width, height, 0, 0, 1, level_count, 0],
+    );
+    u32s(&mut out, &[dfd_offset, dfd.len() as u32, 0, 0]);
+    out.extend([0u8; 16]);
+
+    // The level index goes from the largest level but the data from the smallest
+    let mut offsets = vec![0; levels.len()];
+    let mut offset = data_offset;
+    for (i, level) in levels.iter().enumerate().rev() {
+        offsets[i] = offset;
+        offset += level.as_raw().len() as u64;
+    }
+    for (level, offset) in levels.iter().zip(offsets) {
+        let length = level.as_raw().len() as u64;
+        for value in [offset, length, length] {
+            out.extend(value.to_le_bytes());
+        }
+    }
+    out.extend(dfd);
+    for level in levels.iter().rev() {
+        out.extend(level.as_raw());
+    }
+    out
+}
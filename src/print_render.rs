@@ -0,0 +1,629 @@
+//! Offline render of the current view at a resolution the realtime path can't handle.
+//!
+//! The camera is temporarily pointed at an offscreen image. If the requested size is larger than
+//! the GPU supports, the image is split in tiles rendered one after the other by narrowing the
+//! projection to each tile. Every tile is rendered a few times with a sub-pixel jitter and the
+//! frames are averaged on the CPU, which replaces TAA and smooths out the SSR and SSAO noise.
+//! The tiles are stitched in a single PNG and the camera goes back to its realtime setup.
+
+use std::sync::{
+    mpsc::{channel, Receiver, Sender},
+    Mutex,
+};
+
+use bevy::{
+    core_pipeline::experimental::taa::{TemporalAntiAliasBundle, TemporalAntiAliasSettings},
+    ecs::query::QueryState,
+    math::Vec3A,
+    pbr::MotionBlur,
+    prelude::*,
+    render::{
+        camera::{CameraProjection, CameraProjectionPlugin, MipBias, RenderTarget, TemporalJitter},
+        extract_component::{ExtractComponent, ExtractComponentPlugin},
+        render_asset::{RenderAssetUsages, RenderAssets},
+        render_graph::{self, NodeRunError, RenderGraph, RenderGraphContext, RenderLabel},
+        render_resource::{
+            Buffer, BufferDescriptor, BufferUsages, Extent3d, ImageCopyBuffer, ImageDataLayout,
+            Maintain, MapMode, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+            COPY_BYTES_PER_ROW_ALIGNMENT,
+        },
+        renderer::{RenderContext, RenderDevice},
+        texture::GpuImage,
+        Render, RenderApp, RenderSet,
+    },
+    tasks::{block_on, IoTaskPool},
+};
+use image::RgbaImage;
+
+use crate::{
+    camera_controller::CameraController,
+    cli::CliArgs,
+    overlay::StatsOverlay,
+    photo_mode::{crop_rect, CropAspect, PhotoMode},
+};
+
+const DEFAULT_RESOLUTION: UVec2 = UVec2::new(7680, 4320);
+const DEFAULT_SAMPLES: u32 = 16;
+/// The sum of the samples is kept in a u16 per channel
+const MAX_SAMPLES: u32 = 256;
+/// Frames thrown away after switching tile so the exposure and the effects settle
+const WARMUP_FRAMES: u32 = 4;
+/// Below this the render is aborted instead of splitting the tiles further
+const MIN_TILE_SIZE: u32 = 512;
+const FORMAT: TextureFormat = TextureFormat::Rgba8UnormSrgb;
+
+pub struct PrintRenderPlugin;
+
+impl Plugin for PrintRenderPlugin {
+    fn build(&self, app: &mut App) {
+        let (sender, receiver) = channel();
+        app.add_plugins((
+            CameraProjectionPlugin::<TileProjection>::default(),
+            ExtractComponentPlugin::<ReadbackTarget>::default(),
+        ))
+        .insert_resource(ReadbackReceiver(Mutex::new(receiver)));
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app
+            .insert_resource(ReadbackSender(sender))
+            .add_systems(Render, send_readback.after(RenderSet::Render));
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        let readback_node = ReadbackNode::from_world(render_app.world_mut());
+        let mut graph = render_app.world_mut().resource_mut::<RenderGraph>();
+        graph.add_node(ReadbackLabel, readback_node);
+        graph.add_node_edge(bevy::render::graph::CameraDriverLabel, ReadbackLabel);
+    }
+}
+
+/// Perspective projection restricted to one tile of a larger image.
+///
+/// The frustum corners are the ones of the whole image so the shadow cascades are identical in
+/// every tile and there are no seams between them.
+#[derive(Component, Reflect, Clone, Default)]
+#[reflect(Component, Default)]
+pub struct TileProjection {
+    /// Projection of the whole image, its aspect ratio is the one of the full render
+    pub perspective: PerspectiveProjection,
+    /// Area covered by the tile in normalized device coordinates of the full image
+    pub ndc_min: Vec2,
+    pub ndc_max: Vec2,
+    /// Sub-pixel offset of the current sample in normalized device coordinates
+    pub jitter: Vec2,
+}
+
+impl CameraProjection for TileProjection {
+    fn get_clip_from_view(&self) -> Mat4 {
+        let scale = 2.0 / (self.ndc_max - self.ndc_min);
+        let offset = (self.jitter - (self.ndc_min + self.ndc_max) / 2.0) * scale;
+        let tile = Mat4::from_cols(
+            Vec4::new(scale.x, 0.0, 0.0, 0.0),
+            Vec4::new(0.0, scale.y, 0.0, 0.0),
+            Vec4::Z,
+            Vec4::new(offset.x, offset.y, 0.0, 1.0),
+        );
+        tile * self.perspective.get_clip_from_view()
+    }
+
+    fn update(&mut self, _width: f32, _height: f32) {
+        // The aspect ratio is the one of the full image, not the tile
+    }
+
+    fn far(&self) -> f32 {
+        self.perspective.far
+    }
+
+    fn get_frustum_corners(&self, z_near: f32, z_far: f32) -> [Vec3A; 8] {
+        self.perspective.get_frustum_corners(z_near, z_far)
+    }
+}
+
+/// Copies the offscreen image to `buffer` after every frame where `sample` is set
+#[derive(Component, ExtractComponent, Clone)]
+struct ReadbackTarget {
+    image: Handle<Image>,
+    buffer: Buffer,
+    size: UVec2,
+    tile: usize,
+    sample: Option<u32>,
+}
+
+struct Readback {
+    tile: usize,
+    sample: u32,
+    bytes: Vec<u8>,
+}
+
+#[derive(Resource)]
+struct ReadbackSender(Sender<Readback>);
+
+#[derive(Resource)]
+struct ReadbackReceiver(Mutex<Receiver<Readback>>);
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+struct ReadbackLabel;
+
+struct ReadbackNode {
+    targets: QueryState<&'static ReadbackTarget>,
+}
+
+impl FromWorld for ReadbackNode {
+    fn from_world(world: &mut World) -> Self {
+        Self {
+            targets: QueryState::new(world),
+        }
+    }
+}
+
+impl render_graph::Node for ReadbackNode {
+    fn update(&mut self, world: &mut World) {
+        self.targets.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let gpu_images = world.resource::<RenderAssets<GpuImage>>();
+        for target in self.targets.iter_manual(world) {
+            let (Some(_), Some(gpu_image)) = (target.sample, gpu_images.get(&target.image)) else {
+                continue;
+            };
+            render_context.command_encoder().copy_texture_to_buffer(
+                gpu_image.texture.as_image_copy(),
+                ImageCopyBuffer {
+                    buffer: &target.buffer,
+                    layout: ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: Some(padded_bytes_per_row(target.size.x)),
+                        rows_per_image: None,
+                    },
+                },
+                Extent3d {
+                    width: target.size.x,
+                    height: target.size.y,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Waits for the copies of this frame and sends them to the main world
+fn send_readback(
+    targets: Query<&ReadbackTarget>,
+    gpu_images: Res<RenderAssets<GpuImage>>,
+    render_device: Res<RenderDevice>,
+    sender: Res<ReadbackSender>,
+) {
+    for target in &targets {
+        // Nothing was copied if the image wasn't ready yet
+        let (Some(sample), Some(_)) = (target.sample, gpu_images.get(&target.image)) else {
+            continue;
+        };
+        let slice = target.buffer.slice(..);
+        let (mapped_sender, mapped) = channel();
+        slice.map_async(MapMode::Read, move |result| {
+            let _ = mapped_sender.send(result);
+        });
+        let _ = render_device.poll(Maintain::Wait);
+        if let Ok(Ok(())) = mapped.recv() {
+            let _ = sender.0.send(Readback {
+                tile: target.tile,
+                sample,
+                bytes: slice.get_mapped_range().to_vec(),
+            });
+        }
+        target.buffer.unmap();
+    }
+}
+
+fn padded_bytes_per_row(width: u32) -> u32 {
+    (width * 4).div_ceil(COPY_BYTES_PER_ROW_ALIGNMENT) * COPY_BYTES_PER_ROW_ALIGNMENT
+}
+
+/// Camera settings changed for the render and put back once it's done
+struct RealtimeCamera {
+    entity: Entity,
+    target: RenderTarget,
+    projection: Projection,
+    taa: bool,
+    shutter_angle: f32,
+    controller_enabled: bool,
+}
+
+/// A render in progress
+#[derive(Resource)]
+pub struct PrintRender {
+    size: UVec2,
+    samples: u32,
+    tile_size: UVec2,
+    /// Top left corner of every tile, the last row and column can go past the image
+    tiles: Vec<UVec2>,
+    current: usize,
+    frames: u32,
+    received: u32,
+    accumulated: Vec<u16>,
+    output: RgbaImage,
+    readback: Entity,
+    ui_camera: Entity,
+    realtime: RealtimeCamera,
+}
+
+impl PrintRender {
+    fn ndc_rect(&self, corner: UVec2) -> (Vec2, Vec2) {
+        let size = self.size.as_vec2();
+        let min = corner.as_vec2() / size;
+        let max = (corner + self.tile_size).as_vec2() / size;
+        // Image rows go down, normalized device coordinates go up
+        (
+            vec2(min.x * 2.0 - 1.0, 1.0 - max.y * 2.0),
+            vec2(max.x * 2.0 - 1.0, 1.0 - min.y * 2.0),
+        )
+    }
+
+    /// Sub-pixel offset of a sample, spread with a Halton sequence
+    fn jitter(&self, sample: u32) -> Vec2 {
+        let offset = vec2(halton(sample + 1, 2), halton(sample + 1, 3)) - 0.5;
+        offset * 2.0 / self.size.as_vec2()
+    }
+}
+
+fn halton(mut index: u32, base: u32) -> f32 {
+    let mut fraction = 1.0;
+    let mut result = 0.0;
+    while index > 0 {
+        fraction /= base as f32;
+        result += fraction * (index % base) as f32;
+        index /= base;
+    }
+    result
+}
+
+/// Allocates what a tile needs on the GPU to find out if it fits in memory.
+///
+/// The render pipeline allocates its own targets later, the probe is sized to roughly cover the
+/// HDR, depth, prepass and gbuffer textures of the camera at that resolution.
+fn probe_tile(render_device: &RenderDevice, tile_size: UVec2) -> Result<Buffer, String> {
+    let device = render_device.wgpu_device();
+    device.push_error_scope(wgpu::ErrorFilter::OutOfMemory);
+    let buffer = render_device.create_buffer(&BufferDescriptor {
+        label: Some("print_render_readback"),
+        size: (padded_bytes_per_row(tile_size.x) * tile_size.y) as u64,
+        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    let probe = render_device.create_texture(&TextureDescriptor {
+        label: Some("print_render_probe"),
+        size: Extent3d {
+            width: tile_size.x,
+            height: tile_size.y,
+            depth_or_array_layers: 8,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format: TextureFormat::Rgba16Float,
+        usage: TextureUsages::RENDER_ATTACHMENT,
+        view_formats: &[],
+    });
+    let error = block_on(device.pop_error_scope());
+    probe.destroy();
+    match error {
+        Some(error) => Err(error.to_string()),
+        None => Ok(buffer),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn start_print_render(
+    mut commands: Commands,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    cli: Res<CliArgs>,
+    print_render: Option<Res<PrintRender>>,
+    mut camera: Query<
+        (
+            Entity,
+            &mut Camera,
+            &Projection,
+            &mut MotionBlur,
+            &mut CameraController,
+            Has<TemporalAntiAliasSettings>,
+        ),
+        With<Camera3d>,
+    >,
+    render_device: Res<RenderDevice>,
+    mut images: ResMut<Assets<Image>>,
+    mut stats: ResMut<StatsOverlay>,
+    mut started_from_cli: Local<bool>,
+    time: Res<Time<Real>>,
+) {
+    // Give the world a few seconds to load when started from the command line
+    let from_cli = cli.render.is_some() && !*started_from_cli && time.elapsed_seconds() > 5.0;
+    if print_render.is_some() || !(from_cli || keyboard.just_pressed(KeyCode::F10)) {
+        return;
+    }
+    *started_from_cli |= from_cli;
+
+    let size = cli.render.unwrap_or(DEFAULT_RESOLUTION);
+    let samples = cli.render_samples.unwrap_or(DEFAULT_SAMPLES);
+    let samples = samples.clamp(1, MAX_SAMPLES);
+    let Ok((entity, mut camera, projection, mut motion_blur, mut controller, taa)) =
+        camera.get_single_mut()
+    else {
+        return;
+    };
+    let Projection::Perspective(perspective) = projection else {
+        error!("print render needs a perspective camera");
+        return;
+    };
+
+    // Split in tiles until they fit in the GPU limits and its memory
+    let max_dimension = render_device.limits().max_texture_dimension_2d;
+    let mut grid = UVec2::ONE;
+    while size.x.div_ceil(grid.x) > max_dimension {
+        grid.x *= 2;
+    }
+    while size.y.div_ceil(grid.y) > max_dimension {
+        grid.y *= 2;
+    }
+    let (tile_size, buffer) = loop {
+        let tile_size = UVec2::new(size.x.div_ceil(grid.x), size.y.div_ceil(grid.y));
+        match probe_tile(&render_device, tile_size) {
+            Ok(buffer) => break (tile_size, buffer),
+            Err(err) if tile_size.max_element() / 2 < MIN_TILE_SIZE => {
+                error!("print render aborted, not enough GPU memory for {tile_size} tiles: {err}");
+                return;
+            }
+            Err(_) => grid *= 2,
+        }
+    };
+
+    // In usize, the bytes of a large render don't fit in a u32
+    let Some(bytes) = (size.x as usize)
+        .checked_mul(size.y as usize)
+        .and_then(|pixels| pixels.checked_mul(4))
+    else {
+        error!("print render aborted, a {size} image is too large");
+        return;
+    };
+    let mut output = Vec::new();
+    if output.try_reserve_exact(bytes).is_err() {
+        error!("print render aborted, not enough memory for a {size} image");
+        return;
+    }
+    output.resize(bytes, 255);
+    let Some(output) = RgbaImage::from_raw(size.x, size.y, output) else {
+        error!("print render aborted, the {size} image doesn't fit in its buffer");
+        return;
+    };
+
+    let mut image = Image::new_fill(
+        Extent3d {
+            width: tile_size.x,
+            height: tile_size.y,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, 255],
+        FORMAT,
+        RenderAssetUsages::default(),
+    );
+    image.texture_descriptor.usage |= TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC;
+    let image = images.add(image);
+
+    let tiles = (0..grid.y)
+        .flat_map(|y| (0..grid.x).map(move |x| UVec2::new(x, y) * tile_size))
+        .collect::<Vec<_>>();
+    info!(
+        "print render {size} with {samples} samples in {} tiles of {tile_size}",
+        tiles.len()
+    );
+
+    let realtime = RealtimeCamera {
+        entity,
+        target: std::mem::replace(&mut camera.target, RenderTarget::Image(image.clone())),
+        projection: projection.clone(),
+        taa,
+        shutter_angle: std::mem::replace(&mut motion_blur.shutter_angle, 0.0),
+        controller_enabled: std::mem::replace(&mut controller.enabled, false),
+    };
+
+    let mut perspective = perspective.clone();
+    perspective.aspect_ratio = size.x as f32 / size.y as f32;
+    commands
+        .entity(entity)
+        .remove::<(
+            Projection,
+            TemporalAntiAliasSettings,
+            TemporalJitter,
+            MipBias,
+        )>()
+        .insert(TileProjection {
+            perspective,
+            ..default()
+        });
+
+    let readback = commands
+        .spawn(ReadbackTarget {
+            image,
+            buffer,
+            size: tile_size,
+            tile: 0,
+            sample: None,
+        })
+        .id();
+    // The window is only used for the progress while the main camera renders offscreen
+    let ui_camera = commands
+        .spawn(Camera2dBundle {
+            camera: Camera {
+                order: 1,
+                ..default()
+            },
+            ..default()
+        })
+        .id();
+
+    stats.set("Print render", "starting");
+    commands.insert_resource(PrintRender {
+        size,
+        samples,
+        tile_size,
+        tiles,
+        current: 0,
+        frames: 0,
+        received: 0,
+        accumulated: vec![0; (tile_size.x * tile_size.y * 4) as usize],
+        output,
+        readback,
+        ui_camera,
+        realtime,
+    });
+}
+
+pub fn update_print_render(
+    mut commands: Commands,
+    mut print_render: ResMut<PrintRender>,
+    receiver: Res<ReadbackReceiver>,
+    mut projection: Query<&mut TileProjection>,
+    mut readback_target: Query<&mut ReadbackTarget>,
+    mut camera: Query<(&mut Camera, &mut MotionBlur, &mut CameraController)>,
+    mut stats: ResMut<StatsOverlay>,
+    photo_mode: Res<PhotoMode>,
+) {
+    let render = &mut *print_render;
+    let tile_size = render.tile_size;
+    let padded_row = padded_bytes_per_row(tile_size.x) as usize;
+    for readback in receiver.0.lock().unwrap().try_iter() {
+        // Readbacks of a previous tile or duplicates of a sample are still in flight sometimes
+        if readback.tile != render.current || readback.sample != render.received {
+            continue;
+        }
+        for (y, row) in readback
+            .bytes
+            .chunks_exact(padded_row)
+            .take(tile_size.y as usize)
+            .enumerate()
+        {
+            let start = y * tile_size.x as usize * 4;
+            let sums = &mut render.accumulated[start..start + tile_size.x as usize * 4];
+            for (sum, value) in sums.iter_mut().zip(row) {
+                *sum += *value as u16;
+            }
+        }
+        render.received += 1;
+    }
+
+    if render.received == render.samples {
+        let corner = render.tiles[render.current];
+        let half = render.samples / 2;
+        for y in 0..tile_size.y.min(render.size.y - corner.y) {
+            for x in 0..tile_size.x.min(render.size.x - corner.x) {
+                let i = ((y * tile_size.x + x) * 4) as usize;
+                let pixel = render.output.get_pixel_mut(corner.x + x, corner.y + y);
+                for c in 0..3 {
+                    pixel[c] = ((render.accumulated[i + c] as u32 + half) / render.samples) as u8;
+                }
+            }
+        }
+        info!(
+            "print render tile {}/{} done",
+            render.current + 1,
+            render.tiles.len()
+        );
+        render.current += 1;
+        render.frames = 0;
+        render.received = 0;
+        render.accumulated.fill(0);
+    }
+
+    if render.current == render.tiles.len() {
+        finish_print_render(
+            &mut commands,
+            render,
+            &mut camera,
+            &mut stats,
+            photo_mode.crop,
+        );
+        return;
+    }
+
+    let (ndc_min, ndc_max) = render.ndc_rect(render.tiles[render.current]);
+    let warm = render.frames >= WARMUP_FRAMES;
+    if let Ok(mut projection) = projection.get_mut(render.realtime.entity) {
+        projection.ndc_min = ndc_min;
+        projection.ndc_max = ndc_max;
+        // Until a sample comes back the same one is requested again
+        projection.jitter = render.jitter(render.received);
+    }
+    if let Ok(mut target) = readback_target.get_mut(render.readback) {
+        target.tile = render.current;
+        target.sample = warm.then_some(render.received);
+    }
+    render.frames += 1;
+
+    stats.set(
+        "Print render",
+        format!(
+            "tile {}/{}, sample {}/{}",
+            render.current + 1,
+            render.tiles.len(),
+            render.received,
+            render.samples
+        ),
+    );
+}
+
+fn finish_print_render(
+    commands: &mut Commands,
+    render: &mut PrintRender,
+    camera: &mut Query<(&mut Camera, &mut MotionBlur, &mut CameraController)>,
+    stats: &mut StatsOverlay,
+    crop: Option<CropAspect>,
+) {
+    let realtime = &render.realtime;
+    if let Ok((mut camera, mut motion_blur, mut controller)) = camera.get_mut(realtime.entity) {
+        camera.target = realtime.target.clone();
+        motion_blur.shutter_angle = realtime.shutter_angle;
+        controller.enabled = realtime.controller_enabled;
+    }
+    let mut entity = commands.entity(realtime.entity);
+    entity
+        .remove::<TileProjection>()
+        .insert(realtime.projection.clone());
+    if realtime.taa {
+        entity.insert(TemporalAntiAliasBundle {
+            settings: TemporalAntiAliasSettings { reset: true },
+            ..default()
+        });
+    }
+    commands.entity(render.readback).despawn();
+    commands.entity(render.ui_camera).despawn();
+    commands.remove_resource::<PrintRender>();
+    stats.remove("Print render");
+
+    // Same framing as the crop bars of the photo mode
+    let rect = crop_rect(render.size.as_vec2(), crop);
+    let (min, size) = (rect.min.as_uvec2(), rect.size().as_uvec2());
+    let output = image::imageops::crop_imm(&render.output, min.x, min.y, size.x, size.y).to_image();
+    render.output = default();
+    let path = format!("print_{}x{}.png", size.x, size.y);
+    #[cfg(not(target_arch = "wasm32"))]
+    IoTaskPool::get()
+        .spawn(async move {
+            match output.save(&path) {
+                Ok(()) => info!("print render saved to {path}"),
+                Err(err) => error!("failed to save the print render to {path}: {err}"),
+            }
+        })
+        .detach();
+}
@@ -0,0 +1,3 @@
+//! Assets generated from parameters instead of loaded from files.
+
+pub mod tree;
@@ -0,0 +1,357 @@
+//! Parametric conifers used when the fir glb is missing or when they're enabled in the config.
+//!
+//! Each variant is a tapered trunk bent a little by noise with whorls of drooping branch cards
+//! that get shorter toward the top. The meshes are vertex colored so the trees don't need any
+//! texture. Every variant is generated from the terrain seed on a background task, once done
+//! they're added to [`TerrainResources::trees`] like the glb variants so the placement, the LODs
+//! and the material swaps don't know the difference.
+
+use bevy::{
+    math::vec3,
+    prelude::*,
+    render::{
+        mesh::{Indices, PrimitiveTopology},
+        render_asset::RenderAssetUsages,
+    },
+    tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task},
+};
+use noise::{NoiseFn, Simplex};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    cli::CliArgs,
+    foliage::TreeAlphaMode,
+    terrain::{add_tree_variants, asset_exists, TerrainConfig, TerrainResources, TREES_GLTF_PATH},
+    validation::{clamp_field, clamp_float_field, ValidationIssue},
+};
+
+/// Mixed with the terrain seed so the trees don't change with the other generated objects
+const PROCEDURAL_TREE_SEED: u64 = 0x7ee5_c0de_f125;
+const TRUNK_SIDES: usize = 8;
+const TRUNK_RINGS: usize = 12;
+/// Radius of the base of the trunk relative to the tree height
+const TRUNK_RADIUS: f32 = 0.025;
+/// Fraction of the trunk under the lowest whorl
+const CLEAR_TRUNK: f32 = 0.2;
+/// How far the trunk drifts sideways at the top relative to the tree height
+const TRUNK_BEND: f32 = 0.03;
+/// Length of the lowest branches relative to the tree height
+const BRANCH_LENGTH: f32 = 0.35;
+
+#[derive(Reflect, Clone, Debug, PartialEq)]
+pub struct ProceduralTreeConfig {
+    /// Use the generated trees even when the glb is there
+    pub enabled: bool,
+    /// Number of different trees generated from the seed
+    pub variants: usize,
+    /// Height range of the trees in meters at the average scale of the tree layer
+    pub min_height: f32,
+    pub max_height: f32,
+    /// Rings of branches along the trunk
+    pub whorls: usize,
+    pub branches_per_whorl: usize,
+    pub needle_color: Color,
+    pub bark_color: Color,
+}
+
+impl Default for ProceduralTreeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            variants: 3,
+            min_height: 12.0,
+            max_height: 22.0,
+            whorls: 10,
+            branches_per_whorl: 6,
+            needle_color: Color::srgb(0.13, 0.25, 0.12),
+            bark_color: Color::srgb(0.27, 0.17, 0.1),
+        }
+    }
+}
+
+impl ProceduralTreeConfig {
+    /// Clamps the fields in a range that can be generated and reports what was changed
+    pub fn validate(&mut self) -> Vec<ValidationIssue> {
+        let default = Self::default();
+        let mut issues = vec![];
+        clamp_field(
+            &mut issues,
+            "procedural_trees.variants",
+            &mut self.variants,
+            1,
+            16,
+        );
+        clamp_field(
+            &mut issues,
+            "procedural_trees.whorls",
+            &mut self.whorls,
+            1,
+            64,
+        );
+        clamp_field(
+            &mut issues,
+            "procedural_trees.branches_per_whorl",
+            &mut self.branches_per_whorl,
+            1,
+            32,
+        );
+        clamp_float_field(
+            &mut issues,
+            "procedural_trees.min_height",
+            &mut self.min_height,
+            1.0,
+            100.0,
+            default.min_height,
+        );
+        clamp_float_field(
+            &mut issues,
+            "procedural_trees.max_height",
+            &mut self.max_height,
+            self.min_height,
+            100.0,
+            self.min_height.max(default.max_height),
+        );
+        issues
+    }
+}
+
+/// Whether the trees are generated instead of loaded from the glb, the smoke test always
+/// generates them so it doesn't depend on the asset
+pub fn use_procedural_trees(terrain_config: &TerrainConfig, cli: &CliArgs) -> bool {
+    terrain_config.procedural_trees.enabled || cli.smoke_test || !asset_exists(TREES_GLTF_PATH)
+}
+
+/// The bark and the needles of a generated tree
+struct ProceduralTreeMeshes {
+    trunk: Mesh,
+    branches: Mesh,
+}
+
+/// Generation of the tree variants running in the background
+#[derive(Resource)]
+pub struct ProceduralTreeTask {
+    task: Task<Vec<ProceduralTreeMeshes>>,
+}
+
+#[derive(Default)]
+struct MeshBuilder {
+    positions: Vec<[f32; 3]>,
+    normals: Vec<[f32; 3]>,
+    uvs: Vec<[f32; 2]>,
+    colors: Vec<[f32; 4]>,
+    indices: Vec<u32>,
+}
+
+impl MeshBuilder {
+    fn vertex(&mut self, position: Vec3, normal: Vec3, uv: [f32; 2], color: [f32; 4]) -> u32 {
+        self.positions.push(position.to_array());
+        self.normals.push(normal.to_array());
+        self.uvs.push(uv);
+        self.colors.push(color);
+        self.positions.len() as u32 - 1
+    }
+
+    /// Moves the mesh from meters and Y up to the space the tree layer expects, the one of the
+    /// glb trees
+    fn build(self, placement: Transform) -> Mesh {
+        let to_glb = placement.compute_matrix().inverse();
+        let positions: Vec<[f32; 3]> = self
+            .positions
+            .iter()
+            .map(|p| to_glb.transform_point3(Vec3::from(*p)).to_array())
+            .collect();
+        let normals: Vec<[f32; 3]> = self
+            .normals
+            .iter()
+            .map(|n| (placement.rotation.inverse() * Vec3::from(*n)).to_array())
+            .collect();
+        Mesh::new(
+            PrimitiveTopology::TriangleList,
+            // The LODs are simplified from the copy in the main world
+            RenderAssetUsages::default(),
+        )
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, self.uvs)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, self.colors)
+        .with_inserted_indices(Indices::U32(self.indices))
+    }
+}
+
+/// Darkens `color` without touching its alpha, the needles are alpha masked
+fn shade(color: LinearRgba, amount: f32) -> [f32; 4] {
+    [
+        color.red * amount,
+        color.green * amount,
+        color.blue * amount,
+        color.alpha,
+    ]
+}
+
+/// Sideways offset of the trunk at `t` along its height, 0 at the base
+fn trunk_offset(bend: &Simplex, t: f32, height: f32) -> Vec3 {
+    let sample = |axis: f64| bend.get([t as f64 * 1.5, axis]) as f32;
+    vec3(sample(0.0), 0.0, sample(17.0)) * t * height * TRUNK_BEND
+}
+
+fn generate_tree(
+    config: &ProceduralTreeConfig,
+    seed: u64,
+    placement: Transform,
+) -> ProceduralTreeMeshes {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let bend = Simplex::new(rng.gen());
+    let height = rng.gen_range(config.min_height..=config.max_height);
+    let radius = height * TRUNK_RADIUS;
+    let bark_color = config.bark_color.to_linear();
+    let needle_color = config.needle_color.to_linear();
+
+    let mut trunk = MeshBuilder::default();
+    for ring in 0..=TRUNK_RINGS {
+        let t = ring as f32 / TRUNK_RINGS as f32;
+        let center = trunk_offset(&bend, t, height) + Vec3::Y * t * height;
+        let ring_radius = radius * (1.0 - t).max(0.05);
+        for side in 0..=TRUNK_SIDES {
+            let u = side as f32 / TRUNK_SIDES as f32;
+            let (sin, cos) = (u * std::f32::consts::TAU).sin_cos();
+            let normal = vec3(cos, 0.0, sin);
+            trunk.vertex(
+                center + normal * ring_radius,
+                normal,
+                [u, 1.0 - t],
+                shade(bark_color, rng.gen_range(0.85..1.0)),
+            );
+        }
+    }
+    let stride = TRUNK_SIDES as u32 + 1;
+    for ring in 0..TRUNK_RINGS as u32 {
+        for side in 0..TRUNK_SIDES as u32 {
+            let a = ring * stride + side;
+            let b = a + stride;
+            trunk.indices.extend([a, b, a + 1, a + 1, b, b + 1]);
+        }
+    }
+
+    // Two crossed cards per branch, one flat and one upright, tapering to the drooping tip
+    let mut branches = MeshBuilder::default();
+    for whorl in 0..config.whorls {
+        let t = CLEAR_TRUNK + (1.0 - CLEAR_TRUNK) * whorl as f32 / config.whorls as f32;
+        let center = trunk_offset(&bend, t, height) + Vec3::Y * t * height;
+        let length = height * BRANCH_LENGTH * (1.0 - t) / (1.0 - CLEAR_TRUNK);
+        let twist = rng.gen_range(0.0..std::f32::consts::TAU);
+        for branch in 0..config.branches_per_whorl {
+            let angle = twist
+                + branch as f32 / config.branches_per_whorl as f32 * std::f32::consts::TAU
+                + rng.gen_range(-0.2..0.2);
+            let outward = vec3(angle.cos(), 0.0, angle.sin());
+            let length = length * rng.gen_range(0.8..1.1);
+            let droop = length * rng.gen_range(0.2..0.4);
+            let tip = center + outward * length - Vec3::Y * droop;
+            let across = outward.cross(Vec3::Y);
+            let half_width = length * 0.3;
+            // Darker close to the trunk where the branches shade each other
+            let brightness = rng.gen_range(0.8..1.1);
+            let inner = shade(needle_color, 0.6 * brightness);
+            let outer = shade(needle_color, brightness);
+            for (side, normal) in [
+                (
+                    across * half_width,
+                    (tip - center).cross(across).normalize(),
+                ),
+                (Vec3::Y * half_width * 0.5, across),
+            ] {
+                let base = branches.vertex(center, normal, [0.5, 1.0], inner);
+                let middle = center + (tip - center) * 0.4;
+                branches.vertex(middle + side, normal, [1.0, 0.6], outer);
+                branches.vertex(tip, normal, [0.5, 0.0], outer);
+                branches.vertex(middle - side, normal, [0.0, 0.6], outer);
+                branches
+                    .indices
+                    .extend([base, base + 1, base + 2, base, base + 2, base + 3]);
+            }
+        }
+    }
+
+    ProceduralTreeMeshes {
+        trunk: trunk.build(placement),
+        branches: branches.build(placement),
+    }
+}
+
+/// Starts generating the tree variants once the config is loaded, when they replace the glb
+pub fn start_procedural_trees(
+    mut commands: Commands,
+    terrain_config: Res<TerrainConfig>,
+    cli: Res<CliArgs>,
+    mut started: Local<bool>,
+) {
+    if *started || !use_procedural_trees(&terrain_config, &cli) {
+        return;
+    }
+    *started = true;
+
+    let config = terrain_config.procedural_trees.clone();
+    let seed = PROCEDURAL_TREE_SEED ^ terrain_config.seed as u64;
+    // The tree layer scales and turns the trees, the config heights are the ones of a tree at
+    // the average scale
+    let layer = terrain_config.tree_layer();
+    let placement = Transform::from_rotation(layer.base_rotation)
+        .with_scale(Vec3::splat((layer.min_scale + layer.max_scale) / 2.0));
+    info!("generating {} procedural tree variants", config.variants);
+    let task = AsyncComputeTaskPool::get().spawn(async move {
+        (0..config.variants as u64)
+            .map(|variant| generate_tree(&config, seed.wrapping_add(variant), placement))
+            .collect()
+    });
+    commands.insert_resource(ProceduralTreeTask { task });
+}
+
+/// Adds the generated variants to the tree resources like the glb ones
+pub fn finish_procedural_trees(
+    mut commands: Commands,
+    mut task: ResMut<ProceduralTreeTask>,
+    mut terrain_resources: ResMut<TerrainResources>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut pbr_materials: ResMut<Assets<StandardMaterial>>,
+    mut scenes: ResMut<Assets<Scene>>,
+    mut terrain_config: ResMut<TerrainConfig>,
+    alpha_mode: Res<TreeAlphaMode>,
+) {
+    let Some(generated) = block_on(future::poll_once(&mut task.task)) else {
+        return;
+    };
+    commands.remove_resource::<ProceduralTreeTask>();
+
+    // The colors are in the vertices, the bark is opaque and the needles are masked like the glb
+    let bark = pbr_materials.add(StandardMaterial {
+        perceptual_roughness: 1.0,
+        ..default()
+    });
+    let needles = pbr_materials.add(StandardMaterial {
+        perceptual_roughness: 1.0,
+        alpha_mode: alpha_mode.0,
+        double_sided: true,
+        cull_mode: None,
+        ..default()
+    });
+    let variants = generated
+        .into_iter()
+        .map(|tree| {
+            vec![
+                (meshes.add(tree.branches), needles.clone()),
+                (meshes.add(tree.trunk), bark.clone()),
+            ]
+        })
+        .collect();
+    add_tree_variants(
+        &mut commands,
+        &mut terrain_resources,
+        variants,
+        &meshes,
+        &mut scenes,
+        &terrain_config,
+    );
+    terrain_config.set_changed();
+
+    info!("procedural trees generated");
+}
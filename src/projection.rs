@@ -0,0 +1,158 @@
+//! Orthographic projection for isometric-style captures.
+//!
+//! `projection` in the scene config, or I, switches the main camera between its perspective and an
+//! orthographic projection `orthographic_scale` meters high. In orthographic the controller pans
+//! along the ground instead of flying forward and the scroll wheel zooms, see the
+//! `camera_controller` module.
+//!
+//! The depth of field, the SSAO, the SSR and the volumetric fog expect a perspective projection.
+//! They're taken off the camera while it's orthographic and put back as they were with the
+//! perspective parameters when switching back. The deferred renderer, the ground fog and the
+//! aerial perspective work with both.
+
+use bevy::{
+    core_pipeline::dof::DepthOfFieldSettings,
+    pbr::{
+        ScreenSpaceAmbientOcclusionSettings, ScreenSpaceReflectionsSettings, VolumetricFogSettings,
+    },
+    prelude::*,
+    render::camera::ScalingMode,
+};
+
+use crate::{
+    camera_controller::CameraController, overlay::StatsOverlay, split_view::SecondaryCamera,
+    SceneConfig,
+};
+
+/// Far enough to see across the largest terrain
+const ORTHOGRAPHIC_FAR: f32 = 10_000.0;
+
+#[derive(Reflect, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CameraProjectionMode {
+    #[default]
+    Perspective,
+    Orthographic,
+}
+
+/// What the camera had before switching to the orthographic projection
+struct SavedPerspective {
+    perspective: PerspectiveProjection,
+    fog: Option<VolumetricFogSettings>,
+    ssr: bool,
+    ssao: Option<ScreenSpaceAmbientOcclusionSettings>,
+    dof: Option<DepthOfFieldSettings>,
+}
+
+#[derive(Resource, Default)]
+pub struct OrthographicCamera {
+    /// Only there while the camera is orthographic
+    saved: Option<SavedPerspective>,
+}
+
+pub fn toggle_projection(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut scene_config: ResMut<SceneConfig>,
+) {
+    if !keyboard.just_pressed(KeyCode::KeyI) {
+        return;
+    }
+    scene_config.projection = match scene_config.projection {
+        CameraProjectionMode::Perspective => CameraProjectionMode::Orthographic,
+        CameraProjectionMode::Orthographic => CameraProjectionMode::Perspective,
+    };
+}
+
+#[allow(clippy::type_complexity)]
+pub fn apply_projection(
+    mut commands: Commands,
+    scene_config: Res<SceneConfig>,
+    mut orthographic: ResMut<OrthographicCamera>,
+    mut camera: Query<
+        (
+            Entity,
+            &mut Projection,
+            Option<&VolumetricFogSettings>,
+            Has<ScreenSpaceReflectionsSettings>,
+            Option<&ScreenSpaceAmbientOcclusionSettings>,
+            Option<&DepthOfFieldSettings>,
+        ),
+        (With<CameraController>, Without<SecondaryCamera>),
+    >,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    if !scene_config.is_changed() {
+        return;
+    }
+    let Ok((entity, mut projection, fog, ssr, ssao, dof)) = camera.get_single_mut() else {
+        return;
+    };
+
+    match scene_config.projection {
+        CameraProjectionMode::Orthographic => {
+            if orthographic.saved.is_none() {
+                let Projection::Perspective(perspective) = &*projection else {
+                    warn!("the camera isn't using a perspective projection, it's left as is");
+                    return;
+                };
+                orthographic.saved = Some(SavedPerspective {
+                    perspective: perspective.clone(),
+                    fog: fog.cloned(),
+                    ssr,
+                    ssao: ssao.cloned(),
+                    dof: dof.cloned(),
+                });
+                commands.entity(entity).remove::<(
+                    VolumetricFogSettings,
+                    ScreenSpaceReflectionsSettings,
+                    ScreenSpaceAmbientOcclusionSettings,
+                    DepthOfFieldSettings,
+                )>();
+                info!(
+                    "orthographic projection, the depth of field, SSAO, SSR and volumetric fog \
+                    are off until the camera is back in perspective"
+                );
+            }
+            // The zoom of the controller is kept until the configured height changes
+            let height = scene_config.orthographic_scale;
+            let configured = matches!(
+                &*projection,
+                Projection::Orthographic(current)
+                    if matches!(current.scaling_mode, ScalingMode::FixedVertical(h) if h == height)
+            );
+            if !configured {
+                *projection = Projection::Orthographic(OrthographicProjection {
+                    near: 0.0,
+                    far: ORTHOGRAPHIC_FAR,
+                    scaling_mode: ScalingMode::FixedVertical(height),
+                    ..default()
+                });
+            }
+            stats.set("Projection", format!("orthographic, {height:.0}m high"));
+        }
+        CameraProjectionMode::Perspective => {
+            let Some(saved) = orthographic.saved.take() else {
+                return;
+            };
+            *projection = Projection::Perspective(saved.perspective);
+            let mut camera = commands.entity(entity);
+            // The settings coming from the config follow the changes made in orthographic
+            if let Some(mut fog) = saved.fog {
+                fog.ambient_intensity = scene_config.fog_ambient_intensity;
+                fog.fog_color = scene_config.fog_color;
+                fog.light_intensity = scene_config.fog_light_intensity;
+                camera.insert(fog);
+            }
+            if saved.ssr {
+                camera.insert(scene_config.ssr);
+            }
+            if let Some(ssao) = saved.ssao {
+                camera.insert(ssao);
+            }
+            if let Some(dof) = saved.dof {
+                camera.insert(dof);
+            }
+            info!("perspective projection");
+            stats.remove("Projection");
+        }
+    }
+}
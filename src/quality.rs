@@ -0,0 +1,287 @@
+//! Picking a quality preset for the GPU at the first launch.
+//!
+//! The defaults of the configs assume a desktop GPU. At the first launch, when there's no
+//! [`PREFS_PATH`] yet, the render adapter is matched against [`ADAPTER_RULES`] in order and the
+//! first rule that matches picks the preset. An adapter no rule matches gets `Medium`. The preset
+//! is written to the prefs so the detection only runs again once they're deleted or with
+//! `--redetect`, editing `quality` in the prefs picks another preset.
+//!
+//! The preset caps the configs rather than replacing them:
+//! - `Low` turns off SSR, TAA (FXAA takes over) and the volumetric fog, loads the 1k textures and
+//!   places half the trees.
+//! - `Medium` turns off SSR, loads the 2k textures and places three quarters of the trees.
+//! - `High` leaves the configs as they are.
+//!
+//! `--texture-tier` wins over the textures of the preset.
+
+use bevy::{
+    pbr::{ScreenSpaceReflectionsSettings, VolumetricFogSettings},
+    prelude::*,
+    render::renderer::{RenderAdapter, RenderAdapterInfo},
+};
+use serde::{Deserialize, Serialize};
+use wgpu::DeviceType;
+
+use crate::{
+    anti_aliasing::{AntiAliasing, FxaaQuality},
+    camera_controller::CameraController,
+    cli::CliArgs,
+    debug_palette::DebugPaletteKind,
+    overlay::StatsOverlay,
+    split_view::SecondaryCamera,
+};
+
+const PREFS_PATH: &str = "prefs.ron";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QualityPreset {
+    Low,
+    #[default]
+    Medium,
+    High,
+}
+
+/// What a preset turns off or scales down
+struct QualitySettings {
+    ssr: bool,
+    taa: bool,
+    volumetric_fog: bool,
+    /// Downscaled textures to load, see the `preprocess` module
+    texture_tier: Option<&'static str>,
+    /// Multiplies the density of the trees
+    tree_density: f32,
+}
+
+impl QualityPreset {
+    fn settings(self) -> QualitySettings {
+        match self {
+            QualityPreset::Low => QualitySettings {
+                ssr: false,
+                taa: false,
+                volumetric_fog: false,
+                texture_tier: Some("1k"),
+                tree_density: 0.5,
+            },
+            QualityPreset::Medium => QualitySettings {
+                ssr: false,
+                taa: true,
+                volumetric_fog: true,
+                texture_tier: Some("2k"),
+                tree_density: 0.75,
+            },
+            QualityPreset::High => QualitySettings {
+                ssr: true,
+                taa: true,
+                volumetric_fog: true,
+                texture_tier: None,
+                tree_density: 1.0,
+            },
+        }
+    }
+}
+
+/// Picks a preset for the adapters it matches, every condition has to hold
+struct AdapterRule {
+    /// Any device type when `None`
+    device_type: Option<DeviceType>,
+    /// Lowercase parts of the adapter name, one of them has to be in the name. Any name when
+    /// empty.
+    names: &'static [&'static str],
+    /// Only matches adapters with a smaller maximum 2d texture size, 0 to ignore the limits
+    max_texture_size_below: u32,
+    preset: QualityPreset,
+}
+
+impl AdapterRule {
+    const ANY: Self = Self {
+        device_type: None,
+        names: &[],
+        max_texture_size_below: 0,
+        preset: QualityPreset::Medium,
+    };
+
+    fn matches(&self, name: &str, device_type: DeviceType, max_texture_size: u32) -> bool {
+        let name = name.to_lowercase();
+        self.device_type.map_or(true, |t| t == device_type)
+            && (self.names.is_empty() || self.names.iter().any(|part| name.contains(part)))
+            && (self.max_texture_size_below == 0 || max_texture_size < self.max_texture_size_below)
+    }
+}
+
+/// Checked in order, the first rule that matches picks the preset
+const ADAPTER_RULES: &[AdapterRule] = &[
+    // WebGL2 and old GPUs
+    AdapterRule {
+        max_texture_size_below: 8192,
+        preset: QualityPreset::Low,
+        ..AdapterRule::ANY
+    },
+    // Software rasterizers like llvmpipe and WARP
+    AdapterRule {
+        device_type: Some(DeviceType::Cpu),
+        preset: QualityPreset::Low,
+        ..AdapterRule::ANY
+    },
+    AdapterRule {
+        device_type: Some(DeviceType::IntegratedGpu),
+        preset: QualityPreset::Low,
+        ..AdapterRule::ANY
+    },
+    AdapterRule {
+        device_type: Some(DeviceType::DiscreteGpu),
+        names: &[
+            "rtx 3070", "rtx 3080", "rtx 3090", "rtx 40", "rtx 50", "rx 6800", "rx 6900",
+            "rx 7800", "rx 7900", "rx 9070",
+        ],
+        preset: QualityPreset::High,
+        ..AdapterRule::ANY
+    },
+    AdapterRule {
+        device_type: Some(DeviceType::DiscreteGpu),
+        preset: QualityPreset::Medium,
+        ..AdapterRule::ANY
+    },
+];
+
+/// The preset picked for this machine
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct Quality {
+    pub preset: QualityPreset,
+}
+
+impl Quality {
+    /// TAA is replaced by FXAA when the preset turns it off
+    pub fn anti_aliasing(&self, anti_aliasing: AntiAliasing) -> AntiAliasing {
+        if anti_aliasing == AntiAliasing::Taa && !self.preset.settings().taa {
+            return AntiAliasing::Fxaa {
+                quality: FxaaQuality::default(),
+            };
+        }
+        anti_aliasing
+    }
+
+    pub fn tree_density(&self, density: f32) -> f32 {
+        density * self.preset.settings().tree_density
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Prefs {
+    quality: QualityPreset,
+    /// Adapter the preset was detected on, only there for reference
+    adapter: String,
+    /// Advance the scene by fixed steps, see the `deterministic` module
+    #[serde(default)]
+    deterministic: bool,
+    /// Colors of the debug visualizations, see the `debug_palette` module
+    #[serde(default)]
+    debug_palette: DebugPaletteKind,
+}
+
+impl Prefs {
+    fn load() -> Option<Self> {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let file = std::fs::read_to_string(PREFS_PATH).ok()?;
+            match ron::from_str(&file) {
+                Ok(prefs) => return Some(prefs),
+                Err(err) => {
+                    warn!("failed to read {PREFS_PATH}, detecting the quality again: {err}")
+                }
+            }
+        }
+        None
+    }
+
+    fn save(&self) {
+        let Ok(serialized) = ron::ser::to_string_pretty(self, default()) else {
+            error!("failed to serialize the prefs");
+            return;
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Err(err) = std::fs::write(PREFS_PATH, serialized) {
+            error!("failed to write {PREFS_PATH}: {err}");
+        }
+    }
+}
+
+/// Preset of the first rule matching the render adapter, with the name of the adapter
+fn detect_preset(world: &World) -> Option<(QualityPreset, String)> {
+    let info = world.get_resource::<RenderAdapterInfo>()?;
+    let adapter = world.get_resource::<RenderAdapter>()?;
+    let max_texture_size = adapter.limits().max_texture_dimension_2d;
+    let preset = ADAPTER_RULES
+        .iter()
+        .find(|rule| rule.matches(&info.name, info.device_type, max_texture_size))
+        .map_or(QualityPreset::default(), |rule| rule.preset);
+    info!(
+        "detected {:?} ({:?}, {:?}, max texture size {max_texture_size}), using the {preset:?} \
+        quality preset",
+        info.name, info.device_type, info.backend
+    );
+    Some((preset, info.name.clone()))
+}
+
+/// Reads the preset from the prefs or detects it, needs to run after the render plugin is built
+/// and before the startup systems
+pub fn pick_quality_preset(world: &mut World) {
+    let redetect = world.resource::<CliArgs>().redetect;
+    let prefs = Prefs::load();
+    let deterministic = prefs.as_ref().is_some_and(|prefs| prefs.deterministic);
+    let debug_palette = prefs
+        .as_ref()
+        .map_or(DebugPaletteKind::default(), |prefs| prefs.debug_palette);
+    let preset = match prefs {
+        Some(prefs) if !redetect => {
+            info!(
+                "using the {:?} quality preset from {PREFS_PATH}",
+                prefs.quality
+            );
+            prefs.quality
+        }
+        _ => match detect_preset(world) {
+            Some((quality, adapter)) => {
+                Prefs {
+                    quality,
+                    adapter,
+                    deterministic,
+                    debug_palette,
+                }
+                .save();
+                quality
+            }
+            // Not saved so it's detected at the next launch
+            None => {
+                warn!("no render adapter to detect the quality from, using the medium preset");
+                QualityPreset::default()
+            }
+        },
+    };
+    let mut cli = world.resource_mut::<CliArgs>();
+    if cli.texture_tier.is_none() {
+        cli.texture_tier = preset.settings().texture_tier.map(String::from);
+    }
+    cli.deterministic |= deterministic;
+    world.insert_resource(Quality { preset });
+    world.insert_resource(debug_palette.palette());
+}
+
+/// Takes the effects the preset turns off out of the main camera
+pub fn apply_quality_preset(
+    mut commands: Commands,
+    quality: Res<Quality>,
+    cameras: Query<Entity, (With<CameraController>, Without<SecondaryCamera>)>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    let settings = quality.preset.settings();
+    for entity in &cameras {
+        let mut camera = commands.entity(entity);
+        if !settings.ssr {
+            camera.remove::<ScreenSpaceReflectionsSettings>();
+        }
+        if !settings.volumetric_fog {
+            camera.remove::<VolumetricFogSettings>();
+        }
+    }
+    stats.set("Quality", format!("{:?}", quality.preset));
+}
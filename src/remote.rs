@@ -0,0 +1,759 @@
+//! Tweaking the scene from another device, like a phone next to a demo installation.
+//!
+//! Only built with the `remote_control` feature. A small HTTP server listens on `--remote-bind`,
+//! [`DEFAULT_BIND`] otherwise, which is reachable from the whole network. The listener is polled
+//! every frame and every connection is read on the IO task pool, a request only ever queues a
+//! [`RemoteCommand`] in a channel. The commands are applied at the start of the next frame by
+//! writing the configs, so they go through the validation, the transitions and the change
+//! detection like a reloaded config. A malformed request gets a 400 with the reason and never
+//! reaches the world.
+//!
+//! The POST requests need `Content-Type: application/json`. A page of another site open in a
+//! browser on the network can't send that without a CORS preflight, which the server never
+//! answers, so it can't drive the scene behind the back of its visitor.
+//!
+//! - `GET /state`: the fields below, the time of day, the presets and the bookmarks
+//! - `POST /scene`, `POST /terrain`: an object of [`SCENE_FIELDS`] or [`TERRAIN_FIELDS`] and their
+//!   new values, like `{"rain_intensity": 0.8}`
+//! - `POST /time_of_day`: `{"hour": 17.5}`, moves the sun, see [`sun_direction`]
+//! - `POST /weather`: `{"preset": "dusk_lake"}`, loads a scene file of `assets/presets`
+//! - `POST /bookmarks`: `{"save": "name"}` bookmarks the camera, `{"go": "name"}` moves it back
+//! - `POST /generation_lock`: `{"action": "lock"}`, or `"unlock"`, `"apply"` and `"discard"`, see
+//!   the `generation_lock` module
+//!
+//! The bookmarks are kept in `camera_bookmarks.ron` in the assets. The listener is closed when the
+//! app exits, the connections in flight are dropped after [`READ_TIMEOUT`] at most.
+
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use bevy::{app::AppExit, prelude::*, reflect::GetPath, tasks::IoTaskPool};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    camera_controller::CameraController,
+    camera_path::CameraKeyframe,
+    cli::CliArgs,
+    generation_lock::{GenerationLock, LockAction},
+    scene_debug::ConfigCarrier,
+    split_view::SecondaryCamera,
+    terrain::TerrainConfig,
+    validation, SceneConfig,
+};
+
+const DEFAULT_BIND: &str = "0.0.0.0:8787";
+const BOOKMARKS_PATH: &str = "assets/camera_bookmarks.ron";
+const PRESETS_DIR: &str = "assets/presets";
+/// A slow client can't hold an IO thread for longer than this
+const READ_TIMEOUT: Duration = Duration::from_secs(2);
+/// Larger requests are refused, the biggest valid one is a few hundred bytes
+const MAX_BODY_BYTES: usize = 16 * 1024;
+/// Same for the request line and the headers together
+const MAX_HEAD_BYTES: usize = 8 * 1024;
+/// Height of the sun above the horizon at noon
+const MAX_SUN_ELEVATION: f32 = 60.0;
+
+/// How a whitelisted field is written in JSON
+#[derive(Clone, Copy)]
+enum FieldKind {
+    Number,
+    /// A number without a fractional part, 0 or more
+    Integer,
+    Bool,
+}
+
+/// Fields of the [`SceneConfig`] that can be changed remotely
+const SCENE_FIELDS: &[(&str, FieldKind)] = &[
+    ("env_map_intensity", FieldKind::Number),
+    ("skybox_brightness", FieldKind::Number),
+    ("fog_height_base", FieldKind::Number),
+    ("fog_height_density", FieldKind::Number),
+    ("aerial_perspective_strength", FieldKind::Number),
+    ("dust_density", FieldKind::Number),
+    ("rain_intensity", FieldKind::Number),
+    ("auto_white_balance", FieldKind::Number),
+    ("snow_height", FieldKind::Number),
+    ("camera_walk_speed", FieldKind::Number),
+    ("transition_seconds", FieldKind::Number),
+    ("water_ripples", FieldKind::Bool),
+];
+
+/// Fields of the [`TerrainConfig`] that can be changed remotely, most of them regenerate the
+/// terrain
+const TERRAIN_FIELDS: &[(&str, FieldKind)] = &[
+    ("seed", FieldKind::Integer),
+    ("frequency", FieldKind::Number),
+    ("octaves", FieldKind::Integer),
+    ("density", FieldKind::Number),
+    ("water_level", FieldKind::Number),
+    ("root_blend_strength", FieldKind::Number),
+    ("lod_distance", FieldKind::Number),
+    ("instanced_trees", FieldKind::Bool),
+];
+
+#[derive(Clone, Copy, Debug)]
+enum FieldValue {
+    Number(f64),
+    Bool(bool),
+}
+
+#[derive(Debug)]
+enum RemoteCommand {
+    Scene(Vec<(&'static str, FieldValue)>),
+    Terrain(Vec<(&'static str, FieldValue)>),
+    TimeOfDay(f32),
+    /// Name of a file of [`PRESETS_DIR`] without the extension
+    Weather(String),
+    SaveBookmark(String),
+    GoToBookmark(String),
+    GenerationLock(LockAction),
+}
+
+/// What the connections can read without the world, refreshed by [`update_remote_state`]
+#[derive(Default)]
+struct SharedState {
+    /// The answer to `GET /state`
+    state: Value,
+    bookmarks: Vec<String>,
+}
+
+#[derive(Resource)]
+struct RemoteServer {
+    listener: TcpListener,
+    sender: Sender<RemoteCommand>,
+    shared: Arc<Mutex<SharedState>>,
+}
+
+#[derive(Resource)]
+struct RemoteCommands(Mutex<Receiver<RemoteCommand>>);
+
+#[derive(Resource, Default)]
+struct RemoteState {
+    /// Hour last set with `/time_of_day`, the sun only follows the config otherwise
+    hour: Option<f32>,
+    bookmarks: BTreeMap<String, CameraKeyframe>,
+}
+
+pub struct RemotePlugin;
+
+impl Plugin for RemotePlugin {
+    fn build(&self, app: &mut App) {
+        let bind = app
+            .world()
+            .get_resource::<CliArgs>()
+            .and_then(|cli| cli.remote_bind.clone())
+            .unwrap_or_else(|| DEFAULT_BIND.to_string());
+        let listener = match TcpListener::bind(&bind) {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("remote control: failed to listen on {bind}: {err}");
+                return;
+            }
+        };
+        if let Err(err) = listener.set_nonblocking(true) {
+            error!("remote control: {err}");
+            return;
+        }
+        info!("remote control listening on {bind}");
+
+        let (sender, receiver) = channel();
+        app.insert_resource(RemoteServer {
+            listener,
+            sender,
+            shared: default(),
+        })
+        .insert_resource(RemoteCommands(Mutex::new(receiver)))
+        .insert_resource(RemoteState {
+            bookmarks: load_bookmarks(),
+            ..default()
+        })
+        .add_systems(
+            PreUpdate,
+            apply_remote_commands.before(validation::validate_scene_config),
+        )
+        .add_systems(
+            Update,
+            (
+                accept_remote_connections,
+                update_remote_state,
+                stop_remote_control,
+            )
+                .run_if(resource_exists::<RemoteServer>),
+        );
+    }
+}
+
+fn load_bookmarks() -> BTreeMap<String, CameraKeyframe> {
+    let Ok(file) = std::fs::read_to_string(BOOKMARKS_PATH) else {
+        return default();
+    };
+    ron::from_str(&file).unwrap_or_else(|err| {
+        warn!("failed to read {BOOKMARKS_PATH}: {err}");
+        default()
+    })
+}
+
+fn save_bookmarks(bookmarks: &BTreeMap<String, CameraKeyframe>) {
+    let Ok(serialized) = ron::ser::to_string_pretty(bookmarks, default()) else {
+        error!("failed to serialize the camera bookmarks");
+        return;
+    };
+    IoTaskPool::get()
+        .spawn(async move {
+            if let Err(err) = std::fs::write(BOOKMARKS_PATH, serialized) {
+                error!("failed to write {BOOKMARKS_PATH}: {err}");
+            }
+        })
+        .detach();
+}
+
+/// Where the directional light looks at `hour`. The sun rises toward +x at 6, peaks at
+/// [`MAX_SUN_ELEVATION`] toward +z at noon and sets toward -x at 18.
+fn sun_direction(hour: f32) -> Vec3 {
+    let angle = (hour - 6.0) / 12.0 * std::f32::consts::PI;
+    let (sin, cos) = angle.sin_cos();
+    let elevation = MAX_SUN_ELEVATION.to_radians();
+    let sun = Vec3::new(cos, sin * elevation.sin(), sin * elevation.cos());
+    -sun
+}
+
+/// Hands the waiting connections to the IO task pool
+fn accept_remote_connections(server: Res<RemoteServer>) {
+    loop {
+        match server.listener.accept() {
+            Ok((stream, _)) => {
+                let sender = server.sender.clone();
+                let shared = server.shared.clone();
+                IoTaskPool::get()
+                    .spawn(async move { handle_connection(stream, &sender, &shared) })
+                    .detach();
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+            Err(err) => {
+                warn!("remote control: {err}");
+                return;
+            }
+        }
+    }
+}
+
+fn stop_remote_control(mut commands: Commands, mut exit: EventReader<AppExit>) {
+    if exit.read().next().is_some() {
+        commands.remove_resource::<RemoteServer>();
+        info!("remote control stopped");
+    }
+}
+
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl HttpError {
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self {
+            status: 400,
+            message: message.into(),
+        }
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    sender: &Sender<RemoteCommand>,
+    shared: &Mutex<SharedState>,
+) {
+    // Accepted sockets inherit the non blocking listener on some platforms
+    let setup = stream
+        .set_nonblocking(false)
+        .and_then(|()| stream.set_read_timeout(Some(READ_TIMEOUT)));
+    if let Err(err) = setup {
+        warn!("remote control: {err}");
+        return;
+    }
+    let mut reader = BufReader::new(&stream);
+    let (status, body) = match read_request(&mut reader)
+        .and_then(|(method, path, body)| route(&method, &path, &body, sender, shared))
+    {
+        Ok(body) => (200, body),
+        Err(err) => (err.status, json!({ "error": err.message })),
+    };
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        431 => "Request Header Fields Too Large",
+        _ => "Bad Request",
+    };
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+        Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    if let Err(err) = (&stream).write_all(response.as_bytes()) {
+        warn!("remote control: {err}");
+    }
+}
+
+/// Reads a line of the request line or the headers, `budget` is what's left of
+/// [`MAX_HEAD_BYTES`]
+fn read_head_line(reader: &mut impl BufRead, budget: &mut usize) -> Result<String, HttpError> {
+    let mut line = String::new();
+    let read = reader
+        .by_ref()
+        .take(*budget as u64)
+        .read_line(&mut line)
+        .map_err(|err| HttpError::bad_request(err.to_string()))?;
+    *budget -= read;
+    if *budget == 0 && !line.ends_with('\n') {
+        return Err(HttpError {
+            status: 431,
+            message: format!("the headers are limited to {MAX_HEAD_BYTES} bytes"),
+        });
+    }
+    Ok(line)
+}
+
+/// Reads the method, the path and the body of a request
+fn read_request(reader: &mut impl BufRead) -> Result<(String, String, String), HttpError> {
+    let read_error = |err: std::io::Error| HttpError::bad_request(err.to_string());
+    let mut budget = MAX_HEAD_BYTES;
+    let request_line = read_head_line(reader, &mut budget)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(HttpError::bad_request("malformed request line"));
+    };
+
+    let mut content_length = 0;
+    let mut json = false;
+    loop {
+        let header = read_head_line(reader, &mut budget)?;
+        if header.is_empty() {
+            return Err(HttpError::bad_request("the headers never end"));
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| HttpError::bad_request("invalid Content-Length"))?;
+            } else if name.eq_ignore_ascii_case("content-type") {
+                // Without the parameters, like `; charset=utf-8`
+                let media_type = value.split(';').next().unwrap_or_default().trim();
+                json = media_type.eq_ignore_ascii_case("application/json");
+            }
+        }
+    }
+    if method == "POST" && !json {
+        return Err(HttpError {
+            status: 415,
+            message: "the body has to be sent as Content-Type: application/json".into(),
+        });
+    }
+    if content_length > MAX_BODY_BYTES {
+        return Err(HttpError {
+            status: 413,
+            message: format!("the body is limited to {MAX_BODY_BYTES} bytes"),
+        });
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).map_err(read_error)?;
+    let body =
+        String::from_utf8(body).map_err(|_| HttpError::bad_request("the body isn't UTF-8"))?;
+    Ok((method.to_string(), path.to_string(), body))
+}
+
+fn route(
+    method: &str,
+    path: &str,
+    body: &str,
+    sender: &Sender<RemoteCommand>,
+    shared: &Mutex<SharedState>,
+) -> Result<Value, HttpError> {
+    let command = match (method, path) {
+        ("GET", "/state") => return Ok(shared.lock().unwrap().state.clone()),
+        ("POST", "/scene") => RemoteCommand::Scene(parse_fields(SCENE_FIELDS, body)?),
+        ("POST", "/terrain") => RemoteCommand::Terrain(parse_fields(TERRAIN_FIELDS, body)?),
+        ("POST", "/time_of_day") => {
+            let hour = parse_object(body)?
+                .get("hour")
+                .and_then(Value::as_f64)
+                .ok_or_else(|| HttpError::bad_request("expected {\"hour\": <number>}"))?;
+            if !(0.0..=24.0).contains(&hour) {
+                return Err(HttpError::bad_request("the hour goes from 0 to 24"));
+            }
+            RemoteCommand::TimeOfDay(hour as f32)
+        }
+        ("POST", "/weather") => {
+            let preset = parse_object(body)?
+                .get("preset")
+                .and_then(Value::as_str)
+                .ok_or_else(|| HttpError::bad_request("expected {\"preset\": <name>}"))?
+                .to_string();
+            if !presets().contains(&preset) {
+                return Err(HttpError::bad_request(format!(
+                    "no preset named {preset:?} in {PRESETS_DIR}"
+                )));
+            }
+            RemoteCommand::Weather(preset)
+        }
+        ("POST", "/bookmarks") => {
+            let object = parse_object(body)?;
+            match (object.get("save"), object.get("go")) {
+                (Some(Value::String(name)), None) if !name.is_empty() => {
+                    RemoteCommand::SaveBookmark(name.clone())
+                }
+                (None, Some(Value::String(name))) => {
+                    if !shared.lock().unwrap().bookmarks.contains(name) {
+                        return Err(HttpError::bad_request(format!(
+                            "no bookmark named {name:?}"
+                        )));
+                    }
+                    RemoteCommand::GoToBookmark(name.clone())
+                }
+                _ => {
+                    return Err(HttpError::bad_request(
+                        "expected {\"save\": <name>} or {\"go\": <name>}",
+                    ))
+                }
+            }
+        }
+        ("POST", "/generation_lock") => {
+            let object = parse_object(body)?;
+            let action = match object.get("action").and_then(Value::as_str) {
+                Some("lock") => LockAction::Lock,
+                Some("unlock") => LockAction::Unlock,
+                Some("apply") => LockAction::Apply,
+                Some("discard") => LockAction::Discard,
+                _ => {
+                    return Err(HttpError::bad_request(
+                        "expected {\"action\": <lock, unlock, apply or discard>}",
+                    ))
+                }
+            };
+            RemoteCommand::GenerationLock(action)
+        }
+        (
+            _,
+            "/state" | "/scene" | "/terrain" | "/time_of_day" | "/weather" | "/bookmarks"
+            | "/generation_lock",
+        ) => {
+            return Err(HttpError {
+                status: 405,
+                message: format!("{method} isn't supported on {path}"),
+            })
+        }
+        _ => {
+            return Err(HttpError {
+                status: 404,
+                message: format!("nothing at {path}"),
+            })
+        }
+    };
+    // Only fails once the app is closing
+    let _ = sender.send(command);
+    Ok(json!({ "queued": true }))
+}
+
+fn parse_object(body: &str) -> Result<Map<String, Value>, HttpError> {
+    match serde_json::from_str(body) {
+        Ok(Value::Object(object)) => Ok(object),
+        Ok(_) => Err(HttpError::bad_request("expected a JSON object")),
+        Err(err) => Err(HttpError::bad_request(format!("invalid JSON: {err}"))),
+    }
+}
+
+/// Checks every field of the body against the whitelist, nothing is applied if one is wrong
+fn parse_fields(
+    whitelist: &[(&'static str, FieldKind)],
+    body: &str,
+) -> Result<Vec<(&'static str, FieldValue)>, HttpError> {
+    let mut fields = vec![];
+    for (name, value) in parse_object(body)? {
+        let Some(&(field, kind)) = whitelist.iter().find(|(field, _)| *field == name) else {
+            return Err(HttpError::bad_request(format!(
+                "{name} can't be changed remotely"
+            )));
+        };
+        let value = match (kind, &value) {
+            (FieldKind::Bool, Value::Bool(value)) => FieldValue::Bool(*value),
+            (FieldKind::Number, Value::Number(number)) => {
+                FieldValue::Number(number.as_f64().unwrap_or_default())
+            }
+            (FieldKind::Integer, Value::Number(number)) if number.is_u64() => {
+                FieldValue::Number(number.as_f64().unwrap_or_default())
+            }
+            (FieldKind::Bool, _) => {
+                return Err(HttpError::bad_request(format!("{name} is a bool")))
+            }
+            (FieldKind::Number, _) => {
+                return Err(HttpError::bad_request(format!("{name} is a number")))
+            }
+            (FieldKind::Integer, _) => {
+                return Err(HttpError::bad_request(format!(
+                    "{name} is a positive integer"
+                )))
+            }
+        };
+        fields.push((field, value));
+    }
+    Ok(fields)
+}
+
+/// Names of the scene files in [`PRESETS_DIR`]
+fn presets() -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(PRESETS_DIR) else {
+        return vec![];
+    };
+    let mut presets: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            Some(name.strip_suffix(".scn.ron")?.to_string())
+        })
+        .collect();
+    presets.sort();
+    presets
+}
+
+/// Writes a whitelisted field, its type was checked against the [`FieldKind`] already
+fn set_field(config: &mut dyn Reflect, path: &str, value: FieldValue) {
+    let Ok(field) = config.reflect_path_mut(path) else {
+        error!("remote control: no field {path} in the config");
+        return;
+    };
+    match value {
+        FieldValue::Bool(value) => {
+            if let Some(field) = field.downcast_mut::<bool>() {
+                *field = value;
+            }
+        }
+        FieldValue::Number(value) => {
+            if let Some(field) = field.downcast_mut::<f32>() {
+                *field = value as f32;
+            } else if let Some(field) = field.downcast_mut::<f64>() {
+                *field = value;
+            } else if let Some(field) = field.downcast_mut::<u32>() {
+                *field = value as u32;
+            } else if let Some(field) = field.downcast_mut::<usize>() {
+                *field = value as usize;
+            }
+        }
+    }
+}
+
+fn field_to_json(config: &dyn Reflect, path: &str) -> Value {
+    let Ok(field) = config.reflect_path(path) else {
+        return Value::Null;
+    };
+    if let Some(value) = field.downcast_ref::<bool>() {
+        json!(value)
+    } else if let Some(value) = field.downcast_ref::<f32>() {
+        json!(value)
+    } else if let Some(value) = field.downcast_ref::<f64>() {
+        json!(value)
+    } else if let Some(value) = field.downcast_ref::<u32>() {
+        json!(value)
+    } else if let Some(value) = field.downcast_ref::<usize>() {
+        json!(value)
+    } else {
+        Value::Null
+    }
+}
+
+fn fields_to_json(config: &dyn Reflect, whitelist: &[(&str, FieldKind)]) -> Value {
+    let fields = whitelist
+        .iter()
+        .map(|(field, _)| (field.to_string(), field_to_json(config, field)))
+        .collect();
+    Value::Object(fields)
+}
+
+/// Applies the commands queued since the last frame
+#[allow(clippy::too_many_arguments)]
+fn apply_remote_commands(
+    mut commands: Commands,
+    receiver: Res<RemoteCommands>,
+    asset_server: Res<AssetServer>,
+    mut remote: ResMut<RemoteState>,
+    mut scene_config: Option<ResMut<SceneConfig>>,
+    mut terrain_config: Option<ResMut<TerrainConfig>>,
+    mut generation_lock: ResMut<GenerationLock>,
+    mut camera: Query<
+        (&mut Transform, &mut CameraController),
+        (With<Camera3d>, Without<SecondaryCamera>),
+    >,
+) {
+    let receiver = receiver.0.lock().unwrap();
+    for command in receiver.try_iter() {
+        info!("remote control: {command:?}");
+        match command {
+            RemoteCommand::Scene(fields) => {
+                let Some(scene_config) = scene_config.as_deref_mut() else {
+                    warn!("remote control: the scene config isn't loaded yet");
+                    continue;
+                };
+                for (field, value) in fields {
+                    set_field(scene_config, field, value);
+                }
+            }
+            RemoteCommand::Terrain(fields) => {
+                let Some(terrain_config) = terrain_config.as_deref_mut() else {
+                    warn!("remote control: the terrain config isn't loaded yet");
+                    continue;
+                };
+                for (field, value) in fields {
+                    set_field(terrain_config, field, value);
+                }
+            }
+            RemoteCommand::TimeOfDay(hour) => {
+                let Some(scene_config) = scene_config.as_deref_mut() else {
+                    warn!("remote control: the scene config isn't loaded yet");
+                    continue;
+                };
+                scene_config.directional_light_looking_to = sun_direction(hour);
+                remote.hour = Some(hour);
+            }
+            RemoteCommand::Weather(preset) => {
+                commands.spawn((
+                    DynamicSceneBundle {
+                        scene: asset_server.load(format!("presets/{preset}.scn.ron")),
+                        ..default()
+                    },
+                    ConfigCarrier,
+                ));
+            }
+            RemoteCommand::SaveBookmark(name) => {
+                let Ok((transform, _)) = camera.get_single() else {
+                    continue;
+                };
+                let keyframe = CameraKeyframe {
+                    position: transform.translation.to_array(),
+                    look_at: (transform.translation + *transform.forward() * 10.0).to_array(),
+                };
+                remote.bookmarks.insert(name, keyframe);
+                save_bookmarks(&remote.bookmarks);
+            }
+            RemoteCommand::GoToBookmark(name) => {
+                let (Some(keyframe), Ok((mut transform, mut controller))) =
+                    (remote.bookmarks.get(&name), camera.get_single_mut())
+                else {
+                    continue;
+                };
+                *transform = Transform::from_translation(Vec3::from(keyframe.position))
+                    .looking_at(Vec3::from(keyframe.look_at), Vec3::Y);
+                // Picks up the yaw and pitch of the new transform
+                controller.initialized = false;
+            }
+            RemoteCommand::GenerationLock(action) => generation_lock.request(action),
+        }
+    }
+}
+
+/// Refreshes what `GET /state` answers once something it shows changed
+fn update_remote_state(
+    server: Res<RemoteServer>,
+    mut remote: ResMut<RemoteState>,
+    scene_config: Option<Res<SceneConfig>>,
+    terrain_config: Option<Res<TerrainConfig>>,
+    generation_lock: Res<GenerationLock>,
+) {
+    let configs_changed = scene_config
+        .as_ref()
+        .is_some_and(|config| config.is_changed())
+        || terrain_config
+            .as_ref()
+            .is_some_and(|config| config.is_changed());
+    if !configs_changed && !remote.is_changed() && !generation_lock.is_changed() {
+        return;
+    }
+    let bookmarks: Vec<String> = remote.bookmarks.keys().cloned().collect();
+    let state = json!({
+        "scene": scene_config.map(|config| fields_to_json(&*config, SCENE_FIELDS)),
+        "terrain": terrain_config.map(|config| fields_to_json(&*config, TERRAIN_FIELDS)),
+        "time_of_day": remote.hour,
+        "presets": presets(),
+        "bookmarks": bookmarks,
+        "generation": {
+            "locked": generation_lock.locked,
+            "pending": generation_lock.pending_changes(),
+        },
+    });
+    let mut shared = server.shared.lock().unwrap();
+    shared.state = state;
+    shared.bookmarks = bookmarks;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(request: &str) -> Result<(String, String, String), u16> {
+        read_request(&mut request.as_bytes()).map_err(|err| err.status)
+    }
+
+    #[test]
+    fn posts_need_a_json_body() {
+        let body = r#"{"rain_intensity": 0.8}"#;
+        let post = |content_type: &str| {
+            read(&format!(
+                "POST /scene HTTP/1.1\r\n{content_type}Content-Length: {}\r\n\r\n{body}",
+                body.len()
+            ))
+        };
+        assert_eq!(
+            post("Content-Type: application/json; charset=utf-8\r\n"),
+            Ok(("POST".into(), "/scene".into(), body.into()))
+        );
+        assert_eq!(
+            post("content-type: Application/JSON\r\n").map(|_| ()),
+            Ok(())
+        );
+        // What a form or a plain fetch of another site sends without a preflight
+        assert_eq!(post(""), Err(415));
+        assert_eq!(post("Content-Type: text/plain\r\n"), Err(415));
+        assert_eq!(
+            read("GET /state HTTP/1.1\r\n\r\n"),
+            Ok(("GET".into(), "/state".into(), String::new()))
+        );
+    }
+
+    #[test]
+    fn the_headers_and_the_body_are_limited() {
+        let header = format!("X-Padding: {}\r\n", "a".repeat(MAX_HEAD_BYTES));
+        assert_eq!(
+            read(&format!("GET /state HTTP/1.1\r\n{header}\r\n")),
+            Err(431)
+        );
+        let headers = "X-Padding: a\r\n".repeat(MAX_HEAD_BYTES / 8);
+        assert_eq!(
+            read(&format!("GET /state HTTP/1.1\r\n{headers}\r\n")),
+            Err(431)
+        );
+        assert_eq!(read(&"a".repeat(MAX_HEAD_BYTES * 2)), Err(431));
+        assert_eq!(
+            read(&format!(
+                "POST /scene HTTP/1.1\r\nContent-Type: application/json\r\n\
+                Content-Length: {}\r\n\r\n",
+                MAX_BODY_BYTES + 1
+            )),
+            Err(413)
+        );
+        assert_eq!(read("GET /state HTTP/1.1\r\nHost: scene\r\n"), Err(400));
+    }
+}
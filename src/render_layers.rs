@@ -0,0 +1,153 @@
+//! Every render layer used by the scene, in one place so two features never pick the same number.
+//!
+//! The entities and the cameras get their [`RenderLayers`] from [`SceneLayer`] instead of a raw
+//! layer number. `MainScene` is layer 0, the one bevy gives to the entities without
+//! `RenderLayers`, so anything spawned without going through here still shows up in the main view.
+//!
+//! - `MainScene`: the terrain, the trees, the water and everything else in the world.
+//! - `WaterReflection`: only seen by a planar reflection camera, like a cheaper stand-in for the
+//!   trees. The water only has SSR for now so nothing uses it yet.
+//! - `Minimap`: only seen by the top down view of the split view, like icons.
+//! - `BakeOnly`: only seen by the cameras baking atlases or impostors, no regular camera should
+//!   render it, see [`check_camera_layers`].
+//! - `ShadowProxy`: the shadow proxies of the trees, only there for the lights. The main camera
+//!   keeps it so the visibility ranges of the proxies are still computed from it.
+
+use bevy::{prelude::*, render::view::RenderLayers};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SceneLayer {
+    MainScene = 0,
+    #[allow(dead_code)]
+    WaterReflection = 1,
+    Minimap = 2,
+    BakeOnly = 3,
+    ShadowProxy = 4,
+}
+
+impl SceneLayer {
+    /// Only this layer
+    pub fn layers(self) -> RenderLayers {
+        RenderLayers::layer(self as usize)
+    }
+
+    /// Every layer in `layers`
+    pub fn mask(layers: &[SceneLayer]) -> RenderLayers {
+        layers.iter().fold(RenderLayers::none(), |mask, layer| {
+            mask.with(*layer as usize)
+        })
+    }
+
+    /// What the main camera renders
+    pub fn main_camera() -> RenderLayers {
+        Self::mask(&[SceneLayer::MainScene, SceneLayer::ShadowProxy])
+    }
+
+    /// What the top down view of the split view renders
+    pub fn top_down_camera() -> RenderLayers {
+        Self::main_camera().with(SceneLayer::Minimap as usize)
+    }
+
+    /// What the lights cast shadows from
+    pub fn lights() -> RenderLayers {
+        Self::mask(&[SceneLayer::MainScene, SceneLayer::ShadowProxy])
+    }
+}
+
+/// Marks the cameras allowed to render [`SceneLayer::BakeOnly`]
+#[derive(Component)]
+pub struct BakeCamera;
+
+/// Catches the cameras that render the bake layer without being bake cameras, and a main camera
+/// that doesn't see the entities without `RenderLayers` anymore
+pub fn check_camera_layers(
+    cameras: Query<(&RenderLayers, Has<BakeCamera>), (With<Camera3d>, Changed<RenderLayers>)>,
+) {
+    let bake_only = SceneLayer::BakeOnly.layers();
+    for (layers, bake_camera) in &cameras {
+        debug_assert!(
+            bake_camera || !layers.intersects(&bake_only),
+            "a camera renders the bake layer without being a bake camera: {layers:?}"
+        );
+        debug_assert!(
+            bake_camera || layers.intersects(&RenderLayers::default()),
+            "a camera doesn't render the main scene anymore: {layers:?}"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+
+    const ALL: [SceneLayer; 5] = [
+        SceneLayer::MainScene,
+        SceneLayer::WaterReflection,
+        SceneLayer::Minimap,
+        SceneLayer::BakeOnly,
+        SceneLayer::ShadowProxy,
+    ];
+
+    /// The scene layers seen by a camera rendering `camera`, bevy draws an entity when its layers
+    /// intersect the ones of the camera
+    fn visible(camera: RenderLayers) -> Vec<SceneLayer> {
+        ALL.into_iter()
+            .filter(|layer| camera.intersects(&layer.layers()))
+            .collect()
+    }
+
+    #[test]
+    fn layers_dont_overlap() {
+        for (i, a) in ALL.iter().enumerate() {
+            assert_eq!(SceneLayer::mask(&[*a]), a.layers());
+            for b in &ALL[i + 1..] {
+                assert!(!a.layers().intersects(&b.layers()), "{a:?} and {b:?}");
+            }
+        }
+        assert_eq!(SceneLayer::mask(&[]), RenderLayers::none());
+    }
+
+    #[test]
+    fn cameras_see_their_layers_and_never_the_bake_one() {
+        // Same as the entities spawned without `RenderLayers`
+        assert_eq!(SceneLayer::MainScene.layers(), RenderLayers::default());
+        assert_eq!(
+            visible(SceneLayer::main_camera()),
+            [SceneLayer::MainScene, SceneLayer::ShadowProxy]
+        );
+        assert_eq!(
+            visible(SceneLayer::top_down_camera()),
+            [
+                SceneLayer::MainScene,
+                SceneLayer::Minimap,
+                SceneLayer::ShadowProxy
+            ]
+        );
+        assert_eq!(
+            visible(SceneLayer::lights()),
+            [SceneLayer::MainScene, SceneLayer::ShadowProxy]
+        );
+    }
+
+    fn check(camera: impl Bundle) {
+        let mut world = World::new();
+        world.spawn((Camera3d::default(), camera));
+        world.run_system_once(check_camera_layers);
+    }
+
+    #[test]
+    fn the_scene_cameras_pass_the_check() {
+        check(SceneLayer::main_camera());
+        check(SceneLayer::top_down_camera());
+        check((BakeCamera, SceneLayer::BakeOnly.layers()));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "bake layer")]
+    fn regular_cameras_cant_render_the_bake_layer() {
+        check(SceneLayer::main_camera().with(SceneLayer::BakeOnly as usize));
+    }
+}
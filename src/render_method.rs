@@ -0,0 +1,54 @@
+//! Switches the scene between the deferred and the forward renderer.
+//!
+//! Deferred is the default. Forward is picked with `--forward` or `forward_rendering` in the
+//! scene config, it works better on some GPUs and drivers. It's a startup setting, the config is
+//! only read the first time it's loaded. SSR needs the deferred gbuffer so it's dropped in
+//! forward, SSAO gets its normals from the normal prepass instead.
+//!
+//! The app is always built with the deferred renderer and switched once the scene config is
+//! there, the materials prepared until then are prepared again.
+
+use bevy::{
+    core_pipeline::prepass::{DeferredPrepass, NormalPrepass},
+    pbr::{DefaultOpaqueRendererMethod, ExtendedMaterial, ScreenSpaceReflectionsSettings},
+    prelude::*,
+};
+
+use crate::{cli::CliArgs, terrain::TerrainMaterial, water::WaterMaterial, SceneConfig};
+
+#[allow(clippy::too_many_arguments)]
+pub fn apply_renderer_method(
+    mut commands: Commands,
+    cli: Res<CliArgs>,
+    scene_config: Res<SceneConfig>,
+    mut default_method: ResMut<DefaultOpaqueRendererMethod>,
+    cameras: Query<Entity, With<Camera3d>>,
+    mut standard_materials: ResMut<Assets<StandardMaterial>>,
+    mut terrain_materials: ResMut<Assets<ExtendedMaterial<StandardMaterial, TerrainMaterial>>>,
+    mut water_materials: ResMut<Assets<WaterMaterial>>,
+    mut applied: Local<bool>,
+) {
+    if *applied {
+        return;
+    }
+    *applied = true;
+
+    if !cli.forward && !scene_config.forward_rendering {
+        return;
+    }
+    info!("using the forward renderer");
+
+    default_method.set_to_forward();
+    // The materials that were already prepared still target the deferred pipeline, touching them
+    // prepares them again with the new default
+    for _ in standard_materials.iter_mut() {}
+    for _ in terrain_materials.iter_mut() {}
+    for _ in water_materials.iter_mut() {}
+
+    for entity in &cameras {
+        commands
+            .entity(entity)
+            .remove::<(DeferredPrepass, ScreenSpaceReflectionsSettings)>()
+            .insert(NormalPrepass);
+    }
+}
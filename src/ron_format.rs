@@ -0,0 +1,491 @@
+//! Stable formatting for the RON files we write.
+//!
+//! `DynamicScene::serialize` emits fields in whatever order the reflection data has them which
+//! changes between bevy versions and makes the config diffs noisy. The output is parsed back into
+//! a small tree, struct fields and map keys are sorted and floats are printed with a fixed
+//! precision so saving the same config always produces the same bytes.
+
+use std::fmt::Write;
+
+/// Number of decimals kept for floats, trailing zeros are trimmed
+const FLOAT_PRECISION: usize = 6;
+const INDENT: &str = "  ";
+
+#[derive(Debug)]
+pub struct FormatError {
+    pub position: usize,
+    pub message: &'static str,
+}
+
+impl std::fmt::Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.position)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Node {
+    /// Numbers, strings, chars and identifiers
+    Atom(String),
+    Struct {
+        name: Option<String>,
+        fields: Vec<(String, Node)>,
+    },
+    Tuple {
+        name: Option<String>,
+        items: Vec<Node>,
+    },
+    List(Vec<Node>),
+    Map(Vec<(Node, Node)>),
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &'static str) -> FormatError {
+        FormatError {
+            position: self.position,
+            message,
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.position..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.position += rest.len() - trimmed.len();
+            if trimmed.starts_with("//") {
+                self.position += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else if trimmed.starts_with("/*") {
+                self.position += trimmed.find("*/").map_or(trimmed.len(), |end| end + 2);
+            } else {
+                return;
+            }
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.rest().chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.position += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char, message: &'static str) -> Result<(), FormatError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(message))
+        }
+    }
+
+    /// Identifiers and numbers
+    fn word(&mut self) -> Option<&'a str> {
+        self.skip_whitespace();
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_alphanumeric() || matches!(c, '_' | '.' | '+' | '-')))
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return None;
+        }
+        self.position += len;
+        Some(&rest[..len])
+    }
+
+    fn quoted(&mut self, quote: char) -> Result<String, FormatError> {
+        let rest = self.rest();
+        let mut escaped = false;
+        for (i, c) in rest.char_indices().skip(1) {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == quote {
+                self.position += i + 1;
+                return Ok(rest[..=i].to_string());
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+
+    fn value(&mut self) -> Result<Node, FormatError> {
+        match self.peek() {
+            Some('(') => self.parenthesized(None),
+            Some('[') => {
+                self.position += 1;
+                let mut items = Vec::new();
+                while !self.eat(']') {
+                    items.push(self.value()?);
+                    if !self.eat(',') {
+                        self.expect(']', "expected `,` or `]`")?;
+                        break;
+                    }
+                }
+                Ok(Node::List(items))
+            }
+            Some('{') => {
+                self.position += 1;
+                let mut entries = Vec::new();
+                while !self.eat('}') {
+                    let key = self.value()?;
+                    self.expect(':', "expected `:` after map key")?;
+                    entries.push((key, self.value()?));
+                    if !self.eat(',') {
+                        self.expect('}', "expected `,` or `}`")?;
+                        break;
+                    }
+                }
+                Ok(Node::Map(entries))
+            }
+            Some(quote @ ('"' | '\'')) => Ok(Node::Atom(self.quoted(quote)?)),
+            Some(_) => {
+                let word = self
+                    .word()
+                    .ok_or_else(|| self.error("unexpected character"))?;
+                if self.peek() == Some('(') {
+                    self.parenthesized(Some(word.to_string()))
+                } else {
+                    Ok(Node::Atom(format_number(word)))
+                }
+            }
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    /// Either a struct or a tuple, they only differ by the `name:` before the first value
+    fn parenthesized(&mut self, name: Option<String>) -> Result<Node, FormatError> {
+        self.expect('(', "expected `(`")?;
+        let start = self.position;
+        let is_struct = self.word().is_some() && self.peek() == Some(':');
+        self.position = start;
+
+        if is_struct {
+            let mut fields = Vec::new();
+            while !self.eat(')') {
+                let field = self
+                    .word()
+                    .ok_or_else(|| self.error("expected field name"))?
+                    .to_string();
+                self.expect(':', "expected `:` after field name")?;
+                fields.push((field, self.value()?));
+                if !self.eat(',') {
+                    self.expect(')', "expected `,` or `)`")?;
+                    break;
+                }
+            }
+            Ok(Node::Struct { name, fields })
+        } else {
+            let mut items = Vec::new();
+            while !self.eat(')') {
+                items.push(self.value()?);
+                if !self.eat(',') {
+                    self.expect(')', "expected `,` or `)`")?;
+                    break;
+                }
+            }
+            Ok(Node::Tuple { name, items })
+        }
+    }
+}
+
+/// Prints floats with a fixed precision, everything else is kept as is
+fn format_number(word: &str) -> String {
+    let is_float = word.contains(['.', 'e', 'E']) && !word.starts_with(|c: char| c.is_alphabetic());
+    match word.parse::<f64>() {
+        Ok(value) if is_float => {
+            let formatted = format!("{value:.FLOAT_PRECISION$}");
+            let trimmed = formatted.trim_end_matches('0');
+            let formatted = if trimmed.ends_with('.') {
+                format!("{trimmed}0")
+            } else {
+                trimmed.to_string()
+            };
+            // Avoid saving -0.0 when something rounds to zero
+            if formatted == "-0.0" {
+                "0.0".to_string()
+            } else {
+                formatted
+            }
+        }
+        _ => word.to_string(),
+    }
+}
+
+/// Integer keys are compared by value so entity ids don't end up in lexicographic order
+fn key_order(a: &Node, b: &Node) -> std::cmp::Ordering {
+    match (a, b) {
+        (Node::Atom(a), Node::Atom(b)) => match (a.parse::<i64>(), b.parse::<i64>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            _ => a.cmp(b),
+        },
+        _ => print(a, 0).cmp(&print(b, 0)),
+    }
+}
+
+fn sort(node: &mut Node) {
+    match node {
+        Node::Atom(_) => {}
+        Node::Struct { fields, .. } => {
+            fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+            fields.iter_mut().for_each(|(_, value)| sort(value));
+        }
+        Node::Tuple { items, .. } | Node::List(items) => items.iter_mut().for_each(sort),
+        Node::Map(entries) => {
+            entries.sort_by(|(a, _), (b, _)| key_order(a, b));
+            for (key, value) in entries {
+                sort(key);
+                sort(value);
+            }
+        }
+    }
+}
+
+fn print(node: &Node, depth: usize) -> String {
+    let mut out = String::new();
+    write_node(&mut out, node, depth);
+    out
+}
+
+fn write_node(out: &mut String, node: &Node, depth: usize) {
+    let indent = INDENT.repeat(depth + 1);
+    let close_indent = INDENT.repeat(depth);
+    match node {
+        Node::Atom(atom) => out.push_str(atom),
+        Node::Struct { name, fields } => {
+            out.push_str(name.as_deref().unwrap_or(""));
+            if fields.is_empty() {
+                out.push_str("()");
+                return;
+            }
+            out.push_str("(\n");
+            for (field, value) in fields {
+                let _ = write!(out, "{indent}{field}: ");
+                write_node(out, value, depth + 1);
+                out.push_str(",\n");
+            }
+            let _ = write!(out, "{close_indent})");
+        }
+        Node::Tuple { name, items } => {
+            out.push_str(name.as_deref().unwrap_or(""));
+            out.push('(');
+            write_items(out, items, depth);
+            out.push(')');
+        }
+        Node::List(items) => {
+            out.push('[');
+            write_items(out, items, depth);
+            out.push(']');
+        }
+        Node::Map(entries) => {
+            if entries.is_empty() {
+                out.push_str("{}");
+                return;
+            }
+            out.push_str("{\n");
+            for (key, value) in entries {
+                out.push_str(&indent);
+                write_node(out, key, depth + 1);
+                out.push_str(": ");
+                write_node(out, value, depth + 1);
+                out.push_str(",\n");
+            }
+            let _ = write!(out, "{close_indent}}}");
+        }
+    }
+}
+
+/// Single atoms and wrappers like `Srgba((...))` stay on one line, the rest gets one item per line
+fn write_items(out: &mut String, items: &[Node], depth: usize) {
+    if let [item] = items {
+        write_node(out, item, depth);
+        return;
+    }
+    if items.is_empty() {
+        return;
+    }
+    out.push('\n');
+    let indent = INDENT.repeat(depth + 1);
+    for item in items {
+        out.push_str(&indent);
+        write_node(out, item, depth + 1);
+        out.push_str(",\n");
+    }
+    out.push_str(&INDENT.repeat(depth));
+}
+
+pub fn parse(input: &str) -> Result<Node, FormatError> {
+    let mut parser = Parser { input, position: 0 };
+    let node = parser.value()?;
+    if parser.peek().is_some() {
+        return Err(parser.error("trailing characters"));
+    }
+    Ok(node)
+}
+
+/// Removes the fields of the top level resources that are the same as in `defaults`.
+///
+/// The resulting file only loads if the stripped fields can be filled in on load, the configs
+/// register `ReflectDefault` for that. The `version` is always kept, the migration would take a
+/// file without it for one written before the configs were versioned.
+fn strip_defaults(node: &mut Node, defaults: &Node) {
+    let (
+        Node::Struct { fields, .. },
+        Node::Struct {
+            fields: defaults, ..
+        },
+    ) = (node, defaults)
+    else {
+        return;
+    };
+    let Some((_, Node::Map(resources))) = fields.iter_mut().find(|(name, _)| name == "resources")
+    else {
+        return;
+    };
+    let Some((_, Node::Map(default_resources))) =
+        defaults.iter().find(|(name, _)| name == "resources")
+    else {
+        return;
+    };
+    for (key, resource) in resources {
+        let Some((
+            _,
+            Node::Struct {
+                fields: default_fields,
+                ..
+            },
+        )) = default_resources.iter().find(|(k, _)| *k == *key)
+        else {
+            continue;
+        };
+        if let Node::Struct { fields, .. } = resource {
+            fields.retain(|field| field.0 == "version" || !default_fields.contains(field));
+        }
+    }
+}
+
+/// Prints a parsed file with the same formatting as [`format_ron`]
+pub fn to_pretty_string(node: &Node) -> String {
+    let mut node = node.clone();
+    sort(&mut node);
+    let mut out = print(&node, 0);
+    out.push('\n');
+    out
+}
+
+/// Formats serialized RON with sorted struct fields and fixed float precision.
+///
+/// When `defaults` is set to the serialization of the default config, fields equal to their
+/// default are removed to keep the file short.
+pub fn format_ron(input: &str, defaults: Option<&str>) -> Result<String, FormatError> {
+    let mut node = parse(input)?;
+    sort(&mut node);
+    if let Some(defaults) = defaults {
+        let mut defaults = parse(defaults)?;
+        sort(&mut defaults);
+        strip_defaults(&mut node, &defaults);
+    }
+    Ok(to_pretty_string(&node))
+}
+
+#[cfg(test)]
+mod tests {
+    // Not the whole prelude, its UI `Node` would clash with ours
+    use bevy::prelude::{default, Resource};
+
+    use super::*;
+    use crate::{
+        migration::{config_type_registry, load_config, serialize_config},
+        terrain::TerrainConfig,
+        SceneConfig,
+    };
+
+    /// Saves the config like Ctrl+S, loads the file back like the scene loader and saves it again
+    fn save_load_save<R: Resource + Default>(config: R, compact: bool) -> (String, String) {
+        let type_registry = config_type_registry();
+        let defaults = compact.then(|| serialize_config(&type_registry, R::default()));
+        let save = |config: R| {
+            format_ron(
+                &serialize_config(&type_registry, config),
+                defaults.as_deref(),
+            )
+            .unwrap()
+        };
+        let saved = save(config);
+        let loaded = load_config::<R>(&type_registry, &saved);
+        (saved, save(loaded))
+    }
+
+    #[test]
+    fn saving_a_loaded_config_gives_the_same_bytes() {
+        let scene_config = SceneConfig {
+            env_map_intensity: 1234.567_9,
+            fog_height_falloff: 0.1,
+            directional_light_kelvin: Some(4500.0),
+            ..default()
+        };
+        let terrain_config = TerrainConfig {
+            seed: 7,
+            frequency: 1.1,
+            rotation: -0.3,
+            ..default()
+        };
+        for compact in [false, true] {
+            let (saved, saved_again) = save_load_save(scene_config.clone(), compact);
+            assert_eq!(saved, saved_again);
+            let (saved, saved_again) = save_load_save(terrain_config.clone(), compact);
+            assert_eq!(saved, saved_again);
+        }
+    }
+
+    #[test]
+    fn compact_configs_only_keep_the_changed_fields_and_the_version() {
+        let terrain_config = TerrainConfig {
+            seed: 7,
+            ..default()
+        };
+        let (saved, _) = save_load_save(terrain_config, true);
+        let file = parse(&saved).unwrap();
+        let fields = resource_fields(&file);
+        let names: Vec<_> = fields.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["seed", "version"]);
+    }
+
+    fn resource_fields(file: &Node) -> &[(String, Node)] {
+        let Node::Struct { fields, .. } = file else {
+            panic!("not a scene");
+        };
+        let Some((_, Node::Map(resources))) = fields.iter().find(|(name, _)| name == "resources")
+        else {
+            panic!("no resources");
+        };
+        match &resources[0].1 {
+            Node::Struct { fields, .. } => fields,
+            node => panic!("not a struct: {node:?}"),
+        }
+    }
+
+    #[test]
+    fn floats_are_printed_with_a_fixed_precision() {
+        assert_eq!(format_number("0.30000001192092896"), "0.3");
+        assert_eq!(format_number("2000.0"), "2000.0");
+        assert_eq!(format_number("1e-9"), "0.0");
+        assert_eq!(format_number("-0.0000001"), "0.0");
+        assert_eq!(format_number("42"), "42");
+        assert_eq!(format_number("inf"), "inf");
+    }
+}
@@ -0,0 +1,605 @@
+//! Data driven placement of objects on the terrain.
+//!
+//! Every layer walks the terrain vertices and keeps the ones matching its height, slope and
+//! spacing rules. The trees are the built-in layer, user layers from the terrain config go
+//! through the same code path after them.
+//!
+//! The vertices come from the heightfield so the placements are in the same world space as every
+//! other consumer of the terrain heights, whatever the terrain rotation.
+//!
+//! The candidates are split in strips of rows scattered in parallel on the compute task pool.
+//! Every strip has its own rng derived from the layer seed and the strip index, and the strips
+//! are concatenated in order, so the result is the same as scattering them one after the other.
+
+use bevy::{math::vec3, prelude::*, tasks::ComputeTaskPool, utils::HashMap};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    expression::{Expression, ExpressionInputs},
+    heightfield::TerrainHeightfield,
+    terrain::GenerationLayer,
+};
+
+#[derive(Reflect, Clone, Debug, PartialEq)]
+pub enum ScatterAsset {
+    /// The built-in tree variants
+    Trees,
+    /// Paths of scenes relative to the assets folder, like `lantern.glb#Scene0`. One is picked
+    /// at random for every instance.
+    Scenes(Vec<String>),
+}
+
+#[derive(Reflect, Clone, Debug, PartialEq)]
+pub struct ScatterLayer {
+    /// Also names the rng stream so adding a layer doesn't move the objects of the other ones
+    pub name: String,
+    pub asset: ScatterAsset,
+    /// Probability that a terrain vertex gets an instance
+    pub density: f32,
+    pub min_height: f32,
+    pub max_height: f32,
+    /// Steepness is 0 on flat ground and 1 on a vertical cliff
+    pub min_steepness: f32,
+    pub max_steepness: f32,
+    pub min_scale: f32,
+    pub max_scale: f32,
+    /// Scale multiplier lost per meter of altitude
+    pub height_scale_falloff: f32,
+    /// Rotation applied before the random spin around the up axis, used for models that aren't
+    /// y up
+    pub base_rotation: Quat,
+    /// 0 keeps the instances upright, 1 aligns them with the terrain normal
+    pub align_to_normal: f32,
+    /// Minimum height above the water surface
+    pub water_distance: f32,
+    /// Minimum distance to the instances of the previous layers
+    pub spacing: f32,
+    /// Rocks or Props for the scenes, decides which config changes spawn the layer again. The
+    /// trees are always in Trees.
+    #[reflect(default)]
+    pub layer: GenerationLayer,
+}
+
+impl ScatterLayer {
+    pub fn variant_count(&self, tree_variants: usize) -> usize {
+        match &self.asset {
+            ScatterAsset::Trees => tree_variants,
+            ScatterAsset::Scenes(paths) => paths.len(),
+        }
+    }
+
+    /// Seed of the rngs of the layer, the trees use the terrain seed directly
+    pub fn rng_seed(&self, seed: u32) -> u64 {
+        if self.asset == ScatterAsset::Trees {
+            return seed as u64;
+        }
+        // FNV-1a, the std hasher isn't guaranteed to be stable between releases
+        let hash = self
+            .name
+            .bytes()
+            .fold(0xcbf29ce484222325_u64, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            });
+        hash ^ seed as u64
+    }
+}
+
+/// Largest multiplier the modifiers give, a formula going through a height close to zero would
+/// otherwise give giant trees
+const MAX_MODIFIER: f32 = 10.0;
+
+/// Formulas varying the density and scale of a layer with the position, see the `expression`
+/// module
+#[derive(Default)]
+pub struct ScatterModifiers {
+    pub density: Option<Expression>,
+    pub scale: Option<Expression>,
+}
+
+#[derive(PartialEq, Debug)]
+pub struct ScatterPlacement {
+    /// Index of the terrain vertex the instance was placed on, stable for a given seed
+    pub candidate: u32,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: f32,
+    pub variant: usize,
+    /// Height of the terrain vertex before the random offset
+    pub terrain_height: f32,
+    pub steepness: f32,
+}
+
+/// Positions of the instances placed so far, bucketed to check the spacing of the next layers
+#[derive(Default)]
+pub struct PlacementGrid {
+    cells: HashMap<IVec2, Vec<Vec3>>,
+}
+
+const GRID_CELL_SIZE: f32 = 4.0;
+
+impl PlacementGrid {
+    fn cell(position: Vec3) -> IVec2 {
+        (position.xz() / GRID_CELL_SIZE).floor().as_ivec2()
+    }
+
+    pub fn insert(&mut self, position: Vec3) {
+        self.cells
+            .entry(Self::cell(position))
+            .or_default()
+            .push(position);
+    }
+
+    pub fn is_free(&self, position: Vec3, spacing: f32) -> bool {
+        if spacing <= 0.0 {
+            return true;
+        }
+        let reach = (spacing / GRID_CELL_SIZE).ceil() as i32;
+        let center = Self::cell(position);
+        for z in -reach..=reach {
+            for x in -reach..=reach {
+                let Some(positions) = self.cells.get(&(center + IVec2::new(x, z))) else {
+                    continue;
+                };
+                if positions
+                    .iter()
+                    .any(|p| p.xz().distance_squared(position.xz()) < spacing * spacing)
+                {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Rows of terrain vertices scattered by a single task
+const STRIP_ROWS: usize = 16;
+
+/// Everything a strip needs, shared by the tasks
+pub struct ScatterInputs<'a> {
+    pub layer: &'a ScatterLayer,
+    pub modifiers: &'a ScatterModifiers,
+    pub heightfield: &'a TerrainHeightfield,
+    /// World space normals of the vertices in the order of the heightfield
+    pub normals: &'a [[f32; 3]],
+    pub water_level: f32,
+    pub variant_count: usize,
+    /// From [`ScatterLayer::rng_seed`]
+    pub seed: u64,
+    pub grid: &'a PlacementGrid,
+}
+
+impl ScatterInputs<'_> {
+    /// Index of every strip with its first candidate and its normals
+    fn strips(&self) -> impl Iterator<Item = (usize, usize, &[[f32; 3]])> {
+        let strip_len = (STRIP_ROWS * self.heightfield.resolution).max(1);
+        self.normals
+            .chunks(strip_len)
+            .enumerate()
+            .map(move |(strip, normals)| (strip, strip * strip_len, normals))
+    }
+}
+
+/// Places the instances of a layer on the terrain vertices, the strips are scattered in parallel
+pub fn scatter(inputs: &ScatterInputs) -> Vec<ScatterPlacement> {
+    let _span = info_span!("scatter_candidates", candidates = inputs.normals.len()).entered();
+    if inputs.variant_count == 0 {
+        return vec![];
+    }
+    // The results come back in the order the tasks were spawned
+    ComputeTaskPool::get()
+        .scope(|scope| {
+            for (strip, first, normals) in inputs.strips() {
+                scope.spawn(async move { scatter_strip(inputs, strip, first, normals) });
+            }
+        })
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Same result as [`scatter`] on the calling thread, to measure what the parallelism brings
+pub fn scatter_sequential(inputs: &ScatterInputs) -> Vec<ScatterPlacement> {
+    if inputs.variant_count == 0 {
+        return vec![];
+    }
+    inputs
+        .strips()
+        .flat_map(|(strip, first, normals)| scatter_strip(inputs, strip, first, normals))
+        .collect()
+}
+
+/// Scatters the candidates of a strip starting at `first`, the rng only depends on the seed and
+/// the strip so the strips can run in any order
+fn scatter_strip(
+    inputs: &ScatterInputs,
+    strip: usize,
+    first: usize,
+    normals: &[[f32; 3]],
+) -> Vec<ScatterPlacement> {
+    let ScatterInputs {
+        layer,
+        modifiers,
+        heightfield,
+        water_level,
+        variant_count,
+        grid,
+        ..
+    } = *inputs;
+    let mut rng =
+        StdRng::seed_from_u64(inputs.seed ^ (strip as u64).wrapping_mul(0x9e3779b97f4a7c15));
+    let mut placements = vec![];
+    let resolution = heightfield.resolution;
+    for (i, n) in normals.iter().enumerate() {
+        let candidate = first + i;
+        let pos = heightfield.world_position(candidate % resolution, candidate / resolution);
+        let terrain_height = pos.y;
+        let normal = Vec3::from_array(*n);
+        let steepness = normal.cross(Vec3::Y).length();
+        let inputs = ExpressionInputs::new(pos, steepness, water_level);
+        // Evaluated without the rng so the expressions don't move the other candidates. A result
+        // that isn't finite rejects the candidate, like a division by a height of zero.
+        let modifier = |expression: &Option<Expression>| match expression {
+            Some(expression) => {
+                let value = expression.evaluate(&inputs);
+                value.is_finite().then(|| value.clamp(0.0, MAX_MODIFIER))
+            }
+            None => Some(1.0),
+        };
+        let density = layer.density * modifier(&modifiers.density).unwrap_or(0.0);
+
+        // The order of the checks matters, the rng is only used once the height is valid
+        if terrain_height < water_level + layer.water_distance
+            || terrain_height < layer.min_height
+            || terrain_height > layer.max_height
+            || rng.gen_range(0.0..1.0) < 1.0 - density
+            || steepness < layer.min_steepness
+            || steepness > layer.max_steepness
+        {
+            continue;
+        }
+
+        // add a random offset to make it less grid like
+        let random_offset = vec3(
+            rng.gen_range(-0.25..0.25),
+            rng.gen_range(-0.05..0.0),
+            rng.gen_range(-0.25..0.25),
+        );
+        let translation = pos + random_offset;
+
+        let variant = rng.gen_range(0..variant_count);
+        let scale_modifier = modifier(&modifiers.scale);
+        let scale = if layer.max_scale > layer.min_scale {
+            rng.gen_range(layer.min_scale..layer.max_scale)
+        } else {
+            layer.min_scale
+        } * (1.0 - terrain_height * layer.height_scale_falloff)
+            * scale_modifier.unwrap_or(0.0);
+        let spin_axis = layer.base_rotation.inverse() * Vec3::Y;
+        let mut rotation = layer.base_rotation.mul_quat(Quat::from_axis_angle(
+            spin_axis,
+            rng.gen_range(0.0..std::f32::consts::TAU),
+        ));
+        if layer.align_to_normal > 0.0 {
+            let align = Quat::IDENTITY.slerp(
+                Quat::from_rotation_arc(Vec3::Y, normal),
+                layer.align_to_normal,
+            );
+            rotation = align * rotation;
+        }
+
+        // The offset can move a shoreline instance over the water, checked after the rng is
+        // used so the following candidates don't move
+        let over_water = heightfield
+            .height_at(translation.xz())
+            .map_or(true, |height| height < water_level + layer.water_distance);
+        if over_water || scale_modifier.is_none() || !grid.is_free(translation, layer.spacing) {
+            continue;
+        }
+        placements.push(ScatterPlacement {
+            candidate: candidate as u32,
+            translation,
+            rotation,
+            scale,
+            variant,
+            terrain_height,
+            steepness,
+        });
+    }
+    placements
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::tasks::TaskPool;
+
+    use super::*;
+
+    /// Rolling hills going below the water level on one side
+    fn hills(rotation: f32) -> TerrainHeightfield {
+        let resolution = 65;
+        let heights = (0..resolution * resolution)
+            .map(|i| {
+                let (x, z) = ((i % resolution) as f32, (i / resolution) as f32);
+                (x * 0.3).sin() * 4.0 + (z * 0.2).cos() * 3.0 + x * 0.1
+            })
+            .collect();
+        TerrainHeightfield {
+            size: 128.0,
+            resolution,
+            rotation,
+            heights,
+        }
+    }
+
+    fn normals(heightfield: &TerrainHeightfield) -> Vec<[f32; 3]> {
+        let resolution = heightfield.resolution;
+        (0..resolution * resolution)
+            .map(|i| {
+                let position = heightfield.world_position(i % resolution, i / resolution);
+                heightfield
+                    .normal_at(position.xz())
+                    .unwrap_or(Vec3::Y)
+                    .to_array()
+            })
+            .collect()
+    }
+
+    fn layer(name: &str) -> ScatterLayer {
+        ScatterLayer {
+            name: name.into(),
+            asset: ScatterAsset::Scenes(vec!["rock.glb#Scene0".into(), "rock2.glb#Scene0".into()]),
+            density: 0.3,
+            min_height: f32::MIN,
+            max_height: f32::MAX,
+            min_steepness: 0.0,
+            max_steepness: 0.8,
+            min_scale: 0.5,
+            max_scale: 1.5,
+            height_scale_falloff: 0.01,
+            base_rotation: Quat::IDENTITY,
+            align_to_normal: 0.5,
+            water_distance: 0.5,
+            spacing: 0.0,
+            layer: GenerationLayer::Rocks,
+        }
+    }
+
+    fn scatter_layer(
+        layer: &ScatterLayer,
+        modifiers: &ScatterModifiers,
+        heightfield: &TerrainHeightfield,
+        grid: &PlacementGrid,
+    ) -> Vec<ScatterPlacement> {
+        scatter_sequential(&ScatterInputs {
+            layer,
+            modifiers,
+            heightfield,
+            normals: &normals(heightfield),
+            water_level: 0.0,
+            variant_count: layer.variant_count(3),
+            seed: layer.rng_seed(42),
+            grid,
+        })
+    }
+
+    #[test]
+    fn scattering_again_gives_the_same_placements() {
+        let heightfield = hills(0.3);
+        let layer = layer("rocks");
+        let modifiers = ScatterModifiers::default();
+        let grid = PlacementGrid::default();
+        let placements = scatter_layer(&layer, &modifiers, &heightfield, &grid);
+        assert!(!placements.is_empty());
+        assert_eq!(
+            placements,
+            scatter_layer(&layer, &modifiers, &heightfield, &grid)
+        );
+    }
+
+    #[test]
+    fn layer_seeds_only_depend_on_the_name_and_the_seed() {
+        let rocks = layer("rocks");
+        let denser_rocks = ScatterLayer {
+            density: 0.9,
+            ..layer("rocks")
+        };
+        assert_eq!(rocks.rng_seed(42), denser_rocks.rng_seed(42));
+        assert_ne!(rocks.rng_seed(42), layer("bushes").rng_seed(42));
+        assert_ne!(rocks.rng_seed(42), rocks.rng_seed(43));
+        // FNV-1a of the name, saved worlds rely on it not changing
+        assert_eq!(layer("").rng_seed(0), 0xcbf29ce484222325);
+        let trees = ScatterLayer {
+            asset: ScatterAsset::Trees,
+            layer: GenerationLayer::Trees,
+            ..layer("trees")
+        };
+        assert_eq!(trees.rng_seed(42), 42);
+    }
+
+    #[test]
+    fn a_layer_without_spacing_doesnt_move_the_next_ones() {
+        let heightfield = hills(0.3);
+        let modifiers = ScatterModifiers::default();
+        let rocks = layer("rocks");
+        let alone = scatter_layer(&rocks, &modifiers, &heightfield, &PlacementGrid::default());
+
+        let mut grid = PlacementGrid::default();
+        for placement in scatter_layer(&layer("bushes"), &modifiers, &heightfield, &grid) {
+            grid.insert(placement.translation);
+        }
+        assert_eq!(
+            alone,
+            scatter_layer(&rocks, &modifiers, &heightfield, &grid)
+        );
+    }
+
+    #[test]
+    fn placements_respect_the_layer_rules() {
+        let heightfield = hills(0.3);
+        let mut grid = PlacementGrid::default();
+        let modifiers = ScatterModifiers::default();
+        let bushes = scatter_layer(&layer("bushes"), &modifiers, &heightfield, &grid);
+        for placement in &bushes {
+            grid.insert(placement.translation);
+        }
+        let rocks = ScatterLayer {
+            spacing: 3.0,
+            ..layer("rocks")
+        };
+        let placements = scatter_layer(&rocks, &modifiers, &heightfield, &grid);
+        assert!(!placements.is_empty());
+        for placement in &placements {
+            assert!(placement.terrain_height >= rocks.water_distance);
+            assert!(placement.steepness <= rocks.max_steepness);
+            assert!(placement.variant < 2);
+            assert!(bushes.iter().all(|bush| bush
+                .translation
+                .xz()
+                .distance(placement.translation.xz())
+                >= rocks.spacing));
+        }
+    }
+
+    #[test]
+    fn rotated_terrains_keep_the_instances_out_of_the_water() {
+        let heightfield = hills(0.7);
+        let layer = ScatterLayer {
+            water_distance: 1.0,
+            ..layer("rocks")
+        };
+        let placements = scatter_layer(
+            &layer,
+            &ScatterModifiers::default(),
+            &heightfield,
+            &PlacementGrid::default(),
+        );
+        assert!(!placements.is_empty());
+        let resolution = heightfield.resolution as u32;
+        for placement in &placements {
+            // The candidate is the vertex the instance is on in world space, whatever the rotation
+            let vertex = heightfield.world_position(
+                (placement.candidate % resolution) as usize,
+                (placement.candidate / resolution) as usize,
+            );
+            assert_eq!(placement.terrain_height, vertex.y);
+            assert!(vertex.xz().distance(placement.translation.xz()) < 0.36);
+            // and the ground under the offset instance is what the shoreline sees
+            let ground = heightfield.height_at(placement.translation.xz()).unwrap();
+            assert!(
+                ground >= layer.water_distance,
+                "{placement:?} is in the water"
+            );
+        }
+    }
+
+    fn modifiers(density: Option<&str>, scale: Option<&str>) -> ScatterModifiers {
+        let parse =
+            |source: Option<&str>| source.map(|source| Expression::parse(source, 42).unwrap());
+        ScatterModifiers {
+            density: parse(density),
+            scale: parse(scale),
+        }
+    }
+
+    #[test]
+    fn height_dependent_densities_keep_the_low_ground_empty() {
+        let heightfield = hills(0.3);
+        let layer = layer("rocks");
+        let placements = scatter_layer(
+            &layer,
+            &modifiers(Some("min(height - 4, 1)"), None),
+            &heightfield,
+            &PlacementGrid::default(),
+        );
+        assert!(!placements.is_empty());
+        assert!(placements
+            .iter()
+            .all(|placement| placement.terrain_height >= 4.0));
+    }
+
+    #[test]
+    fn modifiers_that_arent_finite_reject_the_candidate() {
+        let heightfield = hills(0.3);
+        let layer = layer("rocks");
+        let grid = PlacementGrid::default();
+        let infinite = modifiers(Some("1 / 0"), None);
+        assert!(scatter_layer(&layer, &infinite, &heightfield, &grid).is_empty());
+        let infinite = modifiers(None, Some("1 / 0"));
+        assert!(scatter_layer(&layer, &infinite, &heightfield, &grid).is_empty());
+
+        // Infinite under a height of 3, the other candidates don't move
+        let plain = scatter_layer(&layer, &ScatterModifiers::default(), &heightfield, &grid);
+        let high = modifiers(None, Some("1 / max(height - 3, 0)"));
+        let placements = scatter_layer(&layer, &high, &heightfield, &grid);
+        assert!(!placements.is_empty());
+        let key = |placement: &ScatterPlacement| {
+            (
+                placement.candidate,
+                placement.translation,
+                placement.rotation,
+            )
+        };
+        let expected: Vec<_> = plain
+            .iter()
+            .filter(|placement| placement.terrain_height > 3.0)
+            .map(key)
+            .collect();
+        let placed: Vec<_> = placements.iter().map(key).collect();
+        assert_eq!(placed, expected);
+    }
+
+    #[test]
+    fn large_modifiers_are_clamped() {
+        let heightfield = hills(0.3);
+        let layer = layer("rocks");
+        let placements = scatter_layer(
+            &layer,
+            &modifiers(Some("10 ^ 30"), Some("10 ^ 30")),
+            &heightfield,
+            &PlacementGrid::default(),
+        );
+        assert!(!placements.is_empty());
+        for placement in &placements {
+            let falloff = 1.0 - placement.terrain_height * layer.height_scale_falloff;
+            assert!(placement.scale <= layer.max_scale * falloff * MAX_MODIFIER);
+        }
+    }
+
+    #[test]
+    fn parallel_and_sequential_scatters_give_the_same_placements() {
+        ComputeTaskPool::get_or_init(TaskPool::default);
+        let heightfield = hills(0.3);
+        let normals = normals(&heightfield);
+        let mut grid = PlacementGrid::default();
+        let bushes = scatter_layer(
+            &layer("bushes"),
+            &ScatterModifiers::default(),
+            &heightfield,
+            &grid,
+        );
+        for placement in bushes {
+            grid.insert(placement.translation);
+        }
+        let layer = ScatterLayer {
+            spacing: 2.0,
+            ..layer("rocks")
+        };
+        let modifiers = modifiers(Some("noise(0, 0.05)"), None);
+        let inputs = ScatterInputs {
+            layer: &layer,
+            modifiers: &modifiers,
+            heightfield: &heightfield,
+            normals: &normals,
+            water_level: 0.0,
+            variant_count: 2,
+            seed: layer.rng_seed(42),
+            grid: &grid,
+        };
+        // Several strips, the hills have 65 rows
+        assert!(inputs.strips().count() > 1);
+        let placements = scatter(&inputs);
+        assert!(!placements.is_empty());
+        assert_eq!(placements, scatter_sequential(&inputs));
+    }
+}
@@ -0,0 +1,209 @@
+//! Scripted demos.
+//!
+//! `--scenario <path>` plays a list of timestamped actions once the world is loaded. The times are
+//! in seconds of the virtual clock, pausing the scene pauses the scenario too. `--scenario-loop`
+//! starts it over after the last action, a `Quit` then restarts it instead of closing the app.
+//!
+//! ```ron
+//! (
+//!     actions: [
+//!         (at: 0.0, action: LoadConfig("presets/clear.scn.ron")),
+//!         (at: 0.0, action: PlaceCamera(position: (0.0, 30.0, 60.0), look_at: (0.0, 10.0, 0.0))),
+//!         (at: 0.0, action: PlayCameraPath),
+//!         (at: 30.0, action: LoadConfig("presets/storm.scn.ron")),
+//!         (at: 45.0, action: Screenshot),
+//!         (at: 60.0, action: Quit),
+//!     ],
+//! )
+//! ```
+//!
+//! The configs are scene files in the assets like `scene_config.scn.ron`, with a scene config, a
+//! terrain config or both. A scene config crossfades with its `transition_seconds`. The file is
+//! checked before the window opens, an unknown action or a missing config exits with its line.
+
+use std::path::Path;
+
+use bevy::{
+    app::AppExit, prelude::*, render::view::screenshot::ScreenshotManager, window::PrimaryWindow,
+};
+use serde::Deserialize;
+
+use crate::{
+    camera_controller::CameraController,
+    camera_path::{Flythrough, FLYTHROUGH_FRAMES},
+    heightfield::TerrainHeightfield,
+    photo_mode::{save_screenshot, PhotoMode},
+    scene_debug::ConfigCarrier,
+    split_view::SecondaryCamera,
+    tree_lod::TreeLods,
+};
+
+#[derive(Deserialize, Debug)]
+pub struct Scenario {
+    pub actions: Vec<TimedAction>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TimedAction {
+    /// Seconds of virtual time since the start of the scenario
+    pub at: f32,
+    pub action: ScenarioAction,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub enum ScenarioAction {
+    /// Scene file relative to the assets folder, holding a scene config, a terrain config or both
+    LoadConfig(String),
+    PlaceCamera {
+        position: [f32; 3],
+        look_at: [f32; 3],
+    },
+    /// Flies the path of `camera_path.ron`, or around the terrain when there's none
+    PlayCameraPath,
+    StopCameraPath,
+    /// Like F12 in the photo mode, cropped the same way
+    Screenshot,
+    Quit,
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+        // The position of the error is in the message, like `4:23: Unexpected variant`
+        let mut scenario: Self =
+            ron::from_str(&source).map_err(|err| format!("{}:{err}", path.display()))?;
+
+        for (i, timed) in scenario.actions.iter().enumerate() {
+            if !timed.at.is_finite() || timed.at < 0.0 {
+                return Err(format!(
+                    "{}: the action {i} starts at {}, it needs a time of 0 or more",
+                    path.display(),
+                    timed.at
+                ));
+            }
+            if let ScenarioAction::LoadConfig(config) = &timed.action {
+                if !Path::new("assets").join(config).exists() {
+                    let line = source
+                        .lines()
+                        .position(|line| line.contains(config.as_str()))
+                        .map_or(0, |line| line + 1);
+                    return Err(format!(
+                        "{}:{line}: there's no {config} in the assets",
+                        path.display()
+                    ));
+                }
+            }
+        }
+        // Stable so the actions at the same time run in the order of the file
+        scenario.actions.sort_by(|a, b| a.at.total_cmp(&b.at));
+        Ok(scenario)
+    }
+}
+
+#[derive(Resource)]
+pub struct ScenarioRun {
+    scenario: Scenario,
+    repeat: bool,
+    /// Virtual seconds since the start of the run, `None` until the world is loaded
+    elapsed: Option<f32>,
+    /// Index of the next action to run
+    next: usize,
+}
+
+impl ScenarioRun {
+    pub fn new(scenario: Scenario, repeat: bool) -> Self {
+        Self {
+            scenario,
+            repeat,
+            elapsed: None,
+            next: 0,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn run_scenario(
+    mut commands: Commands,
+    time: Res<Time<Virtual>>,
+    mut run: ResMut<ScenarioRun>,
+    asset_server: Res<AssetServer>,
+    heightfield: Option<Res<TerrainHeightfield>>,
+    tree_lods: Option<Res<TreeLods>>,
+    photo_mode: Res<PhotoMode>,
+    window: Query<Entity, With<PrimaryWindow>>,
+    mut screenshot_manager: ResMut<ScreenshotManager>,
+    mut camera: Query<
+        (&mut Transform, &mut CameraController),
+        (With<Camera3d>, Without<SecondaryCamera>),
+    >,
+    mut exit: EventWriter<AppExit>,
+) {
+    let (Some(heightfield), Some(_)) = (heightfield, tree_lods) else {
+        return;
+    };
+    let elapsed = match run.elapsed {
+        Some(elapsed) => elapsed + time.delta_seconds(),
+        None => {
+            info!("world loaded, playing the scenario");
+            0.0
+        }
+    };
+    run.elapsed = Some(elapsed);
+
+    while let Some(timed) = run.scenario.actions.get(run.next) {
+        if timed.at > elapsed {
+            return;
+        }
+        info!("scenario at {:.1}s: {:?}", timed.at, timed.action);
+        let action = timed.action.clone();
+        run.next += 1;
+        match action {
+            ScenarioAction::LoadConfig(config) => {
+                commands.spawn((
+                    DynamicSceneBundle {
+                        scene: asset_server.load(config),
+                        ..default()
+                    },
+                    ConfigCarrier,
+                ));
+            }
+            ScenarioAction::PlaceCamera { position, look_at } => {
+                if let Ok((mut transform, mut controller)) = camera.get_single_mut() {
+                    *transform = Transform::from_translation(Vec3::from(position))
+                        .looking_at(Vec3::from(look_at), Vec3::Y);
+                    // Picks up the yaw and pitch of the new transform
+                    controller.initialized = false;
+                }
+            }
+            ScenarioAction::PlayCameraPath => commands.insert_resource(Flythrough::new(
+                Flythrough::load_path(&heightfield),
+                FLYTHROUGH_FRAMES,
+            )),
+            ScenarioAction::StopCameraPath => commands.remove_resource::<Flythrough>(),
+            ScenarioAction::Screenshot => {
+                if let Ok(window) = window.get_single() {
+                    save_screenshot(&mut screenshot_manager, window, photo_mode.crop);
+                }
+            }
+            ScenarioAction::Quit if run.repeat => {
+                run.next = run.scenario.actions.len();
+            }
+            ScenarioAction::Quit => {
+                exit.send(AppExit::Success);
+                commands.remove_resource::<ScenarioRun>();
+                return;
+            }
+        }
+    }
+
+    if run.repeat {
+        info!("scenario over, starting it again");
+        commands.remove_resource::<Flythrough>();
+        run.elapsed = Some(0.0);
+        run.next = 0;
+    } else {
+        info!("scenario over");
+        commands.remove_resource::<ScenarioRun>();
+    }
+}
@@ -0,0 +1,244 @@
+//! Debug view listing what's actually spawned in the world.
+//!
+//! F6 toggles the panel and dumps the counts to the log, F7 cycles the selected category which is
+//! highlighted with gizmos and Delete despawns every entity of the selected category. Single trees
+//! can be picked while the panel is open, see the `tree_inspect` module.
+
+use bevy::{prelude::*, render::primitives::Aabb};
+
+use crate::{
+    debug_palette::DebugPalette,
+    terrain::{Terrain, TreeInstance},
+    tree_edit::TreeEditMode,
+    water::{FoamPlane, WaterPlane},
+};
+
+/// Marker for the entities carrying the dynamic scenes used to load the configs
+#[derive(Component)]
+pub struct ConfigCarrier;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EntityCategory {
+    Terrain,
+    Trees,
+    Water,
+    Lights,
+    Cameras,
+    ConfigCarriers,
+}
+
+const CATEGORIES: [EntityCategory; 6] = [
+    EntityCategory::Terrain,
+    EntityCategory::Trees,
+    EntityCategory::Water,
+    EntityCategory::Lights,
+    EntityCategory::Cameras,
+    EntityCategory::ConfigCarriers,
+];
+
+#[derive(Resource, Default)]
+pub struct SceneDebug {
+    pub open: bool,
+    pub selected: Option<EntityCategory>,
+}
+
+type CategoryFilter = Or<(
+    With<Terrain>,
+    With<TreeInstance>,
+    With<WaterPlane>,
+    With<FoamPlane>,
+    With<DirectionalLight>,
+    With<Camera>,
+    With<ConfigCarrier>,
+)>;
+
+type CategoryQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        Has<Terrain>,
+        Has<TreeInstance>,
+        Has<WaterPlane>,
+        Has<FoamPlane>,
+        Has<DirectionalLight>,
+        Has<Camera>,
+        Has<ConfigCarrier>,
+    ),
+    CategoryFilter,
+>;
+
+fn categorized(query: &CategoryQuery) -> impl Iterator<Item = (Entity, EntityCategory)> + '_ {
+    query.iter().filter_map(
+        |(entity, terrain, tree, water, foam, light, camera, carrier)| {
+            let category = if terrain {
+                EntityCategory::Terrain
+            } else if tree {
+                EntityCategory::Trees
+            } else if water || foam {
+                EntityCategory::Water
+            } else if light {
+                EntityCategory::Lights
+            } else if camera {
+                EntityCategory::Cameras
+            } else if carrier {
+                EntityCategory::ConfigCarriers
+            } else {
+                return None;
+            };
+            Some((entity, category))
+        },
+    )
+}
+
+#[derive(Component)]
+pub struct SceneDebugText;
+
+pub fn spawn_scene_debug_panel(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 16.0,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(5.0),
+            right: Val::Px(5.0),
+            ..default()
+        })
+        .with_background_color(Color::srgba(0.0, 0.0, 0.0, 0.6)),
+        Visibility::Hidden,
+        SceneDebugText,
+    ));
+}
+
+pub fn scene_debug_input(
+    mut commands: Commands,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut scene_debug: ResMut<SceneDebug>,
+    tree_edit_mode: Res<TreeEditMode>,
+    entities: CategoryQuery,
+    mut panel: Query<&mut Visibility, With<SceneDebugText>>,
+) {
+    if keyboard.just_pressed(KeyCode::F6) {
+        scene_debug.open = !scene_debug.open;
+        if !scene_debug.open {
+            scene_debug.selected = None;
+        }
+        for mut visibility in &mut panel {
+            *visibility = if scene_debug.open {
+                Visibility::Inherited
+            } else {
+                Visibility::Hidden
+            };
+        }
+        if scene_debug.open {
+            info!("{}", describe(&entities));
+        }
+    }
+    if !scene_debug.open {
+        return;
+    }
+
+    if keyboard.just_pressed(KeyCode::F7) {
+        scene_debug.selected = match scene_debug.selected {
+            None => Some(CATEGORIES[0]),
+            Some(selected) => {
+                let index = CATEGORIES.iter().position(|c| *c == selected).unwrap_or(0);
+                CATEGORIES.get(index + 1).copied()
+            }
+        };
+    }
+
+    // Delete removes the hovered tree in the tree edit mode
+    if keyboard.just_pressed(KeyCode::Delete) && !tree_edit_mode.active {
+        if let Some(selected) = scene_debug.selected {
+            let mut count = 0;
+            for (entity, category) in categorized(&entities) {
+                if category == selected {
+                    commands.entity(entity).despawn_recursive();
+                    count += 1;
+                }
+            }
+            info!("despawned {count} {selected:?} entities");
+        }
+    }
+}
+
+fn describe(entities: &CategoryQuery) -> String {
+    let mut counts = [0; CATEGORIES.len()];
+    for (_, category) in categorized(entities) {
+        let index = CATEGORIES.iter().position(|c| *c == category).unwrap();
+        counts[index] += 1;
+    }
+    CATEGORIES
+        .iter()
+        .zip(counts)
+        .map(|(category, count)| format!("{category:?}: {count}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn update_scene_debug_panel(
+    scene_debug: Res<SceneDebug>,
+    entities: CategoryQuery,
+    trees: Query<&TreeInstance>,
+    mut panel: Query<&mut Text, With<SceneDebugText>>,
+) {
+    if !scene_debug.open {
+        return;
+    }
+
+    let mut trees_per_variant = Vec::<usize>::new();
+    for tree in &trees {
+        if trees_per_variant.len() <= tree.variant {
+            trees_per_variant.resize(tree.variant + 1, 0);
+        }
+        trees_per_variant[tree.variant] += 1;
+    }
+
+    let mut value = describe(&entities);
+    for (variant, count) in trees_per_variant.iter().enumerate() {
+        value.push_str(&format!("\n  tree variant {variant}: {count}"));
+    }
+    value.push_str(&format!(
+        "\nselected (F7): {}",
+        scene_debug
+            .selected
+            .map_or("none".to_string(), |c| format!("{c:?}"))
+    ));
+
+    for mut text in &mut panel {
+        text.sections[0].value.clone_from(&value);
+    }
+}
+
+/// Draws the bounds of every mesh in the selected category
+pub fn highlight_selected_category(
+    scene_debug: Res<SceneDebug>,
+    entities: CategoryQuery,
+    children: Query<&Children>,
+    bounds: Query<(&Aabb, &GlobalTransform)>,
+    palette: Res<DebugPalette>,
+    mut gizmos: Gizmos,
+) {
+    let Some(selected) = scene_debug.selected else {
+        return;
+    };
+    for (entity, category) in categorized(&entities) {
+        if category != selected {
+            continue;
+        }
+        for entity in std::iter::once(entity).chain(children.iter_descendants(entity)) {
+            let Ok((aabb, transform)) = bounds.get(entity) else {
+                continue;
+            };
+            let local = Transform::from_translation(aabb.center.into())
+                .with_scale((aabb.half_extents * 2.0).into());
+            gizmos.cuboid(transform.mul_transform(local), palette.bounds);
+        }
+    }
+}
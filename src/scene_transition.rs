@@ -0,0 +1,144 @@
+//! Crossfading between scene configs.
+//!
+//! When a new [`SceneConfig`] arrives, from a reload or a preset, with a `transition_seconds`
+//! above 0, it isn't applied at once. Every frame of the transition writes a blend of the previous
+//! and the new config to the resource, so the blends go through the usual change detection and
+//! `on_scene_config_loaded` like any other config. The numbers and colors are interpolated, the
+//! rest (the tonemapping, the toggles, the counts) switches at the midpoint. A config arriving
+//! during a transition starts a new one from the current blend.
+
+use bevy::{
+    color::Mix,
+    ecs::component::Tick,
+    prelude::*,
+    reflect::{ReflectMut, ReflectRef},
+};
+
+use crate::SceneConfig;
+
+#[derive(Resource, Default)]
+pub struct SceneConfigTransition {
+    /// The config in the resource, what the next transition starts from
+    applied: Option<SceneConfig>,
+    from: Option<SceneConfig>,
+    target: Option<SceneConfig>,
+    elapsed: f32,
+    /// Change tick of the last blend written to the resource
+    written: Option<Tick>,
+}
+
+impl SceneConfigTransition {
+    /// Whether the config last changed at `tick` is a blend written by the transition
+    pub fn wrote(&self, tick: Tick) -> bool {
+        self.written == Some(tick)
+    }
+}
+
+/// Runs after the validation so the transitions only ever start from and go to valid configs
+pub fn crossfade_scene_config(
+    time: Res<Time<Real>>,
+    mut scene_config: ResMut<SceneConfig>,
+    mut transition: ResMut<SceneConfigTransition>,
+) {
+    // The blends written here don't count as changes the next time this runs
+    if scene_config.is_changed() {
+        let target = scene_config.clone();
+        match transition.applied.take() {
+            Some(current) if target.transition_seconds > 0.0 => {
+                transition.from = Some(current);
+                transition.target = Some(target);
+                transition.elapsed = 0.0;
+            }
+            // The first config and the configs without a transition are applied at once
+            _ => {
+                transition.from = None;
+                transition.target = None;
+                transition.applied = Some(target);
+                return;
+            }
+        }
+    }
+
+    let (Some(from), Some(target)) = (&transition.from, &transition.target) else {
+        return;
+    };
+    let t = (transition.elapsed + time.delta_seconds()) / target.transition_seconds;
+    let value = if t >= 1.0 {
+        target.clone()
+    } else {
+        blend(from, target, t)
+    };
+    if t >= 1.0 {
+        transition.from = None;
+        transition.target = None;
+    }
+    transition.elapsed += time.delta_seconds();
+    *scene_config = value.clone();
+    transition.applied = Some(value);
+    transition.written = Some(scene_config.last_changed());
+}
+
+fn blend(from: &SceneConfig, to: &SceneConfig, t: f32) -> SceneConfig {
+    let mut blended = if t < 0.5 { from.clone() } else { to.clone() };
+    blend_fields(
+        blended.as_reflect_mut(),
+        from.as_reflect(),
+        to.as_reflect(),
+        t,
+    );
+    blended
+}
+
+/// Overwrites the numbers and colors of `out` with the interpolation of `from` and `to`, walking
+/// the nested structs so new config sections are blended without having to list them here
+fn blend_fields(out: &mut dyn Reflect, from: &dyn Reflect, to: &dyn Reflect, t: f32) {
+    if let (Some(a), Some(b)) = (from.downcast_ref::<f32>(), to.downcast_ref::<f32>()) {
+        if let Some(out) = out.downcast_mut::<f32>() {
+            *out = a + (b - a) * t;
+        }
+        return;
+    }
+    if let (Some(a), Some(b)) = (from.downcast_ref::<Color>(), to.downcast_ref::<Color>()) {
+        if let Some(out) = out.downcast_mut::<Color>() {
+            *out = LinearRgba::from(*a).mix(&LinearRgba::from(*b), t).into();
+        }
+        return;
+    }
+    match (out.reflect_mut(), from.reflect_ref(), to.reflect_ref()) {
+        (ReflectMut::Struct(out), ReflectRef::Struct(from), ReflectRef::Struct(to)) => {
+            for i in 0..out.field_len() {
+                if let (Some(out), Some(from), Some(to)) =
+                    (out.field_at_mut(i), from.field_at(i), to.field_at(i))
+                {
+                    blend_fields(out, from, to, t);
+                }
+            }
+        }
+        (
+            ReflectMut::TupleStruct(out),
+            ReflectRef::TupleStruct(from),
+            ReflectRef::TupleStruct(to),
+        ) => {
+            for i in 0..out.field_len() {
+                if let (Some(out), Some(from), Some(to)) =
+                    (out.field_mut(i), from.field(i), to.field(i))
+                {
+                    blend_fields(out, from, to, t);
+                }
+            }
+        }
+        // Only the same variants can be blended, like the kelvin of `Some(kelvin)`
+        (ReflectMut::Enum(out), ReflectRef::Enum(from), ReflectRef::Enum(to))
+            if from.variant_name() == to.variant_name() =>
+        {
+            for i in 0..out.field_len() {
+                if let (Some(out), Some(from), Some(to)) =
+                    (out.field_at_mut(i), from.field_at(i), to.field_at(i))
+                {
+                    blend_fields(out, from, to, t);
+                }
+            }
+        }
+        _ => {}
+    }
+}
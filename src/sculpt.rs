@@ -0,0 +1,260 @@
+//! Terrain sculpting brush.
+//!
+//! Edits are stored as a sparse layer of height deltas on top of the generated terrain so they can
+//! be re-applied after a regeneration with the same seed and saved next to the terrain config.
+
+use std::collections::BTreeMap;
+
+use bevy::{
+    prelude::*,
+    render::{mesh::VertexAttributeValues, primitives::Aabb},
+    tasks::IoTaskPool,
+    window::PrimaryWindow,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    camera_controller::CameraController,
+    heightfield::TerrainHeightfield,
+    measure::MeasureMode,
+    overlay::StatsOverlay,
+    plane::recompute_normals_and_tangents,
+    split_view::SecondaryCamera,
+    terrain::{TerrainConfig, TreeInstance, TreePlacements},
+    terrain_chunks::TerrainChunk,
+};
+
+pub const TERRAIN_EDITS_PATH: &str = "assets/terrain_edits.ron";
+
+/// Sparse height deltas applied on top of the generated terrain
+#[derive(Resource, Serialize, Deserialize, Default, Clone, Debug)]
+pub struct TerrainEdits {
+    /// The edits only make sense for the terrain they were made on
+    pub seed: u32,
+    pub half_size: u32,
+    /// Height delta for each edited grid vertex
+    pub deltas: BTreeMap<(u32, u32), f32>,
+}
+
+impl TerrainEdits {
+    pub fn matches(&self, terrain_config: &TerrainConfig) -> bool {
+        self.seed == terrain_config.seed && self.half_size == terrain_config.half_size
+    }
+
+    /// Loads the edits saved next to the terrain config, if any
+    pub fn load() -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            if let Ok(file) = std::fs::read_to_string(TERRAIN_EDITS_PATH) {
+                match ron::from_str(&file) {
+                    Ok(edits) => return edits,
+                    Err(err) => warn!("failed to read {TERRAIN_EDITS_PATH}: {err}"),
+                }
+            }
+        }
+        Self::default()
+    }
+
+    fn save(&self) {
+        let Ok(serialized) = ron::ser::to_string_pretty(self, default()) else {
+            error!("failed to serialize terrain edits");
+            return;
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        IoTaskPool::get()
+            .spawn(async move {
+                if let Err(err) = std::fs::write(TERRAIN_EDITS_PATH, serialized) {
+                    error!("failed to write {TERRAIN_EDITS_PATH}: {err}");
+                }
+            })
+            .detach();
+    }
+}
+
+#[derive(Resource)]
+pub struct SculptMode {
+    pub active: bool,
+    pub radius: f32,
+    /// Height change per second at the center of the brush
+    pub strength: f32,
+}
+
+impl Default for SculptMode {
+    fn default() -> Self {
+        Self {
+            active: false,
+            radius: 5.0,
+            strength: 2.0,
+        }
+    }
+}
+
+pub fn toggle_sculpt_mode(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut sculpt_mode: ResMut<SculptMode>,
+    mut controller: Query<&mut CameraController>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    if keyboard.just_pressed(KeyCode::KeyB) {
+        sculpt_mode.active = !sculpt_mode.active;
+        // The right mouse button is needed by the brush so move the cursor grab to the middle one
+        for mut controller in &mut controller {
+            controller.mouse_key_cursor_grab = if sculpt_mode.active {
+                MouseButton::Middle
+            } else {
+                CameraController::default().mouse_key_cursor_grab
+            };
+        }
+    }
+    if !sculpt_mode.active {
+        stats.remove("Sculpt");
+        return;
+    }
+
+    let shift = keyboard.pressed(KeyCode::ShiftLeft);
+    if keyboard.just_pressed(KeyCode::BracketRight) {
+        if shift {
+            sculpt_mode.strength *= 1.25;
+        } else {
+            sculpt_mode.radius *= 1.25;
+        }
+    }
+    if keyboard.just_pressed(KeyCode::BracketLeft) {
+        if shift {
+            sculpt_mode.strength /= 1.25;
+        } else {
+            sculpt_mode.radius = (sculpt_mode.radius / 1.25).max(0.5);
+        }
+    }
+    stats.set(
+        "Sculpt",
+        format!(
+            "radius {:.1} strength {:.1}",
+            sculpt_mode.radius, sculpt_mode.strength
+        ),
+    );
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn sculpt_terrain(
+    mut commands: Commands,
+    time: Res<Time<Real>>,
+    sculpt_mode: Res<SculptMode>,
+    measure_mode: Res<MeasureMode>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    terrain_config: Res<TerrainConfig>,
+    mut heightfield: ResMut<TerrainHeightfield>,
+    mut edits: ResMut<TerrainEdits>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut tree_placements: ResMut<TreePlacements>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform), (With<Camera3d>, Without<SecondaryCamera>)>,
+    mut terrain: Query<(&Handle<Mesh>, &TerrainChunk, Option<&mut Aabb>)>,
+    mut trees: Query<(Entity, &mut Transform), With<TreeInstance>>,
+) {
+    if !sculpt_mode.active || measure_mode.active {
+        return;
+    }
+    if mouse.just_released(MouseButton::Left) || mouse.just_released(MouseButton::Right) {
+        edits.save();
+    }
+    let direction = match (
+        mouse.pressed(MouseButton::Left),
+        mouse.pressed(MouseButton::Right),
+    ) {
+        (true, false) => 1.0,
+        (false, true) => -1.0,
+        _ => return,
+    };
+
+    let Some(cursor) = window.get_single().ok().and_then(|w| w.cursor_position()) else {
+        return;
+    };
+    let Ok((camera, camera_transform)) = camera.get_single() else {
+        return;
+    };
+    let Some(ray) = camera.viewport_to_world(camera_transform, cursor) else {
+        return;
+    };
+    let Some(hit) = heightfield.raycast(ray, 1000.0) else {
+        return;
+    };
+
+    if !edits.matches(&terrain_config) {
+        // Edits from another terrain can't be mixed with the new ones
+        *edits = TerrainEdits {
+            seed: terrain_config.seed,
+            half_size: terrain_config.half_size,
+            deltas: default(),
+        };
+    }
+
+    let amount = direction * sculpt_mode.strength * time.delta_seconds();
+    let vertices = heightfield.vertices_in_radius(hit.xz(), sculpt_mode.radius);
+    for &(x, z, distance) in &vertices {
+        let falloff = (1.0 - (distance / sculpt_mode.radius).powi(2)).powi(2);
+        let delta = amount * falloff;
+        let height = heightfield.height(x, z) + delta;
+        heightfield.set_height(x, z, height);
+        *edits.deltas.entry((x as u32, z as u32)).or_default() += delta;
+    }
+
+    for (handle, chunk, aabb) in &mut terrain {
+        // Only the chunks under the brush are uploaded again
+        let modified: Vec<_> = vertices
+            .iter()
+            .filter_map(|&(x, z, _)| Some((chunk.local_index(x, z)?, heightfield.height(x, z))))
+            .collect();
+        if modified.is_empty() {
+            continue;
+        }
+        let Some(mesh) = meshes.get_mut(handle) else {
+            continue;
+        };
+        let Some(positions) = mesh
+            .attribute_mut(Mesh::ATTRIBUTE_POSITION)
+            .and_then(|a| match a {
+                VertexAttributeValues::Float32x3(p) => Some(p),
+                _ => None,
+            })
+        else {
+            continue;
+        };
+        for &(index, height) in &modified {
+            positions[index][1] = height;
+        }
+        let modified: Vec<_> = modified.into_iter().map(|(index, _)| index).collect();
+        // Only around the brush, a full recompute hitches on the large terrains
+        recompute_normals_and_tangents(mesh, chunk.size(), &modified);
+        // The bounds aren't computed again when the mesh changes
+        if let (Some(mut aabb), Some(bounds)) = (aabb, mesh.compute_aabb()) {
+            *aabb = bounds;
+        }
+    }
+
+    // Trees under the brush need to follow the ground or go away if they're not valid anymore
+    let tree_layer = terrain_config.tree_layer();
+    for (entity, mut transform) in &mut trees {
+        let position = transform.translation.xz();
+        if position.distance(hit.xz()) > sculpt_mode.radius {
+            continue;
+        }
+        let (Some(height), Some(steepness)) = (
+            heightfield.height_at(position),
+            heightfield.steepness_at(position),
+        ) else {
+            continue;
+        };
+        if height < terrain_config.water_level + tree_layer.water_distance
+            || steepness > tree_layer.max_steepness
+        {
+            // Otherwise the map export and the occlusion clusters still see it
+            tree_placements
+                .trees
+                .retain(|tree| tree.position.xz().distance(position) > 0.01);
+            commands.entity(entity).despawn_recursive();
+        } else {
+            transform.translation.y = height - 0.025;
+        }
+    }
+}
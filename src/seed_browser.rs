@@ -0,0 +1,316 @@
+//! Browsing the seeds as a grid of thumbnails.
+//!
+//! K opens a page of [`PAGE_SIZE`] thumbnails of the seeds from the current one onward, Page Down
+//! and Page Up move to the next and previous pages. Every thumbnail is a coarse heightfield of its
+//! seed with the rest of the current terrain config, rasterized like the exported map on the async
+//! compute pool. There's no mesh and no entity besides the UI so a page is ready in a fraction of
+//! a second. The tree coverage comes from the rules of the tree layer, the trees aren't placed.
+//!
+//! Clicking a thumbnail sets the seed of the terrain config, which generates that world for real,
+//! and closes the browser.
+
+use std::time::Duration;
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+    },
+    tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task},
+    utils::Instant,
+};
+use image::RgbaImage;
+use noise::{Fbm, MultiFractal, Simplex};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    heightfield::TerrainHeightfield,
+    map_export::rasterize_map,
+    overlay::StatsOverlay,
+    terrain::{self, TerrainConfig},
+};
+
+const PAGE_SIZE: u32 = 16;
+const COLUMNS: u16 = 4;
+/// Vertices on each side of the heightfield of a thumbnail
+const THUMBNAIL_SAMPLES: usize = 65;
+const THUMBNAIL_RESOLUTION: u32 = 128;
+/// Shown until the thumbnail is done
+const PLACEHOLDER_COLOR: Color = Color::srgb(0.15, 0.15, 0.15);
+
+#[derive(Resource, Default)]
+pub struct SeedBrowser {
+    open: bool,
+    /// Seed of the first thumbnail of the page
+    first_seed: u32,
+    /// The thumbnails still being rasterized, by index in the page
+    tasks: Vec<(u32, Task<RgbaImage>)>,
+    /// When the page was requested, for the stats overlay
+    started: Option<Instant>,
+}
+
+#[derive(Component)]
+pub struct SeedBrowserPanel;
+
+#[derive(Component)]
+pub struct SeedThumbnail {
+    /// Position in the page
+    index: u32,
+}
+
+#[derive(Component)]
+pub struct SeedThumbnailLabel;
+
+pub fn spawn_seed_browser(mut commands: Commands) {
+    let grid = NodeBundle {
+        style: Style {
+            display: Display::Grid,
+            grid_template_columns: RepeatedGridTrack::px(COLUMNS, THUMBNAIL_RESOLUTION as f32),
+            column_gap: Val::Px(4.0),
+            row_gap: Val::Px(4.0),
+            padding: UiRect::all(Val::Px(4.0)),
+            ..default()
+        },
+        background_color: Color::srgba(0.0, 0.0, 0.0, 0.8).into(),
+        ..default()
+    };
+    commands
+        .spawn((
+            // Only there to center the grid
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                visibility: Visibility::Hidden,
+                ..default()
+            },
+            SeedBrowserPanel,
+        ))
+        .with_children(|panel| {
+            panel.spawn(grid).with_children(|grid| {
+                for index in 0..PAGE_SIZE {
+                    grid.spawn((
+                        ButtonBundle {
+                            style: Style {
+                                width: Val::Px(THUMBNAIL_RESOLUTION as f32),
+                                height: Val::Px(THUMBNAIL_RESOLUTION as f32),
+                                align_items: AlignItems::FlexEnd,
+                                ..default()
+                            },
+                            image: UiImage::default().with_color(PLACEHOLDER_COLOR),
+                            ..default()
+                        },
+                        SeedThumbnail { index },
+                    ))
+                    .with_children(|thumbnail| {
+                        thumbnail.spawn((
+                            TextBundle::from_section(
+                                "",
+                                TextStyle {
+                                    font_size: 14.0,
+                                    ..default()
+                                },
+                            )
+                            .with_background_color(Color::srgba(0.0, 0.0, 0.0, 0.6)),
+                            SeedThumbnailLabel,
+                        ));
+                    });
+                }
+            });
+        });
+}
+
+/// K opens and closes the browser, Page Down and Page Up change the page
+pub fn seed_browser_input(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    terrain_config: Res<TerrainConfig>,
+    mut browser: ResMut<SeedBrowser>,
+    mut panel: Query<&mut Visibility, With<SeedBrowserPanel>>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    let first_seed = if keyboard.just_pressed(KeyCode::KeyK) {
+        browser.open = !browser.open;
+        for mut visibility in &mut panel {
+            *visibility = if browser.open {
+                Visibility::Inherited
+            } else {
+                Visibility::Hidden
+            };
+        }
+        if !browser.open {
+            // The pending thumbnails are cancelled when dropped
+            browser.tasks.clear();
+            stats.remove("Seed browser");
+            return;
+        }
+        terrain_config.seed
+    } else if browser.open && keyboard.just_pressed(KeyCode::PageDown) {
+        browser.first_seed.wrapping_add(PAGE_SIZE)
+    } else if browser.open && keyboard.just_pressed(KeyCode::PageUp) {
+        browser.first_seed.wrapping_sub(PAGE_SIZE)
+    } else {
+        return;
+    };
+
+    browser.first_seed = first_seed;
+    browser.started = Some(Instant::now());
+    let task_pool = AsyncComputeTaskPool::get();
+    browser.tasks = (0..PAGE_SIZE)
+        .map(|index| {
+            let config = terrain_config.clone();
+            let seed = first_seed.wrapping_add(index);
+            (
+                index,
+                task_pool.spawn(async move { thumbnail(&config, seed) }),
+            )
+        })
+        .collect();
+}
+
+/// Shows the thumbnails as they're done
+pub fn update_seed_thumbnails(
+    mut browser: ResMut<SeedBrowser>,
+    mut images: ResMut<Assets<Image>>,
+    mut thumbnails: Query<(&SeedThumbnail, &mut UiImage, &Children)>,
+    mut labels: Query<&mut Text, With<SeedThumbnailLabel>>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    if browser.is_changed() {
+        // A new page, the previous thumbnails are cleared until the new ones are done
+        for (thumbnail, mut image, children) in &mut thumbnails {
+            image.texture = Handle::default();
+            image.color = PLACEHOLDER_COLOR;
+            let seed = browser.first_seed.wrapping_add(thumbnail.index);
+            for child in children {
+                if let Ok(mut text) = labels.get_mut(*child) {
+                    text.sections[0].value = format!("seed {seed}");
+                }
+            }
+        }
+    }
+    if browser.tasks.is_empty() {
+        return;
+    }
+
+    let (finished, pending) = std::mem::take(&mut browser.bypass_change_detection().tasks)
+        .into_iter()
+        .partition::<Vec<_>, _>(|(_, task)| task.is_finished());
+    browser.bypass_change_detection().tasks = pending;
+    for (index, task) in finished {
+        let Some(map) = block_on(future::poll_once(task)) else {
+            continue;
+        };
+        let image = images.add(Image::new(
+            Extent3d {
+                width: map.width(),
+                height: map.height(),
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            map.into_raw(),
+            TextureFormat::Rgba8UnormSrgb,
+            RenderAssetUsages::RENDER_WORLD,
+        ));
+        for (thumbnail, mut ui_image, _) in &mut thumbnails {
+            if thumbnail.index == index {
+                ui_image.texture = image.clone();
+                ui_image.color = Color::WHITE;
+            }
+        }
+    }
+
+    if browser.tasks.is_empty() {
+        let elapsed = browser
+            .started
+            .map_or(Duration::ZERO, |started| started.elapsed());
+        stats.set(
+            "Seed browser",
+            format!(
+                "seeds {} to {} in {:.0}ms, click to generate, Page Up/Down for more",
+                browser.first_seed,
+                browser.first_seed.wrapping_add(PAGE_SIZE - 1),
+                elapsed.as_secs_f32() * 1000.0
+            ),
+        );
+    }
+}
+
+/// Clicking a thumbnail generates its seed
+pub fn pick_seed(
+    mut browser: ResMut<SeedBrowser>,
+    mut terrain_config: ResMut<TerrainConfig>,
+    thumbnails: Query<(&SeedThumbnail, &Interaction), Changed<Interaction>>,
+    mut panel: Query<&mut Visibility, With<SeedBrowserPanel>>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    if !browser.open {
+        return;
+    }
+    let Some((thumbnail, _)) = thumbnails
+        .iter()
+        .find(|(_, interaction)| **interaction == Interaction::Pressed)
+    else {
+        return;
+    };
+    let seed = browser.first_seed.wrapping_add(thumbnail.index);
+    info!("generating seed {seed} picked in the seed browser");
+    terrain_config.seed = seed;
+    browser.open = false;
+    browser.tasks.clear();
+    for mut visibility in &mut panel {
+        *visibility = Visibility::Hidden;
+    }
+    stats.remove("Seed browser");
+}
+
+/// Map of `seed` with the rest of `config`, from a heightfield of [`THUMBNAIL_SAMPLES`] vertices
+/// on each side
+fn thumbnail(config: &TerrainConfig, seed: u32) -> RgbaImage {
+    let fbm = Fbm::<Simplex>::new(seed)
+        .set_frequency(config.frequency)
+        .set_octaves(config.octaves);
+    let size = config.half_size as f32 * 2.0;
+    let spacing = size / (THUMBNAIL_SAMPLES - 1) as f32;
+    let positions: Vec<[f32; 3]> = (0..THUMBNAIL_SAMPLES)
+        .flat_map(|z| (0..THUMBNAIL_SAMPLES).map(move |x| (x, z)))
+        .map(|(x, z)| {
+            let local = Vec2::new(x as f32, z as f32) * spacing - size * 0.5;
+            let height = terrain::get_terrain_height(&fbm, local);
+            let height = config
+                .edge
+                .height(local, height, size * 0.5, config.water_level);
+            [local.x, height, local.y]
+        })
+        .collect();
+    let heightfield = TerrainHeightfield::from_positions(size, config.rotation, &positions);
+
+    // A cell of the thumbnail holds many tree candidates, it's covered as soon as one is placed
+    let layer = config.tree_layer();
+    let coverage = (layer.density * spacing * spacing).min(1.0);
+    let mut rng = StdRng::seed_from_u64(seed as u64);
+    let trees: Vec<Vec3> = (0..THUMBNAIL_SAMPLES)
+        .flat_map(|z| (0..THUMBNAIL_SAMPLES).map(move |x| (x, z)))
+        .map(|(x, z)| heightfield.world_position(x, z))
+        .filter(|position| {
+            position.y >= config.water_level + layer.water_distance
+                && heightfield
+                    .steepness_at(position.xz())
+                    .is_some_and(|steepness| steepness <= layer.max_steepness)
+                && rng.gen_range(0.0..1.0) < coverage
+        })
+        .collect();
+
+    rasterize_map(
+        &heightfield,
+        config.water_level,
+        &trees,
+        None,
+        THUMBNAIL_RESOLUTION,
+    )
+}
@@ -0,0 +1,609 @@
+//! Reeds, lily pads and floating debris in the water along the shore.
+//!
+//! The decorations are placed on the heightfield vertices that are a little below the water
+//! level. They are respawned every time the heightfield changes so they follow the terrain
+//! reloads and the sculpting. The reeds sway in the wind in their vertex shader, the lily pads
+//! float on the water surface.
+//!
+//! The debris, needle clusters, leaves and twigs, is thickest within `debris_shore_distance` of
+//! the shore and drifts slowly with the [`Wind`]. It never moves over ground above
+//! the water, a piece pushed against the shore stays there for a while before it's recycled, like
+//! one drifting too far out.
+
+use bevy::{
+    math::vec3,
+    pbr::{ExtendedMaterial, MaterialExtension, NotShadowCaster, OpaqueRendererMethod},
+    prelude::*,
+    render::{
+        mesh::{Indices, PrimitiveTopology},
+        render_asset::RenderAssetUsages,
+        render_resource::{AsBindGroup, ShaderRef, ShaderType},
+    },
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    deterministic::DeterministicClock,
+    heightfield::TerrainHeightfield,
+    terrain::{GeneratedBy, GenerationLayer, InvalidatedLayers, TerrainConfig},
+    water,
+    wind::{Wind, WindMaterial, WindUniform},
+};
+
+/// Mixed with the terrain seed so the shoreline doesn't follow the tree placement
+const SHORELINE_SEED: u64 = 0x5eed_5407_e11e;
+const REED_BLADES: usize = 7;
+/// Keeps the lily pads above the foam plane
+const LILY_PAD_FLOAT: f32 = 0.01;
+/// Above the lily pads so the debris drifting over them doesn't flicker
+const DEBRIS_FLOAT: f32 = 0.012;
+/// Water depth under which the debris would run aground
+const DEBRIS_MIN_DEPTH: f32 = 0.05;
+/// Pieces further than this many `debris_shore_distance` from the shore are recycled
+const DEBRIS_RECYCLE_DISTANCE: f32 = 3.0;
+/// How long a piece pushed against the shore stays before it's recycled
+const DEBRIS_STRANDED_SECONDS: f32 = 30.0;
+
+#[derive(Reflect, Clone, Debug, PartialEq)]
+pub struct ShorelineConfig {
+    /// Probability that a vertex in the reed band gets a clump, 0 disables the reeds
+    pub reed_density: f32,
+    /// Water depth range where the reeds grow
+    pub reed_min_depth: f32,
+    pub reed_max_depth: f32,
+    /// Probability that a vertex in the lily pad band gets a pad, 0 disables the lily pads
+    pub lily_pad_density: f32,
+    /// Water depth range where the lily pads float
+    pub lily_pad_min_depth: f32,
+    pub lily_pad_max_depth: f32,
+    /// Number of floating needle clusters, leaves and twigs, 0 disables them
+    pub debris_count: u32,
+    /// Distance from the shore within which the debris is the densest, it thins out beyond
+    pub debris_shore_distance: f32,
+    /// Drift speed of the debris with the wind, in meters per second
+    pub debris_drift_speed: f32,
+}
+
+impl Default for ShorelineConfig {
+    fn default() -> Self {
+        Self {
+            reed_density: 0.3,
+            reed_min_depth: 0.0,
+            reed_max_depth: 0.4,
+            lily_pad_density: 0.1,
+            lily_pad_min_depth: 0.3,
+            lily_pad_max_depth: 1.5,
+            debris_count: 400,
+            debris_shore_distance: 12.0,
+            debris_drift_speed: 0.1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ShaderType, Debug, Default)]
+pub struct ReedSwaySettings {
+    /// How far the tip of a 1m reed moves
+    pub strength: f32,
+}
+
+/// Reed material bent by the wind in the vertex shader.
+///
+/// It's forward only and stays out of the prepass, the prepass would need the same vertex
+/// displacement.
+pub type ReedMaterial = ExtendedMaterial<StandardMaterial, ReedSway>;
+
+#[derive(Asset, TypePath, AsBindGroup, Clone, Default)]
+pub struct ReedSway {
+    #[uniform(100)]
+    pub settings: ReedSwaySettings,
+    #[uniform(120)]
+    pub wind: WindUniform,
+}
+
+impl WindMaterial for ReedMaterial {
+    fn wind(&self) -> WindUniform {
+        self.extension.wind
+    }
+
+    fn wind_mut(&mut self) -> &mut WindUniform {
+        &mut self.extension.wind
+    }
+}
+
+impl MaterialExtension for ReedSway {
+    fn vertex_shader() -> ShaderRef {
+        "reeds.wgsl".into()
+    }
+}
+
+#[derive(Component)]
+pub struct LilyPad;
+
+#[derive(Component)]
+pub struct FloatingDebris {
+    /// Fraction of the drift speed, so the pieces don't move as a block
+    drift: f32,
+    /// Radians per second around the vertical axis
+    spin: f32,
+    /// Seconds spent pushed against the shore
+    stranded: f32,
+}
+
+/// Where the debris can float, kept to recycle the pieces after the spawn
+#[derive(Resource)]
+pub struct DebrisField {
+    /// Distance of every heightfield vertex to the nearest one above the water
+    shore_distances: Vec<f32>,
+    /// Water vertices with the running sum of their spawn weights
+    candidates: Vec<(Vec2, f32)>,
+    rng: StdRng,
+}
+
+impl DebrisField {
+    fn new(
+        heightfield: &TerrainHeightfield,
+        water_level: f32,
+        shore_distance: f32,
+        seed: u64,
+    ) -> Self {
+        let shore_distances = shore_distances(heightfield, water_level);
+        let mut candidates = vec![];
+        let mut total = 0.0;
+        for z in 0..heightfield.resolution {
+            for x in 0..heightfield.resolution {
+                let position = heightfield.world_position(x, z);
+                if water_level - position.y < DEBRIS_MIN_DEPTH {
+                    continue;
+                }
+                let distance = shore_distances[z * heightfield.resolution + x];
+                if distance > shore_distance * DEBRIS_RECYCLE_DISTANCE {
+                    continue;
+                }
+                let weight = if distance <= shore_distance {
+                    1.0
+                } else {
+                    (shore_distance / distance).powi(2)
+                };
+                if weight > 0.0 {
+                    total += weight;
+                    candidates.push((position.xz(), total));
+                }
+            }
+        }
+        Self {
+            shore_distances,
+            candidates,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// A random spot on the water, near the shore more often than not
+    fn sample(&mut self, spacing: f32) -> Option<Vec2> {
+        let total = self.candidates.last()?.1;
+        let roll = self.rng.gen_range(0.0..total);
+        let index = self.candidates.partition_point(|(_, sum)| *sum < roll);
+        let (position, _) = self.candidates[index.min(self.candidates.len() - 1)];
+        let jitter = Vec2::new(self.rng.gen_range(-0.5..0.5), self.rng.gen_range(-0.5..0.5));
+        Some(position + jitter * spacing)
+    }
+
+    fn shore_distance(&self, heightfield: &TerrainHeightfield, position: Vec2) -> Option<f32> {
+        let (x, z) = heightfield.nearest_vertex(position)?;
+        Some(self.shore_distances[z * heightfield.resolution + x])
+    }
+}
+
+/// Distance of every vertex to the nearest one above the water, with the two passes of a chamfer
+/// distance transform
+fn shore_distances(heightfield: &TerrainHeightfield, water_level: f32) -> Vec<f32> {
+    let resolution = heightfield.resolution;
+    let straight = heightfield.spacing();
+    let diagonal = straight * std::f32::consts::SQRT_2;
+    let mut distances: Vec<f32> = heightfield
+        .heights
+        .iter()
+        .map(|height| {
+            if *height >= water_level {
+                0.0
+            } else {
+                f32::INFINITY
+            }
+        })
+        .collect();
+    // The neighbours already visited by each pass, the second pass is the first one mirrored
+    let forward = [
+        (-1, 0, straight),
+        (0, -1, straight),
+        (-1, -1, diagonal),
+        (1, -1, diagonal),
+    ];
+    let backward = forward.map(|(dx, dz, cost)| (-dx, -dz, cost));
+    let mut relax = |x: usize, z: usize, neighbours: &[(isize, isize, f32)]| {
+        let mut distance = distances[z * resolution + x];
+        for (dx, dz, cost) in neighbours {
+            let (nx, nz) = (x as isize + dx, z as isize + dz);
+            if nx >= 0 && nz >= 0 && (nx as usize) < resolution && (nz as usize) < resolution {
+                distance = distance.min(distances[nz as usize * resolution + nx as usize] + cost);
+            }
+        }
+        distances[z * resolution + x] = distance;
+    };
+    for z in 0..resolution {
+        for x in 0..resolution {
+            relax(x, z, &forward);
+        }
+    }
+    for z in (0..resolution).rev() {
+        for x in (0..resolution).rev() {
+            relax(x, z, &backward);
+        }
+    }
+    distances
+}
+
+/// A few thin needles fanning out from the same point
+fn needle_cluster_mesh() -> Mesh {
+    let mut positions = vec![];
+    let mut indices = vec![];
+    for needle in 0..5 {
+        let angle = needle as f32 / 5.0 * std::f32::consts::PI;
+        let (sin, cos) = angle.sin_cos();
+        let along = Vec3::new(cos, 0.0, sin) * 0.12;
+        let across = Vec3::new(-sin, 0.0, cos) * 0.004;
+        let start = positions.len() as u32;
+        for corner in [
+            -along - across,
+            -along + across,
+            along + across,
+            along - across,
+        ] {
+            positions.push(corner.to_array());
+        }
+        indices.extend([start, start + 1, start + 2, start, start + 2, start + 3]);
+    }
+    flat_mesh(positions, indices)
+}
+
+/// A pointed oval
+fn leaf_mesh() -> Mesh {
+    let outline = [
+        (0.0, -0.07),
+        (0.03, -0.04),
+        (0.035, 0.0),
+        (0.025, 0.04),
+        (0.0, 0.07),
+        (-0.025, 0.04),
+        (-0.035, 0.0),
+        (-0.03, -0.04),
+    ];
+    let mut positions = vec![[0.0; 3]];
+    positions.extend(outline.map(|(x, z)| [x, 0.0, z]));
+    let count = outline.len() as u32;
+    let indices = (0..count)
+        .flat_map(|i| [0, i + 1, (i + 1) % count + 1])
+        .collect();
+    flat_mesh(positions, indices)
+}
+
+/// A thin stick with a short side branch
+fn twig_mesh() -> Mesh {
+    let quad = |from: Vec3, to: Vec3, width: f32| {
+        let across = (to - from).cross(Vec3::Y).normalize() * width;
+        [from - across, from + across, to + across, to - across].map(Vec3::to_array)
+    };
+    let mut positions = vec![];
+    positions.extend(quad(vec3(-0.15, 0.0, 0.0), vec3(0.15, 0.0, 0.0), 0.008));
+    positions.extend(quad(vec3(0.03, 0.0, 0.0), vec3(0.1, 0.0, 0.05), 0.005));
+    flat_mesh(positions, vec![0, 1, 2, 0, 2, 3, 4, 5, 6, 4, 6, 7])
+}
+
+/// Mesh lying on the XZ plane and facing up, drawn double sided
+fn flat_mesh(positions: Vec<[f32; 3]>, indices: Vec<u32>) -> Mesh {
+    let normals = vec![[0.0, 1.0, 0.0]; positions.len()];
+    let uvs: Vec<[f32; 2]> = positions.iter().map(|p| [p[0], p[2]]).collect();
+    Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::RENDER_WORLD,
+    )
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+    .with_inserted_indices(Indices::U32(indices))
+}
+
+/// A clump of thin tapered blades crossing each other, 1m high
+fn reed_clump_mesh() -> Mesh {
+    let mut positions = vec![];
+    let mut normals = vec![];
+    let mut uvs = vec![];
+    let mut indices = vec![];
+    let mut rng = StdRng::seed_from_u64(SHORELINE_SEED);
+    for blade in 0..REED_BLADES {
+        let angle = blade as f32 / REED_BLADES as f32 * std::f32::consts::PI;
+        let (sin, cos) = angle.sin_cos();
+        let across = vec3(cos, 0.0, sin) * 0.02;
+        let base = vec3(rng.gen_range(-0.1..0.1), 0.0, rng.gen_range(-0.1..0.1));
+        let lean = vec3(rng.gen_range(-0.15..0.15), 0.0, rng.gen_range(-0.15..0.15));
+        let tip = base + lean + Vec3::Y * rng.gen_range(0.7..1.0);
+        let normal = vec3(-sin, 0.0, cos);
+
+        let start = positions.len() as u32;
+        for (position, uv) in [
+            (base - across, [0.0, 1.0]),
+            (base + across, [1.0, 1.0]),
+            (tip, [0.5, 0.0]),
+        ] {
+            positions.push(position.to_array());
+            normals.push(normal.to_array());
+            uvs.push(uv);
+        }
+        indices.extend([start, start + 1, start + 2]);
+    }
+
+    Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::RENDER_WORLD,
+    )
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+    .with_inserted_indices(Indices::U32(indices))
+}
+
+/// Places the reeds and lily pads again every time the ground is sculpted or a terrain reload
+/// invalidates the water layer
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_shoreline(
+    mut commands: Commands,
+    terrain_config: Res<TerrainConfig>,
+    heightfield: Res<TerrainHeightfield>,
+    invalidated: Res<InvalidatedLayers>,
+    previous: Query<(Entity, &GeneratedBy)>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut reed_materials: ResMut<Assets<ReedMaterial>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    if !heightfield.is_changed() && !invalidated.0.contains(&GenerationLayer::Water) {
+        return;
+    }
+    let _span = info_span!("spawn_shoreline").entered();
+    for (entity, GeneratedBy(layer)) in &previous {
+        if *layer == GenerationLayer::Water {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+
+    let config = &terrain_config.shoreline;
+    spawn_debris(
+        &mut commands,
+        &terrain_config,
+        &heightfield,
+        &mut meshes,
+        &mut materials,
+    );
+    if config.reed_density <= 0.0 && config.lily_pad_density <= 0.0 {
+        return;
+    }
+
+    let reed_mesh = meshes.add(reed_clump_mesh());
+    let reed_material = reed_materials.add(ExtendedMaterial {
+        base: StandardMaterial {
+            base_color: Color::srgb(0.35, 0.42, 0.18),
+            perceptual_roughness: 0.8,
+            double_sided: true,
+            cull_mode: None,
+            opaque_render_method: OpaqueRendererMethod::Forward,
+            ..default()
+        },
+        extension: ReedSway {
+            settings: ReedSwaySettings { strength: 0.08 },
+            wind: default(),
+        },
+    });
+    let lily_pad_mesh = meshes.add(Circle::new(0.3).mesh().resolution(12));
+    let lily_pad_material = materials.add(StandardMaterial {
+        base_color: Color::srgb(0.16, 0.35, 0.12),
+        perceptual_roughness: 0.4,
+        double_sided: true,
+        cull_mode: None,
+        ..default()
+    });
+
+    let mut rng = StdRng::seed_from_u64(SHORELINE_SEED ^ terrain_config.seed as u64);
+    let water_level = terrain_config.water_level;
+    let in_band = |depth: f32, min: f32, max: f32| depth >= min && depth <= max;
+    let mut reeds = 0;
+    let mut lily_pads = 0;
+    for z in 0..heightfield.resolution {
+        for x in 0..heightfield.resolution {
+            let position = heightfield.world_position(x, z);
+            let depth = water_level - position.y;
+            if depth < 0.0 {
+                continue;
+            }
+            // Both rolls always happen so changing one density doesn't move the other objects
+            let reed_roll = rng.gen_range(0.0..1.0);
+            let lily_pad_roll = rng.gen_range(0.0..1.0);
+            let offset = vec3(rng.gen_range(-0.4..0.4), 0.0, rng.gen_range(-0.4..0.4));
+            let spin = Quat::from_rotation_y(rng.gen_range(0.0..std::f32::consts::TAU));
+            let scale = rng.gen_range(0.7..1.3);
+
+            if in_band(depth, config.reed_min_depth, config.reed_max_depth)
+                && reed_roll < config.reed_density
+            {
+                reeds += 1;
+                commands.spawn((
+                    MaterialMeshBundle {
+                        mesh: reed_mesh.clone(),
+                        material: reed_material.clone(),
+                        // Rooted under the water and sticking out of it
+                        transform: Transform::from_translation(position + offset)
+                            .with_rotation(spin)
+                            .with_scale(Vec3::splat(scale * (1.0 + depth))),
+                        ..default()
+                    },
+                    NotShadowCaster,
+                    GeneratedBy(GenerationLayer::Water),
+                ));
+            } else if in_band(depth, config.lily_pad_min_depth, config.lily_pad_max_depth)
+                && lily_pad_roll < config.lily_pad_density
+            {
+                lily_pads += 1;
+                let position = position + offset;
+                let height =
+                    water::water_surface_height(&terrain_config, position.xz()) + LILY_PAD_FLOAT;
+                commands.spawn((
+                    PbrBundle {
+                        mesh: lily_pad_mesh.clone(),
+                        material: lily_pad_material.clone(),
+                        transform: Transform::from_xyz(position.x, height, position.z)
+                            .with_rotation(
+                                spin * Quat::from_rotation_x(-std::f32::consts::FRAC_PI_2),
+                            )
+                            .with_scale(Vec3::splat(scale)),
+                        ..default()
+                    },
+                    NotShadowCaster,
+                    LilyPad,
+                    GeneratedBy(GenerationLayer::Water),
+                ));
+            }
+        }
+    }
+    info!("placed {reeds} reed clumps and {lily_pads} lily pads along the shore");
+}
+
+/// Scatters the floating debris, it's despawned with the reeds and lily pads
+fn spawn_debris(
+    commands: &mut Commands,
+    terrain_config: &TerrainConfig,
+    heightfield: &TerrainHeightfield,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+) {
+    let config = &terrain_config.shoreline;
+    if config.debris_count == 0 {
+        commands.remove_resource::<DebrisField>();
+        return;
+    }
+    let mut field = DebrisField::new(
+        heightfield,
+        terrain_config.water_level,
+        config.debris_shore_distance,
+        SHORELINE_SEED.rotate_left(1) ^ terrain_config.seed as u64,
+    );
+    let mut kind = |mesh: Mesh, color: Color| {
+        let material = materials.add(StandardMaterial {
+            base_color: color,
+            perceptual_roughness: 0.9,
+            double_sided: true,
+            cull_mode: None,
+            ..default()
+        });
+        (meshes.add(mesh), material)
+    };
+    let kinds = [
+        kind(needle_cluster_mesh(), Color::srgb(0.36, 0.22, 0.1)),
+        kind(leaf_mesh(), Color::srgb(0.45, 0.34, 0.12)),
+        kind(twig_mesh(), Color::srgb(0.28, 0.22, 0.16)),
+    ];
+
+    let mut spawned = 0;
+    for _ in 0..config.debris_count {
+        let Some(position) = field.sample(heightfield.spacing()) else {
+            break;
+        };
+        let (mesh, material) = kinds[field.rng.gen_range(0..kinds.len())].clone();
+        let height = water::water_surface_height(terrain_config, position) + DEBRIS_FLOAT;
+        let spin = Quat::from_rotation_y(field.rng.gen_range(0.0..std::f32::consts::TAU));
+        let debris = FloatingDebris {
+            drift: field.rng.gen_range(0.5..1.5),
+            spin: field.rng.gen_range(-0.2..0.2),
+            stranded: 0.0,
+        };
+        commands.spawn((
+            PbrBundle {
+                mesh,
+                material,
+                transform: Transform::from_xyz(position.x, height, position.y)
+                    .with_rotation(spin)
+                    .with_scale(Vec3::splat(field.rng.gen_range(0.7..1.3))),
+                ..default()
+            },
+            NotShadowCaster,
+            debris,
+            GeneratedBy(GenerationLayer::Water),
+        ));
+        spawned += 1;
+    }
+    info!("placed {spawned} pieces of floating debris");
+    commands.insert_resource(field);
+}
+
+/// Drifts the debris with the wind along the water surface and recycles the pieces that got too
+/// far from the shore or stayed stranded against it
+pub fn drift_water_debris(
+    time: Res<Time>,
+    clock: Res<DeterministicClock>,
+    wind: Res<Wind>,
+    terrain_config: Res<TerrainConfig>,
+    heightfield: Res<TerrainHeightfield>,
+    mut field: ResMut<DebrisField>,
+    mut debris: Query<(&mut Transform, &mut FloatingDebris)>,
+) {
+    let config = &terrain_config.shoreline;
+    let drift = wind.direction * wind.strength * config.debris_drift_speed;
+    let recycle_distance = config.debris_shore_distance * DEBRIS_RECYCLE_DISTANCE;
+    let afloat = |position: Vec2| {
+        let surface = water::water_surface_height(&terrain_config, position);
+        heightfield
+            .height_at(position)
+            .is_some_and(|ground| surface - ground >= DEBRIS_MIN_DEPTH)
+    };
+
+    for (mut transform, mut piece) in &mut debris {
+        for delta in clock.deltas(time.delta_seconds()) {
+            let position = transform.translation.xz();
+            let next = position + drift * piece.drift * delta;
+            if afloat(next) {
+                transform.translation.x = next.x;
+                transform.translation.z = next.y;
+                piece.stranded = 0.0;
+            } else {
+                piece.stranded += delta;
+            }
+            transform.rotate_y(piece.spin * delta);
+
+            let too_far = field
+                .shore_distance(&heightfield, transform.translation.xz())
+                .map_or(true, |distance| distance > recycle_distance);
+            if too_far || piece.stranded > DEBRIS_STRANDED_SECONDS {
+                if let Some(position) = field.sample(heightfield.spacing()) {
+                    transform.translation.x = position.x;
+                    transform.translation.z = position.y;
+                }
+                piece.stranded = 0.0;
+            }
+        }
+        // Follows the surface if it's ever displaced by the waves
+        let height =
+            water::water_surface_height(&terrain_config, transform.translation.xz()) + DEBRIS_FLOAT;
+        if transform.translation.y != height {
+            transform.translation.y = height;
+        }
+    }
+}
+
+/// Keeps the lily pads on the water surface
+pub fn float_lily_pads(
+    terrain_config: Res<TerrainConfig>,
+    mut lily_pads: Query<&mut Transform, With<LilyPad>>,
+) {
+    for mut transform in &mut lily_pads {
+        let height = water::water_surface_height(&terrain_config, transform.translation.xz())
+            + LILY_PAD_FLOAT;
+        if transform.translation.y != height {
+            transform.translation.y = height;
+        }
+    }
+}
@@ -0,0 +1,406 @@
+//! Procedural sky used when the HDRI isn't wanted or available.
+//!
+//! The sky is an inverted sphere that follows the camera and is pushed to the far plane in the
+//! vertex shader so everything else draws in front of it.
+//!
+//! There's no cubemap of the procedural sky for the environment map light, it's composed on the
+//! CPU from the same settings as the shader, see the `sky_env_map` module. The ambient light stands
+//! in for it until the first one is done.
+//!
+//! The procedural sky also stands in for the HDRI until its cubemap is loaded, the skybox is the
+//! last thing requested at startup.
+//!
+//! At night the procedural sky shows stars turning around the celestial pole and a moon opposite
+//! the sun going through its phases. Both follow the virtual time so pausing it freezes the sky.
+
+use std::f32::consts::TAU;
+
+use bevy::{
+    core_pipeline::Skybox,
+    math::vec4,
+    pbr::{MaterialPipeline, MaterialPipelineKey, NotShadowCaster},
+    prelude::*,
+    render::{
+        mesh::MeshVertexBufferLayoutRef,
+        render_resource::{
+            AsBindGroup, Face, RenderPipelineDescriptor, ShaderRef, ShaderType,
+            SpecializedMeshPipelineError,
+        },
+        view::NoFrustumCulling,
+    },
+};
+
+use crate::{
+    render_layers::SceneLayer, sky_env_map::SkyEnvironmentMap, texture_streaming::TextureStreaming,
+    SceneConfig,
+};
+
+#[derive(Reflect, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Sky {
+    /// Use the cubemap for the skybox and the environment map
+    #[default]
+    Hdri,
+    Procedural {
+        zenith_color: Color,
+        horizon_color: Color,
+        /// Angular radius of the sun disk in degrees
+        sun_size: f32,
+        /// Brightness of the sun disk relative to the sky
+        sun_intensity: f32,
+        night: NightSky,
+    },
+}
+
+impl Sky {
+    /// Shown while the HDRI loads, close to its colors in daylight
+    fn interim() -> Self {
+        Self::Procedural {
+            zenith_color: Color::srgb(0.3, 0.5, 0.8),
+            horizon_color: Color::srgb(0.6, 0.7, 0.86),
+            sun_size: 0.5,
+            sun_intensity: 20.0,
+            night: NightSky::default(),
+        }
+    }
+}
+
+#[derive(Reflect, Clone, Copy, Debug, PartialEq)]
+pub struct NightSky {
+    /// Brightness of the stars relative to the sky, 0 disables them
+    pub star_brightness: f32,
+    /// Direction of the axis the stars turn around
+    pub celestial_pole: Vec3,
+    /// Time it takes for the stars to do a full turn
+    pub day_minutes: f32,
+    /// Days between two full moons
+    pub lunar_cycle_days: f32,
+    /// Angular radius of the moon disk in degrees
+    pub moon_size: f32,
+    /// Brightness of the moon disk relative to the sky
+    pub moon_intensity: f32,
+    /// Illuminance of the moonlight when the moon is full
+    pub moonlight_illuminance: f32,
+}
+
+impl Default for NightSky {
+    fn default() -> Self {
+        Self {
+            star_brightness: 1.0,
+            celestial_pole: Vec3::new(0.0, 0.7, 0.7),
+            day_minutes: 20.0,
+            lunar_cycle_days: 29.5,
+            moon_size: 1.0,
+            moon_intensity: 10.0,
+            moonlight_illuminance: 400.0,
+        }
+    }
+}
+
+impl NightSky {
+    /// Rotation of the stars around the pole in radians
+    fn star_angle(&self, elapsed_seconds: f64) -> f32 {
+        let day = self.day_minutes as f64 * 60.0;
+        (elapsed_seconds / day).fract() as f32 * TAU
+    }
+
+    /// Angle between the sun and the moon as seen from the moon, 0 is a new moon and PI is full
+    fn phase_angle(&self, elapsed_seconds: f64) -> f32 {
+        let cycle = self.lunar_cycle_days as f64 * self.day_minutes as f64 * 60.0;
+        (elapsed_seconds / cycle).fract() as f32 * TAU
+    }
+}
+
+#[derive(Clone, Copy, ShaderType, Debug, Default)]
+pub struct SkySettings {
+    zenith_color: Vec4,
+    horizon_color: Vec4,
+    /// xyz: direction the light travels, w: cosine of the sun radius
+    sun: Vec4,
+    /// x: brightness of the sky, y: brightness of the sun disk
+    params: Vec4,
+    /// xyz: celestial pole, w: rotation of the stars around it
+    stars: Vec4,
+    /// xyz: direction toward the moon, w: cosine of the moon radius
+    moon: Vec4,
+    /// x: brightness of the stars, y: moon phase angle, z: brightness of the moon disk
+    night: Vec4,
+}
+
+impl SkySettings {
+    /// Color of the sky toward `direction` relative to its brightness, what `sky.wgsl` draws
+    /// without the stars which are too small to light anything
+    pub fn radiance(&self, direction: Vec3) -> Vec3 {
+        let horizon_color = self.horizon_color.xyz();
+        let height = direction.y.clamp(0.0, 1.0);
+        let mut color = horizon_color.lerp(self.zenith_color.xyz(), height.sqrt());
+        if direction.y < 0.0 {
+            color *= 1.0 - 0.5 * (-direction.y * 4.0).clamp(0.0, 1.0);
+        }
+
+        let to_sun = -self.sun.xyz();
+        let cos_angle = direction.dot(to_sun);
+        let cos_radius = self.sun.w;
+        let edge = (1.0 - cos_radius) * 0.2;
+        let disk = smoothstep(cos_radius - edge, cos_radius + edge, cos_angle);
+        let glow = cos_angle.clamp(0.0, 1.0).powf(256.0) * 0.25;
+        color += (disk * self.params.y + glow) * horizon_color;
+        color + self.moon(direction) * self.night.z * Vec3::new(0.9, 0.92, 1.0)
+    }
+
+    fn moon(&self, direction: Vec3) -> f32 {
+        let to_moon = self.moon.xyz();
+        let cos_radius = self.moon.w;
+        if direction.dot(to_moon) < cos_radius {
+            return 0.0;
+        }
+        let right = to_moon.cross(Vec3::Y).normalize_or_zero();
+        let up = right.cross(to_moon);
+        let sin_radius = (1.0 - cos_radius * cos_radius).sqrt();
+        let uv = Vec2::new(direction.dot(right), direction.dot(up)) / sin_radius;
+        let normal = uv.extend((1.0 - uv.length_squared()).clamp(0.0, 1.0).sqrt());
+        let phase = self.night.y;
+        let light = Vec3::new(phase.sin(), 0.0, -phase.cos());
+        0.03 + smoothstep(-0.05, 0.05, normal.dot(light))
+    }
+
+    /// Whether the sky lights the scene differently than with `other`: other colors, or the sun
+    /// or the moon phase moved by more than `angle` radians. The brightness and the stars don't
+    /// count.
+    pub fn differs_from(&self, other: &Self, angle: f32) -> bool {
+        let moon_brightness = self.night.z.max(other.night.z);
+        self.zenith_color != other.zenith_color
+            || self.horizon_color != other.horizon_color
+            || self.params.y != other.params.y
+            || self.sun.w != other.sun.w
+            || self.moon.w != other.moon.w
+            || self.sun.xyz().angle_between(other.sun.xyz()) > angle
+            || (self.night.y - other.night.y).abs() > angle
+            || (self.night.z - other.night.z).abs() > moon_brightness * 0.05
+    }
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[derive(Asset, TypePath, AsBindGroup, Clone)]
+pub struct SkyMaterial {
+    #[uniform(0)]
+    settings: SkySettings,
+}
+
+impl Material for SkyMaterial {
+    fn vertex_shader() -> ShaderRef {
+        "sky.wgsl".into()
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        "sky.wgsl".into()
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayoutRef,
+        _key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        let vertex_layout = layout
+            .0
+            .get_layout(&[Mesh::ATTRIBUTE_POSITION.at_shader_location(0)])?;
+        descriptor.vertex.buffers = vec![vertex_layout];
+        // We are looking at the sphere from the inside
+        descriptor.primitive.cull_mode = Some(Face::Front);
+        Ok(())
+    }
+}
+
+#[derive(Component)]
+pub struct ProceduralSky;
+
+/// Directional light coming from the moon, only lit at night with the procedural sky
+#[derive(Component)]
+pub struct Moonlight;
+
+fn linear(color: Color) -> Vec4 {
+    let color = color.to_linear();
+    vec4(color.red, color.green, color.blue, color.alpha)
+}
+
+pub fn spawn_sky(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<SkyMaterial>>,
+) {
+    commands.spawn((
+        MaterialMeshBundle {
+            mesh: meshes.add(Sphere::new(1.0).mesh().uv(32, 16)),
+            material: materials.add(SkyMaterial {
+                settings: SkySettings::default(),
+            }),
+            visibility: Visibility::Hidden,
+            ..default()
+        },
+        // The sphere is placed around the camera in the shader
+        NoFrustumCulling,
+        NotShadowCaster,
+        ProceduralSky,
+    ));
+
+    commands.spawn((
+        DirectionalLightBundle {
+            directional_light: DirectionalLight {
+                illuminance: 0.0,
+                ..default()
+            },
+            ..default()
+        },
+        Moonlight,
+        SceneLayer::lights(),
+    ));
+}
+
+/// Swaps between the HDRI skybox and the procedural sky, keeps the sun disk aligned with the
+/// directional light and moves the night sky
+#[allow(clippy::too_many_arguments)]
+pub fn update_sky(
+    mut commands: Commands,
+    scene_config: Res<SceneConfig>,
+    texture_streaming: Res<TextureStreaming>,
+    time: Res<Time<Virtual>>,
+    mut ambient_light: ResMut<AmbientLight>,
+    light: Query<Ref<GlobalTransform>, (With<DirectionalLight>, Without<Moonlight>)>,
+    mut moonlight: Query<(&mut DirectionalLight, &mut Transform), With<Moonlight>>,
+    mut cameras: Query<(Entity, Has<Skybox>, &mut EnvironmentMapLight), With<Camera3d>>,
+    mut sky: Query<(&mut Visibility, &Handle<SkyMaterial>), With<ProceduralSky>>,
+    mut materials: ResMut<Assets<SkyMaterial>>,
+    mut sky_env_map: ResMut<SkyEnvironmentMap>,
+    mut last_elapsed: Local<f64>,
+    mut was_interim: Local<bool>,
+) {
+    let Ok(light) = light.get_single() else {
+        return;
+    };
+    let elapsed = time.elapsed_seconds_f64();
+    let time_advanced = elapsed != *last_elapsed;
+    *last_elapsed = elapsed;
+    let interim = scene_config.sky == Sky::Hdri && !texture_streaming.skybox.is_upgraded();
+    // Also changed once the first environment map of the procedural sky is composed
+    let sky_changed =
+        scene_config.is_changed() || interim != *was_interim || sky_env_map.is_changed();
+    *was_interim = interim;
+    if !sky_changed && !light.is_changed() && !time_advanced {
+        return;
+    }
+
+    let shown = if interim {
+        Sky::interim()
+    } else {
+        scene_config.sky
+    };
+    let Sky::Procedural {
+        zenith_color,
+        horizon_color,
+        sun_size,
+        sun_intensity,
+        night,
+    } = shown
+    else {
+        // Only changed once a new environment map is composed
+        sky_env_map.bypass_change_detection().track(None);
+        for (mut moonlight, _) in &mut moonlight {
+            moonlight.illuminance = 0.0;
+        }
+        for (entity, has_skybox, mut env_map_light) in &mut cameras {
+            if !has_skybox {
+                commands.entity(entity).insert(Skybox {
+                    image: texture_streaming.skybox.current(),
+                    brightness: scene_config.skybox_brightness,
+                });
+                env_map_light.diffuse_map = texture_streaming.skybox.current();
+                env_map_light.specular_map = texture_streaming.skybox.current();
+                env_map_light.intensity = scene_config.env_map_intensity;
+            }
+        }
+        for (mut visibility, _) in &mut sky {
+            *visibility = Visibility::Hidden;
+        }
+        if sky_changed {
+            ambient_light.brightness = 0.0;
+        }
+        return;
+    };
+
+    let composed = sky_env_map.maps();
+    for (entity, has_skybox, mut env_map_light) in &mut cameras {
+        if has_skybox {
+            commands.entity(entity).remove::<Skybox>();
+        }
+        let Some(maps) = composed else {
+            // The cubemap doesn't match the procedural sky, the ambient light is used instead
+            env_map_light.intensity = 0.0;
+            continue;
+        };
+        if env_map_light.diffuse_map != maps.diffuse {
+            env_map_light.diffuse_map = maps.diffuse.clone();
+            env_map_light.specular_map = maps.specular.clone();
+        }
+        env_map_light.intensity = scene_config.env_map_intensity;
+    }
+
+    if sky_changed && composed.is_some() {
+        ambient_light.brightness = 0.0;
+    } else if sky_changed {
+        // Rough approximation of the light coming from the whole sky dome until the environment
+        // map is composed
+        let color = (linear(horizon_color) + linear(zenith_color)) * 0.5;
+        ambient_light.color = Color::linear_rgb(color.x, color.y, color.z);
+        ambient_light.brightness = scene_config.env_map_intensity;
+    }
+
+    let sun = light.forward();
+    // The moon is opposite the sun, the night sky fades in once the sun is below the horizon
+    let to_moon = *sun;
+    let darkness = ((sun.y + 0.05) * 8.0).clamp(0.0, 1.0);
+    let moon_up = (to_moon.y * 10.0).clamp(0.0, 1.0);
+    let phase_angle = night.phase_angle(elapsed);
+    let lit_fraction = (1.0 - phase_angle.cos()) / 2.0;
+    for (mut moonlight, mut transform) in &mut moonlight {
+        moonlight.illuminance = night.moonlight_illuminance * lit_fraction * darkness * moon_up;
+        // Moonlight is reflected sunlight
+        moonlight.color = match scene_config.directional_light_kelvin {
+            Some(kelvin) => crate::color_temp::kelvin_to_color(kelvin),
+            None => scene_config.directional_light_color,
+        };
+        *transform = Transform::default().looking_to(-to_moon, Vec3::Y);
+    }
+
+    let pole = night.celestial_pole.normalize_or_zero();
+    let settings = SkySettings {
+        zenith_color: linear(zenith_color),
+        horizon_color: linear(horizon_color),
+        sun: vec4(sun.x, sun.y, sun.z, sun_size.to_radians().cos()),
+        params: vec4(scene_config.skybox_brightness, sun_intensity, 0.0, 0.0),
+        stars: vec4(pole.x, pole.y, pole.z, night.star_angle(elapsed)),
+        moon: vec4(
+            to_moon.x,
+            to_moon.y,
+            to_moon.z,
+            night.moon_size.to_radians().cos(),
+        ),
+        night: vec4(
+            night.star_brightness * darkness,
+            phase_angle,
+            night.moon_intensity * darkness,
+            0.0,
+        ),
+    };
+    sky_env_map.bypass_change_detection().track(Some(settings));
+    for (mut visibility, handle) in &mut sky {
+        *visibility = Visibility::Visible;
+        let Some(material) = materials.get_mut(handle) else {
+            continue;
+        };
+        material.settings = settings;
+    }
+}
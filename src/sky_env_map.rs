@@ -0,0 +1,467 @@
+//! Environment map composed from the procedural sky.
+//!
+//! The environment map light needs a cubemap and the procedural sky only exists in its shader, so
+//! the ambient light used to stand in for it with the average of the sky colors. The ambient light
+//! doesn't change with the direction, the forest was as bright under the zenith as under the glow
+//! of the sun at dusk and nothing reflected the sky.
+//!
+//! [`SkySettings::radiance`] mirrors the sky shader on the CPU. It's sampled into a small cubemap
+//! on the async compute pool, which is prefiltered into the specular map, a mip per roughness level
+//! from GGX importance samples, and projected on spherical harmonics for the diffuse map. The maps
+//! hold the color of the sky without its brightness, `env_map_intensity` is the intensity of the
+//! light like with the HDRI.
+//!
+//! The sky is composed again once the sun or the moon phase move by more than
+//! [`REFRESH_DEGREES`], or the sky colors change. A single composition runs at a time and takes a
+//! few frames, the cameras keep the previous maps until it's done.
+
+use std::{f32::consts::PI, time::Duration};
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{
+            Extent3d, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+            TextureViewDescriptor, TextureViewDimension,
+        },
+    },
+    tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task},
+    utils::Instant,
+};
+
+use crate::{overlay::StatsOverlay, sky::SkySettings};
+
+/// Texels on each side of the faces of the specular map, halved by every mip down to 1
+const SPECULAR_SIZE: usize = 64;
+const DIFFUSE_SIZE: usize = 16;
+/// Sky samples on each side of a texel of the specular map, the sun disk is smaller than a texel
+const SUPERSAMPLES: usize = 4;
+/// GGX samples for every texel of the rough mips of the specular map
+const PREFILTER_SAMPLES: u32 = 64;
+/// How far the sun or the moon phase move before the sky is composed again
+const REFRESH_DEGREES: f32 = 1.0;
+
+/// The cubemaps given to the cameras, their images are replaced by every composition
+pub struct SkyMaps {
+    pub diffuse: Handle<Image>,
+    pub specular: Handle<Image>,
+}
+
+#[derive(Resource, Default)]
+pub struct SkyEnvironmentMap {
+    maps: Option<SkyMaps>,
+    /// Settings of the procedural sky shown, none with the HDRI
+    shown: Option<SkySettings>,
+    /// Settings the current or the running composition is made from
+    composed: Option<SkySettings>,
+    task: Option<Task<ComposedSky>>,
+    /// Light falling from the sky on the ground, relative to the intensity of the environment map
+    ground_light: Vec3,
+}
+
+impl SkyEnvironmentMap {
+    /// Sets the sky to compose, or none to stop composing
+    pub fn track(&mut self, settings: Option<SkySettings>) {
+        self.shown = settings;
+    }
+
+    /// The maps of the procedural sky, once the first one is composed
+    pub fn maps(&self) -> Option<&SkyMaps> {
+        self.maps.as_ref()
+    }
+
+    /// Color of the light falling from the sky on the ground when `env_map` is the composed one,
+    /// relative to its intensity
+    pub fn ground_light(&self, env_map: &EnvironmentMapLight) -> Option<Vec3> {
+        let maps = self.maps.as_ref()?;
+        (env_map.diffuse_map == maps.diffuse).then_some(self.ground_light)
+    }
+}
+
+struct ComposedSky {
+    diffuse: Image,
+    specular: Image,
+    ground_light: Vec3,
+    duration: Duration,
+}
+
+/// Composes the environment map when the sky changed enough, and hands the finished one to the
+/// cameras
+pub fn compose_sky_env_map(
+    mut sky_env_map: ResMut<SkyEnvironmentMap>,
+    mut images: ResMut<Assets<Image>>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    // Only changed when the cameras get the maps for the first time
+    let env_map = sky_env_map.bypass_change_detection();
+    let mut first = false;
+    if let Some(task) = &mut env_map.task {
+        if !task.is_finished() {
+            return;
+        }
+        let composed = block_on(future::poll_once(task));
+        env_map.task = None;
+        if let Some(composed) = composed {
+            stats.set(
+                "Sky env map",
+                format!(
+                    "composed in {:.0}ms",
+                    composed.duration.as_secs_f32() * 1000.0
+                ),
+            );
+            env_map.ground_light = composed.ground_light;
+            match &env_map.maps {
+                Some(maps) => {
+                    images.insert(maps.diffuse.id(), composed.diffuse);
+                    images.insert(maps.specular.id(), composed.specular);
+                }
+                None => {
+                    env_map.maps = Some(SkyMaps {
+                        diffuse: images.add(composed.diffuse),
+                        specular: images.add(composed.specular),
+                    });
+                    first = true;
+                }
+            }
+        }
+    }
+
+    let Some(shown) = env_map.shown else {
+        env_map.composed = None;
+        stats.remove("Sky env map");
+        return;
+    };
+    let refresh = match &env_map.composed {
+        Some(composed) => shown.differs_from(composed, REFRESH_DEGREES.to_radians()),
+        None => true,
+    };
+    if refresh {
+        env_map.composed = Some(shown);
+        env_map.task = Some(AsyncComputeTaskPool::get().spawn(async move { compose(&shown) }));
+    }
+    if first {
+        sky_env_map.set_changed();
+    }
+}
+
+/// Colors of the six faces of a cubemap, in the +X, -X, +Y, -Y, +Z, -Z order of the GPU
+struct Cubemap {
+    size: usize,
+    texels: Vec<Vec3>,
+}
+
+impl Cubemap {
+    /// Fills the texels from their face and the coordinates of their center on it, from -1 to 1
+    fn new(size: usize, mut texel: impl FnMut(usize, Vec2) -> Vec3) -> Self {
+        let mut texels = Vec::with_capacity(6 * size * size);
+        for face in 0..6 {
+            for y in 0..size {
+                for x in 0..size {
+                    texels.push(texel(face, texel_center(size, x, y)));
+                }
+            }
+        }
+        Self { size, texels }
+    }
+
+    fn get(&self, face: usize, x: usize, y: usize) -> Vec3 {
+        self.texels[(face * self.size + y) * self.size + x]
+    }
+
+    /// The texel toward `direction`, without filtering
+    fn sample(&self, direction: Vec3) -> Vec3 {
+        let (face, coordinates) = face_coordinates(direction);
+        let texel = ((coordinates * 0.5 + 0.5) * self.size as f32).as_uvec2();
+        let max = self.size as u32 - 1;
+        self.get(face, texel.x.min(max) as usize, texel.y.min(max) as usize)
+    }
+
+    /// Half the size, every texel the average of four
+    fn downsampled(&self) -> Self {
+        let size = self.size / 2;
+        let mut texels = Vec::with_capacity(6 * size * size);
+        for face in 0..6 {
+            for y in 0..size {
+                for x in 0..size {
+                    let sum = self.get(face, 2 * x, 2 * y)
+                        + self.get(face, 2 * x + 1, 2 * y)
+                        + self.get(face, 2 * x, 2 * y + 1)
+                        + self.get(face, 2 * x + 1, 2 * y + 1);
+                    texels.push(sum * 0.25);
+                }
+            }
+        }
+        Self { size, texels }
+    }
+}
+
+fn texel_center(size: usize, x: usize, y: usize) -> Vec2 {
+    (Vec2::new(x as f32, y as f32) + 0.5) / size as f32 * 2.0 - 1.0
+}
+
+/// Direction toward the coordinates on `face`, from -1 to 1 with y going down
+fn face_direction(face: usize, coordinates: Vec2) -> Vec3 {
+    let Vec2 { x: s, y: t } = coordinates;
+    let direction = match face {
+        0 => Vec3::new(1.0, -t, -s),
+        1 => Vec3::new(-1.0, -t, s),
+        2 => Vec3::new(s, 1.0, t),
+        3 => Vec3::new(s, -1.0, -t),
+        4 => Vec3::new(s, -t, 1.0),
+        _ => Vec3::new(-s, -t, -1.0),
+    };
+    direction.normalize()
+}
+
+/// The face toward `direction` and the coordinates on it, the inverse of [`face_direction`]
+fn face_coordinates(direction: Vec3) -> (usize, Vec2) {
+    let abs = direction.abs();
+    let (face, s, t, major) = if abs.x >= abs.y && abs.x >= abs.z {
+        if direction.x > 0.0 {
+            (0, -direction.z, -direction.y, abs.x)
+        } else {
+            (1, direction.z, -direction.y, abs.x)
+        }
+    } else if abs.y >= abs.z {
+        if direction.y > 0.0 {
+            (2, direction.x, direction.z, abs.y)
+        } else {
+            (3, direction.x, -direction.z, abs.y)
+        }
+    } else if direction.z > 0.0 {
+        (4, direction.x, -direction.y, abs.z)
+    } else {
+        (5, -direction.x, -direction.y, abs.z)
+    };
+    (face, Vec2::new(s, t) / major)
+}
+
+/// Solid angle covered by the texel centered on `coordinates`
+fn texel_solid_angle(size: usize, coordinates: Vec2) -> f32 {
+    let area = |x: f32, y: f32| (x * y).atan2((x * x + y * y + 1.0).sqrt());
+    let half = 1.0 / size as f32;
+    let (min, max) = (coordinates - half, coordinates + half);
+    area(min.x, min.y) - area(min.x, max.y) - area(max.x, min.y) + area(max.x, max.y)
+}
+
+/// Bevy samples the environment maps with z flipped, the cubemaps are left-handed
+fn world_direction(direction: Vec3) -> Vec3 {
+    direction * Vec3::new(1.0, 1.0, -1.0)
+}
+
+fn compose(settings: &SkySettings) -> ComposedSky {
+    let started = Instant::now();
+    let radiance = Cubemap::new(SPECULAR_SIZE, |face, center| {
+        let step = 2.0 / (SPECULAR_SIZE * SUPERSAMPLES) as f32;
+        let mut sum = Vec3::ZERO;
+        for y in 0..SUPERSAMPLES {
+            for x in 0..SUPERSAMPLES {
+                let offset =
+                    (Vec2::new(x as f32, y as f32) + 0.5) * step - 1.0 / SPECULAR_SIZE as f32;
+                let direction = face_direction(face, center + offset);
+                sum += settings.radiance(world_direction(direction));
+            }
+        }
+        sum / (SUPERSAMPLES * SUPERSAMPLES) as f32
+    });
+    let mut levels = vec![radiance];
+    while levels.last().unwrap().size > 1 {
+        let next = levels.last().unwrap().downsampled();
+        levels.push(next);
+    }
+
+    let last_mip = levels.len() - 1;
+    let specular: Vec<Cubemap> = (0..levels.len())
+        .map(|mip| {
+            let size = levels[mip].size;
+            if mip == 0 {
+                return Cubemap {
+                    size,
+                    texels: levels[0].texels.clone(),
+                };
+            }
+            let roughness = mip as f32 / last_mip as f32;
+            Cubemap::new(size, |face, center| {
+                prefilter(&levels, face_direction(face, center), roughness)
+            })
+        })
+        .collect();
+
+    let harmonics = project_harmonics(
+        levels
+            .iter()
+            .find(|level| level.size == DIFFUSE_SIZE)
+            .unwrap_or(&levels[0]),
+    );
+    let diffuse = Cubemap::new(DIFFUSE_SIZE, |face, center| {
+        irradiance(&harmonics, face_direction(face, center))
+    });
+
+    ComposedSky {
+        diffuse: cubemap_image(&[diffuse], "sky diffuse map"),
+        specular: cubemap_image(&specular, "sky specular map"),
+        ground_light: irradiance(&harmonics, Vec3::Y),
+        duration: started.elapsed(),
+    }
+}
+
+/// Sequence of points well spread over the unit square
+fn hammersley(index: u32, count: u32) -> Vec2 {
+    Vec2::new(
+        index as f32 / count as f32,
+        index.reverse_bits() as f32 * 2.328_306_4e-10,
+    )
+}
+
+/// The radiance of `levels` toward `normal` convolved with the GGX lobe of `roughness`, viewed
+/// along the normal. Every sample reads the mip matching its share of the lobe so the sun isn't
+/// missed between the samples.
+fn prefilter(levels: &[Cubemap], normal: Vec3, roughness: f32) -> Vec3 {
+    let alpha = roughness * roughness;
+    let alpha2 = alpha * alpha;
+    let (tangent, bitangent) = normal.any_orthonormal_pair();
+    let texel_solid_angle = 4.0 * PI / (6 * levels[0].size * levels[0].size) as f32;
+
+    let mut sum = Vec3::ZERO;
+    let mut weight = 0.0;
+    for index in 0..PREFILTER_SAMPLES {
+        let xi = hammersley(index, PREFILTER_SAMPLES);
+        let phi = 2.0 * PI * xi.x;
+        let cos_theta = ((1.0 - xi.y) / (1.0 + (alpha2 - 1.0) * xi.y)).sqrt();
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let half = (tangent * phi.cos() + bitangent * phi.sin()) * sin_theta + normal * cos_theta;
+        let light = 2.0 * normal.dot(half) * half - normal;
+        let n_dot_l = normal.dot(light);
+        if n_dot_l <= 0.0 {
+            continue;
+        }
+
+        // The view is the normal, the pdf of the light direction is D / 4
+        let d = alpha2 / (PI * (cos_theta * cos_theta * (alpha2 - 1.0) + 1.0).powi(2));
+        let sample_solid_angle = 4.0 / (PREFILTER_SAMPLES as f32 * d);
+        let level = 0.5 * (sample_solid_angle / texel_solid_angle).log2() + 1.0;
+        let level = (level.round().max(0.0) as usize).min(levels.len() - 1);
+        sum += levels[level].sample(light) * n_dot_l;
+        weight += n_dot_l;
+    }
+    sum / weight
+}
+
+/// The first 9 real spherical harmonics toward `direction`
+fn harmonics_basis(direction: Vec3) -> [f32; 9] {
+    let Vec3 { x, y, z } = direction;
+    [
+        0.282_095,
+        0.488_603 * y,
+        0.488_603 * z,
+        0.488_603 * x,
+        1.092_548 * x * y,
+        1.092_548 * y * z,
+        0.315_392 * (3.0 * z * z - 1.0),
+        1.092_548 * x * z,
+        0.546_274 * (x * x - y * y),
+    ]
+}
+
+fn project_harmonics(cubemap: &Cubemap) -> [Vec3; 9] {
+    let mut harmonics = [Vec3::ZERO; 9];
+    for face in 0..6 {
+        for y in 0..cubemap.size {
+            for x in 0..cubemap.size {
+                let center = texel_center(cubemap.size, x, y);
+                let solid_angle = texel_solid_angle(cubemap.size, center);
+                let radiance = cubemap.get(face, x, y) * solid_angle;
+                let basis = harmonics_basis(face_direction(face, center));
+                for (harmonic, basis) in harmonics.iter_mut().zip(basis) {
+                    *harmonic += radiance * basis;
+                }
+            }
+        }
+    }
+    harmonics
+}
+
+/// Radiance of the harmonics convolved with the cosine lobe around `normal`, the irradiance over
+/// pi which is what bevy expects in the diffuse map
+fn irradiance(harmonics: &[Vec3; 9], normal: Vec3) -> Vec3 {
+    // The convolution scales each band
+    const BANDS: [f32; 9] = [
+        1.0,
+        2.0 / 3.0,
+        2.0 / 3.0,
+        2.0 / 3.0,
+        0.25,
+        0.25,
+        0.25,
+        0.25,
+        0.25,
+    ];
+    let sum = harmonics
+        .iter()
+        .zip(harmonics_basis(normal))
+        .zip(BANDS)
+        .map(|((harmonic, basis), band)| *harmonic * basis * band)
+        .sum::<Vec3>();
+    sum.max(Vec3::ZERO)
+}
+
+/// Bits of the half float closest to `value`, the tiny values are flushed to 0 and the huge ones
+/// clamped to the largest half
+fn half_bits(value: f32) -> u16 {
+    if value.is_nan() {
+        return 0x7e00;
+    }
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = ((bits >> 13) & 0x3ff) as u16;
+    if exponent <= 0 {
+        sign
+    } else if exponent >= 31 {
+        sign | 0x7bff
+    } else {
+        sign | ((exponent as u16) << 10) | mantissa
+    }
+}
+
+/// A cubemap texture with the `mips` from the largest, they're uploaded face after face
+fn cubemap_image(mips: &[Cubemap], label: &'static str) -> Image {
+    let mut data = Vec::new();
+    for face in 0..6 {
+        for mip in mips {
+            for y in 0..mip.size {
+                for x in 0..mip.size {
+                    let color = mip.get(face, x, y);
+                    for channel in [color.x, color.y, color.z, 1.0] {
+                        data.extend(half_bits(channel).to_le_bytes());
+                    }
+                }
+            }
+        }
+    }
+    let size = mips[0].size as u32;
+    Image {
+        data,
+        texture_descriptor: TextureDescriptor {
+            label: Some(label),
+            size: Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 6,
+            },
+            mip_level_count: mips.len() as u32,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba16Float,
+            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+            view_formats: &[],
+        },
+        texture_view_descriptor: Some(TextureViewDescriptor {
+            dimension: Some(TextureViewDimension::Cube),
+            ..default()
+        }),
+        asset_usage: RenderAssetUsages::RENDER_WORLD,
+        ..default()
+    }
+}
@@ -0,0 +1,137 @@
+//! Checks that the app gets to a populated scene.
+//!
+//! `--smoke-test` runs the real app for at most [`SMOKE_TEST_FRAMES`] frames and exits as soon as
+//! every stage of the startup happened: both configs loaded, the terrain, at least
+//! [`MIN_TREES`] trees for the configured seed and the water spawned. The trees are always the
+//! procedural ones so the test doesn't depend on the glb. When a stage never happens the app
+//! exits with an error naming it, a panicking system already makes the process fail.
+//!
+//! With the `smoke_test` feature, `cargo test` runs the same checks on the app built without a
+//! window nor a GPU.
+
+use bevy::{app::AppExit, pbr::ExtendedMaterial, prelude::*};
+
+use crate::{
+    cli::CliArgs,
+    terrain::{Terrain, TerrainConfig, TerrainMaterial, TreeInstance},
+    water::WaterPlane,
+    SceneConfig,
+};
+
+pub const SMOKE_TEST_FRAMES: u32 = 1200;
+pub const MIN_TREES: usize = 100;
+
+#[derive(Resource, Default)]
+pub struct SmokeTest {
+    frames: u32,
+}
+
+pub fn start_smoke_test(mut commands: Commands, cli: Res<CliArgs>) {
+    if cli.smoke_test {
+        info!("smoke test, waiting up to {SMOKE_TEST_FRAMES} frames for the scene");
+        commands.init_resource::<SmokeTest>();
+    }
+}
+
+/// Stages of the startup in the order they happen, with whether they happened yet
+fn stages(world: &mut World) -> [(&'static str, bool); 5] {
+    let terrain_material = world
+        .query_filtered::<(), (
+            With<Terrain>,
+            With<Handle<ExtendedMaterial<StandardMaterial, TerrainMaterial>>>,
+        )>()
+        .iter(world)
+        .next()
+        .is_some();
+    let trees = world
+        .query_filtered::<(), With<TreeInstance>>()
+        .iter(world)
+        .len();
+    let water = world
+        .query_filtered::<(), With<WaterPlane>>()
+        .iter(world)
+        .next()
+        .is_some();
+    [
+        (
+            "the terrain config loaded",
+            world.contains_resource::<TerrainConfig>(),
+        ),
+        (
+            "the scene config loaded",
+            world.contains_resource::<SceneConfig>(),
+        ),
+        (
+            "the terrain mesh spawned with its material",
+            terrain_material,
+        ),
+        ("the trees spawned", trees >= MIN_TREES),
+        ("the water spawned", water),
+    ]
+}
+
+pub fn run_smoke_test(world: &mut World) {
+    let frames = {
+        let mut smoke_test = world.resource_mut::<SmokeTest>();
+        smoke_test.frames += 1;
+        smoke_test.frames
+    };
+    let stages = stages(world);
+    let missing = stages.iter().find(|(_, done)| !done).map(|(name, _)| *name);
+    let exit = match missing {
+        None => {
+            info!("smoke test passed after {frames} frames");
+            AppExit::Success
+        }
+        Some(stage) if frames >= SMOKE_TEST_FRAMES => {
+            error!("smoke test failed, {stage} never happened in {frames} frames");
+            AppExit::error()
+        }
+        Some(_) => return,
+    };
+    world.remove_resource::<SmokeTest>();
+    world.send_event(exit);
+}
+
+/// The app built headless with `--smoke-test`, updated until every stage happened
+#[cfg(all(test, feature = "smoke_test"))]
+pub fn populated_headless_app() -> App {
+    let cli = CliArgs {
+        smoke_test: true,
+        no_config_writeback: true,
+        ..default()
+    };
+    let mut app = crate::build_app(cli, true);
+    app.finish();
+    app.cleanup();
+    let exit = loop {
+        update_paced(&mut app);
+        if let Some(exit) = app.should_exit() {
+            break exit;
+        }
+    };
+    let missing: Vec<_> = stages(app.world_mut())
+        .into_iter()
+        .filter(|(_, done)| !done)
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(exit, AppExit::Success, "never happened: {missing:?}");
+    app
+}
+
+/// Paced like the real app so the assets and the generation tasks get the time to finish
+#[cfg(all(test, feature = "smoke_test"))]
+pub fn update_paced(app: &mut App) {
+    app.update();
+    std::thread::sleep(std::time::Duration::from_secs_f64(1.0 / 60.0));
+}
+
+#[cfg(all(test, feature = "smoke_test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_headless_app_gets_to_a_populated_scene() {
+        populated_headless_app();
+    }
+}
@@ -0,0 +1,302 @@
+//! Falling snow and the snow it leaves on the ground.
+//!
+//! The flakes work like the dust motes, a single mesh animated in the vertex shader in a volume
+//! that wraps around the camera. They drift with the wind and are recycled at the top of the
+//! volume once they reach the ground under the camera.
+//!
+//! While it snows the accumulation rises up to its cap and lowers the effective snow line from
+//! the snow height of the scene config, or the top of the terrain, towards the water level. The
+//! terrain and the tree frost both follow that line. Once it stops snowing the accumulation slowly
+//! melts, unless it's too cold. Nothing changes while the snowfall is disabled.
+
+use bevy::{
+    math::vec4,
+    pbr::{MaterialPipeline, MaterialPipelineKey, NotShadowCaster},
+    prelude::*,
+    render::{
+        mesh::{Indices, MeshVertexBufferLayoutRef, PrimitiveTopology},
+        render_asset::RenderAssetUsages,
+        render_resource::{
+            AsBindGroup, RenderPipelineDescriptor, ShaderRef, ShaderType,
+            SpecializedMeshPipelineError,
+        },
+        view::NoFrustumCulling,
+    },
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    camera_controller::CameraController, deterministic::DeterministicClock,
+    heightfield::TerrainHeightfield, overlay::StatsOverlay, split_view::SecondaryCamera,
+    terrain::TerrainConfig, SceneConfig,
+};
+
+const FLAKE_COUNT: usize = 4096;
+/// Size of the volume of flakes around the camera
+const VOLUME_SIZE: f32 = 24.0;
+const FLAKE_SIZE: f32 = 0.03;
+
+/// Settings of the snowfall weather
+#[derive(Reflect, Clone, Debug)]
+pub struct SnowfallConfig {
+    pub enabled: bool,
+    /// 0 stops the snowfall, 1 is a heavy snowfall
+    pub intensity: f32,
+    /// Horizontal drift of the flakes in meters per second, x and z in world space
+    pub wind: Vec2,
+    /// Meters per second
+    pub fall_speed: f32,
+    pub brightness: f32,
+    /// Accumulation gained per minute of heavy snowfall
+    pub accumulation_rate: f32,
+    /// Accumulation lost per minute once the snowfall stops
+    pub melt_rate: f32,
+    /// Highest accumulation, 1 brings the snow line down to the water
+    pub max_accumulation: f32,
+    /// Air temperature in celsius
+    pub temperature: f32,
+    /// The snow only melts above this temperature
+    pub melt_temperature: f32,
+}
+
+impl Default for SnowfallConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            intensity: 0.5,
+            wind: Vec2::new(0.8, 0.3),
+            fall_speed: 1.2,
+            brightness: 1.0,
+            accumulation_rate: 0.1,
+            melt_rate: 0.05,
+            max_accumulation: 1.0,
+            temperature: -2.0,
+            melt_temperature: 0.0,
+        }
+    }
+}
+
+#[derive(Resource, PartialEq)]
+pub struct Snowfall {
+    /// 0 is the plain snow line of the scene config, 1 is snow down to the water
+    pub accumulation: f32,
+    /// Altitude above which the ground and the trees are covered in snow, what the materials use
+    /// instead of the snow height of the scene config
+    pub snow_height: f32,
+}
+
+impl Default for Snowfall {
+    fn default() -> Self {
+        Self {
+            accumulation: 0.0,
+            snow_height: f32::MAX,
+        }
+    }
+}
+
+pub fn update_snow_accumulation(
+    time: Res<Time<Virtual>>,
+    clock: Res<DeterministicClock>,
+    scene_config: Res<SceneConfig>,
+    terrain_config: Option<Res<TerrainConfig>>,
+    heightfield: Option<Res<TerrainHeightfield>>,
+    mut peak: Local<Option<f32>>,
+    mut snowfall: ResMut<Snowfall>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    let config = &scene_config.snowfall;
+    if !config.enabled {
+        let accumulation = snowfall.accumulation;
+        snowfall.set_if_neq(Snowfall {
+            accumulation,
+            snow_height: scene_config.snow_height,
+        });
+        stats.remove("Snow");
+        return;
+    }
+
+    let mut accumulation = snowfall.accumulation;
+    for delta in clock.deltas(time.delta_seconds()) {
+        let minutes = delta / 60.0;
+        if config.intensity > 0.0 {
+            accumulation += minutes * config.accumulation_rate * config.intensity;
+        } else if config.temperature > config.melt_temperature {
+            accumulation -= minutes * config.melt_rate;
+        }
+        accumulation = accumulation.clamp(0.0, config.max_accumulation);
+    }
+
+    if let Some(heightfield) = heightfield
+        .as_ref()
+        .filter(|h| h.is_changed() || peak.is_none())
+    {
+        let (x, z) = heightfield.highest_point();
+        *peak = Some(heightfield.height(x, z));
+    }
+    let snow_height = match (&terrain_config, *peak) {
+        (Some(terrain_config), Some(peak)) => {
+            // Starting from the top of the terrain, a snow line far above it would hide the
+            // accumulation until the very end
+            let top = scene_config.snow_height.min(peak);
+            // Quantized so the materials aren't prepared again every frame while it snows
+            let amount = (accumulation * 64.0).round() / 64.0;
+            (top + (terrain_config.water_level - top) * amount).min(scene_config.snow_height)
+        }
+        _ => scene_config.snow_height,
+    };
+    snowfall.set_if_neq(Snowfall {
+        accumulation,
+        snow_height,
+    });
+
+    let state = if config.intensity > 0.0 {
+        "snowing"
+    } else if config.temperature > config.melt_temperature {
+        "melting"
+    } else {
+        "frozen"
+    };
+    stats.set(
+        "Snow",
+        format!("{state}, {:.0}% accumulated", accumulation * 100.0),
+    );
+}
+
+#[derive(Clone, Copy, ShaderType, Debug, Default, PartialEq)]
+pub struct SnowSettings {
+    /// x: size of the volume around the camera, y: fraction of visible flakes,
+    /// z: flake size, w: fall speed
+    params: Vec4,
+    /// xy: wind drift on x and z, z: ground height under the camera, w: brightness
+    wind: Vec4,
+}
+
+#[derive(Asset, TypePath, AsBindGroup, Clone)]
+pub struct SnowMaterial {
+    #[uniform(0)]
+    settings: SnowSettings,
+}
+
+impl Material for SnowMaterial {
+    fn vertex_shader() -> ShaderRef {
+        "snow.wgsl".into()
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        "snow.wgsl".into()
+    }
+
+    fn alpha_mode(&self) -> AlphaMode {
+        AlphaMode::Blend
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayoutRef,
+        _key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        let vertex_layout = layout.0.get_layout(&[
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_UV_0.at_shader_location(1),
+        ])?;
+        descriptor.vertex.buffers = vec![vertex_layout];
+        Ok(())
+    }
+}
+
+#[derive(Component)]
+pub struct Snowflakes;
+
+/// Same layout as the dust, a random point in the unit cube shared by the 4 vertices of every
+/// quad and the uv to expand it in the vertex shader
+fn snow_mesh() -> Mesh {
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut positions = Vec::with_capacity(FLAKE_COUNT * 4);
+    let mut uvs = Vec::with_capacity(FLAKE_COUNT * 4);
+    let mut indices = Vec::with_capacity(FLAKE_COUNT * 6);
+    for i in 0..FLAKE_COUNT as u32 {
+        let position: [f32; 3] = rng.gen();
+        for uv in [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]] {
+            positions.push(position);
+            uvs.push(uv);
+        }
+        let base = i * 4;
+        indices.extend([base, base + 1, base + 2, base, base + 2, base + 3]);
+    }
+    Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::RENDER_WORLD,
+    )
+    .with_inserted_indices(Indices::U32(indices))
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+}
+
+pub fn spawn_snowflakes(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<SnowMaterial>>,
+) {
+    commands.spawn((
+        MaterialMeshBundle {
+            mesh: meshes.add(snow_mesh()),
+            material: materials.add(SnowMaterial {
+                settings: SnowSettings::default(),
+            }),
+            visibility: Visibility::Hidden,
+            ..default()
+        },
+        // The flakes are placed around the camera in the shader
+        NoFrustumCulling,
+        NotShadowCaster,
+        Snowflakes,
+    ));
+}
+
+pub fn update_snowflakes(
+    scene_config: Res<SceneConfig>,
+    heightfield: Option<Res<TerrainHeightfield>>,
+    camera: Query<&Transform, (With<CameraController>, Without<SecondaryCamera>)>,
+    mut snow: Query<(&mut Visibility, &Handle<SnowMaterial>), With<Snowflakes>>,
+    mut materials: ResMut<Assets<SnowMaterial>>,
+) {
+    let config = &scene_config.snowfall;
+    let enabled = config.enabled && config.intensity > 0.0;
+    // Whole meters so the material isn't prepared again every time the camera moves
+    let ground = camera
+        .get_single()
+        .ok()
+        .and_then(|camera| heightfield.as_ref()?.height_at(camera.translation.xz()))
+        .map_or(f32::MIN, f32::floor);
+    let settings = SnowSettings {
+        params: vec4(
+            VOLUME_SIZE,
+            config.intensity.min(1.0),
+            FLAKE_SIZE,
+            config.fall_speed,
+        ),
+        wind: vec4(config.wind.x, config.wind.y, ground, config.brightness),
+    };
+
+    for (mut visibility, handle) in &mut snow {
+        let target = if enabled {
+            Visibility::Visible
+        } else {
+            Visibility::Hidden
+        };
+        if *visibility != target {
+            *visibility = target;
+        }
+        if !enabled
+            || materials
+                .get(handle)
+                .is_some_and(|m| m.settings == settings)
+        {
+            continue;
+        }
+        if let Some(material) = materials.get_mut(handle) {
+            material.settings = settings;
+        }
+    }
+}
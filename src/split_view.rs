@@ -0,0 +1,273 @@
+//! A second view of the scene next to the main one.
+//!
+//! F11 cycles between no second view, the right half of the window and a picture in picture in
+//! the bottom right corner. The second camera looks straight down from above the main camera
+//! until V hands it the controller to fly it around, V again gives the controller back. It has
+//! none of the expensive effects of the main camera: no TAA, SSR, volumetric fog, depth of field
+//! or motion blur.
+
+use bevy::{
+    core_pipeline::{
+        prepass::{DeferredPrepass, DepthPrepass, NormalPrepass},
+        tonemapping::Tonemapping,
+        Skybox,
+    },
+    prelude::*,
+    render::{camera::Viewport, view::ColorGrading},
+    window::PrimaryWindow,
+};
+
+use crate::{
+    camera_controller::CameraController, overlay::StatsOverlay, render_layers::SceneLayer,
+};
+
+/// Height of the top down view above the main camera
+const TOP_DOWN_HEIGHT: f32 = 120.0;
+/// Fraction of the window width covered by the picture in picture
+const PICTURE_IN_PICTURE_SIZE: f32 = 0.3;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SplitViewMode {
+    #[default]
+    Off,
+    SideBySide,
+    PictureInPicture,
+}
+
+#[derive(Resource, Default)]
+pub struct SplitView {
+    pub mode: SplitViewMode,
+    /// The second camera gets the controller instead of following the main one
+    pub free_fly: bool,
+}
+
+impl SplitView {
+    /// Whether the controller moves the second camera
+    pub fn controls_secondary(&self) -> bool {
+        self.mode != SplitViewMode::Off && self.free_fly
+    }
+}
+
+/// Marker for the second camera, the systems working with the main camera filter it out
+#[derive(Component)]
+pub struct SecondaryCamera;
+
+type PrimaryCamera<'a> = (
+    Entity,
+    &'a mut Camera,
+    &'a Transform,
+    &'a Tonemapping,
+    &'a ColorGrading,
+    &'a EnvironmentMapLight,
+    Option<&'a Skybox>,
+    Has<DeferredPrepass>,
+);
+
+pub fn split_view_input(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut split_view: ResMut<SplitView>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    if keyboard.just_pressed(KeyCode::F11) {
+        split_view.mode = match split_view.mode {
+            SplitViewMode::Off => SplitViewMode::SideBySide,
+            SplitViewMode::SideBySide => SplitViewMode::PictureInPicture,
+            SplitViewMode::PictureInPicture => SplitViewMode::Off,
+        };
+        split_view.free_fly = false;
+    }
+    if keyboard.just_pressed(KeyCode::KeyV) && split_view.mode != SplitViewMode::Off {
+        split_view.free_fly = !split_view.free_fly;
+    }
+
+    match split_view.mode {
+        SplitViewMode::Off => stats.remove("Split view"),
+        mode => stats.set(
+            "Split view",
+            format!(
+                "{mode:?}, {}, V to switch",
+                if split_view.free_fly {
+                    "flying the second view"
+                } else {
+                    "top down"
+                }
+            ),
+        ),
+    }
+}
+
+/// Spawns and despawns the second camera, keeps both viewports in sync with the window size and
+/// moves the top down view
+#[allow(clippy::type_complexity)]
+pub fn update_split_view(
+    mut commands: Commands,
+    split_view: Res<SplitView>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    mut primary: Query<PrimaryCamera, (With<CameraController>, Without<SecondaryCamera>)>,
+    mut secondary: Query<(Entity, &mut Camera, &mut Transform), With<SecondaryCamera>>,
+) {
+    let Ok((
+        primary_entity,
+        mut primary_camera,
+        primary_transform,
+        tonemapping,
+        color_grading,
+        env_map,
+        skybox,
+        deferred,
+    )) = primary.get_single_mut()
+    else {
+        return;
+    };
+
+    if split_view.mode == SplitViewMode::Off {
+        for (entity, ..) in &secondary {
+            commands.entity(entity).despawn_recursive();
+        }
+        if primary_camera.viewport.is_some() {
+            primary_camera.viewport = None;
+        }
+        return;
+    }
+
+    let Ok(window) = window.get_single() else {
+        return;
+    };
+    let size = window.physical_size();
+    // Minimized, a viewport can't be empty
+    if size.x < 2 || size.y < 2 {
+        return;
+    }
+    let (primary_viewport, secondary_viewport) = match split_view.mode {
+        SplitViewMode::SideBySide => {
+            let half = UVec2::new(size.x / 2, size.y);
+            (
+                Some(Viewport {
+                    physical_position: UVec2::ZERO,
+                    physical_size: half,
+                    ..default()
+                }),
+                Viewport {
+                    physical_position: UVec2::new(half.x, 0),
+                    physical_size: UVec2::new(size.x - half.x, size.y),
+                    ..default()
+                },
+            )
+        }
+        _ => {
+            let width = (size.x as f32 * PICTURE_IN_PICTURE_SIZE) as u32;
+            let height = (width * size.y / size.x).max(1);
+            (
+                None,
+                Viewport {
+                    physical_position: size - UVec2::new(width, height),
+                    physical_size: UVec2::new(width, height),
+                    ..default()
+                },
+            )
+        }
+    };
+    if primary_camera.viewport != primary_viewport {
+        primary_camera.viewport = primary_viewport;
+    }
+
+    // Looking down with the top of the view where the main camera looks
+    let forward = primary_transform.forward().with_y(0.0).normalize_or_zero();
+    let up = if forward == Vec3::ZERO {
+        Vec3::NEG_Z
+    } else {
+        forward
+    };
+    let top_down =
+        Transform::from_translation(primary_transform.translation + Vec3::Y * TOP_DOWN_HEIGHT)
+            .looking_to(Vec3::NEG_Y, up);
+
+    let Ok((_, mut camera, mut transform)) = secondary.get_single_mut() else {
+        let mut entity = commands.spawn((
+            Camera3dBundle {
+                camera: Camera {
+                    hdr: true,
+                    order: 1,
+                    viewport: Some(secondary_viewport),
+                    ..default()
+                },
+                transform: top_down,
+                tonemapping: *tonemapping,
+                color_grading: color_grading.clone(),
+                ..default()
+            },
+            env_map.clone(),
+            // The materials are prepared for the same passes as the main camera
+            DepthPrepass,
+            CameraController::default(),
+            SecondaryCamera,
+            SceneLayer::top_down_camera(),
+        ));
+        if let Some(skybox) = skybox {
+            entity.insert(skybox.clone());
+        }
+        if deferred {
+            entity.insert(DeferredPrepass);
+        } else {
+            entity.insert(NormalPrepass);
+        }
+        // The UI stays on the main view
+        commands.entity(primary_entity).insert(IsDefaultUiCamera);
+        return;
+    };
+    if camera.viewport.as_ref() != Some(&secondary_viewport) {
+        camera.viewport = Some(secondary_viewport);
+    }
+    if !split_view.free_fly {
+        *transform = top_down;
+    }
+}
+
+/// Keeps the look of the second view in sync with the scene config applied to the main camera
+#[allow(clippy::type_complexity)]
+pub fn sync_secondary_camera(
+    primary: Query<
+        (
+            Ref<Tonemapping>,
+            Ref<ColorGrading>,
+            Ref<EnvironmentMapLight>,
+            Option<Ref<Skybox>>,
+        ),
+        (With<CameraController>, Without<SecondaryCamera>),
+    >,
+    mut secondary: Query<
+        (
+            &mut Tonemapping,
+            &mut ColorGrading,
+            &mut EnvironmentMapLight,
+            Option<&mut Skybox>,
+        ),
+        With<SecondaryCamera>,
+    >,
+) {
+    let Ok((tonemapping, color_grading, env_map, skybox)) = primary.get_single() else {
+        return;
+    };
+    for (
+        mut secondary_tonemapping,
+        mut secondary_grading,
+        mut secondary_env_map,
+        secondary_skybox,
+    ) in &mut secondary
+    {
+        if tonemapping.is_changed() {
+            *secondary_tonemapping = *tonemapping;
+        }
+        if color_grading.is_changed() {
+            *secondary_grading = color_grading.clone();
+        }
+        if env_map.is_changed() {
+            *secondary_env_map = env_map.clone();
+        }
+        if let (Some(skybox), Some(mut secondary_skybox)) = (&skybox, secondary_skybox) {
+            if skybox.is_changed() {
+                *secondary_skybox = (**skybox).clone();
+            }
+        }
+    }
+}
@@ -1,129 +1,395 @@
+use std::time::Duration;
+
 use bevy::{
     gltf::{Gltf, GltfMesh, GltfNode},
-    math::{vec2, vec3, Affine2},
-    pbr::{ExtendedMaterial, MaterialExtension},
+    math::{vec2, Affine2},
+    pbr::{
+        ExtendedMaterial, MaterialExtension, MaterialExtensionKey, MaterialExtensionPipeline,
+        VolumetricFogSettings,
+    },
     prelude::*,
     render::{
-        mesh::VertexAttributeValues,
-        render_resource::{AsBindGroup, ShaderRef, ShaderType},
-        texture::{ImageAddressMode, ImageLoaderSettings, ImageSampler, ImageSamplerDescriptor},
+        mesh::{Indices, MeshVertexBufferLayoutRef, VertexAttributeValues},
+        render_resource::{
+            AsBindGroup, RenderPipelineDescriptor, ShaderDefVal, ShaderRef, ShaderType,
+            SpecializedMeshPipelineError,
+        },
+        texture::ImageLoaderSettings,
     },
     scene::SceneInstance,
+    tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task},
+    utils::{HashSet, Instant},
 };
 use noise::{Fbm, MultiFractal, NoiseFn, Simplex};
-use rand::{rngs::StdRng, Rng, SeedableRng};
 
-use crate::plane::Plane;
+use crate::{
+    benchmark::GenerationTimings,
+    camera_spawn::StartPosition,
+    canopy_shadow::CanopyShadow,
+    cli::CliArgs,
+    expression::{self, ExpressionInputs, TerrainExpressions},
+    foliage::{FoliageMesh, TreeAlphaMode},
+    footprints::Footprints,
+    heightfield::TerrainHeightfield,
+    migration::{MigratedConfigs, TERRAIN_CONFIG_VERSION},
+    plane::Plane,
+    procgen::tree::{use_procedural_trees, ProceduralTreeConfig},
+    quality::Quality,
+    render_layers::SceneLayer,
+    scatter::{
+        scatter, PlacementGrid, ScatterAsset, ScatterInputs, ScatterLayer, ScatterModifiers,
+        ScatterPlacement,
+    },
+    scene_debug::ConfigCarrier,
+    sculpt::TerrainEdits,
+    shoreline::ShorelineConfig,
+    snowfall::Snowfall,
+    terrain_chunks::{split_terrain_mesh, TerrainChunk},
+    terrain_edge::TerrainEdge,
+    terrain_variants::{TerrainVariants, VARIANT_DEF},
+    texture_streaming::{terrain_sampler, TextureStreaming},
+    tree_edit::TreeEdits,
+    tree_lod::{build_tree_scene, start_tree_lod_generation, TreeLods, TreePrimitive},
+    tree_thinning::{default_far_tree_bands, validate_far_tree_bands, FarTree, FarTreeBand},
+    validation::{clamp_field, clamp_float_field, ValidationIssue},
+    water,
+    weather::Wetness,
+    SceneConfig,
+};
 
 #[derive(Resource)]
 pub struct TerrainResources {
     // material: Handle<StandardMaterial>,
     // tree: Handle<Scene>,
     trees_gltf: Handle<Gltf>,
-    trees: Vec<Handle<Scene>>,
+    pub trees: Vec<Handle<Scene>>,
+}
+
+impl TerrainResources {
+    /// Drops the tree glb once the variants are extracted, they keep the meshes and materials
+    /// they use alive and the rest of the glb can be unloaded. The glb isn't reloaded when it's
+    /// re-exported anymore after that.
+    pub fn purge(&mut self) {
+        if !self.trees.is_empty() {
+            self.trees_gltf = Handle::default();
+        }
+    }
+
+    /// Starts loading the tree glb, the procedural trees replace it when it's missing
+    pub fn load_trees(&mut self, asset_server: &AssetServer) {
+        if asset_exists(TREES_GLTF_PATH) {
+            self.trees_gltf = asset_server.load(TREES_GLTF_PATH);
+        }
+    }
+
+    pub fn trees_gltf(&self) -> &Handle<Gltf> {
+        &self.trees_gltf
+    }
 }
 
-pub fn setup_terrain_resources(mut commands: Commands, asset_server: Res<AssetServer>) {
+pub const TREES_GLTF_PATH: &str = "fir_tree_stylized.glb";
+
+pub fn setup_terrain_resources(mut commands: Commands) {
     commands.insert_resource(TerrainResources {
         // material: asset_server.load("forest_ground/forest_ground_04_4k.gltf#Material0"),
         // tree: asset_server.load("japanese_spruce_trees.glb#Scene3"),
-        trees_gltf: asset_server.load("fir_tree_stylized.glb"),
+        // Requested once the terrain is loaded, see the `loading` module
+        trees_gltf: Handle::default(),
         trees: vec![],
     });
 }
 
+/// Adds the tree variants to the resources and starts simplifying them, the terrain config needs
+/// to be flagged as changed to place them
+pub fn add_tree_variants(
+    commands: &mut Commands,
+    terrain_resources: &mut TerrainResources,
+    variants: Vec<Vec<TreePrimitive>>,
+    meshes: &Assets<Mesh>,
+    scenes: &mut Assets<Scene>,
+    terrain_config: &TerrainConfig,
+) {
+    for primitives in &variants {
+        // Full detail only until the LODs are generated
+        let scene_handle = scenes.add(build_tree_scene(
+            std::slice::from_ref(primitives),
+            &[],
+            terrain_config.lod_distance,
+            None,
+            None,
+        ));
+        terrain_resources.trees.push(scene_handle);
+    }
+    start_tree_lod_generation(commands, variants, meshes, terrain_config);
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn on_terrain_resource_loaded(
+    mut commands: Commands,
     mut terrain_resources: ResMut<TerrainResources>,
     gltf_assets: Res<Assets<Gltf>>,
     gltf_nodes: Res<Assets<GltfNode>>,
     gltf_meshes: Res<Assets<GltfMesh>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut pbr_materials: ResMut<Assets<StandardMaterial>>,
     mut scenes: ResMut<Assets<Scene>>,
     mut terrain_config: ResMut<TerrainConfig>,
+    cli: Res<CliArgs>,
     mut loaded: Local<bool>,
 ) {
-    if *loaded {
+    if *loaded || use_procedural_trees(&terrain_config, &cli) {
         return;
     }
     let Some(trees_gltf) = gltf_assets.get(&terrain_resources.trees_gltf) else {
         return;
     };
 
-    // tree 0
-    let mut scene_world = World::new();
-    let gltf_node = gltf_nodes.get(&trees_gltf.named_nodes["Branches"]).unwrap();
-    spawn_gltf_node(&mut scene_world, gltf_node, &gltf_meshes);
-    let gltf_node = gltf_nodes
-        .get(&trees_gltf.named_nodes["Tree_bark"])
-        .unwrap();
-    spawn_gltf_node(&mut scene_world, gltf_node, &gltf_meshes);
-    let scene_handle = scenes.add(Scene::new(scene_world));
-    terrain_resources.trees.push(scene_handle);
-
-    // tree 1
-    let mut scene_world = World::new();
-    let gltf_node = gltf_nodes
-        .get(&trees_gltf.named_nodes["Branches001"])
-        .unwrap();
-    spawn_gltf_node(&mut scene_world, gltf_node, &gltf_meshes);
-    let gltf_node = gltf_nodes
-        .get(&trees_gltf.named_nodes["Tree_bark001"])
-        .unwrap();
-    spawn_gltf_node(&mut scene_world, gltf_node, &gltf_meshes);
-    let scene_handle = scenes.add(Scene::new(scene_world));
-    terrain_resources.trees.push(scene_handle);
-
-    // tree 2
-    let mut scene_world = World::new();
-    let gltf_node = gltf_nodes
-        .get(&trees_gltf.named_nodes["Branches002"])
-        .unwrap();
-    spawn_gltf_node(&mut scene_world, gltf_node, &gltf_meshes);
-    let gltf_node = gltf_nodes
-        .get(&trees_gltf.named_nodes["Tree_bark002"])
-        .unwrap();
-    spawn_gltf_node(&mut scene_world, gltf_node, &gltf_meshes);
-    let scene_handle = scenes.add(Scene::new(scene_world));
-    terrain_resources.trees.push(scene_handle);
-
+    let variants = extract_tree_variants(
+        trees_gltf,
+        &terrain_config.tree_nodes,
+        &gltf_nodes,
+        &gltf_meshes,
+        &mut meshes,
+        &mut pbr_materials,
+    );
+    if variants.is_empty() {
+        error!("the tree gltf has none of the trees of tree_nodes, there are no trees to place");
+    }
+    add_tree_variants(
+        &mut commands,
+        &mut terrain_resources,
+        variants,
+        &meshes,
+        &mut scenes,
+        &terrain_config,
+    );
     terrain_config.set_changed();
 
-    println!("tree scene loaded");
+    info!("tree scene loaded");
     *loaded = true;
 }
 
-fn spawn_gltf_node(scene: &mut World, gltf_node: &GltfNode, gltf_meshes: &Assets<GltfMesh>) {
-    if let Some(gltf_mesh) = &gltf_node.mesh {
-        spawn_gltf_mesh(scene, gltf_mesh, gltf_meshes);
+/// The variants of the tree glb made of the `tree_nodes`, a primitive list per variant. The
+/// variants missing a node are skipped.
+fn extract_tree_variants(
+    trees_gltf: &Gltf,
+    tree_nodes: &[Vec<String>],
+    gltf_nodes: &Assets<GltfNode>,
+    gltf_meshes: &Assets<GltfMesh>,
+    meshes: &mut Assets<Mesh>,
+    pbr_materials: &mut Assets<StandardMaterial>,
+) -> Vec<Vec<TreePrimitive>> {
+    let mut variants = vec![];
+    for names in tree_nodes {
+        let nodes: Option<Vec<&GltfNode>> = names
+            .iter()
+            .map(|name| {
+                let node = trees_gltf
+                    .named_nodes
+                    .get(name.as_str())
+                    .and_then(|node| gltf_nodes.get(node));
+                if node.is_none() {
+                    warn!(
+                        "the tree gltf has no node named {name:?}, the tree {names:?} is skipped"
+                    );
+                }
+                node
+            })
+            .collect();
+        let Some(nodes) = nodes else {
+            continue;
+        };
+        let mut primitives = vec![];
+        for gltf_node in nodes {
+            collect_gltf_node(
+                &mut primitives,
+                gltf_node,
+                gltf_meshes,
+                meshes,
+                pbr_materials,
+            );
+        }
+        if primitives.is_empty() {
+            warn!("the tree {names:?} has nothing to draw, it's skipped");
+            continue;
+        }
+        variants.push(primitives);
     }
-    // recursion stops once there are no children
-    for gltf_node in &gltf_node.children {
-        spawn_gltf_node(scene, gltf_node, gltf_meshes);
+    variants
+}
+
+/// Rebuilds the tree scenes when the tree glb is re-exported while the assets are watched.
+///
+/// The trees are respawned in place: their scene handle is flagged as changed so the scene spawner
+/// replaces their instance, the entities and the placements are kept and the rest of the terrain
+/// is left alone. A re-import that fails keeps the previous asset and sends no event, a glb where
+/// none of the `tree_nodes` are found or with another number of trees keeps the previous scenes
+/// too. The gltf meshes are labeled assets of the glb, they're reloaded with it.
+#[allow(clippy::too_many_arguments)]
+pub fn reload_tree_gltf(
+    mut commands: Commands,
+    mut gltf_events: EventReader<AssetEvent<Gltf>>,
+    terrain_resources: Res<TerrainResources>,
+    gltf_assets: Res<Assets<Gltf>>,
+    gltf_nodes: Res<Assets<GltfNode>>,
+    gltf_meshes: Res<Assets<GltfMesh>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut pbr_materials: ResMut<Assets<StandardMaterial>>,
+    mut scenes: ResMut<Assets<Scene>>,
+    terrain_config: Res<TerrainConfig>,
+    cli: Res<CliArgs>,
+    mut trees: Query<(Entity, &mut Handle<Scene>), With<TreeInstance>>,
+) {
+    let trees_gltf = terrain_resources.trees_gltf.id();
+    // Every event is read so none is left for the next frame
+    let modified = gltf_events
+        .read()
+        .filter(|event| event.is_modified(trees_gltf))
+        .count()
+        > 0;
+    // Before the first load, on_terrain_resource_loaded takes care of it
+    if !modified
+        || terrain_resources.trees.is_empty()
+        || use_procedural_trees(&terrain_config, &cli)
+    {
+        return;
+    }
+    let Some(trees_gltf) = gltf_assets.get(&terrain_resources.trees_gltf) else {
+        return;
+    };
+    let variants = extract_tree_variants(
+        trees_gltf,
+        &terrain_config.tree_nodes,
+        &gltf_nodes,
+        &gltf_meshes,
+        &mut meshes,
+        &mut pbr_materials,
+    );
+    if variants.is_empty() {
+        error!("the reloaded tree gltf has no tree to draw, the previous trees are kept");
+        return;
+    }
+    // The spawned trees keep their variant, they're only rebuilt in place
+    if variants.len() != terrain_resources.trees.len() {
+        error!(
+            "the reloaded tree gltf has {} trees instead of {}, the previous trees are kept until \
+            the next launch",
+            variants.len(),
+            terrain_resources.trees.len()
+        );
+        return;
+    }
+
+    info!("tree gltf modified, rebuilding the trees");
+    for (scene, primitives) in terrain_resources.trees.iter().zip(&variants) {
+        scenes.insert(
+            scene,
+            build_tree_scene(
+                std::slice::from_ref(primitives),
+                &[],
+                terrain_config.lod_distance,
+                None,
+                None,
+            ),
+        );
+    }
+    // The LODs of the previous meshes are stale, the scenes are rebuilt once the new ones are done
+    commands.remove_resource::<TreeLods>();
+    start_tree_lod_generation(&mut commands, variants, &meshes, &terrain_config);
+    for (entity, mut scene) in &mut trees {
+        scene.set_changed();
+        // The reloaded materials need to be customized again
+        commands.entity(entity).insert(CustomizeTreeMaterial);
     }
 }
 
-fn spawn_gltf_mesh(
-    scene: &mut World,
-    gltf_mesh: &Handle<GltfMesh>,
+fn collect_gltf_node(
+    primitives: &mut Vec<TreePrimitive>,
+    gltf_node: &GltfNode,
     gltf_meshes: &Assets<GltfMesh>,
+    meshes: &mut Assets<Mesh>,
+    pbr_materials: &mut Assets<StandardMaterial>,
 ) {
-    let gltf_mesh = gltf_meshes.get(gltf_mesh).unwrap();
-    for primitive in &gltf_mesh.primitives {
-        scene.spawn(PbrBundle {
-            mesh: primitive.mesh.clone(),
-            material: if let Some(mat) = primitive.material.as_ref() {
-                mat.clone()
-            } else {
-                Default::default()
-            },
-            ..default()
-        });
+    if let Some(gltf_mesh) = gltf_node.mesh.as_ref().and_then(|m| gltf_meshes.get(m)) {
+        for primitive in &gltf_mesh.primitives {
+            let primitive = (
+                primitive.mesh.clone(),
+                primitive.material.clone().unwrap_or_default(),
+            );
+            primitives.extend(prepare_tree_primitive(
+                &gltf_node.name,
+                primitive,
+                meshes,
+                pbr_materials,
+            ));
+        }
+    }
+    // recursion stops once there are no children
+    for gltf_node in &gltf_node.children {
+        collect_gltf_node(primitives, gltf_node, gltf_meshes, meshes, pbr_materials);
     }
 }
 
-#[derive(Resource, Reflect, Debug)]
-#[reflect(Resource)]
+/// Makes sure a primitive of the gltf node `node` has what the tree material needs.
+///
+/// The missing tangents of normal mapped primitives are generated, when that fails the primitive
+/// gets a copy of its material without the normal map. Primitives without positions or uvs can't
+/// be drawn or simplified and are left out.
+fn prepare_tree_primitive(
+    node: &str,
+    (mesh_handle, material_handle): TreePrimitive,
+    meshes: &mut Assets<Mesh>,
+    pbr_materials: &mut Assets<StandardMaterial>,
+) -> Option<TreePrimitive> {
+    let Some(mesh) = meshes.get_mut(&mesh_handle) else {
+        error!("a primitive of the tree node {node:?} has no mesh, it's left out");
+        return None;
+    };
+    for attribute in [Mesh::ATTRIBUTE_POSITION, Mesh::ATTRIBUTE_UV_0] {
+        if !mesh.contains_attribute(attribute) {
+            error!(
+                "a primitive of the tree node {node:?} has no {} attribute, it's left out",
+                attribute.name
+            );
+            return None;
+        }
+    }
+
+    let normal_mapped = pbr_materials
+        .get(&material_handle)
+        .is_some_and(|material| material.normal_map_texture.is_some());
+    if !normal_mapped || mesh.contains_attribute(Mesh::ATTRIBUTE_TANGENT) {
+        return Some((mesh_handle, material_handle));
+    }
+    match mesh.generate_tangents() {
+        Ok(()) => {
+            info!("generated the missing tangents of a primitive of the tree node {node:?}");
+            Some((mesh_handle, material_handle))
+        }
+        Err(err) => {
+            warn!(
+                "failed to generate the tangents of a primitive of the tree node {node:?}, its \
+                normal map is disabled: {err}"
+            );
+            let material = pbr_materials.get(&material_handle)?.clone();
+            let material = pbr_materials.add(StandardMaterial {
+                normal_map_texture: None,
+                ..material
+            });
+            Some((mesh_handle, material))
+        }
+    }
+}
+
+#[derive(Resource, Reflect, Clone, Debug)]
+#[reflect(Resource, Default)]
 pub struct TerrainConfig {
+    /// Layout version of the config, see the `migration` module
+    pub version: u32,
     pub half_size: u32,
+    /// Quads on each side of the terrain chunks, the chunks out of view are culled, see the
+    /// `terrain_chunks` module
+    pub chunk_size: u32,
     pub seed: u32,
     pub frequency: f64,
     pub octaves: usize,
@@ -131,12 +397,61 @@ pub struct TerrainConfig {
     pub max_steepness: f32,
     pub use_depth_map: bool,
     pub rotation: f32,
+    /// Height of the water surface, nothing is placed below it
+    pub water_level: f32,
+    /// Fraction of the triangles kept in each simplified tree LOD, only read when the trees load
+    pub lod1_ratio: f32,
+    pub lod2_ratio: f32,
+    /// Distance at which the trees switch to the first LOD, every following LOD starts at twice
+    /// the previous distance and the trees are culled after the last one
+    pub lod_distance: f32,
+    /// Keep the shadows of the culled trees with a proxy only drawn in the shadow maps
+    pub shadow_proxies: bool,
+    /// Distance up to which the shadow proxies are used
+    pub shadow_proxy_distance: f32,
+    /// Fraction of the trees kept past the last LOD up to each distance, none culls them all
+    /// after the last LOD, see the `tree_thinning` module
+    pub far_tree_bands: Vec<FarTreeBand>,
+    /// Draw the trees with an instanced draw per mesh of every LOD instead of a scene per tree, see
+    /// the `tree_instancing` module
+    pub instanced_trees: bool,
+    /// Objects scattered on the terrain after the trees
+    pub scatter_layers: Vec<ScatterLayer>,
+    /// How much the ground darkens under the debris at the base of the trees, 0 disables it
+    pub root_blend_strength: f32,
+    /// Small tiling normal map in the assets blended over the ground close to the camera, none
+    /// disables the detail layer
+    pub detail_normal: Option<String>,
+    /// Tiling texture multiplying the ground color close to the camera, centered on mid gray
+    pub detail_albedo: Option<String>,
+    /// Size of one tile of the detail textures in meters
+    pub detail_size: f32,
+    pub detail_strength: f32,
+    /// Distance range where the detail fades out, it would only add noise further away
+    pub detail_fade_start: f32,
+    pub detail_fade_end: f32,
+    /// Reeds and lily pads in the shallow water
+    pub shoreline: ShorelineConfig,
+    /// Names of the glb nodes making each tree variant, a variant missing one of them is skipped.
+    /// Only read when the trees load or the glb is re-exported.
+    pub tree_nodes: Vec<Vec<String>>,
+    /// Generated trees used instead of the glb, only read when the trees load
+    pub procedural_trees: ProceduralTreeConfig,
+    /// Formulas varying the tree density and scale and the snow with the position
+    pub expressions: TerrainExpressions,
+    /// Where the camera starts, found again when it's missing or for another seed, see the
+    /// `camera_spawn` module
+    pub start: Option<StartPosition>,
+    /// Skirt, wall or island falloff at the border, see the `terrain_edge` module
+    pub edge: TerrainEdge,
 }
 
 impl Default for TerrainConfig {
     fn default() -> Self {
         Self {
+            version: TERRAIN_CONFIG_VERSION,
             half_size: 100,
+            chunk_size: 64,
             seed: 42,
             frequency: 1.0,
             octaves: 6,
@@ -144,169 +459,1157 @@ impl Default for TerrainConfig {
             max_steepness: 0.5,
             use_depth_map: false,
             rotation: 0.0,
+            water_level: 0.0,
+            lod1_ratio: 0.4,
+            lod2_ratio: 0.15,
+            lod_distance: 80.0,
+            shadow_proxies: true,
+            shadow_proxy_distance: 800.0,
+            far_tree_bands: default_far_tree_bands(),
+            instanced_trees: false,
+            scatter_layers: vec![],
+            root_blend_strength: 0.7,
+            detail_normal: None,
+            detail_albedo: None,
+            detail_size: 0.25,
+            detail_strength: 0.6,
+            detail_fade_start: 2.0,
+            detail_fade_end: 6.0,
+            shoreline: ShorelineConfig::default(),
+            tree_nodes: default_tree_nodes(),
+            procedural_trees: ProceduralTreeConfig::default(),
+            expressions: TerrainExpressions::default(),
+            start: None,
+            edge: TerrainEdge::None,
+        }
+    }
+}
+
+/// The branches and the bark of the three trees of `fir_tree_stylized.glb`
+fn default_tree_nodes() -> Vec<Vec<String>> {
+    [
+        ["Branches", "Tree_bark"],
+        ["Branches001", "Tree_bark001"],
+        ["Branches002", "Tree_bark002"],
+    ]
+    .iter()
+    .map(|nodes| nodes.iter().map(|name| name.to_string()).collect())
+    .collect()
+}
+
+impl TerrainConfig {
+    /// The trees are placed like any other scatter layer
+    pub fn tree_layer(&self) -> ScatterLayer {
+        ScatterLayer {
+            name: "trees".into(),
+            asset: ScatterAsset::Trees,
+            density: self.density,
+            min_height: f32::MIN,
+            max_height: f32::MAX,
+            min_steepness: 0.0,
+            max_steepness: self.max_steepness,
+            min_scale: 0.02,
+            max_scale: 0.025,
+            // try to scale it so trees are smaller next to water
+            height_scale_falloff: 0.01,
+            base_rotation: Quat::from_axis_angle(Vec3::X, 3.0 * std::f32::consts::FRAC_PI_2),
+            align_to_normal: 0.0,
+            water_distance: 0.01,
+            spacing: 0.0,
+            layer: GenerationLayer::Trees,
+        }
+    }
+
+    /// Layers that have to be generated again when the config goes from `previous` to this one
+    pub fn invalidated_layers(&self, previous: &TerrainConfig) -> HashSet<GenerationLayer> {
+        use GenerationLayer::*;
+        let mut layers = HashSet::default();
+        if self.half_size != previous.half_size
+            || self.seed != previous.seed
+            || self.frequency != previous.frequency
+            || self.octaves != previous.octaves
+            || self.rotation != previous.rotation
+            || self.water_level != previous.water_level
+            || self.edge != previous.edge
+        {
+            layers.extend([Terrain, Trees, Rocks, Props, Water]);
+        }
+        if self.chunk_size != previous.chunk_size
+            || self.use_depth_map != previous.use_depth_map
+            || self.root_blend_strength != previous.root_blend_strength
+            || self.max_steepness != previous.max_steepness
+            || self.detail_normal != previous.detail_normal
+            || self.detail_albedo != previous.detail_albedo
+            || self.detail_size != previous.detail_size
+            || self.detail_strength != previous.detail_strength
+            || self.detail_fade_start != previous.detail_fade_start
+            || self.detail_fade_end != previous.detail_fade_end
+            || self.expressions.snow != previous.expressions.snow
+        {
+            layers.insert(Terrain);
+        }
+        // The other layers keep their spacing to the trees so they move with them. The tree
+        // variants are picked with the same rng as the positions, another variant count moves them.
+        if self.density != previous.density
+            || self.max_steepness != previous.max_steepness
+            || self.expressions.density != previous.expressions.density
+            || self.expressions.scale != previous.expressions.scale
+            || self.tree_nodes != previous.tree_nodes
+            || self.procedural_trees != previous.procedural_trees
+        {
+            layers.extend([Trees, Rocks, Props]);
+        }
+        if self.scatter_layers != previous.scatter_layers {
+            layers.extend([Rocks, Props]);
+        }
+        if self.shoreline != previous.shoreline {
+            layers.insert(Water);
+        }
+        // The LOD, shadow proxy, far tree and instancing settings only rebuild the tree scenes, the
+        // spawned trees follow them
+        layers
+    }
+
+    /// Clamps every field in a range that can be generated and reports what was changed
+    pub fn validate(&mut self) -> Vec<ValidationIssue> {
+        let default = Self::default();
+        let mut issues = vec![];
+        clamp_field(&mut issues, "half_size", &mut self.half_size, 1, 2000);
+        clamp_field(&mut issues, "chunk_size", &mut self.chunk_size, 8, 512);
+        clamp_field(
+            &mut issues,
+            "octaves",
+            &mut self.octaves,
+            1,
+            Fbm::<Simplex>::MAX_OCTAVES,
+        );
+        if !self.frequency.is_finite() || self.frequency <= 0.0 {
+            issues.push(ValidationIssue {
+                field: "frequency",
+                value: format!("{:?}", self.frequency),
+                allowed: "a positive number".into(),
+                substituted: format!("{:?}", default.frequency),
+            });
+            self.frequency = default.frequency;
+        }
+        clamp_float_field(
+            &mut issues,
+            "density",
+            &mut self.density,
+            0.0,
+            1.0,
+            default.density,
+        );
+        clamp_float_field(
+            &mut issues,
+            "max_steepness",
+            &mut self.max_steepness,
+            0.01,
+            1.0,
+            default.max_steepness,
+        );
+        clamp_float_field(
+            &mut issues,
+            "rotation",
+            &mut self.rotation,
+            -std::f32::consts::TAU,
+            std::f32::consts::TAU,
+            default.rotation,
+        );
+        clamp_float_field(
+            &mut issues,
+            "water_level",
+            &mut self.water_level,
+            -100.0,
+            100.0,
+            default.water_level,
+        );
+        clamp_float_field(
+            &mut issues,
+            "lod1_ratio",
+            &mut self.lod1_ratio,
+            0.01,
+            1.0,
+            default.lod1_ratio,
+        );
+        clamp_float_field(
+            &mut issues,
+            "lod2_ratio",
+            &mut self.lod2_ratio,
+            0.01,
+            1.0,
+            default.lod2_ratio,
+        );
+        clamp_float_field(
+            &mut issues,
+            "lod_distance",
+            &mut self.lod_distance,
+            1.0,
+            f32::MAX,
+            default.lod_distance,
+        );
+        clamp_float_field(
+            &mut issues,
+            "shadow_proxy_distance",
+            &mut self.shadow_proxy_distance,
+            0.0,
+            f32::MAX,
+            default.shadow_proxy_distance,
+        );
+        clamp_float_field(
+            &mut issues,
+            "root_blend_strength",
+            &mut self.root_blend_strength,
+            0.0,
+            1.0,
+            default.root_blend_strength,
+        );
+        for (field, path) in [
+            ("detail_normal", &mut self.detail_normal),
+            ("detail_albedo", &mut self.detail_albedo),
+        ] {
+            if path.as_deref().is_some_and(|path| !asset_exists(path)) {
+                issues.push(ValidationIssue {
+                    field,
+                    value: format!("{path:?}"),
+                    allowed: "the path of an existing file in the assets folder".into(),
+                    substituted: "None".into(),
+                });
+                *path = None;
+            }
+        }
+        clamp_float_field(
+            &mut issues,
+            "detail_size",
+            &mut self.detail_size,
+            0.01,
+            100.0,
+            default.detail_size,
+        );
+        clamp_float_field(
+            &mut issues,
+            "detail_strength",
+            &mut self.detail_strength,
+            0.0,
+            1.0,
+            default.detail_strength,
+        );
+        clamp_float_field(
+            &mut issues,
+            "detail_fade_start",
+            &mut self.detail_fade_start,
+            0.0,
+            f32::MAX,
+            default.detail_fade_start,
+        );
+        clamp_float_field(
+            &mut issues,
+            "detail_fade_end",
+            &mut self.detail_fade_end,
+            self.detail_fade_start,
+            f32::MAX,
+            self.detail_fade_start.max(default.detail_fade_end),
+        );
+        let shoreline = &mut self.shoreline;
+        for (field, value, fallback) in [
+            (
+                "shoreline.reed_density",
+                &mut shoreline.reed_density,
+                default.shoreline.reed_density,
+            ),
+            (
+                "shoreline.lily_pad_density",
+                &mut shoreline.lily_pad_density,
+                default.shoreline.lily_pad_density,
+            ),
+        ] {
+            clamp_float_field(&mut issues, field, value, 0.0, 1.0, fallback);
+        }
+        for (field, value, fallback) in [
+            (
+                "shoreline.reed_min_depth",
+                &mut shoreline.reed_min_depth,
+                default.shoreline.reed_min_depth,
+            ),
+            (
+                "shoreline.reed_max_depth",
+                &mut shoreline.reed_max_depth,
+                default.shoreline.reed_max_depth,
+            ),
+            (
+                "shoreline.lily_pad_min_depth",
+                &mut shoreline.lily_pad_min_depth,
+                default.shoreline.lily_pad_min_depth,
+            ),
+            (
+                "shoreline.lily_pad_max_depth",
+                &mut shoreline.lily_pad_max_depth,
+                default.shoreline.lily_pad_max_depth,
+            ),
+        ] {
+            clamp_float_field(
+                &mut issues,
+                field,
+                value,
+                0.0,
+                water::MAX_WATER_DEPTH,
+                fallback,
+            );
         }
+        clamp_field(
+            &mut issues,
+            "shoreline.debris_count",
+            &mut shoreline.debris_count,
+            0,
+            5000,
+        );
+        clamp_float_field(
+            &mut issues,
+            "shoreline.debris_shore_distance",
+            &mut shoreline.debris_shore_distance,
+            0.5,
+            200.0,
+            default.shoreline.debris_shore_distance,
+        );
+        clamp_float_field(
+            &mut issues,
+            "shoreline.debris_drift_speed",
+            &mut shoreline.debris_drift_speed,
+            0.0,
+            2.0,
+            default.shoreline.debris_drift_speed,
+        );
+        issues.extend(self.procedural_trees.validate());
+        issues.extend(self.expressions.validate());
+        issues.extend(self.edge.validate());
+        issues.extend(validate_far_tree_bands(&mut self.far_tree_bands));
+        let empty_variants = self
+            .tree_nodes
+            .iter()
+            .filter(|nodes| nodes.is_empty())
+            .count();
+        if empty_variants > 0 {
+            issues.push(ValidationIssue {
+                field: "tree_nodes",
+                value: format!("{empty_variants} variants without nodes"),
+                allowed: "at least one node per variant".into(),
+                substituted: "the variants are skipped".into(),
+            });
+            self.tree_nodes.retain(|nodes| !nodes.is_empty());
+        }
+        if self.tree_nodes.is_empty() {
+            issues.push(ValidationIssue {
+                field: "tree_nodes",
+                value: "[]".into(),
+                allowed: "at least one variant".into(),
+                substituted: format!("{:?}", default.tree_nodes),
+            });
+            self.tree_nodes = default.tree_nodes;
+        }
+        self.scatter_layers.retain(|layer| {
+            let missing = match &layer.asset {
+                ScatterAsset::Trees => None,
+                ScatterAsset::Scenes(paths) if paths.is_empty() => Some("<no scenes>".to_string()),
+                ScatterAsset::Scenes(paths) => {
+                    paths.iter().find(|path| !asset_exists(path)).cloned()
+                }
+            };
+            let Some(missing) = missing else {
+                return true;
+            };
+            issues.push(ValidationIssue {
+                field: "scatter_layers",
+                value: format!("layer {:?} uses {missing}", layer.name),
+                allowed: "paths of existing files in the assets folder".into(),
+                substituted: "the layer is skipped".into(),
+            });
+            false
+        });
+        for layer in &mut self.scatter_layers {
+            let expected = match (&layer.asset, layer.layer) {
+                (ScatterAsset::Trees, GenerationLayer::Trees)
+                | (ScatterAsset::Scenes(_), GenerationLayer::Rocks | GenerationLayer::Props) => {
+                    None
+                }
+                (ScatterAsset::Trees, _) => Some(GenerationLayer::Trees),
+                (ScatterAsset::Scenes(_), _) => Some(GenerationLayer::Props),
+            };
+            if let Some(expected) = expected {
+                issues.push(ValidationIssue {
+                    field: "scatter_layers.layer",
+                    value: format!("{:?} for layer {:?}", layer.layer, layer.name),
+                    allowed: "Trees for the trees, Rocks or Props for the scenes".into(),
+                    substituted: format!("{expected:?}"),
+                });
+                layer.layer = expected;
+            }
+            clamp_float_field(
+                &mut issues,
+                "scatter_layers.density",
+                &mut layer.density,
+                0.0,
+                1.0,
+                0.0,
+            );
+            clamp_float_field(
+                &mut issues,
+                "scatter_layers.align_to_normal",
+                &mut layer.align_to_normal,
+                0.0,
+                1.0,
+                0.0,
+            );
+        }
+        issues
+    }
+}
+
+/// Checks that the file of an asset path exists, the label after `#` is ignored
+pub fn asset_exists(path: &str) -> bool {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let file = path.split('#').next().unwrap_or(path);
+        std::path::Path::new("assets").join(file).exists()
     }
+    #[cfg(target_arch = "wasm32")]
+    {
+        let _ = path;
+        true
+    }
+}
+
+/// Group of generated entities that are despawned and spawned again together
+#[derive(Reflect, Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum GenerationLayer {
+    /// The terrain mesh
+    Terrain,
+    Trees,
+    /// Scatter layers marked as rocks
+    Rocks,
+    /// Reeds and lily pads along the shore
+    Water,
+    /// Every other scatter layer, the default
+    #[default]
+    Props,
+    /// Placed by hand, never despawned by a terrain reload
+    UserPlaced,
+}
+
+/// Layer an entity was generated by, a terrain reload only despawns the layers invalidated by the
+/// config fields that changed
+#[derive(Component, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct GeneratedBy(pub GenerationLayer);
+
+/// Layers generated again by the last terrain reload
+#[derive(Resource, Default, Debug)]
+pub struct InvalidatedLayers(pub HashSet<GenerationLayer>);
+
+/// What the previous terrain reload was generated from
+pub struct LastGeneration {
+    config: TerrainConfig,
+    tree_variants: usize,
 }
 
+/// Marker for the terrain mesh
 #[derive(Component)]
-pub struct DespawnOnTerrainReload;
+pub struct Terrain;
 
-pub fn load_terrain_config(mut commands: Commands, asset_server: Res<AssetServer>) {
-    commands.spawn(DynamicSceneBundle {
-        scene: asset_server.load("terrain_config.scn.ron"),
-        ..default()
+/// Added to the objects spawned by the scatter layers of the config
+#[derive(Component)]
+pub struct ScatterInstance {
+    pub layer: String,
+}
+
+/// Added to every spawned tree, with what it was placed from to reproduce placement bugs
+#[derive(Component, Clone, Debug)]
+pub struct TreeInstance {
+    /// Index in [`TerrainResources::trees`]
+    pub variant: usize,
+    /// Scatter candidate the tree was generated from, `None` for the trees planted by hand. It's
+    /// the index of the terrain vertex, the tree layer rng is only drawn from for the candidates
+    /// above the water so it also gives the position in the seed stream.
+    pub candidate: Option<u32>,
+    /// Height of the terrain where the tree was placed, before the random offset
+    pub terrain_height: f32,
+    pub steepness: f32,
+    pub scale: f32,
+}
+
+pub struct TreePlacement {
+    pub position: Vec3,
+    pub scale: f32,
+    /// Index in [`TerrainResources::trees`]
+    pub variant: usize,
+}
+
+/// Every tree placed by the last terrain generation
+#[derive(Resource, Default)]
+pub struct TreePlacements {
+    pub trees: Vec<TreePlacement>,
+}
+
+/// Saves closer together than this only reload the terrain config once
+const CONFIG_RELOAD_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// The entity carrying the terrain config file, watched for changes by [`reload_terrain_config`]
+#[derive(Resource)]
+pub struct TerrainConfigCarrier {
+    entity: Entity,
+    /// When the file was last modified, the reload waits for the saves to settle
+    modified: Option<Instant>,
+}
+
+pub fn load_terrain_config(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    migrated_configs: Res<MigratedConfigs>,
+) {
+    let entity = commands
+        .spawn((
+            DynamicSceneBundle {
+                scene: migrated_configs.load(&asset_server, "terrain_config.scn.ron"),
+                ..default()
+            },
+            ConfigCarrier,
+        ))
+        .id();
+    commands.insert_resource(TerrainConfigCarrier {
+        entity,
+        modified: None,
     });
 }
 
+/// Applies the terrain config again when its file changes while the assets are watched.
+///
+/// The carrier's scene handle is flagged as changed so the scene spawner replaces its instance
+/// and writes the new config, no other carrier is spawned. The reload goes through the validation
+/// and [`on_terrain_config_loaded`] like the first load, which only regenerates the layers of the
+/// fields that changed and keeps the previous terrain until the new one is spawned. A file that
+/// fails to parse keeps the previous config. A config migrated in memory with
+/// `--no-config-writeback` isn't loaded from the file and isn't reloaded.
+pub fn reload_terrain_config(
+    mut scene_events: EventReader<AssetEvent<DynamicScene>>,
+    mut carrier: ResMut<TerrainConfigCarrier>,
+    mut scenes: Query<&mut Handle<DynamicScene>, With<ConfigCarrier>>,
+) {
+    let Ok(mut scene) = scenes.get_mut(carrier.entity) else {
+        return;
+    };
+    let id = scene.id();
+    // Every event is read so none is left for the next frame
+    let modified = scene_events
+        .read()
+        .filter(|event| event.is_modified(id))
+        .count()
+        > 0;
+    if modified {
+        carrier.modified = Some(Instant::now());
+    }
+    let Some(modified) = carrier.modified else {
+        return;
+    };
+    if modified.elapsed() < CONFIG_RELOAD_DEBOUNCE {
+        return;
+    }
+    info!("terrain config modified, reloading it");
+    carrier.modified = None;
+    scene.set_changed();
+}
+
+/// Terrain generated on the async compute pool, the previous one stays until it's done. A config
+/// change while it runs replaces it, dropping the task cancels it.
+#[derive(Resource)]
+pub struct TerrainGenerationTask {
+    task: Task<GeneratedTerrain>,
+    /// Layers replaced once it's done, with the ones of the generations it superseded
+    invalidated: HashSet<GenerationLayer>,
+    config: TerrainConfig,
+}
+
+/// Everything generated off the main thread, spawned by [`apply_generated_terrain`]
+struct GeneratedTerrain {
+    chunks: Vec<(TerrainChunk, Mesh)>,
+    repairs: MeshRepairs,
+    /// Whether the sculpted edits were applied to the heights
+    edited: bool,
+    heightfield: TerrainHeightfield,
+    /// Placements kept in every scatter layer, the trees first
+    layers: Vec<(ScatterLayer, Vec<ScatterPlacement>)>,
+    /// Trees planted by hand, placed on the new terrain
+    planted: Vec<(Transform, TreeInstance)>,
+    tree_placements: TreePlacements,
+    timings: GenerationTimings,
+}
+
+/// Starts generating the terrain again for the layers invalidated by the config change
 #[allow(clippy::too_many_arguments)]
 pub fn on_terrain_config_loaded(
     mut commands: Commands,
     terrain_config: Res<TerrainConfig>,
     terrain_resources: Res<TerrainResources>,
-    despawn_on_reload: Query<Entity, With<DespawnOnTerrainReload>>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut terrain_materials: ResMut<Assets<ExtendedMaterial<StandardMaterial, TerrainMaterial>>>,
-    asset_server: Res<AssetServer>,
+    terrain_edits: Res<TerrainEdits>,
+    tree_edits: Res<TreeEdits>,
+    tree_lods: Option<Res<TreeLods>>,
+    mut scenes: ResMut<Assets<Scene>>,
+    quality: Res<Quality>,
+    pending: Option<Res<TerrainGenerationTask>>,
+    mut last_generation: Local<Option<LastGeneration>>,
 ) {
-    println!("terrain config changed {:?}", terrain_config);
+    info!("terrain config changed");
+    debug!("{:?}", terrain_config);
 
-    // despawn any previous entities
-    for e in &despawn_on_reload {
-        commands.entity(e).despawn_recursive();
+    let mut invalidated = match &*last_generation {
+        Some(last) => terrain_config.invalidated_layers(&last.config),
+        None => HashSet::from_iter([
+            GenerationLayer::Terrain,
+            GenerationLayer::Trees,
+            GenerationLayer::Rocks,
+            GenerationLayer::Props,
+            GenerationLayer::Water,
+        ]),
+    };
+    // The trees are skipped until their scenes are loaded
+    if last_generation
+        .as_ref()
+        .is_some_and(|last| last.tree_variants != terrain_resources.trees.len())
+    {
+        invalidated.extend([
+            GenerationLayer::Trees,
+            GenerationLayer::Rocks,
+            GenerationLayer::Props,
+        ]);
+    }
+    // The generation still running never gets spawned, this one replaces its layers too
+    if let Some(pending) = &pending {
+        info!("superseding the terrain generation in progress");
+        invalidated.extend(pending.invalidated.iter().copied());
+    }
+    *last_generation = Some(LastGeneration {
+        config: terrain_config.clone(),
+        tree_variants: terrain_resources.trees.len(),
+    });
+    info!("regenerating {:?}", invalidated);
+
+    // Rebuild the tree scenes so they use the current LOD distance
+    if let Some(tree_lods) = tree_lods {
+        for (scene, lods) in terrain_resources.trees.iter().zip(&tree_lods.variants) {
+            // The instanced trees are drawn by the tree_instancing module
+            let tree_scene = if terrain_config.instanced_trees {
+                Scene::new(World::new())
+            } else {
+                build_tree_scene(
+                    &lods.levels,
+                    &lods.shadow_proxy,
+                    terrain_config.lod_distance,
+                    terrain_config
+                        .shadow_proxies
+                        .then_some(terrain_config.shadow_proxy_distance),
+                    terrain_config.far_tree_bands.last().map(|band| band.end),
+                )
+            };
+            scenes.insert(scene, tree_scene);
+        }
     }
 
-    // generate terrain with loaded configs
+    let config = terrain_config.clone();
+    let edits = terrain_edits
+        .matches(&config)
+        .then(|| terrain_edits.clone());
+    // The edits of another terrain are discarded once it's generated
+    let tree_edits = if tree_edits.matches(&config) {
+        tree_edits.clone()
+    } else {
+        TreeEdits::new(&config)
+    };
+    let tree_variants = terrain_resources.trees.len();
+    let tree_density = quality.tree_density(config.density);
+    let task = AsyncComputeTaskPool::get().spawn(async move {
+        generate_terrain(
+            &config,
+            edits.as_ref(),
+            &tree_edits,
+            tree_variants,
+            tree_density,
+        )
+    });
+    // Replacing the previous task drops it
+    commands.insert_resource(TerrainGenerationTask {
+        task,
+        invalidated,
+        config: terrain_config.clone(),
+    });
+}
+
+/// Generates the terrain mesh and scatters the layers on it, on the async compute pool
+fn generate_terrain(
+    terrain_config: &TerrainConfig,
+    edits: Option<&TerrainEdits>,
+    tree_edits: &TreeEdits,
+    tree_variants: usize,
+    tree_density: f32,
+) -> GeneratedTerrain {
+    let _span = info_span!("generate_terrain").entered();
+    let started = Instant::now();
     let fbm = Fbm::<Simplex>::new(terrain_config.seed)
         .set_frequency(terrain_config.frequency)
         .set_octaves(terrain_config.octaves);
 
-    let mut rng = StdRng::seed_from_u64(terrain_config.seed as u64);
-
-    let terrain_mesh = generate_terrain_mesh(&fbm, terrain_config.half_size);
-    let terrain_mesh =
-        terrain_mesh.rotated_by(Quat::from_axis_angle(Vec3::Y, terrain_config.rotation));
-
-    if !terrain_resources.trees.is_empty() {
-        let positions = terrain_mesh
+    let (terrain_mesh, repairs) = generate_terrain_mesh(
+        &fbm,
+        terrain_config.half_size,
+        terrain_config.water_level,
+        &terrain_config.edge,
+        edits,
+    );
+    let heightfield = TerrainHeightfield::from_positions(
+        terrain_config.half_size as f32 * 2.0,
+        terrain_config.rotation,
+        terrain_mesh
             .attribute(Mesh::ATTRIBUTE_POSITION)
             .and_then(|a| a.as_float3())
-            .unwrap();
-        let normals = terrain_mesh
-            .attribute(Mesh::ATTRIBUTE_NORMAL)
-            .and_then(|a| a.as_float3())
-            .unwrap();
-        for (pos, n) in positions.iter().zip(normals) {
-            let terrain_height = pos[1];
-            let steepness = Vec3::from_array(*n).cross(Vec3::Y).length();
-
-            if terrain_height < 0.01
-                || rng.gen_range(0.0..1.0) < 1.0 - terrain_config.density
-                || steepness > terrain_config.max_steepness
+            .unwrap(),
+    );
+    let mut terrain_mesh =
+        terrain_mesh.rotated_by(Quat::from_axis_angle(Vec3::Y, terrain_config.rotation));
+    let mesh_time = started.elapsed();
+
+    let mut tree_placements = TreePlacements::default();
+    if tree_variants == 0 {
+        info!("trees not ready yet");
+    }
+    // The heights come from the heightfield, only the rotated normals are read from the mesh
+    let normals = terrain_mesh
+        .attribute(Mesh::ATTRIBUTE_NORMAL)
+        .and_then(|a| a.as_float3())
+        .unwrap();
+    let mut grid = PlacementGrid::default();
+    // The expressions only apply to the trees
+    let tree_modifiers = ScatterModifiers {
+        density: expression::parse_field(&terrain_config.expressions.density, terrain_config.seed),
+        scale: expression::parse_field(&terrain_config.expressions.scale, terrain_config.seed),
+    };
+    let no_modifiers = ScatterModifiers::default();
+    let mut tree_layer = terrain_config.tree_layer();
+    tree_layer.density = tree_density;
+    let layers = std::iter::once(tree_layer).chain(terrain_config.scatter_layers.iter().cloned());
+    let mut scattered = vec![];
+    for layer in layers {
+        let _span = info_span!("scatter_layer", layer = %layer.name).entered();
+        let modifiers = if layer.asset == ScatterAsset::Trees {
+            &tree_modifiers
+        } else {
+            &no_modifiers
+        };
+        let placements = scatter(&ScatterInputs {
+            layer: &layer,
+            modifiers,
+            heightfield: &heightfield,
+            normals,
+            water_level: terrain_config.water_level,
+            variant_count: layer.variant_count(tree_variants),
+            seed: layer.rng_seed(terrain_config.seed),
+            grid: &grid,
+        });
+        // The placements are deterministic, the layers that are kept still go through the
+        // scatter so the following layers and the tree placements see the same instances
+        let mut kept = Vec::with_capacity(placements.len());
+        for placement in placements {
+            if terrain_config
+                .edge
+                .near_cut(&heightfield, placement.translation)
             {
                 continue;
             }
+            grid.insert(placement.translation);
+            if layer.asset == ScatterAsset::Trees {
+                if tree_edits.removed.contains(&placement.candidate) {
+                    continue;
+                }
+                tree_placements.trees.push(TreePlacement {
+                    position: placement.translation,
+                    scale: placement.scale,
+                    variant: placement.variant,
+                });
+            }
+            kept.push(placement);
+        }
+        scattered.push((layer, kept));
+    }
+    let base_rotation = terrain_config.tree_layer().base_rotation;
+    let mut planted = vec![];
+    for tree in &tree_edits.added {
+        if tree.variant >= tree_variants {
+            continue;
+        }
+        let Some(transform) = tree.transform(&heightfield, base_rotation) else {
+            continue;
+        };
+        tree_placements.trees.push(TreePlacement {
+            position: transform.translation,
+            scale: tree.scale,
+            variant: tree.variant,
+        });
+        planted.push((transform, tree.instance(&heightfield)));
+    }
+    let timings = GenerationTimings {
+        mesh: mesh_time,
+        trees: started.elapsed() - mesh_time,
+    };
 
-            // add a random offset to make it less grid like
-            let random_offset = vec3(
-                rng.gen_range(-0.25..0.25),
-                rng.gen_range(-0.05..0.0),
-                rng.gen_range(-0.25..0.25),
-            );
-            let translation = Vec3::from(*pos) + random_offset;
-
-            commands.spawn((
-                SceneBundle {
-                    scene: terrain_resources.trees[rng.gen_range(0..terrain_resources.trees.len())]
-                        .clone(),
-                    transform: Transform::from_translation(translation)
-                        .with_scale(Vec3::splat(
-                            // try to scale it so trees are smaller next to water
-                            rng.gen_range(0.02..0.025) * (1.0 - (terrain_height / 100.0)),
-                        ))
-                        .with_rotation(
-                            Quat::from_axis_angle(Vec3::X, 3.0 * std::f32::consts::FRAC_PI_2)
-                                .mul_quat(Quat::from_axis_angle(
-                                    Vec3::Z,
-                                    rng.gen_range(0.0..std::f32::consts::TAU),
-                                )),
-                        ),
-                    ..default()
-                },
-                CustomizeTreeMaterial,
-                DespawnOnTerrainReload,
-            ));
+    if let Some(VertexAttributeValues::Float32x2(uvs)) =
+        terrain_mesh.attribute_mut(Mesh::ATTRIBUTE_UV_1)
+    {
+        root_mask(uvs, &heightfield, &tree_placements.trees);
+    }
+    if let Some(snow) =
+        expression::parse_field(&terrain_config.expressions.snow, terrain_config.seed)
+    {
+        let snow = snow_mask(
+            &snow,
+            &heightfield,
+            &terrain_mesh,
+            terrain_config.water_level,
+        );
+        terrain_mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, snow);
+    }
+    let chunks = split_terrain_mesh(
+        &terrain_mesh,
+        (terrain_config.half_size * 2 + 2) as usize,
+        terrain_config.chunk_size as usize,
+    );
+
+    GeneratedTerrain {
+        chunks,
+        repairs,
+        edited: edits.is_some(),
+        heightfield,
+        layers: scattered,
+        planted,
+        tree_placements,
+        timings,
+    }
+}
+
+/// Replaces the invalidated layers with the generated ones once the task is done
+#[allow(clippy::too_many_arguments)]
+pub fn apply_generated_terrain(
+    mut commands: Commands,
+    mut task: ResMut<TerrainGenerationTask>,
+    terrain_resources: Res<TerrainResources>,
+    generated: Query<(Entity, &GeneratedBy)>,
+    mut user_placed_trees: Query<(&GeneratedBy, &mut Transform), With<TreeInstance>>,
+    terrain: Query<(&Handle<Mesh>, &TerrainChunk)>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut terrain_materials: ResMut<Assets<ExtendedMaterial<StandardMaterial, TerrainMaterial>>>,
+    texture_streaming: Res<TextureStreaming>,
+    mut tree_edits: ResMut<TreeEdits>,
+    asset_server: Res<AssetServer>,
+) {
+    let Some(generated_terrain) = block_on(future::poll_once(&mut task.task)) else {
+        return;
+    };
+    commands.remove_resource::<TerrainGenerationTask>();
+    let invalidated = std::mem::take(&mut task.invalidated);
+    let terrain_config = &task.config;
+    let GeneratedTerrain {
+        chunks,
+        repairs,
+        edited,
+        heightfield,
+        layers,
+        planted,
+        tree_placements,
+        timings,
+    } = generated_terrain;
+    if !repairs.is_empty() {
+        warn!(
+            "terrain config: the generated mesh had to be repaired ({repairs}), most likely \
+            because of {}",
+            repairs.suspect(terrain_config, edited)
+        );
+    }
+
+    // despawn the previous entities of the invalidated layers
+    {
+        let _span = info_span!("despawn_previous_terrain").entered();
+        for (e, GeneratedBy(layer)) in &generated {
+            if invalidated.contains(layer) {
+                commands.entity(e).despawn_recursive();
+            }
         }
-    } else {
-        println!("trees not ready yet");
     }
 
-    fn terrain_sampler() -> ImageSampler {
-        ImageSampler::Descriptor(ImageSamplerDescriptor {
-            label: Some("terrain sampler".into()),
-            address_mode_u: ImageAddressMode::Repeat,
-            address_mode_v: ImageAddressMode::Repeat,
-            ..ImageSamplerDescriptor::linear()
-        })
+    if !tree_edits.matches(terrain_config) {
+        if !tree_edits.is_empty() {
+            warn!(
+                "discarding the tree edits made on seed {} with half size {}",
+                tree_edits.seed, tree_edits.half_size
+            );
+        }
+        *tree_edits = TreeEdits::new(terrain_config);
+        // The planted trees go with their edits, they wouldn't be saved anymore
+        for (e, GeneratedBy(layer)) in &generated {
+            if *layer == GenerationLayer::UserPlaced {
+                commands.entity(e).despawn_recursive();
+            }
+        }
     }
-    commands
-        .spawn(MaterialMeshBundle {
-            mesh: meshes.add(terrain_mesh),
-            material: terrain_materials.add(ExtendedMaterial {
-                base: StandardMaterial {
-                    uv_transform: Affine2::from_scale(vec2(25.0, 25.0)),
-                    base_color_texture: Some(asset_server.load_with_settings(
-                        "forest_ground/textures/forest_ground_04_diff_4k.jpg",
-                        |s: &mut ImageLoaderSettings| {
-                            s.sampler = terrain_sampler();
+    for (layer, placements) in layers {
+        let generation_layer = layer.layer;
+        if !invalidated.contains(&generation_layer) {
+            continue;
+        }
+        let _span = info_span!("spawn_scatter_batch", instances = placements.len()).entered();
+        for placement in placements {
+            let transform = Transform::from_translation(placement.translation)
+                .with_scale(Vec3::splat(placement.scale))
+                .with_rotation(placement.rotation);
+            match &layer.asset {
+                ScatterAsset::Trees => {
+                    spawn_tree(
+                        &mut commands,
+                        &terrain_resources,
+                        transform,
+                        TreeInstance {
+                            variant: placement.variant,
+                            candidate: Some(placement.candidate),
+                            terrain_height: placement.terrain_height,
+                            steepness: placement.steepness,
+                            scale: placement.scale,
                         },
-                    )),
-                    normal_map_texture: Some(asset_server.load_with_settings(
-                        "forest_ground/textures/forest_ground_04_nor_gl_4k.jpg",
-                        |s: &mut ImageLoaderSettings| {
-                            s.sampler = terrain_sampler();
+                    );
+                }
+                ScatterAsset::Scenes(paths) => {
+                    commands.spawn((
+                        SceneBundle {
+                            scene: asset_server.load(&paths[placement.variant]),
+                            transform,
+                            ..default()
                         },
-                    )),
-                    perceptual_roughness: 1.0,
-                    metallic_roughness_texture: Some(asset_server.load_with_settings(
-                        "forest_ground/textures/forest_ground_04_rough_4k.jpg",
-                        |s: &mut ImageLoaderSettings| {
-                            s.sampler = terrain_sampler();
+                        ScatterInstance {
+                            layer: layer.name.clone(),
                         },
-                    )),
-                    parallax_depth_scale: 0.1,
-                    parallax_mapping_method: ParallaxMappingMethod::Relief { max_steps: 4 },
-                    depth_map: terrain_config.use_depth_map.then(|| {
-                        asset_server.load_with_settings(
-                            "forest_ground/textures/forest_ground_04_disp_4k.jpg",
-                            |s: &mut ImageLoaderSettings| {
-                                s.sampler = terrain_sampler();
-                            },
-                        )
-                    }),
-                    opaque_render_method: bevy::pbr::OpaqueRendererMethod::Deferred,
-                    double_sided: true,
-                    cull_mode: None,
-                    ..Default::default()
-                },
-                extension: TerrainMaterial {
-                    settings: TerrainMaterialSettings {
-                        max_steepness: terrain_config.max_steepness,
-                    },
-                },
+                        GeneratedBy(generation_layer),
+                    ));
+                }
+            }
+        }
+    }
+    // The planted trees are only spawned once, after that they follow the ground
+    let mut planted_spawned = false;
+    for (GeneratedBy(layer), mut transform) in &mut user_placed_trees {
+        if *layer != GenerationLayer::UserPlaced {
+            continue;
+        }
+        planted_spawned = true;
+        if let Some(height) = heightfield.height_at(transform.translation.xz()) {
+            transform.translation.y = height - 0.025;
+        }
+    }
+    if !planted_spawned {
+        for (transform, instance) in planted {
+            spawn_tree(&mut commands, &terrain_resources, transform, instance);
+        }
+    }
+    commands.insert_resource(timings);
+    if invalidated.contains(&GenerationLayer::Terrain) {
+        commands.insert_resource(heightfield);
+    }
+    if invalidated.contains(&GenerationLayer::Trees) {
+        commands.insert_resource(tree_placements);
+    }
+    let respawn_terrain = invalidated.contains(&GenerationLayer::Terrain);
+    commands.insert_resource(InvalidatedLayers(invalidated));
+
+    if !respawn_terrain {
+        // Same meshes apart from the root mask of the new trees, the chunks are kept
+        for (chunk, mesh) in chunks {
+            let handle = terrain
+                .iter()
+                .find_map(|(handle, spawned)| (*spawned == chunk).then_some(handle));
+            if let Some(handle) = handle {
+                meshes.insert(handle, mesh);
+            }
+        }
+        return;
+    }
+
+    let material = terrain_materials.add(ExtendedMaterial {
+        base: StandardMaterial {
+            uv_transform: Affine2::from_scale(vec2(25.0, 25.0)),
+            base_color_texture: Some(texture_streaming.ground_base_color.current()),
+            normal_map_texture: Some(texture_streaming.ground_normal.current()),
+            perceptual_roughness: 1.0,
+            metallic_roughness_texture: Some(texture_streaming.ground_roughness.current()),
+            parallax_depth_scale: 0.1,
+            parallax_mapping_method: ParallaxMappingMethod::Relief { max_steps: 4 },
+            depth_map: terrain_config
+                .use_depth_map
+                .then(|| texture_streaming.ground_depth.current()),
+            // Follows DefaultOpaqueRendererMethod, see the `render_method` module
+            opaque_render_method: bevy::pbr::OpaqueRendererMethod::Auto,
+            double_sided: true,
+            cull_mode: None,
+            ..Default::default()
+        },
+        extension: TerrainMaterial {
+            settings: TerrainMaterialSettings {
+                max_steepness: terrain_config.max_steepness,
+                // Set from the scene config by sync_terrain_material_settings
+                snow_height: f32::MAX,
+                puddle_amount: 0.0,
+                root_blend_strength: terrain_config.root_blend_strength,
+                haze_sky_tint: Vec4::ZERO,
+                haze_water_tint: Vec4::ZERO,
+                haze_start: 0.0,
+                water_level: terrain_config.water_level,
+                detail_tiling: terrain_config.half_size as f32 * 2.0 / terrain_config.detail_size,
+                detail_strength: terrain_config.detail_strength,
+                detail_fade_start: terrain_config.detail_fade_start,
+                detail_fade_end: terrain_config.detail_fade_end,
+                footprint_region: Vec4::ZERO,
+                canopy_region: Vec4::ZERO,
+                canopy_fade_start: 0.0,
+                canopy_fade_end: 0.0,
+            },
+            detail_normal: terrain_config
+                .detail_normal
+                .as_ref()
+                .map(|path| load_detail_texture(&asset_server, path)),
+            detail_albedo: terrain_config
+                .detail_albedo
+                .as_ref()
+                .map(|path| load_detail_texture(&asset_server, path)),
+            footprints: None,
+            canopy_shadow: None,
+        },
+    });
+    for (chunk, mesh) in chunks {
+        commands.spawn((
+            MaterialMeshBundle {
+                mesh: meshes.add(mesh),
+                material: material.clone(),
+                ..default()
+            },
+            Terrain,
+            chunk,
+            GeneratedBy(GenerationLayer::Terrain),
+            SceneLayer::MainScene.layers(),
+        ));
+    }
+}
+
+/// The detail textures hold data rather than colors and tile many times over the terrain
+fn load_detail_texture(asset_server: &AssetServer, path: &str) -> Handle<Image> {
+    asset_server.load_with_settings(path.to_string(), |s: &mut ImageLoaderSettings| {
+        s.is_srgb = false;
+        s.sampler = terrain_sampler();
+    })
+}
+
+pub fn spawn_tree(
+    commands: &mut Commands,
+    terrain_resources: &TerrainResources,
+    transform: Transform,
+    instance: TreeInstance,
+) -> Entity {
+    commands
+        .spawn((
+            SceneBundle {
+                scene: terrain_resources.trees[instance.variant].clone(),
+                transform,
+                ..default()
+            },
+            CustomizeTreeMaterial,
+            FarTree::default(),
+            GeneratedBy(match instance.candidate {
+                Some(_) => GenerationLayer::Trees,
+                None => GenerationLayer::UserPlaced,
             }),
-            ..default()
-        })
-        .insert(DespawnOnTerrainReload);
+            instance,
+        ))
+        .id()
 }
 
-fn get_terrain_height<T: NoiseFn<f64, 2>>(fbm: &Fbm<T>, pos: Vec2) -> f32 {
+/// Height of the generated terrain at a position of the un-rotated plane, before the edits
+pub fn get_terrain_height<T: NoiseFn<f64, 2>>(fbm: &Fbm<T>, pos: Vec2) -> f32 {
     let scale = 0.05;
     let pos = pos * scale;
     let pos = pos.as_dvec2();
     (fbm.get([pos.x, pos.y]) as f32) * 100.0
 }
 
-fn generate_terrain_mesh<T: NoiseFn<f64, 2>>(fbm: &Fbm<T>, half_size: u32) -> Mesh {
+/// Heights past this are clamped, far above anything the noise and the edits give but low enough
+/// for the normals to stay finite
+const MAX_TERRAIN_HEIGHT: f32 = 10_000.0;
+
+/// What had to be repaired in a generated terrain mesh, nothing with a sane config
+#[derive(Default, Debug)]
+struct MeshRepairs {
+    /// NaN or infinite heights, moved to the water level
+    non_finite_heights: usize,
+    /// Heights past [`MAX_TERRAIN_HEIGHT`]
+    clamped_heights: usize,
+    /// Triangles without an area, left out of the indices
+    degenerate_triangles: usize,
+    /// Normals that came out NaN or zero, pointing up instead
+    bad_normals: usize,
+    /// Why the tangents couldn't be generated, they all point along x instead
+    tangents: Option<String>,
+}
+
+impl MeshRepairs {
+    fn is_empty(&self) -> bool {
+        self.non_finite_heights == 0
+            && self.clamped_heights == 0
+            && self.degenerate_triangles == 0
+            && self.bad_normals == 0
+            && self.tangents.is_none()
+    }
+
+    /// The config value most likely behind the repairs
+    fn suspect(&self, terrain_config: &TerrainConfig, edited: bool) -> String {
+        if self.non_finite_heights > 0 || self.clamped_heights > 0 {
+            if edited {
+                return "the sculpted edits".into();
+            }
+            return format!(
+                "`frequency` ({:?}) with {} octaves",
+                terrain_config.frequency, terrain_config.octaves
+            );
+        }
+        format!("`half_size` ({})", terrain_config.half_size)
+    }
+}
+
+impl std::fmt::Display for MeshRepairs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut repairs = vec![];
+        for (count, what) in [
+            (self.non_finite_heights, "non finite heights"),
+            (self.clamped_heights, "clamped heights"),
+            (self.degenerate_triangles, "degenerate triangles"),
+            (self.bad_normals, "invalid normals"),
+        ] {
+            if count > 0 {
+                repairs.push(format!("{count} {what}"));
+            }
+        }
+        if let Some(err) = &self.tangents {
+            repairs.push(format!("default tangents: {err}"));
+        }
+        write!(f, "{}", repairs.join(", "))
+    }
+}
+
+fn generate_terrain_mesh<T: NoiseFn<f64, 2>>(
+    fbm: &Fbm<T>,
+    half_size: u32,
+    water_level: f32,
+    edge: &TerrainEdge,
+    edits: Option<&TerrainEdits>,
+) -> (Mesh, MeshRepairs) {
+    let mut repairs = MeshRepairs::default();
     let mut plane: Mesh = Plane {
         size: half_size as f32 * 2.0,
         subdivisions: half_size * 2,
@@ -315,17 +1618,201 @@ fn generate_terrain_mesh<T: NoiseFn<f64, 2>>(fbm: &Fbm<T>, half_size: u32) -> Me
 
     match plane.attribute_mut(Mesh::ATTRIBUTE_POSITION).unwrap() {
         VertexAttributeValues::Float32x3(vertices) => {
-            for pos in vertices {
-                pos[1] = get_terrain_height(fbm, vec2(pos[0], pos[2])) as f32;
+            let _span = info_span!("height_sampling", vertices = vertices.len()).entered();
+            let half_extent = half_size as f32;
+            for pos in vertices.iter_mut() {
+                let local = vec2(pos[0], pos[2]);
+                let height = get_terrain_height(fbm, local);
+                pos[1] = edge.height(local, height, half_extent, water_level);
+            }
+            if let Some(edits) = edits {
+                let resolution = (half_size * 2 + 2) as usize;
+                for (&(x, z), delta) in &edits.deltas {
+                    if let Some(pos) = vertices.get_mut(z as usize * resolution + x as usize) {
+                        pos[1] += delta;
+                    }
+                }
+            }
+            for pos in vertices.iter_mut() {
+                if !pos[1].is_finite() {
+                    repairs.non_finite_heights += 1;
+                    pos[1] = water_level;
+                } else if pos[1].abs() > MAX_TERRAIN_HEIGHT {
+                    repairs.clamped_heights += 1;
+                    pos[1] = pos[1].clamp(-MAX_TERRAIN_HEIGHT, MAX_TERRAIN_HEIGHT);
+                }
             }
         }
         _ => unreachable!(),
     }
 
+    let puddles = match plane.attribute(Mesh::ATTRIBUTE_POSITION).unwrap() {
+        VertexAttributeValues::Float32x3(vertices) => {
+            puddle_mask(vertices, (half_size * 2 + 2) as usize, water_level)
+        }
+        _ => unreachable!(),
+    };
+    // The mask goes in the second uv channel since the standard material doesn't use it, the
+    // tree roots are added to the y channel once the trees are placed
+    plane.insert_attribute(Mesh::ATTRIBUTE_UV_1, puddles);
+
+    let _span = info_span!("normals_and_tangents", vertices = plane.count_vertices()).entered();
+    repairs.degenerate_triangles = remove_degenerate_triangles(&mut plane);
     plane.compute_smooth_normals();
-    plane.generate_tangents().unwrap();
+    if let Some(VertexAttributeValues::Float32x3(normals)) =
+        plane.attribute_mut(Mesh::ATTRIBUTE_NORMAL)
+    {
+        for normal in normals.iter_mut() {
+            if !Vec3::from_array(*normal).is_normalized() {
+                repairs.bad_normals += 1;
+                *normal = Vec3::Y.to_array();
+            }
+        }
+    }
+    if let Err(err) = plane.generate_tangents() {
+        repairs.tangents = Some(err.to_string());
+        let tangents = vec![[1.0, 0.0, 0.0, 1.0]; plane.count_vertices()];
+        plane.insert_attribute(Mesh::ATTRIBUTE_TANGENT, tangents);
+    }
+
+    (plane, repairs)
+}
 
-    plane
+/// Drops the triangles without an area, which would give NaN normals and tangents. Returns how
+/// many there were.
+fn remove_degenerate_triangles(mesh: &mut Mesh) -> usize {
+    let Some(positions) = mesh
+        .attribute(Mesh::ATTRIBUTE_POSITION)
+        .and_then(|a| a.as_float3())
+    else {
+        return 0;
+    };
+    let Some(Indices::U32(indices)) = mesh.indices() else {
+        return 0;
+    };
+    let kept: Vec<u32> = indices
+        .chunks_exact(3)
+        .filter(|triangle| {
+            let [a, b, c] = [0, 1, 2].map(|i| Vec3::from_array(positions[triangle[i] as usize]));
+            let area = (b - a).cross(c - a).length();
+            area.is_finite() && area > f32::EPSILON
+        })
+        .flatten()
+        .copied()
+        .collect();
+    let removed = (indices.len() - kept.len()) / 3;
+    if removed > 0 {
+        mesh.insert_indices(Indices::U32(kept));
+    }
+    removed
+}
+
+/// Finds the flat depressions that hold water after rain.
+///
+/// A vertex lower than the average of its neighbours is in a depression, only the ones on
+/// almost flat ground are kept since water doesn't pool on slopes.
+fn puddle_mask(vertices: &[[f32; 3]], resolution: usize, water_level: f32) -> Vec<[f32; 2]> {
+    const MIN_DEPTH: f32 = 0.01;
+    const FULL_DEPTH: f32 = 0.08;
+    const MAX_SLOPE: f32 = 0.15;
+
+    let height = |x: usize, z: usize| vertices[z * resolution + x][1];
+    let cell_size = (vertices[1][0] - vertices[0][0]).abs().max(f32::EPSILON);
+
+    let mut mask = vec![0.0; vertices.len()];
+    for z in 1..resolution - 1 {
+        for x in 1..resolution - 1 {
+            let h = height(x, z);
+            if h < water_level {
+                continue;
+            }
+            let (left, right) = (height(x - 1, z), height(x + 1, z));
+            let (down, up) = (height(x, z - 1), height(x, z + 1));
+            let depth = (left + right + down + up) / 4.0 - h;
+            let slope = vec2(right - left, up - down).length() / (2.0 * cell_size);
+
+            let depression = ((depth - MIN_DEPTH) / (FULL_DEPTH - MIN_DEPTH)).clamp(0.0, 1.0);
+            let flat = (1.0 - slope / MAX_SLOPE).clamp(0.0, 1.0);
+            mask[z * resolution + x] = depression * flat;
+        }
+    }
+
+    // Blur it once so the puddles cover more than a single vertex
+    let mut blurred = vec![[0.0, 0.0]; vertices.len()];
+    for z in 1..resolution - 1 {
+        for x in 1..resolution - 1 {
+            let mut sum = 0.0;
+            for dz in 0..3 {
+                for dx in 0..3 {
+                    sum += mask[(z + dz - 1) * resolution + x + dx - 1];
+                }
+            }
+            let center = mask[z * resolution + x];
+            blurred[z * resolution + x][0] = (sum / 9.0 * 2.0).max(center).min(1.0);
+        }
+    }
+    blurred
+}
+
+/// Writes the debris around the base of each tree in the y channel of the terrain uvs.
+///
+/// The uvs are in the vertex order of the heightfield, which finds the vertices around the world
+/// space positions of the trees.
+fn root_mask(uvs: &mut [[f32; 2]], heightfield: &TerrainHeightfield, trees: &[TreePlacement]) {
+    /// Radius of the debris for a tree of scale 1, the tree models are in centimeters
+    const ROOT_RADIUS: f32 = 80.0;
+
+    for uv in uvs.iter_mut() {
+        uv[1] = 0.0;
+    }
+    for tree in trees {
+        let radius = ROOT_RADIUS * tree.scale;
+        for (x, z, distance) in heightfield.vertices_in_radius(tree.position.xz(), radius) {
+            let debris = 1.0 - smoothstep(0.3, 1.0, distance / radius);
+            let uv = &mut uvs[z * heightfield.resolution + x];
+            uv[1] = uv[1].max(debris);
+        }
+    }
+}
+
+/// Evaluates the snow expression for every vertex, in the alpha of the vertex colors since the
+/// uv channels are full. The color stays white so it doesn't tint the ground.
+fn snow_mask(
+    snow: &expression::Expression,
+    heightfield: &TerrainHeightfield,
+    terrain_mesh: &Mesh,
+    water_level: f32,
+) -> Vec<[f32; 4]> {
+    let normals = terrain_mesh
+        .attribute(Mesh::ATTRIBUTE_NORMAL)
+        .and_then(|a| a.as_float3())
+        .unwrap();
+    normals
+        .iter()
+        .enumerate()
+        .map(|(i, normal)| {
+            let position =
+                heightfield.world_position(i % heightfield.resolution, i / heightfield.resolution);
+            let steepness = Vec3::from_array(*normal).cross(Vec3::Y).length();
+            let inputs = ExpressionInputs::new(position, steepness, water_level);
+            let amount = snow.evaluate(&inputs);
+            [
+                1.0,
+                1.0,
+                1.0,
+                if amount.is_nan() {
+                    0.0
+                } else {
+                    amount.clamp(0.0, 1.0)
+                },
+            ]
+        })
+        .collect()
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
 }
 
 #[derive(Component)]
@@ -336,22 +1823,155 @@ pub fn customize_tree_material(
     handles: Query<(Entity, &Handle<StandardMaterial>)>,
     mut pbr_materials: ResMut<Assets<StandardMaterial>>,
     scene_manager: Res<SceneSpawner>,
+    alpha_mode: Res<TreeAlphaMode>,
+    mut bark_materials: Local<HashSet<AssetId<StandardMaterial>>>,
 ) {
+    let _span = info_span!(
+        "customize_tree_material",
+        instances = unloaded_instances.iter().len()
+    )
+    .entered();
     for (entity, instance) in unloaded_instances.iter() {
         if scene_manager.instance_is_ready(**instance) {
             commands.entity(entity).remove::<CustomizeTreeMaterial>();
         }
         // Iterate over all entities in scene (once it's loaded)
         let handles = handles.iter_many(scene_manager.iter_instance_entities(**instance));
-        for (_entity, material_handle) in handles {
+        for (entity, material_handle) in handles {
             let Some(material) = pbr_materials.get_mut(material_handle) else {
                 continue;
             };
+            // Only the branches are masked in the gltf, remember the rest before overriding it
+            if matches!(material.alpha_mode, AlphaMode::Opaque) {
+                bark_materials.insert(material_handle.id());
+            }
+            commands.entity(entity).insert(FoliageMesh {
+                original: material_handle.clone(),
+                bark: bark_materials.contains(&material_handle.id()),
+            });
+
+            apply_tree_material_overrides(material, alpha_mode.0);
+        }
+    }
+}
+
+/// The leaves are cut out with `alpha_mode` and the gltf materials are too shiny for a forest
+pub fn apply_tree_material_overrides(material: &mut StandardMaterial, alpha_mode: AlphaMode) {
+    material.alpha_mode = alpha_mode;
+    material.perceptual_roughness = 1.0;
+    material.metallic = 0.0;
+    material.reflectance = 0.0;
+}
+
+/// Keeps the weather dependent settings of the terrain material in sync, this also covers the
+/// new material created when the terrain is regenerated.
+///
+/// A change that needs another variant of the shader waits for it to be compiled, see the
+/// `terrain_variants` module.
+#[allow(clippy::too_many_arguments)]
+pub fn sync_terrain_material_settings(
+    mut commands: Commands,
+    scene_config: Res<SceneConfig>,
+    wetness: Res<Wetness>,
+    snowfall: Res<Snowfall>,
+    footprints: Res<Footprints>,
+    canopy_shadow: Res<CanopyShadow>,
+    heightfield: Option<Res<TerrainHeightfield>>,
+    mut variants: ResMut<TerrainVariants>,
+    fog: Query<&VolumetricFogSettings, With<Camera3d>>,
+    terrain: Query<
+        (
+            Entity,
+            &Handle<Mesh>,
+            &Handle<ExtendedMaterial<StandardMaterial, TerrainMaterial>>,
+        ),
+        With<Terrain>,
+    >,
+    mut terrain_materials: ResMut<Assets<ExtendedMaterial<StandardMaterial, TerrainMaterial>>>,
+) {
+    // Quantized so the material isn't prepared again every frame while the puddles dry
+    let puddle_amount = (wetness.puddle_amount * 64.0).round() / 64.0;
+    // The snow is left out of the shader while the snow line is over the highest point
+    let peak = heightfield.as_ref().map(|heightfield| {
+        let (x, z) = heightfield.highest_point();
+        heightfield.height(x, z)
+    });
+    let snow_height = if peak.is_some_and(|peak| peak > snowfall.snow_height) {
+        snowfall.snow_height
+    } else {
+        f32::MAX
+    };
+    let tint = |color: Color, w: f32| {
+        let color = color.to_linear();
+        Vec4::new(color.red, color.green, color.blue, w)
+    };
+    let haze_sky_tint = tint(
+        scene_config.aerial_perspective_sky_tint,
+        scene_config.aerial_perspective_strength,
+    );
+    let haze_water_tint = tint(scene_config.aerial_perspective_water_tint, 0.0);
+    // The volumetric fog already covers the ground close to the camera
+    let haze_start = fog.get_single().map_or(0.0, |fog| fog.max_depth);
+    let footprint_region = footprints.region();
+    let canopy_region = canopy_shadow.region();
+    let canopy_fade = canopy_shadow.fade();
+    // The terrain chunks share their material, it's only updated once
+    let mut seen = HashSet::default();
+    for (entity, mesh, handle) in &terrain {
+        if !seen.insert(handle.id()) {
+            continue;
+        }
+        let Some(material) = terrain_materials.get(handle) else {
+            continue;
+        };
+        let settings = &material.extension.settings;
+        let outdated = settings.snow_height != snow_height
+            || settings.puddle_amount != puddle_amount
+            || settings.haze_sky_tint != haze_sky_tint
+            || settings.haze_water_tint != haze_water_tint
+            || settings.haze_start != haze_start
+            || settings.footprint_region != footprint_region
+            || material.extension.footprints.as_ref() != footprints.image()
+            || settings.canopy_region != canopy_region
+            || settings.canopy_fade_start != canopy_fade.x
+            || settings.canopy_fade_end != canopy_fade.y
+            || material.extension.canopy_shadow.as_ref() != canopy_shadow.image();
+        if !outdated {
+            if variants.is_pending(entity) {
+                // Back to the live variant before the new one was compiled
+                variants.cancel(&mut commands);
+            }
+            continue;
+        }
 
-            material.alpha_mode = AlphaMode::Mask(0.5);
-            material.perceptual_roughness = 1.0;
-            material.metallic = 0.0;
-            material.reflectance = 0.0;
+        let mut updated = material.clone();
+        let settings = &mut updated.extension.settings;
+        settings.snow_height = snow_height;
+        settings.puddle_amount = puddle_amount;
+        settings.haze_sky_tint = haze_sky_tint;
+        settings.haze_water_tint = haze_water_tint;
+        settings.haze_start = haze_start;
+        settings.footprint_region = footprint_region;
+        settings.canopy_region = canopy_region;
+        settings.canopy_fade_start = canopy_fade.x;
+        settings.canopy_fade_end = canopy_fade.y;
+        updated.extension.footprints = footprints.image().cloned();
+        updated.extension.canopy_shadow = canopy_shadow.image().cloned();
+        let live = TerrainMaterialKey::from(&material.extension);
+        let key = TerrainMaterialKey::from(&updated.extension);
+        if variants.can_switch(live, key) {
+            if variants.is_pending(entity) {
+                variants.cancel(&mut commands);
+            }
+            terrain_materials.insert(handle, updated);
+        } else {
+            variants.request(
+                &mut commands,
+                entity,
+                mesh.clone(),
+                updated,
+                &mut terrain_materials,
+            );
         }
     }
 }
@@ -359,18 +1979,436 @@ pub fn customize_tree_material(
 #[derive(Clone, Copy, ShaderType)]
 pub struct TerrainMaterialSettings {
     max_steepness: f32,
+    /// Altitude above which the ground is covered in snow
+    snow_height: f32,
+    /// How much water the depressions hold, scales the baked puddle mask
+    puddle_amount: f32,
+    /// Scales the baked debris mask around the tree roots
+    root_blend_strength: f32,
+    /// rgb: color of the horizon haze over the land, a: strength of the aerial perspective
+    haze_sky_tint: Vec4,
+    /// rgb: color of the haze when looking low across the water
+    haze_water_tint: Vec4,
+    /// Distance from the camera where the haze starts
+    haze_start: f32,
+    water_level: f32,
+    /// Repeats of the detail textures across the terrain uvs
+    detail_tiling: f32,
+    detail_strength: f32,
+    detail_fade_start: f32,
+    detail_fade_end: f32,
+    /// xy: world origin of the footprint texture, z: its extent, w: 1 to show the footprints
+    footprint_region: Vec4,
+    /// xy: world origin of the canopy texture, z: its extent, w: strength of the darkening
+    canopy_region: Vec4,
+    /// Distances from the camera between which the canopy darkening fades in
+    canopy_fade_start: f32,
+    canopy_fade_end: f32,
 }
 
 #[derive(Asset, TypePath, AsBindGroup, Clone)]
+#[bind_group_data(TerrainMaterialKey)]
 pub struct TerrainMaterial {
     // #[texture(100)]
     // ground_displacement: Handle<Image>,
     #[uniform(100)]
     settings: TerrainMaterialSettings,
+    #[texture(101)]
+    #[sampler(102)]
+    detail_normal: Option<Handle<Image>>,
+    #[texture(103)]
+    #[sampler(104)]
+    detail_albedo: Option<Handle<Image>>,
+    /// Set by sync_terrain_material_settings, see the `footprints` module
+    #[texture(105)]
+    #[sampler(106)]
+    footprints: Option<Handle<Image>>,
+    /// Set by sync_terrain_material_settings, see the `canopy_shadow` module
+    #[texture(107)]
+    #[sampler(108)]
+    canopy_shadow: Option<Handle<Image>>,
+}
+
+/// Shader defs of the terrain material, every feature is only compiled in when the material uses
+/// it. Each combination is a variant of the shader, see the `terrain_variants` module.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct TerrainMaterialKey {
+    detail_normal: bool,
+    detail_albedo: bool,
+    footprints: bool,
+    /// The snow line is under the highest point of the terrain
+    snow: bool,
+    puddles: bool,
+    canopy_shadow: bool,
+}
+
+impl TerrainMaterialKey {
+    /// One bit per feature, identifies the variant in the pipeline cache
+    pub fn bits(self) -> u32 {
+        [
+            self.detail_normal,
+            self.detail_albedo,
+            self.footprints,
+            self.snow,
+            self.puddles,
+            self.canopy_shadow,
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, enabled)| (*enabled as u32) << i)
+        .sum()
+    }
+}
+
+impl From<&TerrainMaterial> for TerrainMaterialKey {
+    fn from(material: &TerrainMaterial) -> Self {
+        Self {
+            detail_normal: material.detail_normal.is_some(),
+            detail_albedo: material.detail_albedo.is_some(),
+            footprints: material.footprints.is_some(),
+            snow: material.settings.snow_height < f32::MAX,
+            puddles: material.settings.puddle_amount > 0.0,
+            canopy_shadow: material.canopy_shadow.is_some(),
+        }
+    }
 }
 
 impl MaterialExtension for TerrainMaterial {
+    fn fragment_shader() -> ShaderRef {
+        "terrain.wgsl".into()
+    }
+
     fn deferred_fragment_shader() -> ShaderRef {
         "terrain.wgsl".into()
     }
+
+    fn specialize(
+        _pipeline: &MaterialExtensionPipeline,
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayoutRef,
+        key: MaterialExtensionKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        if let Some(fragment) = descriptor.fragment.as_mut() {
+            let key = key.bind_group_data;
+            fragment
+                .shader_defs
+                .push(ShaderDefVal::UInt(VARIANT_DEF.into(), key.bits()));
+            if key.detail_normal {
+                fragment.shader_defs.push("TERRAIN_DETAIL_NORMAL".into());
+            }
+            if key.detail_albedo {
+                fragment.shader_defs.push("TERRAIN_DETAIL_ALBEDO".into());
+            }
+            if key.footprints {
+                fragment.shader_defs.push("TERRAIN_FOOTPRINTS".into());
+            }
+            if key.snow {
+                fragment.shader_defs.push("TERRAIN_SNOW".into());
+            }
+            if key.puddles {
+                fragment.shader_defs.push("TERRAIN_PUDDLES".into());
+            }
+            if key.canopy_shadow {
+                fragment.shader_defs.push("TERRAIN_CANOPY_SHADOW".into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn layers<const N: usize>(layers: [GenerationLayer; N]) -> HashSet<GenerationLayer> {
+        HashSet::from_iter(layers)
+    }
+
+    #[test]
+    fn an_unchanged_config_invalidates_nothing() {
+        let config = TerrainConfig::default();
+        assert!(config.invalidated_layers(&config.clone()).is_empty());
+    }
+
+    #[test]
+    fn the_density_only_invalidates_the_scattered_layers() {
+        let previous = TerrainConfig::default();
+        let config = TerrainConfig {
+            density: 0.8,
+            ..default()
+        };
+        use GenerationLayer::*;
+        assert_eq!(
+            config.invalidated_layers(&previous),
+            layers([Trees, Rocks, Props])
+        );
+    }
+
+    #[test]
+    fn the_seed_invalidates_every_generated_layer() {
+        let previous = TerrainConfig::default();
+        let config = TerrainConfig {
+            seed: previous.seed + 1,
+            ..default()
+        };
+        use GenerationLayer::*;
+        assert_eq!(
+            config.invalidated_layers(&previous),
+            layers([Terrain, Trees, Rocks, Props, Water])
+        );
+    }
+
+    #[test]
+    fn the_tree_variants_invalidate_the_trees() {
+        let previous = TerrainConfig::default();
+        let mut config = TerrainConfig::default();
+        config.tree_nodes.pop();
+        use GenerationLayer::*;
+        assert_eq!(
+            config.invalidated_layers(&previous),
+            layers([Trees, Rocks, Props])
+        );
+
+        let mut config = TerrainConfig::default();
+        config.procedural_trees.enabled = !previous.procedural_trees.enabled;
+        assert_eq!(
+            config.invalidated_layers(&previous),
+            layers([Trees, Rocks, Props])
+        );
+    }
+
+    #[test]
+    fn scatter_layers_are_kept_in_a_layer_they_can_be_spawned_in() {
+        let mut config = TerrainConfig::default();
+        let mut trees = config.tree_layer();
+        trees.layer = GenerationLayer::Props;
+        let mut scenes = config.tree_layer();
+        scenes.asset = ScatterAsset::Scenes(vec![TREES_GLTF_PATH.into()]);
+        scenes.layer = GenerationLayer::UserPlaced;
+        let mut rocks = scenes.clone();
+        rocks.layer = GenerationLayer::Rocks;
+        config.scatter_layers = vec![trees, scenes, rocks];
+
+        let issues = config.validate();
+        assert_eq!(issues.len(), 2, "{issues:?}");
+        let fixed: Vec<_> = config
+            .scatter_layers
+            .iter()
+            .map(|layer| layer.layer)
+            .collect();
+        assert_eq!(
+            fixed,
+            [
+                GenerationLayer::Trees,
+                GenerationLayer::Props,
+                GenerationLayer::Rocks
+            ]
+        );
+    }
+
+    /// A cube with a normal mapped material, like the bark of the tree glb
+    fn fabricated_primitive(
+        meshes: &mut Assets<Mesh>,
+        pbr_materials: &mut Assets<StandardMaterial>,
+        mesh: Mesh,
+        normal_mapped: bool,
+    ) -> TreePrimitive {
+        let material = StandardMaterial {
+            normal_map_texture: normal_mapped.then(Handle::default),
+            ..default()
+        };
+        (meshes.add(mesh), pbr_materials.add(material))
+    }
+
+    #[test]
+    fn missing_tangents_are_generated() {
+        let mut meshes = Assets::<Mesh>::default();
+        let mut pbr_materials = Assets::<StandardMaterial>::default();
+        let mesh = Mesh::from(Cuboid::default());
+        assert!(!mesh.contains_attribute(Mesh::ATTRIBUTE_TANGENT));
+        let primitive = fabricated_primitive(&mut meshes, &mut pbr_materials, mesh, true);
+
+        let prepared = prepare_tree_primitive(
+            "Tree_bark",
+            primitive.clone(),
+            &mut meshes,
+            &mut pbr_materials,
+        )
+        .unwrap();
+        assert_eq!(prepared, primitive);
+        assert!(meshes
+            .get(&prepared.0)
+            .unwrap()
+            .contains_attribute(Mesh::ATTRIBUTE_TANGENT));
+    }
+
+    #[test]
+    fn the_normal_map_is_dropped_when_the_tangents_cant_be_generated() {
+        let mut meshes = Assets::<Mesh>::default();
+        let mut pbr_materials = Assets::<StandardMaterial>::default();
+        // The tangents are generated from the normals
+        let mesh = Mesh::from(Cuboid::default()).with_removed_attribute(Mesh::ATTRIBUTE_NORMAL);
+        let primitive = fabricated_primitive(&mut meshes, &mut pbr_materials, mesh, true);
+
+        let (mesh, material) = prepare_tree_primitive(
+            "Tree_bark",
+            primitive.clone(),
+            &mut meshes,
+            &mut pbr_materials,
+        )
+        .unwrap();
+        assert_eq!(mesh, primitive.0);
+        assert_ne!(material, primitive.1);
+        assert!(pbr_materials
+            .get(&material)
+            .unwrap()
+            .normal_map_texture
+            .is_none());
+        // The original material is left alone, other primitives may use it
+        assert!(pbr_materials
+            .get(&primitive.1)
+            .unwrap()
+            .normal_map_texture
+            .is_some());
+    }
+
+    #[test]
+    fn primitives_without_a_normal_map_are_kept_as_they_are() {
+        let mut meshes = Assets::<Mesh>::default();
+        let mut pbr_materials = Assets::<StandardMaterial>::default();
+        let mesh = Mesh::from(Cuboid::default()).with_removed_attribute(Mesh::ATTRIBUTE_NORMAL);
+        let primitive = fabricated_primitive(&mut meshes, &mut pbr_materials, mesh, false);
+
+        let prepared = prepare_tree_primitive(
+            "Branches",
+            primitive.clone(),
+            &mut meshes,
+            &mut pbr_materials,
+        );
+        assert_eq!(prepared, Some(primitive.clone()));
+        assert!(!meshes
+            .get(&primitive.0)
+            .unwrap()
+            .contains_attribute(Mesh::ATTRIBUTE_TANGENT));
+    }
+
+    #[test]
+    fn primitives_that_cant_be_drawn_are_left_out() {
+        let mut meshes = Assets::<Mesh>::default();
+        let mut pbr_materials = Assets::<StandardMaterial>::default();
+        for attribute in [Mesh::ATTRIBUTE_POSITION, Mesh::ATTRIBUTE_UV_0] {
+            let mesh = Mesh::from(Cuboid::default()).with_removed_attribute(attribute);
+            let primitive = fabricated_primitive(&mut meshes, &mut pbr_materials, mesh, true);
+            assert_eq!(
+                prepare_tree_primitive("Branches", primitive, &mut meshes, &mut pbr_materials),
+                None
+            );
+        }
+
+        // A handle to a mesh that isn't loaded
+        let primitive = (
+            Handle::default(),
+            pbr_materials.add(StandardMaterial::default()),
+        );
+        assert_eq!(
+            prepare_tree_primitive("Branches", primitive, &mut meshes, &mut pbr_materials),
+            None
+        );
+    }
+
+    /// Generates the terrain mesh of `config`, checking that every attribute is finite
+    fn generate_finite_mesh(config: &TerrainConfig, edits: Option<&TerrainEdits>) -> MeshRepairs {
+        let fbm = Fbm::<Simplex>::new(config.seed)
+            .set_frequency(config.frequency)
+            .set_octaves(config.octaves);
+        let (mesh, repairs) = generate_terrain_mesh(
+            &fbm,
+            config.half_size,
+            config.water_level,
+            &config.edge,
+            edits,
+        );
+        for attribute in [
+            Mesh::ATTRIBUTE_POSITION,
+            Mesh::ATTRIBUTE_NORMAL,
+            Mesh::ATTRIBUTE_TANGENT,
+            Mesh::ATTRIBUTE_UV_0,
+            Mesh::ATTRIBUTE_UV_1,
+        ] {
+            let finite = match mesh.attribute(attribute.id) {
+                Some(VertexAttributeValues::Float32x2(values)) => {
+                    values.iter().flatten().all(|value| value.is_finite())
+                }
+                Some(VertexAttributeValues::Float32x3(values)) => {
+                    values.iter().flatten().all(|value| value.is_finite())
+                }
+                Some(VertexAttributeValues::Float32x4(values)) => {
+                    values.iter().flatten().all(|value| value.is_finite())
+                }
+                _ => false,
+            };
+            assert!(
+                finite,
+                "{} isn't finite, repaired {repairs}",
+                attribute.name
+            );
+        }
+        repairs
+    }
+
+    #[test]
+    fn sane_configs_need_no_repairs() {
+        let config = TerrainConfig {
+            half_size: 16,
+            ..default()
+        };
+        let repairs = generate_finite_mesh(&config, None);
+        assert!(repairs.is_empty(), "{repairs}");
+    }
+
+    #[test]
+    fn pathological_configs_give_finite_meshes() {
+        for config in [
+            TerrainConfig {
+                half_size: 0,
+                ..default()
+            },
+            TerrainConfig {
+                half_size: 1,
+                ..default()
+            },
+            TerrainConfig {
+                half_size: 16,
+                frequency: 1e6,
+                octaves: 1,
+                ..default()
+            },
+            TerrainConfig {
+                half_size: 16,
+                frequency: f64::MAX,
+                ..default()
+            },
+        ] {
+            generate_finite_mesh(&config, None);
+        }
+    }
+
+    #[test]
+    fn broken_edits_are_repaired() {
+        let config = TerrainConfig {
+            half_size: 16,
+            ..default()
+        };
+        let edits = TerrainEdits {
+            seed: config.seed,
+            half_size: config.half_size,
+            deltas: BTreeMap::from([((1, 1), f32::NAN), ((2, 2), f32::INFINITY), ((3, 3), 1e30)]),
+        };
+        let repairs = generate_finite_mesh(&config, Some(&edits));
+        assert_eq!(repairs.non_finite_heights, 2);
+        assert_eq!(repairs.clamped_heights, 1);
+        assert_eq!(repairs.suspect(&config, true), "the sculpted edits");
+    }
 }
@@ -0,0 +1,130 @@
+//! The terrain split in chunks so the parts out of view are culled.
+//!
+//! The terrain used to be a single mesh. Past a `half_size` of ~200 it was always partly in view,
+//! so all of it was drawn, and every sculpt stroke uploaded all of it again. It's still generated
+//! as one grid, since the heightfield and the scatter read the whole grid and the normals have no
+//! seams that way. Then it's cut in chunks of `chunk_size` quads, each one its own entity with its
+//! own mesh and bounds. They all share the terrain material.
+//!
+//! A chunk also keeps a border one vertex wide from its neighbours, which isn't part of any
+//! triangle. The normals around a sculpted vertex on the edge of a chunk are recomputed from the
+//! same quads as in the neighbouring chunk, so the seam stays invisible.
+
+use bevy::{
+    prelude::*,
+    render::mesh::{Indices, MeshVertexAttribute, PrimitiveTopology, VertexAttributeValues},
+};
+
+/// Attributes of the terrain mesh copied to the chunks when they're there
+const CHUNK_ATTRIBUTES: [MeshVertexAttribute; 6] = [
+    Mesh::ATTRIBUTE_POSITION,
+    Mesh::ATTRIBUTE_NORMAL,
+    Mesh::ATTRIBUTE_UV_0,
+    Mesh::ATTRIBUTE_UV_1,
+    Mesh::ATTRIBUTE_TANGENT,
+    Mesh::ATTRIBUTE_COLOR,
+];
+
+/// Part of the terrain grid drawn by one of the terrain entities
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TerrainChunk {
+    /// First vertex of the chunk mesh in the terrain grid, border included
+    origin: UVec2,
+    /// Vertices of the chunk mesh along x and z, border included
+    size: UVec2,
+}
+
+impl TerrainChunk {
+    pub fn size(&self) -> UVec2 {
+        self.size
+    }
+
+    /// Index in the chunk mesh of the vertex at `x`, `z` in the terrain grid, if the chunk has it
+    pub fn local_index(&self, x: usize, z: usize) -> Option<usize> {
+        let x = x.checked_sub(self.origin.x as usize)?;
+        let z = z.checked_sub(self.origin.y as usize)?;
+        let width = self.size.x as usize;
+        (x < width && z < self.size.y as usize).then_some(z * width + x)
+    }
+}
+
+/// Cuts `mesh`, the whole terrain grid of `resolution` vertices per side, in chunks of
+/// `chunk_size` quads per side. The triangles removed from the terrain mesh stay removed.
+pub fn split_terrain_mesh(
+    mesh: &Mesh,
+    resolution: usize,
+    chunk_size: usize,
+) -> Vec<(TerrainChunk, Mesh)> {
+    let quads = resolution as u32 - 1;
+    let chunk_size = chunk_size.max(1) as u32;
+    let chunks_per_side = quads.div_ceil(chunk_size);
+    let mut chunks = Vec::with_capacity((chunks_per_side * chunks_per_side) as usize);
+    for chunk_z in 0..chunks_per_side {
+        for chunk_x in 0..chunks_per_side {
+            let start = UVec2::new(chunk_x, chunk_z) * chunk_size;
+            let end = (start + chunk_size).min(UVec2::splat(quads));
+            let origin = start.saturating_sub(UVec2::ONE);
+            let last = (end + 1).min(UVec2::splat(quads));
+            chunks.push(TerrainChunk {
+                origin,
+                size: last - origin + 1,
+            });
+        }
+    }
+
+    // Each triangle goes to the chunk of its quad, at its lowest x and z in the grid
+    let mut indices = vec![vec![]; chunks.len()];
+    if let Some(Indices::U32(terrain_indices)) = mesh.indices() {
+        let resolution = resolution as u32;
+        for triangle in terrain_indices.chunks_exact(3) {
+            let quad = triangle.iter().fold(UVec2::MAX, |quad, &vertex| {
+                quad.min(UVec2::new(vertex % resolution, vertex / resolution))
+            });
+            let chunk = quad / chunk_size;
+            let chunk = (chunk.y * chunks_per_side + chunk.x) as usize;
+            let origin = chunks[chunk].origin;
+            let width = chunks[chunk].size.x;
+            indices[chunk].extend(triangle.iter().map(|&vertex| {
+                let local = UVec2::new(vertex % resolution, vertex / resolution) - origin;
+                local.y * width + local.x
+            }));
+        }
+    }
+
+    chunks
+        .into_iter()
+        .zip(indices)
+        .map(|(chunk, indices)| {
+            let vertices: Vec<usize> = (chunk.origin.y..chunk.origin.y + chunk.size.y)
+                .flat_map(|z| {
+                    (chunk.origin.x..chunk.origin.x + chunk.size.x)
+                        .map(move |x| z as usize * resolution + x as usize)
+                })
+                .collect();
+            let mut chunk_mesh = Mesh::new(PrimitiveTopology::TriangleList, mesh.asset_usage);
+            for attribute in CHUNK_ATTRIBUTES {
+                if let Some(values) = mesh
+                    .attribute(attribute)
+                    .and_then(|values| pick_vertices(values, &vertices))
+                {
+                    chunk_mesh.insert_attribute(attribute, values);
+                }
+            }
+            chunk_mesh.insert_indices(Indices::U32(indices));
+            (chunk, chunk_mesh)
+        })
+        .collect()
+}
+
+fn pick_vertices(
+    values: &VertexAttributeValues,
+    vertices: &[usize],
+) -> Option<VertexAttributeValues> {
+    use VertexAttributeValues::*;
+    Some(match values {
+        Float32x2(values) => Float32x2(vertices.iter().map(|&i| values[i]).collect()),
+        Float32x3(values) => Float32x3(vertices.iter().map(|&i| values[i]).collect()),
+        Float32x4(values) => Float32x4(vertices.iter().map(|&i| values[i]).collect()),
+        _ => return None,
+    })
+}
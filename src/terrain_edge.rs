@@ -0,0 +1,301 @@
+//! What happens at the border of the terrain.
+//!
+//! `edge` in the terrain config picks the treatment:
+//! - `None` leaves the border open, the underside of the terrain shows from low angles.
+//! - `Skirt` hangs the border straight down to `depth` under the water or the lowest border
+//!   vertex, whichever is lower, so there's no gap between the terrain and the water.
+//! - `Wall` does the same with a cross-section material, a layer of soil over stratified rock,
+//!   for diorama-like slices of forest.
+//! - `Falloff` sinks the terrain under the water toward the border, making an island.
+//!
+//! The skirt and the wall are meshes of their own built from the border of the heightfield, they
+//! follow the sculpting. Nothing is scattered within [`EDGE_MARGIN`] of the cut so the trees don't
+//! hang over it.
+
+use bevy::{
+    pbr::{ExtendedMaterial, MaterialExtension},
+    prelude::*,
+    render::{
+        mesh::{Indices, PrimitiveTopology},
+        render_asset::RenderAssetUsages,
+        render_resource::{AsBindGroup, ShaderRef, ShaderType},
+    },
+};
+
+use crate::{
+    heightfield::TerrainHeightfield,
+    render_layers::SceneLayer,
+    terrain::TerrainConfig,
+    validation::{clamp_float_field, ValidationIssue},
+};
+
+/// Distance from a skirt or a wall where nothing is scattered
+pub const EDGE_MARGIN: f32 = 2.0;
+const SKIRT_COLOR: Color = Color::srgb(0.12, 0.09, 0.07);
+
+#[derive(Reflect, Clone, Debug, Default, PartialEq)]
+pub enum TerrainEdge {
+    #[default]
+    None,
+    Skirt {
+        /// How far under the water the skirt goes
+        depth: f32,
+    },
+    Wall {
+        /// How far under the water the wall goes
+        depth: f32,
+        strata: WallStrata,
+    },
+    Falloff {
+        /// Width of the band along the border where the terrain sinks
+        width: f32,
+        /// How far under the water the terrain is at the border
+        depth: f32,
+    },
+}
+
+/// Look of the cut of a [`TerrainEdge::Wall`]
+#[derive(Reflect, Clone, Debug, PartialEq)]
+pub struct WallStrata {
+    pub soil_color: Color,
+    /// Thickness of the soil under the ground
+    pub soil_depth: f32,
+    pub rock_color: Color,
+    /// Thickness of each band of rock
+    pub layer_thickness: f32,
+}
+
+impl Default for WallStrata {
+    fn default() -> Self {
+        Self {
+            soil_color: Color::srgb(0.22, 0.15, 0.1),
+            soil_depth: 1.5,
+            rock_color: Color::srgb(0.45, 0.42, 0.38),
+            layer_thickness: 0.8,
+        }
+    }
+}
+
+impl TerrainEdge {
+    /// Height of the ground at `local`, a position of the un-rotated terrain, once the falloff
+    /// is applied to `height`
+    pub fn height(&self, local: Vec2, height: f32, half_size: f32, water_level: f32) -> f32 {
+        let TerrainEdge::Falloff { width, depth } = *self else {
+            return height;
+        };
+        // Round so the island doesn't take the shape of the square terrain
+        let distance = local.length();
+        let t = ((distance - (half_size - width)) / width.max(f32::EPSILON)).clamp(0.0, 1.0);
+        let t = t * t * (3.0 - 2.0 * t);
+        height + (height.min(water_level - depth) - height) * t
+    }
+
+    pub fn validate(&mut self) -> Vec<ValidationIssue> {
+        let mut issues = vec![];
+        match self {
+            TerrainEdge::None => {}
+            TerrainEdge::Skirt { depth } => {
+                clamp_float_field(&mut issues, "edge.depth", depth, 0.0, 1000.0, 10.0);
+            }
+            TerrainEdge::Wall { depth, strata } => {
+                clamp_float_field(&mut issues, "edge.depth", depth, 0.0, 1000.0, 10.0);
+                let default = WallStrata::default();
+                clamp_float_field(
+                    &mut issues,
+                    "edge.strata.soil_depth",
+                    &mut strata.soil_depth,
+                    0.0,
+                    100.0,
+                    default.soil_depth,
+                );
+                clamp_float_field(
+                    &mut issues,
+                    "edge.strata.layer_thickness",
+                    &mut strata.layer_thickness,
+                    0.01,
+                    100.0,
+                    default.layer_thickness,
+                );
+            }
+            TerrainEdge::Falloff { width, depth } => {
+                clamp_float_field(&mut issues, "edge.width", width, 1.0, 2000.0, 30.0);
+                clamp_float_field(&mut issues, "edge.depth", depth, 0.0, 1000.0, 5.0);
+            }
+        }
+        issues
+    }
+
+    /// Whether `position` is too close to a skirt or a wall to scatter something there
+    pub fn near_cut(&self, heightfield: &TerrainHeightfield, position: Vec3) -> bool {
+        if !matches!(self, TerrainEdge::Skirt { .. } | TerrainEdge::Wall { .. }) {
+            return false;
+        }
+        let grid = heightfield.to_grid(position.xz());
+        let margin = EDGE_MARGIN / heightfield.spacing();
+        let last = (heightfield.resolution - 1) as f32;
+        grid.min_element() < margin || grid.max_element() > last - margin
+    }
+}
+
+/// Draws the cut of a [`TerrainEdge::Wall`] as bands going down from the ground
+pub type WallMaterial = ExtendedMaterial<StandardMaterial, WallCrossSection>;
+
+#[derive(Asset, TypePath, AsBindGroup, Clone)]
+pub struct WallCrossSection {
+    #[uniform(100)]
+    settings: WallSettings,
+}
+
+#[derive(Clone, Copy, ShaderType)]
+struct WallSettings {
+    soil_color: Vec4,
+    rock_color: Vec4,
+    soil_depth: f32,
+    layer_thickness: f32,
+}
+
+impl MaterialExtension for WallCrossSection {
+    fn fragment_shader() -> ShaderRef {
+        "terrain_wall.wgsl".into()
+    }
+
+    fn deferred_fragment_shader() -> ShaderRef {
+        "terrain_wall.wgsl".into()
+    }
+}
+
+/// Marks the skirt or the wall, its mesh follows the heightfield and it's only respawned when the
+/// edge of the config changes
+#[derive(Component)]
+pub struct TerrainEdgeMesh;
+
+pub fn spawn_terrain_edge(
+    mut commands: Commands,
+    terrain_config: Res<TerrainConfig>,
+    heightfield: Res<TerrainHeightfield>,
+    previous: Query<(Entity, &Handle<Mesh>), With<TerrainEdgeMesh>>,
+    mut spawned_edge: Local<Option<TerrainEdge>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut wall_materials: ResMut<Assets<WallMaterial>>,
+) {
+    let (depth, strata) = match &terrain_config.edge {
+        TerrainEdge::Skirt { depth } => (Some(*depth), None),
+        TerrainEdge::Wall { depth, strata } => (Some(*depth), Some(strata)),
+        TerrainEdge::None | TerrainEdge::Falloff { .. } => (None, None),
+    };
+    let _span = info_span!("spawn_terrain_edge").entered();
+    // Every frame of a sculpt stroke, the entity and its material are kept
+    if let (Some(depth), Ok((_, mesh))) = (depth, previous.get_single()) {
+        if spawned_edge.as_ref() == Some(&terrain_config.edge) {
+            let edge_mesh = edge_mesh(&heightfield, terrain_config.water_level - depth);
+            meshes.insert(mesh, edge_mesh);
+            return;
+        }
+    }
+    *spawned_edge = Some(terrain_config.edge.clone());
+    for (entity, _) in &previous {
+        commands.entity(entity).despawn_recursive();
+    }
+    let Some(depth) = depth else {
+        return;
+    };
+    let mesh = meshes.add(edge_mesh(&heightfield, terrain_config.water_level - depth));
+    let mut edge = commands.spawn((
+        TerrainEdgeMesh,
+        SpatialBundle::default(),
+        SceneLayer::MainScene.layers(),
+    ));
+    match strata {
+        None => {
+            edge.insert((
+                mesh,
+                materials.add(StandardMaterial {
+                    base_color: SKIRT_COLOR,
+                    perceptual_roughness: 1.0,
+                    ..default()
+                }),
+            ));
+        }
+        Some(strata) => {
+            let linear = |color: Color| {
+                let color = color.to_linear();
+                Vec4::new(color.red, color.green, color.blue, 1.0)
+            };
+            edge.insert((
+                mesh,
+                wall_materials.add(ExtendedMaterial {
+                    base: StandardMaterial {
+                        perceptual_roughness: 0.95,
+                        ..default()
+                    },
+                    extension: WallCrossSection {
+                        settings: WallSettings {
+                            soil_color: linear(strata.soil_color),
+                            rock_color: linear(strata.rock_color),
+                            soil_depth: strata.soil_depth,
+                            layer_thickness: strata.layer_thickness,
+                        },
+                    },
+                }),
+            ));
+        }
+    }
+}
+
+/// Vertical strips hanging from the four sides of the heightfield down to `bottom`, or below the
+/// lowest border vertex.
+///
+/// The first uv is the distance along the side and below the ground in meters, the wall shader
+/// lays its bands out with it.
+fn edge_mesh(heightfield: &TerrainHeightfield, bottom: f32) -> Mesh {
+    let last = heightfield.resolution - 1;
+    let sides: [(Vec<(usize, usize)>, Vec3); 4] = [
+        ((0..=last).map(|i| (i, 0)).collect(), Vec3::NEG_Z),
+        ((0..=last).map(|i| (last, i)).collect(), Vec3::X),
+        ((0..=last).rev().map(|i| (i, last)).collect(), Vec3::Z),
+        ((0..=last).rev().map(|i| (0, i)).collect(), Vec3::NEG_X),
+    ];
+    let rotation = Quat::from_axis_angle(Vec3::Y, heightfield.rotation);
+    let bottom = sides
+        .iter()
+        .flat_map(|(vertices, _)| vertices)
+        .map(|&(x, z)| heightfield.height(x, z))
+        .fold(bottom, f32::min)
+        - 0.5;
+
+    let mut positions = vec![];
+    let mut normals = vec![];
+    let mut uvs = vec![];
+    let mut indices = vec![];
+    for (vertices, outward) in sides {
+        let normal = rotation * outward;
+        let first = positions.len() as u32;
+        let mut along = 0.0;
+        let mut previous: Option<Vec3> = None;
+        for (x, z) in vertices {
+            let top = heightfield.world_position(x, z);
+            along += previous.map_or(0.0, |previous| previous.xz().distance(top.xz()));
+            previous = Some(top);
+            positions.extend([top.to_array(), [top.x, bottom, top.z]]);
+            normals.extend([normal.to_array(); 2]);
+            uvs.extend([[along, 0.0], [along, top.y - bottom]]);
+        }
+        for i in 0..last as u32 {
+            let (top, low) = (first + i * 2, first + i * 2 + 1);
+            let (next_top, next_low) = (top + 2, low + 2);
+            // The sides go around the terrain in the same direction, so these all face outward
+            indices.extend([top, next_top, low, low, next_top, next_low]);
+        }
+    }
+
+    let mut mesh = Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::default(),
+    );
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.insert_indices(Indices::U32(indices));
+    mesh
+}
@@ -0,0 +1,274 @@
+//! Compiling the permutations of the terrain material before the terrain switches to them.
+//!
+//! Every optional feature of the terrain shader is behind a shader def derived from the material,
+//! see [`TerrainMaterialKey`], so every combination of features is its own set of pipelines. One
+//! takes a few hundred milliseconds to compile and bevy skips the entities whose pipelines aren't
+//! ready, so turning the snow on mid-session would make the terrain vanish for a moment.
+//!
+//! [`sync_terrain_material_settings`](crate::terrain::sync_terrain_material_settings) only changes
+//! the variant of the live material when the pipelines of the new one are compiled. Otherwise the
+//! new material goes on a warm-up copy of the terrain shrunk to a point, the terrain keeps drawing
+//! with the old material and it's swapped once the render world reports every pipeline of the new
+//! variant as compiled. The variant of the loaded config is applied right away since nothing is
+//! drawn yet, it compiles while the ground textures stream in.
+//!
+//! The render world tells which variants are compiled through a channel, every terrain pipeline
+//! carries its variant in the `TERRAIN_VARIANT` shader def to find them in the pipeline cache.
+
+use std::sync::{
+    mpsc::{channel, Receiver, Sender},
+    Mutex,
+};
+
+use bevy::{
+    pbr::{ExtendedMaterial, NotShadowCaster},
+    prelude::*,
+    render::{
+        render_resource::{CachedPipelineState, PipelineCache, PipelineDescriptor, ShaderDefVal},
+        view::NoFrustumCulling,
+        Render, RenderApp, RenderSet,
+    },
+    utils::{HashMap, HashSet, Instant},
+};
+
+use crate::{
+    overlay::StatsOverlay,
+    render_layers::SceneLayer,
+    terrain::{Terrain, TerrainMaterial, TerrainMaterialKey},
+};
+
+/// Shader def holding [`TerrainMaterialKey::bits`] in every terrain pipeline
+pub const VARIANT_DEF: &str = "TERRAIN_VARIANT";
+
+type TerrainExtendedMaterial = ExtendedMaterial<StandardMaterial, TerrainMaterial>;
+
+pub struct TerrainVariantsPlugin;
+
+impl Plugin for TerrainVariantsPlugin {
+    fn build(&self, app: &mut App) {
+        let (sender, receiver) = channel();
+        app.insert_resource(CompiledVariantsReceiver(Mutex::new(receiver)))
+            .init_resource::<TerrainVariants>()
+            .add_systems(
+                Update,
+                (receive_compiled_variants, swap_compiled_variant).chain(),
+            );
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app
+            .insert_resource(CompiledVariantsSender(sender))
+            .add_systems(Render, send_compiled_variants.after(RenderSet::Render));
+    }
+}
+
+#[derive(Resource)]
+struct CompiledVariantsSender(Sender<HashMap<u32, CachedVariantState>>);
+
+#[derive(Resource)]
+struct CompiledVariantsReceiver(Mutex<Receiver<HashMap<u32, CachedVariantState>>>);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CachedVariantState {
+    Compiling,
+    Compiled,
+    Failed,
+}
+
+/// A variant compiling on the warm-up entity
+struct PendingVariant {
+    key: TerrainMaterialKey,
+    /// Terrain chunk the material was requested for, it goes to every chunk once compiled
+    terrain: Entity,
+    material: Handle<TerrainExtendedMaterial>,
+    warmup: Entity,
+    started: Instant,
+}
+
+#[derive(Resource, Default)]
+pub struct TerrainVariants {
+    /// Variants with every pipeline compiled, by [`TerrainMaterialKey::bits`]
+    compiled: HashSet<u32>,
+    failed: HashSet<u32>,
+    pending: Option<PendingVariant>,
+}
+
+impl TerrainVariants {
+    /// Whether the terrain can switch from `live` to `key` without waiting for a compile
+    pub fn can_switch(&self, live: TerrainMaterialKey, key: TerrainMaterialKey) -> bool {
+        // When the live variant isn't compiled nothing is drawn yet, there's nothing to keep
+        live == key || self.compiled.contains(&key.bits()) || !self.compiled.contains(&live.bits())
+    }
+
+    /// Compiles `material` on the warm-up entity and gives it to `terrain` once it's done,
+    /// replacing any other variant still compiling
+    pub fn request(
+        &mut self,
+        commands: &mut Commands,
+        terrain: Entity,
+        mesh: Handle<Mesh>,
+        material: TerrainExtendedMaterial,
+        materials: &mut Assets<TerrainExtendedMaterial>,
+    ) {
+        let key = TerrainMaterialKey::from(&material.extension);
+        if self.failed.contains(&key.bits()) {
+            return;
+        }
+        if let Some(pending) = &self.pending {
+            if pending.key == key && pending.terrain == terrain {
+                // Same variant, only the uniforms changed
+                materials.insert(&pending.material, material);
+                return;
+            }
+        }
+        self.cancel(commands);
+        info!("compiling the terrain variant {key:?}");
+        let material = materials.add(material);
+        let warmup = commands
+            .spawn((
+                MaterialMeshBundle {
+                    mesh,
+                    material: material.clone(),
+                    // Out of sight without being culled, the pipelines are only specialized for
+                    // the entities that are drawn
+                    transform: Transform::from_scale(Vec3::splat(1e-5)),
+                    ..default()
+                },
+                NoFrustumCulling,
+                NotShadowCaster,
+                SceneLayer::MainScene.layers(),
+                Name::new("Terrain variant warm-up"),
+            ))
+            .id();
+        self.pending = Some(PendingVariant {
+            key,
+            terrain,
+            material,
+            warmup,
+            started: Instant::now(),
+        });
+    }
+
+    /// Drops the variant compiling, the terrain keeps its material
+    pub fn cancel(&mut self, commands: &mut Commands) {
+        if let Some(pending) = self.pending.take() {
+            commands.entity(pending.warmup).despawn_recursive();
+        }
+    }
+
+    pub fn is_pending(&self, terrain: Entity) -> bool {
+        self.pending
+            .as_ref()
+            .is_some_and(|pending| pending.terrain == terrain)
+    }
+}
+
+/// Finds the state of every terrain variant in the pipeline cache
+fn send_compiled_variants(pipeline_cache: Res<PipelineCache>, sender: Res<CompiledVariantsSender>) {
+    let mut variants = HashMap::default();
+    for pipeline in pipeline_cache.pipelines() {
+        let PipelineDescriptor::RenderPipelineDescriptor(descriptor) = &pipeline.descriptor else {
+            continue;
+        };
+        let Some(variant) = descriptor
+            .fragment
+            .iter()
+            .flat_map(|fragment| &fragment.shader_defs)
+            .find_map(|def| match def {
+                ShaderDefVal::UInt(name, bits) if name == VARIANT_DEF => Some(*bits),
+                _ => None,
+            })
+        else {
+            continue;
+        };
+        let state = match pipeline.state {
+            CachedPipelineState::Ok(_) => CachedVariantState::Compiled,
+            CachedPipelineState::Err(_) => CachedVariantState::Failed,
+            _ => CachedVariantState::Compiling,
+        };
+        // A variant is only as far along as its slowest pipeline
+        let variant = variants.entry(variant).or_insert(state);
+        *variant = match (*variant, state) {
+            (CachedVariantState::Failed, _) | (_, CachedVariantState::Failed) => {
+                CachedVariantState::Failed
+            }
+            (CachedVariantState::Compiled, CachedVariantState::Compiled) => {
+                CachedVariantState::Compiled
+            }
+            _ => CachedVariantState::Compiling,
+        };
+    }
+    let _ = sender.0.send(variants);
+}
+
+fn receive_compiled_variants(
+    receiver: Res<CompiledVariantsReceiver>,
+    mut variants: ResMut<TerrainVariants>,
+) {
+    let Some(states) = receiver.0.lock().unwrap().try_iter().last() else {
+        return;
+    };
+    let variants = variants.bypass_change_detection();
+    variants.compiled.clear();
+    for (bits, state) in states {
+        match state {
+            CachedVariantState::Compiled => {
+                variants.compiled.insert(bits);
+            }
+            CachedVariantState::Failed => {
+                if variants.failed.insert(bits) {
+                    error!("the terrain variant {bits:#b} failed to compile");
+                }
+            }
+            CachedVariantState::Compiling => {}
+        }
+    }
+}
+
+/// Gives the terrain the pending variant once it's compiled
+fn swap_compiled_variant(
+    mut commands: Commands,
+    mut variants: ResMut<TerrainVariants>,
+    terrain: Query<Entity, With<Terrain>>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    let Some(pending) = &variants.pending else {
+        return;
+    };
+    let bits = pending.key.bits();
+    let failed = variants.failed.contains(&bits);
+    if !failed && !variants.compiled.contains(&bits) {
+        stats.set(
+            "Terrain shader",
+            format!(
+                "compiling {:?} for {:.1}s",
+                pending.key,
+                pending.started.elapsed().as_secs_f32()
+            ),
+        );
+        return;
+    }
+
+    let Some(pending) = variants.pending.take() else {
+        return;
+    };
+    commands.entity(pending.warmup).despawn_recursive();
+    stats.remove("Terrain shader");
+    // The previous variant stays on the terrain when the new one can't be used
+    if failed {
+        return;
+    }
+    // Requested for one chunk, they all share the material. The chunks were spawned again if that
+    // one is gone.
+    if terrain.contains(pending.terrain) {
+        for chunk in &terrain {
+            commands.entity(chunk).insert(pending.material.clone());
+        }
+        info!(
+            "terrain variant {:?} compiled in {:.2}s",
+            pending.key,
+            pending.started.elapsed().as_secs_f32()
+        );
+    }
+}
@@ -0,0 +1,244 @@
+//! Two stage texture loading.
+//!
+//! The 4k textures take several seconds to decode so a tiny placeholder is bound right away and
+//! the handles are swapped on the live materials once the full resolution image is loaded. The
+//! decoding happens on the async loader so the swap itself is cheap.
+//!
+//! The skybox is only requested once the terrain and the trees are loaded, see the `loading`
+//! module, the procedural sky stands in for it until then.
+//!
+//! The mipmapped KTX2s written by `--preprocess-assets` are loaded instead of the jpgs when they
+//! exist, in the downscaled variant of `--texture-tier` if there's one.
+
+use bevy::{
+    asset::LoadState,
+    core_pipeline::Skybox,
+    pbr::ExtendedMaterial,
+    prelude::*,
+    render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{
+            Extent3d, TextureDimension, TextureFormat, TextureViewDescriptor, TextureViewDimension,
+        },
+        texture::{ImageAddressMode, ImageLoaderSettings, ImageSampler, ImageSamplerDescriptor},
+    },
+};
+
+use crate::{cli::CliArgs, preprocess::ProcessedManifest, terrain::TerrainMaterial};
+
+pub const SKYBOX_PATH: &str = "skybox/kloppenheim_01_puresky_4k_cubemap.ktx2";
+
+/// A texture that starts as a placeholder and is upgraded once the full resolution is loaded
+pub struct StreamedTexture {
+    pub name: &'static str,
+    placeholder: Handle<Image>,
+    full: Handle<Image>,
+    upgraded: bool,
+}
+
+impl StreamedTexture {
+    /// The best texture currently available
+    pub fn current(&self) -> Handle<Image> {
+        if self.upgraded {
+            self.full.clone()
+        } else {
+            self.placeholder.clone()
+        }
+    }
+
+    pub fn is_upgraded(&self) -> bool {
+        self.upgraded
+    }
+
+    /// Whether the full resolution is still on its way, a texture that failed to load never will
+    pub fn is_loading(&self, asset_server: &AssetServer) -> bool {
+        !self.upgraded && !matches!(asset_server.load_state(&self.full), LoadState::Failed(_))
+    }
+}
+
+#[derive(Resource)]
+pub struct TextureStreaming {
+    pub ground_base_color: StreamedTexture,
+    pub ground_normal: StreamedTexture,
+    pub ground_roughness: StreamedTexture,
+    pub ground_depth: StreamedTexture,
+    pub skybox: StreamedTexture,
+}
+
+impl TextureStreaming {
+    fn textures_mut(&mut self) -> [&mut StreamedTexture; 5] {
+        [
+            &mut self.ground_base_color,
+            &mut self.ground_normal,
+            &mut self.ground_roughness,
+            &mut self.ground_depth,
+            &mut self.skybox,
+        ]
+    }
+
+    pub fn ground_textures(&self) -> [&StreamedTexture; 4] {
+        [
+            &self.ground_base_color,
+            &self.ground_normal,
+            &self.ground_roughness,
+            &self.ground_depth,
+        ]
+    }
+
+    /// Starts loading the full resolution skybox
+    pub fn load_skybox(&mut self, asset_server: &AssetServer) {
+        self.skybox.full = asset_server.load(SKYBOX_PATH);
+    }
+}
+
+pub fn terrain_sampler() -> ImageSampler {
+    ImageSampler::Descriptor(ImageSamplerDescriptor {
+        label: Some("terrain sampler".into()),
+        address_mode_u: ImageAddressMode::Repeat,
+        address_mode_v: ImageAddressMode::Repeat,
+        ..ImageSamplerDescriptor::linear()
+    })
+}
+
+fn placeholder(color: [u8; 4], format: TextureFormat) -> Image {
+    Image::new_fill(
+        Extent3d {
+            width: 1,
+            height: 1,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &color,
+        format,
+        RenderAssetUsages::RENDER_WORLD,
+    )
+}
+
+fn placeholder_cubemap(color: [u8; 4]) -> Image {
+    let mut image = Image::new_fill(
+        Extent3d {
+            width: 1,
+            height: 1,
+            depth_or_array_layers: 6,
+        },
+        TextureDimension::D2,
+        &color,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+    image.texture_view_descriptor = Some(TextureViewDescriptor {
+        dimension: Some(TextureViewDimension::Cube),
+        ..default()
+    });
+    image
+}
+
+pub fn setup_texture_streaming(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut images: ResMut<Assets<Image>>,
+    cli: Res<CliArgs>,
+) {
+    let processed = ProcessedManifest::load();
+    let mut ground =
+        |name: &'static str, path: &'static str, color: [u8; 4], format| StreamedTexture {
+            name,
+            placeholder: images.add(placeholder(color, format)),
+            full: asset_server.load_with_settings(
+                processed.resolve(path, cli.texture_tier.as_deref()),
+                |s: &mut ImageLoaderSettings| {
+                    s.sampler = terrain_sampler();
+                },
+            ),
+            upgraded: false,
+        };
+
+    let ground_base_color = ground(
+        "ground base color",
+        "forest_ground/textures/forest_ground_04_diff_4k.jpg",
+        // roughly the average color of the texture
+        [74, 60, 45, 255],
+        TextureFormat::Rgba8UnormSrgb,
+    );
+    let ground_normal = ground(
+        "ground normal",
+        "forest_ground/textures/forest_ground_04_nor_gl_4k.jpg",
+        [128, 128, 255, 255],
+        TextureFormat::Rgba8Unorm,
+    );
+    let ground_roughness = ground(
+        "ground roughness",
+        "forest_ground/textures/forest_ground_04_rough_4k.jpg",
+        [0, 255, 0, 255],
+        TextureFormat::Rgba8Unorm,
+    );
+    let ground_depth = ground(
+        "ground depth",
+        "forest_ground/textures/forest_ground_04_disp_4k.jpg",
+        [0, 0, 0, 255],
+        TextureFormat::Rgba8Unorm,
+    );
+    let skybox = StreamedTexture {
+        name: "skybox",
+        placeholder: images.add(placeholder_cubemap([150, 180, 220, 255])),
+        // Requested last by the loading stages
+        full: Handle::default(),
+        upgraded: false,
+    };
+
+    commands.insert_resource(TextureStreaming {
+        ground_base_color,
+        ground_normal,
+        ground_roughness,
+        ground_depth,
+        skybox,
+    });
+}
+
+/// Swaps the placeholders for the full resolution textures as soon as they are loaded
+pub fn upgrade_streamed_textures(
+    asset_server: Res<AssetServer>,
+    mut streaming: ResMut<TextureStreaming>,
+    mut terrain_materials: ResMut<Assets<ExtendedMaterial<StandardMaterial, TerrainMaterial>>>,
+    mut cameras: Query<(Option<&mut Skybox>, &mut EnvironmentMapLight)>,
+) {
+    for texture in streaming.textures_mut() {
+        if texture.upgraded || !asset_server.is_loaded_with_dependencies(&texture.full) {
+            continue;
+        }
+        texture.upgraded = true;
+
+        let placeholder = texture.placeholder.id();
+        let swap = |handle: &mut Handle<Image>| {
+            if handle.id() == placeholder {
+                *handle = texture.full.clone();
+            }
+        };
+
+        for (_, material) in terrain_materials.iter_mut() {
+            let base = &mut material.base;
+            for handle in [
+                base.base_color_texture.as_mut(),
+                base.normal_map_texture.as_mut(),
+                base.metallic_roughness_texture.as_mut(),
+                base.depth_map.as_mut(),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                swap(handle);
+            }
+        }
+
+        for (skybox, mut environment_map) in &mut cameras {
+            // The skybox is removed when using the procedural sky
+            if let Some(mut skybox) = skybox {
+                swap(&mut skybox.image);
+            }
+            swap(&mut environment_map.diffuse_map);
+            swap(&mut environment_map.specular_map);
+        }
+
+        info!("{} upgraded to full resolution", texture.name);
+    }
+}
@@ -0,0 +1,41 @@
+//! Chrome tracing of a few seconds of the app.
+//!
+//! Needs the `trace` cargo feature, the trace is only flushed when the app exits so it's closed
+//! after [`TRACE_DURATION`].
+
+use std::time::Duration;
+
+use bevy::{app::AppExit, prelude::*};
+
+use crate::cli::CliArgs;
+
+pub const TRACE_PATH: &str = "trace.json";
+pub const TRACE_DURATION: Duration = Duration::from_secs(15);
+
+/// Must be called before the app is built, the tracing layer reads it when the log plugin starts
+pub fn configure_chrome_trace() {
+    if std::env::args().any(|arg| arg == "--trace") {
+        std::env::set_var("TRACE_CHROME", TRACE_PATH);
+    }
+}
+
+pub fn log_trace_path(cli: Res<CliArgs>) {
+    if !cli.trace {
+        return;
+    }
+    if cfg!(feature = "trace") {
+        info!(
+            "recording a trace to {TRACE_PATH} for {}s, the app will exit once it's written",
+            TRACE_DURATION.as_secs()
+        );
+    } else {
+        warn!("--trace needs the app to be built with `--features trace`");
+    }
+}
+
+pub fn stop_trace(cli: Res<CliArgs>, time: Res<Time<Real>>, mut exit: EventWriter<AppExit>) {
+    if cfg!(feature = "trace") && cli.trace && time.elapsed() > TRACE_DURATION {
+        info!("trace written to {TRACE_PATH}");
+        exit.send(AppExit::Success);
+    }
+}
@@ -0,0 +1,279 @@
+//! Removing and planting single trees by hand.
+//!
+//! P toggles the edit mode. The tree closest to the cursor is highlighted and Delete removes it,
+//! Ctrl+click plants a tree of the selected variant, Tab cycles the variant and holding Alt while
+//! planting skips the height and steepness checks.
+//!
+//! Like the sculpting edits, the changes are stored as a sparse overlay on top of the generated
+//! trees so they can be re-applied after a regeneration with the same seed.
+
+use std::collections::BTreeSet;
+
+use bevy::{prelude::*, tasks::IoTaskPool, window::PrimaryWindow};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    debug_palette::DebugPalette,
+    heightfield::TerrainHeightfield,
+    measure::MeasureMode,
+    overlay::StatsOverlay,
+    split_view::SecondaryCamera,
+    terrain::{
+        spawn_tree, TerrainConfig, TerrainResources, TreeInstance, TreePlacement, TreePlacements,
+    },
+};
+
+pub const TREE_EDITS_PATH: &str = "assets/tree_edits.ron";
+
+/// Only the trees closer than this to the cursor can be highlighted
+const HOVER_RADIUS: f32 = 3.0;
+
+/// A tree planted by hand, the height is taken from the terrain when it's spawned
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlantedTree {
+    pub x: f32,
+    pub z: f32,
+    /// Rotation around the trunk
+    pub spin: f32,
+    pub scale: f32,
+    /// Index in [`TerrainResources::trees`]
+    pub variant: usize,
+}
+
+impl PlantedTree {
+    pub fn transform(
+        &self,
+        heightfield: &TerrainHeightfield,
+        base_rotation: Quat,
+    ) -> Option<Transform> {
+        let height = heightfield.height_at(vec2(self.x, self.z))?;
+        // Same rotation as the generated trees
+        let spin_axis = base_rotation.inverse() * Vec3::Y;
+        Some(
+            Transform::from_xyz(self.x, height - 0.025, self.z)
+                .with_scale(Vec3::splat(self.scale))
+                .with_rotation(base_rotation * Quat::from_axis_angle(spin_axis, self.spin)),
+        )
+    }
+
+    pub fn instance(&self, heightfield: &TerrainHeightfield) -> TreeInstance {
+        let position = vec2(self.x, self.z);
+        TreeInstance {
+            variant: self.variant,
+            candidate: None,
+            terrain_height: heightfield.height_at(position).unwrap_or_default(),
+            steepness: heightfield.steepness_at(position).unwrap_or_default(),
+            scale: self.scale,
+        }
+    }
+}
+
+/// Trees removed and planted on top of the generated ones
+#[derive(Resource, Serialize, Deserialize, Default, Clone, Debug)]
+pub struct TreeEdits {
+    /// The edits only make sense for the terrain they were made on
+    pub seed: u32,
+    pub half_size: u32,
+    /// Scatter candidates of the generated trees that were removed
+    pub removed: BTreeSet<u32>,
+    pub added: Vec<PlantedTree>,
+}
+
+impl TreeEdits {
+    pub fn new(terrain_config: &TerrainConfig) -> Self {
+        Self {
+            seed: terrain_config.seed,
+            half_size: terrain_config.half_size,
+            ..default()
+        }
+    }
+
+    pub fn matches(&self, terrain_config: &TerrainConfig) -> bool {
+        self.seed == terrain_config.seed && self.half_size == terrain_config.half_size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.added.is_empty()
+    }
+
+    /// Loads the edits saved next to the terrain config, if any
+    pub fn load() -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            if let Ok(file) = std::fs::read_to_string(TREE_EDITS_PATH) {
+                match ron::from_str(&file) {
+                    Ok(edits) => return edits,
+                    Err(err) => warn!("failed to read {TREE_EDITS_PATH}: {err}"),
+                }
+            }
+        }
+        Self::default()
+    }
+
+    fn save(&self) {
+        let Ok(serialized) = ron::ser::to_string_pretty(self, default()) else {
+            error!("failed to serialize tree edits");
+            return;
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        IoTaskPool::get()
+            .spawn(async move {
+                if let Err(err) = std::fs::write(TREE_EDITS_PATH, serialized) {
+                    error!("failed to write {TREE_EDITS_PATH}: {err}");
+                }
+            })
+            .detach();
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct TreeEditMode {
+    pub active: bool,
+    /// Variant planted by Ctrl+click
+    pub variant: usize,
+}
+
+pub fn toggle_tree_edit_mode(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    terrain_resources: Option<Res<TerrainResources>>,
+    mut tree_edit_mode: ResMut<TreeEditMode>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    if keyboard.just_pressed(KeyCode::KeyP) {
+        tree_edit_mode.active = !tree_edit_mode.active;
+    }
+    if !tree_edit_mode.active {
+        stats.remove("Tree edit");
+        return;
+    }
+
+    let variant_count = terrain_resources.map_or(0, |r| r.trees.len());
+    if keyboard.just_pressed(KeyCode::Tab) && variant_count > 0 {
+        tree_edit_mode.variant = (tree_edit_mode.variant + 1) % variant_count;
+    }
+    stats.set(
+        "Tree edit",
+        format!("variant {} / {variant_count}", tree_edit_mode.variant + 1),
+    );
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn edit_trees(
+    mut commands: Commands,
+    tree_edit_mode: Res<TreeEditMode>,
+    measure_mode: Res<MeasureMode>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    terrain_config: Res<TerrainConfig>,
+    terrain_resources: Res<TerrainResources>,
+    heightfield: Res<TerrainHeightfield>,
+    mut edits: ResMut<TreeEdits>,
+    mut tree_placements: ResMut<TreePlacements>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform), (With<Camera3d>, Without<SecondaryCamera>)>,
+    trees: Query<(Entity, &Transform, &TreeInstance)>,
+    palette: Res<DebugPalette>,
+    mut gizmos: Gizmos,
+) {
+    if !tree_edit_mode.active || measure_mode.active {
+        return;
+    }
+    let Some(cursor) = window.get_single().ok().and_then(|w| w.cursor_position()) else {
+        return;
+    };
+    let Ok((camera, camera_transform)) = camera.get_single() else {
+        return;
+    };
+    let Some(ray) = camera.viewport_to_world(camera_transform, cursor) else {
+        return;
+    };
+    let Some(hit) = heightfield.raycast(ray, 1000.0) else {
+        return;
+    };
+
+    if !edits.matches(&terrain_config) {
+        // The edits are reset when the terrain is generated so this only happens before that
+        return;
+    }
+
+    let hovered = trees
+        .iter()
+        .map(|(entity, transform, instance)| {
+            let distance = transform.translation.xz().distance(hit.xz());
+            (entity, transform, instance, distance)
+        })
+        .filter(|(.., distance)| *distance < HOVER_RADIUS)
+        .min_by(|(.., a), (.., b)| a.total_cmp(b));
+
+    if let Some((entity, transform, instance, _)) = hovered {
+        gizmos.cuboid(
+            Transform::from_translation(transform.translation + Vec3::Y * 5.0)
+                .with_scale(vec3(4.0, 10.0, 4.0)),
+            palette.hovered,
+        );
+
+        if keyboard.just_pressed(KeyCode::Delete) {
+            let position = transform.translation.xz();
+            match instance.candidate {
+                Some(candidate) => {
+                    edits.removed.insert(candidate);
+                }
+                None => edits
+                    .added
+                    .retain(|planted| vec2(planted.x, planted.z).distance(position) > 0.01),
+            }
+            tree_placements
+                .trees
+                .retain(|tree| tree.position.xz().distance(position) > 0.01);
+            commands.entity(entity).despawn_recursive();
+            edits.save();
+        }
+    }
+
+    let ctrl = keyboard.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+    if !ctrl || !mouse.just_pressed(MouseButton::Left) {
+        return;
+    }
+    if tree_edit_mode.variant >= terrain_resources.trees.len() {
+        warn!("trees not ready yet");
+        return;
+    }
+
+    let layer = terrain_config.tree_layer();
+    let overridden = keyboard.any_pressed([KeyCode::AltLeft, KeyCode::AltRight]);
+    let steepness = heightfield.steepness_at(hit.xz()).unwrap_or(0.0);
+    if !overridden
+        && (hit.y < terrain_config.water_level + layer.water_distance
+            || steepness > layer.max_steepness)
+    {
+        info!("can't plant a tree here, hold Alt to plant it anyway");
+        return;
+    }
+
+    let mut rng = rand::thread_rng();
+    let planted = PlantedTree {
+        x: hit.x,
+        z: hit.z,
+        spin: rng.gen_range(0.0..std::f32::consts::TAU),
+        scale: rng.gen_range(layer.min_scale..layer.max_scale)
+            * (1.0 - hit.y * layer.height_scale_falloff),
+        variant: tree_edit_mode.variant,
+    };
+    let Some(transform) = planted.transform(&heightfield, layer.base_rotation) else {
+        return;
+    };
+    tree_placements.trees.push(TreePlacement {
+        position: transform.translation,
+        scale: planted.scale,
+        variant: planted.variant,
+    });
+    spawn_tree(
+        &mut commands,
+        &terrain_resources,
+        transform,
+        planted.instance(&heightfield),
+    );
+    edits.added.push(planted);
+    edits.save();
+}
@@ -0,0 +1,213 @@
+//! Picking single trees to see what they were placed from.
+//!
+//! While the scene debug panel is open, clicking a tree selects it, prints its [`TreeInstance`]
+//! to the log and shows it in a small panel. X despawns the selected tree and R spawns it again
+//! where it was, neither is saved in the tree edits.
+
+use bevy::{prelude::*, window::PrimaryWindow};
+
+use crate::{
+    debug_palette::DebugPalette,
+    heightfield::TerrainHeightfield,
+    measure::MeasureMode,
+    scene_debug::SceneDebug,
+    split_view::SecondaryCamera,
+    terrain::{spawn_tree, TerrainResources, TreeInstance},
+    tree_edit::TreeEditMode,
+};
+
+/// Bounding sphere of a tree, centered on the trunk above its base
+const PICK_RADIUS: f32 = 4.0;
+const PICK_HEIGHT: f32 = 6.0;
+
+/// The tree picked last, kept after it's despawned so it can be spawned again
+#[derive(Resource, Default)]
+pub struct TreeInspection {
+    selected: Option<InspectedTree>,
+}
+
+struct InspectedTree {
+    /// `None` once despawned
+    entity: Option<Entity>,
+    transform: Transform,
+    instance: TreeInstance,
+}
+
+#[derive(Component)]
+pub struct TreeInspectionText;
+
+pub fn spawn_tree_inspection_panel(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 16.0,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(5.0),
+            right: Val::Px(5.0),
+            ..default()
+        })
+        .with_background_color(Color::srgba(0.0, 0.0, 0.0, 0.6)),
+        Visibility::Hidden,
+        TreeInspectionText,
+    ));
+}
+
+/// Distance along the ray to the bounding sphere of the tree, if the ray hits it
+fn ray_sphere(ray: Ray3d, center: Vec3, radius: f32) -> Option<f32> {
+    let to_center = center - ray.origin;
+    let along = to_center.dot(*ray.direction);
+    let closest_squared = to_center.length_squared() - along * along;
+    if along < 0.0 || closest_squared > radius * radius {
+        return None;
+    }
+    Some(along - (radius * radius - closest_squared).sqrt())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn inspect_trees(
+    mut commands: Commands,
+    scene_debug: Res<SceneDebug>,
+    tree_edit_mode: Res<TreeEditMode>,
+    measure_mode: Res<MeasureMode>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    heightfield: Res<TerrainHeightfield>,
+    terrain_resources: Res<TerrainResources>,
+    mut inspection: ResMut<TreeInspection>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform), (With<Camera3d>, Without<SecondaryCamera>)>,
+    trees: Query<(Entity, &Transform, &TreeInstance)>,
+    palette: Res<DebugPalette>,
+    mut gizmos: Gizmos,
+) {
+    // The clicks plant trees in the tree edit mode and measure in the measure mode
+    if !scene_debug.open || tree_edit_mode.active || measure_mode.active {
+        return;
+    }
+
+    if mouse.just_pressed(MouseButton::Left) {
+        let ray = window
+            .get_single()
+            .ok()
+            .and_then(|w| w.cursor_position())
+            .zip(camera.get_single().ok())
+            .and_then(|(cursor, (camera, camera_transform))| {
+                camera.viewport_to_world(camera_transform, cursor)
+            });
+        if let Some(ray) = ray {
+            // The trees behind a hill can't be picked through it
+            let max_distance = heightfield
+                .raycast(ray, 1000.0)
+                .map_or(f32::MAX, |hit| hit.distance(ray.origin) + PICK_RADIUS);
+            let picked = trees
+                .iter()
+                .filter_map(|(entity, transform, instance)| {
+                    let center = transform.translation + Vec3::Y * PICK_HEIGHT;
+                    let distance = ray_sphere(ray, center, PICK_RADIUS)?;
+                    Some((entity, transform, instance, distance))
+                })
+                .filter(|(.., distance)| *distance < max_distance)
+                .min_by(|(.., a), (.., b)| a.total_cmp(b));
+            inspection.selected = picked.map(|(entity, transform, instance, _)| {
+                let position = transform.translation;
+                info!("picked tree at {position}: {instance:?}");
+                InspectedTree {
+                    entity: Some(entity),
+                    transform: *transform,
+                    instance: instance.clone(),
+                }
+            });
+        }
+    }
+
+    let Some(selected) = &mut inspection.selected else {
+        return;
+    };
+    // A terrain reload despawns the tree without going through here
+    if selected
+        .entity
+        .is_some_and(|entity| trees.get(entity).is_err())
+    {
+        selected.entity = None;
+    }
+    if keyboard.just_pressed(KeyCode::KeyX) {
+        if let Some(entity) = selected.entity.take() {
+            commands.entity(entity).despawn_recursive();
+            info!("despawned the inspected tree");
+        }
+    }
+    if keyboard.just_pressed(KeyCode::KeyR) {
+        if let Some(entity) = selected.entity.take() {
+            commands.entity(entity).despawn_recursive();
+        }
+        if selected.instance.variant < terrain_resources.trees.len() {
+            selected.entity = Some(spawn_tree(
+                &mut commands,
+                &terrain_resources,
+                selected.transform,
+                selected.instance.clone(),
+            ));
+            info!("respawned the inspected tree");
+        }
+    }
+
+    let color = if selected.entity.is_some() {
+        palette.selected
+    } else {
+        palette.missing
+    };
+    gizmos.sphere(
+        selected.transform.translation + Vec3::Y * PICK_HEIGHT,
+        Quat::IDENTITY,
+        PICK_RADIUS,
+        color,
+    );
+}
+
+pub fn update_tree_inspection_panel(
+    scene_debug: Res<SceneDebug>,
+    inspection: Res<TreeInspection>,
+    mut panel: Query<(&mut Text, &mut Visibility), With<TreeInspectionText>>,
+) {
+    let value = inspection
+        .selected
+        .as_ref()
+        .filter(|_| scene_debug.open)
+        .map(|selected| {
+            let TreeInstance {
+                variant,
+                candidate,
+                terrain_height,
+                steepness,
+                scale,
+            } = &selected.instance;
+            let position = selected.transform.translation;
+            format!(
+                "tree at {:.2}, {:.2}, {:.2}\nvariant: {variant}\ncandidate: {}\n\
+                terrain height: {terrain_height:.3}\nsteepness: {steepness:.3}\nscale: {scale:.4}\n\
+                {}",
+                position.x,
+                position.y,
+                position.z,
+                candidate.map_or("planted by hand".to_string(), |c| c.to_string()),
+                if selected.entity.is_some() {
+                    "X despawn, R respawn"
+                } else {
+                    "despawned, R respawn"
+                },
+            )
+        });
+    for (mut text, mut visibility) in &mut panel {
+        *visibility = if value.is_some() {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+        text.sections[0].value = value.clone().unwrap_or_default();
+    }
+}
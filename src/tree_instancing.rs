@@ -0,0 +1,792 @@
+//! Instanced drawing of the trees.
+//!
+//! Every tree used to be a scene with an entity per mesh of every LOD and shadow proxy, so a dense
+//! forest was hundreds of thousands of entities to propagate, cull and extract every frame. With
+//! `instanced_trees` in the terrain config, the tree scenes are empty and each mesh of each LOD of
+//! a variant is a single [`TreeBatch`] entity holding the transforms of the trees it draws. The
+//! batches are drawn with one instanced draw per view, with the tree materials from the gltf and
+//! their customization, and cast their shadows.
+//!
+//! The LOD of every tree is picked on the CPU from the same ranges as the tree scenes, including
+//! the far tree bands of the `tree_thinning` module and the shadow proxies. It's only picked again
+//! when the camera moved [`REBUILD_DISTANCE`] or a tree moved, was hidden or removed, and the
+//! instance buffers are only uploaded again when their trees changed. The LODs don't crossfade,
+//! the instanced trees keep their material without the snow and they aren't in the prepass, so
+//! they don't show up in the SSR or the SSAO. The trees show up as scenes until their LODs are
+//! generated.
+
+use std::sync::Arc;
+
+use bevy::{
+    core_pipeline::{
+        core_3d::{AlphaMask3d, Opaque3d, Opaque3dBinKey},
+        prepass::{
+            DeferredPrepass, DepthPrepass, MotionVectorPrepass, NormalPrepass,
+            OpaqueNoLightmap3dBinKey,
+        },
+        tonemapping::{DebandDither, Tonemapping},
+    },
+    ecs::{
+        entity::EntityHashMap,
+        query::ROQueryItem,
+        system::{lifetimeless::SRes, SystemParamItem},
+    },
+    pbr::{
+        alpha_mode_pipeline_key, tonemapping_pipeline_key, EnvironmentMapLight, LightEntity,
+        MaterialPipeline, MaterialPipelineKey, MeshPipelineKey, PreparedMaterial, PrepassPipeline,
+        RenderMeshInstances, RenderViewLightProbes, ScreenSpaceAmbientOcclusionSettings,
+        SetMeshBindGroup, SetMeshViewBindGroup, SetPrepassViewBindGroup, Shadow, ShadowBinKey,
+        ShadowFilteringMethod, ViewLightEntities,
+    },
+    prelude::*,
+    render::{
+        camera::TemporalJitter,
+        extract_component::{ExtractComponent, ExtractComponentPlugin},
+        mesh::{GpuBufferInfo, GpuMesh, MeshVertexBufferLayoutRef},
+        render_asset::RenderAssets,
+        render_phase::{
+            AddRenderCommand, BinnedRenderPhaseType, DrawFunctions, PhaseItem, RenderCommand,
+            RenderCommandResult, SetItemPipeline, TrackedRenderPass, ViewBinnedRenderPhases,
+        },
+        render_resource::{
+            Buffer, BufferInitDescriptor, BufferUsages, PipelineCache, RenderPipelineDescriptor,
+            SpecializedMeshPipeline, SpecializedMeshPipelineError, SpecializedMeshPipelines,
+            VertexAttribute, VertexBufferLayout, VertexFormat, VertexStepMode,
+        },
+        renderer::RenderDevice,
+        view::{ExtractedView, NoFrustumCulling, VisibilitySystems, VisibleEntities, WithMesh},
+        Render, RenderApp, RenderSet,
+    },
+    transform::TransformSystem,
+    utils::HashMap,
+};
+
+use crate::{
+    foliage::TreeAlphaMode,
+    overlay::StatsOverlay,
+    render_layers::SceneLayer,
+    split_view::SecondaryCamera,
+    terrain::{apply_tree_material_overrides, TerrainConfig, TreeInstance},
+    tree_lod::TreeLods,
+    tree_thinning::kept,
+};
+
+const SHADER_PATH: &str = "tree_instancing.wgsl";
+/// Distance the camera moves before the LODs of the trees are picked again
+const REBUILD_DISTANCE: f32 = 1.0;
+/// First shader location of the instance transform, after every vertex attribute of the meshes
+const INSTANCE_LOCATION: u32 = 10;
+
+pub struct TreeInstancingPlugin;
+
+impl Plugin for TreeInstancingPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(ExtractComponentPlugin::<TreeBatch>::default())
+            .add_systems(
+                PostUpdate,
+                update_tree_batches
+                    .after(TransformSystem::TransformPropagate)
+                    .after(VisibilitySystems::VisibilityPropagate),
+            );
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app
+            .init_resource::<TreeInstanceBuffers>()
+            .init_resource::<SpecializedMeshPipelines<TreeInstancingPipeline>>()
+            .add_render_command::<Opaque3d, DrawTreeBatch>()
+            .add_render_command::<AlphaMask3d, DrawTreeBatch>()
+            .add_render_command::<Shadow, DrawTreeBatchShadow>()
+            .add_systems(
+                Render,
+                (
+                    (queue_tree_batches, queue_tree_batch_shadows).in_set(RenderSet::QueueMeshes),
+                    prepare_tree_instance_buffers.in_set(RenderSet::PrepareResources),
+                ),
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app.init_resource::<TreeInstancingPipeline>();
+    }
+}
+
+/// What a batch draws of its tree variant
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum BatchKind {
+    Lod(usize),
+    /// The last LOD of the trees past it, only drawn in the shadow maps
+    ShadowProxy,
+}
+
+/// A mesh of a tree variant drawn once per tree in a single instanced draw
+#[derive(Component, ExtractComponent, Clone)]
+pub struct TreeBatch {
+    variant: usize,
+    kind: BatchKind,
+    material: Handle<StandardMaterial>,
+    /// Rows of the affine transform of every tree, shared by the batches of the same LOD
+    instances: Arc<Vec<[Vec4; 3]>>,
+}
+
+/// What draws a tree `distance` away, in the same ranges as the tree scenes
+fn batch_kind(
+    terrain_config: &TerrainConfig,
+    levels: usize,
+    candidate: Option<u32>,
+    distance: f32,
+) -> Option<BatchKind> {
+    // Without LODs the tree is visible at any distance
+    if levels < 2 {
+        return Some(BatchKind::Lod(0));
+    }
+    let level = if distance < terrain_config.lod_distance {
+        0
+    } else {
+        (distance / terrain_config.lod_distance).log2() as usize + 1
+    };
+    if level < levels {
+        return Some(BatchKind::Lod(level));
+    }
+    let band = terrain_config
+        .far_tree_bands
+        .iter()
+        .find(|band| distance < band.end);
+    if band.is_some_and(|band| kept(candidate, band.fraction)) {
+        return Some(BatchKind::Lod(levels - 1));
+    }
+    (terrain_config.shadow_proxies && distance < terrain_config.shadow_proxy_distance)
+        .then_some(BatchKind::ShadowProxy)
+}
+
+fn instance_rows(transform: &GlobalTransform) -> [Vec4; 3] {
+    let rows = Mat4::from(transform.affine()).transpose();
+    [rows.x_axis, rows.y_axis, rows.z_axis]
+}
+
+/// Spawns a batch per mesh of the LODs of every variant and sorts the trees in them
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub fn update_tree_batches(
+    mut commands: Commands,
+    terrain_config: Res<TerrainConfig>,
+    tree_lods: Option<Res<TreeLods>>,
+    alpha_mode: Res<TreeAlphaMode>,
+    mut pbr_materials: ResMut<Assets<StandardMaterial>>,
+    camera: Query<&GlobalTransform, (With<Camera3d>, Without<SecondaryCamera>)>,
+    trees: Query<(&GlobalTransform, &TreeInstance, &InheritedVisibility)>,
+    changed_trees: Query<
+        (),
+        (
+            With<TreeInstance>,
+            Or<(Changed<GlobalTransform>, Changed<InheritedVisibility>)>,
+        ),
+    >,
+    mut removed_trees: RemovedComponents<TreeInstance>,
+    mut batches: Query<(Entity, &mut TreeBatch)>,
+    mut last_eye: Local<Option<Vec3>>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    let removed = removed_trees.read().count() > 0;
+    let Some(tree_lods) = tree_lods.filter(|_| terrain_config.instanced_trees) else {
+        if !batches.is_empty() {
+            for (entity, _) in &batches {
+                commands.entity(entity).despawn();
+            }
+            stats.remove("Tree instances");
+        }
+        *last_eye = None;
+        return;
+    };
+
+    if tree_lods.is_changed() || batches.is_empty() {
+        for (entity, _) in &batches {
+            commands.entity(entity).despawn();
+        }
+        for (variant, lods) in tree_lods.variants.iter().enumerate() {
+            // The last LOD with its own material casts the shadows of the trees past it
+            let shadow_proxy = lods
+                .levels
+                .last()
+                .filter(|_| lods.levels.len() > 1)
+                .map(|primitives| (BatchKind::ShadowProxy, primitives));
+            let levels = lods
+                .levels
+                .iter()
+                .enumerate()
+                .map(|(level, primitives)| (BatchKind::Lod(level), primitives));
+            for (kind, primitives) in levels.chain(shadow_proxy) {
+                for (mesh, material) in primitives {
+                    // What customize_tree_material does to the materials of the tree scenes
+                    if let Some(material) = pbr_materials.get_mut(material) {
+                        apply_tree_material_overrides(material, alpha_mode.0);
+                    }
+                    let layer = match kind {
+                        BatchKind::Lod(_) => SceneLayer::MainScene,
+                        BatchKind::ShadowProxy => SceneLayer::ShadowProxy,
+                    };
+                    commands.spawn((
+                        TreeBatch {
+                            variant,
+                            kind,
+                            material: material.clone(),
+                            instances: default(),
+                        },
+                        mesh.clone(),
+                        SpatialBundle::default(),
+                        // The instances are all over the terrain
+                        NoFrustumCulling,
+                        layer.layers(),
+                    ));
+                }
+            }
+        }
+        // The trees are sorted in the batches once they're spawned
+        *last_eye = None;
+        return;
+    }
+
+    let Ok(camera) = camera.get_single() else {
+        return;
+    };
+    let eye = camera.translation();
+    let moved = last_eye.map_or(true, |last| last.distance(eye) > REBUILD_DISTANCE);
+    if !moved && !removed && changed_trees.is_empty() && !terrain_config.is_changed() {
+        return;
+    }
+    *last_eye = Some(eye);
+
+    let _span = info_span!("update_tree_batches", trees = trees.iter().len()).entered();
+    let mut instances: HashMap<(usize, BatchKind), Vec<[Vec4; 3]>> = HashMap::default();
+    for (transform, instance, visibility) in &trees {
+        // Hidden by the occlusion culling
+        if !visibility.get() {
+            continue;
+        }
+        let Some(lods) = tree_lods.variants.get(instance.variant) else {
+            continue;
+        };
+        let distance = transform.translation().distance(eye);
+        let Some(kind) = batch_kind(
+            &terrain_config,
+            lods.levels.len(),
+            instance.candidate,
+            distance,
+        ) else {
+            continue;
+        };
+        instances
+            .entry((instance.variant, kind))
+            .or_default()
+            .push(instance_rows(transform));
+    }
+    let instances: HashMap<_, _> = instances
+        .into_iter()
+        .map(|(key, rows)| (key, Arc::new(rows)))
+        .collect();
+
+    let (mut drawn, mut shadows, mut draws) = (0, 0, 0);
+    for ((_, kind), rows) in &instances {
+        match kind {
+            BatchKind::Lod(_) => drawn += rows.len(),
+            BatchKind::ShadowProxy => shadows += rows.len(),
+        }
+    }
+    for (_, mut batch) in &mut batches {
+        let rows = instances
+            .get(&(batch.variant, batch.kind))
+            .cloned()
+            .unwrap_or_default();
+        if !rows.is_empty() {
+            draws += 1;
+        }
+        // Unchanged batches keep their buffer on the GPU
+        if *batch.instances != *rows {
+            batch.instances = rows;
+        }
+    }
+    stats.set(
+        "Tree instances",
+        format!("{drawn} drawn, {shadows} only in the shadows, {draws} draws"),
+    );
+}
+
+/// The instances of every batch on the GPU, uploaded again only when they change
+#[derive(Resource, Default)]
+struct TreeInstanceBuffers(EntityHashMap<TreeInstanceBuffer>);
+
+struct TreeInstanceBuffer {
+    instances: Arc<Vec<[Vec4; 3]>>,
+    buffer: Buffer,
+    count: u32,
+}
+
+fn prepare_tree_instance_buffers(
+    batches: Query<(Entity, &TreeBatch)>,
+    render_device: Res<RenderDevice>,
+    mut buffers: ResMut<TreeInstanceBuffers>,
+) {
+    let mut kept = EntityHashMap::default();
+    for (entity, batch) in &batches {
+        if batch.instances.is_empty() {
+            continue;
+        }
+        let buffer = match buffers.0.remove(&entity) {
+            Some(buffer) if Arc::ptr_eq(&buffer.instances, &batch.instances) => buffer,
+            _ => {
+                let contents: Vec<u8> = batch
+                    .instances
+                    .iter()
+                    .flatten()
+                    .flat_map(|row| row.to_array())
+                    .flat_map(f32::to_le_bytes)
+                    .collect();
+                TreeInstanceBuffer {
+                    instances: batch.instances.clone(),
+                    buffer: render_device.create_buffer_with_data(&BufferInitDescriptor {
+                        label: Some("tree_instance_buffer"),
+                        contents: &contents,
+                        usage: BufferUsages::VERTEX,
+                    }),
+                    count: batch.instances.len() as u32,
+                }
+            }
+        };
+        kept.insert(entity, buffer);
+    }
+    buffers.0 = kept;
+}
+
+/// The pipelines of `StandardMaterial` with a vertex shader placing the mesh at every instance
+#[derive(Resource)]
+struct TreeInstancingPipeline {
+    shader: Handle<Shader>,
+    forward: MaterialPipeline<StandardMaterial>,
+    shadow: PrepassPipeline<StandardMaterial>,
+}
+
+impl FromWorld for TreeInstancingPipeline {
+    fn from_world(world: &mut World) -> Self {
+        Self {
+            shader: world.resource::<AssetServer>().load(SHADER_PATH),
+            forward: world
+                .resource::<MaterialPipeline<StandardMaterial>>()
+                .clone(),
+            shadow: PrepassPipeline::from_world(world),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct TreePipelineKey {
+    shadow: bool,
+    material: MaterialPipelineKey<StandardMaterial>,
+}
+
+impl SpecializedMeshPipeline for TreeInstancingPipeline {
+    type Key = TreePipelineKey;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayoutRef,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut descriptor = if key.shadow {
+            self.shadow.specialize(key.material, layout)?
+        } else {
+            let mut descriptor = self.forward.specialize(key.material, layout)?;
+            if let Some(fragment) = descriptor.fragment.as_mut() {
+                fragment.shader = self.shader.clone();
+            }
+            descriptor
+        };
+        descriptor.vertex.shader = self.shader.clone();
+        descriptor.vertex.buffers.push(VertexBufferLayout {
+            array_stride: std::mem::size_of::<[Vec4; 3]>() as u64,
+            step_mode: VertexStepMode::Instance,
+            attributes: (0..3)
+                .map(|row| VertexAttribute {
+                    format: VertexFormat::Float32x4,
+                    offset: row * std::mem::size_of::<Vec4>() as u64,
+                    shader_location: INSTANCE_LOCATION + row as u32,
+                })
+                .collect(),
+        });
+        Ok(descriptor)
+    }
+}
+
+type DrawTreeBatch = (
+    SetItemPipeline,
+    SetMeshViewBindGroup<0>,
+    SetMeshBindGroup<1>,
+    SetTreeMaterialBindGroup<2>,
+    DrawTreeInstances,
+);
+
+type DrawTreeBatchShadow = (
+    SetItemPipeline,
+    SetPrepassViewBindGroup<0>,
+    SetMeshBindGroup<1>,
+    SetTreeMaterialBindGroup<2>,
+    DrawTreeInstances,
+);
+
+/// The batches have no material component, their material comes from the [`TreeBatch`]
+struct SetTreeMaterialBindGroup<const I: usize>;
+
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetTreeMaterialBindGroup<I> {
+    type Param = SRes<RenderAssets<PreparedMaterial<StandardMaterial>>>;
+    type ViewQuery = ();
+    type ItemQuery = &'static TreeBatch;
+
+    fn render<'w>(
+        _item: &P,
+        _view: (),
+        batch: Option<ROQueryItem<'w, Self::ItemQuery>>,
+        materials: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some(material) = batch.and_then(|batch| materials.into_inner().get(&batch.material))
+        else {
+            return RenderCommandResult::Failure;
+        };
+        pass.set_bind_group(I, &material.bind_group, &[]);
+        RenderCommandResult::Success
+    }
+}
+
+/// Draws the mesh of the batch once per tree, the batch range of the item is ignored
+struct DrawTreeInstances;
+
+impl<P: PhaseItem> RenderCommand<P> for DrawTreeInstances {
+    type Param = (
+        SRes<RenderAssets<GpuMesh>>,
+        SRes<RenderMeshInstances>,
+        SRes<TreeInstanceBuffers>,
+    );
+    type ViewQuery = ();
+    type ItemQuery = ();
+
+    fn render<'w>(
+        item: &P,
+        _view: (),
+        _entity: Option<()>,
+        (meshes, mesh_instances, buffers): SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some(mesh_instance) = mesh_instances.render_mesh_queue_data(item.entity()) else {
+            return RenderCommandResult::Failure;
+        };
+        let (Some(mesh), Some(instances)) = (
+            meshes.into_inner().get(mesh_instance.mesh_asset_id),
+            buffers.into_inner().0.get(&item.entity()),
+        ) else {
+            return RenderCommandResult::Failure;
+        };
+        pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+        pass.set_vertex_buffer(1, instances.buffer.slice(..));
+        match &mesh.buffer_info {
+            GpuBufferInfo::Indexed {
+                buffer,
+                index_format,
+                count,
+            } => {
+                pass.set_index_buffer(buffer.slice(..), 0, *index_format);
+                pass.draw_indexed(0..*count, 0, 0..instances.count);
+            }
+            GpuBufferInfo::NonIndexed => {
+                pass.draw(0..mesh.vertex_count, 0..instances.count);
+            }
+        }
+        RenderCommandResult::Success
+    }
+}
+
+/// The mesh and the material of a batch with something to draw
+fn batch_assets<'a>(
+    entity: Entity,
+    batch: &TreeBatch,
+    render_mesh_instances: &RenderMeshInstances,
+    render_meshes: &'a RenderAssets<GpuMesh>,
+    render_materials: &'a RenderAssets<PreparedMaterial<StandardMaterial>>,
+) -> Option<(
+    AssetId<Mesh>,
+    &'a GpuMesh,
+    &'a PreparedMaterial<StandardMaterial>,
+)> {
+    if batch.instances.is_empty() {
+        return None;
+    }
+    let mesh_id = render_mesh_instances
+        .render_mesh_queue_data(entity)?
+        .mesh_asset_id;
+    let mesh = render_meshes.get(mesh_id)?;
+    let material = render_materials.get(&batch.material)?;
+    Some((mesh_id, mesh, material))
+}
+
+/// The bits of the mesh pipeline key coming from the view, what bevy's material queue sets
+#[allow(clippy::too_many_arguments)]
+fn view_key(
+    msaa: &Msaa,
+    view: &ExtractedView,
+    tonemapping: Option<&Tonemapping>,
+    dither: Option<&DebandDither>,
+    shadow_filtering_method: Option<&ShadowFilteringMethod>,
+    ssao: bool,
+    (normal_prepass, depth_prepass, motion_vector_prepass, deferred_prepass): (
+        bool,
+        bool,
+        bool,
+        bool,
+    ),
+    temporal_jitter: bool,
+    projection: Option<&Projection>,
+    environment_map: bool,
+) -> MeshPipelineKey {
+    let mut key =
+        MeshPipelineKey::from_msaa_samples(msaa.samples()) | MeshPipelineKey::from_hdr(view.hdr);
+    key.set(MeshPipelineKey::NORMAL_PREPASS, normal_prepass);
+    key.set(MeshPipelineKey::DEPTH_PREPASS, depth_prepass);
+    key.set(
+        MeshPipelineKey::MOTION_VECTOR_PREPASS,
+        motion_vector_prepass,
+    );
+    key.set(MeshPipelineKey::DEFERRED_PREPASS, deferred_prepass);
+    key.set(MeshPipelineKey::TEMPORAL_JITTER, temporal_jitter);
+    key.set(MeshPipelineKey::ENVIRONMENT_MAP, environment_map);
+    key.set(MeshPipelineKey::SCREEN_SPACE_AMBIENT_OCCLUSION, ssao);
+    key |= match projection {
+        Some(Projection::Orthographic(_)) => MeshPipelineKey::ORTHOGRAPHIC_PROJECTION,
+        Some(Projection::Perspective(_)) => MeshPipelineKey::PERSPECTIVE_PROJECTION,
+        None => MeshPipelineKey::NONE,
+    };
+    key |= match shadow_filtering_method.copied().unwrap_or_default() {
+        ShadowFilteringMethod::Hardware2x2 => MeshPipelineKey::SHADOW_FILTER_METHOD_HARDWARE_2X2,
+        ShadowFilteringMethod::Gaussian => MeshPipelineKey::SHADOW_FILTER_METHOD_GAUSSIAN,
+        ShadowFilteringMethod::Temporal => MeshPipelineKey::SHADOW_FILTER_METHOD_TEMPORAL,
+    };
+    if !view.hdr {
+        if let Some(tonemapping) = tonemapping {
+            key |= MeshPipelineKey::TONEMAP_IN_SHADER | tonemapping_pipeline_key(*tonemapping);
+        }
+        if dither == Some(&DebandDither::Enabled) {
+            key |= MeshPipelineKey::DEBAND_DITHER;
+        }
+    }
+    key
+}
+
+/// Queues the batches seen by every view in the opaque and the alpha mask passes
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn queue_tree_batches(
+    opaque_draw_functions: Res<DrawFunctions<Opaque3d>>,
+    alpha_mask_draw_functions: Res<DrawFunctions<AlphaMask3d>>,
+    pipeline: Res<TreeInstancingPipeline>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<TreeInstancingPipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    msaa: Res<Msaa>,
+    render_meshes: Res<RenderAssets<GpuMesh>>,
+    render_mesh_instances: Res<RenderMeshInstances>,
+    render_materials: Res<RenderAssets<PreparedMaterial<StandardMaterial>>>,
+    batches: Query<&TreeBatch>,
+    mut opaque_phases: ResMut<ViewBinnedRenderPhases<Opaque3d>>,
+    mut alpha_mask_phases: ResMut<ViewBinnedRenderPhases<AlphaMask3d>>,
+    views: Query<(
+        Entity,
+        &ExtractedView,
+        &VisibleEntities,
+        Option<&Tonemapping>,
+        Option<&DebandDither>,
+        Option<&ShadowFilteringMethod>,
+        Has<ScreenSpaceAmbientOcclusionSettings>,
+        (
+            Has<NormalPrepass>,
+            Has<DepthPrepass>,
+            Has<MotionVectorPrepass>,
+            Has<DeferredPrepass>,
+        ),
+        Has<TemporalJitter>,
+        Option<&Projection>,
+        Has<RenderViewLightProbes<EnvironmentMapLight>>,
+    )>,
+) {
+    let draw_opaque = opaque_draw_functions.read().id::<DrawTreeBatch>();
+    let draw_alpha_mask = alpha_mask_draw_functions.read().id::<DrawTreeBatch>();
+    for (
+        view_entity,
+        view,
+        visible_entities,
+        tonemapping,
+        dither,
+        shadow_filtering_method,
+        ssao,
+        prepasses,
+        temporal_jitter,
+        projection,
+        environment_map,
+    ) in &views
+    {
+        let (Some(opaque_phase), Some(alpha_mask_phase)) = (
+            opaque_phases.get_mut(&view_entity),
+            alpha_mask_phases.get_mut(&view_entity),
+        ) else {
+            continue;
+        };
+        let view_key = view_key(
+            &msaa,
+            view,
+            tonemapping,
+            dither,
+            shadow_filtering_method,
+            ssao,
+            prepasses,
+            temporal_jitter,
+            projection,
+            environment_map,
+        );
+
+        // Only the batches in the render layers of the view
+        for &entity in visible_entities.iter::<WithMesh>() {
+            let Ok(batch) = batches.get(entity) else {
+                continue;
+            };
+            if batch.kind == BatchKind::ShadowProxy {
+                continue;
+            }
+            let Some((mesh_id, mesh, material)) = batch_assets(
+                entity,
+                batch,
+                &render_mesh_instances,
+                &render_meshes,
+                &render_materials,
+            ) else {
+                continue;
+            };
+            let alpha_mode = material.properties.alpha_mode;
+            let mesh_key = view_key
+                | MeshPipelineKey::from_bits_retain(mesh.key_bits.bits())
+                | alpha_mode_pipeline_key(alpha_mode, &msaa);
+            let key = TreePipelineKey {
+                shadow: false,
+                material: MaterialPipelineKey {
+                    mesh_key,
+                    bind_group_data: material.key.clone(),
+                },
+            };
+            let pipeline_id =
+                match pipelines.specialize(&pipeline_cache, &pipeline, key, &mesh.layout) {
+                    Ok(pipeline_id) => pipeline_id,
+                    Err(err) => {
+                        error!("{err}");
+                        continue;
+                    }
+                };
+            let material_bind_group_id = material.get_bind_group_id().0;
+            // The procedural bark is the only opaque tree material
+            if alpha_mode == AlphaMode::Opaque {
+                opaque_phase.add(
+                    Opaque3dBinKey {
+                        pipeline: pipeline_id,
+                        draw_function: draw_opaque,
+                        asset_id: mesh_id,
+                        material_bind_group_id,
+                        lightmap_image: None,
+                    },
+                    entity,
+                    BinnedRenderPhaseType::UnbatchableMesh,
+                );
+            } else {
+                alpha_mask_phase.add(
+                    OpaqueNoLightmap3dBinKey {
+                        pipeline: pipeline_id,
+                        draw_function: draw_alpha_mask,
+                        asset_id: mesh_id,
+                        material_bind_group_id,
+                    },
+                    entity,
+                    BinnedRenderPhaseType::UnbatchableMesh,
+                );
+            }
+        }
+    }
+}
+
+/// Queues every batch in the shadow maps of every light, like the shadow proxies all the trees
+/// cast their shadow
+#[allow(clippy::too_many_arguments)]
+fn queue_tree_batch_shadows(
+    shadow_draw_functions: Res<DrawFunctions<Shadow>>,
+    pipeline: Res<TreeInstancingPipeline>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<TreeInstancingPipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    render_meshes: Res<RenderAssets<GpuMesh>>,
+    render_mesh_instances: Res<RenderMeshInstances>,
+    render_materials: Res<RenderAssets<PreparedMaterial<StandardMaterial>>>,
+    batches: Query<(Entity, &TreeBatch)>,
+    view_lights: Query<&ViewLightEntities>,
+    light_views: Query<&LightEntity>,
+    mut shadow_phases: ResMut<ViewBinnedRenderPhases<Shadow>>,
+) {
+    let draw_shadow = shadow_draw_functions.read().id::<DrawTreeBatchShadow>();
+    for view_lights in &view_lights {
+        for &light_view in &view_lights.lights {
+            let (Ok(light), Some(shadow_phase)) = (
+                light_views.get(light_view),
+                shadow_phases.get_mut(&light_view),
+            ) else {
+                continue;
+            };
+            let mut light_key = MeshPipelineKey::DEPTH_PREPASS;
+            light_key.set(
+                MeshPipelineKey::UNCLIPPED_DEPTH_ORTHO,
+                matches!(light, LightEntity::Directional { .. }),
+            );
+
+            for (entity, batch) in &batches {
+                let Some((mesh_id, mesh, material)) = batch_assets(
+                    entity,
+                    batch,
+                    &render_mesh_instances,
+                    &render_meshes,
+                    &render_materials,
+                ) else {
+                    continue;
+                };
+                let mut mesh_key =
+                    light_key | MeshPipelineKey::from_bits_retain(mesh.key_bits.bits());
+                if material.properties.alpha_mode != AlphaMode::Opaque {
+                    mesh_key |= MeshPipelineKey::MAY_DISCARD;
+                }
+                let key = TreePipelineKey {
+                    shadow: true,
+                    material: MaterialPipelineKey {
+                        mesh_key,
+                        bind_group_data: material.key.clone(),
+                    },
+                };
+                let pipeline_id =
+                    match pipelines.specialize(&pipeline_cache, &pipeline, key, &mesh.layout) {
+                        Ok(pipeline_id) => pipeline_id,
+                        Err(err) => {
+                            error!("{err}");
+                            continue;
+                        }
+                    };
+                shadow_phase.add(
+                    ShadowBinKey {
+                        pipeline: pipeline_id,
+                        draw_function: draw_shadow,
+                        asset_id: mesh_id,
+                    },
+                    entity,
+                    BinnedRenderPhaseType::UnbatchableMesh,
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,331 @@
+//! Simplified tree meshes for the mid and far range.
+//!
+//! The LODs are generated once per tree variant on a background task with vertex clustering:
+//! vertices that fall in the same grid cell and have close uvs are collapsed into one and the
+//! triangles that become degenerate are dropped. Only the index buffer changes so every vertex
+//! attribute, including the uvs of the masked leaves, is preserved as is.
+
+use bevy::{
+    prelude::*,
+    render::{
+        mesh::{Indices, VertexAttributeValues},
+        view::VisibilityRange,
+    },
+    tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task},
+    utils::HashMap,
+};
+
+use crate::{
+    foliage::{ShadowProxy, ShadowProxyMaterial},
+    overlay::StatsOverlay,
+    render_layers::SceneLayer,
+    terrain::{TerrainConfig, TreePlacements},
+};
+
+/// A mesh primitive of a tree and its material
+pub type TreePrimitive = (Handle<Mesh>, Handle<StandardMaterial>);
+/// A primitive of the last LOD with a material only drawn in the shadow maps
+pub type ShadowProxyPrimitive = (Handle<Mesh>, Handle<ShadowProxyMaterial>);
+
+/// Fraction of the distance between two LODs used to crossfade them
+const CROSSFADE: f32 = 0.1;
+/// Uv distance under which two vertices in the same cell can be merged
+const UV_CELLS: f32 = 16.0;
+
+/// Every level of detail of a tree variant, the first one being the full mesh
+#[derive(Clone, Default)]
+pub struct TreeVariantLods {
+    pub levels: Vec<Vec<TreePrimitive>>,
+    pub triangles: Vec<usize>,
+    /// Casts the shadows of the tree once it's culled
+    pub shadow_proxy: Vec<ShadowProxyPrimitive>,
+}
+
+#[derive(Resource, Default)]
+pub struct TreeLods {
+    pub variants: Vec<TreeVariantLods>,
+}
+
+/// Simplification of every variant still running in the background
+#[derive(Resource)]
+pub struct TreeLodTasks {
+    tasks: Vec<Task<Vec<Vec<Mesh>>>>,
+    primitives: Vec<Vec<TreePrimitive>>,
+}
+
+fn triangle_count(mesh: &Mesh) -> usize {
+    mesh.indices().map_or(0, |indices| indices.len() / 3)
+}
+
+/// Collapses the vertices of `mesh` on a grid of `resolution` cells along its largest axis
+fn cluster(mesh: &Mesh, resolution: f32) -> Option<Vec<u32>> {
+    let Some(VertexAttributeValues::Float32x3(positions)) =
+        mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+    else {
+        return None;
+    };
+    let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+        Some(VertexAttributeValues::Float32x2(uvs)) => Some(uvs),
+        _ => None,
+    };
+    let indices = mesh.indices()?;
+
+    let (min, max) = positions.iter().fold(
+        (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+        |(min, max), p| (min.min(Vec3::from(*p)), max.max(Vec3::from(*p))),
+    );
+    let cell_size = (max - min).max_element().max(f32::EPSILON) / resolution;
+
+    let mut representatives = HashMap::new();
+    let remap: Vec<u32> = positions
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            let cell = ((Vec3::from(*p) - min) / cell_size).floor().as_ivec3();
+            // Keep the uv islands apart so the leaf cards don't sample the wrong part of the atlas
+            let uv = uvs.map_or(IVec2::ZERO, |uvs| {
+                (Vec2::from(uvs[i]) * UV_CELLS).floor().as_ivec2()
+            });
+            *representatives.entry((cell, uv)).or_insert(i as u32)
+        })
+        .collect();
+
+    let mut simplified = Vec::with_capacity(indices.len());
+    let indices: Vec<usize> = indices.iter().collect();
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [remap[triangle[0]], remap[triangle[1]], remap[triangle[2]]];
+        if a != b && b != c && a != c {
+            simplified.extend([a, b, c]);
+        }
+    }
+    Some(simplified)
+}
+
+/// Simplifies the mesh to roughly `ratio` of its triangles
+pub fn simplify(mesh: &Mesh, ratio: f32) -> Mesh {
+    let target = (triangle_count(mesh) as f32 * ratio) as usize;
+    let mut best: Option<Vec<u32>> = None;
+    // Binary search the grid resolution giving the closest triangle count
+    let (mut low, mut high) = (1.0_f32, 1024.0_f32);
+    for _ in 0..10 {
+        let resolution = (low + high) / 2.0;
+        let Some(indices) = cluster(mesh, resolution) else {
+            break;
+        };
+        let triangles = indices.len() / 3;
+        let closer = best.as_ref().map_or(true, |best| {
+            triangles.abs_diff(target) < (best.len() / 3).abs_diff(target)
+        });
+        if triangles > target {
+            high = resolution;
+        } else {
+            low = resolution;
+        }
+        if closer {
+            best = Some(indices);
+        }
+    }
+
+    let mut simplified = mesh.clone();
+    if let Some(indices) = best {
+        simplified.insert_indices(Indices::U32(indices));
+    }
+    simplified
+}
+
+/// Starts simplifying every primitive of every tree variant in the background
+pub fn start_tree_lod_generation(
+    commands: &mut Commands,
+    primitives: Vec<Vec<TreePrimitive>>,
+    meshes: &Assets<Mesh>,
+    terrain_config: &TerrainConfig,
+) {
+    let ratios = [terrain_config.lod1_ratio, terrain_config.lod2_ratio];
+    let task_pool = AsyncComputeTaskPool::get();
+    let tasks = primitives
+        .iter()
+        .map(|variant| {
+            let variant_meshes: Vec<Mesh> = variant
+                .iter()
+                .filter_map(|(mesh, _)| meshes.get(mesh).cloned())
+                .collect();
+            task_pool.spawn(async move {
+                ratios
+                    .iter()
+                    .flat_map(|ratio| variant_meshes.iter().map(|mesh| simplify(mesh, *ratio)))
+                    .collect()
+            })
+        })
+        .collect();
+    commands.insert_resource(TreeLodTasks { tasks, primitives });
+}
+
+/// Collects the finished LODs and rebuilds the tree scenes with them
+pub fn finish_tree_lods(
+    mut commands: Commands,
+    mut tasks: ResMut<TreeLodTasks>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    pbr_materials: Res<Assets<StandardMaterial>>,
+    mut shadow_proxy_materials: ResMut<Assets<ShadowProxyMaterial>>,
+    mut terrain_config: ResMut<TerrainConfig>,
+) {
+    if !tasks.tasks.iter().all(|task| task.is_finished()) {
+        return;
+    }
+
+    let mut tree_lods = TreeLods::default();
+    let primitives = std::mem::take(&mut tasks.primitives);
+    for (task, full) in tasks.tasks.drain(..).zip(primitives) {
+        let simplified = block_on(future::poll_once(task)).unwrap_or_default();
+        let mut lods = TreeVariantLods {
+            triangles: vec![full
+                .iter()
+                .filter_map(|(mesh, _)| meshes.get(mesh))
+                .map(triangle_count)
+                .sum()],
+            levels: vec![full.clone()],
+            shadow_proxy: vec![],
+        };
+        for level in simplified.chunks(full.len().max(1)) {
+            lods.triangles.push(level.iter().map(triangle_count).sum());
+            lods.levels.push(
+                level
+                    .iter()
+                    .zip(&full)
+                    .map(|(mesh, (_, material))| (meshes.add(mesh.clone()), material.clone()))
+                    .collect(),
+            );
+        }
+        lods.shadow_proxy = lods
+            .levels
+            .last()
+            .into_iter()
+            .flatten()
+            .filter_map(|(mesh, material)| {
+                let material = pbr_materials.get(material)?;
+                let proxy = shadow_proxy_materials.add(ShadowProxyMaterial {
+                    base: StandardMaterial {
+                        alpha_mode: AlphaMode::Mask(0.5),
+                        ..material.clone()
+                    },
+                    extension: ShadowProxy {},
+                });
+                Some((mesh.clone(), proxy))
+            })
+            .collect();
+        tree_lods.variants.push(lods);
+    }
+
+    info!(
+        "tree LODs generated: {:?}",
+        tree_lods
+            .variants
+            .iter()
+            .map(|lods| &lods.triangles)
+            .collect::<Vec<_>>()
+    );
+    commands.insert_resource(tree_lods);
+    commands.remove_resource::<TreeLodTasks>();
+    // Rebuilds the tree scenes with the LODs, the spawned trees follow their modified scene
+    terrain_config.set_changed();
+}
+
+/// Builds the scene of a tree, each LOD is only visible in its own distance range.
+///
+/// The shadow proxy takes over after the last LOD until `shadow_proxy_distance`. Entities out of
+/// their visibility range don't cast shadows either so the proxy needs to be in range too, its
+/// material keeps it out of the main pass. With a `far_distance`, the last LOD stays visible until
+/// it for the trees kept by the `tree_thinning` module.
+pub fn build_tree_scene(
+    levels: &[Vec<TreePrimitive>],
+    shadow_proxy: &[ShadowProxyPrimitive],
+    lod_distance: f32,
+    shadow_proxy_distance: Option<f32>,
+    far_distance: Option<f32>,
+) -> Scene {
+    let mut world = World::new();
+    for (level, primitives) in levels.iter().enumerate() {
+        let visibility_range = (levels.len() > 1).then(|| {
+            let start = if level == 0 {
+                0.0
+            } else {
+                lod_distance * 2.0_f32.powi(level as i32 - 1)
+            };
+            let end = lod_distance * 2.0_f32.powi(level as i32);
+            let end = match far_distance {
+                Some(far_distance) if level == levels.len() - 1 => end.max(far_distance),
+                _ => end,
+            };
+            let start_margin = if level == 0 {
+                0.0..0.0
+            } else {
+                start * (1.0 - CROSSFADE)..start * (1.0 + CROSSFADE)
+            };
+            VisibilityRange {
+                start_margin,
+                end_margin: end * (1.0 - CROSSFADE)..end * (1.0 + CROSSFADE),
+            }
+        });
+        for (mesh, material) in primitives {
+            let mut entity = world.spawn((
+                PbrBundle {
+                    mesh: mesh.clone(),
+                    material: material.clone(),
+                    ..default()
+                },
+                SceneLayer::MainScene.layers(),
+            ));
+            if let Some(visibility_range) = visibility_range.clone() {
+                entity.insert(visibility_range);
+            }
+        }
+    }
+
+    let culled = lod_distance * 2.0_f32.powi(levels.len() as i32 - 1);
+    if let Some(end) = shadow_proxy_distance.filter(|end| levels.len() > 1 && *end > culled) {
+        // Starts while the last LOD fades out so there's no gap in the shadows
+        let visibility_range = VisibilityRange {
+            start_margin: culled * (1.0 - CROSSFADE)..culled * (1.0 + CROSSFADE),
+            end_margin: end * (1.0 - CROSSFADE)..end,
+        };
+        for (mesh, material) in shadow_proxy {
+            world.spawn((
+                MaterialMeshBundle {
+                    mesh: mesh.clone(),
+                    material: material.clone(),
+                    ..default()
+                },
+                visibility_range.clone(),
+                SceneLayer::ShadowProxy.layers(),
+            ));
+        }
+    }
+    Scene::new(world)
+}
+
+/// Shows how many triangles each LOD contributes over every placed tree
+pub fn report_tree_lod_triangles(
+    tree_lods: Res<TreeLods>,
+    tree_placements: Res<TreePlacements>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    let mut totals = vec![];
+    for tree in &tree_placements.trees {
+        let Some(lods) = tree_lods.variants.get(tree.variant) else {
+            continue;
+        };
+        if totals.len() < lods.triangles.len() {
+            totals.resize(lods.triangles.len(), 0);
+        }
+        for (total, triangles) in totals.iter_mut().zip(&lods.triangles) {
+            *total += triangles;
+        }
+    }
+    let totals = totals
+        .iter()
+        .enumerate()
+        .map(|(level, total)| format!("LOD{level} {total}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    stats.set("Tree triangles", totals);
+}
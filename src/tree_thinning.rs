@@ -0,0 +1,185 @@
+//! Sparser forest past the last tree LOD instead of none at all.
+//!
+//! The trees used to be culled after their last LOD, which left the distant hillsides bald. With
+//! `far_tree_bands` in the terrain config, the last LOD stays visible up to the end of the last
+//! band and only a fraction of the trees is kept in each band. The kept trees are picked from
+//! their scatter candidate index so it's always the same ones, and the subset of a band is part of
+//! the subset of every nearer band: a tree never shows up further away than where it was hidden.
+//! The trees planted by hand are always kept.
+//!
+//! The bands of the trees are updated as the camera moves. A tree only changes band once it's
+//! [`HYSTERESIS`] past the boundary so the ones right on it don't flicker. Only the meshes of the
+//! tree are hidden, the shadow proxy keeps casting its shadow and the occlusion culling still
+//! hides the whole tree.
+
+use bevy::prelude::*;
+
+use crate::{
+    foliage::ShadowProxyMaterial,
+    overlay::StatsOverlay,
+    split_view::SecondaryCamera,
+    terrain::{TerrainConfig, TreeInstance},
+    tree_lod::TreeLods,
+    validation::{clamp_float_field, ValidationIssue},
+};
+
+/// Fraction of a boundary distance a tree has to go past it to change band
+const HYSTERESIS: f32 = 0.05;
+/// Spreads the candidate indices evenly over 0..1, the fractional part of the golden ratio
+const GOLDEN_RATIO_FRACT: f64 = 0.618_033_988_749_895;
+
+/// Trees kept up to some distance past the last LOD
+#[derive(Reflect, Clone, Debug, PartialEq)]
+pub struct FarTreeBand {
+    /// Distance from the camera where the band ends, the first band starts after the last LOD
+    pub end: f32,
+    /// Fraction of the trees kept in the band, at most the fraction of the previous band
+    pub fraction: f32,
+}
+
+pub fn default_far_tree_bands() -> Vec<FarTreeBand> {
+    vec![
+        FarTreeBand {
+            end: 640.0,
+            fraction: 0.5,
+        },
+        FarTreeBand {
+            end: 1280.0,
+            fraction: 0.25,
+        },
+    ]
+}
+
+/// Sorts the bands by distance and makes the fractions decrease with it
+pub fn validate_far_tree_bands(bands: &mut [FarTreeBand]) -> Vec<ValidationIssue> {
+    let mut issues = vec![];
+    bands.sort_by(|a, b| a.end.total_cmp(&b.end));
+    let mut previous_fraction = 1.0;
+    for band in bands {
+        clamp_float_field(
+            &mut issues,
+            "far_tree_bands.end",
+            &mut band.end,
+            1.0,
+            100_000.0,
+            1000.0,
+        );
+        clamp_float_field(
+            &mut issues,
+            "far_tree_bands.fraction",
+            &mut band.fraction,
+            0.0,
+            previous_fraction,
+            previous_fraction,
+        );
+        previous_fraction = band.fraction;
+    }
+    issues
+}
+
+/// Band of a tree past its last LOD, added to every spawned tree
+#[derive(Component, Default)]
+pub struct FarTree {
+    /// 0 until the last LOD ends, then 1 for the first band and so on
+    band: usize,
+    hidden: bool,
+}
+
+/// Whether the tree from `candidate` is part of the `fraction` of the trees kept
+pub fn kept(candidate: Option<u32>, fraction: f32) -> bool {
+    let Some(candidate) = candidate else {
+        return true;
+    };
+    (candidate as f64 * GOLDEN_RATIO_FRACT).fract() < fraction as f64
+}
+
+/// Moves the trees between the bands as the camera moves and hides the meshes of the ones left out
+#[allow(clippy::type_complexity)]
+pub fn thin_far_trees(
+    terrain_config: Res<TerrainConfig>,
+    tree_lods: Res<TreeLods>,
+    camera: Query<&GlobalTransform, (With<Camera3d>, Without<SecondaryCamera>)>,
+    mut trees: Query<(&GlobalTransform, &TreeInstance, &mut FarTree, Ref<Children>)>,
+    mut meshes: Query<&mut Visibility, (With<Handle<Mesh>>, Without<Handle<ShadowProxyMaterial>>)>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    // The instanced trees are thinned by the tree_instancing module
+    if terrain_config.instanced_trees {
+        stats.remove("Far trees");
+        return;
+    }
+    let Ok(camera) = camera.get_single() else {
+        return;
+    };
+    let eye = camera.translation();
+    let bands = &terrain_config.far_tree_bands;
+    // The boundaries of the bands for every variant, starting where its last LOD ends
+    let boundaries: Vec<Vec<f32>> = tree_lods
+        .variants
+        .iter()
+        .map(|lods| {
+            let culled = terrain_config.lod_distance * 2.0_f32.powi(lods.levels.len() as i32 - 1);
+            std::iter::once(culled)
+                .chain(bands.iter().map(|band| band.end))
+                .collect()
+        })
+        .collect();
+
+    let mut counts = vec![(0, 0); bands.len() + 1];
+    for (transform, instance, mut far_tree, children) in &mut trees {
+        let Some(boundaries) = boundaries.get(instance.variant) else {
+            continue;
+        };
+        // Without LODs the tree is visible at any distance
+        if tree_lods.variants[instance.variant].levels.len() < 2 {
+            continue;
+        }
+        let distance = transform.translation().distance(eye);
+        let mut band = far_tree.band.min(boundaries.len());
+        while band < boundaries.len() && distance > boundaries[band] * (1.0 + HYSTERESIS) {
+            band += 1;
+        }
+        while band > 0 && distance < boundaries[band - 1] * (1.0 - HYSTERESIS) {
+            band -= 1;
+        }
+        let hidden = match band {
+            0 => false,
+            band => !bands
+                .get(band - 1)
+                .is_some_and(|far_band| kept(instance.candidate, far_band.fraction)),
+        };
+        if let Some((visible, total)) = counts.get_mut(band) {
+            *total += 1;
+            if !hidden {
+                *visible += 1;
+            }
+        }
+
+        // The meshes are spawned again with the scene when the LODs change
+        if far_tree.band != band || far_tree.hidden != hidden || children.is_changed() {
+            far_tree.band = band;
+            far_tree.hidden = hidden;
+            let mut tree_meshes = meshes.iter_many_mut(children.iter());
+            while let Some(mut visibility) = tree_meshes.fetch_next() {
+                *visibility = if hidden {
+                    Visibility::Hidden
+                } else {
+                    Visibility::Inherited
+                };
+            }
+        }
+    }
+
+    if bands.is_empty() {
+        stats.remove("Far trees");
+        return;
+    }
+    let (near, _) = counts[0];
+    let bands = bands
+        .iter()
+        .zip(&counts[1..])
+        .map(|(band, (visible, total))| format!("{visible}/{total} to {:.0}m", band.end))
+        .collect::<Vec<_>>()
+        .join(", ");
+    stats.set("Far trees", format!("{near} near, {bands}"));
+}
@@ -0,0 +1,83 @@
+//! Moving the overlay to its own window.
+//!
+//! O opens a second window for the stats overlay, the error box, the log and the debug panels so
+//! they stop covering the scene on a single monitor, a UI camera of its own renders only them.
+//! Closing that window, or O again, puts them back over the scene. The UI is laid out with the
+//! scale factor of the window it's in, both windows can be moved between monitors with different
+//! scale factors. Desktop only, there's nothing to toggle on the web.
+//!
+//! The keyboard input is shared by every window so O works from both, but the camera controller
+//! ignores everything unless the main window is focused and hovered.
+
+use bevy::{
+    prelude::*,
+    render::camera::RenderTarget,
+    window::{WindowRef, WindowResolution},
+};
+
+use crate::overlay::OverlayNodes;
+
+#[derive(Resource, Default)]
+pub struct DetachedOverlay {
+    /// The second window and the camera rendering the UI in it
+    window: Option<(Entity, Entity)>,
+}
+
+#[derive(Component)]
+pub struct OverlayWindowCamera;
+
+pub fn toggle_detached_overlay(
+    mut commands: Commands,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut detached: ResMut<DetachedOverlay>,
+    windows: Query<(), With<Window>>,
+    nodes: Query<Entity, OverlayNodes>,
+) {
+    let toggle = keyboard.just_pressed(KeyCode::KeyO);
+    if let Some((window, camera)) = detached.window {
+        // Closing the window from its title bar despawns it, the UI still has to come back
+        let closed = windows.get(window).is_err();
+        if !toggle && !closed {
+            return;
+        }
+        for node in &nodes {
+            commands.entity(node).remove::<TargetCamera>();
+        }
+        commands.entity(camera).despawn_recursive();
+        if !closed {
+            commands.entity(window).despawn_recursive();
+        }
+        detached.window = None;
+        info!("overlay back in the main window");
+        return;
+    }
+    if !toggle {
+        return;
+    }
+
+    let window = commands
+        .spawn(Window {
+            title: "Forest scene overlay".to_string(),
+            resolution: WindowResolution::new(480.0, 720.0),
+            ..default()
+        })
+        .id();
+    let camera = commands
+        .spawn((
+            Camera2dBundle {
+                camera: Camera {
+                    target: RenderTarget::Window(WindowRef::Entity(window)),
+                    clear_color: ClearColorConfig::Custom(Color::srgb(0.05, 0.05, 0.05)),
+                    ..default()
+                },
+                ..default()
+            },
+            OverlayWindowCamera,
+        ))
+        .id();
+    for node in &nodes {
+        commands.entity(node).insert(TargetCamera(camera));
+    }
+    detached.window = Some((window, camera));
+    info!("overlay moved to its own window");
+}
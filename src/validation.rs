@@ -0,0 +1,213 @@
+use std::fmt::{Debug, Display};
+
+use bevy::prelude::*;
+
+use crate::{
+    overlay::ErrorBox, scene_transition::SceneConfigTransition, terrain::TerrainConfig, SceneConfig,
+};
+
+/// A config value that was out of range and got replaced
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationIssue {
+    pub field: &'static str,
+    pub value: String,
+    pub allowed: String,
+    pub substituted: String,
+}
+
+impl Display for ValidationIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "`{}` was {}, allowed range is {}, using {} instead",
+            self.field, self.value, self.allowed, self.substituted
+        )
+    }
+}
+
+/// Clamps `value` in `min..=max` and records an issue if it was outside of it
+pub fn clamp_field<T: PartialOrd + Debug + Copy>(
+    issues: &mut Vec<ValidationIssue>,
+    field: &'static str,
+    value: &mut T,
+    min: T,
+    max: T,
+) {
+    let clamped = if *value < min {
+        min
+    } else if *value > max {
+        max
+    } else {
+        return;
+    };
+    issues.push(ValidationIssue {
+        field,
+        value: format!("{value:?}"),
+        allowed: format!("{min:?}..={max:?}"),
+        substituted: format!("{clamped:?}"),
+    });
+    *value = clamped;
+}
+
+/// Same as [`clamp_field`] but also replaces NaN and infinities with `fallback`
+pub fn clamp_float_field(
+    issues: &mut Vec<ValidationIssue>,
+    field: &'static str,
+    value: &mut f32,
+    min: f32,
+    max: f32,
+    fallback: f32,
+) {
+    if !value.is_finite() {
+        issues.push(ValidationIssue {
+            field,
+            value: format!("{value:?}"),
+            allowed: format!("{min:?}..={max:?}"),
+            substituted: format!("{fallback:?}"),
+        });
+        *value = fallback;
+        return;
+    }
+    clamp_field(issues, field, value, min, max);
+}
+
+fn report(config_name: &str, issues: &[ValidationIssue], error_box: &mut ErrorBox) {
+    error_box.clear(config_name);
+    for issue in issues {
+        warn!("{config_name}: {issue}");
+        error_box.push(config_name, format!("{config_name}: {issue}"));
+    }
+}
+
+// Validating marks the resources as changed without going through change detection, otherwise
+// validation would trigger itself again every frame.
+
+pub fn validate_terrain_config(
+    mut terrain_config: ResMut<TerrainConfig>,
+    mut error_box: ResMut<ErrorBox>,
+) {
+    let issues = terrain_config.bypass_change_detection().validate();
+    report("terrain config", &issues, &mut error_box);
+}
+
+pub fn validate_scene_config(
+    mut scene_config: ResMut<SceneConfig>,
+    transition: Res<SceneConfigTransition>,
+    mut error_box: ResMut<ErrorBox>,
+) {
+    // The blends of a transition are between two configs validated already
+    if transition.wrote(scene_config.last_changed()) {
+        return;
+    }
+    let issues = scene_config.bypass_change_detection().validate();
+    report("scene config", &issues, &mut error_box);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamp_field_keeps_the_bounds() {
+        let mut issues = vec![];
+        for mut value in [1, 5, 2000] {
+            clamp_field(&mut issues, "half_size", &mut value, 1, 2000);
+        }
+        let mut value = 0.0;
+        clamp_float_field(&mut issues, "density", &mut value, 0.0, 1.0, 0.5);
+        assert_eq!(value, 0.0);
+        assert!(issues.is_empty(), "{issues:?}");
+    }
+
+    #[test]
+    fn clamp_field_clamps_to_the_nearest_bound() {
+        let mut issues = vec![];
+        let mut below = 0;
+        let mut above = 2001;
+        clamp_field(&mut issues, "half_size", &mut below, 1, 2000);
+        clamp_field(&mut issues, "half_size", &mut above, 1, 2000);
+        assert_eq!((below, above), (1, 2000));
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0].value, "0");
+        assert_eq!(issues[0].allowed, "1..=2000");
+        assert_eq!(issues[0].substituted, "1");
+        assert_eq!(issues[1].substituted, "2000");
+    }
+
+    #[test]
+    fn clamp_float_field_replaces_non_finite_values() {
+        let mut issues = vec![];
+        for value in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let mut value = value;
+            clamp_float_field(&mut issues, "density", &mut value, 0.0, 1.0, 0.5);
+            assert_eq!(value, 0.5);
+        }
+        assert_eq!(issues.len(), 3);
+        // Infinity is past the max but the fallback is used, not the bound
+        assert!(issues.iter().all(|issue| issue.substituted == "0.5"));
+    }
+
+    #[test]
+    fn default_configs_are_valid() {
+        let issues = TerrainConfig::default().validate();
+        assert!(issues.is_empty(), "{issues:?}");
+        let issues = SceneConfig::default().validate();
+        assert!(issues.is_empty(), "{issues:?}");
+    }
+
+    #[test]
+    fn terrain_config_is_clamped_at_the_boundaries() {
+        let mut config = TerrainConfig {
+            half_size: 0,
+            chunk_size: 4096,
+            frequency: 0.0,
+            density: 1.5,
+            max_steepness: 0.0,
+            water_level: f32::NAN,
+            ..default()
+        };
+        let issues = config.validate();
+        let fields: Vec<_> = issues.iter().map(|issue| issue.field).collect();
+        assert_eq!(
+            fields,
+            [
+                "half_size",
+                "chunk_size",
+                "frequency",
+                "density",
+                "max_steepness",
+                "water_level"
+            ]
+        );
+        let default = TerrainConfig::default();
+        assert_eq!(config.half_size, 1);
+        assert_eq!(config.chunk_size, 512);
+        assert_eq!(config.frequency, default.frequency);
+        assert_eq!(config.density, 1.0);
+        assert_eq!(config.max_steepness, 0.01);
+        assert_eq!(config.water_level, default.water_level);
+        // Validating again finds nothing left to fix
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn scene_config_is_clamped_at_the_boundaries() {
+        let mut config = SceneConfig {
+            rain_intensity: 2.0,
+            env_map_intensity: -1.0,
+            motion_blur_samples: 65,
+            camera_walk_speed: f32::INFINITY,
+            ..default()
+        };
+        let issues = config.validate();
+        assert_eq!(issues.len(), 4, "{issues:?}");
+        assert_eq!(config.rain_intensity, 1.0);
+        assert_eq!(config.env_map_intensity, 0.0);
+        assert_eq!(config.motion_blur_samples, 64);
+        assert_eq!(
+            config.camera_walk_speed,
+            SceneConfig::default().camera_walk_speed
+        );
+        assert!(config.validate().is_empty());
+    }
+}
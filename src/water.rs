@@ -1,10 +1,14 @@
 use bevy::{
     color::palettes::css::BLACK,
-    math::vec4,
-    pbr::{ExtendedMaterial, MaterialExtension},
+    math::{vec2, vec4},
+    pbr::{ExtendedMaterial, LightProbe, MaterialExtension},
     prelude::*,
     render::{
-        render_resource::{AsBindGroup, ShaderRef, ShaderType},
+        mesh::{Indices, PrimitiveTopology},
+        render_asset::RenderAssetUsages,
+        render_resource::{
+            AsBindGroup, Extent3d, ShaderRef, ShaderType, TextureDimension, TextureFormat,
+        },
         texture::{
             ImageAddressMode, ImageFilterMode, ImageLoaderSettings, ImageSampler,
             ImageSamplerDescriptor,
@@ -12,6 +16,16 @@ use bevy::{
     },
 };
 
+use crate::{
+    camera_controller::CameraController,
+    heightfield::TerrainHeightfield,
+    render_layers::SceneLayer,
+    split_view::SecondaryCamera,
+    terrain::TerrainConfig,
+    wind::{WindMaterial, WindUniform},
+    SceneConfig,
+};
+
 /// A custom [`ExtendedMaterial`] that creates animated water ripples.
 #[derive(Asset, TypePath, AsBindGroup, Debug, Clone)]
 pub struct Water {
@@ -25,24 +39,329 @@ pub struct Water {
     // Parameters to the water shader.
     #[uniform(102)]
     settings: WaterSettings,
+
+    /// The slopes of the ripple simulation around the camera.
+    ///
+    /// See [`WaterRipples`].
+    #[texture(103)]
+    #[sampler(104)]
+    ripples: Handle<Image>,
+
+    /// How deep the water is over the terrain, normalized by [`MAX_WATER_DEPTH`].
+    #[texture(105)]
+    #[sampler(106)]
+    depth: Handle<Image>,
+
+    /// How sheltered from the wind the water is by the terrain upwind, over the same region as
+    /// the depth.
+    #[texture(107)]
+    #[sampler(108)]
+    shelter: Handle<Image>,
+
+    /// See the `wind` module.
+    #[uniform(120)]
+    wind: WindUniform,
 }
 
 impl MaterialExtension for Water {
+    fn fragment_shader() -> ShaderRef {
+        "water_material.wgsl".into()
+    }
+
     fn deferred_fragment_shader() -> ShaderRef {
         "water_material.wgsl".into()
     }
 }
 
+/// The full water material, the [`Water`] extension over a [`StandardMaterial`].
+pub type WaterMaterial = ExtendedMaterial<StandardMaterial, Water>;
+
+impl WindMaterial for WaterMaterial {
+    fn wind(&self) -> WindUniform {
+        self.extension.wind
+    }
+
+    fn wind_mut(&mut self) -> &mut WindUniform {
+        &mut self.extension.wind
+    }
+}
+
 /// Parameters to the water shader.
 #[derive(ShaderType, Debug, Clone)]
 pub struct WaterSettings {
     /// How much to displace each octave each frame, in the u and v directions.
-    /// Two octaves are packed into each `vec4`.
+    /// Two octaves are packed into each `vec4`. The primary octave drifts downwind instead, at
+    /// the speed in x.
     octave_vectors: [Vec4; 2],
     /// How wide the waves are in each octave.
     octave_scales: Vec4,
     /// How high the waves are in each octave.
     octave_strengths: Vec4,
+    /// The region covered by the ripple texture.
+    /// xy is the world space origin, z the extent and w the strength of the ripples.
+    ripple_region: Vec4,
+    /// Color of the suspended particles in deep water
+    murk_color: Vec4,
+    /// x: murk density, y: clarity depth, z: depth texture origin x, w: depth texture origin z
+    murk_params: Vec4,
+    /// x: depth and shelter texture extent, y: max depth encoded in the texture,
+    /// z: shelter strength
+    depth_region: Vec4,
+}
+
+/// Settings for the look of the water body
+#[derive(Reflect, Clone, Debug)]
+pub struct WaterConfig {
+    /// Color the water fades to with depth
+    pub murk_color: Color,
+    /// How fast the water gets murky past the clarity depth, 0 keeps the water clear
+    pub murk_density: f32,
+    /// The water is perfectly clear until this depth
+    pub clarity_depth: f32,
+    /// Direction the wind blows toward on the ground plane, for everything that moves with the
+    /// wind, see the `wind` module
+    pub wind_direction: Vec2,
+    /// How much calmer the water gets in the lee of the terrain, 0 keeps the waves uniform
+    pub shelter_strength: f32,
+    /// Terrain further upwind than this doesn't shelter the water
+    pub shelter_distance: f32,
+    /// Height of the terrain above the water that fully shelters what's right behind it
+    pub shelter_height: f32,
+}
+
+impl Default for WaterConfig {
+    fn default() -> Self {
+        Self {
+            murk_color: Color::srgb(0.05, 0.12, 0.08),
+            murk_density: 0.3,
+            clarity_depth: 0.5,
+            wind_direction: Vec2::new(1.0, 0.3),
+            shelter_strength: 0.7,
+            shelter_distance: 60.0,
+            shelter_height: 8.0,
+        }
+    }
+}
+
+/// Settings for the foam plane over the shallow water
+#[derive(Reflect, Clone, Debug)]
+pub struct FoamConfig {
+    /// How much the foam is broken into streaks drifting with the water ripples, 0 keeps the
+    /// foam uniform
+    pub streak_strength: f32,
+    /// Water depth at which the foam is gone, 0 lets it cover any depth
+    pub shore_falloff_depth: f32,
+}
+
+impl Default for FoamConfig {
+    fn default() -> Self {
+        Self {
+            streak_strength: 0.6,
+            shore_falloff_depth: 1.5,
+        }
+    }
+}
+
+/// Depth encoded as 1.0 in the water depth texture
+pub const MAX_WATER_DEPTH: f32 = 25.0;
+const WATER_DEPTH_RESOLUTION: u32 = 256;
+const WATER_SHELTER_RESOLUTION: u32 = 128;
+/// Terrain samples along the upwind line of every shelter texel
+const SHELTER_STEPS: u32 = 16;
+/// The shelter is only baked again once the terrain and the wind stopped changing for this long,
+/// the sculpting brush changes the terrain every frame
+const SHELTER_DEBOUNCE_SECONDS: f32 = 0.5;
+
+/// Marker for the main water plane
+#[derive(Component)]
+pub struct WaterPlane;
+
+/// Marker for the reflection probe giving the water the sky at the brightness of the skybox.
+///
+/// The water is black and smooth, what it shows is the specular of the environment map. SSR can't
+/// hit the skybox since it has no depth, so the sky in the lake always comes from the environment
+/// map, lit by `env_map_intensity` instead of the `skybox_brightness` of the sky above it. A dusk
+/// look lowering the skybox brightness left the lake glowing. The probe is a thin box around the
+/// surface with the cubemap of the camera at the skybox brightness, see [`sync_water_reflection`].
+/// The terrain right at the shoreline is inside it too and gets its diffuse from it.
+#[derive(Component)]
+pub struct WaterReflectionProbe;
+
+/// Marker for the foam plane just above the water
+#[derive(Component)]
+pub struct FoamPlane;
+
+/// Anything with this component will create ripples when moving through the water
+#[derive(Component)]
+pub struct WaterDisturber {
+    /// How far from the center of the entity the water is disturbed
+    pub radius: f32,
+    /// How strong the impulse is when the entity moves
+    pub strength: f32,
+    last_position: Option<Vec3>,
+}
+
+impl Default for WaterDisturber {
+    fn default() -> Self {
+        Self {
+            radius: 0.5,
+            strength: 1.0,
+            last_position: None,
+        }
+    }
+}
+
+/// Resolution of the ripple simulation grid, in texels
+const RIPPLE_RESOLUTION: usize = 128;
+/// The simulation runs at a fixed rate so the waves travel at the same speed on every machine
+const RIPPLE_STEP: f32 = 1.0 / 60.0;
+
+/// A small wave simulation covering a region around the camera.
+///
+/// The heights are simulated on the CPU and the slopes are uploaded to an RG texture that the
+/// water shader uses to perturb its normals.
+#[derive(Resource)]
+pub struct WaterRipples {
+    pub enabled: bool,
+    /// Size of the simulated region in world units
+    pub extent: f32,
+    /// Roughly how long it takes for a ripple to die out
+    pub decay_seconds: f32,
+    /// How much the ripples perturb the water normals
+    pub strength: f32,
+    image: Handle<Image>,
+    /// Origin of the region in texels, it's always snapped to a texel so scrolling doesn't swim
+    origin: IVec2,
+    current: Vec<f32>,
+    previous: Vec<f32>,
+    accumulator: f32,
+}
+
+impl WaterRipples {
+    fn new(image: Handle<Image>) -> Self {
+        Self {
+            enabled: true,
+            extent: 48.0,
+            decay_seconds: 2.0,
+            strength: 4.0,
+            image,
+            origin: IVec2::ZERO,
+            current: vec![0.0; RIPPLE_RESOLUTION * RIPPLE_RESOLUTION],
+            previous: vec![0.0; RIPPLE_RESOLUTION * RIPPLE_RESOLUTION],
+            accumulator: 0.0,
+        }
+    }
+
+    fn texel_size(&self) -> f32 {
+        self.extent / RIPPLE_RESOLUTION as f32
+    }
+
+    fn origin_world(&self) -> Vec2 {
+        self.origin.as_vec2() * self.texel_size()
+    }
+
+    /// Moves the region so it stays centered on `center`.
+    ///
+    /// The existing heights are shifted by whole texels so the ripples stay in place in the world.
+    fn recenter(&mut self, center: Vec2) {
+        let half = RIPPLE_RESOLUTION as i32 / 2;
+        let origin = (center / self.texel_size()).floor().as_ivec2() - IVec2::splat(half);
+        let delta = origin - self.origin;
+        if delta == IVec2::ZERO {
+            return;
+        }
+        self.origin = origin;
+        for buffer in [&mut self.current, &mut self.previous] {
+            let old = buffer.clone();
+            for y in 0..RIPPLE_RESOLUTION as i32 {
+                for x in 0..RIPPLE_RESOLUTION as i32 {
+                    let (sx, sy) = (x + delta.x, y + delta.y);
+                    let in_bounds = (0..RIPPLE_RESOLUTION as i32).contains(&sx)
+                        && (0..RIPPLE_RESOLUTION as i32).contains(&sy);
+                    buffer[y as usize * RIPPLE_RESOLUTION + x as usize] = if in_bounds {
+                        old[sy as usize * RIPPLE_RESOLUTION + sx as usize]
+                    } else {
+                        0.0
+                    };
+                }
+            }
+        }
+    }
+
+    fn add_impulse(&mut self, position: Vec2, radius: f32, strength: f32) {
+        let center = position / self.texel_size() - self.origin.as_vec2();
+        let radius = (radius / self.texel_size()).max(1.0);
+        let min = (center - radius).floor().as_ivec2().max(IVec2::ONE);
+        let max = (center + radius)
+            .ceil()
+            .as_ivec2()
+            .min(IVec2::splat(RIPPLE_RESOLUTION as i32 - 2));
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                let distance = vec2(x as f32, y as f32).distance(center);
+                if distance > radius {
+                    continue;
+                }
+                // smooth falloff so the impulse doesn't create a sharp ring
+                let falloff = 0.5 + 0.5 * (distance / radius * std::f32::consts::PI).cos();
+                self.current[y as usize * RIPPLE_RESOLUTION + x as usize] -= strength * falloff;
+            }
+        }
+    }
+
+    fn step(&mut self) {
+        let damping = 0.01_f32.powf(RIPPLE_STEP / self.decay_seconds.max(RIPPLE_STEP));
+        let n = RIPPLE_RESOLUTION;
+        for y in 1..n - 1 {
+            for x in 1..n - 1 {
+                let i = y * n + x;
+                let neighbours = self.current[i - 1]
+                    + self.current[i + 1]
+                    + self.current[i - n]
+                    + self.current[i + n];
+                self.previous[i] = (neighbours * 0.5 - self.previous[i]) * damping;
+            }
+        }
+        std::mem::swap(&mut self.current, &mut self.previous);
+    }
+
+    /// Writes the slopes of the current heights in the image data
+    fn write_slopes(&self, data: &mut [u8]) {
+        let n = RIPPLE_RESOLUTION;
+        let encode = |v: f32| ((v * 0.5).clamp(-1.0, 1.0) * 127.0) as i8 as u8;
+        for y in 0..n {
+            for x in 0..n {
+                let height = |x: usize, y: usize| self.current[y * n + x];
+                let dx = height((x + 1).min(n - 1), y) - height(x.saturating_sub(1), y);
+                let dz = height(x, (y + 1).min(n - 1)) - height(x, y.saturating_sub(1));
+                let i = (y * n + x) * 2;
+                data[i] = encode(dx);
+                data[i + 1] = encode(dz);
+            }
+        }
+    }
+}
+
+fn ripple_image() -> Image {
+    let mut image = Image::new_fill(
+        Extent3d {
+            width: RIPPLE_RESOLUTION as u32,
+            height: RIPPLE_RESOLUTION as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0],
+        TextureFormat::Rg8Snorm,
+        RenderAssetUsages::default(),
+    );
+    image.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
+        address_mode_u: ImageAddressMode::ClampToEdge,
+        address_mode_v: ImageAddressMode::ClampToEdge,
+        mag_filter: ImageFilterMode::Linear,
+        min_filter: ImageFilterMode::Linear,
+        ..default()
+    });
+    image
 }
 
 pub fn spawn_water(
@@ -51,57 +370,468 @@ pub fn spawn_water(
     mut meshes: ResMut<Assets<Mesh>>,
     mut water_materials: ResMut<Assets<ExtendedMaterial<StandardMaterial, Water>>>,
     mut foam_materials: ResMut<Assets<FoamMaterial>>,
+    mut images: ResMut<Assets<Image>>,
 ) {
-    commands.spawn(MaterialMeshBundle {
-        mesh: meshes.add(Plane3d::new(Vec3::Y, Vec2::splat(1.0))),
-        material: water_materials.add(ExtendedMaterial {
-            base: StandardMaterial {
-                base_color: BLACK.into(),
-                perceptual_roughness: 0.0,
-                ..default()
-            },
-            extension: Water {
-                normals: asset_server.load_with_settings::<Image, ImageLoaderSettings>(
-                    "water_normals.png",
-                    |settings| {
-                        settings.is_srgb = false;
-                        settings.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
-                            address_mode_u: ImageAddressMode::Repeat,
-                            address_mode_v: ImageAddressMode::Repeat,
-                            mag_filter: ImageFilterMode::Linear,
-                            min_filter: ImageFilterMode::Linear,
-                            ..default()
-                        });
+    let ripples = images.add(ripple_image());
+    let depth = images.add(water_region_image(&[u8::MAX]));
+    let shelter = images.add(water_region_image(&[0]));
+    // Shared by the water and the foam, replaced once the terrain is generated
+    let water_mesh = meshes.add(Plane3d::new(Vec3::Y, Vec2::splat(WATER_EXTENT)));
+    commands.insert_resource(WaterRipples::new(ripples.clone()));
+
+    let mut water = commands.spawn((
+        MaterialMeshBundle {
+            mesh: water_mesh.clone(),
+            material: water_materials.add(ExtendedMaterial {
+                base: StandardMaterial {
+                    base_color: BLACK.into(),
+                    perceptual_roughness: 0.0,
+                    ..default()
+                },
+                extension: Water {
+                    normals: asset_server.load_with_settings::<Image, ImageLoaderSettings>(
+                        "water_normals.png",
+                        |settings| {
+                            settings.is_srgb = false;
+                            settings.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
+                                address_mode_u: ImageAddressMode::Repeat,
+                                address_mode_v: ImageAddressMode::Repeat,
+                                mag_filter: ImageFilterMode::Linear,
+                                min_filter: ImageFilterMode::Linear,
+                                ..default()
+                            });
+                        },
+                    ),
+                    // These water settings are just random values to create some
+                    // variety.
+                    settings: WaterSettings {
+                        octave_vectors: [
+                            vec4(0.1, 0.0, 0.073, -0.062),
+                            vec4(0.153, 0.138, -0.149, -0.195),
+                        ],
+                        octave_scales: vec4(1.0, 2.1, 7.9, 14.9) * 20.0,
+                        octave_strengths: vec4(0.16, 0.18, 0.093, 0.044),
+                        ripple_region: Vec4::ZERO,
+                        murk_color: Vec4::ZERO,
+                        murk_params: Vec4::ZERO,
+                        depth_region: Vec4::ZERO,
                     },
-                ),
-                // These water settings are just random values to create some
-                // variety.
-                settings: WaterSettings {
-                    octave_vectors: [
-                        vec4(0.080, 0.059, 0.073, -0.062),
-                        vec4(0.153, 0.138, -0.149, -0.195),
-                    ],
-                    octave_scales: vec4(1.0, 2.1, 7.9, 14.9) * 20.0,
-                    octave_strengths: vec4(0.16, 0.18, 0.093, 0.044),
+                    ripples,
+                    depth: depth.clone(),
+                    shelter,
+                    wind: default(),
                 },
-            },
-        }),
-        transform: Transform::from_scale(Vec3::splat(1000.0))
-            .with_translation(Vec3::new(0.0, -0.05, 0.0)),
-        ..default()
+            }),
+            transform: Transform::from_xyz(0.0, -0.05, 0.0),
+            ..default()
+        },
+        WaterPlane,
+        SceneLayer::MainScene.layers(),
+    ));
+    // The environment map is added once the camera has one, see `sync_water_reflection`
+    water.with_children(|water| {
+        water.spawn((
+            LightProbe,
+            SpatialBundle::from_transform(Transform::from_scale(Vec3::new(
+                WATER_EXTENT * 2.0,
+                REFLECTION_PROBE_HEIGHT,
+                WATER_EXTENT * 2.0,
+            ))),
+            WaterReflectionProbe,
+        ));
     });
     // add foam just above the water
-    commands.spawn(MaterialMeshBundle {
-        mesh: meshes.add(Plane3d::new(Vec3::Y, Vec2::splat(1.0))),
-        material: foam_materials.add(FoamMaterial {}),
-        transform: Transform::from_scale(Vec3::splat(1000.0))
-            .with_translation(Vec3::new(0.0, 0.0, 0.0)),
+    commands.spawn((
+        MaterialMeshBundle {
+            mesh: water_mesh,
+            material: foam_materials.add(FoamMaterial {
+                settings: FoamSettings::default(),
+                depth,
+                wind: default(),
+            }),
+            ..default()
+        },
+        FoamPlane,
+        SceneLayer::MainScene.layers(),
+    ));
+}
+
+/// Matches the time scale of the normals in `water_material.wgsl`
+const WATER_TIME_SCALE: f32 = 0.15;
+
+/// Keeps the foam streaks drifting with the primary water octave and fading away from the shore.
+pub fn sync_foam_with_water(
+    scene_config: Res<SceneConfig>,
+    water: Query<&Handle<WaterMaterial>, With<WaterPlane>>,
+    foam: Query<&Handle<FoamMaterial>, With<FoamPlane>>,
+    water_materials: Res<Assets<WaterMaterial>>,
+    mut foam_materials: ResMut<Assets<FoamMaterial>>,
+) {
+    let Some(water) = water
+        .get_single()
+        .ok()
+        .and_then(|handle| water_materials.get(handle))
+    else {
+        return;
+    };
+    let water = &water.extension;
+    let foam_config = &scene_config.foam;
+    let settings = FoamSettings {
+        streaks: vec4(
+            water.settings.octave_vectors[0].x,
+            WATER_TIME_SCALE,
+            water.settings.octave_scales.x,
+            foam_config.streak_strength,
+        ),
+        shore: vec4(
+            foam_config.shore_falloff_depth,
+            water.settings.murk_params.z,
+            water.settings.murk_params.w,
+            water.settings.depth_region.x,
+        ),
+        depth_region: vec4(water.settings.depth_region.y, 0.0, 0.0, 0.0),
+    };
+    for handle in &foam {
+        if let Some(foam) = foam_materials.get_mut(handle) {
+            foam.settings = settings.clone();
+        }
+    }
+}
+
+/// Half the size of the water plane
+const WATER_EXTENT: f32 = 1000.0;
+/// How far above the water surface the terrain still gets water under it, this keeps the
+/// shoreline covered
+const SHORE_MARGIN: f32 = 0.1;
+
+/// Builds a water plane with holes where the terrain is above the water.
+///
+/// The terrain is covered cell by cell, a cell only gets water if any of its vertices or of its
+/// neighbours' is below the surface. Around the terrain the plane is made of 4 large quads.
+fn clipped_water_mesh(heightfield: &TerrainHeightfield, water_level: f32) -> Mesh {
+    let resolution = heightfield.resolution;
+    let half = heightfield.size * 0.5;
+    let spacing = heightfield.size / (resolution - 1) as f32;
+
+    // Rectangles in the terrain's local space
+    let mut rects = vec![
+        (
+            vec2(-WATER_EXTENT, -WATER_EXTENT),
+            vec2(-half, WATER_EXTENT),
+        ),
+        (vec2(half, -WATER_EXTENT), vec2(WATER_EXTENT, WATER_EXTENT)),
+        (vec2(-half, -WATER_EXTENT), vec2(half, -half)),
+        (vec2(-half, half), vec2(half, WATER_EXTENT)),
+    ];
+    let wet = |x: usize, z: usize| {
+        let (x0, x1) = (x.saturating_sub(1), (x + 2).min(resolution - 1));
+        let (z0, z1) = (z.saturating_sub(1), (z + 2).min(resolution - 1));
+        (z0..=z1).any(|z| (x0..=x1).any(|x| heightfield.height(x, z) < water_level + SHORE_MARGIN))
+    };
+    for z in 0..resolution - 1 {
+        // Merge the runs of wet cells of a row in a single quad
+        let mut run_start = None;
+        for x in 0..resolution {
+            let is_wet = x < resolution - 1 && wet(x, z);
+            match (is_wet, run_start) {
+                (true, None) => run_start = Some(x),
+                (false, Some(start)) => {
+                    let min = vec2(start as f32, z as f32) * spacing - half;
+                    let max = vec2(x as f32, (z + 1) as f32) * spacing - half;
+                    rects.push((min, max));
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let rotation = Quat::from_axis_angle(Vec3::Y, heightfield.rotation);
+    let mut positions = Vec::with_capacity(rects.len() * 4);
+    let mut uvs = Vec::with_capacity(rects.len() * 4);
+    let mut indices = Vec::with_capacity(rects.len() * 6);
+    for (min, max) in rects {
+        let base = positions.len() as u32;
+        for corner in [min, vec2(min.x, max.y), max, vec2(max.x, min.y)] {
+            let world = rotation * Vec3::new(corner.x, 0.0, corner.y);
+            positions.push(world.to_array());
+            // Uvs span the whole plane like before it was clipped
+            uvs.push(((world.xz() + WATER_EXTENT) / (2.0 * WATER_EXTENT)).to_array());
+        }
+        indices.extend([base, base + 1, base + 2, base, base + 2, base + 3]);
+    }
+    let normals = vec![[0.0, 1.0, 0.0]; positions.len()];
+
+    Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::RENDER_WORLD,
+    )
+    .with_inserted_indices(Indices::U32(indices))
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+}
+
+/// Cuts the water under the terrain so it isn't shaded for nothing
+pub fn clip_water_plane(
+    terrain_config: Res<TerrainConfig>,
+    heightfield: Res<TerrainHeightfield>,
+    water: Query<&Handle<Mesh>, With<WaterPlane>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    if !heightfield.is_changed() && !terrain_config.is_changed() {
+        return;
+    }
+    let _span = info_span!("clip_water_plane").entered();
+    for handle in &water {
+        meshes.insert(
+            handle,
+            clipped_water_mesh(&heightfield, terrain_config.water_level),
+        );
+    }
+}
+
+/// Height of the water surface at a world position.
+///
+/// The waves only perturb the normals so the surface is flat, anything floating should go through
+/// this so it follows if the surface is ever displaced.
+pub fn water_surface_height(terrain_config: &TerrainConfig, _position: Vec2) -> f32 {
+    terrain_config.water_level
+}
+
+/// Height of the box of the [`WaterReflectionProbe`], centered on the surface
+const REFLECTION_PROBE_HEIGHT: f32 = 1.0;
+
+/// Gives the [`WaterReflectionProbe`] the cubemap of the main camera at the skybox brightness.
+///
+/// The camera has no environment map until the procedural sky is composed, the water doesn't either
+/// then.
+pub fn sync_water_reflection(
+    mut commands: Commands,
+    scene_config: Res<SceneConfig>,
+    camera: Query<Ref<EnvironmentMapLight>, (With<CameraController>, Without<SecondaryCamera>)>,
+    probes: Query<Entity, With<WaterReflectionProbe>>,
+) {
+    let Ok(env_map) = camera.get_single() else {
+        return;
+    };
+    if !env_map.is_changed() && !scene_config.is_changed() {
+        return;
+    }
+    for probe in &probes {
+        let mut probe = commands.entity(probe);
+        if env_map.intensity > 0.0 {
+            probe.insert(EnvironmentMapLight {
+                diffuse_map: env_map.diffuse_map.clone(),
+                specular_map: env_map.specular_map.clone(),
+                intensity: scene_config.skybox_brightness,
+            });
+        } else {
+            probe.remove::<EnvironmentMapLight>();
+        }
+    }
+}
+
+/// Moves the water and foam planes to the configured water level
+pub fn follow_water_level(
+    terrain_config: Res<TerrainConfig>,
+    mut water: Query<&mut Transform, (With<WaterPlane>, Without<FoamPlane>)>,
+    mut foam: Query<&mut Transform, (With<FoamPlane>, Without<WaterPlane>)>,
+) {
+    for mut transform in &mut water {
+        transform.translation.y = terrain_config.water_level - 0.05;
+    }
+    for mut transform in &mut foam {
+        transform.translation.y = terrain_config.water_level;
+    }
+}
+
+/// Square single channel texture over the region of [`water_region`]
+fn water_region_image(data: &[u8]) -> Image {
+    let size = (data.len() as f32).sqrt() as u32;
+    let mut image = Image::new(
+        Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data.to_vec(),
+        TextureFormat::R8Unorm,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+    image.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
+        address_mode_u: ImageAddressMode::ClampToEdge,
+        address_mode_v: ImageAddressMode::ClampToEdge,
+        mag_filter: ImageFilterMode::Linear,
+        min_filter: ImageFilterMode::Linear,
         ..default()
     });
+    image
+}
+
+/// Square covering the rotated terrain, the origin and the extent of the depth and shelter
+/// textures
+fn water_region(heightfield: &TerrainHeightfield) -> (Vec2, f32) {
+    let (sin, cos) = heightfield.rotation.sin_cos();
+    let extent = heightfield.size * (sin.abs() + cos.abs());
+    (Vec2::splat(-extent * 0.5), extent)
+}
+
+/// Bakes the depth of the water over the terrain and updates the murk settings
+pub fn update_water_murk(
+    scene_config: Res<SceneConfig>,
+    terrain_config: Res<TerrainConfig>,
+    heightfield: Res<TerrainHeightfield>,
+    mut images: ResMut<Assets<Image>>,
+    mut water_materials: ResMut<Assets<WaterMaterial>>,
+    water: Query<&Handle<WaterMaterial>, With<WaterPlane>>,
+) {
+    if !scene_config.is_changed() && !terrain_config.is_changed() && !heightfield.is_changed() {
+        return;
+    }
+    let Ok(handle) = water.get_single() else {
+        return;
+    };
+    let Some(material) = water_materials.get_mut(handle) else {
+        return;
+    };
+
+    let water_config = &scene_config.water;
+    let (origin, extent) = water_region(&heightfield);
+
+    // Only bake the depth when the terrain changed, the murk settings don't need it
+    if heightfield.is_changed() || terrain_config.is_changed() {
+        let resolution = WATER_DEPTH_RESOLUTION;
+        let mut data = Vec::with_capacity((resolution * resolution) as usize);
+        for y in 0..resolution {
+            for x in 0..resolution {
+                let uv = (Vec2::new(x as f32, y as f32) + 0.5) / resolution as f32;
+                let depth = heightfield
+                    .height_at(origin + uv * extent)
+                    .map_or(MAX_WATER_DEPTH, |h| terrain_config.water_level - h);
+                data.push(
+                    (depth / MAX_WATER_DEPTH)
+                        .clamp(0.0, 1.0)
+                        .mul_add(255.0, 0.5) as u8,
+                );
+            }
+        }
+        material.extension.depth = images.add(water_region_image(&data));
+    }
+
+    let murk_color = water_config.murk_color.to_linear();
+    let settings = &mut material.extension.settings;
+    settings.murk_color = vec4(murk_color.red, murk_color.green, murk_color.blue, 1.0);
+    settings.murk_params = vec4(
+        water_config.murk_density,
+        water_config.clarity_depth,
+        origin.x,
+        origin.y,
+    );
+    settings.depth_region = vec4(extent, MAX_WATER_DEPTH, water_config.shelter_strength, 0.0);
+}
+
+/// Bakes how sheltered the water is from the wind by the terrain upwind.
+///
+/// Every texel looks for the terrain rising above the water along the upwind line, the closer and
+/// the higher the more sheltered. The strength is applied in the shader.
+#[allow(clippy::too_many_arguments)]
+pub fn update_water_shelter(
+    time: Res<Time<Real>>,
+    scene_config: Res<SceneConfig>,
+    terrain_config: Res<TerrainConfig>,
+    heightfield: Res<TerrainHeightfield>,
+    mut images: ResMut<Assets<Image>>,
+    mut water_materials: ResMut<Assets<WaterMaterial>>,
+    water: Query<&Handle<WaterMaterial>, With<WaterPlane>>,
+    mut last_inputs: Local<Option<(Vec2, f32, f32)>>,
+    mut changed_at: Local<Option<f32>>,
+) {
+    let water_config = &scene_config.water;
+    let wind = water_config.wind_direction.normalize_or_zero();
+    let inputs = (
+        wind,
+        water_config.shelter_distance,
+        water_config.shelter_height,
+    );
+    let now = time.elapsed_seconds();
+    if heightfield.is_changed() || terrain_config.is_changed() || *last_inputs != Some(inputs) {
+        *last_inputs = Some(inputs);
+        *changed_at = Some(now);
+    }
+    match *changed_at {
+        Some(since) if now - since >= SHELTER_DEBOUNCE_SECONDS => *changed_at = None,
+        _ => return,
+    }
+    let Some(material) = water
+        .get_single()
+        .ok()
+        .and_then(|handle| water_materials.get_mut(handle))
+    else {
+        return;
+    };
+    let _span = info_span!("bake_water_shelter").entered();
+
+    let (origin, extent) = water_region(&heightfield);
+    let resolution = WATER_SHELTER_RESOLUTION;
+    let distance = water_config.shelter_distance;
+    let mut data = Vec::with_capacity((resolution * resolution) as usize);
+    for y in 0..resolution {
+        for x in 0..resolution {
+            let uv = (Vec2::new(x as f32, y as f32) + 0.5) / resolution as f32;
+            let position = origin + uv * extent;
+            let mut shelter = 0.0_f32;
+            if wind != Vec2::ZERO {
+                for step in 1..=SHELTER_STEPS {
+                    let along = step as f32 / SHELTER_STEPS as f32;
+                    let Some(height) = heightfield.height_at(position - wind * along * distance)
+                    else {
+                        break;
+                    };
+                    let rise = (height - terrain_config.water_level) / water_config.shelter_height;
+                    shelter = shelter.max(rise.clamp(0.0, 1.0) * (1.0 - along));
+                }
+            }
+            data.push(shelter.mul_add(255.0, 0.5) as u8);
+        }
+    }
+    material.extension.shelter = images.add(water_region_image(&data));
+}
+
+/// Parameters to the foam shader, copied from the water material by [`sync_foam_with_water`]
+#[derive(ShaderType, Debug, Clone, Default)]
+pub struct FoamSettings {
+    /// x: speed of the primary water octave, y: time scale of the water, z: its scale,
+    /// w: streak strength
+    streaks: Vec4,
+    /// x: depth where the foam is gone, yz: depth texture origin, w: depth texture extent
+    shore: Vec4,
+    /// x: max depth encoded in the depth texture
+    depth_region: Vec4,
 }
 
 #[derive(Asset, AsBindGroup, Clone, TypePath)]
-pub struct FoamMaterial {}
+pub struct FoamMaterial {
+    #[uniform(0)]
+    settings: FoamSettings,
+    /// Same depth texture as the water, see [`Water::depth`]
+    #[texture(1)]
+    #[sampler(2)]
+    depth: Handle<Image>,
+    #[uniform(120)]
+    wind: WindUniform,
+}
+
+impl WindMaterial for FoamMaterial {
+    fn wind(&self) -> WindUniform {
+        self.wind
+    }
+
+    fn wind_mut(&mut self) -> &mut WindUniform {
+        &mut self.wind
+    }
+}
 
 impl Material for FoamMaterial {
     fn fragment_shader() -> ShaderRef {
@@ -112,3 +842,65 @@ impl Material for FoamMaterial {
         AlphaMode::Blend
     }
 }
+
+pub fn update_water_ripples(
+    time: Res<Time<Virtual>>,
+    mut ripples: ResMut<WaterRipples>,
+    mut images: ResMut<Assets<Image>>,
+    mut water_materials: ResMut<Assets<WaterMaterial>>,
+    water: Query<(&GlobalTransform, &Handle<WaterMaterial>), With<WaterPlane>>,
+    camera: Query<&GlobalTransform, (With<Camera3d>, Without<SecondaryCamera>)>,
+    mut disturbers: Query<(&GlobalTransform, &mut WaterDisturber)>,
+) {
+    let Ok((water_transform, water_material)) = water.get_single() else {
+        return;
+    };
+
+    let region = if ripples.enabled {
+        let water_level = water_transform.translation().y;
+        if let Ok(camera_transform) = camera.get_single() {
+            ripples.recenter(camera_transform.translation().xz());
+        }
+
+        for (transform, mut disturber) in &mut disturbers {
+            let position = transform.translation();
+            let moved = disturber
+                .last_position
+                .is_some_and(|last| last.distance_squared(position) > 1e-6);
+            disturber.last_position = Some(position);
+            if moved && (position.y - water_level).abs() < disturber.radius {
+                ripples.add_impulse(position.xz(), disturber.radius, disturber.strength);
+            }
+        }
+
+        ripples.accumulator += time.delta_seconds();
+        // cap the number of steps so a long frame doesn't stall the app
+        let mut steps = 0;
+        while ripples.accumulator >= RIPPLE_STEP && steps < 4 {
+            ripples.step();
+            ripples.accumulator -= RIPPLE_STEP;
+            steps += 1;
+        }
+        ripples.accumulator = ripples.accumulator.min(RIPPLE_STEP);
+
+        if let Some(image) = images.get_mut(&ripples.image) {
+            ripples.write_slopes(&mut image.data);
+        }
+
+        let origin = ripples.origin_world();
+        vec4(origin.x, origin.y, ripples.extent, ripples.strength)
+    } else {
+        Vec4::ZERO
+    };
+
+    // Avoid touching the material when nothing changed, it would re-prepare it every frame
+    let unchanged = water_materials
+        .get(water_material)
+        .is_some_and(|m| m.extension.settings.ripple_region == region);
+    if unchanged {
+        return;
+    }
+    if let Some(material) = water_materials.get_mut(water_material) {
+        material.extension.settings.ripple_region = region;
+    }
+}
@@ -0,0 +1,39 @@
+//! How wet the scene is.
+//!
+//! There's no rain rendering yet, `rain_intensity` in the scene config drives the puddles on the
+//! terrain which fill up while it rains and slowly dry out once it stops.
+
+use bevy::prelude::*;
+
+use crate::{deterministic::DeterministicClock, SceneConfig};
+
+/// Minutes it takes for the puddles to fill up under full rain
+const PUDDLE_FILL_MINUTES: f32 = 2.0;
+
+#[derive(Resource, Default)]
+pub struct Wetness {
+    /// 0 is dry ground, 1 is fully formed puddles
+    pub puddle_amount: f32,
+}
+
+pub fn update_wetness(
+    time: Res<Time<Virtual>>,
+    clock: Res<DeterministicClock>,
+    scene_config: Res<SceneConfig>,
+    mut wetness: ResMut<Wetness>,
+) {
+    let target = scene_config.rain_intensity;
+    let mut amount = wetness.puddle_amount;
+    for delta in clock.deltas(time.delta_seconds()) {
+        let minutes = delta / 60.0;
+        amount = if target > amount {
+            (amount + minutes / PUDDLE_FILL_MINUTES).min(target)
+        } else {
+            (amount - minutes / scene_config.puddle_drying_minutes.max(0.01)).max(target)
+        };
+    }
+    // Only flag the resource as changed when something happens
+    if amount != wetness.puddle_amount {
+        wetness.puddle_amount = amount;
+    }
+}
@@ -0,0 +1,142 @@
+//! White balance following the light of the scene.
+//!
+//! At dawn and dusk the low sun turned the forest orange unless the grading temperature of the
+//! preset was tuned by hand. With `auto_white_balance` above 0, the color of the light falling on
+//! the scene is estimated every frame from the sun, the moon, the ambient light and the environment
+//! map, and the grading temperature and tint are pushed against it. 1 makes that light white, 0
+//! leaves the grading alone. The `temperature` and `tint` of `color_grading_global` are offsets on
+//! top of the correction.
+//!
+//! The correction eases toward its target so it follows the sun smoothly and doesn't pop when the
+//! sky changes. It's applied after `on_scene_config_loaded`, so it sees the blends of a scene
+//! transition like any other config and only ever writes the temperature and the tint.
+
+use bevy::{prelude::*, render::view::ColorGrading};
+
+use crate::{
+    color_temp::{kelvin_to_color, MAX_KELVIN, MIN_KELVIN},
+    overlay::StatsOverlay,
+    sky_env_map::SkyEnvironmentMap,
+    split_view::SecondaryCamera,
+    SceneConfig,
+};
+
+/// Seconds for the correction to go most of the way to a new target
+const SMOOTHING_SECONDS: f32 = 1.5;
+/// Grading temperature canceling one unit of ln(red / blue) of the light. The white point of the
+/// grading moves like Unity's, -1 makes a light around 2500K white and its ln(red / blue) is ~2.9.
+const TEMPERATURE_PER_WARMTH: f32 = 0.35;
+/// Grading tint canceling one unit of ln(green) above the black body of the same warmth
+const TINT_PER_GREENNESS: f32 = 2.0;
+
+/// Correction added to the grading temperature and tint, eased toward the current light
+#[derive(Resource, Default)]
+pub struct WhiteBalance {
+    temperature: f32,
+    tint: f32,
+    /// Whether the correction was ever computed, the first one is applied at once
+    started: bool,
+}
+
+fn linear(color: Color) -> Vec3 {
+    let color = color.to_linear();
+    Vec3::new(color.red, color.green, color.blue)
+}
+
+/// ln(red / blue) of a color, 0 for white and positive for warm light
+fn warmth(color: Vec3) -> f32 {
+    (color.x / color.z).ln()
+}
+
+/// Temperature of the black body with the same warmth as `color`
+fn correlated_kelvin(color: Vec3) -> f32 {
+    let target = warmth(color);
+    // The warmth only goes down with the temperature
+    let (mut low, mut high) = (MIN_KELVIN, MAX_KELVIN);
+    for _ in 0..24 {
+        let middle = (low + high) / 2.0;
+        if warmth(linear(kelvin_to_color(middle))) > target {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    (low + high) / 2.0
+}
+
+/// Eases the correction toward the color of the light and adds it to the grading of the main camera
+#[allow(clippy::type_complexity)]
+pub fn update_white_balance(
+    time: Res<Time<Real>>,
+    scene_config: Res<SceneConfig>,
+    ambient_light: Res<AmbientLight>,
+    sky_env_map: Res<SkyEnvironmentMap>,
+    lights: Query<(&DirectionalLight, &GlobalTransform)>,
+    mut cameras: Query<
+        (&mut ColorGrading, &EnvironmentMapLight),
+        (With<Camera3d>, Without<SecondaryCamera>),
+    >,
+    mut white_balance: ResMut<WhiteBalance>,
+    mut stats: ResMut<StatsOverlay>,
+) {
+    let Ok((mut color_grading, env_map)) = cameras.get_single_mut() else {
+        return;
+    };
+
+    let strength = scene_config.auto_white_balance;
+    let mut target = Vec2::ZERO;
+    if strength > 0.0 {
+        let mut light = Vec3::ZERO;
+        for (directional_light, transform) in &lights {
+            // A low light spreads over more ground
+            let elevation = (-transform.forward().y).max(0.0);
+            light += linear(directional_light.color) * directional_light.illuminance * elevation;
+        }
+        light += linear(ambient_light.color) * ambient_light.brightness;
+        // The colors of the HDRI aren't known, it counts as white. Those of the procedural sky are.
+        let env_map_color = sky_env_map.ground_light(env_map).unwrap_or(Vec3::ONE);
+        light += env_map_color * env_map.intensity;
+
+        if light.min_element() > 0.0 && light.is_finite() {
+            let kelvin = correlated_kelvin(light);
+            let black_body = linear(kelvin_to_color(kelvin));
+            let greenness = (light.y / light.x).ln() - (black_body.y / black_body.x).ln();
+            target = Vec2::new(
+                -TEMPERATURE_PER_WARMTH * warmth(light),
+                TINT_PER_GREENNESS * greenness,
+            )
+            .clamp(Vec2::NEG_ONE, Vec2::ONE)
+                * strength;
+            stats.set(
+                "White balance",
+                format!(
+                    "light {kelvin:.0}K, temperature {:+.2}, tint {:+.2}",
+                    white_balance.temperature, white_balance.tint
+                ),
+            );
+        }
+    } else {
+        stats.remove("White balance");
+    }
+
+    let blend = if white_balance.started {
+        1.0 - (-time.delta_seconds() / SMOOTHING_SECONDS).exp()
+    } else {
+        1.0
+    };
+    let current = Vec2::new(white_balance.temperature, white_balance.tint);
+    let correction = current.lerp(target, blend);
+    if correction != current || !white_balance.started {
+        white_balance.temperature = correction.x;
+        white_balance.tint = correction.y;
+        white_balance.started = true;
+    }
+
+    let manual = &scene_config.color_grading_global;
+    let temperature = manual.temperature + white_balance.temperature;
+    let tint = manual.tint + white_balance.tint;
+    if color_grading.global.temperature != temperature || color_grading.global.tint != tint {
+        color_grading.global.temperature = temperature;
+        color_grading.global.tint = tint;
+    }
+}
@@ -0,0 +1,193 @@
+//! The wind every moving material agrees on.
+//!
+//! The water, the foam and the reeds each had their own idea of where the wind blew. [`Wind`] is
+//! now the only source, it follows `water.wind_direction` which also shelters the water and drifts
+//! the debris. Every material registered with [`AppWindExt::add_wind_material`] gets it in a
+//! uniform at binding 120, kept in sync by a single system, and `wind.wgsl` has the layout and the
+//! helpers: the gusts, the sway of a stem and the scroll of something drifting downwind.
+//!
+//! Making another material move with the wind takes a `#[uniform(120)] wind: WindUniform` field,
+//! an impl of [`WindMaterial`] and `add_wind_material` on the Rust side, and in its shader:
+//!
+//! ```wgsl
+//! #import forest_scene::wind::wind_sway
+//! world_position += vec4(wind_sway(origin, height, flexibility, globals.time), 0.0);
+//! ```
+
+use bevy::{prelude::*, render::render_resource::ShaderType};
+
+use crate::SceneConfig;
+
+/// Path of the shader with the wind uniform and helpers
+const WIND_SHADER_PATH: &str = "wind.wgsl";
+
+#[derive(Resource, Clone, Copy, Debug, PartialEq)]
+pub struct Wind {
+    /// Normalized direction the wind blows toward on the ground plane
+    pub direction: Vec2,
+    /// Scales the sways and the drifts, 1 is the look the materials were tuned for
+    pub strength: f32,
+    /// Gusts per second
+    pub gust_frequency: f32,
+}
+
+impl Default for Wind {
+    fn default() -> Self {
+        Self {
+            direction: Vec2::new(1.0, 0.3).normalize(),
+            strength: 1.0,
+            gust_frequency: 0.6,
+        }
+    }
+}
+
+/// The [`Wind`] as the shaders see it, see `wind.wgsl`
+#[derive(Clone, Copy, ShaderType, Debug, Default, PartialEq)]
+pub struct WindUniform {
+    /// xy: direction, z: strength, w: gusts per second
+    params: Vec4,
+}
+
+impl From<&Wind> for WindUniform {
+    fn from(wind: &Wind) -> Self {
+        Self {
+            params: wind
+                .direction
+                .extend(wind.strength)
+                .extend(wind.gust_frequency),
+        }
+    }
+}
+
+/// A material with a [`WindUniform`] at binding 120
+pub trait WindMaterial: Asset {
+    fn wind(&self) -> WindUniform;
+
+    fn wind_mut(&mut self) -> &mut WindUniform;
+}
+
+/// Handle keeping the shader loaded so its import path can be resolved
+#[derive(Resource)]
+struct WindShader(#[allow(dead_code)] Handle<Shader>);
+
+pub struct WindPlugin;
+
+impl Plugin for WindPlugin {
+    fn build(&self, app: &mut App) {
+        let shader = app.world().resource::<AssetServer>().load(WIND_SHADER_PATH);
+        app.insert_resource(WindShader(shader))
+            .init_resource::<Wind>()
+            .add_systems(
+                Update,
+                follow_scene_config.run_if(resource_exists_and_changed::<SceneConfig>),
+            );
+    }
+}
+
+pub trait AppWindExt {
+    /// Keeps the wind of every `M` in sync with [`Wind`]
+    fn add_wind_material<M: WindMaterial>(&mut self) -> &mut Self;
+}
+
+impl AppWindExt for App {
+    fn add_wind_material<M: WindMaterial>(&mut self) -> &mut Self {
+        self.add_systems(Update, sync_wind::<M>.after(follow_scene_config))
+    }
+}
+
+fn follow_scene_config(scene_config: Res<SceneConfig>, mut wind: ResMut<Wind>) {
+    let direction = scene_config.water.wind_direction.normalize_or_zero();
+    if wind.direction != direction {
+        wind.direction = direction;
+    }
+}
+
+/// Gives the wind to the materials that don't have it yet, the new ones or all of them once it
+/// changed
+fn sync_wind<M: WindMaterial>(wind: Res<Wind>, mut materials: ResMut<Assets<M>>) {
+    let uniform = WindUniform::from(&*wind);
+    let stale: Vec<_> = materials
+        .iter()
+        .filter(|(_, material)| material.wind() != uniform)
+        .map(|(id, _)| id)
+        .collect();
+    for id in stale {
+        if let Some(material) = materials.get_mut(id) {
+            *material.wind_mut() = uniform;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{ecs::system::RunSystemOnce, render::render_resource::encase::UniformBuffer};
+
+    use super::*;
+
+    #[derive(Asset, TypePath)]
+    struct Swaying {
+        wind: WindUniform,
+    }
+
+    impl WindMaterial for Swaying {
+        fn wind(&self) -> WindUniform {
+            self.wind
+        }
+
+        fn wind_mut(&mut self) -> &mut WindUniform {
+            &mut self.wind
+        }
+    }
+
+    #[test]
+    fn the_uniform_matches_the_shader_layout() {
+        assert!(include_str!("../assets/wind.wgsl").contains("params: vec4<f32>,\n}"));
+        assert_eq!(WindUniform::min_size().get(), 16);
+        let wind = Wind {
+            direction: Vec2::new(0.6, -0.8),
+            strength: 2.0,
+            gust_frequency: 0.25,
+        };
+        let mut buffer = UniformBuffer::new(Vec::<u8>::new());
+        buffer.write(&WindUniform::from(&wind)).unwrap();
+        let floats: Vec<f32> = buffer
+            .into_inner()
+            .chunks(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(floats, [0.6, -0.8, 2.0, 0.25]);
+    }
+
+    #[test]
+    fn materials_follow_the_wind() {
+        let mut world = World::new();
+        world.init_resource::<Wind>();
+        let mut materials = Assets::<Swaying>::default();
+        let material = materials.add(Swaying {
+            wind: WindUniform::default(),
+        });
+        world.insert_resource(materials);
+        let wind = |world: &World| {
+            world
+                .resource::<Assets<Swaying>>()
+                .get(&material)
+                .unwrap()
+                .wind
+        };
+
+        world.run_system_once(sync_wind::<Swaying>);
+        assert_eq!(wind(&world), WindUniform::from(&Wind::default()));
+
+        world.insert_resource(SceneConfig::default());
+        world.resource_mut::<SceneConfig>().water.wind_direction = Vec2::new(0.0, -2.0);
+        world.resource_mut::<Wind>().strength = 3.0;
+        world.run_system_once(follow_scene_config);
+        world.run_system_once(sync_wind::<Swaying>);
+        assert_eq!(
+            wind(&world),
+            WindUniform {
+                params: Vec4::new(0.0, -1.0, 3.0, Wind::default().gust_frequency),
+            }
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! Shareable world codes.
+//!
+//! A world code is the generation relevant subset of the `TerrainConfig` packed into a few bytes
+//! and encoded as url safe base64 so it can be pasted in a chat. The first byte is a version so
+//! codes from older builds can be rejected instead of silently producing a different world.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bevy::prelude::*;
+
+use crate::{cli::CliArgs, terrain::TerrainConfig};
+
+const WORLD_CODE_VERSION: u8 = 1;
+
+#[derive(Debug)]
+pub enum WorldCodeError {
+    InvalidBase64(base64::DecodeError),
+    UnsupportedVersion(u8),
+    Truncated,
+}
+
+impl std::fmt::Display for WorldCodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WorldCodeError::InvalidBase64(err) => write!(f, "invalid world code: {err}"),
+            WorldCodeError::UnsupportedVersion(version) => write!(
+                f,
+                "world code version {version} isn't supported, expected {WORLD_CODE_VERSION}"
+            ),
+            WorldCodeError::Truncated => write!(f, "world code is truncated"),
+        }
+    }
+}
+
+/// Encodes everything that affects the generated world
+pub fn world_code(terrain_config: &TerrainConfig) -> String {
+    let mut bytes = vec![WORLD_CODE_VERSION];
+    bytes.extend(terrain_config.seed.to_le_bytes());
+    bytes.extend(terrain_config.half_size.to_le_bytes());
+    bytes.extend(terrain_config.frequency.to_le_bytes());
+    bytes.push(terrain_config.octaves.min(u8::MAX as usize) as u8);
+    bytes.extend(terrain_config.density.to_le_bytes());
+    bytes.extend(terrain_config.max_steepness.to_le_bytes());
+    bytes.extend(terrain_config.rotation.to_le_bytes());
+    bytes.extend(terrain_config.water_level.to_le_bytes());
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Applies a world code on top of the given config. Settings that don't affect generation are
+/// left untouched.
+pub fn load_world_code(
+    code: &str,
+    terrain_config: &mut TerrainConfig,
+) -> Result<(), WorldCodeError> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(code.trim())
+        .map_err(WorldCodeError::InvalidBase64)?;
+    let (&version, mut bytes) = bytes.split_first().ok_or(WorldCodeError::Truncated)?;
+    if version != WORLD_CODE_VERSION {
+        return Err(WorldCodeError::UnsupportedVersion(version));
+    }
+
+    let seed = u32::from_le_bytes(take(&mut bytes, 4)?.try_into().unwrap());
+    let half_size = u32::from_le_bytes(take(&mut bytes, 4)?.try_into().unwrap());
+    let frequency = f64::from_le_bytes(take(&mut bytes, 8)?.try_into().unwrap());
+    let octaves = take(&mut bytes, 1)?[0] as usize;
+    let density = f32::from_le_bytes(take(&mut bytes, 4)?.try_into().unwrap());
+    let max_steepness = f32::from_le_bytes(take(&mut bytes, 4)?.try_into().unwrap());
+    let rotation = f32::from_le_bytes(take(&mut bytes, 4)?.try_into().unwrap());
+    let water_level = f32::from_le_bytes(take(&mut bytes, 4)?.try_into().unwrap());
+
+    terrain_config.seed = seed;
+    terrain_config.half_size = half_size;
+    terrain_config.frequency = frequency;
+    terrain_config.octaves = octaves;
+    terrain_config.density = density;
+    terrain_config.max_steepness = max_steepness;
+    terrain_config.rotation = rotation;
+    terrain_config.water_level = water_level;
+    Ok(())
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], WorldCodeError> {
+    if bytes.len() < len {
+        return Err(WorldCodeError::Truncated);
+    }
+    let (head, tail) = bytes.split_at(len);
+    *bytes = tail;
+    Ok(head)
+}
+
+/// World code passed on the command line, applied once the terrain config is loaded
+#[derive(Resource)]
+pub struct PendingWorldCode(pub String);
+
+pub fn queue_world_code_from_cli(mut commands: Commands, cli: Res<CliArgs>) {
+    if let Some(code) = &cli.world_code {
+        commands.insert_resource(PendingWorldCode(code.clone()));
+    }
+}
+
+pub fn apply_pending_world_code(
+    mut commands: Commands,
+    pending: Res<PendingWorldCode>,
+    mut terrain_config: ResMut<TerrainConfig>,
+) {
+    commands.remove_resource::<PendingWorldCode>();
+    match load_world_code(&pending.0, &mut terrain_config) {
+        Ok(()) => info!("loaded world code {}: {:?}", pending.0, *terrain_config),
+        Err(err) => error!("{err}"),
+    }
+}
+
+/// Copies the code of the current world to the clipboard when pressing F8
+pub fn copy_world_code(keyboard: Res<ButtonInput<KeyCode>>, terrain_config: Res<TerrainConfig>) {
+    if !keyboard.just_pressed(KeyCode::F8) {
+        return;
+    }
+    let code = world_code(&terrain_config);
+    info!("world code: {code}");
+    #[cfg(not(target_arch = "wasm32"))]
+    match arboard::Clipboard::new().and_then(|mut clipboard| clipboard.set_text(code)) {
+        Ok(()) => info!("world code copied to the clipboard"),
+        Err(err) => warn!("failed to copy the world code to the clipboard: {err}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generated_config() -> TerrainConfig {
+        TerrainConfig {
+            seed: 0xdead_beef,
+            half_size: 750,
+            frequency: 1.375,
+            octaves: 9,
+            density: 0.31,
+            max_steepness: 0.42,
+            rotation: -2.5,
+            water_level: 3.25,
+            ..default()
+        }
+    }
+
+    #[test]
+    fn decoding_gives_back_the_encoded_settings() {
+        let config = generated_config();
+        let code = world_code(&config);
+
+        let mut decoded = TerrainConfig::default();
+        load_world_code(&code, &mut decoded).unwrap();
+        assert_eq!(decoded.seed, config.seed);
+        assert_eq!(decoded.half_size, config.half_size);
+        assert_eq!(decoded.frequency, config.frequency);
+        assert_eq!(decoded.octaves, config.octaves);
+        assert_eq!(decoded.density, config.density);
+        assert_eq!(decoded.max_steepness, config.max_steepness);
+        assert_eq!(decoded.rotation, config.rotation);
+        assert_eq!(decoded.water_level, config.water_level);
+        assert_eq!(world_code(&decoded), code);
+    }
+
+    #[test]
+    fn surrounding_whitespace_is_ignored() {
+        let code = world_code(&generated_config());
+        let mut decoded = TerrainConfig::default();
+        load_world_code(&format!("  {code}\n"), &mut decoded).unwrap();
+        assert_eq!(world_code(&decoded), code);
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let code = world_code(&generated_config());
+        let mut bytes = URL_SAFE_NO_PAD.decode(&code).unwrap();
+        bytes[0] = WORLD_CODE_VERSION + 1;
+        let code = URL_SAFE_NO_PAD.encode(bytes);
+
+        let mut config = TerrainConfig::default();
+        let result = load_world_code(&code, &mut config);
+        assert!(matches!(
+            result,
+            Err(WorldCodeError::UnsupportedVersion(version)) if version == WORLD_CODE_VERSION + 1
+        ));
+        // Nothing is applied from a rejected code
+        assert_eq!(config.seed, TerrainConfig::default().seed);
+    }
+
+    #[test]
+    fn truncated_codes_are_rejected() {
+        let code = world_code(&generated_config());
+        let bytes = URL_SAFE_NO_PAD.decode(&code).unwrap();
+        for len in [0, 1, bytes.len() - 1] {
+            let code = URL_SAFE_NO_PAD.encode(&bytes[..len]);
+            let result = load_world_code(&code, &mut TerrainConfig::default());
+            assert!(matches!(result, Err(WorldCodeError::Truncated)), "{len}");
+        }
+        let result = load_world_code("not a code!", &mut TerrainConfig::default());
+        assert!(matches!(result, Err(WorldCodeError::InvalidBase64(_))));
+    }
+}